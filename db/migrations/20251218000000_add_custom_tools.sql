@@ -0,0 +1,13 @@
+-- User-defined HTTP tools offered to the model next to MCP tools
+CREATE TABLE IF NOT EXISTS custom_tools (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    description TEXT NOT NULL,
+    parameters TEXT NOT NULL DEFAULT '{"type":"object","properties":{}}', -- JSON schema
+    url TEXT NOT NULL,
+    auth_header TEXT, -- sent verbatim as the Authorization header
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE,
+    UNIQUE (user_id, name)
+);
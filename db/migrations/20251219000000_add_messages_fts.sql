@@ -0,0 +1,23 @@
+-- Full-text index over message bodies for chat search
+CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5(
+    message,
+    content = 'messages',
+    content_rowid = 'id'
+);
+
+-- Keep the index in sync with the messages table
+CREATE TRIGGER IF NOT EXISTS messages_fts_insert AFTER INSERT ON messages BEGIN
+    INSERT INTO messages_fts (rowid, message) VALUES (new.id, new.message);
+END;
+
+CREATE TRIGGER IF NOT EXISTS messages_fts_delete AFTER DELETE ON messages BEGIN
+    INSERT INTO messages_fts (messages_fts, rowid, message) VALUES ('delete', old.id, old.message);
+END;
+
+CREATE TRIGGER IF NOT EXISTS messages_fts_update AFTER UPDATE OF message ON messages BEGIN
+    INSERT INTO messages_fts (messages_fts, rowid, message) VALUES ('delete', old.id, old.message);
+    INSERT INTO messages_fts (rowid, message) VALUES (new.id, new.message);
+END;
+
+-- Index messages written before this migration
+INSERT INTO messages_fts (messages_fts) VALUES ('rebuild');
@@ -0,0 +1,3 @@
+-- Let users pin chats and order them manually in the sidebar
+ALTER TABLE chats ADD COLUMN pinned BOOLEAN NOT NULL DEFAULT 0;
+ALTER TABLE chats ADD COLUMN sort_order INTEGER NOT NULL DEFAULT 0;
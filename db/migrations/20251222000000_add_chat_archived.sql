@@ -0,0 +1,2 @@
+-- Archived chats are hidden from the sidebar but kept until deleted
+ALTER TABLE chats ADD COLUMN archived BOOLEAN NOT NULL DEFAULT 0;
@@ -0,0 +1,3 @@
+-- Anyone holding a chat's share token can read it at /share/{token}
+ALTER TABLE chats ADD COLUMN share_token TEXT;
+CREATE UNIQUE INDEX IF NOT EXISTS idx_chats_share_token ON chats (share_token);
@@ -0,0 +1,64 @@
+-- Each block continues a specific pair of the block before it, so editing a
+-- message branches the conversation instead of overwriting it
+ALTER TABLE message_blocks ADD COLUMN parent_pair_id INTEGER REFERENCES message_pairs (id) ON DELETE CASCADE;
+
+UPDATE message_blocks SET parent_pair_id = (
+  SELECT prev.selected_pair_id FROM message_blocks prev
+  WHERE prev.chat_id = message_blocks.chat_id AND prev.id < message_blocks.id
+  ORDER BY prev.id DESC
+  LIMIT 1
+);
+
+CREATE INDEX IF NOT EXISTS idx_message_blocks_parent_pair_id ON message_blocks (parent_pair_id);
+
+-- Only the selected pair of each block on the selected branch is shown;
+-- block_rank and block_size still count every version in the block
+DROP VIEW IF EXISTS v_chat_messages;
+CREATE VIEW v_chat_messages AS
+WITH RECURSIVE visible_blocks(id) AS (
+  SELECT id FROM message_blocks WHERE parent_pair_id IS NULL
+  UNION ALL
+  SELECT child.id
+  FROM visible_blocks
+  JOIN message_blocks parent ON parent.id = visible_blocks.id
+  JOIN message_blocks child ON child.parent_pair_id = parent.selected_pair_id
+)
+SELECT
+  id, message_block_id, chat_id, model, human_message, ai_message, thinking,
+  tool_calls, images, reasoning, usage_prompt_tokens, usage_completion_tokens,
+  usage_total_tokens, sources, block_rank, block_size
+FROM (
+  SELECT
+    message_pairs.id,
+    message_block_id,
+    message_blocks.chat_id AS chat_id,
+    message_blocks.selected_pair_id AS selected_pair_id,
+    chats.model AS model,
+    human_message.message AS human_message,
+    ai_message.message AS ai_message,
+    ai_message.thinking AS thinking,
+    ai_message.tool_calls AS tool_calls,
+    ai_message.images AS images,
+    ai_message.reasoning AS reasoning,
+    ai_message.usage_prompt_tokens AS usage_prompt_tokens,
+    ai_message.usage_completion_tokens AS usage_completion_tokens,
+    ai_message.usage_total_tokens AS usage_total_tokens,
+    ai_message.sources AS sources,
+    RANK() OVER (
+      PARTITION BY message_block_id
+      ORDER BY
+        message_pairs.created_at ASC, message_pairs.id ASC
+    ) AS block_rank,
+    COUNT(*) OVER (PARTITION BY message_block_id) AS block_size
+  FROM
+    message_pairs
+    JOIN messages human_message ON human_message.id = message_pairs.human_message_id
+    LEFT JOIN messages ai_message ON ai_message.id = message_pairs.ai_message_id
+    JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+    JOIN chats ON chats.id = message_blocks.chat_id
+)
+WHERE
+  id = selected_pair_id
+  AND message_block_id IN (SELECT id FROM visible_blocks)
+ORDER BY
+  message_block_id ASC;
@@ -0,0 +1,3 @@
+-- Deleting a pair checks whether its messages are still referenced
+CREATE INDEX IF NOT EXISTS idx_message_pairs_human_message_id ON message_pairs (human_message_id);
+CREATE INDEX IF NOT EXISTS idx_message_pairs_ai_message_id ON message_pairs (ai_message_id);
@@ -0,0 +1,11 @@
+-- Thumbs up/down ratings of AI responses
+CREATE TABLE IF NOT EXISTS message_feedback (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    message_pair_id INTEGER NOT NULL,
+    rating INTEGER NOT NULL CHECK (rating IN (-1, 1)),
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE,
+    FOREIGN KEY (message_pair_id) REFERENCES message_pairs (id) ON DELETE CASCADE,
+    UNIQUE (user_id, message_pair_id)
+);
@@ -0,0 +1,10 @@
+-- AI responses the user saved for later
+CREATE TABLE IF NOT EXISTS message_bookmarks (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    message_pair_id INTEGER NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE,
+    FOREIGN KEY (message_pair_id) REFERENCES message_pairs (id) ON DELETE CASCADE,
+    UNIQUE (user_id, message_pair_id)
+);
@@ -0,0 +1,6 @@
+-- Per-chat overrides applied to completion requests
+CREATE TABLE IF NOT EXISTS chat_settings (
+    chat_id INTEGER PRIMARY KEY,
+    stop_sequences TEXT, -- JSON array of strings, sent as `stop`
+    FOREIGN KEY (chat_id) REFERENCES chats (id) ON DELETE CASCADE
+);
@@ -0,0 +1,4 @@
+-- Per-chat sampling overrides; NULL falls back to the user's settings
+ALTER TABLE chat_settings ADD COLUMN temperature REAL;
+ALTER TABLE chat_settings ADD COLUMN top_p REAL;
+ALTER TABLE chat_settings ADD COLUMN max_tokens INTEGER;
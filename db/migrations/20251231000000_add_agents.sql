@@ -0,0 +1,18 @@
+-- Reusable model + prompt + parameter presets that chats can be bound to
+CREATE TABLE IF NOT EXISTS agents (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    model TEXT NOT NULL,
+    system_prompt TEXT,
+    temperature REAL,
+    top_p REAL,
+    max_tokens INTEGER,
+    stop_sequences TEXT, -- JSON array of strings
+    tools_enabled BOOLEAN NOT NULL DEFAULT 1,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE,
+    UNIQUE (user_id, name)
+);
+
+ALTER TABLE chats ADD COLUMN agent_id INTEGER REFERENCES agents (id) ON DELETE SET NULL;
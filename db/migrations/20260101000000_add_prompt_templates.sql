@@ -0,0 +1,24 @@
+-- Starter prompts for the home page gallery; rows without a user are curated
+CREATE TABLE IF NOT EXISTS prompt_templates (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER,
+    title TEXT NOT NULL,
+    description TEXT NOT NULL DEFAULT '',
+    prompt TEXT NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+INSERT INTO prompt_templates (title, description, prompt) VALUES
+    ('Learn something new', 'A short lesson on a topic you pick',
+     'Teach me something interesting I probably don''t know. Start by asking which field I''m curious about, then explain one idea from it in a few paragraphs with a concrete example.'),
+    ('Code review partner', 'Get feedback on a snippet',
+     'Act as a senior engineer reviewing my code. Ask me to paste a snippet, then point out bugs first, readability issues second, and suggest idiomatic improvements.'),
+    ('Plan a project', 'Milestones, risks and first steps',
+     'Help me plan a project. Ask me a few questions about the goal, deadline and resources, then propose milestones, the main risks and what I should do first.'),
+    ('Write an email', 'Polite, concise and to the point',
+     'Help me write an email. Ask me who it is for and what I need to say, then draft a polite version under 150 words.'),
+    ('Practice a language', 'A conversation with gentle corrections',
+     'Let''s practice a foreign language. Ask me which language and my level, then chat with me in it, correcting my mistakes briefly after each reply.'),
+    ('Brainstorm ideas', 'Ten varied ideas to pick from',
+     'Let''s brainstorm. Ask me what I need ideas for, then give me ten varied options, from safe to unconventional.');
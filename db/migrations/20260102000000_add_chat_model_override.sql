@@ -0,0 +1,3 @@
+-- Model picked for a single chat with `/model`; NULL falls back to the agent's
+-- or the user's model
+ALTER TABLE chat_settings ADD COLUMN model TEXT;
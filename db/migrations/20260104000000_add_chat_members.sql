@@ -0,0 +1,29 @@
+-- People the owner shared a chat with. The owner is `chats.user_id` and never
+-- has a row here
+CREATE TABLE IF NOT EXISTS chat_members (
+    chat_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    role TEXT NOT NULL CHECK (role IN ('editor', 'viewer')),
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (chat_id, user_id),
+    FOREIGN KEY (chat_id) REFERENCES chats (id) ON DELETE CASCADE,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_chat_members_user_id ON chat_members (user_id);
+
+-- Invitations for emails without an account yet; they become memberships on signup
+CREATE TABLE IF NOT EXISTS chat_invitations (
+    chat_id INTEGER NOT NULL,
+    email TEXT NOT NULL,
+    role TEXT NOT NULL CHECK (role IN ('editor', 'viewer')),
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (chat_id, email),
+    FOREIGN KEY (chat_id) REFERENCES chats (id) ON DELETE CASCADE
+);
+
+-- Everyone with access to a chat and their role
+CREATE VIEW IF NOT EXISTS v_chat_access AS
+SELECT id AS chat_id, user_id, 'owner' AS role FROM chats
+UNION ALL
+SELECT chat_id, user_id, role FROM chat_members;
@@ -0,0 +1,3 @@
+-- Model that produced a response and how long it took, for per-chat stats
+ALTER TABLE messages ADD COLUMN model TEXT;
+ALTER TABLE messages ADD COLUMN latency_ms INTEGER;
@@ -0,0 +1,14 @@
+-- Deleted chats stay in the trash until restored or purged after 30 days
+ALTER TABLE chats ADD COLUMN deleted_at DATETIME;
+
+CREATE INDEX IF NOT EXISTS idx_chats_deleted_at ON chats (deleted_at);
+
+-- Nobody has access to a chat in the trash
+DROP VIEW IF EXISTS v_chat_access;
+CREATE VIEW v_chat_access AS
+SELECT id AS chat_id, user_id, 'owner' AS role FROM chats WHERE deleted_at IS NULL
+UNION ALL
+SELECT chat_members.chat_id, chat_members.user_id, chat_members.role
+FROM chat_members
+JOIN chats ON chats.id = chat_members.chat_id
+WHERE chats.deleted_at IS NULL;
@@ -0,0 +1,16 @@
+-- New accounts confirm their email before chatting. Existing accounts count
+-- as verified
+ALTER TABLE users ADD COLUMN verified_at DATETIME;
+
+UPDATE users SET verified_at = created_at;
+
+-- Same shape as `password_resets`: only the token's SHA-256 hash is stored
+CREATE TABLE IF NOT EXISTS email_verifications (
+    token_hash TEXT PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    expires_at DATETIME NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_email_verifications_user_id ON email_verifications (user_id);
@@ -0,0 +1,9 @@
+-- API tokens are stored as their SHA-256 hash, carry a scope and can expire.
+-- Tokens created before keep their plain text here until their first use,
+-- when `authenticate_api_token` swaps it for the hash
+ALTER TABLE api_tokens RENAME COLUMN token TO token_hash;
+
+-- 'read' or 'write'; existing tokens could do everything
+ALTER TABLE api_tokens ADD COLUMN scope TEXT NOT NULL DEFAULT 'write';
+
+ALTER TABLE api_tokens ADD COLUMN expires_at TIMESTAMP;
@@ -0,0 +1,9 @@
+-- Admins manage other accounts under /admin/users. The role is granted at
+-- startup to the emails in ADMIN_EMAILS
+ALTER TABLE users ADD COLUMN is_admin BOOLEAN NOT NULL DEFAULT 0;
+
+-- Disabled accounts can't log in, and their sessions and API tokens stop working
+ALTER TABLE users ADD COLUMN disabled_at DATETIME;
+
+-- Set on sessions an admin opened as another user for support
+ALTER TABLE sessions ADD COLUMN impersonator_id INTEGER REFERENCES users (id) ON DELETE CASCADE;
@@ -0,0 +1,21 @@
+-- "Remember me" logins. The cookie holds `<series>.<token>` and only the
+-- token's hash is stored. The token is replaced each time it logs the browser
+-- back in; an old token coming back means the cookie was copied, and the
+-- series is revoked. `previous_hash` stays valid for a moment after rotating,
+-- for requests the browser sent in parallel
+CREATE TABLE IF NOT EXISTS remember_tokens (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    series TEXT NOT NULL UNIQUE,
+    token_hash TEXT NOT NULL,
+    previous_hash TEXT,
+    rotated_at DATETIME,
+    expires_at DATETIME NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_remember_tokens_user_id ON remember_tokens (user_id);
+
+-- Sessions started from a "Remember me" cookie end with its series
+ALTER TABLE sessions ADD COLUMN remember_id INTEGER REFERENCES remember_tokens (id) ON DELETE CASCADE;
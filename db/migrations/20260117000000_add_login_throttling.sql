@@ -0,0 +1,15 @@
+-- Failed password logins, by the email tried and the client IP. Recent rows
+-- slow down and block guessing from one address; older ones are pruned
+CREATE TABLE IF NOT EXISTS login_failures (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    email TEXT NOT NULL,
+    ip TEXT,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_login_failures_ip ON login_failures (ip, created_at);
+
+-- Failed logins since the last successful one; enough of them lock the
+-- account's password login until `locked_until`
+ALTER TABLE users ADD COLUMN failed_logins INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE users ADD COLUMN locked_until DATETIME;
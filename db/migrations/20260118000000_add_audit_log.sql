@@ -0,0 +1,15 @@
+-- Security events on accounts: logins, password and API key changes, tool
+-- approvals and admin actions. `actor` is the admin's email when someone
+-- other than the user acted on the account
+CREATE TABLE IF NOT EXISTS audit_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER REFERENCES users (id) ON DELETE CASCADE,
+    event TEXT NOT NULL,
+    detail TEXT,
+    actor TEXT,
+    ip TEXT,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS idx_audit_log_user ON audit_log (user_id, created_at);
+CREATE INDEX IF NOT EXISTS idx_audit_log_event ON audit_log (event, created_at);
@@ -0,0 +1,4 @@
+-- Shown instead of the email address where set. `avatar` is the path of a
+-- square image under `uploads/avatars/`
+ALTER TABLE users ADD COLUMN display_name TEXT;
+ALTER TABLE users ADD COLUMN avatar TEXT;
@@ -0,0 +1,13 @@
+-- An admin's override of the QUOTA_* limits for one user. NULL keeps the
+-- default and 0 lifts the limit
+CREATE TABLE IF NOT EXISTS user_quotas (
+    user_id INTEGER PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE,
+    daily_tokens INTEGER,
+    monthly_tokens INTEGER,
+    daily_messages INTEGER,
+    monthly_messages INTEGER,
+    updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Usage totals look up a user's recent messages by date
+CREATE INDEX IF NOT EXISTS idx_message_pairs_created_at ON message_pairs (created_at);
@@ -0,0 +1,12 @@
+-- Invite codes admins hand out when INVITE_ONLY=true closes open signup.
+-- Each code creates one account
+CREATE TABLE IF NOT EXISTS invites (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    code TEXT NOT NULL UNIQUE,
+    note TEXT,
+    created_by INTEGER REFERENCES users (id) ON DELETE SET NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    used_by INTEGER REFERENCES users (id) ON DELETE SET NULL,
+    used_at DATETIME,
+    revoked_at DATETIME
+);
@@ -0,0 +1,43 @@
+-- Team workspaces. Members see the org's chats and agents, and its chats
+-- use the org's API key and model when it has one
+CREATE TABLE IF NOT EXISTS orgs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL,
+    -- Encrypted like `settings.openai_api_key`
+    openai_api_key TEXT,
+    model TEXT,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS org_members (
+    org_id INTEGER NOT NULL REFERENCES orgs (id) ON DELETE CASCADE,
+    user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE,
+    role TEXT NOT NULL CHECK (role IN ('member', 'admin', 'owner')),
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (org_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_org_members_user_id ON org_members (user_id);
+
+-- The workspace picked in the navbar; NULL is the personal one
+ALTER TABLE users ADD COLUMN current_org_id INTEGER REFERENCES orgs (id) ON DELETE SET NULL;
+
+ALTER TABLE agents ADD COLUMN org_id INTEGER REFERENCES orgs (id) ON DELETE CASCADE;
+ALTER TABLE chats ADD COLUMN org_id INTEGER REFERENCES orgs (id) ON DELETE SET NULL;
+
+CREATE INDEX IF NOT EXISTS idx_chats_org_id ON chats (org_id);
+
+-- Org members can edit the org's chats
+DROP VIEW IF EXISTS v_chat_access;
+CREATE VIEW v_chat_access AS
+SELECT id AS chat_id, user_id, 'owner' AS role FROM chats WHERE deleted_at IS NULL
+UNION ALL
+SELECT chat_members.chat_id, chat_members.user_id, chat_members.role
+FROM chat_members
+JOIN chats ON chats.id = chat_members.chat_id
+WHERE chats.deleted_at IS NULL
+UNION ALL
+SELECT chats.id, org_members.user_id, 'editor'
+FROM chats
+JOIN org_members ON org_members.org_id = chats.org_id
+WHERE chats.deleted_at IS NULL;
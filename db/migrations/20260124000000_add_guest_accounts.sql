@@ -0,0 +1,7 @@
+-- Trial accounts made for visitors when GUEST_MODE=true. They're removed
+-- with their chats after `guest_expires_at`, unless they sign up first, which
+-- clears it
+ALTER TABLE users ADD COLUMN guest_expires_at DATETIME;
+
+CREATE INDEX IF NOT EXISTS idx_users_guest_expires_at ON users (guest_expires_at)
+    WHERE guest_expires_at IS NOT NULL;
@@ -0,0 +1,21 @@
+-- Embeddings of answered message pairs for semantic search. `embedding` is a
+-- little-endian f32 blob, the format sqlite-vec reads
+CREATE TABLE IF NOT EXISTS message_chunks (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    pair_id INTEGER NOT NULL UNIQUE,
+    chat_id INTEGER NOT NULL,
+    content TEXT NOT NULL,
+    embedding BLOB NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (pair_id) REFERENCES message_pairs (id) ON DELETE CASCADE,
+    FOREIGN KEY (chat_id) REFERENCES chats (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_message_chunks_chat ON message_chunks (chat_id);
+
+-- Edited messages are embedded again
+CREATE TRIGGER IF NOT EXISTS message_chunks_stale AFTER UPDATE OF message ON messages BEGIN
+    DELETE FROM message_chunks WHERE pair_id IN (
+        SELECT id FROM message_pairs WHERE human_message_id = new.id OR ai_message_id = new.id
+    );
+END;
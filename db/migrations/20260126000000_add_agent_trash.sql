@@ -0,0 +1,5 @@
+-- Deleted agents wait in the trash like chats before they're purged; chats
+-- bound to one fall back to the defaults meanwhile
+ALTER TABLE agents ADD COLUMN deleted_at DATETIME;
+
+CREATE INDEX IF NOT EXISTS idx_agents_deleted_at ON agents (deleted_at);
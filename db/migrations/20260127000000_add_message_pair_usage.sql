@@ -0,0 +1,14 @@
+-- Tokens and cost of the pair's current response, so usage can be summed
+-- per chat, user and day without joining every message. `cost` is USD and
+-- stays NULL for models without a known price, see `ai::pricing`
+ALTER TABLE message_pairs ADD COLUMN prompt_tokens INTEGER;
+ALTER TABLE message_pairs ADD COLUMN completion_tokens INTEGER;
+ALTER TABLE message_pairs ADD COLUMN cost REAL;
+
+-- Earlier responses keep their tokens; their cost is unknown
+UPDATE message_pairs
+SET prompt_tokens = (SELECT usage_prompt_tokens FROM messages WHERE messages.id = message_pairs.ai_message_id),
+    completion_tokens = (SELECT usage_completion_tokens FROM messages WHERE messages.id = message_pairs.ai_message_id)
+WHERE ai_message_id IS NOT NULL;
+
+CREATE INDEX IF NOT EXISTS idx_message_pairs_created_at ON message_pairs (created_at);
@@ -0,0 +1,17 @@
+-- Files uploaded with a message. `path` is the stored file's name under
+-- `uploads/` and `name` the one it was uploaded as; `kind` is 'image' for
+-- files shown inline and 'file' for the rest
+CREATE TABLE IF NOT EXISTS attachments (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    message_pair_id INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    path TEXT NOT NULL,
+    mime TEXT NOT NULL,
+    size INTEGER NOT NULL,
+    kind TEXT NOT NULL CHECK (kind IN ('image', 'file')),
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (message_pair_id) REFERENCES message_pairs (id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_attachments_message_pair ON attachments (message_pair_id);
+CREATE INDEX IF NOT EXISTS idx_attachments_path ON attachments (path);
@@ -0,0 +1,4 @@
+-- Bumped on every save; forms send back the version they were rendered
+-- with so a save from a stale page is rejected instead of overwriting
+ALTER TABLE settings ADD COLUMN version INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE agents ADD COLUMN version INTEGER NOT NULL DEFAULT 0;
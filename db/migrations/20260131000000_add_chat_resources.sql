@@ -0,0 +1,15 @@
+-- MCP server resources attached to a chat. The text is read once when the
+-- resource is attached and goes into the system prompt of every response,
+-- so the chat keeps working when the server is down
+CREATE TABLE IF NOT EXISTS chat_resources (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    chat_id INTEGER NOT NULL,
+    server TEXT NOT NULL,
+    uri TEXT NOT NULL,
+    name TEXT NOT NULL,
+    mime_type TEXT,
+    content TEXT NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (chat_id) REFERENCES chats (id) ON DELETE CASCADE,
+    UNIQUE (chat_id, server, uri)
+);
@@ -0,0 +1,12 @@
+-- MCP tools a user chose to always allow, so calls to them run without
+-- asking. `tool` is the tool's own name on the server, not the prefixed one
+-- the model sees
+CREATE TABLE IF NOT EXISTS tool_approvals (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    server TEXT NOT NULL,
+    tool TEXT NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (user_id) REFERENCES users (id) ON DELETE CASCADE,
+    UNIQUE (user_id, server, tool)
+);
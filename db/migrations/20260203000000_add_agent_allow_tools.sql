@@ -0,0 +1,3 @@
+-- The tools an agent may call, as a JSON array of tool names where
+-- `server__*` stands for all of an MCP server's tools. NULL allows every tool
+ALTER TABLE agents ADD COLUMN allow_tools TEXT;
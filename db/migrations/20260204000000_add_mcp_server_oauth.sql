@@ -0,0 +1,3 @@
+-- OAuth tokens of remote MCP servers the user authorized, as JSON, see
+-- `mcp::oauth::OAuthTokens`
+ALTER TABLE mcp_servers ADD COLUMN oauth TEXT;
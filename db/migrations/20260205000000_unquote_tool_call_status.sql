@@ -0,0 +1,3 @@
+-- Statuses were saved JSON-encoded, as "Pending" with the quotes, so the
+-- 'Pending' checks on approval never matched them
+UPDATE tool_call_confirmations SET status = trim(status, '"');
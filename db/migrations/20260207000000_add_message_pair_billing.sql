@@ -0,0 +1,6 @@
+-- The user a response was generated for, with their key and against their
+-- quota. Members of a shared chat generate on their own account; pairs
+-- without one count against the chat's owner
+ALTER TABLE message_pairs ADD COLUMN billed_user_id INTEGER REFERENCES users(id) ON DELETE SET NULL;
+
+CREATE INDEX IF NOT EXISTS idx_message_pairs_billed_user_id ON message_pairs (billed_user_id);
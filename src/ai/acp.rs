@@ -0,0 +1,87 @@
+use serde_json::{json, Value};
+
+use super::stream::GenerationEvent;
+
+// Map a generation event to an ACP `session/update` notification. Events with no
+// ACP counterpart (usage, sources, end of turn) return None; the turn itself is
+// finished by the prompt response, not by a notification.
+pub fn session_update(session_id: &str, event: &GenerationEvent) -> Option<Value> {
+    let update = match event {
+        GenerationEvent::Text(text) => json!({
+            "sessionUpdate": "agent_message_chunk",
+            "content": { "type": "text", "text": text }
+        }),
+        GenerationEvent::Thinking(text) | GenerationEvent::Reasoning(text) => json!({
+            "sessionUpdate": "agent_thought_chunk",
+            "content": { "type": "text", "text": text }
+        }),
+        GenerationEvent::Image(url) => json!({
+            "sessionUpdate": "agent_message_chunk",
+            "content": { "type": "resource_link", "uri": url, "name": "image" }
+        }),
+        GenerationEvent::ToolCall(tool_call) => json!({
+            "sessionUpdate": "tool_call",
+            "toolCallId": tool_call.id,
+            "title": tool_call.function.name,
+            "kind": "other",
+            "status": "pending",
+            "rawInput": serde_json::from_str::<Value>(&tool_call.function.arguments)
+                .unwrap_or_else(|_| Value::String(tool_call.function.arguments.clone()))
+        }),
+        GenerationEvent::ToolCancelled(id) => json!({
+            "sessionUpdate": "tool_call_update",
+            "toolCallId": id,
+            "status": "failed"
+        }),
+        GenerationEvent::ToolCallConfirmation(confirmation) => json!({
+            "sessionUpdate": "plan",
+            "entries": [{
+                "content": format!("Waiting for approval to run {}", confirmation.tool_call.function.name),
+                "priority": "high",
+                "status": "pending"
+            }]
+        }),
+        GenerationEvent::ThinkingUpdate(_)
+        | GenerationEvent::ReasoningUpdate(_)
+        | GenerationEvent::ToolRunning(_)
+        | GenerationEvent::Usage(_)
+        | GenerationEvent::Sources(_)
+        | GenerationEvent::FinishReason(_)
+        | GenerationEvent::End(_) => return None,
+    };
+
+    Some(json!({
+        "jsonrpc": "2.0",
+        "method": "session/update",
+        "params": { "sessionId": session_id, "update": update }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::model::{FunctionCall, ToolCall};
+
+    #[test]
+    fn test_session_update_mapping() {
+        let chunk = session_update("7", &GenerationEvent::Text("hi".to_string())).unwrap();
+        assert_eq!(chunk["method"], "session/update");
+        assert_eq!(chunk["params"]["sessionId"], "7");
+        assert_eq!(chunk["params"]["update"]["sessionUpdate"], "agent_message_chunk");
+        assert_eq!(chunk["params"]["update"]["content"]["text"], "hi");
+
+        let tool_call = ToolCall {
+            id: "call_1".to_string(),
+            r#type: "function".to_string(),
+            function: FunctionCall {
+                name: "search".to_string(),
+                arguments: r#"{"q":"rust"}"#.to_string(),
+            },
+        };
+        let update = session_update("7", &GenerationEvent::ToolCall(tool_call)).unwrap();
+        assert_eq!(update["params"]["update"]["sessionUpdate"], "tool_call");
+        assert_eq!(update["params"]["update"]["rawInput"]["q"], "rust");
+
+        assert!(session_update("7", &GenerationEvent::End(String::new())).is_none());
+    }
+}
@@ -0,0 +1,115 @@
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use reqwest::header::{AUTHORIZATION, CONTENT_TYPE};
+use serde_json::{json, Value};
+
+use crate::data::model::{CustomTool, ToolInfo};
+use crate::encryption::Encryption;
+use crate::utils::outbound;
+
+pub fn tool_info(tool: &CustomTool) -> ToolInfo {
+    ToolInfo {
+        name: tool.name.clone(),
+        description: tool.description.clone(),
+        parameters: serde_json::from_str(&tool.parameters).ok(),
+    }
+}
+
+// Custom tool names share the model's tool namespace with MCP tools, which are
+// recognised by their `server__tool` form
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.contains("__")
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+// The tool with its stored auth header decrypted. A header that can't be is
+// left off, so the tool turns the call down rather than getting the sealed value
+pub fn opened(mut tool: CustomTool, encryption: &Encryption) -> CustomTool {
+    if let Some(stored) = tool.auth_header.take() {
+        match encryption.decrypt(&stored) {
+            Ok(auth_header) => tool.auth_header = Some(auth_header),
+            Err(e) => tracing::warn!("Can't decrypt the auth header of custom tool {}: {}", tool.name, e),
+        }
+    }
+    tool
+}
+
+// What `tool_executions.server` holds for these tools
+pub const CUSTOM_TOOL_SERVER: &str = "custom";
+
+#[derive(Debug, thiserror::Error)]
+pub enum CustomToolError {
+    #[error("invalid arguments: {0}")]
+    InvalidArguments(String),
+    #[error("{0}")]
+    Blocked(String),
+    #[error(transparent)]
+    Request(#[from] reqwest::Error),
+}
+
+// Shared by every tool call; a tool that hangs fails the call instead of
+// holding up the generation
+static CLIENT: LazyLock<reqwest::Client> = LazyLock::new(|| {
+    outbound::client_builder()
+        .connect_timeout(Duration::from_secs(10))
+        .timeout(Duration::from_secs(60))
+        .build()
+        .expect("can't build custom tool HTTP client")
+});
+
+// POST the model's arguments to the tool URL and return the response body
+pub async fn execute_custom_tool(tool: &CustomTool, arguments: &str) -> Result<String, CustomToolError> {
+    let body: Value = match arguments.trim() {
+        "" => json!({}),
+        arguments => serde_json::from_str(arguments).map_err(|e| CustomToolError::InvalidArguments(e.to_string()))?,
+    };
+    outbound::check_url(&tool.url).await.map_err(CustomToolError::Blocked)?;
+
+    let mut request = CLIENT
+        .post(&tool.url)
+        .header(CONTENT_TYPE, "application/json")
+        .json(&body);
+    if let Some(auth_header) = tool.auth_header.as_deref().filter(|h| !h.is_empty()) {
+        request = request.header(AUTHORIZATION, auth_header);
+    }
+
+    Ok(request.send().await?.error_for_status()?.text().await?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_custom_tool_names_cannot_shadow_mcp_tools() {
+        assert!(is_valid_name("weather_lookup"));
+        assert!(!is_valid_name("github__search"));
+        assert!(!is_valid_name("has space"));
+        assert!(!is_valid_name(""));
+    }
+
+    #[tokio::test]
+    async fn test_bad_arguments_and_private_urls_fail() {
+        let tool = CustomTool {
+            id: 1,
+            user_id: 1,
+            name: "lookup".to_string(),
+            description: String::new(),
+            parameters: "{}".to_string(),
+            url: "http://127.0.0.1:9/lookup".to_string(),
+            auth_header: None,
+        };
+        assert!(matches!(
+            execute_custom_tool(&tool, r#"{"city": "#).await,
+            Err(CustomToolError::InvalidArguments(_))
+        ));
+        assert!(matches!(
+            execute_custom_tool(&tool, r#"{"city": "Oslo"}"#).await,
+            Err(CustomToolError::Blocked(_))
+        ));
+    }
+}
@@ -0,0 +1,133 @@
+use serde::{Deserialize, Serialize};
+
+use crate::data::repository::ChatRepository;
+
+const DEFAULT_URL: &str = "https://api.siliconflow.cn/v1/embeddings";
+// Long answers are cut down, embedding models take a few thousand tokens
+const MAX_CHUNK_CHARS: usize = 4000;
+const BATCH_SIZE: i64 = 16;
+
+// Semantic search is on with `EMBEDDING_API_KEY` and `EMBEDDING_MODEL`;
+// `EMBEDDING_URL` points at another OpenAI compatible endpoint
+#[derive(Clone, Debug)]
+pub struct EmbeddingConfig {
+    api_key: String,
+    model: String,
+    url: String,
+}
+
+impl EmbeddingConfig {
+    pub fn from_env() -> Option<Self> {
+        let api_key = dotenv::var("EMBEDDING_API_KEY").ok().filter(|key| !key.trim().is_empty())?;
+        let model = dotenv::var("EMBEDDING_MODEL").ok().filter(|model| !model.trim().is_empty())?;
+        Some(EmbeddingConfig {
+            api_key,
+            model,
+            url: dotenv::var("EMBEDDING_URL").unwrap_or_else(|_| DEFAULT_URL.to_string()),
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct EmbeddingRequest<'a> {
+    model: &'a str,
+    input: &'a [String],
+}
+
+#[derive(Deserialize)]
+struct EmbeddingResponse {
+    data: Vec<Embedding>,
+}
+
+#[derive(Deserialize)]
+struct Embedding {
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+// One embedding per input, in the same order
+pub async fn embed(config: &EmbeddingConfig, inputs: &[String]) -> Result<Vec<Vec<f32>>, reqwest::Error> {
+    let client = reqwest::Client::new();
+    let mut res: EmbeddingResponse = client
+        .post(&config.url)
+        .bearer_auth(&config.api_key)
+        .json(&EmbeddingRequest {
+            model: &config.model,
+            input: inputs,
+        })
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    res.data.sort_by_key(|embedding| embedding.index);
+    Ok(res.data.into_iter().map(|embedding| embedding.embedding).collect())
+}
+
+fn chunk_content(human_message: &str, ai_message: &str) -> String {
+    let content = format!("{}\n\n{}", human_message.trim(), ai_message.trim());
+    match content.char_indices().nth(MAX_CHUNK_CHARS) {
+        Some((end, _)) => content[..end].to_string(),
+        None => content,
+    }
+}
+
+// Embeds answered pairs as they come in. A failed batch is tried again on
+// the next tick
+pub async fn run_indexer(repo: ChatRepository, config: EmbeddingConfig) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(30));
+    loop {
+        interval.tick().await;
+
+        loop {
+            let pairs = match repo.unindexed_pairs(BATCH_SIZE).await {
+                Ok(pairs) if !pairs.is_empty() => pairs,
+                Ok(_) => break,
+                Err(e) => {
+                    tracing::error!("Failed to load pairs to embed: {}", e);
+                    break;
+                }
+            };
+            let contents: Vec<String> = pairs
+                .iter()
+                .map(|pair| chunk_content(&pair.human_message, &pair.ai_message))
+                .collect();
+            let embeddings = match embed(&config, &contents).await {
+                Ok(embeddings) if embeddings.len() == contents.len() => embeddings,
+                Ok(_) => {
+                    tracing::warn!("The embedding endpoint returned the wrong number of embeddings");
+                    break;
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to embed messages: {}", e);
+                    break;
+                }
+            };
+
+            let mut failed = false;
+            for ((pair, content), embedding) in pairs.iter().zip(&contents).zip(&embeddings) {
+                if let Err(e) = repo.save_chunk(pair.pair_id, pair.chat_id, content, embedding).await {
+                    tracing::error!("Failed to save the embedding of pair {}: {}", pair.pair_id, e);
+                    failed = true;
+                }
+            }
+            // Otherwise the same pairs would come right back
+            if failed {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_content() {
+        assert_eq!(chunk_content(" Hi ", "Hello!\n"), "Hi\n\nHello!");
+        let long = "é".repeat(MAX_CHUNK_CHARS + 10);
+        assert_eq!(chunk_content(&long, "").chars().count(), MAX_CHUNK_CHARS);
+    }
+}
@@ -0,0 +1,260 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::broadcast;
+
+use super::stream::GenerationEvent;
+
+const CHANNEL_CAPACITY: usize = 256;
+
+// Fans generation events for a chat out to any number of extra consumers
+// (ACP session feeds) next to the primary web SSE/WebSocket response
+#[derive(Clone, Default)]
+pub struct GenerationHub {
+    channels: Arc<Mutex<HashMap<i64, broadcast::Sender<GenerationEvent>>>>,
+    generating: Arc<Mutex<HashSet<i64>>>,
+}
+
+impl GenerationHub {
+    // Marks the chat as generating until `release`
+    pub fn begin(&self, chat_id: i64) {
+        self.generating.lock().unwrap().insert(chat_id);
+    }
+
+    pub fn is_generating(&self, chat_id: i64) -> bool {
+        self.generating.lock().unwrap().contains(&chat_id)
+    }
+
+    pub fn subscribe(&self, chat_id: i64) -> Subscription {
+        let mut channels = self.channels.lock().unwrap();
+        let receiver = channels
+            .entry(chat_id)
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+            .subscribe();
+        Subscription {
+            hub: self.clone(),
+            chat_id,
+            receiver: Some(receiver),
+        }
+    }
+
+    pub fn publish(&self, chat_id: i64, event: &GenerationEvent) {
+        let channels = self.channels.lock().unwrap();
+        if let Some(sender) = channels.get(&chat_id) {
+            // No receivers just means nobody is listening right now
+            let _ = sender.send(event.clone());
+        }
+    }
+
+    // Called when a generation ends. Drops the channel once nobody is
+    // subscribed, so idle chats don't keep one around
+    pub fn release(&self, chat_id: i64) {
+        self.generating.lock().unwrap().remove(&chat_id);
+        let mut channels = self.channels.lock().unwrap();
+        if channels
+            .get(&chat_id)
+            .is_some_and(|sender| sender.receiver_count() == 0)
+        {
+            channels.remove(&chat_id);
+        }
+    }
+}
+
+// A chat's generation events until dropped. The last subscription to go
+// drops the chat's channel, unless a generation is still publishing to it
+pub struct Subscription {
+    hub: GenerationHub,
+    chat_id: i64,
+    receiver: Option<broadcast::Receiver<GenerationEvent>>,
+}
+
+impl Subscription {
+    pub async fn recv(&mut self) -> Result<GenerationEvent, broadcast::error::RecvError> {
+        match self.receiver.as_mut() {
+            Some(receiver) => receiver.recv().await,
+            None => Err(broadcast::error::RecvError::Closed),
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        // The receiver goes under the lock, so concurrent drops can't each
+        // see the other still subscribed
+        let mut channels = self.hub.channels.lock().unwrap();
+        self.receiver.take();
+        let unused = channels
+            .get(&self.chat_id)
+            .is_some_and(|sender| sender.receiver_count() == 0);
+        if unused && !self.hub.is_generating(self.chat_id) {
+            channels.remove(&self.chat_id);
+        }
+    }
+}
+
+// What members of a shared chat see of each other, besides the generation
+// stream itself
+#[derive(Clone, Debug)]
+pub enum RoomEvent {
+    // Emails of everyone currently viewing the chat
+    Presence(Vec<String>),
+    Typing { user_id: i64, email: String },
+    // A message was sent; `html` renders it with a placeholder for the answer
+    Message { author_id: i64, html: String },
+}
+
+struct Room {
+    sender: broadcast::Sender<RoomEvent>,
+    // Open connections per user, who can have the chat open in several tabs
+    present: HashMap<i64, (String, usize)>,
+}
+
+#[derive(Clone, Default)]
+pub struct ChatRooms {
+    rooms: Arc<Mutex<HashMap<i64, Room>>>,
+}
+
+impl ChatRooms {
+    // The user stays present until the returned guard is dropped
+    pub fn join(
+        &self,
+        chat_id: i64,
+        user_id: i64,
+        email: &str,
+    ) -> (broadcast::Receiver<RoomEvent>, RoomGuard) {
+        let mut rooms = self.rooms.lock().unwrap();
+        let room = rooms.entry(chat_id).or_insert_with(|| Room {
+            sender: broadcast::channel(CHANNEL_CAPACITY).0,
+            present: HashMap::new(),
+        });
+        let receiver = room.sender.subscribe();
+        room.present
+            .entry(user_id)
+            .or_insert_with(|| (email.to_string(), 0))
+            .1 += 1;
+        Self::announce(room);
+
+        let guard = RoomGuard {
+            rooms: self.clone(),
+            chat_id,
+            user_id,
+        };
+        (receiver, guard)
+    }
+
+    pub fn publish(&self, chat_id: i64, event: RoomEvent) {
+        let rooms = self.rooms.lock().unwrap();
+        if let Some(room) = rooms.get(&chat_id) {
+            let _ = room.sender.send(event);
+        }
+    }
+
+    fn leave(&self, chat_id: i64, user_id: i64) {
+        let mut rooms = self.rooms.lock().unwrap();
+        let Some(room) = rooms.get_mut(&chat_id) else {
+            return;
+        };
+        if let Some((_, connections)) = room.present.get_mut(&user_id) {
+            *connections -= 1;
+            if *connections == 0 {
+                room.present.remove(&user_id);
+            }
+        }
+        if room.present.is_empty() {
+            rooms.remove(&chat_id);
+        } else {
+            Self::announce(room);
+        }
+    }
+
+    fn announce(room: &Room) {
+        let mut emails: Vec<String> = room.present.values().map(|(email, _)| email.clone()).collect();
+        emails.sort();
+        let _ = room.sender.send(RoomEvent::Presence(emails));
+    }
+}
+
+pub struct RoomGuard {
+    rooms: ChatRooms,
+    chat_id: i64,
+    user_id: i64,
+}
+
+impl Drop for RoomGuard {
+    fn drop(&mut self) {
+        self.rooms.leave(self.chat_id, self.user_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_publish_reaches_every_subscriber() {
+        let hub = GenerationHub::default();
+        let mut first = hub.subscribe(1);
+        let mut second = hub.subscribe(1);
+        let mut other_chat = hub.subscribe(2);
+
+        hub.publish(1, &GenerationEvent::Text("hello".to_string()));
+
+        for rx in [&mut first, &mut second] {
+            match rx.recv().await.unwrap() {
+                GenerationEvent::Text(text) => assert_eq!(text, "hello"),
+                _ => panic!("unexpected event"),
+            }
+        }
+        assert!(other_chat.receiver.as_mut().unwrap().try_recv().is_err());
+
+        drop(first);
+        drop(second);
+        hub.release(1);
+        assert!(hub.channels.lock().unwrap().get(&1).is_none());
+    }
+
+    #[test]
+    fn test_last_subscription_drops_channel() {
+        let hub = GenerationHub::default();
+        let first = hub.subscribe(1);
+        let second = hub.subscribe(1);
+
+        drop(first);
+        assert!(hub.channels.lock().unwrap().contains_key(&1));
+        drop(second);
+        assert!(hub.channels.lock().unwrap().is_empty());
+
+        // A running generation keeps its channel until `release`
+        hub.begin(2);
+        drop(hub.subscribe(2));
+        assert!(hub.channels.lock().unwrap().contains_key(&2));
+        hub.release(2);
+        assert!(hub.channels.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_room_presence() {
+        let rooms = ChatRooms::default();
+        let (mut alice, alice_guard) = rooms.join(1, 1, "alice@example.com");
+        let (_bob, bob_guard) = rooms.join(1, 2, "bob@example.com");
+        let (_second_tab, second_tab_guard) = rooms.join(1, 2, "bob@example.com");
+
+        let mut latest_presence = || {
+            let mut presence = Vec::new();
+            while let Ok(RoomEvent::Presence(emails)) = alice.try_recv() {
+                presence = emails;
+            }
+            presence
+        };
+        assert_eq!(latest_presence(), vec!["alice@example.com", "bob@example.com"]);
+
+        // Bob is still there while one of his tabs is open
+        drop(bob_guard);
+        assert_eq!(latest_presence(), vec!["alice@example.com", "bob@example.com"]);
+        drop(second_tab_guard);
+        assert_eq!(latest_presence(), vec!["alice@example.com"]);
+
+        drop(alice_guard);
+        assert!(rooms.rooms.lock().unwrap().is_empty());
+    }
+}
@@ -1 +1,6 @@
+pub mod acp;
+pub mod custom_tools;
+pub mod embeddings;
+pub mod fanout;
+pub mod pricing;
 pub mod stream;
@@ -0,0 +1,20 @@
+// Approximate SiliconFlow list prices in USD per million prompt and
+// completion tokens. Models missing here are reported as unpriced
+const PRICES: &[(&str, f64, f64)] = &[
+    ("Qwen/Qwen2.5-7B-Instruct", 0.0, 0.0),
+    ("Qwen/Qwen2.5-14B-Instruct", 0.10, 0.10),
+    ("Qwen/Qwen2.5-32B-Instruct", 0.18, 0.18),
+    ("Qwen/Qwen2.5-72B-Instruct", 0.59, 0.59),
+    ("Qwen/QwQ-32B", 0.15, 0.58),
+    ("deepseek-ai/DeepSeek-V3", 0.27, 1.10),
+    ("deepseek-ai/DeepSeek-R1", 0.55, 2.19),
+];
+
+pub fn cost(model: &str, prompt_tokens: i64, completion_tokens: i64) -> Option<f64> {
+    PRICES
+        .iter()
+        .find(|(name, _, _)| name.eq_ignore_ascii_case(model))
+        .map(|(_, prompt, completion)| {
+            (prompt_tokens as f64 * prompt + completion_tokens as f64 * completion) / 1_000_000.0
+        })
+}
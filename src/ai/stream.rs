@@ -3,12 +3,21 @@ use reqwest::header::{HeaderValue, AUTHORIZATION, CONTENT_TYPE};
 use reqwest_eventsource::{Event as ReqwestEvent, EventSource as ReqwestEventSource};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
 use tokio::select;
 use tokio::sync::mpsc;
 use tokio_stream::StreamExt;
 
-use crate::data::model::{ChatMessagePair, ToolCallConfirmation};
-use crate::mcp::tools::{execute_mcp_tool_streaming, get_available_tools, parse_tool_call_from_ai};
+use super::custom_tools;
+use crate::data::model::{ChatMessagePair, CustomTool, NewToolExecution};
+use crate::data::repository::ChatRepository;
+use crate::encryption::Encryption;
+use crate::mcp::users::{self, UserMcp};
+use crate::mcp::{cancel, sampling, McpManagerError};
+use crate::mcp::tools::{
+    argument_errors, execute_mcp_tool, execute_mcp_tool_streaming, format_tool_result_for_openai,
+    get_available_tools, parse_tool_call_from_ai, McpToolCall,
+};
 
 // Define a struct to represent a model.
 #[derive(Serialize, Deserialize, Debug)]
@@ -39,48 +48,282 @@ pub async fn list_engines(api_key: &str) -> Result<Vec<Model>, reqwest::Error> {
     Ok(res.data)
 }
 
+// The answer of a completion that isn't streamed
+pub struct Completion {
+    pub text: String,
+    pub finish_reason: Option<String>,
+}
+
+// A single completion without tools, for MCP servers' sampling requests
+pub async fn complete(
+    api_key: &str,
+    model: &str,
+    messages: Vec<Value>,
+    max_tokens: i64,
+    temperature: Option<f64>,
+) -> Result<Completion, reqwest::Error> {
+    let mut body = json!({ "model": model, "messages": messages, "max_tokens": max_tokens });
+    if let Some(temperature) = temperature {
+        body["temperature"] = json!(temperature);
+    }
+    let response: Value = reqwest::Client::new()
+        .post("https://api.siliconflow.cn/v1/chat/completions")
+        .bearer_auth(api_key)
+        .json(&body)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    let choice = &response["choices"][0];
+    Ok(Completion {
+        text: choice["message"]["content"].as_str().unwrap_or_default().to_string(),
+        finish_reason: choice["finish_reason"].as_str().map(String::from),
+    })
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct Message {
     role: String,
     content: String,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
 pub enum GenerationEvent {
     Text(String),
     Thinking(String),
     ThinkingUpdate(String),
     ToolCall(crate::data::model::ToolCall),
     ToolCallConfirmation(crate::data::model::ToolCallConfirmation),
+    // The execution id of the tool call just shown, while it runs; it's
+    // cancelled by that id
+    ToolRunning(String),
+    // The id of a tool call cancelled while it ran
+    ToolCancelled(String),
     Image(String),
     Reasoning(String),
     ReasoningUpdate(String),
     Usage(crate::data::model::UsageInfo),
     Sources(Vec<crate::data::model::Source>),
+    FinishReason(String),
     End(String),
 }
 
+// Model and per-chat request parameters for a completion
+#[derive(Debug, Clone, Default)]
+pub struct GenerationOptions {
+    pub model: String,
+    pub system_prompt: Option<String>,
+    // Appended to the system prompt, e.g. the chat's attached resources
+    pub context: Option<String>,
+    pub disable_tools: bool,
+    // The agent's allowed tools, `None` for every tool
+    pub allow_tools: Option<Vec<String>>,
+    pub stop: Vec<String>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub max_tokens: Option<i64>,
+}
+
+impl GenerationOptions {
+    // Whether a tool may be offered and called: listed by name, or as
+    // `server__*` for all of an MCP server's tools
+    pub fn allows_tool(&self, name: &str) -> bool {
+        let Some(allowed) = &self.allow_tools else {
+            return true;
+        };
+        allowed.iter().any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => pattern.ends_with("__*") && name.starts_with(prefix),
+            None => pattern == name,
+        })
+    }
+}
+
+// The API accepts at most four stop sequences
+const MAX_STOP_SEQUENCES: usize = 4;
+
+// Stop sequences entered one per line; `None` if there are too many
+pub fn parse_stop_sequences(text: &str) -> Option<Vec<String>> {
+    let sequences: Vec<String> = text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(str::to_string)
+        .collect();
+    (sequences.len() <= MAX_STOP_SEQUENCES).then_some(sequences)
+}
+
+// Where MCP tool calls wait for the user's confirmation and tool runs are logged
+pub struct ConfirmationTarget<'a> {
+    pub repo: &'a ChatRepository,
+    // Opens the secrets of the user's MCP servers
+    pub encryption: &'a Encryption,
+    pub user_id: i64,
+    pub chat_id: i64,
+    pub message_pair_id: i64,
+}
+
+// The server of an MCP tool the user always allows, if they do
+async fn always_allowed_server(target: &ConfirmationTarget<'_>, mcp: &UserMcp, name: &str) -> Option<String> {
+    let tool = mcp.get_tool(name).await?;
+    match target.repo.is_tool_approved(target.user_id, &tool.server_name, &tool.tool_info.name).await {
+        Ok(approved) => approved.then_some(tool.server_name),
+        Err(e) => {
+            tracing::error!("Failed to check the approval of {}: {}", name, e);
+            None
+        }
+    }
+}
+
+// Runs an always allowed MCP tool and logs it like a confirmed one; returns
+// the event the result is shown with
+async fn run_allowed_tool(
+    target: &ConfirmationTarget<'_>,
+    mcp: &UserMcp,
+    server: &str,
+    tool_call: &McpToolCall,
+    execution_id: &str,
+) -> GenerationEvent {
+    let started = std::time::Instant::now();
+    let client = mcp.client_name(server).await;
+    let call = sampling::on_behalf_of(&client, target.user_id, target.chat_id, execute_mcp_tool(mcp, tool_call));
+    let outcome = cancel::cancellable(target.chat_id, execution_id, call)
+        .await
+        .unwrap_or_else(|| Err(McpManagerError::Cancelled(tool_call.name.clone())));
+    let output = match &outcome {
+        Ok(result) => format_tool_result_for_openai(result).await.map(|result| result.output).unwrap_or_default(),
+        Err(_) => String::new(),
+    };
+
+    let arguments = tool_call.arguments.to_string();
+    let execution = NewToolExecution {
+        user_id: target.user_id,
+        chat_id: target.chat_id,
+        server,
+        tool: &tool_call.name,
+        arguments: &arguments,
+        result_bytes: output.len() as i64,
+        duration_ms: started.elapsed().as_millis() as i64,
+        error: match &outcome {
+            Ok(result) if result.is_error => Some("The tool reported an error".to_string()),
+            Ok(_) => None,
+            Err(e) => Some(e.to_string()),
+        },
+    };
+    if let Err(e) = target.repo.record_tool_execution(&execution).await {
+        tracing::error!("Failed to log tool execution: {}", e);
+    }
+
+    match outcome {
+        Ok(_) => GenerationEvent::Text(format!("Tool Result: {}", output)),
+        Err(McpManagerError::Cancelled(_)) => GenerationEvent::ToolCancelled(tool_call.id.clone()),
+        Err(e) => GenerationEvent::Text(format!("Tool Execution Error: {}", e)),
+    }
+}
+
+// How many times a completion is continued to let the model correct the
+// arguments of its tool calls
+const MAX_CORRECTIONS: usize = 3;
+
+// Tool calls as the model streams them, by their index in the response
+#[derive(Default)]
+struct PendingToolCalls(BTreeMap<usize, crate::data::model::ToolCall>);
+
+impl PendingToolCalls {
+    // Folds a `tool_calls` delta into the call it continues
+    fn push(&mut self, delta: &Value) {
+        let index = delta.get("index").and_then(|i| i.as_i64()).unwrap_or(0) as usize;
+        let tool_call = self.0.entry(index).or_insert_with(|| crate::data::model::ToolCall {
+            id: format!("call_{}", index),
+            r#type: "function".to_string(),
+            function: crate::data::model::FunctionCall {
+                name: String::new(),
+                arguments: String::new(),
+            },
+        });
+
+        if let Some(id) = delta.get("id").and_then(|id| id.as_str()) {
+            tool_call.id = id.to_string();
+        }
+        if let Some(t_type) = delta.get("type").and_then(|t| t.as_str()) {
+            tool_call.r#type = t_type.to_string();
+        }
+        if let Some(function) = delta.get("function").and_then(|f| f.as_object()) {
+            if let Some(name) = function.get("name").and_then(|n| n.as_str()) {
+                tool_call.function.name = name.to_string();
+            }
+            if let Some(args) = function.get("arguments").and_then(|a| a.as_str()) {
+                tool_call.function.arguments.push_str(args);
+            }
+        }
+    }
+
+    // The named calls so far, in order, once the model is done with them
+    fn take(&mut self) -> Vec<crate::data::model::ToolCall> {
+        std::mem::take(&mut self.0)
+            .into_values()
+            .filter(|tool_call| !tool_call.function.name.is_empty())
+            .collect()
+    }
+}
+
+// The assistant turn with the calls whose arguments didn't fit, and the
+// answer to each saying what's wrong, for the model to call them again
+fn correction_messages(text: &str, corrections: &[(crate::data::model::ToolCall, String)]) -> Vec<Value> {
+    let tool_calls: Vec<&crate::data::model::ToolCall> = corrections.iter().map(|(tool_call, _)| tool_call).collect();
+    std::iter::once(json!({ "role": "assistant", "content": text, "tool_calls": tool_calls }))
+        .chain(corrections.iter().map(|(tool_call, error)| {
+            json!({ "role": "tool", "tool_call_id": tool_call.id, "content": error })
+        }))
+        .collect()
+}
+
+// Starts a streamed chat completion
+fn open_completion(
+    client: &reqwest::Client,
+    url: &str,
+    api_key: &str,
+    body: &Value,
+) -> Result<ReqwestEventSource, Box<dyn std::error::Error>> {
+    let request = client
+        .post(url)
+        .header(
+            AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {}", api_key))?,
+        )
+        .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
+        .body(body.to_string());
+    Ok(ReqwestEventSource::new(request)?)
+}
+
+const DEFAULT_SYSTEM_PROMPT: &str = "You are a helpful assistant. Use the available tools when they are relevant to the user's request. Always call tools to get the most accurate and up-to-date information.";
+
 pub async fn generate_sse_stream(
     api_key: &str,
-    model: &str,
+    options: &GenerationOptions,
     messages: Vec<ChatMessagePair>,
+    custom_tools: Vec<CustomTool>,
     sender: mpsc::Sender<Result<GenerationEvent, Error>>,
-    chat_id: Option<i64>,
-    message_pair_id: Option<i64>,
+    confirmations: Option<ConfirmationTarget<'_>>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     // Monitor if the sender channel is closed (client disconnected)
     let mut sender_closed = false;
 
     // Track tool calls being built across streaming chunks
-    let mut current_tool_calls: std::collections::HashMap<String, crate::data::model::ToolCall> = std::collections::HashMap::new();
+    let mut pending_tool_calls = PendingToolCalls::default();
     // Your OpenAI API key
 
     // The API endpoint for chat completions
     let url = "https://api.siliconflow.cn/v1/chat/completions";
 
+    let system_prompt = options.system_prompt.as_deref().unwrap_or(DEFAULT_SYSTEM_PROMPT);
     let system_message = json!({
         "role": "system",
-        "content": "You are a helpful assistant. Use the available tools when they are relevant to the user's request. Always call tools to get the most accurate and up-to-date information."
+        "content": match &options.context {
+            Some(context) => format!("{}\n\n{}", system_prompt, context),
+            None => system_prompt.to_string(),
+        }
     });
     let system_message_iter = std::iter::once(Some(system_message));
 
@@ -102,38 +345,71 @@ pub async fn generate_sse_stream(
     });
 
     // Chain the system message with the user and AI messages, filter out the Nones, and collect into a Vec<Value>
-    let body_messages = system_message_iter
+    let mut body_messages = system_message_iter
         .chain(messages_iter)
         .flatten() // This removes any None values
         .collect::<Vec<Value>>();
 
-    // Get available MCP tools and add them to the request
-    let mcp_tools = match get_available_tools().await {
-        Ok(tools) => tools,
-        Err(e) => {
-            eprintln!("Failed to get MCP tools: {}", e);
-            vec![]
+    // An answered last pair means we're continuing a cut-off response: the
+    // model picks up from the partial assistant message instead of answering it
+    if messages.last().is_some_and(|msg| msg.ai_message.is_some()) {
+        if let Some(last) = body_messages.last_mut() {
+            last["prefix"] = json!(true);
+        }
+    }
+
+    // Get available MCP tools and add them to the request: the user's own
+    // servers and the shared ones
+    let mcp = match &confirmations {
+        Some(target) => users::for_user(target.repo, target.encryption, target.user_id).await,
+        None => UserMcp::global_only(),
+    };
+    let mut mcp_tools = if options.disable_tools {
+        vec![]
+    } else {
+        match get_available_tools(&mcp).await {
+            Ok(tools) => tools,
+            Err(e) => {
+                tracing::error!("Failed to get MCP tools: {}", e);
+                vec![]
+            }
         }
     };
+    // User-defined HTTP tools are offered alongside the MCP ones
+    mcp_tools.extend(custom_tools.iter().map(custom_tools::tool_info));
+    mcp_tools.retain(|tool| options.allows_tool(&tool.name));
+    // What the model's arguments are checked against
+    let tool_parameters: HashMap<String, Value> = mcp_tools
+        .iter()
+        .filter_map(|tool| Some((tool.name.clone(), tool.parameters.clone()?)))
+        .collect();
 
     // Prepare the request body with tools
     let mut body = json!({
-        "model": model,
+        "model": options.model,
         "messages": body_messages,
         "stream": true
     });
+    if !options.stop.is_empty() {
+        body["stop"] = json!(options.stop);
+    }
+    if let Some(temperature) = options.temperature {
+        body["temperature"] = json!(temperature);
+    }
+    if let Some(top_p) = options.top_p {
+        body["top_p"] = json!(top_p);
+    }
+    if let Some(max_tokens) = options.max_tokens {
+        body["max_tokens"] = json!(max_tokens);
+    }
 
     // Add tools to the request if any are available
     if !mcp_tools.is_empty() {
-        println!("Found {} MCP tools to send to AI:", mcp_tools.len());
-        for tool in &mcp_tools {
-            println!("Tool: {} - {}", tool.name, tool.description);
-        }
-
+        tracing::debug!("Offering {} tools to the model", mcp_tools.len());
         let openai_tools: Vec<Value> = mcp_tools
             .into_iter()
             .map(|tool| {
-                let tool_json = json!({
+                json!({
                     "type": "function",
                     "function": {
                         "name": tool.name,
@@ -144,70 +420,43 @@ pub async fn generate_sse_stream(
                             "required": []
                         }))
                     }
-                });
-                println!("Formatted tool for OpenAI: {}", serde_json::to_string_pretty(&tool_json).unwrap_or_default());
-                tool_json
+                })
             })
             .collect();
         body["tools"] = serde_json::to_value(openai_tools).unwrap_or(Value::Array(vec![]));
         body["tool_choice"] = json!("auto");
-    } else {
-        println!("No MCP tools available for AI request");
     }
 
-    println!("body: {}", body);
-
     // Create a client
     let client = reqwest::Client::new();
 
-    // Create a request
-    let request = client
-        .post(url)
-        .header(
-            AUTHORIZATION,
-            HeaderValue::from_str(&format!("Bearer {}", api_key))?,
-        )
-        .header(CONTENT_TYPE, HeaderValue::from_static("application/json"))
-        .body(body.to_string());
-
     // Start streaming
-    let mut stream = ReqwestEventSource::new(request)?;
+    let mut stream = open_completion(&client, url, api_key, &body)?;
+    // The assistant text of the current completion, and the calls in it with
+    // arguments that didn't fit, answered when it's done
+    let mut turn_text = String::new();
+    let mut corrections: Vec<(crate::data::model::ToolCall, String)> = Vec::new();
+    let mut corrected = 0;
 
     // Handle streaming events
     while let Some(event) = stream.next().await {
         // Check if sender is closed (client disconnected)
         if sender.is_closed() && !sender_closed {
-            println!("Client disconnected, closing reqwest stream...");
+            tracing::debug!("Client disconnected, closing the model stream");
             stream.close();
             sender_closed = true;
             break;
         }
 
         match event {
-            Ok(ReqwestEvent::Open) => println!("Connection Open!"),
+            Ok(ReqwestEvent::Open) => {}
             Ok(ReqwestEvent::Message(message)) => {
-                if message.data.trim() == "[DONE]" {
-                    println!("Stream completed.");
-                    stream.close();
-                    if sender
-                        .send(Ok(GenerationEvent::End(
-                            r#"<div id="sse-listener" hx-swap-oob="true"></div>"#.to_string(),
-                        )))
-                        .await
-                        .is_err()
-                    {
-                        break; // Receiver has dropped, stop sending.
-                    }
-                    break;
-                } else {
+                let done = message.data.trim() == "[DONE]";
+                let mut finish_reason = None;
+                if !done {
                     let m: Value = serde_json::from_str(&message.data).unwrap();
                     let delta = &m["choices"][0]["delta"];
 
-                    // Debug: Print the delta to see what AI is responding
-                    if !delta.is_null() {
-                        println!("AI delta: {}", serde_json::to_string_pretty(delta).unwrap_or_default());
-                    }
-
                     // Handle thinking (for models like o1)
                     if let Some(thinking) = delta["thinking"].as_str() {
                         if sender
@@ -215,7 +464,7 @@ pub async fn generate_sse_stream(
                             .await
                             .is_err()
                         {
-                            println!("Client disconnected during thinking, closing stream...");
+                            tracing::debug!("Client disconnected during thinking, closing stream");
                             stream.close();
                             break;
                         }
@@ -228,148 +477,41 @@ pub async fn generate_sse_stream(
                             .await
                             .is_err()
                         {
-                            println!("Client disconnected during reasoning, closing stream...");
+                            tracing::debug!("Client disconnected during reasoning, closing stream");
                             stream.close();
                             break;
                         }
                     }
 
-                    // Handle tool calls
+                    // Handle tool calls, which stream in pieces
                     if let Some(tool_calls) = delta["tool_calls"].as_array() {
-                        println!("Received {} tool calls from AI", tool_calls.len());
                         for tool_call_delta in tool_calls {
-                            println!("Tool call delta: {}", serde_json::to_string_pretty(tool_call_delta).unwrap_or_default());
-
-                            // Extract the tool call index to handle multi-part tool calls
-                            let index = tool_call_delta.get("index").and_then(|i| i.as_i64()).unwrap_or(0) as usize;
-                            let tool_key = format!("tool_{}", index);
-
-                            // Get or create tool call entry
-                            let tool_call = current_tool_calls.entry(tool_key.clone()).or_insert_with(|| {
-                                crate::data::model::ToolCall {
-                                    id: tool_call_delta.get("id").and_then(|id| id.as_str()).map(|s| s.to_string()).unwrap_or_else(|| format!("call_{}", index)),
-                                    r#type: tool_call_delta.get("type").and_then(|t| t.as_str()).unwrap_or("function").to_string(),
-                                    function: crate::data::model::FunctionCall {
-                                        name: String::new(),
-                                        arguments: String::new(),
-                                    },
-                                }
-                            });
-
-                            // Update tool call fields if present in delta
-                            if let Some(id) = tool_call_delta.get("id").and_then(|id| id.as_str()) {
-                                tool_call.id = id.to_string();
-                            }
-                            if let Some(t_type) = tool_call_delta.get("type").and_then(|t| t.as_str()) {
-                                tool_call.r#type = t_type.to_string();
-                            }
-                            if let Some(function_delta) = tool_call_delta.get("function") {
-                                if let Some(function_obj) = function_delta.as_object() {
-                                    if let Some(name) = function_obj.get("name").and_then(|n| n.as_str()) {
-                                        tool_call.function.name = name.to_string();
-                                    }
-                                    if let Some(args) = function_obj.get("arguments").and_then(|a| a.as_str()) {
-                                        tool_call.function.arguments.push_str(args);
-                                    }
-                                }
-                            }
-
-                            println!("Current tool call state for {}: {}", tool_key, serde_json::to_string(&tool_call).unwrap_or_default());
-
-                            // Only process complete tool calls (those with both name and arguments)
-                            if !tool_call.function.name.is_empty() && !tool_call.function.arguments.is_empty() {
-                                println!("Processing complete tool call: {}", tool_call.function.name);
-
-                                // Check if this is an MCP tool
-                                let parsed_mcp = parse_tool_call_from_ai(&tool_call);
-                                let is_mcp = parsed_mcp.is_some();
-                                println!("Tool call '{}' is MCP: {}", tool_call.function.name, is_mcp);
-                                if let Some(mcp_tool) = parsed_mcp {
-                                    println!("Parsed MCP tool: {} with args: {}", mcp_tool.name, serde_json::to_string(&mcp_tool.arguments).unwrap_or_default());
-                                } else {
-                                    println!("Failed to parse as MCP tool, arguments: {}", tool_call.function.arguments);
-                                }
-
-                                if is_mcp {
-                                    // Create tool call confirmation for MCP tools
-                                    if let (Some(chat_id_val), Some(message_pair_id_val)) = (chat_id, message_pair_id) {
-                                        let confirmation = crate::data::model::ToolCallConfirmation {
-                                            id: tool_call.id.clone(),
-                                            chat_id: chat_id_val,
-                                            message_pair_id: message_pair_id_val,
-                                            tool_call: tool_call.clone(),
-                                            status: crate::data::model::ToolCallStatus::Pending,
-                                            created_at: chrono::Utc::now(),
-                                            user_response: None,
-                                            result: None,
-                                        };
-
-                                        println!("Creating tool call confirmation for: {}", tool_call.function.name);
-
-                                        // Save confirmation to database
-                                        if let Err(e) = save_tool_call_confirmation(&confirmation).await {
-                                            println!("Error saving tool call confirmation: {}", e);
-                                            // Continue anyway and send the confirmation event
-                                        }
-
-                                        // Send confirmation request to UI
-                                        if sender
-                                            .send(Ok(GenerationEvent::ToolCallConfirmation(confirmation)))
-                                            .await
-                                            .is_err()
-                                        {
-                                            println!("Client disconnected during tool call confirmation, closing stream...");
-                                            stream.close();
-                                            break;
-                                        }
-                                    } else {
-                                        // Fallback: Execute directly if no chat/message IDs
-                                        if let Some(mcp_tool_call) = parse_tool_call_from_ai(&tool_call) {
-                                            if let Err(e) = execute_mcp_tool_streaming(&mcp_tool_call, sender.clone()).await {
-                                                println!("Error executing MCP tool: {}", e);
-                                                let error_text = format!("Tool execution error: {}", e);
-                                                if sender
-                                                    .send(Ok(GenerationEvent::Text(error_text)))
-                                                    .await
-                                                    .is_err()
-                                                {
-                                                    println!("Client disconnected during tool error, closing stream...");
-                                                    stream.close();
-                                                    break;
-                                                }
-                                            }
-                                        }
-                                    }
-                                } else {
-                                    // Regular OpenAI tool call - just forward it
-                                    println!("Forwarding regular tool call: {}", tool_call.function.name);
-                                    if sender
-                                        .send(Ok(GenerationEvent::ToolCall(tool_call.clone())))
-                                        .await
-                                        .is_err()
-                                    {
-                                        println!(
-                                            "Client disconnected during tool call, closing stream..."
-                                        );
-                                        stream.close();
-                                        break;
-                                    }
-                                }
-
-                                // Remove processed tool call from tracking
-                                current_tool_calls.remove(&tool_key);
-                            }
+                            pending_tool_calls.push(tool_call_delta);
                         }
                     }
 
                     // Handle regular text content
                     if let Some(text) = delta["content"].as_str() {
+                        turn_text.push_str(text);
                         if sender
                             .send(Ok(GenerationEvent::Text(text.to_string())))
                             .await
                             .is_err()
                         {
-                            println!("Client disconnected during text, closing stream...");
+                            tracing::debug!("Client disconnected during text, closing stream");
+                            stream.close();
+                            break;
+                        }
+                    }
+
+                    if let Some(reason) = m["choices"][0]["finish_reason"].as_str() {
+                        finish_reason = Some(reason.to_string());
+                        if sender
+                            .send(Ok(GenerationEvent::FinishReason(reason.to_string())))
+                            .await
+                            .is_err()
+                        {
+                            tracing::debug!("Client disconnected during finish reason, closing stream");
                             stream.close();
                             break;
                         }
@@ -392,16 +534,248 @@ pub async fn generate_sse_stream(
                                 .await
                                 .is_err()
                             {
-                                println!("Client disconnected during usage, closing stream...");
+                                tracing::debug!("Client disconnected during usage, closing stream");
                                 stream.close();
                                 break;
                             }
                         }
                     }
                 }
+
+                // Tool calls only run once the model is done with them, as
+                // their arguments stream in pieces
+                let finished = if done || finish_reason.as_deref() == Some("tool_calls") {
+                    pending_tool_calls.take()
+                } else {
+                    Vec::new()
+                };
+                for tool_call in finished {
+                    tracing::debug!("The model called {}", tool_call.function.name);
+
+                    // The model may still name a tool it wasn't offered
+                    if !options.allows_tool(&tool_call.function.name) {
+                        let result_text =
+                            format!("Tool Execution Error: this agent may not use {}", tool_call.function.name);
+                        if sender.send(Ok(GenerationEvent::Text(result_text))).await.is_err() {
+                            tracing::debug!("Client disconnected during tool result, closing stream");
+                            stream.close();
+                            break;
+                        }
+                        continue;
+                    }
+
+                    // Arguments that don't fit go back to the model to correct
+                    let errors = tool_parameters
+                        .get(&tool_call.function.name)
+                        .map(|parameters| argument_errors(parameters, &tool_call.function.arguments))
+                        .unwrap_or_default();
+                    if !errors.is_empty() {
+                        let result_text = format!(
+                            "Tool Execution Error: the arguments don't match the parameters of {}:\n{}",
+                            tool_call.function.name,
+                            errors.join("\n")
+                        );
+                        corrections.push((tool_call.clone(), result_text.clone()));
+                        if sender.send(Ok(GenerationEvent::Text(result_text))).await.is_err() {
+                            tracing::debug!("Client disconnected during tool result, closing stream");
+                            stream.close();
+                            break;
+                        }
+                        continue;
+                    }
+
+                    // User-defined HTTP tool: show the call, run it and stream the result back
+                    if let Some(custom_tool) = custom_tools.iter().find(|t| t.name == tool_call.function.name) {
+                        if sender
+                            .send(Ok(GenerationEvent::ToolCall(tool_call.clone())))
+                            .await
+                            .is_err()
+                        {
+                            tracing::debug!("Client disconnected during tool call, closing stream");
+                            stream.close();
+                            break;
+                        }
+
+                        // Model ids aren't unique (some providers number them per
+                        // turn), so the run is cancelled by its own id
+                        let execution_id = uuid::Uuid::new_v4().to_string();
+                        if confirmations.is_some()
+                            && sender.send(Ok(GenerationEvent::ToolRunning(execution_id.clone()))).await.is_err()
+                        {
+                            tracing::debug!("Client disconnected during tool call, closing stream");
+                            stream.close();
+                            break;
+                        }
+
+                        let started = std::time::Instant::now();
+                        let run = custom_tools::execute_custom_tool(custom_tool, &tool_call.function.arguments);
+                        // `None` when the user cancelled the call
+                        let outcome = match &confirmations {
+                            Some(target) => cancel::cancellable(target.chat_id, &execution_id, run).await,
+                            None => Some(run.await),
+                        };
+                        if let Some(target) = &confirmations {
+                            let execution = NewToolExecution {
+                                user_id: target.user_id,
+                                chat_id: target.chat_id,
+                                server: custom_tools::CUSTOM_TOOL_SERVER,
+                                tool: &custom_tool.name,
+                                arguments: &tool_call.function.arguments,
+                                result_bytes: match &outcome {
+                                    Some(Ok(output)) => output.len() as i64,
+                                    _ => 0,
+                                },
+                                duration_ms: started.elapsed().as_millis() as i64,
+                                error: match &outcome {
+                                    Some(Ok(_)) => None,
+                                    Some(Err(e)) => Some(e.to_string()),
+                                    None => Some("Cancelled".to_string()),
+                                },
+                            };
+                            if let Err(e) = target.repo.record_tool_execution(&execution).await {
+                                tracing::error!("Failed to log tool execution: {}", e);
+                            }
+                        }
+                        let result = match outcome {
+                            Some(Ok(output)) => GenerationEvent::Text(format!("Tool Result: {}", output)),
+                            Some(Err(e)) => GenerationEvent::Text(format!("Tool Execution Error: {}", e)),
+                            None => GenerationEvent::ToolCancelled(tool_call.id.clone()),
+                        };
+                        if sender
+                            .send(Ok(result))
+                            .await
+                            .is_err()
+                        {
+                            tracing::debug!("Client disconnected during tool result, closing stream");
+                            stream.close();
+                            break;
+                        }
+                        continue;
+                    }
+
+                    // Check if this is an MCP tool
+                    let is_mcp = parse_tool_call_from_ai(&tool_call).is_some();
+
+                    if is_mcp {
+                        // Tools the user always allows run without asking
+                        let allowed = match &confirmations {
+                            Some(target) => always_allowed_server(target, &mcp, &tool_call.function.name).await,
+                            None => None,
+                        };
+                        if let (Some(target), Some(server), Some(mcp_tool_call)) =
+                            (&confirmations, allowed, parse_tool_call_from_ai(&tool_call))
+                        {
+                            let execution_id = uuid::Uuid::new_v4().to_string();
+                            if sender.send(Ok(GenerationEvent::ToolCall(tool_call))).await.is_err()
+                                || sender.send(Ok(GenerationEvent::ToolRunning(execution_id.clone()))).await.is_err()
+                            {
+                                tracing::debug!("Client disconnected during tool call, closing stream");
+                                stream.close();
+                                break;
+                            }
+
+                            let result = run_allowed_tool(target, &mcp, &server, &mcp_tool_call, &execution_id).await;
+                            if sender
+                                .send(Ok(result))
+                                .await
+                                .is_err()
+                            {
+                                tracing::debug!("Client disconnected during tool result, closing stream");
+                                stream.close();
+                                break;
+                            }
+                            continue;
+                        }
+
+                        // Create tool call confirmation for MCP tools
+                        if let Some(target) = &confirmations {
+                            let confirmation = crate::data::model::ToolCallConfirmation {
+                                id: tool_call.id.clone(),
+                                chat_id: target.chat_id,
+                                message_pair_id: target.message_pair_id,
+                                tool_call: tool_call.clone(),
+                                status: crate::data::model::ToolCallStatus::Pending,
+                                created_at: chrono::Utc::now(),
+                                user_response: None,
+                                result: None,
+                            };
+
+                            // Save confirmation to database
+                            if let Err(e) = target.repo.save_tool_call_confirmation(&confirmation).await {
+                                tracing::error!("Failed to save tool call confirmation: {}", e);
+                                // Continue anyway and send the confirmation event
+                            }
+
+                            // Send confirmation request to UI
+                            if sender
+                                .send(Ok(GenerationEvent::ToolCallConfirmation(confirmation)))
+                                .await
+                                .is_err()
+                            {
+                                tracing::debug!("Client disconnected during tool call confirmation, closing stream");
+                                stream.close();
+                                break;
+                            }
+                        } else {
+                            // Fallback: Execute directly if no chat/message IDs
+                            if let Some(mcp_tool_call) = parse_tool_call_from_ai(&tool_call) {
+                                if let Err(e) = execute_mcp_tool_streaming(&mcp, &mcp_tool_call, sender.clone()).await {
+                                    tracing::warn!("Failed to execute MCP tool {}: {}", mcp_tool_call.name, e);
+                                    let error_text = format!("Tool execution error: {}", e);
+                                    if sender
+                                        .send(Ok(GenerationEvent::Text(error_text)))
+                                        .await
+                                        .is_err()
+                                    {
+                                        tracing::debug!("Client disconnected during tool error, closing stream");
+                                        stream.close();
+                                        break;
+                                    }
+                                }
+                            }
+                        }
+                    } else {
+                        // Regular OpenAI tool call - just forward it
+                        if sender
+                            .send(Ok(GenerationEvent::ToolCall(tool_call.clone())))
+                            .await
+                            .is_err()
+                        {
+                            tracing::debug!("Client disconnected during tool call, closing stream");
+                            stream.close();
+                            break;
+                        }
+                    }
+                }
+
+                if done {
+                    stream.close();
+                    // Calls with arguments that didn't fit are answered with
+                    // what's wrong, and the model goes on from there
+                    if !corrections.is_empty() && corrected < MAX_CORRECTIONS && !sender.is_closed() {
+                        corrected += 1;
+                        if let Some(messages) = body["messages"].as_array_mut() {
+                            messages.extend(correction_messages(&turn_text, &corrections));
+                        }
+                        turn_text.clear();
+                        corrections.clear();
+                        stream = open_completion(&client, url, api_key, &body)?;
+                        continue;
+                    }
+                    if sender
+                        .send(Ok(GenerationEvent::End(
+                            r#"<div id="sse-listener" hx-swap-oob="true"></div>"#.to_string(),
+                        )))
+                        .await
+                        .is_err()
+                    {
+                        break; // Receiver has dropped, stop sending.
+                    }
+                    break;
+                }
             }
             Err(err) => {
-                println!("Error: {}", err);
+                tracing::error!("The model stream failed: {}", err);
                 stream.close();
                 if sender.send(Err(axum::Error::new(err))).await.is_err() {
                     break; // Receiver has dropped, stop sending.
@@ -410,36 +784,6 @@ pub async fn generate_sse_stream(
         }
     }
 
-    println!("SSE stream generation completed or cancelled.");
-
-    Ok(())
-}
-
-// Save tool call confirmation to database
-async fn save_tool_call_confirmation(confirmation: &ToolCallConfirmation) -> Result<(), Box<dyn std::error::Error>> {
-    let tool_call_json = serde_json::to_string(&confirmation.tool_call)?;
-    let status_json = serde_json::to_string(&confirmation.status)?;
-    let created_at_str = confirmation.created_at.to_rfc3339();
-
-    sqlx::query!(
-        r#"
-        INSERT INTO tool_call_confirmations (id, chat_id, message_pair_id, tool_call, status, created_at)
-        VALUES (?1, ?2, ?3, ?4, ?5, ?6)
-        ON CONFLICT(id) DO UPDATE SET
-            status = excluded.status,
-            user_response = excluded.user_response,
-            result = excluded.result
-        "#,
-        confirmation.id,
-        confirmation.chat_id,
-        confirmation.message_pair_id,
-        tool_call_json,
-        status_json,
-        created_at_str
-    )
-    .execute(crate::get_db_pool())
-    .await?;
-
     Ok(())
 }
 
@@ -449,6 +793,60 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_allows_tool() {
+        let mut options = GenerationOptions::default();
+        assert!(options.allows_tool("fetch__get"));
+
+        options.allow_tools = Some(vec!["fetch__*".to_string(), "weather".to_string()]);
+        assert!(options.allows_tool("fetch__get"));
+        assert!(options.allows_tool("weather"));
+        assert!(!options.allows_tool("fetcher__get"));
+        assert!(!options.allows_tool("weather_alerts"));
+    }
+
+    #[test]
+    fn test_fragmented_tool_calls() {
+        let mut pending = PendingToolCalls::default();
+        for delta in [
+            json!({ "index": 0, "id": "call_a", "type": "function", "function": { "name": "weather", "arguments": "" } }),
+            json!({ "index": 0, "function": { "arguments": "{\"ci" } }),
+            json!({ "index": 1, "id": "call_b", "function": { "name": "weather", "arguments": "{\"days\"" } }),
+            json!({ "index": 0, "function": { "arguments": "ty\": \"Par" } }),
+            json!({ "index": 1, "function": { "arguments": ": 2}" } }),
+            json!({ "index": 0, "function": { "arguments": "is\"}" } }),
+        ] {
+            pending.push(&delta);
+        }
+        let tool_calls = pending.take();
+        assert!(pending.take().is_empty());
+        assert_eq!(tool_calls.len(), 2);
+        assert_eq!(tool_calls[0].id, "call_a");
+        assert_eq!(tool_calls[0].function.arguments, r#"{"city": "Paris"}"#);
+        assert_eq!(tool_calls[1].function.arguments, r#"{"days": 2}"#);
+
+        // Only checked once complete; the second call misses the city
+        let parameters = json!({
+            "type": "object",
+            "properties": { "city": { "type": "string" }, "days": { "type": "integer" } },
+            "required": ["city"]
+        });
+        assert_eq!(argument_errors(&parameters, "{\"ci").len(), 1);
+        assert!(argument_errors(&parameters, &tool_calls[0].function.arguments).is_empty());
+        assert_eq!(argument_errors(&parameters, &tool_calls[1].function.arguments).len(), 1);
+
+        let corrections = vec![(tool_calls[1].clone(), "Tool Execution Error: the city is missing".to_string())];
+        let messages = correction_messages("Checking", &corrections);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0]["role"], "assistant");
+        assert_eq!(messages[0]["tool_calls"][0]["id"], "call_b");
+        assert_eq!(messages[0]["tool_calls"][0]["function"]["arguments"], r#"{"days": 2}"#);
+        assert_eq!(
+            messages[1],
+            json!({ "role": "tool", "tool_call_id": "call_b", "content": "Tool Execution Error: the city is missing" })
+        );
+    }
+
     #[tokio::test]
     async fn test_something_async() {
         // Create a channel for sending SSE events
@@ -469,10 +867,23 @@ mod tests {
             ai_message: Some("Hi there!".to_string()),
             block_rank: 1,
             block_size: 1,
+            thinking: None,
+            tool_calls: None,
+            images: None,
+            reasoning: None,
+            usage_prompt_tokens: None,
+            usage_completion_tokens: None,
+            usage_total_tokens: None,
+            sources: None,
+            finish_reason: None,
         }];
 
         tokio::spawn(async move {
-            generate_sse_stream(&_api_key, "gpt-4", _pairs, _sender, None, None)
+            let options = GenerationOptions {
+                model: "gpt-4".to_string(),
+                ..Default::default()
+            };
+            generate_sse_stream(&_api_key, &options, _pairs, vec![], _sender, None)
                 .await
                 .unwrap();
         });
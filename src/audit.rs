@@ -0,0 +1,118 @@
+use std::net::IpAddr;
+
+use crate::AppState;
+
+// Security events kept in `audit_log`, shown under /settings/audit and
+// /admin/audit
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AuditEvent {
+    Login,
+    LoginFailed,
+    AccountLocked,
+    Logout,
+    PasswordChanged,
+    ApiKeyChanged,
+    ApiTokenCreated,
+    ApiTokenDeleted,
+    SessionRevoked,
+    ToolApproved,
+    ToolRejected,
+    SamplingApproved,
+    SamplingDeclined,
+    AccountDisabled,
+    AccountEnabled,
+    AccountUnlocked,
+    Impersonated,
+}
+
+impl AuditEvent {
+    pub const ALL: [AuditEvent; 17] = [
+        AuditEvent::Login,
+        AuditEvent::LoginFailed,
+        AuditEvent::AccountLocked,
+        AuditEvent::Logout,
+        AuditEvent::PasswordChanged,
+        AuditEvent::ApiKeyChanged,
+        AuditEvent::ApiTokenCreated,
+        AuditEvent::ApiTokenDeleted,
+        AuditEvent::SessionRevoked,
+        AuditEvent::ToolApproved,
+        AuditEvent::ToolRejected,
+        AuditEvent::SamplingApproved,
+        AuditEvent::SamplingDeclined,
+        AuditEvent::AccountDisabled,
+        AuditEvent::AccountEnabled,
+        AuditEvent::AccountUnlocked,
+        AuditEvent::Impersonated,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AuditEvent::Login => "login",
+            AuditEvent::LoginFailed => "login_failed",
+            AuditEvent::AccountLocked => "account_locked",
+            AuditEvent::Logout => "logout",
+            AuditEvent::PasswordChanged => "password_changed",
+            AuditEvent::ApiKeyChanged => "api_key_changed",
+            AuditEvent::ApiTokenCreated => "api_token_created",
+            AuditEvent::ApiTokenDeleted => "api_token_deleted",
+            AuditEvent::SessionRevoked => "session_revoked",
+            AuditEvent::ToolApproved => "tool_approved",
+            AuditEvent::ToolRejected => "tool_rejected",
+            AuditEvent::SamplingApproved => "sampling_approved",
+            AuditEvent::SamplingDeclined => "sampling_declined",
+            AuditEvent::AccountDisabled => "account_disabled",
+            AuditEvent::AccountEnabled => "account_enabled",
+            AuditEvent::AccountUnlocked => "account_unlocked",
+            AuditEvent::Impersonated => "impersonated",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|event| event.as_str() == value)
+    }
+
+    // The names for the viewer's filter
+    pub fn names() -> Vec<&'static str> {
+        Self::ALL.into_iter().map(Self::as_str).collect()
+    }
+}
+
+// Who did it and from where, besides the account the event is about
+#[derive(Debug, Default, Clone)]
+pub struct AuditSource {
+    // The admin's email when it wasn't the user
+    pub actor: Option<String>,
+    pub ip: Option<IpAddr>,
+}
+
+// Records the event. Failing to is logged rather than failing the request
+pub async fn record(
+    state: &AppState,
+    user_id: Option<i64>,
+    event: AuditEvent,
+    detail: Option<&str>,
+    source: AuditSource,
+) {
+    let ip = source.ip.map(|ip| ip.to_string());
+    if let Err(e) = state
+        .chat_repo
+        .record_audit_event(user_id, event.as_str(), detail, source.actor.as_deref(), ip.as_deref())
+        .await
+    {
+        tracing::error!("Failed to record {} for user {:?}: {}", event.as_str(), user_id, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        for event in AuditEvent::ALL {
+            assert_eq!(AuditEvent::parse(event.as_str()), Some(event));
+        }
+        assert_eq!(AuditEvent::parse("nothing"), None);
+    }
+}
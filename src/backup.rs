@@ -0,0 +1,226 @@
+use chrono::{DateTime, Utc};
+use libsqlite3_sys::{
+    sqlite3, sqlite3_backup_finish, sqlite3_backup_init, sqlite3_backup_step, sqlite3_errcode, SQLITE_DONE, SQLITE_OK,
+};
+use serde::Serialize;
+use sqlx::{
+    migrate::Migrator,
+    sqlite::{SqliteConnectOptions, SqlitePool},
+    Connection, SqliteConnection,
+};
+
+use std::path::{Path, PathBuf};
+use std::ptr::NonNull;
+
+// Snapshots of the database, from `BACKUP_DIR`
+pub fn backup_dir() -> PathBuf {
+    PathBuf::from(dotenv::var("BACKUP_DIR").unwrap_or_else(|_| "backups".to_string()))
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum BackupError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+
+    #[error("Failed to read or write the backup: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Failed to load the migrations: {0}")]
+    Migrate(#[from] sqlx::migrate::MigrateError),
+
+    #[error("Not a valid backup: {0}")]
+    Invalid(String),
+
+    #[error("SQLite backup failed with code {0}")]
+    Copy(i32),
+}
+
+#[derive(Debug, Serialize)]
+pub struct BackupFile {
+    pub name: String,
+    pub size: u64,
+    pub created_at: DateTime<Utc>,
+}
+
+// Only names `create` hands out, so a download can't leave the directory
+fn valid_name(name: &str) -> bool {
+    name.ends_with(".db")
+        && !name.starts_with('.')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+pub fn path_for(name: &str) -> Option<PathBuf> {
+    valid_name(name).then(|| backup_dir().join(name))
+}
+
+// A consistent copy of the live database, taken without stopping it
+pub async fn create(pool: &SqlitePool, prefix: &str) -> Result<String, BackupError> {
+    let dir = backup_dir();
+    tokio::fs::create_dir_all(&dir).await?;
+    let name = format!("{}-{}.db", prefix, Utc::now().format("%Y%m%d-%H%M%S-%3f"));
+    let path = dir.join(&name);
+
+    sqlx::query("VACUUM INTO ?")
+        .bind(path.to_string_lossy().as_ref())
+        .execute(pool)
+        .await?;
+    Ok(name)
+}
+
+// Newest first
+pub async fn list() -> Result<Vec<BackupFile>, BackupError> {
+    let mut entries = match tokio::fs::read_dir(backup_dir()).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut backups = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().to_string();
+        if !valid_name(&name) {
+            continue;
+        }
+        let metadata = entry.metadata().await?;
+        backups.push(BackupFile {
+            name,
+            size: metadata.len(),
+            created_at: metadata.modified()?.into(),
+        });
+    }
+    backups.sort_by_key(|backup| std::cmp::Reverse(backup.created_at));
+    Ok(backups)
+}
+
+// The file must be an intact database of this app, with no migrations this
+// build doesn't know
+pub async fn validate(path: &Path, migrator: &Migrator) -> Result<(), BackupError> {
+    let options = SqliteConnectOptions::new().filename(path).read_only(true);
+    let mut conn = SqliteConnection::connect_with(&options)
+        .await
+        .map_err(|_| BackupError::Invalid("not an SQLite database".to_string()))?;
+
+    let integrity: String = sqlx::query_scalar("PRAGMA integrity_check")
+        .fetch_one(&mut conn)
+        .await
+        .map_err(|_| BackupError::Invalid("not an SQLite database".to_string()))?;
+    if integrity != "ok" {
+        return Err(BackupError::Invalid(format!("integrity check failed: {}", integrity)));
+    }
+
+    let versions: Vec<i64> = sqlx::query_scalar("SELECT version FROM _sqlx_migrations")
+        .fetch_all(&mut conn)
+        .await
+        .map_err(|_| BackupError::Invalid("not a database of this app".to_string()))?;
+    if let Some(version) = versions
+        .iter()
+        .find(|version| !migrator.iter().any(|migration| migration.version == **version))
+    {
+        return Err(BackupError::Invalid(format!("made by a newer version (migration {})", version)));
+    }
+    conn.close().await?;
+    Ok(())
+}
+
+// Replaces the live database with a `validate`d backup through SQLite's
+// online backup API, then brings its schema up to date. Other connections
+// see the new contents on their next query
+pub async fn restore(pool: &SqlitePool, path: &Path, migrator: &Migrator) -> Result<(), BackupError> {
+    let options = SqliteConnectOptions::new().filename(path).read_only(true);
+    let mut source = SqliteConnection::connect_with(&options).await?;
+    let mut live = pool.acquire().await?;
+    {
+        let mut source_handle = source.lock_handle().await?;
+        let mut live_handle = live.lock_handle().await?;
+        copy_database(source_handle.as_raw_handle(), live_handle.as_raw_handle())?;
+    }
+    drop(live);
+    source.close().await?;
+
+    migrator.run(pool).await?;
+    Ok(())
+}
+
+fn copy_database(source: NonNull<sqlite3>, destination: NonNull<sqlite3>) -> Result<(), BackupError> {
+    // Both handles are locked by the caller for the duration
+    unsafe {
+        let backup = sqlite3_backup_init(destination.as_ptr(), c"main".as_ptr(), source.as_ptr(), c"main".as_ptr());
+        if backup.is_null() {
+            return Err(BackupError::Copy(sqlite3_errcode(destination.as_ptr())));
+        }
+        let step = sqlite3_backup_step(backup, -1);
+        let finish = sqlite3_backup_finish(backup);
+        if step != SQLITE_DONE {
+            return Err(BackupError::Copy(step));
+        }
+        if finish != SQLITE_OK {
+            return Err(BackupError::Copy(finish));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_name() {
+        assert!(valid_name("backup-20260101-120000-000.db"));
+        assert!(!valid_name("../db.db"));
+        assert!(!valid_name("a/b.db"));
+        assert!(!valid_name(".db"));
+        assert!(!valid_name("backup.sqlite"));
+    }
+
+    #[tokio::test]
+    async fn test_backup_and_restore() {
+        let dir = std::env::temp_dir().join(format!("backups-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let migrator = crate::database::migrator().await.unwrap();
+
+        let options = SqliteConnectOptions::new()
+            .filename(dir.join("live.db"))
+            .create_if_missing(true);
+        let pool = SqlitePool::connect_with(options).await.unwrap();
+        migrator.run(&pool).await.unwrap();
+        sqlx::query("INSERT INTO users (email, password) VALUES ('kept@backup.test', 'x')")
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        let snapshot = dir.join("snapshot.db");
+        sqlx::query("VACUUM INTO ?")
+            .bind(snapshot.to_string_lossy().as_ref())
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query("DELETE FROM users").execute(&pool).await.unwrap();
+
+        validate(&snapshot, &migrator).await.unwrap();
+        restore(&pool, &snapshot, &migrator).await.unwrap();
+        let emails: Vec<String> = sqlx::query_scalar("SELECT email FROM users")
+            .fetch_all(&pool)
+            .await
+            .unwrap();
+        assert_eq!(emails, ["kept@backup.test"]);
+
+        let junk = dir.join("junk.db");
+        std::fs::write(&junk, b"not a database").unwrap();
+        assert!(matches!(validate(&junk, &migrator).await, Err(BackupError::Invalid(_))));
+
+        let options = SqliteConnectOptions::new().filename(&snapshot);
+        let mut conn = SqliteConnection::connect_with(&options).await.unwrap();
+        sqlx::query(
+            "INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time)
+            VALUES (99990101000000, 'future', 1, x'00', 0)",
+        )
+        .execute(&mut conn)
+        .await
+        .unwrap();
+        conn.close().await.unwrap();
+        assert!(matches!(validate(&snapshot, &migrator).await, Err(BackupError::Invalid(_))));
+
+        pool.close().await;
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
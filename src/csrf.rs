@@ -0,0 +1,86 @@
+use axum::{
+    body::{Body, Bytes},
+    extract::{FromRequest, Multipart},
+    http::{header, HeaderMap, Request},
+};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+// Holds the token for the browser session; the same value is embedded in
+// pages and must come back with every state-changing request
+pub const CSRF_COOKIE: &str = "rust-gpt-csrf";
+pub const CSRF_HEADER: &str = "x-csrf-token";
+pub const CSRF_FIELD: &str = "csrf_token";
+
+tokio::task_local! {
+    // The token of the request being handled, for `csrf_token()` in templates
+    pub static CSRF_TOKEN: String;
+}
+
+pub fn new_token() -> String {
+    uuid::Uuid::new_v4().simple().to_string()
+}
+
+// `{{ csrf_token() }}` in templates. Empty when rendering outside a request,
+// which no form submitted by a browser is
+pub fn tera_csrf_token(_: &HashMap<String, tera::Value>) -> tera::Result<tera::Value> {
+    let token = CSRF_TOKEN.try_with(Clone::clone).unwrap_or_default();
+    Ok(tera::Value::String(token))
+}
+
+#[derive(Deserialize)]
+struct CsrfForm {
+    csrf_token: Option<String>,
+}
+
+// The token sent by a plain HTML form, for requests without the header
+pub async fn token_from_form(headers: &HeaderMap, body: Bytes) -> Option<String> {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+
+    if content_type.starts_with("application/x-www-form-urlencoded") {
+        let form: CsrfForm = serde_urlencoded::from_bytes(&body).ok()?;
+        return form.csrf_token;
+    }
+    if content_type.starts_with("multipart/form-data") {
+        let mut req = Request::new(Body::from(body));
+        *req.headers_mut() = headers.clone();
+        let mut multipart = Multipart::from_request(req, &()).await.ok()?;
+        while let Ok(Some(field)) = multipart.next_field().await {
+            if field.name() == Some(CSRF_FIELD) {
+                return field.text().await.ok();
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_token_from_form() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            "application/x-www-form-urlencoded".parse().unwrap(),
+        );
+        let body = Bytes::from("email=a%40b.c&csrf_token=abc");
+        assert_eq!(token_from_form(&headers, body).await.as_deref(), Some("abc"));
+
+        headers.insert(
+            header::CONTENT_TYPE,
+            "multipart/form-data; boundary=X".parse().unwrap(),
+        );
+        let body = Bytes::from(
+            "--X\r\nContent-Disposition: form-data; name=\"csrf_token\"\r\n\r\nabc\r\n--X--\r\n",
+        );
+        assert_eq!(token_from_form(&headers, body).await.as_deref(), Some("abc"));
+
+        headers.insert(header::CONTENT_TYPE, "application/json".parse().unwrap());
+        assert_eq!(token_from_form(&headers, Bytes::from("{}")).await, None);
+    }
+}
@@ -0,0 +1,220 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::model::{Agent, Chat};
+
+// Results of one query, kept in memory for `ttl` and up to `capacity` of
+// them, dropping the least recently used first. Each instance of the app
+// caches on its own, so writes through another instance show up after `ttl`
+pub struct QueryCache<K, V> {
+    ttl: Duration,
+    capacity: usize,
+    inner: Mutex<Inner<K, V>>,
+}
+
+struct Inner<K, V> {
+    entries: HashMap<K, Entry<V>>,
+    // Bumped by `invalidate`, so a load that raced a write isn't stored
+    generation: u64,
+    // Counts lookups, for least recently used
+    clock: u64,
+}
+
+struct Entry<V> {
+    value: V,
+    stored_at: Instant,
+    used: u64,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> QueryCache<K, V> {
+    // A zero `ttl` or `capacity` caches nothing
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        QueryCache {
+            ttl,
+            capacity,
+            inner: Mutex::new(Inner {
+                entries: HashMap::new(),
+                generation: 0,
+                clock: 0,
+            }),
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        !self.ttl.is_zero() && self.capacity > 0
+    }
+
+    // The cached result for `key`, or what `load` returns, which is cached
+    // unless it failed
+    pub async fn get_or_load<F, Fut, E>(&self, key: K, load: F) -> Result<V, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<V, E>>,
+    {
+        if !self.enabled() {
+            return load().await;
+        }
+        let generation = match self.get_at(&key, Instant::now()) {
+            Ok(value) => return Ok(value),
+            Err(generation) => generation,
+        };
+        let value = load().await?;
+        self.insert_at(key, value.clone(), generation, Instant::now());
+        Ok(value)
+    }
+
+    // `Err` holds the generation to store a fresh result under
+    fn get_at(&self, key: &K, now: Instant) -> Result<V, u64> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.clock += 1;
+        let clock = inner.clock;
+        match inner.entries.get_mut(key) {
+            Some(entry) if now.duration_since(entry.stored_at) < self.ttl => {
+                entry.used = clock;
+                Ok(entry.value.clone())
+            }
+            _ => Err(inner.generation),
+        }
+    }
+
+    fn insert_at(&self, key: K, value: V, generation: u64, now: Instant) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.generation != generation {
+            return;
+        }
+        if inner.entries.len() >= self.capacity && !inner.entries.contains_key(&key) {
+            let ttl = self.ttl;
+            inner.entries.retain(|_, entry| now.duration_since(entry.stored_at) < ttl);
+        }
+        if inner.entries.len() >= self.capacity && !inner.entries.contains_key(&key) {
+            let oldest = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.used)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                inner.entries.remove(&oldest);
+            }
+        }
+        let used = inner.clock;
+        inner.entries.insert(
+            key,
+            Entry {
+                value,
+                stored_at: now,
+                used,
+            },
+        );
+    }
+
+    // Forgets everything, for writes that may change any result
+    pub fn invalidate(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.generation += 1;
+        inner.entries.clear();
+    }
+}
+
+// Which chats a sidebar page lists
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ChatScope {
+    User(i64),
+    // The org, and the user whose folders show
+    Org(i64, i64),
+    // The org as a whole, for counts
+    OrgTotal(i64),
+}
+
+// (scope, tag, cursor, limit)
+type ChatPageKey = (ChatScope, Option<String>, Option<i64>, i64);
+// (scope, tag)
+type ChatCountKey = (ChatScope, Option<String>);
+// (user, org, cursor, limit)
+type AgentPageKey = (i64, Option<i64>, Option<i64>, i64);
+// (user, org)
+type AgentCountKey = (i64, Option<i64>);
+
+// The queries run on every page load: the sidebar's chat pages and the
+// agents to pick from
+#[derive(Clone)]
+pub struct RepositoryCache {
+    pub chat_pages: Arc<QueryCache<ChatPageKey, Vec<Chat>>>,
+    pub chat_counts: Arc<QueryCache<ChatCountKey, i64>>,
+    pub agent_pages: Arc<QueryCache<AgentPageKey, Vec<Agent>>>,
+    pub agent_counts: Arc<QueryCache<AgentCountKey, i64>>,
+}
+
+impl RepositoryCache {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        RepositoryCache {
+            chat_pages: Arc::new(QueryCache::new(ttl, capacity)),
+            chat_counts: Arc::new(QueryCache::new(ttl, capacity)),
+            agent_pages: Arc::new(QueryCache::new(ttl, capacity)),
+            agent_counts: Arc::new(QueryCache::new(ttl, capacity)),
+        }
+    }
+
+    // `QUERY_CACHE_TTL_SECS` (30 by default, 0 turns caching off) and
+    // `QUERY_CACHE_SIZE`, the entries kept per query (1000 by default)
+    pub fn from_env() -> Self {
+        let ttl = dotenv::var("QUERY_CACHE_TTL_SECS")
+            .ok()
+            .and_then(|secs| secs.trim().parse().ok())
+            .unwrap_or(30);
+        let capacity = dotenv::var("QUERY_CACHE_SIZE")
+            .ok()
+            .and_then(|size| size.trim().parse().ok())
+            .unwrap_or(1000);
+        Self::new(Duration::from_secs(ttl), capacity)
+    }
+
+    pub fn chats_changed(&self) {
+        self.chat_pages.invalidate();
+        self.chat_counts.invalidate();
+    }
+
+    pub fn agents_changed(&self) {
+        self.agent_pages.invalidate();
+        self.agent_counts.invalidate();
+    }
+
+    // For writes that change both, and after the database was replaced
+    // as a whole
+    pub fn clear(&self) {
+        self.chats_changed();
+        self.agents_changed();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_cache() {
+        let cache: QueryCache<&str, i64> = QueryCache::new(Duration::from_secs(10), 2);
+        let now = Instant::now();
+
+        let generation = cache.get_at(&"a", now).unwrap_err();
+        cache.insert_at("a", 1, generation, now);
+        assert_eq!(cache.get_at(&"a", now), Ok(1));
+        assert!(cache.get_at(&"a", now + Duration::from_secs(10)).is_err());
+
+        // "a" was used more recently than "b", so "b" makes room for "c"
+        cache.insert_at("b", 2, generation, now);
+        assert_eq!(cache.get_at(&"a", now), Ok(1));
+        cache.insert_at("c", 3, generation, now);
+        assert!(cache.get_at(&"b", now).is_err());
+        assert_eq!(cache.get_at(&"a", now), Ok(1));
+
+        // A load that started before the write isn't stored after it
+        let generation = cache.get_at(&"d", now).unwrap_err();
+        cache.invalidate();
+        cache.insert_at("d", 4, generation, now);
+        assert!(cache.get_at(&"d", now).is_err());
+        assert!(cache.get_at(&"a", now).is_err());
+    }
+}
@@ -1,23 +1,134 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
+use utoipa::ToSchema;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct User {
     pub id: i64,
     pub email: String,
-    pub password: String, // Note: Storing plain-text passwords is not recommended. Use hashed passwords instead.
+    pub password: String, // Argon2id PHC string, or plain text until the user's next login
     pub created_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
 pub struct Chat {
     pub id: i64,
     pub name: String,
     pub user_id: i64,
+    pub pinned: bool,
+    pub sort_order: i64,
+    pub folder_id: Option<i64>,
+    pub tags: Option<String>, // comma-separated tag names
+    pub archived: bool,
+    pub share_token: Option<String>,
+}
+
+// Access to a chat, ordered from least to most
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ChatRole {
+    Viewer,
+    Editor,
+    Owner,
+}
+
+impl ChatRole {
+    pub fn parse(role: &str) -> Option<Self> {
+        match role {
+            "viewer" => Some(ChatRole::Viewer),
+            "editor" => Some(ChatRole::Editor),
+            "owner" => Some(ChatRole::Owner),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ChatRole::Viewer => "viewer",
+            ChatRole::Editor => "editor",
+            ChatRole::Owner => "owner",
+        }
+    }
+}
+
+// A member's powers in an org, ordered from least to most. Admins manage
+// members and the org's API key and agents; owners can also delete the org
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum OrgRole {
+    Member,
+    Admin,
+    Owner,
 }
 
+impl OrgRole {
+    pub fn parse(role: &str) -> Option<Self> {
+        match role {
+            "member" => Some(OrgRole::Member),
+            "admin" => Some(OrgRole::Admin),
+            "owner" => Some(OrgRole::Owner),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OrgRole::Member => "member",
+            OrgRole::Admin => "admin",
+            OrgRole::Owner => "owner",
+        }
+    }
+}
+
+// An org the user belongs to, with their role in it
 #[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct OrgMembership {
+    pub id: i64,
+    pub name: String,
+    pub role: String,
+    pub members: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct Org {
+    pub id: i64,
+    pub name: String,
+    // Still encrypted; only `has_api_key` reaches templates
+    #[serde(skip_serializing)]
+    pub openai_api_key: Option<String>,
+    pub model: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Org {
+    pub fn has_api_key(&self) -> bool {
+        self.openai_api_key.as_deref().is_some_and(|key| !key.is_empty())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct OrgMember {
+    pub user_id: i64,
+    pub email: String,
+    pub display_name: Option<String>,
+    pub role: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct ChatMember {
+    pub user_id: i64,
+    pub email: String,
+    pub role: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct ChatInvitation {
+    pub email: String,
+    pub role: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone, ToSchema)]
 pub struct ChatMessagePair {
     pub id: i64,
     pub model: String,
@@ -36,6 +147,651 @@ pub struct ChatMessagePair {
     pub usage_completion_tokens: Option<i64>,
     pub usage_total_tokens: Option<i64>,
     pub sources: Option<String>, // JSON string
+    pub finish_reason: Option<String>,
+}
+
+// A file uploaded with a human message. `path` is the file's name under
+// `uploads/`, `kind` either "image" or "file"
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct Attachment {
+    pub id: i64,
+    pub message_pair_id: i64,
+    pub name: String,
+    pub path: String,
+    pub mime: String,
+    pub size: i64,
+    pub kind: String,
+}
+
+impl Attachment {
+    pub fn is_image(&self) -> bool {
+        self.kind == "image"
+    }
+}
+
+// An upload saved to disk, stored with the message it came with
+#[derive(Debug, Serialize, Clone)]
+pub struct NewAttachment {
+    pub name: String,
+    pub path: String,
+    pub mime: String,
+    pub size: i64,
+    pub kind: String,
+}
+
+// Raw rows for the full data export, one JSON line each. Every version of
+// every message is kept along with the blocks that order them, so the
+// branches of a chat can be rebuilt from the archive
+#[derive(Debug, Serialize, FromRow, Clone)]
+pub struct ExportedBlock {
+    pub id: i64,
+    pub chat_id: i64,
+    pub selected_pair_id: Option<i64>,
+    pub parent_pair_id: Option<i64>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, FromRow, Clone)]
+pub struct ExportedPair {
+    pub id: i64,
+    pub message_block_id: i64,
+    pub human_message: String,
+    pub ai_message: Option<String>,
+    pub thinking: Option<String>,
+    pub reasoning: Option<String>,
+    pub tool_calls: Option<String>, // JSON string
+    pub images: Option<String>,     // JSON string
+    pub sources: Option<String>,    // JSON string
+    pub finish_reason: Option<String>,
+    pub model: Option<String>,
+    pub latency_ms: Option<i64>,
+    pub prompt_tokens: Option<i64>,
+    pub completion_tokens: Option<i64>,
+    pub cost: Option<f64>,
+    pub created_at: DateTime<Utc>,
+}
+
+// Raw AI message as stored, used by maintenance jobs
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct StoredMessage {
+    pub id: i64,
+    pub chat_id: i64,
+    pub message: String,
+}
+
+// A message matching a chat search; `snippet` is raw text with \u{2}/\u{3}
+// around the matched terms, highlighted once it has been escaped
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct SearchHit {
+    pub chat_id: i64,
+    pub chat_name: String,
+    pub pair_id: i64,
+    pub snippet: String,
+}
+
+// An answered pair that has no embedding yet
+#[derive(Debug, FromRow, Clone)]
+pub struct UnindexedPair {
+    pub pair_id: i64,
+    pub chat_id: i64,
+    pub human_message: String,
+    pub ai_message: String,
+}
+
+// A pair close in meaning to a search; `distance` is cosine distance
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct SimilarChunk {
+    pub chat_id: i64,
+    pub chat_name: String,
+    pub pair_id: i64,
+    pub content: String,
+    pub distance: f64,
+}
+
+// Usage analytics rows, exported as CSV from settings
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct DailyUsage {
+    pub day: String,
+    pub responses: i64,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    pub total_tokens: i64,
+}
+
+// Tokens and cost summed over message pairs, for a chat or a user's range
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone, ToSchema)]
+pub struct PairUsage {
+    pub responses: i64,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    // USD over the priced responses only
+    pub cost: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone, ToSchema)]
+pub struct DailyCost {
+    pub day: String,
+    pub responses: i64,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    pub cost: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct ModelUsage {
+    pub model: String,
+    pub responses: i64,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    pub total_tokens: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct ToolUsage {
+    pub tool: String,
+    pub invocations: i64,
+}
+
+// Per-model totals for one chat, counting every response version
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone, ToSchema)]
+pub struct ChatModelStats {
+    pub model: String,
+    pub responses: i64,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    pub total_tokens: i64,
+    // Responses saved before latency was recorded don't count
+    #[serde(skip)]
+    pub timed_responses: i64,
+    pub avg_latency_ms: Option<f64>,
+    // USD, `None` for models without a known price
+    pub cost: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, ToSchema)]
+pub struct ChatStats {
+    pub chat_id: i64,
+    pub messages: i64,
+    pub responses: i64,
+    pub prompt_tokens: i64,
+    pub completion_tokens: i64,
+    pub total_tokens: i64,
+    // USD over the priced models only
+    pub cost: f64,
+    pub avg_latency_ms: Option<f64>,
+    pub models: Vec<ChatModelStats>,
+}
+
+impl ChatStats {
+    pub fn new(chat_id: i64, messages: i64, mut models: Vec<ChatModelStats>) -> Self {
+        for model in &mut models {
+            model.cost = crate::ai::pricing::cost(&model.model, model.prompt_tokens, model.completion_tokens);
+        }
+
+        let timed: i64 = models.iter().map(|m| m.timed_responses).sum();
+        let latency: f64 = models
+            .iter()
+            .filter_map(|m| m.avg_latency_ms.map(|avg| avg * m.timed_responses as f64))
+            .sum();
+
+        ChatStats {
+            chat_id,
+            messages,
+            responses: models.iter().map(|m| m.responses).sum(),
+            prompt_tokens: models.iter().map(|m| m.prompt_tokens).sum(),
+            completion_tokens: models.iter().map(|m| m.completion_tokens).sum(),
+            total_tokens: models.iter().map(|m| m.total_tokens).sum(),
+            cost: models.iter().filter_map(|m| m.cost).sum(),
+            avg_latency_ms: (timed > 0).then(|| latency / timed as f64),
+            models,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct TrashedChat {
+    pub id: i64,
+    pub name: String,
+    pub deleted_at: DateTime<Utc>,
+    // Until the chat is deleted for good
+    pub days_left: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct TrashedAgent {
+    pub id: i64,
+    pub name: String,
+    pub deleted_at: DateTime<Utc>,
+    pub days_left: i64,
+}
+
+// Applied to several of the user's chats at once from the sidebar
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum BulkChatAction {
+    Delete,
+    Archive,
+    // `None` moves the chats out of their folders
+    Move { folder_id: Option<i64> },
+}
+
+// Outcome of signing in with an OAuth provider
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OAuthSignIn {
+    // The identity was known, or got linked to the account with its email
+    Existing(i64),
+    Created(i64),
+    // An account uses the email, but the provider hasn't verified it
+    EmailTaken,
+    // Nobody has the identity or the email, and signup is invitation only
+    SignupClosed,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct Folder {
+    pub id: i64,
+    pub user_id: i64,
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct Tag {
+    pub id: i64,
+    pub name: String,
+    pub chat_count: i64,
+}
+
+// A user's defaults for new chats, with the API key as stored (encrypted)
+#[derive(Debug, Clone)]
+pub struct UserSettings {
+    pub stored_api_key: String,
+    pub base_url: String,
+    pub model: String,
+    pub system_prompt: String,
+    pub temperature: f64,
+    pub top_p: f64,
+    pub max_tokens: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone, Default)]
+pub struct ChatSettings {
+    pub chat_id: i64,
+    pub stop_sequences: Option<String>, // JSON array
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub max_tokens: Option<i64>,
+    pub model: Option<String>,
+}
+
+impl ChatSettings {
+    pub fn stop_sequences(&self) -> Vec<String> {
+        parse_string_list(self.stop_sequences.as_deref())
+    }
+}
+
+// Model, prompt and parameters a chat can be bound to
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone, ToSchema)]
+pub struct Agent {
+    pub id: i64,
+    pub user_id: i64,
+    // Set for agents shared with an org's members
+    pub org_id: Option<i64>,
+    pub name: String,
+    pub model: String,
+    pub system_prompt: Option<String>,
+    pub temperature: Option<f64>,
+    pub top_p: Option<f64>,
+    pub max_tokens: Option<i64>,
+    pub stop_sequences: Option<String>, // JSON array
+    pub tools_enabled: bool,
+    pub allow_tools: Option<String>, // JSON array, NULL for every tool
+    // Bumped on every save, see `ChatRepository::update_agent`
+    pub version: i64,
+}
+
+impl Agent {
+    pub fn stop_sequences(&self) -> Vec<String> {
+        parse_string_list(self.stop_sequences.as_deref())
+    }
+
+    // `None` when the agent may call every tool
+    pub fn allow_tools(&self) -> Option<Vec<String>> {
+        self.allow_tools.as_deref().map(|json| parse_string_list(Some(json)))
+    }
+}
+
+fn parse_string_list(json: Option<&str>) -> Vec<String> {
+    json.and_then(|json| serde_json::from_str(json).ok())
+        .unwrap_or_default()
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct MessageFeedback {
+    pub pair_id: i64,
+    pub rating: i64, // 1 for thumbs up, -1 for thumbs down
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct FeedbackSummary {
+    pub model: String,
+    pub positive: i64,
+    pub negative: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct Bookmark {
+    pub pair_id: i64,
+    pub chat_id: i64,
+    pub chat_name: String,
+    pub human_message: String,
+    pub ai_message: String,
+}
+
+// Prompt the scheduler sends to a chat at `run_at` (UTC)
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct ScheduledMessage {
+    pub id: i64,
+    pub user_id: i64,
+    pub chat_id: i64,
+    pub chat_name: String,
+    pub message: String,
+    pub run_at: DateTime<Utc>,
+    pub status: String,
+    pub error: Option<String>,
+}
+
+// What an API token may do, ordered from least to most
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum ApiScope {
+    Read,
+    Write,
+}
+
+impl ApiScope {
+    pub fn parse(scope: &str) -> Option<Self> {
+        match scope {
+            "read" => Some(ApiScope::Read),
+            "write" => Some(ApiScope::Write),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApiScope::Read => "read",
+            ApiScope::Write => "write",
+        }
+    }
+}
+
+// A token for the JSON API. The secret itself is only shown once, when created
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct ApiToken {
+    pub id: i64,
+    pub name: String,
+    pub scope: String,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+// Who a session cookie belongs to
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionUser {
+    pub user_id: i64,
+    // Email of the admin using the session to impersonate the user
+    pub impersonator: Option<String>,
+}
+
+// Outcome of logging a browser back in with its "Remember me" cookie
+#[derive(Debug, Clone, PartialEq)]
+pub enum RememberLogin {
+    // The token was current, and has been replaced by the new one
+    Rotated { user_id: i64, remember_id: i64 },
+    // The token was replaced moments ago by a request sent alongside this
+    // one, which hands the browser the new token
+    Recent { user_id: i64, remember_id: i64 },
+    // An older token of the series: the cookie was copied, so the series
+    // and its sessions are gone
+    Reused(i64),
+    Invalid,
+}
+
+// A failed password login, as counted against the account
+#[derive(Debug, Clone, PartialEq)]
+pub struct FailedLogin {
+    pub user_id: i64,
+    pub failed_logins: i64,
+    // Set when this failure locked the account
+    pub locked_until: Option<DateTime<Utc>>,
+}
+
+// A row of the admin's user list, with usage totals
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct AdminUser {
+    pub id: i64,
+    pub email: String,
+    pub is_admin: bool,
+    pub created_at: DateTime<Utc>,
+    pub verified_at: Option<DateTime<Utc>>,
+    pub disabled_at: Option<DateTime<Utc>>,
+    pub last_seen_at: Option<DateTime<Utc>>,
+    pub failed_logins: i64,
+    // Only set while the lock lasts
+    pub locked_until: Option<DateTime<Utc>>,
+    pub chats: i64,
+    pub responses: i64,
+    pub total_tokens: i64,
+}
+
+// A device logged in to the account
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct Session {
+    pub id: i64,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    // The session of the request listing them
+    pub current: bool,
+}
+
+// An admin's override of the default quota, see `quota::Quota::with_override`
+#[derive(Debug, Default, Serialize, Deserialize, FromRow, Clone)]
+pub struct QuotaOverride {
+    pub daily_tokens: Option<i64>,
+    pub monthly_tokens: Option<i64>,
+    pub daily_messages: Option<i64>,
+    pub monthly_messages: Option<i64>,
+}
+
+// Tokens generated and messages sent in the user's chats this UTC day and
+// calendar month
+#[derive(Debug, Default, Serialize, Deserialize, FromRow, Clone)]
+pub struct Usage {
+    pub daily_tokens: i64,
+    pub monthly_tokens: i64,
+    pub daily_messages: i64,
+    pub monthly_messages: i64,
+}
+
+// A signup invite, with the emails of the admin who made it and the account
+// it created
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct Invite {
+    pub id: i64,
+    pub code: String,
+    pub note: Option<String>,
+    pub created_by: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub used_by: Option<String>,
+    pub used_at: Option<DateTime<Utc>>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+// A personal data export, see `data_export`. `progress` is a percentage
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct DataExport {
+    pub id: i64,
+    pub status: String,
+    pub progress: i64,
+    #[serde(skip_serializing)]
+    pub file: Option<String>,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+}
+
+// A row of `tool_executions` with the name of its chat
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct ToolExecution {
+    pub id: i64,
+    pub chat_id: i64,
+    pub chat_name: String,
+    pub server: String,
+    pub tool: String,
+    pub arguments_hash: String,
+    pub result_bytes: i64,
+    pub duration_ms: i64,
+    pub outcome: String,
+    pub error: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+// A tool's calls over a period, see `ChatRepository::tool_stats`
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct ToolStats {
+    pub server: String,
+    pub tool: String,
+    pub calls: i64,
+    pub failures: i64,
+    pub avg_duration_ms: i64,
+    pub max_duration_ms: i64,
+    pub last_used: DateTime<Utc>,
+}
+
+// A server's calls over a period, see `ChatRepository::server_tool_stats`
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct ServerToolStats {
+    pub server: String,
+    pub tools: i64,
+    pub calls: i64,
+    pub failures: i64,
+    pub avg_duration_ms: i64,
+}
+
+// An MCP resource attached to a chat, with the text read when it was attached
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct ChatResource {
+    pub id: i64,
+    pub chat_id: i64,
+    pub server: String,
+    pub uri: String,
+    pub name: String,
+    pub mime_type: Option<String>,
+    pub content: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone)]
+pub struct NewChatResource<'a> {
+    pub server: &'a str,
+    pub uri: &'a str,
+    pub name: &'a str,
+    pub mime_type: Option<&'a str>,
+    pub content: &'a str,
+}
+
+// An MCP tool the user always allows
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct ToolApproval {
+    pub id: i64,
+    pub server: String,
+    pub tool: String,
+    pub created_at: DateTime<Utc>,
+}
+
+// An MCP server a user added; `config` is its mcp.json entry as JSON
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct UserMcpServer {
+    pub id: i64,
+    pub name: String,
+    pub config: String,
+    pub oauth: Option<String>,
+    pub updated_at: DateTime<Utc>,
+}
+
+// A finished tool call to log; the arguments are stored hashed
+#[derive(Debug, Clone)]
+pub struct NewToolExecution<'a> {
+    pub user_id: i64,
+    pub chat_id: i64,
+    pub server: &'a str,
+    pub tool: &'a str,
+    pub arguments: &'a str,
+    pub result_bytes: i64,
+    pub duration_ms: i64,
+    // The error message, if the call failed
+    pub error: Option<String>,
+}
+
+// A row of `audit_log`, see `audit::AuditEvent`
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct AuditEntry {
+    pub id: i64,
+    pub user_id: Option<i64>,
+    // Email of the account, gone with it
+    pub email: Option<String>,
+    pub event: String,
+    pub detail: Option<String>,
+    pub actor: Option<String>,
+    pub ip: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct Webhook {
+    pub id: i64,
+    pub url: String,
+    pub secret: String,
+    pub events: String, // comma-separated
+    // Outcome of the latest delivery, if any was attempted
+    pub last_status: Option<String>,
+    pub last_error: Option<String>,
+}
+
+// A queued event with what's needed to send it
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct WebhookDelivery {
+    pub id: i64,
+    pub url: String,
+    pub secret: String,
+    pub event: String,
+    pub payload: String,
+    pub attempts: i64,
+}
+
+// Starter prompt on the home page; `user_id` is `None` for curated ones
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct PromptTemplate {
+    pub id: i64,
+    pub user_id: Option<i64>,
+    pub title: String,
+    pub description: String,
+    pub prompt: String,
+}
+
+// User-defined REST tool, called with the model's arguments as a JSON POST body
+#[derive(Debug, Serialize, Deserialize, FromRow, Clone)]
+pub struct CustomTool {
+    pub id: i64,
+    pub user_id: i64,
+    pub name: String,
+    pub description: String,
+    pub parameters: String, // JSON schema
+    pub url: String,
+    pub auth_header: Option<String>,
 }
 
 // Extended AI response data structures
@@ -96,6 +852,19 @@ pub enum ToolCallStatus {
     Failed,    // 执行失败
 }
 
+impl ToolCallStatus {
+    // As stored in `tool_call_confirmations.status`
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ToolCallStatus::Pending => "Pending",
+            ToolCallStatus::Approved => "Approved",
+            ToolCallStatus::Rejected => "Rejected",
+            ToolCallStatus::Executed => "Executed",
+            ToolCallStatus::Failed => "Failed",
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct UsageInfo {
     pub prompt_tokens: i64,
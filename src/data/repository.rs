@@ -1,289 +1,7220 @@
+use std::collections::HashMap;
 use std::sync::Arc;
 
+use chrono::{DateTime, Utc};
+use futures::stream::{BoxStream, TryStreamExt};
 use sqlx::sqlite::SqlitePool;
 use sqlx::{Sqlite, Transaction};
 
-use super::model::{Chat, ChatMessagePair};
+use crate::ai::pricing;
+use crate::utils::attachments::upload_files;
+use crate::utils::export::ExportedChat;
+use crate::utils::password::hash_token;
+
+use super::cache::{ChatScope, RepositoryCache};
+use super::model::{
+    AdminUser, Agent, ApiScope, Attachment, AuditEntry, ApiToken, Bookmark, BulkChatAction, Chat, ChatInvitation, ChatMember, ChatMessagePair, ChatModelStats, ChatResource, ChatRole, ChatSettings, CustomTool, DailyCost, DailyUsage, DataExport, ExportedBlock, ExportedPair, FeedbackSummary, Folder, MessageFeedback,
+    FailedLogin, Invite, OAuthSignIn, RememberLogin, Session, SessionUser,
+    ModelUsage, NewAttachment, NewChatResource, NewToolExecution, Org, OrgMember, OrgMembership, OrgRole, PairUsage, PromptTemplate, QuotaOverride, Usage, ScheduledMessage, SearchHit, ServerToolStats, SimilarChunk, StoredMessage, Tag, ToolApproval, ToolExecution, ToolStats, ToolUsage, ToolCallConfirmation, TrashedAgent, TrashedChat, UnindexedPair, UserMcpServer, Webhook,
+    UserSettings, WebhookDelivery,
+};
+use super::vector;
+
+// SQLite reads a negative LIMIT as none
+pub const NO_LIMIT: i64 = -1;
 
 #[derive(Clone)]
 pub struct ChatRepository {
     pub pool: Arc<SqlitePool>,
+    // Sidebar chat pages and agent lists; writes below that change them
+    // invalidate it
+    pub cache: RepositoryCache,
 }
 
 impl ChatRepository {
-    pub async fn get_all_chats(&self, user_id: i64) -> sqlx::Result<Vec<Chat>> {
+    pub fn new(pool: Arc<SqlitePool>) -> Self {
+        ChatRepository {
+            pool,
+            cache: RepositoryCache::from_env(),
+        }
+    }
+
+    // The user's personal chats, outside any org. `tag` limits the list to
+    // chats carrying that tag. Up to `limit` chats (`NO_LIMIT` for all) come
+    // after the chat `cursor`
+    pub async fn get_all_chats(
+        &self,
+        user_id: i64,
+        tag: Option<&str>,
+        cursor: Option<i64>,
+        limit: i64,
+    ) -> sqlx::Result<Vec<Chat>> {
+        let key = (ChatScope::User(user_id), tag.map(str::to_string), cursor, limit);
+        self.cache
+            .chat_pages
+            .get_or_load(key, || async {
+                // The row values compare in sort order: pinned first, then
+                // sort_order ascending (hence swapped), then newest
+                sqlx::query_as!(
+                    Chat,
+                    r#"
+                    SELECT c.id AS "id!", c.user_id, c.name, c.pinned, c.sort_order, c.folder_id,
+                        (SELECT group_concat(name, ',') FROM (
+                            SELECT t.name FROM chat_tags ct JOIN tags t ON t.id = ct.tag_id
+                            WHERE ct.chat_id = c.id ORDER BY t.name
+                        )) AS "tags?: String",
+                        c.archived, c.share_token
+                    FROM chats c
+                    LEFT JOIN chats cur ON cur.id = ?3
+                    WHERE c.user_id = ?1
+                        AND c.org_id IS NULL
+                        AND NOT c.archived
+                        AND c.deleted_at IS NULL
+                        AND (?2 IS NULL OR EXISTS (
+                            SELECT 1 FROM chat_tags ct JOIN tags t ON t.id = ct.tag_id
+                            WHERE ct.chat_id = c.id AND t.name = ?2
+                        ))
+                        AND (?3 IS NULL OR (c.pinned, cur.sort_order, c.created_at, c.id)
+                            < (cur.pinned, c.sort_order, cur.created_at, cur.id))
+                    ORDER BY c.pinned DESC, c.sort_order ASC, c.created_at DESC, c.id DESC
+                    LIMIT ?4
+                    "#,
+                    user_id,
+                    tag,
+                    cursor,
+                    limit
+                )
+                .fetch_all(&*self.pool)
+                .await
+            })
+            .await
+    }
+
+    // How many chats `get_all_chats` lists over all pages
+    pub async fn count_chats(&self, user_id: i64, tag: Option<&str>) -> sqlx::Result<i64> {
+        let key = (ChatScope::User(user_id), tag.map(str::to_string));
+        self.cache
+            .chat_counts
+            .get_or_load(key, || async {
+                sqlx::query_scalar!(
+                    r#"
+                    SELECT COUNT(*) AS "count!: i64"
+                    FROM chats c
+                    WHERE c.user_id = ?1
+                        AND c.org_id IS NULL
+                        AND NOT c.archived
+                        AND c.deleted_at IS NULL
+                        AND (?2 IS NULL OR EXISTS (
+                            SELECT 1 FROM chat_tags ct JOIN tags t ON t.id = ct.tag_id
+                            WHERE ct.chat_id = c.id AND t.name = ?2
+                        ))
+                    "#,
+                    user_id,
+                    tag
+                )
+                .fetch_one(&*self.pool)
+                .await
+            })
+            .await
+    }
+
+    // Every member's chats in the org, paged like `get_all_chats`. Folders
+    // are personal, so other members' chats show outside them
+    pub async fn get_org_chats(
+        &self,
+        org_id: i64,
+        user_id: i64,
+        tag: Option<&str>,
+        cursor: Option<i64>,
+        limit: i64,
+    ) -> sqlx::Result<Vec<Chat>> {
+        let key = (ChatScope::Org(org_id, user_id), tag.map(str::to_string), cursor, limit);
+        self.cache
+            .chat_pages
+            .get_or_load(key, || async {
+                sqlx::query_as!(
+                    Chat,
+                    r#"
+                    SELECT c.id AS "id!", c.user_id, c.name, c.pinned, c.sort_order,
+                        CASE WHEN c.user_id = ?2 THEN c.folder_id END AS "folder_id?: i64",
+                        (SELECT group_concat(name, ',') FROM (
+                            SELECT t.name FROM chat_tags ct JOIN tags t ON t.id = ct.tag_id
+                            WHERE ct.chat_id = c.id ORDER BY t.name
+                        )) AS "tags?: String",
+                        c.archived, c.share_token
+                    FROM chats c
+                    LEFT JOIN chats cur ON cur.id = ?4
+                    WHERE c.org_id = ?1
+                        AND NOT c.archived
+                        AND c.deleted_at IS NULL
+                        AND (?3 IS NULL OR EXISTS (
+                            SELECT 1 FROM chat_tags ct JOIN tags t ON t.id = ct.tag_id
+                            WHERE ct.chat_id = c.id AND t.name = ?3
+                        ))
+                        AND (?4 IS NULL OR (c.pinned, cur.sort_order, c.created_at, c.id)
+                            < (cur.pinned, c.sort_order, cur.created_at, cur.id))
+                    ORDER BY c.pinned DESC, c.sort_order ASC, c.created_at DESC, c.id DESC
+                    LIMIT ?5
+                    "#,
+                    org_id,
+                    user_id,
+                    tag,
+                    cursor,
+                    limit
+                )
+                .fetch_all(&*self.pool)
+                .await
+            })
+            .await
+    }
+
+    pub async fn count_org_chats(&self, org_id: i64, tag: Option<&str>) -> sqlx::Result<i64> {
+        let key = (ChatScope::OrgTotal(org_id), tag.map(str::to_string));
+        self.cache
+            .chat_counts
+            .get_or_load(key, || async {
+                sqlx::query_scalar!(
+                    r#"
+                    SELECT COUNT(*) AS "count!: i64"
+                    FROM chats c
+                    WHERE c.org_id = ?1
+                        AND NOT c.archived
+                        AND c.deleted_at IS NULL
+                        AND (?2 IS NULL OR EXISTS (
+                            SELECT 1 FROM chat_tags ct JOIN tags t ON t.id = ct.tag_id
+                            WHERE ct.chat_id = c.id AND t.name = ?2
+                        ))
+                    "#,
+                    org_id,
+                    tag
+                )
+                .fetch_one(&*self.pool)
+                .await
+            })
+            .await
+    }
+
+    pub async fn get_chat(&self, chat_id: i64) -> sqlx::Result<Option<Chat>> {
+        sqlx::query_as!(
+            Chat,
+            r#"
+            SELECT c.id, c.user_id, c.name, c.pinned, c.sort_order, c.folder_id,
+                (SELECT group_concat(name, ',') FROM (
+                    SELECT t.name FROM chat_tags ct JOIN tags t ON t.id = ct.tag_id
+                    WHERE ct.chat_id = c.id ORDER BY t.name
+                )) AS "tags?: String",
+                c.archived, c.share_token
+            FROM chats c
+            WHERE c.id = ? AND c.deleted_at IS NULL
+            "#,
+            chat_id
+        )
+        .fetch_optional(&*self.pool)
+        .await
+    }
+
+    // Chats other people shared with the user
+    pub async fn get_shared_chats(&self, user_id: i64) -> sqlx::Result<Vec<Chat>> {
         sqlx::query_as!(
             Chat,
-            "SELECT id, user_id, name FROM chats WHERE user_id = ? ORDER BY created_at DESC",
+            r#"
+            SELECT c.id AS "id!", c.user_id, c.name, c.pinned, c.sort_order, c.folder_id,
+                NULL AS "tags?: String",
+                c.archived, c.share_token
+            FROM chats c
+            JOIN chat_members m ON m.chat_id = c.id
+            WHERE m.user_id = ? AND NOT c.archived AND c.deleted_at IS NULL
+            ORDER BY c.created_at DESC
+            "#,
             user_id
         )
         .fetch_all(&*self.pool)
         .await
     }
 
-    pub async fn delete_chat(&self, chat_id: i64) -> sqlx::Result<u64> {
-        let result = sqlx::query("DELETE FROM chats WHERE id = ?")
-            .bind(chat_id)
-            .execute(&*self.pool)
-            .await?;
-        Ok(result.rows_affected())
+    // `None` if the user has no access to the chat
+    pub async fn get_chat_role(&self, chat_id: i64, user_id: i64) -> sqlx::Result<Option<ChatRole>> {
+        let role = sqlx::query_scalar!(
+            r#"
+            SELECT role AS "role!: String" FROM v_chat_access
+            WHERE chat_id = ? AND user_id = ?
+            ORDER BY CASE role WHEN 'owner' THEN 0 WHEN 'editor' THEN 1 ELSE 2 END
+            LIMIT 1
+            "#,
+            chat_id,
+            user_id
+        )
+        .fetch_optional(&*self.pool)
+        .await?;
+        Ok(role.as_deref().and_then(ChatRole::parse))
     }
 
-    pub async fn retrieve_chat(&self, chat_id: i64) -> sqlx::Result<Vec<ChatMessagePair>> {
-        let rows = sqlx::query!(
+    pub async fn get_chat_members(&self, chat_id: i64) -> sqlx::Result<Vec<ChatMember>> {
+        sqlx::query_as!(
+            ChatMember,
             r#"
-            SELECT
-                id, message_block_id, chat_id, model, human_message, ai_message,
-                block_rank, block_size, thinking, tool_calls, images, reasoning,
-                usage_prompt_tokens, usage_completion_tokens, usage_total_tokens, sources
-            FROM v_chat_messages
-            WHERE chat_id = ?
+            SELECT users.id AS "user_id!", users.email, chat_members.role
+            FROM chat_members
+            JOIN users ON users.id = chat_members.user_id
+            WHERE chat_members.chat_id = ?
+            ORDER BY users.email
             "#,
             chat_id
         )
         .fetch_all(&*self.pool)
-        .await?;
+        .await
+    }
 
-        let pairs = rows
-            .into_iter()
-            .map(|row| ChatMessagePair {
-                id: row.id,
-                message_block_id: row.message_block_id,
-                chat_id: row.chat_id,
-                model: row.model,
-                human_message: row.human_message,
-                ai_message: row.ai_message,
-                block_rank: row.block_rank,
-                block_size: row.block_size,
-                thinking: row.thinking,
-                tool_calls: row.tool_calls,
-                images: row.images,
-                reasoning: row.reasoning,
-                usage_prompt_tokens: row.usage_prompt_tokens,
-                usage_completion_tokens: row.usage_completion_tokens,
-                usage_total_tokens: row.usage_total_tokens,
-                sources: row.sources,
-            })
-            .collect();
+    pub async fn get_chat_invitations(&self, chat_id: i64) -> sqlx::Result<Vec<ChatInvitation>> {
+        sqlx::query_as!(
+            ChatInvitation,
+            "SELECT email, role FROM chat_invitations WHERE chat_id = ? ORDER BY email",
+            chat_id
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
 
-        Ok(pairs)
+    // Adds the user with that email right away, or keeps an invitation until
+    // someone signs up with it. Inviting someone again changes their role;
+    // the owner can't be invited
+    pub async fn invite_to_chat(&self, chat_id: i64, email: &str, role: ChatRole) -> sqlx::Result<u64> {
+        let role = role.as_str();
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+        let user = sqlx::query!(r#"SELECT id AS "id!" FROM users WHERE email = ?"#, email)
+            .fetch_optional(&mut *tx)
+            .await?;
+
+        let result = match user {
+            Some(user) => {
+                sqlx::query!(
+                    r#"
+                    INSERT INTO chat_members (chat_id, user_id, role)
+                    SELECT id, ?2, ?3 FROM chats WHERE id = ?1 AND user_id != ?2
+                    ON CONFLICT (chat_id, user_id) DO UPDATE SET role = excluded.role
+                    "#,
+                    chat_id,
+                    user.id,
+                    role
+                )
+                .execute(&mut *tx)
+                .await?
+            }
+            None => {
+                sqlx::query!(
+                    r#"
+                    INSERT INTO chat_invitations (chat_id, email, role) VALUES (?, ?, ?)
+                    ON CONFLICT (chat_id, email) DO UPDATE SET role = excluded.role
+                    "#,
+                    chat_id,
+                    email,
+                    role
+                )
+                .execute(&mut *tx)
+                .await?
+            }
+        };
+        tx.commit().await?;
+        Ok(result.rows_affected())
     }
-    pub async fn create_chat(&self, user_id: i64, name: &str, model: &str) -> sqlx::Result<i64> {
-        //create chat
-        let chat = sqlx::query!(
-            r#"
-            INSERT INTO chats (user_id, name, model)
-            VALUES (?, ?, ?) RETURNING id;
-            "#,
-            user_id,
-            name,
-            model
+
+    pub async fn remove_chat_member(&self, chat_id: i64, user_id: i64) -> sqlx::Result<u64> {
+        let result = sqlx::query!(
+            "DELETE FROM chat_members WHERE chat_id = ? AND user_id = ?",
+            chat_id,
+            user_id
         )
-        .fetch_one(&*self.pool)
+        .execute(&*self.pool)
         .await?;
-
-        Ok(chat.id.unwrap())
+        Ok(result.rows_affected())
     }
-    pub async fn add_ai_message_to_pair(&self, pair_id: i64, message: &str) -> sqlx::Result<i64> {
-        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
 
-        let message = sqlx::query!(
-            r#"
-            INSERT INTO messages (message)
-            VALUES (?) RETURNING id;
-            "#,
-            message
+    pub async fn cancel_chat_invitation(&self, chat_id: i64, email: &str) -> sqlx::Result<u64> {
+        let result = sqlx::query!(
+            "DELETE FROM chat_invitations WHERE chat_id = ? AND email = ?",
+            chat_id,
+            email
         )
-        .fetch_one(&mut *tx)
+        .execute(&*self.pool)
         .await?;
+        Ok(result.rows_affected())
+    }
 
-        sqlx::query!(
+    // Turns the invitations sent to a new account's email into memberships
+    pub async fn accept_chat_invitations(&self, user_id: i64, email: &str) -> sqlx::Result<u64> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+
+        let accepted = sqlx::query!(
             r#"
-            UPDATE message_pairs
-            SET ai_message_id = ?
-            WHERE id = ?;
+            INSERT OR IGNORE INTO chat_members (chat_id, user_id, role)
+            SELECT chat_id, ?, role FROM chat_invitations WHERE email = ?
             "#,
-            message.id,
-            pair_id
+            user_id,
+            email
         )
         .execute(&mut *tx)
         .await?;
 
-        tx.commit().await?;
+        sqlx::query!("DELETE FROM chat_invitations WHERE email = ?", email)
+            .execute(&mut *tx)
+            .await?;
 
-        Ok(message.id)
+        tx.commit().await?;
+        Ok(accepted.rows_affected())
     }
 
-    pub async fn add_ai_message_with_extended_data(
-        &self,
-        pair_id: i64,
-        message: &str,
-        thinking: Option<&str>,
-        tool_calls: Option<&str>,
-        images: Option<&str>,
-        reasoning: Option<&str>,
-        usage_prompt_tokens: Option<i64>,
-        usage_completion_tokens: Option<i64>,
-        usage_total_tokens: Option<i64>,
-        sources: Option<&str>,
-    ) -> sqlx::Result<i64> {
-        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
-
-        let message = sqlx::query!(
+    pub async fn get_archived_chats(&self, user_id: i64) -> sqlx::Result<Vec<Chat>> {
+        sqlx::query_as!(
+            Chat,
             r#"
-            INSERT INTO messages (
-                message, thinking, tool_calls, images, reasoning,
-                usage_prompt_tokens, usage_completion_tokens, usage_total_tokens, sources
-            )
-            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?) RETURNING id;
+            SELECT c.id AS "id!", c.user_id, c.name, c.pinned, c.sort_order, c.folder_id,
+                NULL AS "tags?: String",
+                c.archived, c.share_token
+            FROM chats c
+            WHERE c.user_id = ? AND c.archived AND c.deleted_at IS NULL
+            ORDER BY c.created_at DESC
             "#,
-            message,
-            thinking,
-            tool_calls,
-            images,
-            reasoning,
-            usage_prompt_tokens,
-            usage_completion_tokens,
-            usage_total_tokens,
-            sources
+            user_id
         )
-        .fetch_one(&mut *tx)
-        .await?;
+        .fetch_all(&*self.pool)
+        .await
+    }
 
-        sqlx::query!(
+    // Every chat the user started, archived, trashed and org ones included
+    pub async fn get_owned_chats(&self, user_id: i64) -> sqlx::Result<Vec<Chat>> {
+        sqlx::query_as!(
+            Chat,
             r#"
-            UPDATE message_pairs
-            SET ai_message_id = ?
-            WHERE id = ?;
+            SELECT c.id AS "id!", c.user_id, c.name, c.pinned, c.sort_order, c.folder_id,
+                (SELECT group_concat(name, ',') FROM (
+                    SELECT t.name FROM chat_tags ct JOIN tags t ON t.id = ct.tag_id
+                    WHERE ct.chat_id = c.id ORDER BY t.name
+                )) AS "tags?: String",
+                c.archived, c.share_token
+            FROM chats c
+            WHERE c.user_id = ?
+            ORDER BY c.created_at
             "#,
-            message.id,
-            pair_id
+            user_id
         )
-        .execute(&mut *tx)
-        .await?;
-
-        tx.commit().await?;
-
-        Ok(message.id)
+        .fetch_all(&*self.pool)
+        .await
     }
 
-    pub async fn add_message_block(&self, chat_id: i64, human_message: &str) -> sqlx::Result<i64> {
-        //create chat
-        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
-
-        let message_block = sqlx::query!(
-            r#"
-            INSERT INTO message_blocks (chat_id)
-            VALUES (?) RETURNING id;
-            "#,
+    pub async fn set_archived(&self, chat_id: i64, user_id: i64, archived: bool) -> sqlx::Result<u64> {
+        let result = sqlx::query!(
+            "UPDATE chats SET archived = ? WHERE id = ? AND user_id = ?",
+            archived,
             chat_id,
+            user_id
         )
-        .fetch_one(&mut *tx)
+        .execute(&*self.pool)
         .await?;
+        self.cache.chats_changed();
+        Ok(result.rows_affected())
+    }
 
-        let message = sqlx::query!(
+    pub async fn get_chat_by_share_token(&self, token: &str) -> sqlx::Result<Option<Chat>> {
+        sqlx::query_as!(
+            Chat,
             r#"
-            INSERT INTO messages (message)
-            VALUES (?) RETURNING id;
+            SELECT c.id AS "id!", c.user_id, c.name, c.pinned, c.sort_order, c.folder_id,
+                NULL AS "tags?: String",
+                c.archived, c.share_token
+            FROM chats c
+            WHERE c.share_token = ? AND c.deleted_at IS NULL
             "#,
-            human_message
+            token
         )
-        .fetch_one(&mut *tx)
+        .fetch_optional(&*self.pool)
+        .await
+    }
+
+    // `None` revokes the chat's share link
+    pub async fn set_share_token(
+        &self,
+        chat_id: i64,
+        user_id: i64,
+        token: Option<&str>,
+    ) -> sqlx::Result<u64> {
+        let result = sqlx::query!(
+            "UPDATE chats SET share_token = ? WHERE id = ? AND user_id = ?",
+            token,
+            chat_id,
+            user_id
+        )
+        .execute(&*self.pool)
         .await?;
+        self.cache.chats_changed();
+        Ok(result.rows_affected())
+    }
 
-        let message_pair = sqlx::query!(
-            r#"
-            INSERT INTO message_pairs (human_message_id, message_block_id)
-            VALUES (?, ?) RETURNING id;
-            "#,
-            message.id,
-            message_block.id,
+    pub async fn rename_chat(&self, chat_id: i64, user_id: i64, name: &str) -> sqlx::Result<u64> {
+        let result = sqlx::query!(
+            "UPDATE chats SET name = ? WHERE id = ? AND user_id = ?",
+            name,
+            chat_id,
+            user_id
         )
-        .fetch_one(&mut *tx)
+        .execute(&*self.pool)
         .await?;
+        self.cache.chats_changed();
+        Ok(result.rows_affected())
+    }
 
-        sqlx::query!(
-            r#"
-            UPDATE message_blocks
-            SET selected_pair_id = ?
-            WHERE id = ?;
-            "#,
-            message_pair.id,
-            message_block.id
+    pub async fn toggle_pin(&self, chat_id: i64, user_id: i64) -> sqlx::Result<u64> {
+        let result = sqlx::query!(
+            "UPDATE chats SET pinned = NOT pinned WHERE id = ? AND user_id = ?",
+            chat_id,
+            user_id
         )
-        .execute(&mut *tx)
+        .execute(&*self.pool)
         .await?;
+        self.cache.chats_changed();
+        Ok(result.rows_affected())
+    }
+
+    // Persist a drag-and-drop ordering; `chat_ids` is the sidebar order top to bottom
+    pub async fn reorder_chats(&self, user_id: i64, chat_ids: &[i64]) -> sqlx::Result<()> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+
+        for (position, chat_id) in chat_ids.iter().enumerate() {
+            let position = position as i64;
+            sqlx::query!(
+                "UPDATE chats SET sort_order = ? WHERE id = ? AND user_id = ?",
+                position,
+                chat_id,
+                user_id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
 
         tx.commit().await?;
 
-        Ok(message_pair.id.unwrap())
+        self.cache.chats_changed();
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::path::Path;
+    // All or nothing: returns 0 and changes no chat unless every one of them
+    // belongs to the user
+    pub async fn bulk_update_chats(
+        &self,
+        user_id: i64,
+        chat_ids: &[i64],
+        action: BulkChatAction,
+    ) -> sqlx::Result<u64> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
 
-    use sqlx::migrate::Migrator;
+        if let BulkChatAction::Move { folder_id: Some(folder_id) } = action {
+            let folders = sqlx::query_scalar!(
+                r#"SELECT COUNT(*) AS "count!: i64" FROM folders WHERE id = ? AND user_id = ?"#,
+                folder_id,
+                user_id
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+            if folders == 0 {
+                return Ok(0);
+            }
+        }
 
-    use super::*;
+        let mut rows_affected = 0;
+        for chat_id in chat_ids {
+            let result = match action {
+                BulkChatAction::Delete => {
+                    sqlx::query!(
+                        "UPDATE chats SET deleted_at = CURRENT_TIMESTAMP WHERE id = ? AND user_id = ? AND deleted_at IS NULL",
+                        chat_id,
+                        user_id
+                    )
+                    .execute(&mut *tx)
+                    .await?
+                }
+                BulkChatAction::Archive => {
+                    sqlx::query!(
+                        "UPDATE chats SET archived = 1 WHERE id = ? AND user_id = ?",
+                        chat_id,
+                        user_id
+                    )
+                    .execute(&mut *tx)
+                    .await?
+                }
+                BulkChatAction::Move { folder_id } => {
+                    sqlx::query!(
+                        "UPDATE chats SET folder_id = ? WHERE id = ? AND user_id = ?",
+                        folder_id,
+                        chat_id,
+                        user_id
+                    )
+                    .execute(&mut *tx)
+                    .await?
+                }
+            };
+            // Dropping the transaction rolls back the chats already changed
+            if result.rows_affected() == 0 {
+                return Ok(0);
+            }
+            rows_affected += result.rows_affected();
+        }
 
-    async fn setup() -> (Arc<SqlitePool>, ChatRepository, i64) {
+        tx.commit().await?;
+
+        self.cache.chats_changed();
+        Ok(rows_affected)
+    }
+
+    pub async fn get_folders(&self, user_id: i64) -> sqlx::Result<Vec<Folder>> {
+        sqlx::query_as!(
+            Folder,
+            r#"SELECT id AS "id!", user_id, name FROM folders WHERE user_id = ? ORDER BY name"#,
+            user_id
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    pub async fn create_folder(&self, user_id: i64, name: &str) -> sqlx::Result<()> {
+        sqlx::query!(
+            "INSERT INTO folders (user_id, name) VALUES (?, ?) ON CONFLICT (user_id, name) DO NOTHING",
+            user_id,
+            name
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn rename_folder(&self, folder_id: i64, user_id: i64, name: &str) -> sqlx::Result<u64> {
+        let result = sqlx::query!(
+            "UPDATE folders SET name = ? WHERE id = ? AND user_id = ?",
+            name,
+            folder_id,
+            user_id
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    // Chats in a deleted folder are kept and move back to the top level
+    pub async fn delete_folder(&self, folder_id: i64, user_id: i64) -> sqlx::Result<u64> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+
+        sqlx::query!(
+            "UPDATE chats SET folder_id = NULL WHERE folder_id = ? AND user_id = ?",
+            folder_id,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        let result = sqlx::query!(
+            "DELETE FROM folders WHERE id = ? AND user_id = ?",
+            folder_id,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        self.cache.chats_changed();
+        Ok(result.rows_affected())
+    }
+
+    // `folder_id` of `None` moves the chat out of its folder
+    pub async fn move_chat_to_folder(
+        &self,
+        chat_id: i64,
+        user_id: i64,
+        folder_id: Option<i64>,
+    ) -> sqlx::Result<u64> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE chats SET folder_id = ?1
+            WHERE id = ?2 AND user_id = ?3
+                AND (?1 IS NULL OR EXISTS (SELECT 1 FROM folders WHERE id = ?1 AND user_id = ?3))
+            "#,
+            folder_id,
+            chat_id,
+            user_id
+        )
+        .execute(&*self.pool)
+        .await?;
+        self.cache.chats_changed();
+        Ok(result.rows_affected())
+    }
+
+    pub async fn get_tags(&self, user_id: i64) -> sqlx::Result<Vec<Tag>> {
+        sqlx::query_as!(
+            Tag,
+            r#"
+            SELECT t.id AS "id!", t.name, COUNT(ct.chat_id) AS "chat_count!: i64"
+            FROM tags t
+            JOIN chat_tags ct ON ct.tag_id = t.id
+            JOIN chats c ON c.id = ct.chat_id AND c.deleted_at IS NULL
+            WHERE t.user_id = ?
+            GROUP BY t.id
+            ORDER BY t.name
+            "#,
+            user_id
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    pub async fn add_chat_tag(&self, chat_id: i64, user_id: i64, name: &str) -> sqlx::Result<u64> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+
+        sqlx::query!(
+            "INSERT INTO tags (user_id, name) VALUES (?, ?) ON CONFLICT (user_id, name) DO NOTHING",
+            user_id,
+            name
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        let result = sqlx::query!(
+            r#"
+            INSERT INTO chat_tags (chat_id, tag_id)
+            SELECT c.id, t.id
+            FROM chats c JOIN tags t ON t.user_id = c.user_id
+            WHERE c.id = ?1 AND c.user_id = ?2 AND t.name = ?3
+            ON CONFLICT (chat_id, tag_id) DO NOTHING
+            "#,
+            chat_id,
+            user_id,
+            name
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        self.cache.chats_changed();
+        Ok(result.rows_affected())
+    }
+
+    pub async fn remove_chat_tag(&self, chat_id: i64, user_id: i64, name: &str) -> sqlx::Result<u64> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+
+        let result = sqlx::query!(
+            r#"
+            DELETE FROM chat_tags
+            WHERE chat_id = ?1
+                AND tag_id = (SELECT id FROM tags WHERE user_id = ?2 AND name = ?3)
+                AND EXISTS (SELECT 1 FROM chats WHERE id = ?1 AND user_id = ?2)
+            "#,
+            chat_id,
+            user_id,
+            name
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        // Tags only exist while some chat carries them
+        sqlx::query!(
+            r#"
+            DELETE FROM tags
+            WHERE user_id = ? AND NOT EXISTS (SELECT 1 FROM chat_tags WHERE tag_id = tags.id)
+            "#,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        self.cache.chats_changed();
+        Ok(result.rows_affected())
+    }
+
+    // Moves the chat to the trash, see `purge_trashed_chats`
+    pub async fn delete_chat(&self, chat_id: i64) -> sqlx::Result<u64> {
+        let result = sqlx::query!(
+            "UPDATE chats SET deleted_at = CURRENT_TIMESTAMP WHERE id = ? AND deleted_at IS NULL",
+            chat_id
+        )
+        .execute(&*self.pool)
+        .await?;
+        self.cache.chats_changed();
+        Ok(result.rows_affected())
+    }
+
+    pub async fn get_trashed_chats(&self, user_id: i64, retention_days: i64) -> sqlx::Result<Vec<TrashedChat>> {
+        sqlx::query_as!(
+            TrashedChat,
+            r#"
+            SELECT
+                id AS "id!",
+                name,
+                deleted_at AS "deleted_at!: DateTime<Utc>",
+                MAX(0, ?2 - CAST(julianday('now') - julianday(deleted_at) AS INTEGER)) AS "days_left!: i64"
+            FROM chats
+            WHERE user_id = ?1 AND deleted_at IS NOT NULL
+            ORDER BY deleted_at DESC
+            "#,
+            user_id,
+            retention_days
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    pub async fn restore_chat(&self, chat_id: i64, user_id: i64) -> sqlx::Result<u64> {
+        let result = sqlx::query!(
+            "UPDATE chats SET deleted_at = NULL WHERE id = ? AND user_id = ? AND deleted_at IS NOT NULL",
+            chat_id,
+            user_id
+        )
+        .execute(&*self.pool)
+        .await?;
+        self.cache.chats_changed();
+        Ok(result.rows_affected())
+    }
+
+    // Permanently deletes chats that have been in the trash for `retention_days`
+    pub async fn purge_trashed_chats(&self, retention_days: i64) -> sqlx::Result<u64> {
+        let cutoff = format!("-{} days", retention_days);
+        let chat_ids = sqlx::query_scalar!(
+            r#"SELECT id AS "id!" FROM chats WHERE deleted_at <= datetime('now', ?)"#,
+            cutoff
+        )
+        .fetch_all(&*self.pool)
+        .await?;
+        self.purge_chats(&chat_ids).await
+    }
+
+    // Permanently deletes chats, trashed or not, without a message for
+    // `retention_days`. Chats that never got one count from their creation
+    pub async fn purge_inactive_chats(&self, retention_days: i64) -> sqlx::Result<u64> {
+        let cutoff = format!("-{} days", retention_days);
+        let chat_ids = sqlx::query_scalar!(
+            r#"
+            SELECT id AS "id!" FROM chats
+            WHERE COALESCE(
+                (SELECT MAX(created_at) FROM message_blocks WHERE chat_id = chats.id),
+                created_at
+            ) <= datetime('now', ?)
+            "#,
+            cutoff
+        )
+        .fetch_all(&*self.pool)
+        .await?;
+        self.purge_chats(&chat_ids).await
+    }
+
+    // Tool call confirmations and messages aren't removed with their chat by
+    // the foreign keys, so they go first
+    async fn purge_chats(&self, chat_ids: &[i64]) -> sqlx::Result<u64> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+
+        let mut purged = 0;
+        for chat_id in chat_ids {
+            sqlx::query!("DELETE FROM tool_call_confirmations WHERE chat_id = ?", chat_id)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query!(
+                r#"
+                DELETE FROM messages
+                WHERE id IN (
+                    SELECT message_pairs.human_message_id FROM message_pairs
+                    JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+                    WHERE message_blocks.chat_id = ?1
+                    UNION
+                    SELECT message_pairs.ai_message_id FROM message_pairs
+                    JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+                    WHERE message_blocks.chat_id = ?1
+                )
+                "#,
+                chat_id
+            )
+            .execute(&mut *tx)
+            .await?;
+            purged += sqlx::query!("DELETE FROM chats WHERE id = ?", chat_id)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+        }
+
+        tx.commit().await?;
+        self.cache.chats_changed();
+        Ok(purged)
+    }
+
+    // Tool call confirmations older than `retention_days`, whatever their
+    // status. Their results stay in the chat's messages
+    pub async fn purge_tool_call_confirmations(&self, retention_days: i64) -> sqlx::Result<u64> {
+        let cutoff = format!("-{} days", retention_days);
+        // Stored as RFC 3339, which `datetime` normalizes
+        let result = sqlx::query!(
+            "DELETE FROM tool_call_confirmations WHERE datetime(created_at) <= datetime('now', ?)",
+            cutoff
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    pub async fn purge_audit_log(&self, retention_days: i64) -> sqlx::Result<u64> {
+        let cutoff = format!("-{} days", retention_days);
+        let result = sqlx::query!("DELETE FROM audit_log WHERE created_at <= datetime('now', ?)", cutoff)
+            .execute(&*self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    pub async fn retrieve_chat(&self, chat_id: i64) -> sqlx::Result<Vec<ChatMessagePair>> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT
+                id AS "id!", message_block_id, chat_id, model, human_message, ai_message AS "ai_message?",
+                block_rank, block_size, thinking, tool_calls, images, reasoning,
+                usage_prompt_tokens, usage_completion_tokens, usage_total_tokens, sources,
+                finish_reason
+            FROM v_chat_messages
+            WHERE chat_id = ?
+            "#,
+            chat_id
+        )
+        .fetch_all(&*self.pool)
+        .await?;
+
+        let pairs = rows
+            .into_iter()
+            .map(|row| ChatMessagePair {
+                id: row.id,
+                message_block_id: row.message_block_id,
+                chat_id: row.chat_id,
+                model: row.model,
+                human_message: row.human_message,
+                ai_message: row.ai_message,
+                block_rank: row.block_rank,
+                block_size: row.block_size,
+                thinking: row.thinking,
+                tool_calls: row.tool_calls,
+                images: row.images,
+                reasoning: row.reasoning,
+                usage_prompt_tokens: row.usage_prompt_tokens,
+                usage_completion_tokens: row.usage_completion_tokens,
+                usage_total_tokens: row.usage_total_tokens,
+                sources: row.sources,
+                finish_reason: row.finish_reason,
+            })
+            .collect();
+
+        Ok(pairs)
+    }
+    // The `limit` most recent pairs before the block `before`, oldest first
+    pub async fn retrieve_chat_page(
+        &self,
+        chat_id: i64,
+        before: Option<i64>,
+        limit: i64,
+    ) -> sqlx::Result<Vec<ChatMessagePair>> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT
+                id AS "id!", message_block_id AS "message_block_id!", chat_id AS "chat_id!",
+                model AS "model!", human_message AS "human_message!", ai_message AS "ai_message?",
+                block_rank AS "block_rank!", block_size AS "block_size!", thinking, tool_calls,
+                images, reasoning, usage_prompt_tokens, usage_completion_tokens,
+                usage_total_tokens, sources, finish_reason
+            FROM (
+                SELECT *
+                FROM v_chat_messages
+                WHERE chat_id = ?1 AND (?2 IS NULL OR message_block_id < ?2)
+                ORDER BY message_block_id DESC
+                LIMIT ?3
+            )
+            ORDER BY message_block_id ASC
+            "#,
+            chat_id,
+            before,
+            limit
+        )
+        .fetch_all(&*self.pool)
+        .await?;
+
+        let pairs = rows
+            .into_iter()
+            .map(|row| ChatMessagePair {
+                id: row.id,
+                message_block_id: row.message_block_id,
+                chat_id: row.chat_id,
+                model: row.model,
+                human_message: row.human_message,
+                ai_message: row.ai_message,
+                block_rank: row.block_rank,
+                block_size: row.block_size,
+                thinking: row.thinking,
+                tool_calls: row.tool_calls,
+                images: row.images,
+                reasoning: row.reasoning,
+                usage_prompt_tokens: row.usage_prompt_tokens,
+                usage_completion_tokens: row.usage_completion_tokens,
+                usage_total_tokens: row.usage_total_tokens,
+                sources: row.sources,
+                finish_reason: row.finish_reason,
+            })
+            .collect();
+
+        Ok(pairs)
+    }
+
+    pub async fn create_chat(&self, user_id: i64, name: &str, model: &str) -> sqlx::Result<i64> {
+        //create chat
+        let chat = sqlx::query!(
+            r#"
+            INSERT INTO chats (user_id, name, model)
+            VALUES (?, ?, ?) RETURNING id;
+            "#,
+            user_id,
+            name,
+            model
+        )
+        .fetch_one(&*self.pool)
+        .await?;
+
+        self.cache.chats_changed();
+        Ok(chat.id.unwrap())
+    }
+    pub async fn add_ai_message_to_pair(&self, pair_id: i64, message: &str) -> sqlx::Result<i64> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+
+        let message = sqlx::query!(
+            r#"
+            INSERT INTO messages (message)
+            VALUES (?) RETURNING id;
+            "#,
+            message
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            UPDATE message_pairs
+            SET ai_message_id = ?
+            WHERE id = ?;
+            "#,
+            message.id,
+            pair_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(message.id)
+    }
+
+    pub async fn add_ai_message_with_extended_data(
+        &self,
+        pair_id: i64,
+        message: &str,
+        thinking: Option<&str>,
+        tool_calls: Option<&str>,
+        images: Option<&str>,
+        reasoning: Option<&str>,
+        usage_prompt_tokens: Option<i64>,
+        usage_completion_tokens: Option<i64>,
+        usage_total_tokens: Option<i64>,
+        sources: Option<&str>,
+    ) -> sqlx::Result<i64> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+
+        let message = sqlx::query!(
+            r#"
+            INSERT INTO messages (
+                message, thinking, tool_calls, images, reasoning,
+                usage_prompt_tokens, usage_completion_tokens, usage_total_tokens, sources
+            )
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?) RETURNING id;
+            "#,
+            message,
+            thinking,
+            tool_calls,
+            images,
+            reasoning,
+            usage_prompt_tokens,
+            usage_completion_tokens,
+            usage_total_tokens,
+            sources
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let previous = sqlx::query!("SELECT ai_message_id FROM message_pairs WHERE id = ?", pair_id)
+            .fetch_optional(&mut *tx)
+            .await?
+            .and_then(|row| row.ai_message_id);
+
+        sqlx::query!(
+            r#"
+            UPDATE message_pairs
+            SET ai_message_id = ?
+            WHERE id = ?;
+            "#,
+            message.id,
+            pair_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        // A continued response replaces the partial one
+        if let Some(previous) = previous {
+            sqlx::query!(
+                r#"
+                DELETE FROM messages
+                WHERE id = ?1 AND NOT EXISTS (
+                    SELECT 1 FROM message_pairs WHERE human_message_id = ?1 OR ai_message_id = ?1
+                )
+                "#,
+                previous
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(message.id)
+    }
+
+    pub async fn set_finish_reason(&self, message_id: i64, finish_reason: &str) -> sqlx::Result<()> {
+        sqlx::query!(
+            "UPDATE messages SET finish_reason = ? WHERE id = ?",
+            finish_reason,
+            message_id
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(())
+    }
+
+    // Also copies the response's tokens to its pair, priced by the model
+    pub async fn set_response_stats(&self, message_id: i64, model: &str, latency_ms: i64) -> sqlx::Result<()> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+
+        let usage = sqlx::query!(
+            r#"
+            UPDATE messages SET model = ?, latency_ms = ? WHERE id = ?
+            RETURNING usage_prompt_tokens, usage_completion_tokens
+            "#,
+            model,
+            latency_ms,
+            message_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        if let Some(usage) = usage {
+            let cost = match (usage.usage_prompt_tokens, usage.usage_completion_tokens) {
+                (Some(prompt), Some(completion)) => pricing::cost(model, prompt, completion),
+                _ => None,
+            };
+            sqlx::query!(
+                "UPDATE message_pairs SET prompt_tokens = ?, completion_tokens = ?, cost = ? WHERE ai_message_id = ?",
+                usage.usage_prompt_tokens,
+                usage.usage_completion_tokens,
+                cost,
+                message_id
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+        Ok(())
+    }
+
+    pub async fn add_message_block(&self, chat_id: i64, human_message: &str) -> sqlx::Result<i64> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+        let pair_id = self.insert_message_block(&mut tx, chat_id, human_message).await?;
+        tx.commit().await?;
+        Ok(pair_id)
+    }
+
+    pub async fn add_message_with_attachments(
+        &self,
+        chat_id: i64,
+        human_message: &str,
+        attachments: &[NewAttachment],
+    ) -> sqlx::Result<i64> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+        let pair_id = self.insert_message_block(&mut tx, chat_id, human_message).await?;
+        for attachment in attachments {
+            sqlx::query!(
+                r#"
+                INSERT INTO attachments (message_pair_id, name, path, mime, size, kind)
+                VALUES (?, ?, ?, ?, ?, ?)
+                "#,
+                pair_id,
+                attachment.name,
+                attachment.path,
+                attachment.mime,
+                attachment.size,
+                attachment.kind
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(pair_id)
+    }
+
+    // Attachments of every version of every message in the chat, oldest first
+    pub async fn get_chat_attachments(&self, chat_id: i64) -> sqlx::Result<Vec<Attachment>> {
+        sqlx::query_as!(
+            Attachment,
+            r#"
+            SELECT
+                attachments.id AS "id!", attachments.message_pair_id, attachments.name,
+                attachments.path, attachments.mime, attachments.size, attachments.kind
+            FROM attachments
+            JOIN message_pairs ON message_pairs.id = attachments.message_pair_id
+            JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+            WHERE message_blocks.chat_id = ?
+            ORDER BY attachments.id
+            "#,
+            chat_id
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    // The streams below read a user's chats a row at a time for the data
+    // export, so the archive is written without the whole history in memory
+    pub fn stream_message_blocks(&self, user_id: i64) -> BoxStream<'_, sqlx::Result<ExportedBlock>> {
+        // The query borrows `user_id`, so it runs inside the stream
+        Box::pin(async_stream::try_stream! {
+            let mut rows = sqlx::query_as!(
+                ExportedBlock,
+                r#"
+                SELECT
+                    message_blocks.id AS "id!", message_blocks.chat_id, message_blocks.selected_pair_id,
+                    message_blocks.parent_pair_id,
+                    message_blocks.created_at AS "created_at: DateTime<Utc>"
+                FROM message_blocks
+                JOIN chats ON chats.id = message_blocks.chat_id
+                WHERE chats.user_id = ?
+                ORDER BY message_blocks.id
+                "#,
+                user_id
+            )
+            .fetch(&*self.pool);
+            while let Some(row) = rows.try_next().await? {
+                yield row;
+            }
+        })
+    }
+
+    pub fn stream_message_pairs(&self, user_id: i64) -> BoxStream<'_, sqlx::Result<ExportedPair>> {
+        Box::pin(async_stream::try_stream! {
+            let mut rows = sqlx::query_as!(
+                ExportedPair,
+                r#"
+                SELECT
+                    message_pairs.id AS "id!", message_pairs.message_block_id,
+                    human.message AS human_message, ai.message AS "ai_message?",
+                    ai.thinking AS "thinking?", ai.reasoning AS "reasoning?",
+                    ai.tool_calls AS "tool_calls?", ai.images AS "images?", ai.sources AS "sources?",
+                    ai.finish_reason AS "finish_reason?", ai.model AS "model?",
+                    ai.latency_ms AS "latency_ms?",
+                    message_pairs.prompt_tokens, message_pairs.completion_tokens, message_pairs.cost,
+                    message_pairs.created_at AS "created_at: DateTime<Utc>"
+                FROM message_pairs
+                JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+                JOIN chats ON chats.id = message_blocks.chat_id
+                JOIN messages human ON human.id = message_pairs.human_message_id
+                LEFT JOIN messages ai ON ai.id = message_pairs.ai_message_id
+                WHERE chats.user_id = ?
+                ORDER BY message_pairs.id
+                "#,
+                user_id
+            )
+            .fetch(&*self.pool);
+            while let Some(row) = rows.try_next().await? {
+                yield row;
+            }
+        })
+    }
+
+    pub fn stream_attachments(&self, user_id: i64) -> BoxStream<'_, sqlx::Result<Attachment>> {
+        Box::pin(async_stream::try_stream! {
+            let mut rows = sqlx::query_as!(
+                Attachment,
+                r#"
+                SELECT
+                    attachments.id AS "id!", attachments.message_pair_id, attachments.name,
+                    attachments.path, attachments.mime, attachments.size, attachments.kind
+                FROM attachments
+                JOIN message_pairs ON message_pairs.id = attachments.message_pair_id
+                JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+                JOIN chats ON chats.id = message_blocks.chat_id
+                WHERE chats.user_id = ?
+                ORDER BY attachments.id
+                "#,
+                user_id
+            )
+            .fetch(&*self.pool);
+            while let Some(row) = rows.try_next().await? {
+                yield row;
+            }
+        })
+    }
+
+    // Returns the removed file's path when no other attachment still uses it,
+    // so the caller can delete it from `uploads/`
+    pub async fn delete_attachment(&self, chat_id: i64, attachment_id: i64) -> sqlx::Result<Option<String>> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+
+        let Some(path) = sqlx::query_scalar!(
+            r#"
+            DELETE FROM attachments
+            WHERE id = ? AND message_pair_id IN (
+                SELECT message_pairs.id FROM message_pairs
+                JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+                WHERE message_blocks.chat_id = ?
+            )
+            RETURNING path
+            "#,
+            attachment_id,
+            chat_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Ok(None);
+        };
+
+        let in_use = sqlx::query_scalar!(
+            r#"SELECT EXISTS (SELECT 1 FROM attachments WHERE path = ?) AS "in_use!: bool""#,
+            path
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok((!in_use).then_some(path))
+    }
+
+    async fn insert_message_block(
+        &self,
+        tx: &mut Transaction<'_, Sqlite>,
+        chat_id: i64,
+        human_message: &str,
+    ) -> sqlx::Result<i64> {
+        // New blocks continue the pair currently at the end of the conversation
+        let message_block = sqlx::query!(
+            r#"
+            INSERT INTO message_blocks (chat_id, parent_pair_id)
+            VALUES (?1, (SELECT id FROM v_chat_messages WHERE chat_id = ?1 ORDER BY message_block_id DESC LIMIT 1))
+            RETURNING id;
+            "#,
+            chat_id,
+        )
+        .fetch_one(&mut **tx)
+        .await?;
+
+        let message = sqlx::query!(
+            r#"
+            INSERT INTO messages (message)
+            VALUES (?) RETURNING id;
+            "#,
+            human_message
+        )
+        .fetch_one(&mut **tx)
+        .await?;
+
+        let message_pair = sqlx::query!(
+            r#"
+            INSERT INTO message_pairs (human_message_id, message_block_id)
+            VALUES (?, ?) RETURNING id;
+            "#,
+            message.id,
+            message_block.id,
+        )
+        .fetch_one(&mut **tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            UPDATE message_blocks
+            SET selected_pair_id = ?
+            WHERE id = ?;
+            "#,
+            message_pair.id,
+            message_block.id
+        )
+        .execute(&mut **tx)
+        .await?;
+
+        Ok(message_pair.id.unwrap())
+    }
+
+    // Recreate an exported conversation as a new chat in a single transaction
+    // An edit becomes a new version of the pair's block; the blocks that followed
+    // the old version stay on its branch. Returns the new pair, which has no AI
+    // message yet
+    pub async fn edit_human_message(
+        &self,
+        chat_id: i64,
+        pair_id: i64,
+        human_message: &str,
+    ) -> sqlx::Result<Option<i64>> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+
+        let Some(block) = sqlx::query!(
+            r#"
+            SELECT message_block_id AS "id!"
+            FROM v_chat_messages
+            WHERE id = ? AND chat_id = ?
+            "#,
+            pair_id,
+            chat_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Ok(None);
+        };
+
+        let message = sqlx::query!(
+            r#"
+            INSERT INTO messages (message)
+            VALUES (?) RETURNING id;
+            "#,
+            human_message
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        let message_pair = sqlx::query!(
+            r#"
+            INSERT INTO message_pairs (human_message_id, message_block_id)
+            VALUES (?, ?) RETURNING id AS "id!";
+            "#,
+            message.id,
+            block.id,
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            "UPDATE message_blocks SET selected_pair_id = ? WHERE id = ?",
+            message_pair.id,
+            block.id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        // The new version keeps the files sent with the old one
+        self.copy_attachments(&mut tx, pair_id, message_pair.id).await?;
+
+        tx.commit().await?;
+
+        Ok(Some(message_pair.id))
+    }
+
+    // `rank` counts versions the same way as `block_rank` in `v_chat_messages`
+    pub async fn select_block_version(
+        &self,
+        chat_id: i64,
+        user_id: i64,
+        block_id: i64,
+        rank: i64,
+    ) -> sqlx::Result<u64> {
+        let offset = rank - 1;
+        let result = sqlx::query!(
+            r#"
+            UPDATE message_blocks SET selected_pair_id = (
+                SELECT id FROM message_pairs WHERE message_block_id = ?1 AND deleted_at IS NULL
+                ORDER BY created_at ASC, id ASC
+                LIMIT 1 OFFSET ?4
+            )
+            WHERE id = ?1 AND chat_id = ?2
+                AND ?4 >= 0
+                AND ?4 < (
+                    SELECT COUNT(*) FROM message_pairs WHERE message_block_id = ?1 AND deleted_at IS NULL
+                )
+                AND EXISTS (
+                    SELECT 1 FROM v_chat_access WHERE chat_id = ?2 AND user_id = ?3 AND role != 'viewer'
+                )
+            "#,
+            block_id,
+            chat_id,
+            user_id,
+            offset
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    // Moves the pair to the trash, see `purge_trashed_messages`. Trashing the
+    // only version of a block splices it out of the conversation; trashing one
+    // of several versions trashes that version's branch with it and selects
+    // the newest remaining one. Returns how many pairs went to the trash
+    pub async fn delete_message_pair(&self, chat_id: i64, user_id: i64, pair_id: i64) -> sqlx::Result<u64> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+
+        let Some(block) = sqlx::query!(
+            r#"
+            SELECT message_blocks.id AS "id!", message_blocks.parent_pair_id, message_blocks.selected_pair_id
+            FROM message_pairs
+            JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+            WHERE message_pairs.id = ?1 AND message_blocks.chat_id = ?2 AND message_pairs.deleted_at IS NULL
+                AND EXISTS (
+                    SELECT 1 FROM v_chat_access WHERE chat_id = ?2 AND user_id = ?3 AND role != 'viewer'
+                )
+            "#,
+            pair_id,
+            chat_id,
+            user_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Ok(0);
+        };
+
+        let replacement = sqlx::query!(
+            r#"
+            SELECT id AS "id!" FROM message_pairs
+            WHERE message_block_id = ? AND id != ? AND deleted_at IS NULL
+            ORDER BY created_at DESC, id DESC
+            LIMIT 1
+            "#,
+            block.id,
+            pair_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        match &replacement {
+            Some(replacement) if block.selected_pair_id == Some(pair_id) => {
+                sqlx::query!(
+                    "UPDATE message_blocks SET selected_pair_id = ? WHERE id = ?",
+                    replacement.id,
+                    block.id
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
+            Some(_) => {}
+            None => {
+                sqlx::query!(
+                    "UPDATE message_blocks SET parent_pair_id = ? WHERE parent_pair_id = ?",
+                    block.parent_pair_id,
+                    pair_id
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
+        }
+
+        // Confirmations and embeddings aren't kept in the trash
+        let doomed = sqlx::query!(
+            r#"
+            WITH RECURSIVE doomed(pair_id) AS (
+                SELECT ?
+                UNION ALL
+                SELECT message_pairs.id FROM doomed
+                JOIN message_blocks ON message_blocks.parent_pair_id = doomed.pair_id
+                JOIN message_pairs ON message_pairs.message_block_id = message_blocks.id
+            )
+            SELECT json_group_array(pair_id) AS "ids!: String" FROM doomed
+            "#,
+            pair_id
+        )
+        .fetch_one(&mut *tx)
+        .await?
+        .ids;
+        sqlx::query!(
+            "DELETE FROM tool_call_confirmations WHERE message_pair_id IN (SELECT value FROM json_each(?))",
+            doomed
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!("DELETE FROM message_chunks WHERE pair_id IN (SELECT value FROM json_each(?))", doomed)
+            .execute(&mut *tx)
+            .await?;
+
+        let result = sqlx::query!(
+            r#"
+            UPDATE message_pairs SET deleted_at = CURRENT_TIMESTAMP
+            WHERE id IN (SELECT value FROM json_each(?)) AND deleted_at IS NULL
+            "#,
+            doomed
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(result.rows_affected())
+    }
+
+    // Permanently deletes pairs that have been in the trash for
+    // `retention_days`, with their blocks once empty and their messages
+    pub async fn purge_trashed_messages(&self, retention_days: i64) -> sqlx::Result<u64> {
+        let cutoff = format!("-{} days", retention_days);
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+
+        let trashed = sqlx::query!(
+            r#"
+            SELECT
+                COUNT(*) AS "count!: i64",
+                json_group_array(id) AS "pair_ids!: String",
+                json_group_array(message_block_id) AS "block_ids!: String",
+                json_group_array(human_message_id) AS "human_message_ids!: String",
+                json_group_array(ai_message_id) AS "ai_message_ids!: String"
+            FROM message_pairs WHERE deleted_at <= datetime('now', ?)
+            "#,
+            cutoff
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            "DELETE FROM tool_call_confirmations WHERE message_pair_id IN (SELECT value FROM json_each(?))",
+            trashed.pair_ids
+        )
+        .execute(&mut *tx)
+        .await?;
+        // Pairs of a trashed version's branch also go with their block
+        sqlx::query!(
+            "DELETE FROM message_pairs WHERE id IN (SELECT value FROM json_each(?))",
+            trashed.pair_ids
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            r#"
+            DELETE FROM message_blocks
+            WHERE id IN (SELECT value FROM json_each(?))
+                AND NOT EXISTS (SELECT 1 FROM message_pairs WHERE message_block_id = message_blocks.id)
+            "#,
+            trashed.block_ids
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(
+            r#"
+            DELETE FROM messages
+            WHERE (id IN (SELECT value FROM json_each(?1)) OR id IN (SELECT value FROM json_each(?2)))
+                AND NOT EXISTS (SELECT 1 FROM message_pairs WHERE human_message_id = messages.id)
+                AND NOT EXISTS (SELECT 1 FROM message_pairs WHERE ai_message_id = messages.id)
+            "#,
+            trashed.human_message_ids,
+            trashed.ai_message_ids
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(trashed.count as u64)
+    }
+
+    // Chats without a row use the defaults
+    pub async fn get_chat_settings(&self, chat_id: i64) -> sqlx::Result<ChatSettings> {
+        let settings = sqlx::query_as!(
+            ChatSettings,
+            r#"
+            SELECT chat_id, stop_sequences, temperature, top_p, max_tokens, model
+            FROM chat_settings WHERE chat_id = ?
+            "#,
+            chat_id
+        )
+        .fetch_optional(&*self.pool)
+        .await?;
+
+        Ok(settings.unwrap_or(ChatSettings {
+            chat_id,
+            ..Default::default()
+        }))
+    }
+
+    pub async fn save_chat_settings(&self, user_id: i64, settings: &ChatSettings) -> sqlx::Result<u64> {
+        let result = sqlx::query!(
+            r#"
+            INSERT INTO chat_settings (chat_id, stop_sequences, temperature, top_p, max_tokens)
+            SELECT chat_id, ?3, ?4, ?5, ?6 FROM v_chat_access
+            WHERE chat_id = ?1 AND user_id = ?2 AND role != 'viewer'
+            ON CONFLICT (chat_id) DO UPDATE SET
+                stop_sequences = excluded.stop_sequences,
+                temperature = excluded.temperature,
+                top_p = excluded.top_p,
+                max_tokens = excluded.max_tokens
+            "#,
+            settings.chat_id,
+            user_id,
+            settings.stop_sequences,
+            settings.temperature,
+            settings.top_p,
+            settings.max_tokens
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    // Leaves the drawer settings alone, and `save_chat_settings` leaves the model
+    pub async fn set_chat_model(&self, chat_id: i64, user_id: i64, model: &str) -> sqlx::Result<u64> {
+        let result = sqlx::query!(
+            r#"
+            INSERT INTO chat_settings (chat_id, model)
+            SELECT chat_id, ?3 FROM v_chat_access
+            WHERE chat_id = ?1 AND user_id = ?2 AND role != 'viewer'
+            ON CONFLICT (chat_id) DO UPDATE SET model = excluded.model
+            "#,
+            chat_id,
+            user_id,
+            model
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    // Copies every block, version and message so the two chats can diverge
+    // independently. Returns `None` if the chat isn't the user's
+    pub async fn duplicate_chat(&self, chat_id: i64, user_id: i64) -> sqlx::Result<Option<i64>> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+
+        let Some(copy) = sqlx::query!(
+            r#"
+            INSERT INTO chats (user_id, name, model, folder_id, agent_id)
+            SELECT user_id, name || ' (copy)', model, folder_id, agent_id
+            FROM chats WHERE id = ? AND user_id = ?
+            RETURNING id AS "id!"
+            "#,
+            chat_id,
+            user_id
+        )
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Ok(None);
+        };
+
+        sqlx::query!(
+            r#"
+            INSERT INTO chat_settings (chat_id, stop_sequences, temperature, top_p, max_tokens, model)
+            SELECT ?, stop_sequences, temperature, top_p, max_tokens, model
+            FROM chat_settings WHERE chat_id = ?
+            "#,
+            copy.id,
+            chat_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        let pairs = sqlx::query!(
+            r#"
+            SELECT
+                message_pairs.id AS "id!", message_pairs.human_message_id, message_pairs.ai_message_id,
+                message_pairs.created_at, message_blocks.id AS "block_id!",
+                message_blocks.parent_pair_id, message_blocks.selected_pair_id
+            FROM message_pairs
+            JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+            WHERE message_blocks.chat_id = ? AND message_pairs.deleted_at IS NULL
+            ORDER BY message_blocks.id, message_pairs.id
+            "#,
+            chat_id
+        )
+        .fetch_all(&mut *tx)
+        .await?;
+
+        // Parents always live in earlier blocks, so they are copied first
+        let mut pair_ids: HashMap<i64, i64> = HashMap::new();
+        let mut current_block: Option<(i64, i64)> = None;
+        for pair in &pairs {
+            let new_block_id = match current_block {
+                Some((old, new)) if old == pair.block_id => new,
+                _ => {
+                    let parent_pair_id = pair.parent_pair_id.and_then(|id| pair_ids.get(&id).copied());
+                    let block = sqlx::query!(
+                        r#"
+                        INSERT INTO message_blocks (chat_id, parent_pair_id)
+                        VALUES (?, ?) RETURNING id AS "id!"
+                        "#,
+                        copy.id,
+                        parent_pair_id
+                    )
+                    .fetch_one(&mut *tx)
+                    .await?;
+                    current_block = Some((pair.block_id, block.id));
+                    block.id
+                }
+            };
+
+            let human_message_id = self.copy_message(&mut tx, pair.human_message_id).await?;
+            let ai_message_id = match pair.ai_message_id {
+                Some(id) => Some(self.copy_message(&mut tx, id).await?),
+                None => None,
+            };
+
+            let new_pair = sqlx::query!(
+                r#"
+                INSERT INTO message_pairs (human_message_id, ai_message_id, message_block_id, created_at)
+                VALUES (?, ?, ?, ?) RETURNING id AS "id!"
+                "#,
+                human_message_id,
+                ai_message_id,
+                new_block_id,
+                pair.created_at
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+            pair_ids.insert(pair.id, new_pair.id);
+            self.copy_attachments(&mut tx, pair.id, new_pair.id).await?;
+
+            if pair.selected_pair_id == Some(pair.id) {
+                sqlx::query!(
+                    "UPDATE message_blocks SET selected_pair_id = ? WHERE id = ?",
+                    new_pair.id,
+                    new_block_id
+                )
+                .execute(&mut *tx)
+                .await?;
+            }
+        }
+
+        tx.commit().await?;
+
+        self.cache.chats_changed();
+        Ok(Some(copy.id))
+    }
+
+    async fn copy_message(&self, tx: &mut Transaction<'_, Sqlite>, message_id: i64) -> sqlx::Result<i64> {
+        let message = sqlx::query!(
+            r#"
+            INSERT INTO messages (
+                message, thinking, tool_calls, images, reasoning, usage_prompt_tokens,
+                usage_completion_tokens, usage_total_tokens, sources, finish_reason, created_at
+            )
+            SELECT
+                message, thinking, tool_calls, images, reasoning, usage_prompt_tokens,
+                usage_completion_tokens, usage_total_tokens, sources, finish_reason, created_at
+            FROM messages WHERE id = ?
+            RETURNING id AS "id!"
+            "#,
+            message_id
+        )
+        .fetch_one(&mut **tx)
+        .await?;
+        Ok(message.id)
+    }
+
+    // The copies share the stored files
+    async fn copy_attachments(&self, tx: &mut Transaction<'_, Sqlite>, from_pair_id: i64, to_pair_id: i64) -> sqlx::Result<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO attachments (message_pair_id, name, path, mime, size, kind, created_at)
+            SELECT ?, name, path, mime, size, kind, created_at
+            FROM attachments WHERE message_pair_id = ?
+            ORDER BY id
+            "#,
+            to_pair_id,
+            from_pair_id
+        )
+        .execute(&mut **tx)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn import_chat(&self, user_id: i64, chat: &ExportedChat) -> sqlx::Result<i64> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+
+        let model = chat
+            .messages
+            .first()
+            .map(|m| m.model.as_str())
+            .unwrap_or("imported");
+        let chat_id = sqlx::query!(
+            r#"
+            INSERT INTO chats (user_id, name, model)
+            VALUES (?, ?, ?) RETURNING id;
+            "#,
+            user_id,
+            chat.name,
+            model
+        )
+        .fetch_one(&mut *tx)
+        .await?
+        .id;
+
+        let mut parent_pair_id: Option<i64> = None;
+        for message in &chat.messages {
+            let message_block = sqlx::query!(
+                r#"
+                INSERT INTO message_blocks (chat_id, parent_pair_id)
+                VALUES (?, ?) RETURNING id;
+                "#,
+                chat_id,
+                parent_pair_id,
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+
+            let human_message = sqlx::query!(
+                r#"
+                INSERT INTO messages (message)
+                VALUES (?) RETURNING id;
+                "#,
+                message.human
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+
+            let ai_message_id = match &message.ai {
+                Some(ai) => {
+                    let tool_calls = (!message.tool_calls.is_empty())
+                        .then(|| serde_json::to_string(&message.tool_calls).ok())
+                        .flatten();
+                    let images = (!message.images.is_empty())
+                        .then(|| serde_json::to_string(&message.images).ok())
+                        .flatten();
+                    let sources = (!message.sources.is_empty())
+                        .then(|| serde_json::to_string(&message.sources).ok())
+                        .flatten();
+                    let prompt_tokens = message.usage.as_ref().map(|u| u.prompt_tokens);
+                    let completion_tokens = message.usage.as_ref().map(|u| u.completion_tokens);
+                    let total_tokens = message.usage.as_ref().map(|u| u.total_tokens);
+
+                    let ai_message = sqlx::query!(
+                        r#"
+                        INSERT INTO messages (
+                            message, thinking, tool_calls, images, reasoning,
+                            usage_prompt_tokens, usage_completion_tokens, usage_total_tokens, sources
+                        )
+                        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?) RETURNING id;
+                        "#,
+                        ai,
+                        message.thinking,
+                        tool_calls,
+                        images,
+                        message.reasoning,
+                        prompt_tokens,
+                        completion_tokens,
+                        total_tokens,
+                        sources
+                    )
+                    .fetch_one(&mut *tx)
+                    .await?;
+                    Some(ai_message.id)
+                }
+                None => None,
+            };
+
+            let message_pair = sqlx::query!(
+                r#"
+                INSERT INTO message_pairs (human_message_id, ai_message_id, message_block_id)
+                VALUES (?, ?, ?) RETURNING id;
+                "#,
+                human_message.id,
+                ai_message_id,
+                message_block.id,
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+
+            sqlx::query!(
+                r#"
+                UPDATE message_blocks
+                SET selected_pair_id = ?
+                WHERE id = ?;
+                "#,
+                message_pair.id,
+                message_block.id
+            )
+            .execute(&mut *tx)
+            .await?;
+
+            parent_pair_id = message_pair.id;
+        }
+
+        tx.commit().await?;
+
+        self.cache.chats_changed();
+        Ok(chat_id.unwrap())
+    }
+
+    pub async fn sample_ai_messages(&self, limit: i64) -> sqlx::Result<Vec<StoredMessage>> {
+        sqlx::query_as!(
+            StoredMessage,
+            r#"
+            SELECT
+                messages.id AS "id!",
+                message_blocks.chat_id AS "chat_id!",
+                messages.message
+            FROM messages
+            JOIN message_pairs ON message_pairs.ai_message_id = messages.id
+            JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+            WHERE message_pairs.deleted_at IS NULL
+            ORDER BY RANDOM()
+            LIMIT ?
+            "#,
+            limit
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    // Matches in every chat the user can open, shared and org chats included
+    pub async fn search_messages(&self, user_id: i64, query: &str) -> sqlx::Result<Vec<SearchHit>> {
+        let match_query = fts_match_query(query);
+        if match_query.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        sqlx::query_as!(
+            SearchHit,
+            r#"
+            SELECT
+                chats.id AS "chat_id!",
+                chats.name AS chat_name,
+                message_pairs.id AS "pair_id!",
+                snippet(messages_fts, 0, char(2), char(3), '…', 16) AS "snippet!: String"
+            FROM messages_fts
+            JOIN message_pairs
+                ON message_pairs.human_message_id = messages_fts.rowid
+                OR message_pairs.ai_message_id = messages_fts.rowid
+            JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+            JOIN chats ON chats.id = message_blocks.chat_id
+            WHERE messages_fts MATCH ? AND message_pairs.deleted_at IS NULL
+                AND EXISTS (SELECT 1 FROM v_chat_access WHERE chat_id = chats.id AND user_id = ?)
+            ORDER BY rank
+            LIMIT 50
+            "#,
+            match_query,
+            user_id
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    // Answered pairs without an embedding yet, oldest first
+    pub async fn unindexed_pairs(&self, limit: i64) -> sqlx::Result<Vec<UnindexedPair>> {
+        sqlx::query_as!(
+            UnindexedPair,
+            r#"
+            SELECT
+                message_pairs.id AS "pair_id!",
+                message_blocks.chat_id AS "chat_id!",
+                human.message AS human_message,
+                ai.message AS ai_message
+            FROM message_pairs
+            JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+            JOIN chats ON chats.id = message_blocks.chat_id
+            JOIN messages human ON human.id = message_pairs.human_message_id
+            JOIN messages ai ON ai.id = message_pairs.ai_message_id
+            LEFT JOIN message_chunks ON message_chunks.pair_id = message_pairs.id
+            WHERE message_chunks.id IS NULL AND chats.deleted_at IS NULL AND message_pairs.deleted_at IS NULL
+            ORDER BY message_pairs.id
+            LIMIT ?
+            "#,
+            limit
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    // Pairs removed since they were loaded are skipped
+    pub async fn save_chunk(&self, pair_id: i64, chat_id: i64, content: &str, embedding: &[f32]) -> sqlx::Result<()> {
+        let embedding = vector::encode(embedding);
+        sqlx::query!(
+            r#"
+            INSERT INTO message_chunks (pair_id, chat_id, content, embedding)
+            SELECT ?1, ?2, ?3, ?4
+            WHERE EXISTS (SELECT 1 FROM message_pairs WHERE id = ?1 AND deleted_at IS NULL)
+            ON CONFLICT (pair_id) DO UPDATE SET content = excluded.content, embedding = excluded.embedding
+            "#,
+            pair_id,
+            chat_id,
+            content,
+            embedding
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(())
+    }
+
+    // The `k` pairs closest to `embedding` in chats the user can open. Chunks
+    // of another dimension, from an earlier embedding model, are skipped
+    #[cfg(feature = "vector")]
+    pub async fn similar_chunks(&self, user_id: i64, embedding: &[f32], k: i64) -> sqlx::Result<Vec<SimilarChunk>> {
+        // Not a macro: `vec_distance_cosine` only exists once sqlite-vec is loaded
+        sqlx::query_as(
+            r#"
+            SELECT
+                message_chunks.chat_id,
+                chats.name AS chat_name,
+                message_chunks.pair_id,
+                message_chunks.content,
+                vec_distance_cosine(message_chunks.embedding, ?1) AS distance
+            FROM message_chunks
+            JOIN chats ON chats.id = message_chunks.chat_id
+            WHERE length(message_chunks.embedding) = length(?1)
+                AND EXISTS (SELECT 1 FROM v_chat_access WHERE chat_id = message_chunks.chat_id AND user_id = ?2)
+            ORDER BY distance
+            LIMIT ?3
+            "#,
+        )
+        .bind(vector::encode(embedding))
+        .bind(user_id)
+        .bind(k)
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    // Without sqlite-vec the user's chunks are ranked here instead
+    #[cfg(not(feature = "vector"))]
+    pub async fn similar_chunks(&self, user_id: i64, embedding: &[f32], k: i64) -> sqlx::Result<Vec<SimilarChunk>> {
+        let length = (embedding.len() * 4) as i64;
+        let rows = sqlx::query!(
+            r#"
+            SELECT
+                message_chunks.chat_id,
+                chats.name AS chat_name,
+                message_chunks.pair_id,
+                message_chunks.content,
+                message_chunks.embedding
+            FROM message_chunks
+            JOIN chats ON chats.id = message_chunks.chat_id
+            WHERE length(message_chunks.embedding) = ?1
+                AND EXISTS (SELECT 1 FROM v_chat_access WHERE chat_id = message_chunks.chat_id AND user_id = ?2)
+            "#,
+            length,
+            user_id
+        )
+        .fetch_all(&*self.pool)
+        .await?;
+
+        let mut chunks: Vec<SimilarChunk> = rows
+            .into_iter()
+            .map(|row| SimilarChunk {
+                distance: vector::cosine_distance(embedding, &vector::decode(&row.embedding)) as f64,
+                chat_id: row.chat_id,
+                chat_name: row.chat_name,
+                pair_id: row.pair_id,
+                content: row.content,
+            })
+            .collect();
+        chunks.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        chunks.truncate(k.max(0) as usize);
+        Ok(chunks)
+    }
+
+    // Analytics over the user's AI responses; `from` and `to` are inclusive YYYY-MM-DD dates
+    pub async fn daily_usage(&self, user_id: i64, from: &str, to: &str) -> sqlx::Result<Vec<DailyUsage>> {
+        sqlx::query_as!(
+            DailyUsage,
+            r#"
+            SELECT
+                date(messages.created_at) AS "day!: String",
+                COUNT(*) AS "responses!: i64",
+                COALESCE(SUM(messages.usage_prompt_tokens), 0) AS "prompt_tokens!: i64",
+                COALESCE(SUM(messages.usage_completion_tokens), 0) AS "completion_tokens!: i64",
+                COALESCE(SUM(messages.usage_total_tokens), 0) AS "total_tokens!: i64"
+            FROM messages
+            JOIN message_pairs ON message_pairs.ai_message_id = messages.id
+            JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+            JOIN chats ON chats.id = message_blocks.chat_id
+            WHERE chats.user_id = ? AND date(messages.created_at) BETWEEN ? AND ?
+            GROUP BY date(messages.created_at)
+            ORDER BY date(messages.created_at)
+            "#,
+            user_id,
+            from,
+            to
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    // What counts against the quota of the chats' owner: messages sent and
+    // tokens generated since the start of the UTC day and month
+    pub async fn usage_totals(&self, user_id: i64) -> sqlx::Result<Usage> {
+        sqlx::query_as!(
+            Usage,
+            r#"
+            WITH pairs AS (
+                SELECT message_pairs.created_at, messages.created_at AS answered_at,
+                    messages.usage_total_tokens
+                FROM message_pairs
+                JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+                JOIN chats ON chats.id = message_blocks.chat_id
+                LEFT JOIN messages ON messages.id = message_pairs.ai_message_id
+                WHERE COALESCE(message_pairs.billed_user_id, chats.user_id) = ?1
+                    AND message_pairs.created_at >= date('now', 'start of month')
+            )
+            SELECT
+                COALESCE(SUM(CASE WHEN answered_at >= date('now') THEN usage_total_tokens END), 0)
+                    AS "daily_tokens!: i64",
+                COALESCE(SUM(usage_total_tokens), 0) AS "monthly_tokens!: i64",
+                COUNT(CASE WHEN created_at >= date('now') THEN 1 END) AS "daily_messages!: i64",
+                COUNT(*) AS "monthly_messages!: i64"
+            FROM pairs
+            "#,
+            user_id
+        )
+        .fetch_one(&*self.pool)
+        .await
+    }
+
+    // Charges the pair's response to the user it's generated for
+    pub async fn bill_message_pair(&self, pair_id: i64, user_id: i64) -> sqlx::Result<()> {
+        sqlx::query!("UPDATE message_pairs SET billed_user_id = ? WHERE id = ?", user_id, pair_id)
+            .execute(&*self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get_quota_override(&self, user_id: i64) -> sqlx::Result<Option<QuotaOverride>> {
+        sqlx::query_as!(
+            QuotaOverride,
+            r#"
+            SELECT daily_tokens, monthly_tokens, daily_messages, monthly_messages
+            FROM user_quotas
+            WHERE user_id = ?
+            "#,
+            user_id
+        )
+        .fetch_optional(&*self.pool)
+        .await
+    }
+
+    // An override with nothing set is removed
+    pub async fn set_quota_override(&self, user_id: i64, quota: &QuotaOverride) -> sqlx::Result<()> {
+        let empty = quota.daily_tokens.is_none()
+            && quota.monthly_tokens.is_none()
+            && quota.daily_messages.is_none()
+            && quota.monthly_messages.is_none();
+        if empty {
+            sqlx::query!("DELETE FROM user_quotas WHERE user_id = ?", user_id)
+                .execute(&*self.pool)
+                .await?;
+            return Ok(());
+        }
+        sqlx::query!(
+            r#"
+            INSERT INTO user_quotas (user_id, daily_tokens, monthly_tokens, daily_messages, monthly_messages)
+            VALUES (?, ?, ?, ?, ?)
+            ON CONFLICT (user_id) DO UPDATE SET
+                daily_tokens = excluded.daily_tokens,
+                monthly_tokens = excluded.monthly_tokens,
+                daily_messages = excluded.daily_messages,
+                monthly_messages = excluded.monthly_messages,
+                updated_at = CURRENT_TIMESTAMP
+            "#,
+            user_id,
+            quota.daily_tokens,
+            quota.monthly_tokens,
+            quota.daily_messages,
+            quota.monthly_messages
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(())
+    }
+
+    // Every response in the chat, all versions of edited messages included
+    pub async fn chat_usage(&self, chat_id: i64) -> sqlx::Result<PairUsage> {
+        sqlx::query_as!(
+            PairUsage,
+            r#"
+            SELECT
+                COUNT(message_pairs.ai_message_id) AS "responses!: i64",
+                COALESCE(SUM(message_pairs.prompt_tokens), 0) AS "prompt_tokens!: i64",
+                COALESCE(SUM(message_pairs.completion_tokens), 0) AS "completion_tokens!: i64",
+                COALESCE(SUM(message_pairs.cost), 0.0) AS "cost!: f64"
+            FROM message_pairs
+            JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+            WHERE message_blocks.chat_id = ?
+            "#,
+            chat_id
+        )
+        .fetch_one(&*self.pool)
+        .await
+    }
+
+    // Over the chats the user owns, by the UTC day each message was sent
+    pub async fn user_usage(&self, user_id: i64, from: &str, to: &str) -> sqlx::Result<PairUsage> {
+        sqlx::query_as!(
+            PairUsage,
+            r#"
+            SELECT
+                COUNT(message_pairs.ai_message_id) AS "responses!: i64",
+                COALESCE(SUM(message_pairs.prompt_tokens), 0) AS "prompt_tokens!: i64",
+                COALESCE(SUM(message_pairs.completion_tokens), 0) AS "completion_tokens!: i64",
+                COALESCE(SUM(message_pairs.cost), 0.0) AS "cost!: f64"
+            FROM message_pairs
+            JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+            JOIN chats ON chats.id = message_blocks.chat_id
+            WHERE chats.user_id = ? AND date(message_pairs.created_at) BETWEEN ? AND ?
+            "#,
+            user_id,
+            from,
+            to
+        )
+        .fetch_one(&*self.pool)
+        .await
+    }
+
+    // `user_usage` per day, leaving out days without responses
+    pub async fn daily_costs(&self, user_id: i64, from: &str, to: &str) -> sqlx::Result<Vec<DailyCost>> {
+        sqlx::query_as!(
+            DailyCost,
+            r#"
+            SELECT
+                date(message_pairs.created_at) AS "day!: String",
+                COUNT(message_pairs.ai_message_id) AS "responses!: i64",
+                COALESCE(SUM(message_pairs.prompt_tokens), 0) AS "prompt_tokens!: i64",
+                COALESCE(SUM(message_pairs.completion_tokens), 0) AS "completion_tokens!: i64",
+                COALESCE(SUM(message_pairs.cost), 0.0) AS "cost!: f64"
+            FROM message_pairs
+            JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+            JOIN chats ON chats.id = message_blocks.chat_id
+            WHERE chats.user_id = ? AND date(message_pairs.created_at) BETWEEN ? AND ?
+                AND message_pairs.ai_message_id IS NOT NULL
+            GROUP BY date(message_pairs.created_at)
+            ORDER BY date(message_pairs.created_at)
+            "#,
+            user_id,
+            from,
+            to
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    pub async fn model_usage(&self, user_id: i64, from: &str, to: &str) -> sqlx::Result<Vec<ModelUsage>> {
+        sqlx::query_as!(
+            ModelUsage,
+            r#"
+            SELECT
+                chats.model AS "model!: String",
+                COUNT(*) AS "responses!: i64",
+                COALESCE(SUM(messages.usage_prompt_tokens), 0) AS "prompt_tokens!: i64",
+                COALESCE(SUM(messages.usage_completion_tokens), 0) AS "completion_tokens!: i64",
+                COALESCE(SUM(messages.usage_total_tokens), 0) AS "total_tokens!: i64"
+            FROM messages
+            JOIN message_pairs ON message_pairs.ai_message_id = messages.id
+            JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+            JOIN chats ON chats.id = message_blocks.chat_id
+            WHERE chats.user_id = ? AND date(messages.created_at) BETWEEN ? AND ?
+            GROUP BY chats.model
+            ORDER BY 5 DESC
+            "#,
+            user_id,
+            from,
+            to
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    pub async fn tool_usage(&self, user_id: i64, from: &str, to: &str) -> sqlx::Result<Vec<ToolUsage>> {
+        sqlx::query_as!(
+            ToolUsage,
+            r#"
+            SELECT
+                json_extract(tool_call.value, '$.function.name') AS "tool!: String",
+                COUNT(*) AS "invocations!: i64"
+            FROM messages
+            JOIN json_each(messages.tool_calls) AS tool_call
+            JOIN message_pairs ON message_pairs.ai_message_id = messages.id
+            JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+            JOIN chats ON chats.id = message_blocks.chat_id
+            WHERE chats.user_id = ?
+                AND date(messages.created_at) BETWEEN ? AND ?
+                AND json_valid(messages.tool_calls)
+            GROUP BY 1
+            ORDER BY 2 DESC
+            "#,
+            user_id,
+            from,
+            to
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    // Sent messages across every version of the chat
+    pub async fn count_chat_messages(&self, chat_id: i64) -> sqlx::Result<i64> {
+        sqlx::query_scalar!(
+            r#"
+            SELECT COUNT(DISTINCT message_pairs.human_message_id) AS "count!: i64"
+            FROM message_pairs
+            JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+            WHERE message_blocks.chat_id = ? AND message_pairs.deleted_at IS NULL
+            "#,
+            chat_id
+        )
+        .fetch_one(&*self.pool)
+        .await
+    }
+
+    // Responses saved before their model was recorded fall back to the chat's
+    pub async fn chat_model_stats(&self, chat_id: i64) -> sqlx::Result<Vec<ChatModelStats>> {
+        sqlx::query_as!(
+            ChatModelStats,
+            r#"
+            SELECT
+                COALESCE(messages.model, chats.model) AS "model!: String",
+                COUNT(*) AS "responses!: i64",
+                COALESCE(SUM(messages.usage_prompt_tokens), 0) AS "prompt_tokens!: i64",
+                COALESCE(SUM(messages.usage_completion_tokens), 0) AS "completion_tokens!: i64",
+                COALESCE(SUM(messages.usage_total_tokens), 0) AS "total_tokens!: i64",
+                COUNT(messages.latency_ms) AS "timed_responses!: i64",
+                AVG(messages.latency_ms) AS "avg_latency_ms?: f64",
+                NULL AS "cost?: f64"
+            FROM messages
+            JOIN message_pairs ON message_pairs.ai_message_id = messages.id
+            JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+            JOIN chats ON chats.id = message_blocks.chat_id
+            WHERE chats.id = ?
+            GROUP BY 1
+            ORDER BY 5 DESC
+            "#,
+            chat_id
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    // `None` clears the user's rating of the pair
+    pub async fn set_feedback(
+        &self,
+        user_id: i64,
+        chat_id: i64,
+        pair_id: i64,
+        rating: Option<i64>,
+    ) -> sqlx::Result<u64> {
+        let result = match rating {
+            Some(rating) => {
+                sqlx::query!(
+                    r#"
+                    INSERT INTO message_feedback (user_id, message_pair_id, rating)
+                    SELECT ?1, message_pairs.id, ?4
+                    FROM message_pairs
+                    JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+                    WHERE message_pairs.id = ?3 AND message_blocks.chat_id = ?2
+                        AND message_pairs.deleted_at IS NULL
+                        AND EXISTS (SELECT 1 FROM v_chat_access WHERE chat_id = ?2 AND user_id = ?1)
+                    ON CONFLICT (user_id, message_pair_id) DO UPDATE SET rating = excluded.rating
+                    "#,
+                    user_id,
+                    chat_id,
+                    pair_id,
+                    rating
+                )
+                .execute(&*self.pool)
+                .await?
+            }
+            None => {
+                sqlx::query!(
+                    "DELETE FROM message_feedback WHERE user_id = ? AND message_pair_id = ?",
+                    user_id,
+                    pair_id
+                )
+                .execute(&*self.pool)
+                .await?
+            }
+        };
+        Ok(result.rows_affected())
+    }
+
+    pub async fn get_feedback(&self, user_id: i64, chat_id: i64) -> sqlx::Result<Vec<MessageFeedback>> {
+        sqlx::query_as!(
+            MessageFeedback,
+            r#"
+            SELECT message_feedback.message_pair_id AS pair_id, message_feedback.rating
+            FROM message_feedback
+            JOIN message_pairs ON message_pairs.id = message_feedback.message_pair_id
+            JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+            WHERE message_feedback.user_id = ? AND message_blocks.chat_id = ?
+            "#,
+            user_id,
+            chat_id
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    // Returns the new state, or `None` if the pair has no answer or the user can't see it
+    pub async fn toggle_bookmark(
+        &self,
+        user_id: i64,
+        chat_id: i64,
+        pair_id: i64,
+    ) -> sqlx::Result<Option<bool>> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+        let removed = sqlx::query!(
+            "DELETE FROM message_bookmarks WHERE user_id = ? AND message_pair_id = ?",
+            user_id,
+            pair_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        if removed.rows_affected() > 0 {
+            tx.commit().await?;
+            return Ok(Some(false));
+        }
+
+        let added = sqlx::query!(
+            r#"
+            INSERT INTO message_bookmarks (user_id, message_pair_id)
+            SELECT ?1, message_pairs.id
+            FROM message_pairs
+            JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+            WHERE message_pairs.id = ?3 AND message_pairs.ai_message_id IS NOT NULL
+                AND message_blocks.chat_id = ?2 AND message_pairs.deleted_at IS NULL
+                AND EXISTS (SELECT 1 FROM v_chat_access WHERE chat_id = ?2 AND user_id = ?1)
+            "#,
+            user_id,
+            chat_id,
+            pair_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok((added.rows_affected() > 0).then_some(true))
+    }
+
+    pub async fn get_bookmarked_pairs(&self, user_id: i64, chat_id: i64) -> sqlx::Result<Vec<i64>> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT message_bookmarks.message_pair_id
+            FROM message_bookmarks
+            JOIN message_pairs ON message_pairs.id = message_bookmarks.message_pair_id
+            JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+            WHERE message_bookmarks.user_id = ? AND message_blocks.chat_id = ?
+            "#,
+            user_id,
+            chat_id
+        )
+        .fetch_all(&*self.pool)
+        .await?;
+        Ok(rows.into_iter().map(|row| row.message_pair_id).collect())
+    }
+
+    pub async fn get_bookmarks(&self, user_id: i64) -> sqlx::Result<Vec<Bookmark>> {
+        sqlx::query_as!(
+            Bookmark,
+            r#"
+            SELECT
+                message_pairs.id AS "pair_id!",
+                chats.id AS "chat_id!",
+                chats.name AS chat_name,
+                human.message AS human_message,
+                ai.message AS ai_message
+            FROM message_bookmarks
+            JOIN message_pairs ON message_pairs.id = message_bookmarks.message_pair_id
+            JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+            JOIN chats ON chats.id = message_blocks.chat_id
+            JOIN messages human ON human.id = message_pairs.human_message_id
+            JOIN messages ai ON ai.id = message_pairs.ai_message_id
+            WHERE message_bookmarks.user_id = ?1 AND message_pairs.deleted_at IS NULL
+                AND EXISTS (SELECT 1 FROM v_chat_access WHERE chat_id = chats.id AND user_id = ?1)
+            ORDER BY message_bookmarks.id DESC
+            "#,
+            user_id
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    pub async fn feedback_summary(&self, user_id: i64) -> sqlx::Result<Vec<FeedbackSummary>> {
+        sqlx::query_as!(
+            FeedbackSummary,
+            r#"
+            SELECT
+                chats.model,
+                SUM(message_feedback.rating = 1) AS "positive!: i64",
+                SUM(message_feedback.rating = -1) AS "negative!: i64"
+            FROM message_feedback
+            JOIN message_pairs ON message_pairs.id = message_feedback.message_pair_id
+            JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+            JOIN chats ON chats.id = message_blocks.chat_id
+            WHERE message_feedback.user_id = ?
+            GROUP BY chats.model
+            ORDER BY chats.model
+            "#,
+            user_id
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    // Curated templates first, then the user's own
+    pub async fn get_prompt_templates(&self, user_id: Option<i64>) -> sqlx::Result<Vec<PromptTemplate>> {
+        sqlx::query_as!(
+            PromptTemplate,
+            r#"
+            SELECT id AS "id!", user_id, title, description, prompt
+            FROM prompt_templates
+            WHERE user_id IS NULL OR user_id = ?
+            ORDER BY user_id IS NOT NULL, id
+            "#,
+            user_id
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    pub async fn get_prompt_template(
+        &self,
+        template_id: i64,
+        user_id: i64,
+    ) -> sqlx::Result<Option<PromptTemplate>> {
+        sqlx::query_as!(
+            PromptTemplate,
+            r#"
+            SELECT id AS "id!", user_id, title, description, prompt
+            FROM prompt_templates
+            WHERE id = ? AND (user_id IS NULL OR user_id = ?)
+            "#,
+            template_id,
+            user_id
+        )
+        .fetch_optional(&*self.pool)
+        .await
+    }
+
+    pub async fn create_prompt_template(
+        &self,
+        user_id: i64,
+        title: &str,
+        description: &str,
+        prompt: &str,
+    ) -> sqlx::Result<PromptTemplate> {
+        sqlx::query_as!(
+            PromptTemplate,
+            r#"
+            INSERT INTO prompt_templates (user_id, title, description, prompt)
+            VALUES (?, ?, ?, ?)
+            RETURNING id AS "id!", user_id, title, description, prompt
+            "#,
+            user_id,
+            title,
+            description,
+            prompt
+        )
+        .fetch_one(&*self.pool)
+        .await
+    }
+
+    // Curated templates can't be deleted
+    pub async fn delete_prompt_template(&self, template_id: i64, user_id: i64) -> sqlx::Result<u64> {
+        let result = sqlx::query!(
+            "DELETE FROM prompt_templates WHERE id = ? AND user_id = ?",
+            template_id,
+            user_id
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    // Returns `None` if the user can't edit the chat
+    pub async fn schedule_message(
+        &self,
+        user_id: i64,
+        chat_id: i64,
+        message: &str,
+        run_at: DateTime<Utc>,
+    ) -> sqlx::Result<Option<i64>> {
+        // Same format as SQLite's `datetime()`, which the scheduler compares against
+        let run_at = run_at.format("%Y-%m-%d %H:%M:%S").to_string();
+        let scheduled = sqlx::query!(
+            r#"
+            INSERT INTO scheduled_messages (user_id, chat_id, message, run_at)
+            SELECT user_id, chat_id, ?3, ?4 FROM v_chat_access
+            WHERE chat_id = ?1 AND user_id = ?2 AND role != 'viewer'
+            RETURNING id AS "id!"
+            "#,
+            chat_id,
+            user_id,
+            message,
+            run_at
+        )
+        .fetch_optional(&*self.pool)
+        .await?;
+        Ok(scheduled.map(|row| row.id))
+    }
+
+    pub async fn get_scheduled_messages(&self, user_id: i64) -> sqlx::Result<Vec<ScheduledMessage>> {
+        sqlx::query_as!(
+            ScheduledMessage,
+            r#"
+            SELECT
+                scheduled_messages.id AS "id!", scheduled_messages.user_id, scheduled_messages.chat_id,
+                chats.name AS chat_name, scheduled_messages.message,
+                scheduled_messages.run_at AS "run_at: DateTime<Utc>", scheduled_messages.status,
+                scheduled_messages.error
+            FROM scheduled_messages
+            JOIN chats ON chats.id = scheduled_messages.chat_id
+            WHERE scheduled_messages.user_id = ?
+            ORDER BY scheduled_messages.run_at DESC, scheduled_messages.id DESC
+            "#,
+            user_id
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    // Finished runs the user hasn't been told about yet
+    pub async fn get_scheduled_notifications(&self, user_id: i64) -> sqlx::Result<Vec<ScheduledMessage>> {
+        sqlx::query_as!(
+            ScheduledMessage,
+            r#"
+            SELECT
+                scheduled_messages.id AS "id!", scheduled_messages.user_id, scheduled_messages.chat_id,
+                chats.name AS chat_name, scheduled_messages.message,
+                scheduled_messages.run_at AS "run_at: DateTime<Utc>", scheduled_messages.status,
+                scheduled_messages.error
+            FROM scheduled_messages
+            JOIN chats ON chats.id = scheduled_messages.chat_id
+            WHERE scheduled_messages.user_id = ?
+                AND scheduled_messages.status IN ('done', 'failed')
+                AND NOT scheduled_messages.seen
+            ORDER BY scheduled_messages.run_at DESC
+            "#,
+            user_id
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    // Marks every due message as running so each one is picked up once
+    pub async fn claim_due_scheduled_messages(&self) -> sqlx::Result<Vec<ScheduledMessage>> {
+        sqlx::query_as!(
+            ScheduledMessage,
+            r#"
+            UPDATE scheduled_messages SET status = 'running'
+            WHERE status = 'pending' AND run_at <= datetime('now')
+            RETURNING
+                id AS "id!", user_id, chat_id,
+                (SELECT name FROM chats WHERE chats.id = scheduled_messages.chat_id) AS "chat_name!: String",
+                message, run_at AS "run_at: DateTime<Utc>", status, error
+            "#
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    pub async fn finish_scheduled_message(&self, id: i64, error: Option<&str>) -> sqlx::Result<()> {
+        sqlx::query!(
+            r#"
+            UPDATE scheduled_messages
+            SET status = CASE WHEN ?2 IS NULL THEN 'done' ELSE 'failed' END, error = ?2
+            WHERE id = ?1
+            "#,
+            id,
+            error
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(())
+    }
+
+    // Runs cut short by a restart won't finish; report them instead of
+    // leaving them running forever
+    pub async fn fail_interrupted_scheduled_messages(&self) -> sqlx::Result<u64> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE scheduled_messages SET status = 'failed', error = 'Interrupted by a server restart'
+            WHERE status = 'running'
+            "#
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    pub async fn cancel_scheduled_message(&self, id: i64, user_id: i64) -> sqlx::Result<u64> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE scheduled_messages SET status = 'cancelled'
+            WHERE id = ? AND user_id = ? AND status = 'pending'
+            "#,
+            id,
+            user_id
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    // Marks finished runs as seen, narrowed to one run or one chat when given
+    pub async fn mark_scheduled_messages_seen(
+        &self,
+        user_id: i64,
+        id: Option<i64>,
+        chat_id: Option<i64>,
+    ) -> sqlx::Result<()> {
+        sqlx::query!(
+            r#"
+            UPDATE scheduled_messages SET seen = TRUE
+            WHERE user_id = ?1 AND status IN ('done', 'failed')
+                AND (?2 IS NULL OR id = ?2) AND (?3 IS NULL OR chat_id = ?3)
+            "#,
+            user_id,
+            id,
+            chat_id
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_api_tokens(&self, user_id: i64) -> sqlx::Result<Vec<ApiToken>> {
+        sqlx::query_as!(
+            ApiToken,
+            r#"
+            SELECT
+                id AS "id!", name, scope, created_at AS "created_at: DateTime<Utc>",
+                last_used_at AS "last_used_at: DateTime<Utc>",
+                expires_at AS "expires_at: DateTime<Utc>"
+            FROM api_tokens
+            WHERE user_id = ?
+            ORDER BY created_at DESC, id DESC
+            "#,
+            user_id
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    // Returns the secret, which isn't readable afterwards. `expires_in_days`
+    // of `None` makes a token that never expires
+    pub async fn create_api_token(
+        &self,
+        user_id: i64,
+        name: &str,
+        scope: ApiScope,
+        expires_in_days: Option<i64>,
+    ) -> sqlx::Result<String> {
+        let token = format!("ac_{}", uuid::Uuid::new_v4().simple());
+        let token_hash = hash_token(&token);
+        let scope = scope.as_str();
+        let expires_in = expires_in_days.map(|days| format!("{:+} days", days));
+        sqlx::query!(
+            r#"
+            INSERT INTO api_tokens (user_id, name, token_hash, scope, expires_at)
+            VALUES (?, ?, ?, ?, datetime('now', ?))
+            "#,
+            user_id,
+            name,
+            token_hash,
+            scope,
+            expires_in
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(token)
+    }
+
+    pub async fn delete_api_token(&self, user_id: i64, token_id: i64) -> sqlx::Result<u64> {
+        let result = sqlx::query!(
+            "DELETE FROM api_tokens WHERE id = ? AND user_id = ?",
+            token_id,
+            user_id
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    // The user a token belongs to and what it may do; also records when it
+    // was last used. Tokens from before hashing are matched by their plain
+    // text, which only they can hold since hashes never start with `ac_`, and
+    // hashed on the spot
+    pub async fn authenticate_api_token(&self, token: &str) -> sqlx::Result<Option<(i64, ApiScope)>> {
+        let token_hash = hash_token(token);
+        let row = sqlx::query!(
+            r#"
+            UPDATE api_tokens SET token_hash = ?1, last_used_at = CURRENT_TIMESTAMP
+            WHERE (token_hash = ?1 OR (substr(token_hash, 1, 3) = 'ac_' AND token_hash = ?2))
+                AND (expires_at IS NULL OR expires_at > CURRENT_TIMESTAMP)
+            RETURNING user_id, scope
+            "#,
+            token_hash,
+            token
+        )
+        .fetch_optional(&*self.pool)
+        .await?;
+        Ok(row.and_then(|row| Some((row.user_id, ApiScope::parse(&row.scope)?))))
+    }
+
+    // `impersonator_id` is the admin opening the session as the user, if any,
+    // and `remember_id` the "Remember me" series it was started from
+    pub async fn create_session(
+        &self,
+        user_id: i64,
+        token_hash: &str,
+        user_agent: Option<&str>,
+        ip: Option<&str>,
+        impersonator_id: Option<i64>,
+        remember_id: Option<i64>,
+    ) -> sqlx::Result<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO sessions (user_id, token_hash, user_agent, ip, impersonator_id, remember_id)
+            VALUES (?, ?, ?, ?, ?, ?)
+            "#,
+            user_id,
+            token_hash,
+            user_agent,
+            ip,
+            impersonator_id,
+            remember_id
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(())
+    }
+
+    // The user a session belongs to, unless it went unused for `idle_hours`.
+    // `last_seen_at` is only bumped once a minute, so polling pages don't
+    // write on every request
+    pub async fn authenticate_session(
+        &self,
+        token_hash: &str,
+        idle_hours: i64,
+    ) -> sqlx::Result<Option<SessionUser>> {
+        let idle = format!("{:+} hours", -idle_hours);
+        sqlx::query!(
+            "DELETE FROM sessions WHERE token_hash = ? AND last_seen_at < datetime('now', ?)",
+            token_hash,
+            idle
+        )
+        .execute(&*self.pool)
+        .await?;
+        sqlx::query!(
+            r#"
+            UPDATE sessions SET last_seen_at = CURRENT_TIMESTAMP
+            WHERE token_hash = ? AND last_seen_at < datetime('now', '-1 minutes')
+            "#,
+            token_hash
+        )
+        .execute(&*self.pool)
+        .await?;
+
+        sqlx::query_as!(
+            SessionUser,
+            r#"
+            SELECT sessions.user_id, admins.email AS "impersonator?"
+            FROM sessions
+            LEFT JOIN users admins ON admins.id = sessions.impersonator_id
+            WHERE sessions.token_hash = ?
+            "#,
+            token_hash
+        )
+        .fetch_optional(&*self.pool)
+        .await
+    }
+
+    // Sessions still in use, most recently active first; `current_token_hash`
+    // marks the caller's own
+    pub async fn get_sessions(
+        &self,
+        user_id: i64,
+        current_token_hash: &str,
+        idle_hours: i64,
+    ) -> sqlx::Result<Vec<Session>> {
+        let idle = format!("{:+} hours", -idle_hours);
+        sqlx::query_as!(
+            Session,
+            r#"
+            SELECT
+                id AS "id!", user_agent, ip,
+                created_at AS "created_at: DateTime<Utc>",
+                last_seen_at AS "last_seen_at: DateTime<Utc>",
+                token_hash = ? AS "current!: bool"
+            FROM sessions
+            WHERE user_id = ? AND last_seen_at >= datetime('now', ?)
+            ORDER BY last_seen_at DESC, id DESC
+            "#,
+            current_token_hash,
+            user_id,
+            idle
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    // Ending a session ends its "Remember me" series too, or the browser
+    // would be logged right back in
+    pub async fn delete_session(&self, user_id: i64, session_id: i64) -> sqlx::Result<u64> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+        sqlx::query!(
+            r#"
+            DELETE FROM remember_tokens
+            WHERE id = (SELECT remember_id FROM sessions WHERE id = ? AND user_id = ?)
+            "#,
+            session_id,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        let result = sqlx::query!(
+            "DELETE FROM sessions WHERE id = ? AND user_id = ?",
+            session_id,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(result.rows_affected())
+    }
+
+    pub async fn delete_session_by_token(&self, token_hash: &str) -> sqlx::Result<()> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+        sqlx::query!(
+            "DELETE FROM remember_tokens WHERE id = (SELECT remember_id FROM sessions WHERE token_hash = ?)",
+            token_hash
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!("DELETE FROM sessions WHERE token_hash = ?", token_hash)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    // Logs the user out everywhere, "Remember me" cookies included
+    pub async fn delete_sessions(&self, user_id: i64) -> sqlx::Result<u64> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+        sqlx::query!("DELETE FROM remember_tokens WHERE user_id = ?", user_id)
+            .execute(&mut *tx)
+            .await?;
+        let result = sqlx::query!("DELETE FROM sessions WHERE user_id = ?", user_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(result.rows_affected())
+    }
+
+    pub async fn create_remember_token(
+        &self,
+        user_id: i64,
+        series: &str,
+        token_hash: &str,
+        ttl_days: i64,
+    ) -> sqlx::Result<i64> {
+        let expires_in = format!("{:+} days", ttl_days);
+        sqlx::query_scalar!(
+            r#"
+            INSERT INTO remember_tokens (user_id, series, token_hash, expires_at)
+            VALUES (?, ?, ?, datetime('now', ?))
+            RETURNING id AS "id!"
+            "#,
+            user_id,
+            series,
+            token_hash,
+            expires_in
+        )
+        .fetch_one(&*self.pool)
+        .await
+    }
+
+    // Checks the token of a "Remember me" series and replaces it with
+    // `next_hash`, pushing the expiry out by `ttl_days`. Sessions of the
+    // series from before are ended, since the browser lost them
+    pub async fn rotate_remember_token(
+        &self,
+        series: &str,
+        token_hash: &str,
+        next_hash: &str,
+        ttl_days: i64,
+    ) -> sqlx::Result<RememberLogin> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+
+        let row = sqlx::query!(
+            r#"
+            SELECT
+                remember_tokens.id AS "id!",
+                remember_tokens.user_id,
+                remember_tokens.token_hash,
+                remember_tokens.previous_hash,
+                remember_tokens.rotated_at >= datetime('now', '-1 minutes') AS "recent!: bool",
+                remember_tokens.expires_at > datetime('now') AS "live!: bool"
+            FROM remember_tokens
+            JOIN users ON users.id = remember_tokens.user_id
+            WHERE remember_tokens.series = ? AND users.disabled_at IS NULL
+            "#,
+            series
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(row) = row else {
+            return Ok(RememberLogin::Invalid);
+        };
+
+        if !row.live {
+            sqlx::query!("DELETE FROM remember_tokens WHERE id = ?", row.id)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+            return Ok(RememberLogin::Invalid);
+        }
+        if row.token_hash != token_hash {
+            if row.recent && row.previous_hash.as_deref() == Some(token_hash) {
+                return Ok(RememberLogin::Recent {
+                    user_id: row.user_id,
+                    remember_id: row.id,
+                });
+            }
+            sqlx::query!("DELETE FROM remember_tokens WHERE id = ?", row.id)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+            return Ok(RememberLogin::Reused(row.user_id));
+        }
+
+        let expires_in = format!("{:+} days", ttl_days);
+        sqlx::query!(
+            r#"
+            UPDATE remember_tokens
+            SET token_hash = ?, previous_hash = token_hash, rotated_at = CURRENT_TIMESTAMP,
+                expires_at = datetime('now', ?)
+            WHERE id = ?
+            "#,
+            next_hash,
+            expires_in,
+            row.id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!("DELETE FROM sessions WHERE remember_id = ?", row.id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(RememberLogin::Rotated {
+            user_id: row.user_id,
+            remember_id: row.id,
+        })
+    }
+
+    // Returns false, without creating anything, when no account uses `email`
+    pub async fn create_password_reset(
+        &self,
+        email: &str,
+        token_hash: &str,
+        ttl_minutes: i64,
+    ) -> sqlx::Result<bool> {
+        let expires_in = format!("{:+} minutes", ttl_minutes);
+        let result = sqlx::query!(
+            r#"
+            INSERT INTO password_resets (token_hash, user_id, expires_at)
+            SELECT ?, id, datetime('now', ?) FROM users WHERE email = ?
+            ORDER BY id
+            LIMIT 1
+            "#,
+            token_hash,
+            expires_in,
+            email
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn find_password_reset(&self, token_hash: &str) -> sqlx::Result<Option<i64>> {
+        sqlx::query_scalar!(
+            "SELECT user_id FROM password_resets WHERE token_hash = ? AND expires_at > datetime('now')",
+            token_hash
+        )
+        .fetch_optional(&*self.pool)
+        .await
+    }
+
+    // Sets the new password and invalidates every reset link of the user;
+    // false if the link is unknown or expired
+    pub async fn reset_password(&self, token_hash: &str, password_hash: &str) -> sqlx::Result<bool> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+
+        let user_id = sqlx::query_scalar!(
+            "SELECT user_id FROM password_resets WHERE token_hash = ? AND expires_at > datetime('now')",
+            token_hash
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(user_id) = user_id else {
+            return Ok(false);
+        };
+
+        // A new password ends any lock from guesses at the old one
+        sqlx::query!(
+            "UPDATE users SET password = ?, failed_logins = 0, locked_until = NULL WHERE id = ?",
+            password_hash,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!("DELETE FROM password_resets WHERE user_id = ?", user_id)
+            .execute(&mut *tx)
+            .await?;
+        // Whoever knew the old password is logged out too
+        sqlx::query!("DELETE FROM remember_tokens WHERE user_id = ?", user_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("DELETE FROM sessions WHERE user_id = ?", user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(true)
+    }
+
+    pub async fn create_email_verification(
+        &self,
+        user_id: i64,
+        token_hash: &str,
+        ttl_minutes: i64,
+    ) -> sqlx::Result<()> {
+        let expires_in = format!("{:+} minutes", ttl_minutes);
+        sqlx::query!(
+            "INSERT INTO email_verifications (token_hash, user_id, expires_at) VALUES (?, ?, datetime('now', ?))",
+            token_hash,
+            user_id,
+            expires_in
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(())
+    }
+
+    // Marks the user as verified and drops their other links; false if the
+    // link is unknown or expired
+    pub async fn verify_email(&self, token_hash: &str) -> sqlx::Result<bool> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+
+        let user_id = sqlx::query_scalar!(
+            "SELECT user_id FROM email_verifications WHERE token_hash = ? AND expires_at > datetime('now')",
+            token_hash
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(user_id) = user_id else {
+            return Ok(false);
+        };
+
+        sqlx::query!(
+            "UPDATE users SET verified_at = COALESCE(verified_at, CURRENT_TIMESTAMP) WHERE id = ?",
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!("DELETE FROM email_verifications WHERE user_id = ?", user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(true)
+    }
+
+    // Returns false, without creating anything, when no enabled account uses
+    // `email`
+    pub async fn create_login_link(
+        &self,
+        email: &str,
+        token_hash: &str,
+        ttl_minutes: i64,
+    ) -> sqlx::Result<bool> {
+        let expires_in = format!("{:+} minutes", ttl_minutes);
+        let result = sqlx::query!(
+            r#"
+            INSERT INTO login_links (token_hash, user_id, expires_at)
+            SELECT ?, id, datetime('now', ?) FROM users
+            WHERE email = ? AND disabled_at IS NULL
+            ORDER BY id
+            LIMIT 1
+            "#,
+            token_hash,
+            expires_in,
+            email
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn find_login_link(&self, token_hash: &str) -> sqlx::Result<Option<i64>> {
+        sqlx::query_scalar!(
+            "SELECT user_id FROM login_links WHERE token_hash = ? AND expires_at > datetime('now')",
+            token_hash
+        )
+        .fetch_optional(&*self.pool)
+        .await
+    }
+
+    // The user the link signs in, after dropping all of their links. Opening
+    // the link proves the email address, so it counts as verified too
+    pub async fn use_login_link(&self, token_hash: &str) -> sqlx::Result<Option<i64>> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+
+        let user_id = sqlx::query_scalar!(
+            "SELECT user_id FROM login_links WHERE token_hash = ? AND expires_at > datetime('now')",
+            token_hash
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(user_id) = user_id else {
+            return Ok(None);
+        };
+
+        sqlx::query!(
+            "UPDATE users SET verified_at = COALESCE(verified_at, CURRENT_TIMESTAMP) WHERE id = ?",
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!("DELETE FROM login_links WHERE user_id = ?", user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(Some(user_id))
+    }
+
+    // Finds the user an OAuth identity signs in as. An unknown identity is
+    // linked to the account using its email, but only when the provider
+    // vouches for the address; otherwise anyone could claim an account by
+    // registering its email at the provider. `password` is stored for new
+    // accounts
+    pub async fn oauth_sign_in(
+        &self,
+        provider: &str,
+        subject: &str,
+        email: &str,
+        email_verified: bool,
+        password: &str,
+        allow_signup: bool,
+    ) -> sqlx::Result<OAuthSignIn> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+
+        let linked = sqlx::query_scalar!(
+            "SELECT user_id FROM user_identities WHERE provider = ? AND subject = ?",
+            provider,
+            subject
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        if let Some(user_id) = linked {
+            return Ok(OAuthSignIn::Existing(user_id));
+        }
+
+        let existing = sqlx::query_scalar!(
+            r#"SELECT id AS "id!" FROM users WHERE email = ? ORDER BY id LIMIT 1"#,
+            email
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        let (user_id, sign_in) = match existing {
+            Some(_) if !email_verified => return Ok(OAuthSignIn::EmailTaken),
+            Some(user_id) => {
+                sqlx::query!(
+                    "UPDATE users SET verified_at = COALESCE(verified_at, CURRENT_TIMESTAMP) WHERE id = ?",
+                    user_id
+                )
+                .execute(&mut *tx)
+                .await?;
+                (user_id, OAuthSignIn::Existing(user_id))
+            }
+            None if !allow_signup => return Ok(OAuthSignIn::SignupClosed),
+            None => {
+                let user_id = sqlx::query_scalar!(
+                    r#"
+                    INSERT INTO users (email, password, verified_at)
+                    VALUES (?, ?, CASE WHEN ? THEN CURRENT_TIMESTAMP END)
+                    RETURNING id AS "id!"
+                    "#,
+                    email,
+                    password,
+                    email_verified
+                )
+                .fetch_one(&mut *tx)
+                .await?;
+                (user_id, OAuthSignIn::Created(user_id))
+            }
+        };
+
+        sqlx::query!(
+            "INSERT INTO user_identities (user_id, provider, subject, email) VALUES (?, ?, ?, ?)",
+            user_id,
+            provider,
+            subject,
+            email
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(sign_in)
+    }
+
+    // Provider API keys still stored as they were typed, see `encryption`
+    pub async fn plaintext_api_keys(&self) -> sqlx::Result<Vec<(i64, String)>> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT user_id AS "user_id!", openai_api_key
+            FROM settings
+            WHERE openai_api_key != '' AND openai_api_key NOT LIKE 'enc:%'
+            "#
+        )
+        .fetch_all(&*self.pool)
+        .await?;
+        Ok(rows.into_iter().map(|row| (row.user_id, row.openai_api_key)).collect())
+    }
+
+    // For re-encryption under a new master key, see `encryption::reencrypt_secrets`
+    pub async fn encrypted_api_keys(&self) -> sqlx::Result<Vec<(i64, String)>> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT user_id AS "user_id!", openai_api_key
+            FROM settings
+            WHERE openai_api_key LIKE 'enc:%'
+            "#
+        )
+        .fetch_all(&*self.pool)
+        .await?;
+        Ok(rows.into_iter().map(|row| (row.user_id, row.openai_api_key)).collect())
+    }
+
+    pub async fn encrypted_org_api_keys(&self) -> sqlx::Result<Vec<(i64, String)>> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT id AS "id!", openai_api_key AS "openai_api_key!"
+            FROM orgs
+            WHERE openai_api_key LIKE 'enc:%'
+            "#
+        )
+        .fetch_all(&*self.pool)
+        .await?;
+        Ok(rows.into_iter().map(|row| (row.id, row.openai_api_key)).collect())
+    }
+
+    // The user's API key as stored, still encrypted. `None` when none is saved
+    pub async fn stored_api_key(&self, user_id: i64) -> sqlx::Result<Option<String>> {
+        let stored = sqlx::query_scalar!(
+            "SELECT openai_api_key FROM settings WHERE user_id = ? AND openai_api_key != ''",
+            user_id
+        )
+        .fetch_optional(&*self.pool)
+        .await?;
+        Ok(stored)
+    }
+
+    // Saves the provider settings edited on a page rendered at `version`, see
+    // `get_settings_version`. False when they were saved elsewhere since
+    pub async fn save_settings(&self, user_id: i64, settings: &UserSettings, version: i64) -> sqlx::Result<bool> {
+        let result = sqlx::query!(
+            r#"
+            INSERT INTO settings (user_id, openai_api_key, base_url, model, system_prompt, temperature, top_p, max_tokens, version)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, 1)
+            ON CONFLICT (user_id) DO UPDATE SET
+                openai_api_key = excluded.openai_api_key,
+                base_url = excluded.base_url,
+                model = excluded.model,
+                system_prompt = excluded.system_prompt,
+                temperature = excluded.temperature,
+                top_p = excluded.top_p,
+                max_tokens = excluded.max_tokens,
+                version = settings.version + 1
+            WHERE settings.version = ?9
+            "#,
+            user_id,
+            settings.stored_api_key,
+            settings.base_url,
+            settings.model,
+            settings.system_prompt,
+            settings.temperature,
+            settings.top_p,
+            settings.max_tokens,
+            version
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    // 0 until the settings are first saved
+    pub async fn get_settings_version(&self, user_id: i64) -> sqlx::Result<i64> {
+        let version = sqlx::query_scalar!("SELECT version FROM settings WHERE user_id = ?", user_id)
+            .fetch_optional(&*self.pool)
+            .await?;
+        Ok(version.unwrap_or(0))
+    }
+
+    pub async fn set_stored_api_key(&self, user_id: i64, stored: &str) -> sqlx::Result<()> {
+        sqlx::query!("UPDATE settings SET openai_api_key = ? WHERE user_id = ?", stored, user_id)
+            .execute(&*self.pool)
+            .await?;
+        Ok(())
+    }
+
+    // A verified account for `demo::seed`, None when the email is taken
+    pub async fn create_demo_user(&self, email: &str, password: &str) -> sqlx::Result<Option<i64>> {
+        sqlx::query_scalar!(
+            r#"
+            INSERT INTO users (email, password, display_name, verified_at)
+            SELECT ?1, ?2, 'Demo User', CURRENT_TIMESTAMP
+            WHERE NOT EXISTS (SELECT 1 FROM users WHERE email = ?1)
+            RETURNING id AS "id!"
+            "#,
+            email,
+            password
+        )
+        .fetch_optional(&*self.pool)
+        .await
+    }
+
+    // Grants the admin role to the accounts using these emails
+    pub async fn grant_admin(&self, emails: &[String]) -> sqlx::Result<u64> {
+        let mut granted = 0;
+        for email in emails {
+            granted += sqlx::query!("UPDATE users SET is_admin = 1 WHERE email = ?", email)
+                .execute(&*self.pool)
+                .await?
+                .rows_affected();
+        }
+        Ok(granted)
+    }
+
+    pub async fn admin_users(&self) -> sqlx::Result<Vec<AdminUser>> {
+        sqlx::query_as!(
+            AdminUser,
+            r#"
+            SELECT
+                users.id AS "id!", users.email, users.is_admin AS "is_admin: bool",
+                users.created_at AS "created_at: DateTime<Utc>",
+                users.verified_at AS "verified_at: DateTime<Utc>",
+                users.disabled_at AS "disabled_at: DateTime<Utc>",
+                (SELECT MAX(last_seen_at) FROM sessions WHERE sessions.user_id = users.id)
+                    AS "last_seen_at?: DateTime<Utc>",
+                users.failed_logins,
+                CASE WHEN users.locked_until > datetime('now') THEN users.locked_until END
+                    AS "locked_until?: DateTime<Utc>",
+                (SELECT COUNT(*) FROM chats WHERE chats.user_id = users.id AND chats.deleted_at IS NULL)
+                    AS "chats!: i64",
+                COALESCE(usage.responses, 0) AS "responses!: i64",
+                COALESCE(usage.total_tokens, 0) AS "total_tokens!: i64"
+            FROM users
+            LEFT JOIN (
+                SELECT chats.user_id, COUNT(*) AS responses,
+                    SUM(COALESCE(messages.usage_total_tokens, 0)) AS total_tokens
+                FROM messages
+                JOIN message_pairs ON message_pairs.ai_message_id = messages.id
+                JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+                JOIN chats ON chats.id = message_blocks.chat_id
+                GROUP BY chats.user_id
+            ) usage ON usage.user_id = users.id
+            ORDER BY users.id
+            "#
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    // Records a failed password login. When `email` has an account, its
+    // counter goes up and the account is locked for `lock_minutes` once it
+    // reaches `max_failures`; after that, every further failure locks it again
+    pub async fn record_failed_login(
+        &self,
+        email: &str,
+        ip: Option<&str>,
+        max_failures: i64,
+        lock_minutes: i64,
+    ) -> sqlx::Result<Option<FailedLogin>> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+
+        sqlx::query!("DELETE FROM login_failures WHERE created_at < datetime('now', '-1 days')")
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query!("INSERT INTO login_failures (email, ip) VALUES (?, ?)", email, ip)
+            .execute(&mut *tx)
+            .await?;
+
+        let lock_for = format!("{:+} minutes", lock_minutes);
+        let account = sqlx::query!(
+            r#"
+            UPDATE users
+            SET failed_logins = failed_logins + 1,
+                locked_until = CASE
+                    WHEN failed_logins + 1 >= ?1 THEN datetime('now', ?2)
+                    ELSE locked_until
+                END
+            WHERE email = ?3 AND disabled_at IS NULL
+            RETURNING
+                id AS "id!",
+                failed_logins,
+                CASE WHEN failed_logins >= ?1 THEN locked_until END AS "locked_until?: DateTime<Utc>"
+            "#,
+            max_failures,
+            lock_for,
+            email
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(account.map(|account| FailedLogin {
+            user_id: account.id,
+            failed_logins: account.failed_logins,
+            locked_until: account.locked_until,
+        }))
+    }
+
+    // Failed logins from `ip` within the last `minutes`
+    pub async fn recent_login_failures(&self, ip: &str, minutes: i64) -> sqlx::Result<i64> {
+        let since = format!("{:+} minutes", -minutes);
+        sqlx::query_scalar!(
+            r#"
+            SELECT COUNT(*) AS "count!: i64" FROM login_failures
+            WHERE ip = ? AND created_at >= datetime('now', ?)
+            "#,
+            ip,
+            since
+        )
+        .fetch_one(&*self.pool)
+        .await
+    }
+
+    // Until when password logins to the account are locked, if they are
+    pub async fn login_locked_until(&self, email: &str) -> sqlx::Result<Option<DateTime<Utc>>> {
+        let locked_until = sqlx::query_scalar!(
+            r#"
+            SELECT locked_until AS "locked_until: DateTime<Utc>" FROM users
+            WHERE email = ? AND locked_until > datetime('now')
+            ORDER BY id
+            LIMIT 1
+            "#,
+            email
+        )
+        .fetch_optional(&*self.pool)
+        .await?;
+        Ok(locked_until.flatten())
+    }
+
+    // Clears the failure counter and any lock, after a successful login or
+    // when an admin unlocks the account
+    pub async fn reset_failed_logins(&self, user_id: i64) -> sqlx::Result<u64> {
+        let result = sqlx::query!(
+            "UPDATE users SET failed_logins = 0, locked_until = NULL WHERE id = ?",
+            user_id
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    pub async fn create_invite(&self, created_by: i64, code: &str, note: Option<&str>) -> sqlx::Result<i64> {
+        sqlx::query_scalar!(
+            r#"INSERT INTO invites (code, note, created_by) VALUES (?, ?, ?) RETURNING id AS "id!""#,
+            code,
+            note,
+            created_by
+        )
+        .fetch_one(&*self.pool)
+        .await
+    }
+
+    // Newest first
+    pub async fn get_invites(&self) -> sqlx::Result<Vec<Invite>> {
+        sqlx::query_as!(
+            Invite,
+            r#"
+            SELECT
+                invites.id AS "id!", invites.code, invites.note,
+                creator.email AS "created_by?",
+                invites.created_at AS "created_at: DateTime<Utc>",
+                invitee.email AS "used_by?",
+                invites.used_at AS "used_at: DateTime<Utc>",
+                invites.revoked_at AS "revoked_at: DateTime<Utc>"
+            FROM invites
+            LEFT JOIN users AS creator ON creator.id = invites.created_by
+            LEFT JOIN users AS invitee ON invitee.id = invites.used_by
+            ORDER BY invites.id DESC
+            "#
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    // Only invites nobody has used yet can be revoked
+    pub async fn revoke_invite(&self, invite_id: i64) -> sqlx::Result<u64> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE invites SET revoked_at = CURRENT_TIMESTAMP
+            WHERE id = ? AND used_at IS NULL AND revoked_at IS NULL
+            "#,
+            invite_id
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    // Creates the account and uses up the invite together. `None` if the
+    // code is unknown, used or revoked
+    pub async fn create_invited_user(&self, code: &str, email: &str, password: &str) -> sqlx::Result<Option<i64>> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+
+        let invite_id = sqlx::query_scalar!(
+            r#"
+            UPDATE invites SET used_at = CURRENT_TIMESTAMP
+            WHERE code = ? AND used_at IS NULL AND revoked_at IS NULL
+            RETURNING id AS "id!"
+            "#,
+            code
+        )
+        .fetch_optional(&mut *tx)
+        .await?;
+        let Some(invite_id) = invite_id else {
+            return Ok(None);
+        };
+
+        let user_id = sqlx::query_scalar!(
+            r#"INSERT INTO users (email, password) VALUES (?, ?) RETURNING id AS "id!""#,
+            email,
+            password
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        sqlx::query!("UPDATE invites SET used_by = ? WHERE id = ?", user_id, invite_id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        Ok(Some(user_id))
+    }
+
+    // A trial account, ready to use and removed after `ttl_hours` unless
+    // claimed with `claim_guest`
+    pub async fn create_guest(&self, email: &str, password: &str, ttl_hours: i64) -> sqlx::Result<i64> {
+        let expires = format!("{:+} hours", ttl_hours);
+        sqlx::query_scalar!(
+            r#"
+            INSERT INTO users (email, password, display_name, verified_at, guest_expires_at)
+            VALUES (?, ?, 'Guest', CURRENT_TIMESTAMP, datetime('now', ?))
+            RETURNING id AS "id!"
+            "#,
+            email,
+            password,
+            expires
+        )
+        .fetch_one(&*self.pool)
+        .await
+    }
+
+    pub async fn is_guest(&self, user_id: i64) -> sqlx::Result<bool> {
+        let guest = sqlx::query_scalar!(
+            r#"SELECT guest_expires_at IS NOT NULL AS "guest!: bool" FROM users WHERE id = ?"#,
+            user_id
+        )
+        .fetch_optional(&*self.pool)
+        .await?;
+        Ok(guest.unwrap_or(false))
+    }
+
+    // Turns the guest into a regular account that keeps its chats. The email
+    // needs confirming like any new one. With `invite`, the code is used up
+    // too. `false` if the user isn't a guest or the invite can't be used
+    pub async fn claim_guest(&self, user_id: i64, email: &str, password: &str, invite: Option<&str>) -> sqlx::Result<bool> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+
+        let claimed = sqlx::query!(
+            r#"
+            UPDATE users
+            SET email = ?, password = ?, display_name = NULL, verified_at = NULL, guest_expires_at = NULL
+            WHERE id = ? AND guest_expires_at IS NOT NULL
+            "#,
+            email,
+            password,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        if claimed.rows_affected() == 0 {
+            return Ok(false);
+        }
+
+        if let Some(code) = invite {
+            let used = sqlx::query!(
+                r#"
+                UPDATE invites SET used_at = CURRENT_TIMESTAMP, used_by = ?
+                WHERE code = ? AND used_at IS NULL AND revoked_at IS NULL
+                "#,
+                user_id,
+                code
+            )
+            .execute(&mut *tx)
+            .await?;
+            if used.rows_affected() == 0 {
+                return Ok(false);
+            }
+        }
+
+        tx.commit().await?;
+
+        Ok(true)
+    }
+
+    pub async fn expired_guests(&self) -> sqlx::Result<Vec<i64>> {
+        sqlx::query_scalar!(
+            r#"SELECT id AS "id!" FROM users WHERE guest_expires_at <= CURRENT_TIMESTAMP"#
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    // A tool call waiting for the user's approval, see
+    // `ai::stream::generate_sse_stream`
+    pub async fn save_tool_call_confirmation(&self, confirmation: &ToolCallConfirmation) -> sqlx::Result<()> {
+        let tool_call = serde_json::to_string(&confirmation.tool_call).map_err(|e| sqlx::Error::Encode(Box::new(e)))?;
+        let status = confirmation.status.as_str();
+        let created_at = confirmation.created_at.to_rfc3339();
+
+        sqlx::query!(
+            r#"
+            INSERT INTO tool_call_confirmations (id, chat_id, message_pair_id, tool_call, status, created_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+            ON CONFLICT(id) DO UPDATE SET
+                status = excluded.status,
+                user_response = excluded.user_response,
+                result = excluded.result
+            "#,
+            confirmation.id,
+            confirmation.chat_id,
+            confirmation.message_pair_id,
+            tool_call,
+            status,
+            created_at
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(())
+    }
+
+    // The stored call, as JSON, of a confirmation in the chat
+    pub async fn get_tool_call(&self, chat_id: i64, confirmation_id: &str) -> sqlx::Result<Option<String>> {
+        sqlx::query_scalar!(
+            "SELECT tool_call FROM tool_call_confirmations WHERE id = ? AND chat_id = ?",
+            confirmation_id,
+            chat_id
+        )
+        .fetch_optional(&*self.pool)
+        .await
+    }
+
+    // Approves the call and adds the pair its result goes in, together so an
+    // approval never lacks its pair. `None` if it isn't waiting for an answer
+    // in the chat, so a second click or another tab can't run it again
+    pub async fn approve_tool_call(
+        &self,
+        chat_id: i64,
+        confirmation_id: &str,
+        human_message: &str,
+    ) -> sqlx::Result<Option<i64>> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+        let approved = sqlx::query!(
+            "UPDATE tool_call_confirmations SET status = 'Approved' WHERE id = ? AND chat_id = ? AND status = 'Pending'",
+            confirmation_id,
+            chat_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        if approved.rows_affected() != 1 {
+            return Ok(None);
+        }
+
+        let pair_id = self.insert_message_block(&mut tx, chat_id, human_message).await?;
+        sqlx::query!(
+            "UPDATE tool_call_confirmations SET message_pair_id = ? WHERE id = ?",
+            pair_id,
+            confirmation_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(Some(pair_id))
+    }
+
+    // False if the call isn't waiting for an answer in the chat
+    pub async fn reject_tool_call(&self, chat_id: i64, confirmation_id: &str) -> sqlx::Result<bool> {
+        let rejected = sqlx::query!(
+            r#"
+            UPDATE tool_call_confirmations SET status = 'Rejected', user_response = 'Rejected by user'
+            WHERE id = ? AND chat_id = ? AND status = 'Pending'
+            "#,
+            confirmation_id,
+            chat_id
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(rejected.rows_affected() == 1)
+    }
+
+    pub async fn record_audit_event(
+        &self,
+        user_id: Option<i64>,
+        event: &str,
+        detail: Option<&str>,
+        actor: Option<&str>,
+        ip: Option<&str>,
+    ) -> sqlx::Result<()> {
+        sqlx::query!(
+            "INSERT INTO audit_log (user_id, event, detail, actor, ip) VALUES (?, ?, ?, ?, ?)",
+            user_id,
+            event,
+            detail,
+            actor,
+            ip
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(())
+    }
+
+    // Newest first. `user_id` limits it to one account; `email` matches part
+    // of the account's email, for the admin's view of everyone
+    pub async fn get_audit_log(
+        &self,
+        user_id: Option<i64>,
+        event: Option<&str>,
+        email: Option<&str>,
+        limit: i64,
+    ) -> sqlx::Result<Vec<AuditEntry>> {
+        let email = email.map(|email| format!("%{}%", email));
+        sqlx::query_as!(
+            AuditEntry,
+            r#"
+            SELECT
+                audit_log.id AS "id!", audit_log.user_id, users.email AS "email?",
+                audit_log.event, audit_log.detail, audit_log.actor, audit_log.ip,
+                audit_log.created_at AS "created_at: DateTime<Utc>"
+            FROM audit_log
+            LEFT JOIN users ON users.id = audit_log.user_id
+            WHERE (?1 IS NULL OR audit_log.user_id = ?1)
+                AND (?2 IS NULL OR audit_log.event = ?2)
+                AND (?3 IS NULL OR users.email LIKE ?3 OR audit_log.detail LIKE ?3)
+            ORDER BY audit_log.id DESC
+            LIMIT ?4
+            "#,
+            user_id,
+            event,
+            email,
+            limit
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    pub async fn record_tool_execution(&self, execution: &NewToolExecution<'_>) -> sqlx::Result<()> {
+        let arguments_hash = hash_token(execution.arguments);
+        let outcome = if execution.error.is_some() { "error" } else { "success" };
+        sqlx::query!(
+            r#"
+            INSERT INTO tool_executions
+                (user_id, chat_id, server, tool, arguments_hash, result_bytes, duration_ms, outcome, error)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
+            "#,
+            execution.user_id,
+            execution.chat_id,
+            execution.server,
+            execution.tool,
+            arguments_hash,
+            execution.result_bytes,
+            execution.duration_ms,
+            outcome,
+            execution.error
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(())
+    }
+
+    // Newest first, optionally only calls of one tool or with one outcome
+    pub async fn get_tool_executions(
+        &self,
+        user_id: i64,
+        tool: Option<&str>,
+        outcome: Option<&str>,
+        limit: i64,
+    ) -> sqlx::Result<Vec<ToolExecution>> {
+        sqlx::query_as!(
+            ToolExecution,
+            r#"
+            SELECT
+                tool_executions.id AS "id!", tool_executions.chat_id, chats.name AS chat_name,
+                tool_executions.server, tool_executions.tool, tool_executions.arguments_hash,
+                tool_executions.result_bytes, tool_executions.duration_ms,
+                tool_executions.outcome, tool_executions.error,
+                tool_executions.created_at AS "created_at: DateTime<Utc>"
+            FROM tool_executions
+            JOIN chats ON chats.id = tool_executions.chat_id
+            WHERE tool_executions.user_id = ?1
+                AND (?2 IS NULL OR tool_executions.tool = ?2)
+                AND (?3 IS NULL OR tool_executions.outcome = ?3)
+            ORDER BY tool_executions.id DESC
+            LIMIT ?4
+            "#,
+            user_id,
+            tool,
+            outcome,
+            limit
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    // Tools the user has run, for the filter on the log page
+    pub async fn get_executed_tool_names(&self, user_id: i64) -> sqlx::Result<Vec<String>> {
+        sqlx::query_scalar!(
+            "SELECT DISTINCT tool FROM tool_executions WHERE user_id = ? ORDER BY tool",
+            user_id
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    // Calls per tool in the last `days`, most used first
+    pub async fn tool_stats(&self, user_id: i64, days: i64) -> sqlx::Result<Vec<ToolStats>> {
+        let since = format!("-{} days", days);
+        sqlx::query_as!(
+            ToolStats,
+            r#"
+            SELECT
+                server, tool,
+                COUNT(*) AS "calls!: i64",
+                SUM(outcome = 'error') AS "failures!: i64",
+                CAST(AVG(duration_ms) AS INTEGER) AS "avg_duration_ms!: i64",
+                MAX(duration_ms) AS "max_duration_ms!: i64",
+                MAX(created_at) AS "last_used!: DateTime<Utc>"
+            FROM tool_executions
+            WHERE user_id = ? AND created_at >= datetime('now', ?)
+            GROUP BY server, tool
+            ORDER BY 3 DESC, tool
+            "#,
+            user_id,
+            since
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    // Calls per server in the last `days`, most used first
+    pub async fn server_tool_stats(&self, user_id: i64, days: i64) -> sqlx::Result<Vec<ServerToolStats>> {
+        let since = format!("-{} days", days);
+        sqlx::query_as!(
+            ServerToolStats,
+            r#"
+            SELECT
+                server,
+                COUNT(DISTINCT tool) AS "tools!: i64",
+                COUNT(*) AS "calls!: i64",
+                SUM(outcome = 'error') AS "failures!: i64",
+                CAST(AVG(duration_ms) AS INTEGER) AS "avg_duration_ms!: i64"
+            FROM tool_executions
+            WHERE user_id = ? AND created_at >= datetime('now', ?)
+            GROUP BY server
+            ORDER BY 3 DESC, server
+            "#,
+            user_id,
+            since
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    // Attaching a resource again replaces its content with the current one
+    pub async fn attach_chat_resource(&self, chat_id: i64, resource: &NewChatResource<'_>) -> sqlx::Result<i64> {
+        sqlx::query_scalar!(
+            r#"
+            INSERT INTO chat_resources (chat_id, server, uri, name, mime_type, content)
+            VALUES (?, ?, ?, ?, ?, ?)
+            ON CONFLICT (chat_id, server, uri) DO UPDATE SET
+                name = excluded.name,
+                mime_type = excluded.mime_type,
+                content = excluded.content,
+                created_at = CURRENT_TIMESTAMP
+            RETURNING id AS "id!"
+            "#,
+            chat_id,
+            resource.server,
+            resource.uri,
+            resource.name,
+            resource.mime_type,
+            resource.content
+        )
+        .fetch_one(&*self.pool)
+        .await
+    }
+
+    pub async fn get_chat_resources(&self, chat_id: i64) -> sqlx::Result<Vec<ChatResource>> {
+        sqlx::query_as!(
+            ChatResource,
+            r#"
+            SELECT id AS "id!", chat_id, server, uri, name, mime_type, content,
+                created_at AS "created_at: DateTime<Utc>"
+            FROM chat_resources
+            WHERE chat_id = ?
+            ORDER BY id
+            "#,
+            chat_id
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    pub async fn detach_chat_resource(&self, chat_id: i64, resource_id: i64) -> sqlx::Result<bool> {
+        let result = sqlx::query!(
+            "DELETE FROM chat_resources WHERE id = ? AND chat_id = ?",
+            resource_id,
+            chat_id
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn approve_tool(&self, user_id: i64, server: &str, tool: &str) -> sqlx::Result<()> {
+        sqlx::query!(
+            "INSERT INTO tool_approvals (user_id, server, tool) VALUES (?, ?, ?) ON CONFLICT DO NOTHING",
+            user_id,
+            server,
+            tool
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn is_tool_approved(&self, user_id: i64, server: &str, tool: &str) -> sqlx::Result<bool> {
+        sqlx::query_scalar!(
+            r#"
+            SELECT EXISTS (
+                SELECT 1 FROM tool_approvals WHERE user_id = ? AND server = ? AND tool = ?
+            ) AS "approved!: bool"
+            "#,
+            user_id,
+            server,
+            tool
+        )
+        .fetch_one(&*self.pool)
+        .await
+    }
+
+    pub async fn get_tool_approvals(&self, user_id: i64) -> sqlx::Result<Vec<ToolApproval>> {
+        sqlx::query_as!(
+            ToolApproval,
+            r#"
+            SELECT id AS "id!", server, tool, created_at AS "created_at: DateTime<Utc>"
+            FROM tool_approvals
+            WHERE user_id = ?
+            ORDER BY server, tool
+            "#,
+            user_id
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    pub async fn revoke_tool_approval(&self, user_id: i64, approval_id: i64) -> sqlx::Result<bool> {
+        let result = sqlx::query!(
+            "DELETE FROM tool_approvals WHERE id = ? AND user_id = ?",
+            approval_id,
+            user_id
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn get_mcp_servers(&self, user_id: i64) -> sqlx::Result<Vec<UserMcpServer>> {
+        sqlx::query_as!(
+            UserMcpServer,
+            r#"
+            SELECT id AS "id!", name, config, oauth, updated_at AS "updated_at: DateTime<Utc>"
+            FROM mcp_servers
+            WHERE user_id = ?
+            ORDER BY name
+            "#,
+            user_id
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    // Adds the server or replaces the config of the user's one of that name
+    pub async fn save_mcp_server(&self, user_id: i64, name: &str, config: &str) -> sqlx::Result<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO mcp_servers (user_id, name, config) VALUES (?, ?, ?)
+            ON CONFLICT (user_id, name) DO UPDATE SET
+                config = excluded.config,
+                updated_at = CURRENT_TIMESTAMP
+            "#,
+            user_id,
+            name,
+            config
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(())
+    }
+
+    // Like `save_mcp_server`, but keeps a server the user already has. False
+    // if they do
+    pub async fn add_mcp_server(&self, user_id: i64, name: &str, config: &str) -> sqlx::Result<bool> {
+        let result = sqlx::query!(
+            "INSERT INTO mcp_servers (user_id, name, config) VALUES (?, ?, ?) ON CONFLICT DO NOTHING",
+            user_id,
+            name,
+            config
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    // Sets or clears the OAuth tokens of the user's server. False if they
+    // have no server of that name
+    pub async fn save_mcp_server_oauth(&self, user_id: i64, name: &str, oauth: Option<&str>) -> sqlx::Result<bool> {
+        let result = sqlx::query!(
+            "UPDATE mcp_servers SET oauth = ? WHERE user_id = ? AND name = ?",
+            oauth,
+            user_id,
+            name
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    // Every user's servers, to encrypt their secrets, see
+    // `encryption::encrypt_plaintext_secrets`
+    pub async fn all_mcp_servers(&self) -> sqlx::Result<Vec<UserMcpServer>> {
+        sqlx::query_as!(
+            UserMcpServer,
+            r#"
+            SELECT id AS "id!", name, config, oauth, updated_at AS "updated_at: DateTime<Utc>"
+            FROM mcp_servers
+            ORDER BY id
+            "#
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    // Replaces the stored config and OAuth tokens as they are, without
+    // marking the server updated
+    pub async fn set_mcp_server_secrets(&self, id: i64, config: &str, oauth: Option<&str>) -> sqlx::Result<()> {
+        sqlx::query!("UPDATE mcp_servers SET config = ?, oauth = ? WHERE id = ?", config, oauth, id)
+            .execute(&*self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete_mcp_server(&self, user_id: i64, name: &str) -> sqlx::Result<bool> {
+        let result = sqlx::query!("DELETE FROM mcp_servers WHERE user_id = ? AND name = ?", user_id, name)
+            .execute(&*self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn update_display_name(&self, user_id: i64, display_name: Option<&str>) -> sqlx::Result<()> {
+        sqlx::query!("UPDATE users SET display_name = ? WHERE id = ?", display_name, user_id)
+            .execute(&*self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get_avatar(&self, user_id: i64) -> sqlx::Result<Option<String>> {
+        let avatar = sqlx::query_scalar!("SELECT avatar FROM users WHERE id = ?", user_id)
+            .fetch_optional(&*self.pool)
+            .await?;
+        Ok(avatar.flatten())
+    }
+
+    // Returns the avatar replaced, whose file can go
+    pub async fn set_avatar(&self, user_id: i64, avatar: Option<&str>) -> sqlx::Result<Option<String>> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+        let previous = sqlx::query_scalar!("SELECT avatar FROM users WHERE id = ?", user_id)
+            .fetch_optional(&mut *tx)
+            .await?
+            .flatten();
+        sqlx::query!("UPDATE users SET avatar = ? WHERE id = ?", avatar, user_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(previous)
+    }
+
+    pub async fn get_user_email(&self, user_id: i64) -> sqlx::Result<Option<String>> {
+        sqlx::query_scalar!("SELECT email FROM users WHERE id = ?", user_id)
+            .fetch_optional(&*self.pool)
+            .await
+    }
+
+    // Disabling also ends the user's sessions, so they don't come back when
+    // the account is enabled again
+    pub async fn set_user_disabled(&self, user_id: i64, disabled: bool) -> sqlx::Result<u64> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+
+        let result = sqlx::query!(
+            r#"
+            UPDATE users SET disabled_at = CASE WHEN ? THEN COALESCE(disabled_at, CURRENT_TIMESTAMP) END
+            WHERE id = ?
+            "#,
+            disabled,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+        if disabled {
+            sqlx::query!("DELETE FROM remember_tokens WHERE user_id = ?", user_id)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query!("DELETE FROM sessions WHERE user_id = ?", user_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(result.rows_affected())
+    }
+
+    // Chats and everything else of the user go with it. Messages aren't tied
+    // to their chat by a foreign key, so they are deleted first, which takes
+    // their pairs and search index entries along
+    pub async fn delete_user(&self, user_id: i64) -> sqlx::Result<u64> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+
+        sqlx::query!(
+            "DELETE FROM tool_call_confirmations WHERE chat_id IN (SELECT id FROM chats WHERE user_id = ?)",
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            DELETE FROM messages
+            WHERE id IN (
+                SELECT message_pairs.human_message_id FROM message_pairs
+                JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+                JOIN chats ON chats.id = message_blocks.chat_id
+                WHERE chats.user_id = ?1
+                UNION
+                SELECT message_pairs.ai_message_id FROM message_pairs
+                JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+                JOIN chats ON chats.id = message_blocks.chat_id
+                WHERE chats.user_id = ?1
+            )
+            "#,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        let result = sqlx::query!("DELETE FROM users WHERE id = ?", user_id)
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+
+        self.cache.clear();
+        Ok(result.rows_affected())
+    }
+
+    // Files in `uploads/` referenced from the user's chats and from no one
+    // else's, to remove along with the user
+    pub async fn user_uploads(&self, user_id: i64) -> sqlx::Result<Vec<String>> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT messages.message, messages.images
+            FROM messages
+            JOIN message_pairs
+                ON messages.id IN (message_pairs.human_message_id, message_pairs.ai_message_id)
+            JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+            JOIN chats ON chats.id = message_blocks.chat_id
+            WHERE chats.user_id = ?
+                AND (messages.message LIKE '%/uploads/%' OR messages.images LIKE '%/uploads/%')
+            "#,
+            user_id
+        )
+        .fetch_all(&*self.pool)
+        .await?;
+
+        let attached = sqlx::query_scalar!(
+            r#"
+            SELECT DISTINCT attachments.path
+            FROM attachments
+            JOIN message_pairs ON message_pairs.id = attachments.message_pair_id
+            JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+            JOIN chats ON chats.id = message_blocks.chat_id
+            WHERE chats.user_id = ?
+            "#,
+            user_id
+        )
+        .fetch_all(&*self.pool)
+        .await?;
+
+        let mut files: Vec<String> = Vec::new();
+        for row in rows {
+            let images = row.images.unwrap_or_default();
+            for file in upload_files(&row.message).into_iter().chain(upload_files(&images)) {
+                if !files.contains(&file) {
+                    files.push(file);
+                }
+            }
+        }
+        for file in attached {
+            if !files.contains(&file) {
+                files.push(file);
+            }
+        }
+
+        let mut unshared = Vec::new();
+        for file in files {
+            let pattern = format!("%/uploads/{}%", file);
+            let shared = sqlx::query_scalar!(
+                r#"
+                SELECT EXISTS (
+                    SELECT 1 FROM messages
+                    JOIN message_pairs
+                        ON messages.id IN (message_pairs.human_message_id, message_pairs.ai_message_id)
+                    JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+                    JOIN chats ON chats.id = message_blocks.chat_id
+                    WHERE chats.user_id != ?1
+                        AND (messages.message LIKE ?2 OR messages.images LIKE ?2)
+                ) OR EXISTS (
+                    SELECT 1 FROM attachments
+                    JOIN message_pairs ON message_pairs.id = attachments.message_pair_id
+                    JOIN message_blocks ON message_blocks.id = message_pairs.message_block_id
+                    JOIN chats ON chats.id = message_blocks.chat_id
+                    WHERE chats.user_id != ?1 AND attachments.path = ?3
+                ) AS "shared!: bool"
+                "#,
+                user_id,
+                pattern,
+                file
+            )
+            .fetch_one(&*self.pool)
+            .await?;
+            if !shared {
+                unshared.push(file);
+            }
+        }
+
+        Ok(unshared)
+    }
+
+    pub async fn get_webhooks(&self, user_id: i64) -> sqlx::Result<Vec<Webhook>> {
+        sqlx::query_as!(
+            Webhook,
+            r#"
+            SELECT
+                webhooks.id AS "id!", webhooks.url, webhooks.secret, webhooks.events,
+                latest.status AS "last_status?", latest.last_error AS "last_error?"
+            FROM webhooks
+            LEFT JOIN webhook_deliveries latest ON latest.id = (
+                SELECT id FROM webhook_deliveries
+                WHERE webhook_id = webhooks.id AND attempts > 0
+                ORDER BY id DESC LIMIT 1
+            )
+            WHERE webhooks.user_id = ?
+            ORDER BY webhooks.id
+            "#,
+            user_id
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    pub async fn create_webhook(&self, user_id: i64, url: &str, events: &str) -> sqlx::Result<i64> {
+        let secret = format!("whsec_{}", uuid::Uuid::new_v4().simple());
+        let created = sqlx::query!(
+            r#"INSERT INTO webhooks (user_id, url, secret, events) VALUES (?, ?, ?, ?) RETURNING id AS "id!""#,
+            user_id,
+            url,
+            secret,
+            events
+        )
+        .fetch_one(&*self.pool)
+        .await?;
+        Ok(created.id)
+    }
+
+    pub async fn delete_webhook(&self, user_id: i64, webhook_id: i64) -> sqlx::Result<u64> {
+        let result = sqlx::query!(
+            "DELETE FROM webhooks WHERE id = ? AND user_id = ?",
+            webhook_id,
+            user_id
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    // Queue an event for every webhook of the chat's owner subscribed to it
+    pub async fn enqueue_webhook_event(
+        &self,
+        chat_id: i64,
+        event: &str,
+        payload: &str,
+    ) -> sqlx::Result<u64> {
+        let result = sqlx::query!(
+            r#"
+            INSERT INTO webhook_deliveries (webhook_id, event, payload)
+            SELECT id, ?, ? FROM webhooks
+            WHERE user_id = (SELECT user_id FROM chats WHERE id = ?)
+                AND ',' || events || ',' LIKE '%,' || ? || ',%'
+            "#,
+            event,
+            payload,
+            chat_id,
+            event
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    pub async fn get_due_webhook_deliveries(&self, limit: i64) -> sqlx::Result<Vec<WebhookDelivery>> {
+        sqlx::query_as!(
+            WebhookDelivery,
+            r#"
+            SELECT
+                webhook_deliveries.id AS "id!", webhooks.url, webhooks.secret,
+                webhook_deliveries.event, webhook_deliveries.payload, webhook_deliveries.attempts
+            FROM webhook_deliveries
+            JOIN webhooks ON webhooks.id = webhook_deliveries.webhook_id
+            WHERE webhook_deliveries.status = 'pending'
+                AND webhook_deliveries.next_attempt_at <= CURRENT_TIMESTAMP
+            ORDER BY webhook_deliveries.next_attempt_at, webhook_deliveries.id
+            LIMIT ?
+            "#,
+            limit
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    // Record an attempt. A failure is retried after `retry_in` seconds, or
+    // given up on when that's `None`
+    pub async fn record_webhook_attempt(
+        &self,
+        delivery_id: i64,
+        error: Option<&str>,
+        retry_in: Option<i64>,
+    ) -> sqlx::Result<()> {
+        let status = match (error, retry_in) {
+            (None, _) => "delivered",
+            (Some(_), Some(_)) => "pending",
+            (Some(_), None) => "failed",
+        };
+        let retry_in = format!("+{} seconds", retry_in.unwrap_or(0));
+        sqlx::query!(
+            r#"
+            UPDATE webhook_deliveries
+            SET status = ?, attempts = attempts + 1, last_error = ?,
+                next_attempt_at = datetime('now', ?)
+            WHERE id = ?
+            "#,
+            status,
+            error,
+            retry_in,
+            delivery_id
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn create_data_export(&self, user_id: i64) -> sqlx::Result<i64> {
+        sqlx::query_scalar!(
+            r#"INSERT INTO data_exports (user_id) VALUES (?) RETURNING id AS "id!""#,
+            user_id
+        )
+        .fetch_one(&*self.pool)
+        .await
+    }
+
+    pub async fn get_latest_data_export(&self, user_id: i64) -> sqlx::Result<Option<DataExport>> {
+        sqlx::query_as!(
+            DataExport,
+            r#"
+            SELECT id AS "id!", status, progress, file, error,
+                created_at AS "created_at: DateTime<Utc>", finished_at AS "finished_at: DateTime<Utc>"
+            FROM data_exports
+            WHERE user_id = ?
+            ORDER BY id DESC
+            LIMIT 1
+            "#,
+            user_id
+        )
+        .fetch_optional(&*self.pool)
+        .await
+    }
+
+    pub async fn set_data_export_progress(&self, export_id: i64, progress: i64) -> sqlx::Result<()> {
+        sqlx::query!("UPDATE data_exports SET progress = ? WHERE id = ?", progress, export_id)
+            .execute(&*self.pool)
+            .await?;
+        Ok(())
+    }
+
+    // Done with the zip in `file`, or failed with `error`
+    pub async fn finish_data_export(&self, export_id: i64, file: Option<&str>, error: Option<&str>) -> sqlx::Result<()> {
+        sqlx::query!(
+            r#"
+            UPDATE data_exports
+            SET status = CASE WHEN ?3 IS NULL THEN 'done' ELSE 'failed' END,
+                progress = CASE WHEN ?3 IS NULL THEN 100 ELSE progress END,
+                file = ?2, error = ?3, finished_at = CURRENT_TIMESTAMP
+            WHERE id = ?1
+            "#,
+            export_id,
+            file,
+            error
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(())
+    }
+
+    // Removes the user's older exports, returning their files to delete
+    pub async fn delete_data_exports_before(&self, user_id: i64, export_id: i64) -> sqlx::Result<Vec<String>> {
+        let files = sqlx::query_scalar!(
+            "DELETE FROM data_exports WHERE user_id = ? AND id < ? RETURNING file",
+            user_id,
+            export_id
+        )
+        .fetch_all(&*self.pool)
+        .await?;
+        Ok(files.into_iter().flatten().collect())
+    }
+
+    // Exports cut short by a restart won't finish
+    pub async fn fail_interrupted_data_exports(&self) -> sqlx::Result<u64> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE data_exports SET status = 'failed', error = 'Interrupted by a server restart',
+                finished_at = CURRENT_TIMESTAMP
+            WHERE status = 'running'
+            "#
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    // Creates the org with the user as its owner
+    pub async fn create_org(&self, user_id: i64, name: &str) -> sqlx::Result<i64> {
+        let mut tx: Transaction<Sqlite> = self.pool.begin().await?;
+
+        let org_id = sqlx::query_scalar!(r#"INSERT INTO orgs (name) VALUES (?) RETURNING id AS "id!""#, name)
+            .fetch_one(&mut *tx)
+            .await?;
+        sqlx::query!(
+            "INSERT INTO org_members (org_id, user_id, role) VALUES (?, ?, 'owner')",
+            org_id,
+            user_id
+        )
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(org_id)
+    }
+
+    pub async fn get_user_orgs(&self, user_id: i64) -> sqlx::Result<Vec<OrgMembership>> {
+        sqlx::query_as!(
+            OrgMembership,
+            r#"
+            SELECT orgs.id AS "id!", orgs.name, org_members.role,
+                (SELECT COUNT(*) FROM org_members AS others WHERE others.org_id = orgs.id) AS "members!: i64"
+            FROM org_members
+            JOIN orgs ON orgs.id = org_members.org_id
+            WHERE org_members.user_id = ?
+            ORDER BY orgs.name
+            "#,
+            user_id
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    pub async fn get_org(&self, org_id: i64) -> sqlx::Result<Option<Org>> {
+        sqlx::query_as!(
+            Org,
+            r#"
+            SELECT id AS "id!", name, openai_api_key, model, created_at AS "created_at: DateTime<Utc>"
+            FROM orgs
+            WHERE id = ?
+            "#,
+            org_id
+        )
+        .fetch_optional(&*self.pool)
+        .await
+    }
+
+    // `None` if the user isn't a member
+    pub async fn get_org_role(&self, org_id: i64, user_id: i64) -> sqlx::Result<Option<OrgRole>> {
+        let role = sqlx::query_scalar!(
+            "SELECT role FROM org_members WHERE org_id = ? AND user_id = ?",
+            org_id,
+            user_id
+        )
+        .fetch_optional(&*self.pool)
+        .await?;
+        Ok(role.as_deref().and_then(OrgRole::parse))
+    }
+
+    pub async fn get_org_members(&self, org_id: i64) -> sqlx::Result<Vec<OrgMember>> {
+        sqlx::query_as!(
+            OrgMember,
+            r#"
+            SELECT users.id AS "user_id!", users.email, users.display_name, org_members.role
+            FROM org_members
+            JOIN users ON users.id = org_members.user_id
+            WHERE org_members.org_id = ?
+            ORDER BY org_members.role = 'owner' DESC, org_members.role = 'admin' DESC, users.email
+            "#,
+            org_id
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    // 0 if no account uses the email, or it's already a member
+    pub async fn add_org_member(&self, org_id: i64, email: &str, role: OrgRole) -> sqlx::Result<u64> {
+        let role = role.as_str();
+        let result = sqlx::query!(
+            r#"
+            INSERT INTO org_members (org_id, user_id, role)
+            SELECT ?, id, ? FROM users WHERE email = ?
+            ON CONFLICT (org_id, user_id) DO NOTHING
+            "#,
+            org_id,
+            role,
+            email
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    pub async fn set_org_role(&self, org_id: i64, user_id: i64, role: OrgRole) -> sqlx::Result<u64> {
+        let role = role.as_str();
+        let result = sqlx::query!(
+            "UPDATE org_members SET role = ? WHERE org_id = ? AND user_id = ?",
+            role,
+            org_id,
+            user_id
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    pub async fn remove_org_member(&self, org_id: i64, user_id: i64) -> sqlx::Result<u64> {
+        let result = sqlx::query!(
+            "DELETE FROM org_members WHERE org_id = ? AND user_id = ?",
+            org_id,
+            user_id
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    pub async fn count_org_owners(&self, org_id: i64) -> sqlx::Result<i64> {
+        sqlx::query_scalar!(
+            r#"SELECT COUNT(*) AS "n!: i64" FROM org_members WHERE org_id = ? AND role = 'owner'"#,
+            org_id
+        )
+        .fetch_one(&*self.pool)
+        .await
+    }
+
+    pub async fn update_org(&self, org_id: i64, name: &str, model: Option<&str>) -> sqlx::Result<()> {
+        sqlx::query!("UPDATE orgs SET name = ?, model = ? WHERE id = ?", name, model, org_id)
+            .execute(&*self.pool)
+            .await?;
+        Ok(())
+    }
+
+    // `stored` is already encrypted; `None` removes the key
+    pub async fn set_org_api_key(&self, org_id: i64, stored: Option<&str>) -> sqlx::Result<()> {
+        sqlx::query!("UPDATE orgs SET openai_api_key = ? WHERE id = ?", stored, org_id)
+            .execute(&*self.pool)
+            .await?;
+        Ok(())
+    }
+
+    // The org's chats go back to the personal workspaces of their creators
+    pub async fn delete_org(&self, org_id: i64) -> sqlx::Result<u64> {
+        let result = sqlx::query!("DELETE FROM orgs WHERE id = ?", org_id)
+            .execute(&*self.pool)
+            .await?;
+        self.cache.clear();
+        Ok(result.rows_affected())
+    }
+
+    // `None` switches to the personal workspace
+    pub async fn set_current_org(&self, user_id: i64, org_id: Option<i64>) -> sqlx::Result<()> {
+        sqlx::query!("UPDATE users SET current_org_id = ? WHERE id = ?", org_id, user_id)
+            .execute(&*self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn set_chat_org(&self, chat_id: i64, org_id: Option<i64>) -> sqlx::Result<()> {
+        sqlx::query!("UPDATE chats SET org_id = ? WHERE id = ?", org_id, chat_id)
+            .execute(&*self.pool)
+            .await?;
+        self.cache.chats_changed();
+        Ok(())
+    }
+
+    // The org the chat belongs to, if any
+    pub async fn get_chat_org(&self, chat_id: i64) -> sqlx::Result<Option<Org>> {
+        sqlx::query_as!(
+            Org,
+            r#"
+            SELECT orgs.id AS "id!", orgs.name, orgs.openai_api_key, orgs.model,
+                orgs.created_at AS "created_at: DateTime<Utc>"
+            FROM chats
+            JOIN orgs ON orgs.id = chats.org_id
+            WHERE chats.id = ?
+            "#,
+            chat_id
+        )
+        .fetch_optional(&*self.pool)
+        .await
+    }
+
+    // The user's own agents, plus those shared in the org when one is given.
+    // Up to `limit` agents (`NO_LIMIT` for all) come after the agent `cursor`
+    pub async fn get_agents(
+        &self,
+        user_id: i64,
+        org_id: Option<i64>,
+        cursor: Option<i64>,
+        limit: i64,
+    ) -> sqlx::Result<Vec<Agent>> {
+        let key = (user_id, org_id, cursor, limit);
+        self.cache
+            .agent_pages
+            .get_or_load(key, || async {
+                sqlx::query_as!(
+                    Agent,
+                    r#"
+                    SELECT
+                        a.id AS "id!", a.user_id, a.org_id, a.name, a.model, a.system_prompt, a.temperature,
+                        a.top_p, a.max_tokens, a.stop_sequences, a.tools_enabled, a.allow_tools, a.version
+                    FROM agents a
+                    LEFT JOIN agents cur ON cur.id = ?3
+                    WHERE ((a.user_id = ?1 AND a.org_id IS NULL) OR a.org_id = ?2) AND a.deleted_at IS NULL
+                        AND (?3 IS NULL OR (a.org_id IS NOT NULL, a.name, a.id)
+                            > (cur.org_id IS NOT NULL, cur.name, cur.id))
+                    ORDER BY a.org_id IS NOT NULL, a.name, a.id
+                    LIMIT ?4
+                    "#,
+                    user_id,
+                    org_id,
+                    cursor,
+                    limit
+                )
+                .fetch_all(&*self.pool)
+                .await
+            })
+            .await
+    }
+
+    // How many agents `get_agents` lists over all pages
+    pub async fn count_agents(&self, user_id: i64, org_id: Option<i64>) -> sqlx::Result<i64> {
+        let key = (user_id, org_id);
+        self.cache
+            .agent_counts
+            .get_or_load(key, || async {
+                sqlx::query_scalar!(
+                    r#"
+                    SELECT COUNT(*) AS "count!: i64"
+                    FROM agents
+                    WHERE ((user_id = ?1 AND org_id IS NULL) OR org_id = ?2) AND deleted_at IS NULL
+                    "#,
+                    user_id,
+                    org_id
+                )
+                .fetch_one(&*self.pool)
+                .await
+            })
+            .await
+    }
+
+    // Agents are keyed by name per user, so saving an existing name updates
+    // it, along with the org it's shared in, and brings it back from the trash
+    pub async fn save_agent(&self, agent: &Agent) -> sqlx::Result<i64> {
+        let saved = sqlx::query!(
+            r#"
+            INSERT INTO agents (
+                user_id, org_id, name, model, system_prompt, temperature, top_p, max_tokens,
+                stop_sequences, tools_enabled, allow_tools
+            )
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+            ON CONFLICT (user_id, name) DO UPDATE SET
+                org_id = excluded.org_id,
+                model = excluded.model,
+                system_prompt = excluded.system_prompt,
+                temperature = excluded.temperature,
+                top_p = excluded.top_p,
+                max_tokens = excluded.max_tokens,
+                stop_sequences = excluded.stop_sequences,
+                tools_enabled = excluded.tools_enabled,
+                allow_tools = excluded.allow_tools,
+                deleted_at = NULL,
+                version = agents.version + 1
+            RETURNING id
+            "#,
+            agent.user_id,
+            agent.org_id,
+            agent.name,
+            agent.model,
+            agent.system_prompt,
+            agent.temperature,
+            agent.top_p,
+            agent.max_tokens,
+            agent.stop_sequences,
+            agent.tools_enabled,
+            agent.allow_tools
+        )
+        .fetch_one(&*self.pool)
+        .await?;
+        self.cache.agents_changed();
+        Ok(saved.id)
+    }
+
+    // Saves an edit of one of the user's agents made on the page that showed
+    // it at `version`. False when the agent changed since, or is gone
+    pub async fn update_agent(&self, agent: &Agent, version: i64) -> sqlx::Result<bool> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE agents SET
+                org_id = ?, model = ?, system_prompt = ?, temperature = ?, top_p = ?,
+                max_tokens = ?, stop_sequences = ?, tools_enabled = ?, allow_tools = ?,
+                version = version + 1
+            WHERE id = ? AND user_id = ? AND version = ? AND deleted_at IS NULL
+            "#,
+            agent.org_id,
+            agent.model,
+            agent.system_prompt,
+            agent.temperature,
+            agent.top_p,
+            agent.max_tokens,
+            agent.stop_sequences,
+            agent.tools_enabled,
+            agent.allow_tools,
+            agent.id,
+            agent.user_id,
+            version
+        )
+        .execute(&*self.pool)
+        .await?;
+        self.cache.agents_changed();
+        Ok(result.rows_affected() > 0)
+    }
+
+    // Moves the agent to the trash, see `purge_trashed_agents`
+    pub async fn delete_agent(&self, user_id: i64, agent_id: i64) -> sqlx::Result<u64> {
+        let result = sqlx::query!(
+            "UPDATE agents SET deleted_at = CURRENT_TIMESTAMP WHERE id = ? AND user_id = ? AND deleted_at IS NULL",
+            agent_id,
+            user_id
+        )
+        .execute(&*self.pool)
+        .await?;
+        self.cache.agents_changed();
+        Ok(result.rows_affected())
+    }
+
+    pub async fn get_trashed_agents(&self, user_id: i64, retention_days: i64) -> sqlx::Result<Vec<TrashedAgent>> {
+        sqlx::query_as!(
+            TrashedAgent,
+            r#"
+            SELECT
+                id AS "id!",
+                name,
+                deleted_at AS "deleted_at!: DateTime<Utc>",
+                MAX(0, ?2 - CAST(julianday('now') - julianday(deleted_at) AS INTEGER)) AS "days_left!: i64"
+            FROM agents
+            WHERE user_id = ?1 AND deleted_at IS NOT NULL
+            ORDER BY deleted_at DESC
+            "#,
+            user_id,
+            retention_days
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    pub async fn restore_agent(&self, user_id: i64, agent_id: i64) -> sqlx::Result<u64> {
+        let result = sqlx::query!(
+            "UPDATE agents SET deleted_at = NULL WHERE id = ? AND user_id = ? AND deleted_at IS NOT NULL",
+            agent_id,
+            user_id
+        )
+        .execute(&*self.pool)
+        .await?;
+        self.cache.agents_changed();
+        Ok(result.rows_affected())
+    }
+
+    // Permanently deletes agents that have been in the trash for
+    // `retention_days`; their chats keep going without them
+    pub async fn purge_trashed_agents(&self, retention_days: i64) -> sqlx::Result<u64> {
+        let cutoff = format!("-{} days", retention_days);
+        let result = sqlx::query!(
+            "DELETE FROM agents WHERE deleted_at <= datetime('now', ?)",
+            cutoff
+        )
+        .execute(&*self.pool)
+        .await?;
+        self.cache.agents_changed();
+        Ok(result.rows_affected())
+    }
+
+    pub async fn get_chat_agent(&self, chat_id: i64) -> sqlx::Result<Option<Agent>> {
+        sqlx::query_as!(
+            Agent,
+            r#"
+            SELECT
+                agents.id AS "id!", agents.user_id, agents.org_id, agents.name, agents.model,
+                agents.system_prompt, agents.temperature, agents.top_p, agents.max_tokens,
+                agents.stop_sequences, agents.tools_enabled, agents.allow_tools, agents.version
+            FROM chats
+            JOIN agents ON agents.id = chats.agent_id
+            WHERE chats.id = ? AND agents.deleted_at IS NULL
+            "#,
+            chat_id
+        )
+        .fetch_optional(&*self.pool)
+        .await
+    }
+
+    // Binds the chat to one of the user's own agents, or one shared in an
+    // org they belong to
+    pub async fn set_chat_agent(&self, chat_id: i64, user_id: i64, agent_id: i64) -> sqlx::Result<u64> {
+        let result = sqlx::query!(
+            r#"
+            UPDATE chats SET agent_id = ?1
+            WHERE id = ?2 AND user_id = ?3
+                AND EXISTS (
+                    SELECT 1 FROM agents
+                    WHERE id = ?1 AND deleted_at IS NULL AND (
+                        user_id = ?3
+                        OR org_id IN (SELECT org_id FROM org_members WHERE user_id = ?3)
+                    )
+                )
+            "#,
+            agent_id,
+            chat_id,
+            user_id
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+
+    pub async fn get_custom_tools(&self, user_id: i64) -> sqlx::Result<Vec<CustomTool>> {
+        sqlx::query_as!(
+            CustomTool,
+            r#"
+            SELECT id AS "id!", user_id, name, description, parameters, url, auth_header
+            FROM custom_tools
+            WHERE user_id = ?
+            ORDER BY name
+            "#,
+            user_id
+        )
+        .fetch_all(&*self.pool)
+        .await
+    }
+
+    pub async fn save_custom_tool(
+        &self,
+        user_id: i64,
+        name: &str,
+        description: &str,
+        parameters: &str,
+        url: &str,
+        auth_header: Option<&str>,
+    ) -> sqlx::Result<()> {
+        sqlx::query!(
+            r#"
+            INSERT INTO custom_tools (user_id, name, description, parameters, url, auth_header)
+            VALUES (?, ?, ?, ?, ?, ?)
+            ON CONFLICT (user_id, name) DO UPDATE SET
+                description = excluded.description,
+                parameters = excluded.parameters,
+                url = excluded.url,
+                auth_header = excluded.auth_header
+            "#,
+            user_id,
+            name,
+            description,
+            parameters,
+            url,
+            auth_header
+        )
+        .execute(&*self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    // The auth header of every custom tool that has one, as stored
+    pub async fn custom_tool_auth_headers(&self) -> sqlx::Result<Vec<(i64, String)>> {
+        let rows = sqlx::query!(
+            r#"
+            SELECT id AS "id!", auth_header AS "auth_header!"
+            FROM custom_tools
+            WHERE auth_header IS NOT NULL AND auth_header != ''
+            "#
+        )
+        .fetch_all(&*self.pool)
+        .await?;
+        Ok(rows.into_iter().map(|row| (row.id, row.auth_header)).collect())
+    }
+
+    pub async fn set_custom_tool_auth_header(&self, tool_id: i64, stored: &str) -> sqlx::Result<()> {
+        sqlx::query!("UPDATE custom_tools SET auth_header = ? WHERE id = ?", stored, tool_id)
+            .execute(&*self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn delete_custom_tool(&self, user_id: i64, tool_id: i64) -> sqlx::Result<u64> {
+        let result = sqlx::query!(
+            "DELETE FROM custom_tools WHERE id = ? AND user_id = ?",
+            tool_id,
+            user_id
+        )
+        .execute(&*self.pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+}
+
+// Quote every term so user input is matched literally instead of being parsed
+// as FTS5 query syntax
+fn fts_match_query(query: &str) -> String {
+    query
+        .split_whitespace()
+        .map(|term| format!("\"{}\"", term.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use sqlx::migrate::Migrator;
+
+    use super::*;
+    use crate::data::model::{ChatStats, FunctionCall, ToolCall, ToolCallStatus};
+
+    async fn setup() -> (Arc<SqlitePool>, ChatRepository, i64) {
+        vector::register();
         let x = std::env::var("DATABASE_URL").unwrap_or_else(|_| "sqlite:db.db".to_string());
         let pool = SqlitePool::connect(&x).await.unwrap();
 
-        // let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
-        let pool = Arc::new(pool);
+        // let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let pool = Arc::new(pool);
+
+        let migrator = Migrator::new(Path::new(dotenv::var("MIGRATIONS_PATH").unwrap().as_str()))
+            .await
+            .unwrap();
+        // Run the migrations.
+        migrator.run(&*pool).await.unwrap();
+
+        let user = sqlx::query!(
+            "INSERT INTO users (email, password) VALUES ($1, $2) RETURNING id",
+            "test@test.com",
+            "test"
+        )
+        .fetch_one(&*pool)
+        .await
+        .unwrap();
+
+        let repo = ChatRepository::new(pool.clone());
+
+        (pool, repo, user.id)
+    }
+
+    #[tokio::test]
+    async fn test_create_chat() {
+        let (pool, repo, user_id) = setup().await;
+        let chat = repo.create_chat(user_id, "test", "gpt-4").await;
+        assert!(chat.is_ok(), "Failed to create chat");
+    }
+
+    #[tokio::test]
+    async fn test_add_message_block() {
+        let (pool, repo, user_id) = setup().await;
+        let chat = repo.create_chat(user_id, "test", "gpt-4").await;
+        assert!(chat.is_ok(), "Failed to create chat");
+        let chat_id = chat.unwrap();
+
+        let message_block = repo.add_message_block(chat_id, "Test").await;
+        assert!(message_block.is_ok(), "Failed to add message_block")
+    }
+
+    #[tokio::test]
+    async fn test_json() {
+        let (pool, repo, user_id) = setup().await;
+        let chat = repo.create_chat(user_id, "test", "gpt-4").await;
+        assert!(chat.is_ok(), "Failed to create chat");
+        let chat_id = chat.unwrap();
+
+        let message_block = repo.add_message_block(chat_id, "Test").await;
+        assert!(message_block.is_ok(), "Failed to add message_block");
+
+        let chat_message_pairs = repo.retrieve_chat(chat_id).await;
+        print!("{:#?}", chat_message_pairs)
+    }
+
+    #[tokio::test]
+    async fn test_edit_human_message_branches_the_conversation() {
+        let (_pool, repo, user_id) = setup().await;
+        let chat_id = repo.create_chat(user_id, "test", "gpt-4").await.unwrap();
+        let first = repo.add_message_block(chat_id, "first").await.unwrap();
+        repo.add_ai_message_to_pair(first, "answer").await.unwrap();
+        let second = repo.add_message_block(chat_id, "second").await.unwrap();
+
+        let edited = repo
+            .edit_human_message(chat_id, first, "first, edited")
+            .await
+            .unwrap()
+            .unwrap();
+
+        let pairs = repo.retrieve_chat(chat_id).await.unwrap();
+        assert_eq!(pairs.len(), 1);
+        assert_eq!(pairs[0].id, edited);
+        assert_eq!(pairs[0].human_message, "first, edited");
+        assert!(pairs[0].ai_message.is_none());
+        assert_eq!((pairs[0].block_rank, pairs[0].block_size), (2, 2));
+
+        // Superseded and replaced pairs can't be edited again
+        assert!(repo.edit_human_message(chat_id, second, "x").await.unwrap().is_none());
+        assert!(repo.edit_human_message(chat_id, first, "x").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_delete_message_pair() {
+        let (pool, repo, user_id) = setup().await;
+        let chat_id = repo.create_chat(user_id, "test", "gpt-4").await.unwrap();
+        let first = repo.add_message_block(chat_id, "first").await.unwrap();
+        let middle = repo.add_message_block(chat_id, "middle").await.unwrap();
+        repo.add_ai_message_to_pair(middle, "bad answer").await.unwrap();
+        let last = repo.add_message_block(chat_id, "last").await.unwrap();
+
+        assert_eq!(repo.delete_message_pair(chat_id, user_id + 1, middle).await.unwrap(), 0);
+        assert_eq!(repo.delete_message_pair(chat_id, user_id, middle).await.unwrap(), 1);
+
+        // The rest of the conversation is kept, and the pair waits in the trash
+        let ids: Vec<i64> = repo.retrieve_chat(chat_id).await.unwrap().iter().map(|p| p.id).collect();
+        assert_eq!(ids, vec![first, last]);
+        assert_eq!(repo.delete_message_pair(chat_id, user_id, middle).await.unwrap(), 0);
+        assert_eq!(repo.count_chat_messages(chat_id).await.unwrap(), 2);
+        let trashed = |message: &'static str| {
+            let pool = pool.clone();
+            async move {
+                sqlx::query!("SELECT COUNT(*) AS n FROM messages WHERE message = ?", message)
+                    .fetch_one(&*pool)
+                    .await
+                    .unwrap()
+                    .n
+            }
+        };
+        assert_eq!(trashed("bad answer").await, 1);
+
+        // Deleting the selected version falls back to the previous one, and
+        // takes the version's branch with it
+        let edited = repo.edit_human_message(chat_id, last, "last, edited").await.unwrap().unwrap();
+        repo.add_message_block(chat_id, "after the edit").await.unwrap();
+        assert_eq!(repo.delete_message_pair(chat_id, user_id, edited).await.unwrap(), 2);
+        let pairs = repo.retrieve_chat(chat_id).await.unwrap();
+        assert_eq!(pairs.last().unwrap().id, last);
+        assert_eq!(pairs.last().unwrap().block_size, 1);
+
+        // Purging removes trashed pairs for good, along with their messages
+        assert_eq!(repo.purge_trashed_messages(30).await.unwrap(), 0);
+        sqlx::query!(
+            r#"
+            UPDATE message_pairs SET deleted_at = datetime('now', '-31 days')
+            WHERE deleted_at IS NOT NULL
+                AND message_block_id IN (SELECT id FROM message_blocks WHERE chat_id = ?)
+            "#,
+            chat_id
+        )
+        .execute(&*pool)
+        .await
+        .unwrap();
+        assert!(repo.purge_trashed_messages(30).await.unwrap() >= 3);
+        assert_eq!(trashed("bad answer").await, 0);
+        assert_eq!(trashed("after the edit").await, 0);
+        let ids: Vec<i64> = repo.retrieve_chat(chat_id).await.unwrap().iter().map(|p| p.id).collect();
+        assert_eq!(ids, vec![first, last]);
+    }
+
+    #[tokio::test]
+    async fn test_switch_between_versions_restores_branches() {
+        let (_pool, repo, user_id) = setup().await;
+        let chat_id = repo.create_chat(user_id, "test", "gpt-4").await.unwrap();
+        let question = repo.add_message_block(chat_id, "question").await.unwrap();
+        let follow_up = repo.add_message_block(chat_id, "follow-up").await.unwrap();
+        let block_id = repo.retrieve_chat(chat_id).await.unwrap()[0].message_block_id;
+
+        let retry = repo.edit_human_message(chat_id, question, "question").await.unwrap().unwrap();
+        let ids = |pairs: Vec<ChatMessagePair>| pairs.iter().map(|p| p.id).collect::<Vec<_>>();
+        assert_eq!(ids(repo.retrieve_chat(chat_id).await.unwrap()), vec![retry]);
+
+        assert_eq!(repo.select_block_version(chat_id, user_id, block_id, 1).await.unwrap(), 1);
+        assert_eq!(ids(repo.retrieve_chat(chat_id).await.unwrap()), vec![question, follow_up]);
+
+        // Out of range ranks and other users are rejected
+        assert_eq!(repo.select_block_version(chat_id, user_id, block_id, 3).await.unwrap(), 0);
+        assert_eq!(repo.select_block_version(chat_id, user_id + 1, block_id, 2).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_retrieve_chat_page() {
+        let (_pool, repo, user_id) = setup().await;
+        let chat_id = repo.create_chat(user_id, "test", "gpt-4").await.unwrap();
+        let mut pair_ids = Vec::new();
+        for i in 0..5 {
+            pair_ids.push(repo.add_message_block(chat_id, &format!("message {}", i)).await.unwrap());
+        }
+
+        let latest = repo.retrieve_chat_page(chat_id, None, 2).await.unwrap();
+        let ids: Vec<i64> = latest.iter().map(|p| p.id).collect();
+        assert_eq!(ids, pair_ids[3..]);
+
+        let older = repo
+            .retrieve_chat_page(chat_id, Some(latest[0].message_block_id), 10)
+            .await
+            .unwrap();
+        let ids: Vec<i64> = older.iter().map(|p| p.id).collect();
+        assert_eq!(ids, pair_ids[..3]);
+    }
+
+    #[tokio::test]
+    async fn test_message_feedback() {
+        let (_pool, repo, user_id) = setup().await;
+        let chat_id = repo.create_chat(user_id, "test", "feedback-model").await.unwrap();
+        let good = repo.add_message_block(chat_id, "good").await.unwrap();
+        let bad = repo.add_message_block(chat_id, "bad").await.unwrap();
+
+        repo.set_feedback(user_id, chat_id, good, Some(1)).await.unwrap();
+        repo.set_feedback(user_id, chat_id, bad, Some(1)).await.unwrap();
+        repo.set_feedback(user_id, chat_id, bad, Some(-1)).await.unwrap();
+        // Pairs of other users' chats can't be rated
+        assert_eq!(repo.set_feedback(user_id + 1, chat_id, good, Some(-1)).await.unwrap(), 0);
+
+        let summary = repo.feedback_summary(user_id).await.unwrap();
+        assert_eq!(summary.len(), 1);
+        assert_eq!((summary[0].positive, summary[0].negative), (1, 1));
+
+        repo.set_feedback(user_id, chat_id, bad, None).await.unwrap();
+        let feedback = repo.get_feedback(user_id, chat_id).await.unwrap();
+        assert_eq!(feedback.len(), 1);
+        assert_eq!((feedback[0].pair_id, feedback[0].rating), (good, 1));
+    }
+
+    #[tokio::test]
+    async fn test_toggle_bookmark() {
+        let (_pool, repo, user_id) = setup().await;
+        let chat_id = repo.create_chat(user_id, "saved", "gpt-4").await.unwrap();
+        let answered = repo.add_message_block(chat_id, "question").await.unwrap();
+        repo.add_ai_message_to_pair(answered, "answer").await.unwrap();
+        let pending = repo.add_message_block(chat_id, "no answer yet").await.unwrap();
+
+        assert_eq!(repo.toggle_bookmark(user_id, chat_id, answered).await.unwrap(), Some(true));
+        assert_eq!(repo.toggle_bookmark(user_id, chat_id, pending).await.unwrap(), None);
+        assert_eq!(repo.toggle_bookmark(user_id + 1, chat_id, answered).await.unwrap(), None);
+
+        let bookmarks = repo.get_bookmarks(user_id).await.unwrap();
+        assert_eq!(bookmarks.len(), 1);
+        assert_eq!(bookmarks[0].chat_name, "saved");
+        assert_eq!(bookmarks[0].ai_message, "answer");
+        assert_eq!(repo.get_bookmarked_pairs(user_id, chat_id).await.unwrap(), vec![answered]);
+
+        assert_eq!(repo.toggle_bookmark(user_id, chat_id, answered).await.unwrap(), Some(false));
+        assert!(repo.get_bookmarks(user_id).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_continued_response_replaces_the_partial_one() {
+        let (pool, repo, user_id) = setup().await;
+        let chat_id = repo.create_chat(user_id, "test", "gpt-4").await.unwrap();
+        let pair_id = repo.add_message_block(chat_id, "Write a long story").await.unwrap();
+
+        let partial = repo
+            .add_ai_message_with_extended_data(pair_id, "Once upon", None, None, None, None, None, None, None, None)
+            .await
+            .unwrap();
+        repo.set_finish_reason(partial, "length").await.unwrap();
+        let pairs = repo.retrieve_chat(chat_id).await.unwrap();
+        assert_eq!(pairs[0].finish_reason.as_deref(), Some("length"));
+
+        let full = repo
+            .add_ai_message_with_extended_data(pair_id, "Once upon a time", None, None, None, None, None, None, None, None)
+            .await
+            .unwrap();
+        repo.set_finish_reason(full, "stop").await.unwrap();
+
+        let pairs = repo.retrieve_chat(chat_id).await.unwrap();
+        assert_eq!(pairs[0].ai_message.as_deref(), Some("Once upon a time"));
+        assert_eq!(pairs[0].finish_reason.as_deref(), Some("stop"));
+        let leftover = sqlx::query!("SELECT COUNT(*) AS n FROM messages WHERE id = ?", partial)
+            .fetch_one(&*pool)
+            .await
+            .unwrap();
+        assert_eq!(leftover.n, 0);
+    }
+
+    #[tokio::test]
+    async fn test_chat_settings() {
+        let (_pool, repo, user_id) = setup().await;
+        let chat_id = repo.create_chat(user_id, "test", "gpt-4").await.unwrap();
+        assert!(repo.get_chat_settings(chat_id).await.unwrap().stop_sequences().is_empty());
+
+        let settings = ChatSettings {
+            chat_id,
+            stop_sequences: Some(r#"["END", "\n\nUser:"]"#.to_string()),
+            temperature: Some(0.2),
+            ..Default::default()
+        };
+        assert_eq!(repo.save_chat_settings(user_id + 1, &settings).await.unwrap(), 0);
+        assert_eq!(repo.save_chat_settings(user_id, &settings).await.unwrap(), 1);
+        let saved = repo.get_chat_settings(chat_id).await.unwrap();
+        assert_eq!(saved.stop_sequences(), vec!["END", "\n\nUser:"]);
+
+        // The model is set on its own and survives saving the other settings
+        assert_eq!(repo.set_chat_model(chat_id, user_id + 1, "gpt-4o").await.unwrap(), 0);
+        assert_eq!(repo.set_chat_model(chat_id, user_id, "gpt-4o").await.unwrap(), 1);
+        repo.save_chat_settings(user_id, &settings).await.unwrap();
+        let saved = repo.get_chat_settings(chat_id).await.unwrap();
+        assert_eq!(saved.model.as_deref(), Some("gpt-4o"));
+        assert_eq!(saved.temperature, Some(0.2));
+
+        // Copies keep their settings
+        let copy_id = repo.duplicate_chat(chat_id, user_id).await.unwrap().unwrap();
+        let copied = repo.get_chat_settings(copy_id).await.unwrap();
+        assert_eq!(copied.stop_sequences, saved.stop_sequences);
+        assert_eq!((copied.temperature, copied.max_tokens), (Some(0.2), None));
+    }
+
+    #[tokio::test]
+    async fn test_chat_agents() {
+        let (_pool, repo, user_id) = setup().await;
+        let mut agent = Agent {
+            id: 0,
+            user_id,
+            org_id: None,
+            name: "Translator".to_string(),
+            model: "gpt-4".to_string(),
+            system_prompt: Some("Translate everything to French.".to_string()),
+            temperature: Some(0.1),
+            top_p: None,
+            max_tokens: None,
+            stop_sequences: None,
+            tools_enabled: false,
+            allow_tools: None,
+            version: 0,
+        };
+        let agent_id = repo.save_agent(&agent).await.unwrap();
+        // Saving under the same name updates the agent in place
+        agent.model = "gpt-4o".to_string();
+        assert_eq!(repo.save_agent(&agent).await.unwrap(), agent_id);
+
+        // Edits only apply to the version they were made on
+        let saved = repo.get_agents(user_id, None, None, NO_LIMIT).await.unwrap().remove(0);
+        assert_eq!(saved.version, 1);
+        agent.id = agent_id;
+        agent.temperature = Some(0.3);
+        agent.allow_tools = Some(r#"["fetch__*"]"#.to_string());
+        assert!(repo.update_agent(&agent, saved.version).await.unwrap());
+        agent.temperature = Some(0.9);
+        assert!(!repo.update_agent(&agent, saved.version).await.unwrap());
+        let saved = repo.get_agents(user_id, None, None, NO_LIMIT).await.unwrap().remove(0);
+        assert_eq!((saved.version, saved.temperature), (2, Some(0.3)));
+
+        let chat_id = repo.create_chat(user_id, "bonjour", "gpt-4o").await.unwrap();
+        assert!(repo.get_chat_agent(chat_id).await.unwrap().is_none());
+        assert_eq!(repo.set_chat_agent(chat_id, user_id + 1, agent_id).await.unwrap(), 0);
+        assert_eq!(repo.set_chat_agent(chat_id, user_id, agent_id).await.unwrap(), 1);
+
+        let bound = repo.get_chat_agent(chat_id).await.unwrap().unwrap();
+        assert_eq!((bound.id, bound.model.as_str()), (agent_id, "gpt-4o"));
+        assert!(!bound.tools_enabled);
+        assert_eq!(bound.allow_tools(), Some(vec!["fetch__*".to_string()]));
+
+        // Deleting the agent leaves the chat unbound
+        assert_eq!(repo.delete_agent(user_id, agent_id).await.unwrap(), 1);
+        assert!(repo.get_chat_agent(chat_id).await.unwrap().is_none());
+        assert!(repo.get_chat(chat_id).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_agent_trash() {
+        let (_pool, repo, user_id) = setup().await;
+        let agent = Agent {
+            id: 0,
+            user_id,
+            org_id: None,
+            name: "Reviewer".to_string(),
+            model: "gpt-4".to_string(),
+            system_prompt: None,
+            temperature: None,
+            top_p: None,
+            max_tokens: None,
+            stop_sequences: None,
+            tools_enabled: true,
+            allow_tools: None,
+            version: 0,
+        };
+        let agent_id = repo.save_agent(&agent).await.unwrap();
+        let chat_id = repo.create_chat(user_id, "review", "gpt-4").await.unwrap();
+        repo.set_chat_agent(chat_id, user_id, agent_id).await.unwrap();
+
+        assert_eq!(repo.delete_agent(user_id, agent_id).await.unwrap(), 1);
+        assert_eq!(repo.delete_agent(user_id, agent_id).await.unwrap(), 0);
+        assert!(repo.get_agents(user_id, None, None, NO_LIMIT).await.unwrap().is_empty());
+        assert_eq!(repo.set_chat_agent(chat_id, user_id, agent_id).await.unwrap(), 0);
+        let trashed = repo.get_trashed_agents(user_id, 30).await.unwrap();
+        assert_eq!((trashed[0].id, trashed[0].days_left), (agent_id, 30));
+
+        // Restoring binds the chat to it again
+        assert_eq!(repo.restore_agent(user_id + 1, agent_id).await.unwrap(), 0);
+        assert_eq!(repo.restore_agent(user_id, agent_id).await.unwrap(), 1);
+        assert_eq!(repo.get_chat_agent(chat_id).await.unwrap().unwrap().id, agent_id);
+
+        // So does saving one with the same name
+        repo.delete_agent(user_id, agent_id).await.unwrap();
+        assert_eq!(repo.save_agent(&agent).await.unwrap(), agent_id);
+        assert_eq!(repo.get_agents(user_id, None, None, NO_LIMIT).await.unwrap().len(), 1);
+
+        repo.delete_agent(user_id, agent_id).await.unwrap();
+        assert_eq!(repo.purge_trashed_agents(30).await.unwrap(), 0);
+        assert!(repo.purge_trashed_agents(0).await.unwrap() >= 1);
+        assert!(repo.get_trashed_agents(user_id, 30).await.unwrap().is_empty());
+        assert!(repo.get_chat(chat_id).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_prompt_templates() {
+        let (_pool, repo, user_id) = setup().await;
+        let curated = repo.get_prompt_templates(None).await.unwrap();
+        assert!(!curated.is_empty());
+        assert!(curated.iter().all(|t| t.user_id.is_none()));
+
+        let own = repo
+            .create_prompt_template(user_id, "Standup", "Daily notes", "Summarize my standup notes")
+            .await
+            .unwrap();
+        let mine = repo.get_prompt_templates(Some(user_id)).await.unwrap();
+        assert_eq!(mine.len(), curated.len() + 1);
+        assert_eq!(mine.last().unwrap().id, own.id);
+        assert_eq!(repo.get_prompt_templates(Some(user_id + 1)).await.unwrap().len(), curated.len());
+        assert!(repo.get_prompt_template(own.id, user_id + 1).await.unwrap().is_none());
+        assert!(repo.get_prompt_template(curated[0].id, user_id + 1).await.unwrap().is_some());
+
+        assert_eq!(repo.delete_prompt_template(curated[0].id, user_id).await.unwrap(), 0);
+        assert_eq!(repo.delete_prompt_template(own.id, user_id).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_chat_copies_every_version() {
+        let (_pool, repo, user_id) = setup().await;
+        let chat_id = repo.create_chat(user_id, "original", "gpt-4").await.unwrap();
+        let first = repo.add_message_block(chat_id, "first").await.unwrap();
+        repo.add_ai_message_to_pair(first, "answer").await.unwrap();
+        repo.add_message_block(chat_id, "second").await.unwrap();
+        repo.edit_human_message(chat_id, first, "first, edited").await.unwrap();
+
+        assert!(repo.duplicate_chat(chat_id, user_id + 1).await.unwrap().is_none());
+        let copy_id = repo.duplicate_chat(chat_id, user_id).await.unwrap().unwrap();
+        assert_eq!(repo.get_chat(copy_id).await.unwrap().unwrap().name, "original (copy)");
+
+        let copy = repo.retrieve_chat(copy_id).await.unwrap();
+        assert_eq!(copy.len(), 1);
+        assert_eq!(copy[0].human_message, "first, edited");
+        assert_eq!((copy[0].block_rank, copy[0].block_size), (2, 2));
+
+        // The earlier version and its follow-up came along too
+        let block_id = copy[0].message_block_id;
+        repo.select_block_version(copy_id, user_id, block_id, 1).await.unwrap();
+        let copy = repo.retrieve_chat(copy_id).await.unwrap();
+        let messages: Vec<&str> = copy.iter().map(|p| p.human_message.as_str()).collect();
+        assert_eq!(messages, vec!["first", "second"]);
+        assert_eq!(copy[0].ai_message.as_deref(), Some("answer"));
+
+        // The original is untouched
+        assert_eq!(repo.retrieve_chat(chat_id).await.unwrap()[0].human_message, "first, edited");
+    }
+
+    #[tokio::test]
+    async fn test_message_attachments() {
+        let (_pool, repo, user_id) = setup().await;
+        let chat_id = repo.create_chat(user_id, "files", "gpt-4").await.unwrap();
+        let file = |name: &str, kind: &str| NewAttachment {
+            name: name.to_string(),
+            path: format!("1-{}-{}", uuid::Uuid::new_v4(), name),
+            mime: "image/png".to_string(),
+            size: 42,
+            kind: kind.to_string(),
+        };
+        let (image, notes) = (file("cat.png", "image"), file("notes.txt", "file"));
+
+        let pair_id = repo
+            .add_message_with_attachments(chat_id, "", &[image.clone(), notes.clone()])
+            .await
+            .unwrap();
+        let stored = repo.get_chat_attachments(chat_id).await.unwrap();
+        assert_eq!(stored.len(), 2);
+        assert!(stored.iter().all(|a| a.message_pair_id == pair_id));
+        assert!(stored[0].is_image() && !stored[1].is_image());
+        assert_eq!(stored[1].name, "notes.txt");
+        assert!(repo.user_uploads(user_id).await.unwrap().contains(&image.path));
+
+        // Edits and copies keep the files
+        let edited = repo.edit_human_message(chat_id, pair_id, "see these").await.unwrap().unwrap();
+        let copy_id = repo.duplicate_chat(chat_id, user_id).await.unwrap().unwrap();
+        let stored = repo.get_chat_attachments(chat_id).await.unwrap();
+        assert_eq!(stored.iter().filter(|a| a.message_pair_id == edited).count(), 2);
+        assert_eq!(repo.get_chat_attachments(copy_id).await.unwrap().len(), 4);
+
+        // Attachments are only removed through their own chat, and the file
+        // goes once no attachment refers to it
+        let copies = repo.get_chat_attachments(copy_id).await.unwrap();
+        assert_eq!(repo.delete_attachment(copy_id, stored[0].id).await.unwrap(), None);
+        assert_eq!(repo.get_chat_attachments(chat_id).await.unwrap().len(), 4);
+        let mut notes_files: Vec<(i64, i64)> = stored
+            .iter()
+            .map(|a| (chat_id, a))
+            .chain(copies.iter().map(|a| (copy_id, a)))
+            .filter(|(_, a)| a.path == notes.path)
+            .map(|(chat, a)| (chat, a.id))
+            .collect();
+        let (last_chat, last_id) = notes_files.pop().unwrap();
+        for (chat, id) in notes_files {
+            assert_eq!(repo.delete_attachment(chat, id).await.unwrap(), None);
+        }
+        assert_eq!(repo.delete_attachment(last_chat, last_id).await.unwrap(), Some(notes.path));
+    }
+
+    #[tokio::test]
+    async fn test_stream_user_rows() {
+        use futures::TryStreamExt;
+
+        let (pool, repo, user_id) = setup().await;
+        let chat_id = repo.create_chat(user_id, "export", "gpt-4").await.unwrap();
+        let pair_id = repo.add_message_block(chat_id, "first").await.unwrap();
+        repo.add_ai_message_to_pair(pair_id, "answer").await.unwrap();
+        let edited = repo.edit_human_message(chat_id, pair_id, "second").await.unwrap().unwrap();
+        let other_id = sqlx::query_scalar!(
+            r#"INSERT INTO users (email, password) VALUES ('other@test.com', 'test') RETURNING id AS "id!""#
+        )
+        .fetch_one(&*pool)
+        .await
+        .unwrap();
+        let other_chat = repo.create_chat(other_id, "not mine", "gpt-4").await.unwrap();
+        repo.add_message_block(other_chat, "private").await.unwrap();
+
+        // Both versions of the message, in the same block
+        let pairs: Vec<_> = repo.stream_message_pairs(user_id).try_collect().await.unwrap();
+        let pairs: Vec<_> = pairs.iter().filter(|p| p.id == pair_id || p.id == edited).collect();
+        assert_eq!(pairs.len(), 2);
+        assert_eq!(pairs[0].ai_message.as_deref(), Some("answer"));
+        assert_eq!(pairs[1].human_message, "second");
+        let blocks: Vec<_> = repo.stream_message_blocks(user_id).try_collect().await.unwrap();
+        let block = blocks.iter().find(|b| b.id == pairs[0].message_block_id).unwrap();
+        assert_eq!((block.chat_id, block.selected_pair_id), (chat_id, Some(edited)));
+        assert!(blocks.iter().all(|b| b.chat_id != other_chat));
+    }
+
+    #[tokio::test]
+    async fn test_create_demo_user() {
+        let (pool, repo, _user_id) = setup().await;
+        let email = format!("demo-{}@example.com", uuid::Uuid::new_v4());
+
+        let user_id = repo.create_demo_user(&email, "hash").await.unwrap().unwrap();
+        let verified_at = sqlx::query_scalar!("SELECT verified_at FROM users WHERE id = ?", user_id)
+            .fetch_one(&*pool)
+            .await
+            .unwrap();
+        assert!(verified_at.is_some());
+        // Seeding again leaves the account alone
+        assert_eq!(repo.create_demo_user(&email, "other").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_tool_executions() {
+        let (_pool, repo, user_id) = setup().await;
+        let chat_id = repo.create_chat(user_id, "tools", "gpt-4").await.unwrap();
+        let call = |tool, error: Option<&str>| NewToolExecution {
+            user_id,
+            chat_id,
+            server: "custom",
+            tool,
+            arguments: r#"{"city":"Paris"}"#,
+            result_bytes: 12,
+            duration_ms: 30,
+            error: error.map(str::to_string),
+        };
+
+        repo.record_tool_execution(&call("weather", None)).await.unwrap();
+        repo.record_tool_execution(&call("weather", Some("timed out"))).await.unwrap();
+        repo.record_tool_execution(&call("search", None)).await.unwrap();
+
+        let all = repo.get_tool_executions(user_id, None, None, 10).await.unwrap();
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[0].tool, "search");
+        assert_eq!(all[0].chat_name, "tools");
+        assert_eq!(all[0].arguments_hash, hash_token(r#"{"city":"Paris"}"#));
+        let failed = repo.get_tool_executions(user_id, Some("weather"), Some("error"), 10).await.unwrap();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].error.as_deref(), Some("timed out"));
+        assert_eq!(repo.get_executed_tool_names(user_id).await.unwrap(), vec!["search", "weather"]);
+
+        let stats = repo.tool_stats(user_id, 30).await.unwrap();
+        assert_eq!((stats[0].tool.as_str(), stats[0].calls, stats[0].failures), ("weather", 2, 1));
+        assert_eq!((stats[1].tool.as_str(), stats[1].avg_duration_ms), ("search", 30));
+        let servers = repo.server_tool_stats(user_id, 30).await.unwrap();
+        assert_eq!(servers.len(), 1);
+        assert_eq!((servers[0].tools, servers[0].calls, servers[0].failures), (2, 3, 1));
+    }
+
+    #[tokio::test]
+    async fn test_chat_resources() {
+        let (_pool, repo, user_id) = setup().await;
+        let chat_id = repo.create_chat(user_id, "resources", "gpt-4").await.unwrap();
+        let resource = |content| NewChatResource {
+            server: "db",
+            uri: "schema://users",
+            name: "users",
+            mime_type: Some("text/plain"),
+            content,
+        };
+
+        let id = repo.attach_chat_resource(chat_id, &resource("id INTEGER")).await.unwrap();
+        // Attaching again refreshes the content instead of adding a copy
+        let again = repo.attach_chat_resource(chat_id, &resource("id INTEGER, email TEXT")).await.unwrap();
+        assert_eq!(id, again);
+        let resources = repo.get_chat_resources(chat_id).await.unwrap();
+        assert_eq!(resources.len(), 1);
+        assert_eq!(resources[0].content, "id INTEGER, email TEXT");
+
+        assert!(!repo.detach_chat_resource(chat_id + 1, id).await.unwrap());
+        assert!(repo.detach_chat_resource(chat_id, id).await.unwrap());
+        assert!(repo.get_chat_resources(chat_id).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_tool_approvals() {
+        let (_pool, repo, user_id) = setup().await;
+        assert!(!repo.is_tool_approved(user_id, "fs", "read_file").await.unwrap());
+
+        repo.approve_tool(user_id, "fs", "read_file").await.unwrap();
+        // Approving twice keeps one
+        repo.approve_tool(user_id, "fs", "read_file").await.unwrap();
+        assert!(repo.is_tool_approved(user_id, "fs", "read_file").await.unwrap());
+        assert!(!repo.is_tool_approved(user_id, "fs", "write_file").await.unwrap());
+        assert!(!repo.is_tool_approved(user_id + 1, "fs", "read_file").await.unwrap());
+
+        let approvals = repo.get_tool_approvals(user_id).await.unwrap();
+        assert_eq!(approvals.len(), 1);
+        assert!(!repo.revoke_tool_approval(user_id + 1, approvals[0].id).await.unwrap());
+        assert!(repo.revoke_tool_approval(user_id, approvals[0].id).await.unwrap());
+        assert!(!repo.is_tool_approved(user_id, "fs", "read_file").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_mcp_servers() {
+        let (_pool, repo, user_id) = setup().await;
+        let config = r#"{"url":"https://mcp.example.com/mcp","transport":"http"}"#;
+
+        repo.save_mcp_server(user_id, "remote", config).await.unwrap();
+        repo.save_mcp_server(user_id, "remote", r#"{"url":"https://mcp.example.com/v2"}"#).await.unwrap();
+        // Adding keeps the saved one
+        assert!(!repo.add_mcp_server(user_id, "remote", config).await.unwrap());
+        assert!(repo.add_mcp_server(user_id, "docs", config).await.unwrap());
+
+        let servers = repo.get_mcp_servers(user_id).await.unwrap();
+        let names: Vec<&str> = servers.iter().map(|server| server.name.as_str()).collect();
+        assert_eq!(names, ["docs", "remote"]);
+        assert_eq!(servers[1].config, r#"{"url":"https://mcp.example.com/v2"}"#);
+        assert_eq!(servers[1].oauth, None);
+
+        // Saving the config again keeps the tokens
+        assert!(repo.save_mcp_server_oauth(user_id, "remote", Some("{}")).await.unwrap());
+        assert!(!repo.save_mcp_server_oauth(user_id, "missing", Some("{}")).await.unwrap());
+        repo.save_mcp_server(user_id, "remote", config).await.unwrap();
+        assert_eq!(repo.get_mcp_servers(user_id).await.unwrap()[1].oauth.as_deref(), Some("{}"));
+
+        assert!(!repo.delete_mcp_server(user_id + 1, "remote").await.unwrap());
+        assert!(repo.delete_mcp_server(user_id, "remote").await.unwrap());
+        assert_eq!(repo.get_mcp_servers(user_id).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stored_secrets() {
+        let (_pool, repo, user_id) = setup().await;
+
+        repo.save_custom_tool(user_id, "lookup", "", "{}", "https://tools.example.com", Some("Bearer plain"))
+            .await
+            .unwrap();
+        let tool_id = repo.get_custom_tools(user_id).await.unwrap()[0].id;
+        assert!(repo.custom_tool_auth_headers().await.unwrap().contains(&(tool_id, "Bearer plain".to_string())));
+        repo.set_custom_tool_auth_header(tool_id, "enc:v2:1:sealed").await.unwrap();
+        assert_eq!(
+            repo.get_custom_tools(user_id).await.unwrap()[0].auth_header.as_deref(),
+            Some("enc:v2:1:sealed")
+        );
+
+        repo.save_mcp_server(user_id, "remote", r#"{"url":"https://mcp.example.com"}"#).await.unwrap();
+        let server = repo.get_mcp_servers(user_id).await.unwrap().remove(0);
+        assert!(repo.all_mcp_servers().await.unwrap().iter().any(|stored| stored.id == server.id));
+        repo.set_mcp_server_secrets(server.id, r#"{"headers":{"A":"enc:v2:1:x"}}"#, Some("enc:v2:1:y"))
+            .await
+            .unwrap();
+        let stored = repo.get_mcp_servers(user_id).await.unwrap().remove(0);
+        assert_eq!(stored.config, r#"{"headers":{"A":"enc:v2:1:x"}}"#);
+        assert_eq!(stored.oauth.as_deref(), Some("enc:v2:1:y"));
+    }
+
+    #[tokio::test]
+    async fn test_rename_chat() {
+        let (_pool, repo, user_id) = setup().await;
+        let chat_id = repo.create_chat(user_id, "test", "gpt-4").await.unwrap();
+
+        assert_eq!(repo.rename_chat(chat_id, user_id, "renamed").await.unwrap(), 1);
+        assert_eq!(repo.get_chat(chat_id).await.unwrap().unwrap().name, "renamed");
+
+        // Other users can't rename the chat
+        assert_eq!(repo.rename_chat(chat_id, user_id + 1, "nope").await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_pinned_chats_come_first() {
+        let (_pool, repo, user_id) = setup().await;
+        let first = repo.create_chat(user_id, "first", "gpt-4").await.unwrap();
+        let second = repo.create_chat(user_id, "second", "gpt-4").await.unwrap();
+        let third = repo.create_chat(user_id, "third", "gpt-4").await.unwrap();
+
+        repo.reorder_chats(user_id, &[first, second, third]).await.unwrap();
+        repo.toggle_pin(third, user_id).await.unwrap();
+
+        let order: Vec<i64> = repo
+            .get_all_chats(user_id, None, None, NO_LIMIT)
+            .await
+            .unwrap()
+            .iter()
+            .map(|chat| chat.id)
+            .collect();
+        assert_eq!(order, vec![third, first, second]);
+    }
+
+    #[tokio::test]
+    async fn test_save_settings() {
+        let (pool, repo, user_id) = setup().await;
+        let mut settings = UserSettings {
+            stored_api_key: String::new(),
+            base_url: "https://api.example.com/v1".to_string(),
+            model: "small".to_string(),
+            system_prompt: "Be brief.".to_string(),
+            temperature: 0.2,
+            top_p: 1.0,
+            max_tokens: 100,
+        };
+        assert!(repo.save_settings(user_id, &settings, 0).await.unwrap());
+        settings.model = "large".to_string();
+        assert!(repo.save_settings(user_id, &settings, 1).await.unwrap());
+        assert_eq!(repo.get_settings_version(user_id).await.unwrap(), 2);
+
+        // A save from a page rendered before the last one is rejected
+        settings.model = "stale".to_string();
+        assert!(!repo.save_settings(user_id, &settings, 1).await.unwrap());
+
+        let saved = sqlx::query!("SELECT model, temperature FROM settings WHERE user_id = ?", user_id)
+            .fetch_all(&*pool)
+            .await
+            .unwrap();
+        assert_eq!(saved.len(), 1);
+        assert_eq!(saved[0].model.as_deref(), Some("large"));
+        assert_eq!(saved[0].temperature, Some(0.2));
+    }
+
+    #[tokio::test]
+    async fn test_chat_pages() {
+        let (_pool, repo, user_id) = setup().await;
+        let mut ids = Vec::new();
+        for name in ["a", "b", "c", "d", "e"] {
+            ids.push(repo.create_chat(user_id, name, "gpt-4").await.unwrap());
+        }
+        // Chats created within a second tie on everything but the id
+        repo.toggle_pin(ids[1], user_id).await.unwrap();
+        let all: Vec<i64> = repo
+            .get_all_chats(user_id, None, None, NO_LIMIT)
+            .await
+            .unwrap()
+            .iter()
+            .map(|chat| chat.id)
+            .collect();
+        assert_eq!(all[0], ids[1]);
+
+        let mut paged = Vec::new();
+        let mut cursor = None;
+        loop {
+            let page = repo.get_all_chats(user_id, None, cursor, 2).await.unwrap();
+            paged.extend(page.iter().map(|chat| chat.id));
+            if page.len() < 2 {
+                break;
+            }
+            cursor = page.last().map(|chat| chat.id);
+        }
+        assert_eq!(paged, all);
+        assert_eq!(repo.count_chats(user_id, None).await.unwrap(), 5);
+
+        let agent = |name: &str| Agent {
+            id: 0,
+            user_id,
+            org_id: None,
+            name: name.to_string(),
+            model: "gpt-4o".to_string(),
+            system_prompt: None,
+            temperature: None,
+            top_p: None,
+            max_tokens: None,
+            stop_sequences: None,
+            tools_enabled: false,
+            allow_tools: None,
+            version: 0,
+        };
+        for name in ["zed", "amy", "bob"] {
+            repo.save_agent(&agent(name)).await.unwrap();
+        }
+        let first = repo.get_agents(user_id, None, None, 2).await.unwrap();
+        let names: Vec<&str> = first.iter().map(|agent| agent.name.as_str()).collect();
+        assert_eq!(names, ["amy", "bob"]);
+        let rest = repo.get_agents(user_id, None, Some(first[1].id), 2).await.unwrap();
+        assert_eq!(rest[0].name, "zed");
+        assert_eq!(repo.count_agents(user_id, None).await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_share_token_lookup_and_revoke() {
+        let (_pool, repo, user_id) = setup().await;
+        let chat_id = repo.create_chat(user_id, "shared", "gpt-4").await.unwrap();
+        let token = uuid::Uuid::new_v4().simple().to_string();
+
+        // Only the owner can share
+        assert_eq!(repo.set_share_token(chat_id, user_id + 1, Some(&token)).await.unwrap(), 0);
+        repo.set_share_token(chat_id, user_id, Some(&token)).await.unwrap();
+        let shared = repo.get_chat_by_share_token(&token).await.unwrap().unwrap();
+        assert_eq!(shared.id, chat_id);
+
+        repo.set_share_token(chat_id, user_id, None).await.unwrap();
+        assert!(repo.get_chat_by_share_token(&token).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_archived_chats_leave_the_sidebar() {
+        let (_pool, repo, user_id) = setup().await;
+        let kept = repo.create_chat(user_id, "kept", "gpt-4").await.unwrap();
+        let archived = repo.create_chat(user_id, "archived", "gpt-4").await.unwrap();
+
+        assert_eq!(repo.set_archived(archived, user_id, true).await.unwrap(), 1);
+
+        let sidebar: Vec<i64> = repo
+            .get_all_chats(user_id, None, None, NO_LIMIT)
+            .await
+            .unwrap()
+            .iter()
+            .map(|chat| chat.id)
+            .collect();
+        assert_eq!(sidebar, vec![kept]);
+        let archive = repo.get_archived_chats(user_id).await.unwrap();
+        assert_eq!(archive.len(), 1);
+        assert_eq!(archive[0].id, archived);
+
+        repo.set_archived(archived, user_id, false).await.unwrap();
+        assert!(repo.get_archived_chats(user_id).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_chat_folders_and_tags() {
+        let (_pool, repo, user_id) = setup().await;
+        let work = repo.create_chat(user_id, "work", "gpt-4").await.unwrap();
+        let home = repo.create_chat(user_id, "home", "gpt-4").await.unwrap();
+
+        repo.create_folder(user_id, "Projects").await.unwrap();
+        let folder = repo.get_folders(user_id).await.unwrap().remove(0);
+        assert_eq!(repo.move_chat_to_folder(work, user_id, Some(folder.id)).await.unwrap(), 1);
+        // A folder of another user can't be targeted
+        assert_eq!(repo.move_chat_to_folder(home, user_id + 1, Some(folder.id)).await.unwrap(), 0);
+        assert_eq!(repo.get_chat(work).await.unwrap().unwrap().folder_id, Some(folder.id));
+
+        repo.add_chat_tag(work, user_id, "rust").await.unwrap();
+        repo.add_chat_tag(work, user_id, "urgent").await.unwrap();
+        repo.add_chat_tag(home, user_id, "rust").await.unwrap();
+
+        let urgent_chats = repo.get_all_chats(user_id, Some("urgent"), None, NO_LIMIT).await.unwrap();
+        assert_eq!(urgent_chats.len(), 1);
+        assert_eq!(urgent_chats[0].tags.as_deref(), Some("rust,urgent"));
+
+        repo.remove_chat_tag(work, user_id, "urgent").await.unwrap();
+        let tags = repo.get_tags(user_id).await.unwrap();
+        assert_eq!(tags.len(), 1);
+        assert_eq!(tags[0].chat_count, 2);
+
+        assert_eq!(repo.delete_folder(folder.id, user_id).await.unwrap(), 1);
+        assert_eq!(repo.get_chat(work).await.unwrap().unwrap().folder_id, None);
+    }
+
+    #[tokio::test]
+    async fn test_bulk_update_chats() {
+        let (_pool, repo, user_id) = setup().await;
+        let first = repo.create_chat(user_id, "first", "gpt-4").await.unwrap();
+        let second = repo.create_chat(user_id, "second", "gpt-4").await.unwrap();
+        repo.create_folder(user_id, "Work").await.unwrap();
+        let folder_id = repo.get_folders(user_id).await.unwrap()[0].id;
+
+        let moved = BulkChatAction::Move { folder_id: Some(folder_id) };
+        assert_eq!(repo.bulk_update_chats(user_id, &[first, second], moved).await.unwrap(), 2);
+        let chats = repo.get_all_chats(user_id, None, None, NO_LIMIT).await.unwrap();
+        assert!(chats.iter().all(|chat| chat.folder_id == Some(folder_id)));
+
+        // One chat that isn't the user's leaves the others untouched
+        let rows = repo.bulk_update_chats(user_id, &[first, second + 100], BulkChatAction::Archive).await.unwrap();
+        assert_eq!(rows, 0);
+        assert_eq!(repo.get_all_chats(user_id, None, None, NO_LIMIT).await.unwrap().len(), 2);
+
+        repo.bulk_update_chats(user_id, &[first], BulkChatAction::Archive).await.unwrap();
+        assert_eq!(repo.get_archived_chats(user_id).await.unwrap().len(), 1);
+
+        repo.bulk_update_chats(user_id, &[first, second], BulkChatAction::Delete).await.unwrap();
+        assert!(repo.get_chat(first).await.unwrap().is_none());
+        assert!(repo.get_chat(second).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_trash_and_restore() {
+        let (pool, repo, user_id) = setup().await;
+        let chat_id = repo.create_chat(user_id, "test", "gpt-4").await.unwrap();
+
+        assert_eq!(repo.delete_chat(chat_id).await.unwrap(), 1);
+        assert!(repo.get_chat(chat_id).await.unwrap().is_none());
+        assert!(repo.get_chat_role(chat_id, user_id).await.unwrap().is_none());
+        assert!(repo.get_all_chats(user_id, None, None, NO_LIMIT).await.unwrap().is_empty());
+        let trashed = repo.get_trashed_chats(user_id, 30).await.unwrap();
+        assert_eq!(trashed[0].id, chat_id);
+        assert_eq!(trashed[0].days_left, 30);
+
+        assert_eq!(repo.restore_chat(chat_id, user_id).await.unwrap(), 1);
+        assert!(repo.get_chat(chat_id).await.unwrap().is_some());
+        assert_eq!(repo.purge_trashed_chats(30).await.unwrap(), 0);
+
+        // Only chats past the retention period are purged
+        repo.delete_chat(chat_id).await.unwrap();
+        assert_eq!(repo.purge_trashed_chats(30).await.unwrap(), 0);
+        sqlx::query("UPDATE chats SET deleted_at = datetime('now', '-31 days') WHERE id = ?")
+            .bind(chat_id)
+            .execute(&*pool)
+            .await
+            .unwrap();
+        assert_eq!(repo.purge_trashed_chats(30).await.unwrap(), 1);
+        assert!(repo.get_trashed_chats(user_id, 30).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_usage_analytics() {
+        let (_pool, repo, user_id) = setup().await;
+        let chat_id = repo.create_chat(user_id, "test", "gpt-4").await.unwrap();
+        let pair_id = repo.add_message_block(chat_id, "Weather?").await.unwrap();
+        repo.add_ai_message_with_extended_data(
+            pair_id,
+            "Sunny",
+            None,
+            Some(r#"[{"id":"call_1","type":"function","function":{"name":"weather","arguments":"{}"}}]"#),
+            None,
+            None,
+            Some(10),
+            Some(5),
+            Some(15),
+            None,
+        )
+        .await
+        .unwrap();
+
+        let (from, to) = ("2000-01-01", "2999-12-31");
+        let daily = repo.daily_usage(user_id, from, to).await.unwrap();
+        assert_eq!(daily.iter().map(|d| d.total_tokens).sum::<i64>(), 15);
+
+        let models = repo.model_usage(user_id, from, to).await.unwrap();
+        assert_eq!(models[0].model, "gpt-4");
+
+        let tools = repo.tool_usage(user_id, from, to).await.unwrap();
+        assert_eq!(tools[0].tool, "weather");
+        assert_eq!(tools[0].invocations, 1);
+    }
+
+    #[tokio::test]
+    async fn test_chat_stats() {
+        let (_pool, repo, user_id) = setup().await;
+        let chat_id = repo.create_chat(user_id, "test", "Qwen/Qwen2.5-7B-Instruct").await.unwrap();
+        let responses = [
+            (None, None),
+            (Some("deepseek-ai/DeepSeek-V3"), Some(400)),
+            (Some("deepseek-ai/DeepSeek-V3"), Some(200)),
+        ];
+        for (model, latency_ms) in responses {
+            let pair_id = repo.add_message_block(chat_id, "Hi").await.unwrap();
+            let message_id = repo
+                .add_ai_message_with_extended_data(
+                    pair_id,
+                    "Hello",
+                    None,
+                    None,
+                    None,
+                    None,
+                    Some(1_000_000),
+                    Some(0),
+                    Some(1_000_000),
+                    None,
+                )
+                .await
+                .unwrap();
+            if let (Some(model), Some(latency_ms)) = (model, latency_ms) {
+                repo.set_response_stats(message_id, model, latency_ms).await.unwrap();
+            }
+        }
+
+        let messages = repo.count_chat_messages(chat_id).await.unwrap();
+        let stats = ChatStats::new(chat_id, messages, repo.chat_model_stats(chat_id).await.unwrap());
+        assert_eq!(stats.messages, 3);
+        assert_eq!(stats.responses, 3);
+        assert_eq!(stats.total_tokens, 3_000_000);
+        // Older responses count towards the chat's model
+        assert_eq!(stats.models[0].model, "deepseek-ai/DeepSeek-V3");
+        assert_eq!(stats.models[1].model, "Qwen/Qwen2.5-7B-Instruct");
+        assert_eq!(stats.models[1].avg_latency_ms, None);
+        assert!((stats.cost - 0.54).abs() < 1e-9);
+        assert_eq!(stats.avg_latency_ms, Some(300.0));
+    }
+
+    #[tokio::test]
+    async fn test_search_messages() {
+        let (pool, repo, user_id) = setup().await;
+        let chat_id = repo.create_chat(user_id, "test", "gpt-4").await.unwrap();
+        let pair_id = repo
+            .add_message_block(chat_id, "How do lifetimes work in Rust?")
+            .await
+            .unwrap();
+
+        let hits = repo.search_messages(user_id, "lifetimes \"rust").await.unwrap();
+        assert!(hits.iter().any(|hit| hit.pair_id == pair_id));
+        assert!(hits[0].snippet.contains("\u{2}lifetimes\u{3}"));
+        // Members of a shared chat find its messages too
+        let email = format!("{}@search.test", uuid::Uuid::new_v4());
+        let member_id = sqlx::query_scalar!(
+            r#"INSERT INTO users (email, password) VALUES (?, 'test') RETURNING id AS "id!""#,
+            email
+        )
+        .fetch_one(&*pool)
+        .await
+        .unwrap();
+        assert!(repo.search_messages(member_id, "lifetimes").await.unwrap().is_empty());
+        repo.invite_to_chat(chat_id, &email, ChatRole::Viewer).await.unwrap();
+        let hits = repo.search_messages(member_id, "lifetimes").await.unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].pair_id, pair_id);
+
+        repo.delete_chat(chat_id).await.unwrap();
+        assert!(repo.search_messages(member_id, "lifetimes").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_similar_chunks() {
+        let (pool, repo, user_id) = setup().await;
+        let chat_id = repo.create_chat(user_id, "pets", "gpt-4").await.unwrap();
+        let cat = repo.add_message_block(chat_id, "Tell me about cats").await.unwrap();
+        repo.add_ai_message_to_pair(cat, "Cats purr.").await.unwrap();
+        let dog = repo.add_message_block(chat_id, "Tell me about dogs").await.unwrap();
+        repo.add_ai_message_to_pair(dog, "Dogs bark.").await.unwrap();
+        let unanswered = repo.add_message_block(chat_id, "And birds?").await.unwrap();
+
+        let pending: Vec<i64> = repo
+            .unindexed_pairs(i64::MAX)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|pair| pair.pair_id)
+            .collect();
+        assert!(pending.contains(&cat) && pending.contains(&dog));
+        assert!(!pending.contains(&unanswered));
+
+        repo.save_chunk(cat, chat_id, "cats", &[1.0, 0.0, 0.0]).await.unwrap();
+        repo.save_chunk(dog, chat_id, "dogs", &[0.0, 1.0, 0.0]).await.unwrap();
+        let pending = repo.unindexed_pairs(i64::MAX).await.unwrap();
+        assert!(!pending.iter().any(|pair| pair.pair_id == cat || pair.pair_id == dog));
+
+        let similar = repo.similar_chunks(user_id, &[0.9, 0.1, 0.0], 5).await.unwrap();
+        let pairs: Vec<i64> = similar.iter().map(|chunk| chunk.pair_id).collect();
+        assert_eq!(pairs, [cat, dog]);
+        assert!(similar[0].distance < similar[1].distance);
+        assert_eq!(repo.similar_chunks(user_id, &[0.9, 0.1, 0.0], 1).await.unwrap().len(), 1);
+        // Another model's dimensions don't match
+        assert!(repo.similar_chunks(user_id, &[1.0, 0.0], 5).await.unwrap().is_empty());
+        assert!(repo.similar_chunks(-1, &[1.0, 0.0, 0.0], 5).await.unwrap().is_empty());
+
+        // Editing a message embeds it again
+        let ai_message_id = sqlx::query_scalar!("SELECT ai_message_id FROM message_pairs WHERE id = ?", cat)
+            .fetch_one(&*pool)
+            .await
+            .unwrap()
+            .unwrap();
+        sqlx::query!("UPDATE messages SET message = 'Cats sleep.' WHERE id = ?", ai_message_id)
+            .execute(&*pool)
+            .await
+            .unwrap();
+        let pending = repo.unindexed_pairs(i64::MAX).await.unwrap();
+        assert!(pending.iter().any(|pair| pair.pair_id == cat && pair.ai_message == "Cats sleep."));
+    }
+
+    #[tokio::test]
+    async fn test_scheduled_messages() {
+        let (_pool, repo, user_id) = setup().await;
+        let chat_id = repo.create_chat(user_id, "test", "gpt-4").await.unwrap();
+        let now = Utc::now();
+
+        assert!(repo
+            .schedule_message(user_id + 1, chat_id, "hi", now)
+            .await
+            .unwrap()
+            .is_none());
+        let due = repo
+            .schedule_message(user_id, chat_id, "Daily report", now - chrono::Duration::minutes(1))
+            .await
+            .unwrap()
+            .unwrap();
+        let later = repo
+            .schedule_message(user_id, chat_id, "Tomorrow", now + chrono::Duration::days(1))
+            .await
+            .unwrap()
+            .unwrap();
+
+        // Only due messages are claimed, and only once
+        let claimed = repo.claim_due_scheduled_messages().await.unwrap();
+        assert!(claimed.iter().any(|message| message.id == due && message.chat_name == "test"));
+        assert!(!claimed.iter().any(|message| message.id == later));
+        let claimed = repo.claim_due_scheduled_messages().await.unwrap();
+        assert!(!claimed.iter().any(|message| message.id == due));
+
+        repo.finish_scheduled_message(due, None).await.unwrap();
+        assert_eq!(repo.cancel_scheduled_message(due, user_id).await.unwrap(), 0);
+        assert_eq!(repo.cancel_scheduled_message(later, user_id).await.unwrap(), 1);
+
+        let notifications = repo.get_scheduled_notifications(user_id).await.unwrap();
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0].status, "done");
+
+        repo.mark_scheduled_messages_seen(user_id, None, Some(chat_id)).await.unwrap();
+        assert!(repo.get_scheduled_notifications(user_id).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_chat_members() {
+        let (pool, repo, owner_id) = setup().await;
+        let chat_id = repo.create_chat(owner_id, "test", "gpt-4").await.unwrap();
+        let email = format!("{}@members.test", uuid::Uuid::new_v4());
+        let member_id = sqlx::query_scalar!(
+            r#"INSERT INTO users (email, password) VALUES (?, 'test') RETURNING id AS "id!""#,
+            email
+        )
+        .fetch_one(&*pool)
+        .await
+        .unwrap();
+
+        assert_eq!(repo.get_chat_role(chat_id, owner_id).await.unwrap(), Some(ChatRole::Owner));
+        assert_eq!(repo.get_chat_role(chat_id, member_id).await.unwrap(), None);
+
+        // Viewers can rate and bookmark but not change the conversation
+        repo.invite_to_chat(chat_id, &email, ChatRole::Viewer).await.unwrap();
+        assert_eq!(repo.get_chat_role(chat_id, member_id).await.unwrap(), Some(ChatRole::Viewer));
+        let pair_id = repo.add_message_block(chat_id, "Hello").await.unwrap();
+        assert_eq!(repo.set_feedback(member_id, chat_id, pair_id, Some(1)).await.unwrap(), 1);
+        assert_eq!(repo.delete_message_pair(chat_id, member_id, pair_id).await.unwrap(), 0);
+
+        repo.invite_to_chat(chat_id, &email, ChatRole::Editor).await.unwrap();
+        assert_eq!(repo.get_chat_role(chat_id, member_id).await.unwrap(), Some(ChatRole::Editor));
+        assert_eq!(repo.get_chat_members(chat_id).await.unwrap().len(), 1);
+        assert!(repo.get_shared_chats(member_id).await.unwrap().iter().any(|c| c.id == chat_id));
+
+        // Emails without an account wait for a signup
+        let invited = format!("{}@members.test", uuid::Uuid::new_v4());
+        repo.invite_to_chat(chat_id, &invited, ChatRole::Viewer).await.unwrap();
+        assert_eq!(repo.get_chat_invitations(chat_id).await.unwrap().len(), 1);
+        let invited_id = sqlx::query_scalar!(
+            r#"INSERT INTO users (email, password) VALUES (?, 'test') RETURNING id AS "id!""#,
+            invited
+        )
+        .fetch_one(&*pool)
+        .await
+        .unwrap();
+        assert_eq!(repo.accept_chat_invitations(invited_id, &invited).await.unwrap(), 1);
+        assert_eq!(repo.get_chat_role(chat_id, invited_id).await.unwrap(), Some(ChatRole::Viewer));
+        assert!(repo.get_chat_invitations(chat_id).await.unwrap().is_empty());
+
+        assert_eq!(repo.remove_chat_member(chat_id, member_id).await.unwrap(), 1);
+        assert_eq!(repo.get_chat_role(chat_id, member_id).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_webhook_deliveries() {
+        let (_pool, repo, user_id) = setup().await;
+        let chat_id = repo.create_chat(user_id, "test", "gpt-4").await.unwrap();
+        let url = format!("https://hooks.test/{}", uuid::Uuid::new_v4());
+        let webhook_id = repo
+            .create_webhook(user_id, &url, "chat.created,generation.completed")
+            .await
+            .unwrap();
+
+        // Only subscribed events are queued
+        assert_eq!(repo.enqueue_webhook_event(chat_id, "tool.executed", "{}").await.unwrap(), 0);
+        assert_eq!(repo.enqueue_webhook_event(chat_id, "chat.created", "{}").await.unwrap(), 1);
+
+        let due = repo.get_due_webhook_deliveries(100).await.unwrap();
+        let delivery = due.iter().find(|d| d.url == url).unwrap();
+        assert_eq!(delivery.event, "chat.created");
+
+        // A retry waits for its turn
+        repo.record_webhook_attempt(delivery.id, Some("HTTP 500"), Some(60)).await.unwrap();
+        let due = repo.get_due_webhook_deliveries(100).await.unwrap();
+        assert!(!due.iter().any(|d| d.id == delivery.id));
+        let webhook = repo.get_webhooks(user_id).await.unwrap().into_iter().find(|w| w.id == webhook_id).unwrap();
+        assert_eq!(webhook.last_status.as_deref(), Some("pending"));
+        assert_eq!(webhook.last_error.as_deref(), Some("HTTP 500"));
+
+        repo.record_webhook_attempt(delivery.id, None, None).await.unwrap();
+        let webhook = repo.get_webhooks(user_id).await.unwrap().into_iter().find(|w| w.id == webhook_id).unwrap();
+        assert_eq!(webhook.last_status.as_deref(), Some("delivered"));
+
+        assert_eq!(repo.delete_webhook(user_id, webhook_id).await.unwrap(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_password_resets() {
+        let (pool, repo, _user_id) = setup().await;
+        let email = format!("{}@resets.test", uuid::Uuid::new_v4());
+        let user_id = sqlx::query_scalar!(
+            r#"INSERT INTO users (email, password) VALUES (?, 'test') RETURNING id AS "id!""#,
+            email
+        )
+        .fetch_one(&*pool)
+        .await
+        .unwrap();
+        let token = |name: &str| format!("{}-{}", user_id, name);
+
+        assert!(!repo.create_password_reset("nobody@resets.test", &token("unknown"), 60).await.unwrap());
+        assert!(repo.create_password_reset(&email, &token("first"), 60).await.unwrap());
+        assert!(repo.create_password_reset(&email, &token("second"), 60).await.unwrap());
+        assert_eq!(repo.find_password_reset(&token("first")).await.unwrap(), Some(user_id));
 
-        let migrator = Migrator::new(Path::new(dotenv::var("MIGRATIONS_PATH").unwrap().as_str()))
+        assert!(repo.reset_password(&token("first"), "new-hash").await.unwrap());
+        let password = sqlx::query_scalar!("SELECT password FROM users WHERE id = ?", user_id)
+            .fetch_one(&*pool)
             .await
             .unwrap();
-        // Run the migrations.
-        migrator.run(&*pool).await.unwrap();
+        assert_eq!(password, "new-hash");
+        // Links are single use, and using one invalidates the others
+        assert!(!repo.reset_password(&token("first"), "again").await.unwrap());
+        assert!(repo.find_password_reset(&token("second")).await.unwrap().is_none());
 
-        let user = sqlx::query!(
-            "INSERT INTO users (email, password) VALUES ($1, $2) RETURNING id",
-            "test@test.com",
-            "test"
+        assert!(repo.create_password_reset(&email, &token("expired"), -1).await.unwrap());
+        assert!(repo.find_password_reset(&token("expired")).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_email_verification() {
+        let (pool, repo, _user_id) = setup().await;
+        let email = format!("{}@verify.test", uuid::Uuid::new_v4());
+        let user_id = sqlx::query_scalar!(
+            r#"INSERT INTO users (email, password) VALUES (?, 'test') RETURNING id AS "id!""#,
+            email
         )
         .fetch_one(&*pool)
         .await
         .unwrap();
+        let verified_at = || {
+            sqlx::query_scalar!("SELECT verified_at FROM users WHERE id = ?", user_id).fetch_one(&*pool)
+        };
+        let token = |name: &str| format!("{}-{}", user_id, name);
 
-        let repo = ChatRepository { pool: pool.clone() };
+        repo.create_email_verification(user_id, &token("expired"), -1).await.unwrap();
+        assert!(!repo.verify_email(&token("expired")).await.unwrap());
+        assert!(verified_at().await.unwrap().is_none());
 
-        (pool, repo, user.id)
+        repo.create_email_verification(user_id, &token("first"), 60).await.unwrap();
+        repo.create_email_verification(user_id, &token("second"), 60).await.unwrap();
+        assert!(repo.verify_email(&token("second")).await.unwrap());
+        assert!(verified_at().await.unwrap().is_some());
+        // The other links are gone once the address is confirmed
+        assert!(!repo.verify_email(&token("first")).await.unwrap());
     }
 
     #[tokio::test]
-    async fn test_create_chat() {
+    async fn test_oauth_sign_in() {
+        let (pool, repo, _user_id) = setup().await;
+        let email = format!("{}@oauth.test", uuid::Uuid::new_v4());
+        let subject = |name: &str| format!("{}-{}", email, name);
+
+        // A new account, then the same one for the same identity
+        let OAuthSignIn::Created(user_id) =
+            repo.oauth_sign_in("github", &subject("gh"), &email, true, "x", true).await.unwrap()
+        else {
+            panic!("expected a new account");
+        };
+        let verified_at = sqlx::query_scalar!("SELECT verified_at FROM users WHERE id = ?", user_id)
+            .fetch_one(&*pool)
+            .await
+            .unwrap();
+        assert!(verified_at.is_some());
+        assert_eq!(
+            repo.oauth_sign_in("github", &subject("gh"), "changed@oauth.test", true, "x", true).await.unwrap(),
+            OAuthSignIn::Existing(user_id)
+        );
+
+        // Another provider links by email, unless it hasn't verified the address
+        assert_eq!(
+            repo.oauth_sign_in("google", &subject("g1"), &email, false, "x", true).await.unwrap(),
+            OAuthSignIn::EmailTaken
+        );
+        assert_eq!(
+            repo.oauth_sign_in("google", &subject("g2"), &email, true, "x", true).await.unwrap(),
+            OAuthSignIn::Existing(user_id)
+        );
+        // With signup closed, only known accounts get in
+        assert_eq!(
+            repo.oauth_sign_in("github", &subject("gh"), &email, true, "x", false).await.unwrap(),
+            OAuthSignIn::Existing(user_id)
+        );
+        assert_eq!(
+            repo.oauth_sign_in("github", &subject("new"), &subject("new"), true, "x", false).await.unwrap(),
+            OAuthSignIn::SignupClosed
+        );
+        let identities = sqlx::query_scalar!(
+            "SELECT COUNT(*) FROM user_identities WHERE user_id = ?",
+            user_id
+        )
+        .fetch_one(&*pool)
+        .await
+        .unwrap();
+        assert_eq!(identities, 2);
+    }
+
+    #[tokio::test]
+    async fn test_sessions() {
+        let (_pool, repo, user_id) = setup().await;
+        let token = |name: &str| format!("{}-{}", uuid::Uuid::new_v4(), name);
+        let (laptop, phone) = (token("laptop"), token("phone"));
+
+        repo.create_session(user_id, &laptop, Some("Firefox"), Some("10.0.0.1"), None, None).await.unwrap();
+        repo.create_session(user_id, &phone, None, None, None, None).await.unwrap();
+        assert_eq!(
+            repo.authenticate_session(&laptop, 24).await.unwrap(),
+            Some(SessionUser { user_id, impersonator: None })
+        );
+        assert_eq!(repo.authenticate_session("unknown", 24).await.unwrap(), None);
+
+        let sessions = repo.get_sessions(user_id, &laptop, 24).await.unwrap();
+        let current = sessions.iter().find(|s| s.current).unwrap();
+        assert_eq!(current.user_agent.as_deref(), Some("Firefox"));
+        let other = sessions.iter().find(|s| s.user_agent.is_none()).unwrap();
+
+        // Only the owner can revoke a session
+        assert_eq!(repo.delete_session(user_id + 1, other.id).await.unwrap(), 0);
+        assert_eq!(repo.delete_session(user_id, other.id).await.unwrap(), 1);
+        assert_eq!(repo.authenticate_session(&phone, 24).await.unwrap(), None);
+
+        assert!(repo.delete_sessions(user_id).await.unwrap() >= 1);
+        assert_eq!(repo.authenticate_session(&laptop, 24).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_login_throttling() {
+        let (pool, repo, _) = setup().await;
+        let email = format!("{}@lock.test", uuid::Uuid::new_v4());
+        let user_id = sqlx::query_scalar!(
+            r#"INSERT INTO users (email, password) VALUES (?, 'test') RETURNING id AS "id!""#,
+            email
+        )
+        .fetch_one(&*pool)
+        .await
+        .unwrap();
+        let ip = uuid::Uuid::new_v4().to_string();
+
+        let first = repo.record_failed_login(&email, Some(&ip), 3, 15).await.unwrap().unwrap();
+        assert_eq!((first.user_id, first.failed_logins, first.locked_until), (user_id, 1, None));
+        repo.record_failed_login(&email, Some(&ip), 3, 15).await.unwrap();
+        assert_eq!(repo.login_locked_until(&email).await.unwrap(), None);
+        let third = repo.record_failed_login(&email, Some(&ip), 3, 15).await.unwrap().unwrap();
+        assert!(third.locked_until.is_some());
+        assert_eq!(repo.login_locked_until(&email).await.unwrap(), third.locked_until);
+
+        // Unknown emails count against the IP only
+        assert_eq!(repo.record_failed_login("nobody@lock.test", Some(&ip), 3, 15).await.unwrap(), None);
+        assert_eq!(repo.recent_login_failures(&ip, 15).await.unwrap(), 4);
+
+        let listed = repo.admin_users().await.unwrap();
+        let user = listed.iter().find(|u| u.id == user_id).unwrap();
+        assert_eq!((user.failed_logins, user.locked_until), (3, third.locked_until));
+
+        assert_eq!(repo.reset_failed_logins(user_id).await.unwrap(), 1);
+        assert_eq!(repo.login_locked_until(&email).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_profile() {
+        let (_, repo, user_id) = setup().await;
+
+        repo.update_display_name(user_id, Some("Ada")).await.unwrap();
+        assert_eq!(repo.set_avatar(user_id, Some("/uploads/avatars/a.png")).await.unwrap(), None);
+        assert_eq!(
+            repo.set_avatar(user_id, Some("/uploads/avatars/b.png")).await.unwrap().as_deref(),
+            Some("/uploads/avatars/a.png")
+        );
+        assert_eq!(repo.get_avatar(user_id).await.unwrap().as_deref(), Some("/uploads/avatars/b.png"));
+
+        let name = sqlx::query_scalar!("SELECT display_name FROM users WHERE id = ?", user_id)
+            .fetch_one(&*repo.pool)
+            .await
+            .unwrap();
+        assert_eq!(name.as_deref(), Some("Ada"));
+    }
+
+    #[tokio::test]
+    async fn test_usage_and_quota_override() {
+        let (pool, repo, _) = setup().await;
+        let email = format!("{}@quota.test", uuid::Uuid::new_v4());
+        let user_id = sqlx::query_scalar!(
+            r#"INSERT INTO users (email, password) VALUES (?, 'test') RETURNING id AS "id!""#,
+            email
+        )
+        .fetch_one(&*pool)
+        .await
+        .unwrap();
+        let chat_id = repo.create_chat(user_id, "Quota", "gpt-4o").await.unwrap();
+
+        let pair_id = repo.add_message_block(chat_id, "Hello").await.unwrap();
+        let message_id = repo.add_ai_message_to_pair(pair_id, "Hi").await.unwrap();
+        sqlx::query!("UPDATE messages SET usage_total_tokens = 42 WHERE id = ?", message_id)
+            .execute(&*pool)
+            .await
+            .unwrap();
+        repo.add_message_block(chat_id, "Still there?").await.unwrap();
+
+        let usage = repo.usage_totals(user_id).await.unwrap();
+        assert_eq!(usage.daily_tokens, 42);
+        assert_eq!(usage.monthly_tokens, 42);
+        assert_eq!(usage.daily_messages, 2);
+        assert_eq!(usage.monthly_messages, 2);
+
+        assert!(repo.get_quota_override(user_id).await.unwrap().is_none());
+        let quota = QuotaOverride {
+            daily_messages: Some(5),
+            monthly_tokens: Some(0),
+            ..QuotaOverride::default()
+        };
+        repo.set_quota_override(user_id, &quota).await.unwrap();
+        let saved = repo.get_quota_override(user_id).await.unwrap().unwrap();
+        assert_eq!((saved.daily_messages, saved.monthly_tokens, saved.daily_tokens), (Some(5), Some(0), None));
+
+        repo.set_quota_override(user_id, &QuotaOverride::default()).await.unwrap();
+        assert!(repo.get_quota_override(user_id).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_usage_of_shared_chat() {
+        let (pool, repo, _) = setup().await;
+        let mut users = Vec::new();
+        for _ in 0..2 {
+            let email = format!("{}@quota.test", uuid::Uuid::new_v4());
+            let user_id = sqlx::query_scalar!(
+                r#"INSERT INTO users (email, password) VALUES (?, 'test') RETURNING id AS "id!""#,
+                email
+            )
+            .fetch_one(&*pool)
+            .await
+            .unwrap();
+            users.push((user_id, email));
+        }
+        let (owner_id, (editor_id, editor_email)) = (users[0].0, users[1].clone());
+        let chat_id = repo.create_chat(owner_id, "Shared", "gpt-4o").await.unwrap();
+        repo.invite_to_chat(chat_id, &editor_email, ChatRole::Editor).await.unwrap();
+
+        // The owner's own message, then one the editor generates with their key
+        repo.add_message_block(chat_id, "Hello").await.unwrap();
+        let pair_id = repo.add_message_block(chat_id, "From the editor").await.unwrap();
+        repo.bill_message_pair(pair_id, editor_id).await.unwrap();
+        let message_id = repo.add_ai_message_to_pair(pair_id, "Hi").await.unwrap();
+        sqlx::query!("UPDATE messages SET usage_total_tokens = 42 WHERE id = ?", message_id)
+            .execute(&*pool)
+            .await
+            .unwrap();
+
+        let owner = repo.usage_totals(owner_id).await.unwrap();
+        assert_eq!((owner.daily_messages, owner.daily_tokens), (1, 0));
+        let editor = repo.usage_totals(editor_id).await.unwrap();
+        assert_eq!((editor.daily_messages, editor.daily_tokens), (1, 42));
+    }
+
+    #[tokio::test]
+    async fn test_invites() {
+        let (_, repo, admin_id) = setup().await;
+        let code = uuid::Uuid::new_v4().simple().to_string();
+        let invite_id = repo.create_invite(admin_id, &code, Some("For Ada")).await.unwrap();
+
+        let email = format!("{}@invite.test", uuid::Uuid::new_v4());
+        assert!(repo.create_invited_user(&code, &email, "x").await.unwrap().is_some());
+        // Each code creates one account
+        assert_eq!(repo.create_invited_user(&code, "again@invite.test", "x").await.unwrap(), None);
+        assert_eq!(repo.revoke_invite(invite_id).await.unwrap(), 0);
+
+        let invites = repo.get_invites().await.unwrap();
+        let invite = invites.iter().find(|invite| invite.id == invite_id).unwrap();
+        assert_eq!(invite.used_by.as_deref(), Some(email.as_str()));
+        assert_eq!(invite.note.as_deref(), Some("For Ada"));
+
+        let other = uuid::Uuid::new_v4().simple().to_string();
+        let other_id = repo.create_invite(admin_id, &other, None).await.unwrap();
+        assert_eq!(repo.revoke_invite(other_id).await.unwrap(), 1);
+        assert_eq!(repo.create_invited_user(&other, "revoked@invite.test", "x").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_orgs() {
+        let (pool, repo, owner_id) = setup().await;
+        let email = format!("{}@org.test", uuid::Uuid::new_v4());
+        let member_id = sqlx::query_scalar!(
+            r#"INSERT INTO users (email, password) VALUES (?, 'test') RETURNING id AS "id!""#,
+            email
+        )
+        .fetch_one(&*pool)
+        .await
+        .unwrap();
+
+        let org_id = repo.create_org(owner_id, "Acme").await.unwrap();
+        assert_eq!(repo.get_org_role(org_id, owner_id).await.unwrap(), Some(OrgRole::Owner));
+        assert_eq!(repo.add_org_member(org_id, "nobody@org.test", OrgRole::Member).await.unwrap(), 0);
+        assert_eq!(repo.add_org_member(org_id, &email, OrgRole::Member).await.unwrap(), 1);
+        assert_eq!(repo.add_org_member(org_id, &email, OrgRole::Admin).await.unwrap(), 0);
+        assert_eq!(repo.set_org_role(org_id, member_id, OrgRole::Admin).await.unwrap(), 1);
+        assert_eq!(repo.count_org_owners(org_id).await.unwrap(), 1);
+        let orgs = repo.get_user_orgs(member_id).await.unwrap();
+        assert_eq!((orgs[0].role.as_str(), orgs[0].members), ("admin", 2));
+
+        // Org chats leave the personal list and are open to every member
+        let chat_id = repo.create_chat(owner_id, "plans", "gpt-4o").await.unwrap();
+        repo.set_chat_org(chat_id, Some(org_id)).await.unwrap();
+        let personal = repo.get_all_chats(owner_id, None, None, NO_LIMIT).await.unwrap();
+        assert!(personal.iter().all(|chat| chat.id != chat_id));
+        let org_chats = repo.get_org_chats(org_id, member_id, None, None, NO_LIMIT).await.unwrap();
+        assert!(org_chats.iter().any(|chat| chat.id == chat_id));
+        assert!(matches!(repo.get_chat_role(chat_id, member_id).await.unwrap(), Some(ChatRole::Editor)));
+        assert_eq!(repo.get_chat_org(chat_id).await.unwrap().unwrap().id, org_id);
+
+        let agent = Agent {
+            id: 0,
+            user_id: owner_id,
+            org_id: Some(org_id),
+            name: "Reviewer".to_string(),
+            model: "gpt-4o".to_string(),
+            system_prompt: None,
+            temperature: None,
+            top_p: None,
+            max_tokens: None,
+            stop_sequences: None,
+            tools_enabled: false,
+            allow_tools: None,
+            version: 0,
+        };
+        let agent_id = repo.save_agent(&agent).await.unwrap();
+        assert!(repo.get_agents(member_id, None, None, NO_LIMIT).await.unwrap().is_empty());
+        assert_eq!(repo.get_agents(member_id, Some(org_id), None, NO_LIMIT).await.unwrap()[0].id, agent_id);
+
+        // Removed members lose the org's chats
+        assert_eq!(repo.remove_org_member(org_id, member_id).await.unwrap(), 1);
+        assert!(repo.get_chat_role(chat_id, member_id).await.unwrap().is_none());
+
+        // Deleting the org takes its agents and returns its chats
+        assert_eq!(repo.delete_org(org_id).await.unwrap(), 1);
+        let personal = repo.get_all_chats(owner_id, None, None, NO_LIMIT).await.unwrap();
+        assert!(personal.iter().any(|chat| chat.id == chat_id));
+        let agents = repo.get_agents(owner_id, None, None, NO_LIMIT).await.unwrap();
+        assert!(agents.iter().all(|agent| agent.id != agent_id));
+    }
+
+    #[tokio::test]
+    async fn test_data_exports() {
+        let (_pool, repo, user_id) = setup().await;
+        let chat_id = repo.create_chat(user_id, "old notes", "gpt-4o").await.unwrap();
+        repo.set_archived(chat_id, user_id, true).await.unwrap();
+        assert!(repo.get_owned_chats(user_id).await.unwrap().iter().any(|chat| chat.id == chat_id));
+
+        let first = repo.create_data_export(user_id).await.unwrap();
+        repo.finish_data_export(first, Some("first.zip"), None).await.unwrap();
+        let second = repo.create_data_export(user_id).await.unwrap();
+        repo.set_data_export_progress(second, 40).await.unwrap();
+        let latest = repo.get_latest_data_export(user_id).await.unwrap().unwrap();
+        assert_eq!((latest.id, latest.status.as_str(), latest.progress), (second, "running", 40));
+
+        // Only older exports go, with their files
+        assert_eq!(repo.delete_data_exports_before(user_id, second).await.unwrap(), ["first.zip"]);
+        assert!(repo.fail_interrupted_data_exports().await.unwrap() >= 1);
+        let latest = repo.get_latest_data_export(user_id).await.unwrap().unwrap();
+        assert_eq!((latest.id, latest.status.as_str()), (second, "failed"));
+        assert!(latest.finished_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_guests() {
+        let (_pool, repo, user_id) = setup().await;
+        assert!(!repo.is_guest(user_id).await.unwrap());
+        assert!(!repo.claim_guest(user_id, "taken@guest.test", "x", None).await.unwrap());
+
+        let email = format!("guest-{}@guest.invalid", uuid::Uuid::new_v4().simple());
+        let guest_id = repo.create_guest(&email, "x", 24).await.unwrap();
+        assert!(repo.is_guest(guest_id).await.unwrap());
+        assert!(!repo.expired_guests().await.unwrap().contains(&guest_id));
+        let chat_id = repo.create_chat(guest_id, "trial", "gpt-4o").await.unwrap();
+
+        // A bad invite leaves the guest as it was
+        assert!(!repo.claim_guest(guest_id, "kept@guest.test", "y", Some("nope")).await.unwrap());
+        assert!(repo.is_guest(guest_id).await.unwrap());
+
+        assert!(repo.claim_guest(guest_id, "kept@guest.test", "y", None).await.unwrap());
+        assert!(!repo.is_guest(guest_id).await.unwrap());
+        assert_eq!(repo.get_chat(chat_id).await.unwrap().unwrap().user_id, guest_id);
+        assert_eq!(repo.get_user_email(guest_id).await.unwrap().as_deref(), Some("kept@guest.test"));
+
+        let expired = format!("guest-{}@guest.invalid", uuid::Uuid::new_v4().simple());
+        let expired_id = repo.create_guest(&expired, "x", -1).await.unwrap();
+        assert!(repo.expired_guests().await.unwrap().contains(&expired_id));
+    }
+
+    #[tokio::test]
+    async fn test_audit_log() {
+        let (pool, repo, _) = setup().await;
+        let email = format!("{}@audit.test", uuid::Uuid::new_v4());
+        let user_id = sqlx::query_scalar!(
+            r#"INSERT INTO users (email, password) VALUES (?, 'test') RETURNING id AS "id!""#,
+            email
+        )
+        .fetch_one(&*pool)
+        .await
+        .unwrap();
+
+        repo.record_audit_event(Some(user_id), "login", Some("password"), None, Some("127.0.0.1"))
+            .await
+            .unwrap();
+        repo.record_audit_event(Some(user_id), "account_disabled", None, Some("admin@audit.test"), None)
+            .await
+            .unwrap();
+        repo.record_audit_event(None, "login_failed", Some(&email), None, None).await.unwrap();
+
+        let entries = repo.get_audit_log(Some(user_id), None, None, 10).await.unwrap();
+        let events: Vec<_> = entries.iter().map(|entry| entry.event.as_str()).collect();
+        assert_eq!(events, ["account_disabled", "login"]);
+        assert_eq!(entries[0].actor.as_deref(), Some("admin@audit.test"));
+        assert_eq!(entries[1].email.as_deref(), Some(email.as_str()));
+
+        let logins = repo.get_audit_log(Some(user_id), Some("login"), None, 10).await.unwrap();
+        assert_eq!(logins.len(), 1);
+        // Failed logins to unknown emails match on the email tried
+        let matched = repo.get_audit_log(None, None, Some(&email), 10).await.unwrap();
+        assert_eq!(matched.len(), 3);
+
+        // The account's events go with it
+        repo.delete_user(user_id).await.unwrap();
+        assert!(repo.get_audit_log(Some(user_id), None, None, 10).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_login_links() {
+        let (pool, repo, _) = setup().await;
+        let email = format!("{}@link.test", uuid::Uuid::new_v4());
+        let user_id = sqlx::query_scalar!(
+            r#"INSERT INTO users (email, password) VALUES (?, 'test') RETURNING id AS "id!""#,
+            email
+        )
+        .fetch_one(&*pool)
+        .await
+        .unwrap();
+        let token = |name: &str| format!("{}-{}", uuid::Uuid::new_v4(), name);
+        let (first, second, expired) = (token("first"), token("second"), token("expired"));
+
+        assert!(!repo.create_login_link("nobody@link.test", &first, 15).await.unwrap());
+        assert!(repo.create_login_link(&email, &first, 15).await.unwrap());
+        assert!(repo.create_login_link(&email, &second, 15).await.unwrap());
+        assert!(repo.create_login_link(&email, &expired, -1).await.unwrap());
+        assert_eq!(repo.find_login_link(&first).await.unwrap(), Some(user_id));
+        assert_eq!(repo.find_login_link(&expired).await.unwrap(), None);
+
+        // Single use, and the other links of the user go with it
+        assert_eq!(repo.use_login_link(&first).await.unwrap(), Some(user_id));
+        assert_eq!(repo.use_login_link(&first).await.unwrap(), None);
+        assert_eq!(repo.use_login_link(&second).await.unwrap(), None);
+
+        let verified = sqlx::query_scalar!("SELECT verified_at FROM users WHERE id = ?", user_id)
+            .fetch_one(&*pool)
+            .await
+            .unwrap();
+        assert!(verified.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_remember_tokens() {
         let (pool, repo, user_id) = setup().await;
-        let chat = repo.create_chat(user_id, "test", "gpt-4").await;
-        assert!(chat.is_ok(), "Failed to create chat");
+        let token = |name: &str| format!("{}-{}", uuid::Uuid::new_v4(), name);
+        let series = token("series");
+        let (first, second, third) = (token("first"), token("second"), token("third"));
+
+        let remember_id = repo.create_remember_token(user_id, &series, &first, 30).await.unwrap();
+        let session = token("session");
+        repo.create_session(user_id, &session, None, None, None, Some(remember_id)).await.unwrap();
+
+        // Sessions end after going unused for the idle time
+        sqlx::query!(
+            "UPDATE sessions SET last_seen_at = datetime('now', '-2 days') WHERE token_hash = ?",
+            session
+        )
+        .execute(&*pool)
+        .await
+        .unwrap();
+        assert_eq!(repo.authenticate_session(&session, 24).await.unwrap(), None);
+
+        let rotated = RememberLogin::Rotated { user_id, remember_id };
+        assert_eq!(repo.rotate_remember_token(&series, &first, &second, 30).await.unwrap(), rotated);
+        // A request sent alongside still gets in with the old token
+        assert_eq!(
+            repo.rotate_remember_token(&series, &first, &third, 30).await.unwrap(),
+            RememberLogin::Recent { user_id, remember_id }
+        );
+        assert_eq!(repo.rotate_remember_token(&series, &second, &third, 30).await.unwrap(), rotated);
+
+        // Later on, the old token coming back revokes the series
+        sqlx::query!(
+            "UPDATE remember_tokens SET rotated_at = datetime('now', '-1 hours') WHERE id = ?",
+            remember_id
+        )
+        .execute(&*pool)
+        .await
+        .unwrap();
+        assert_eq!(
+            repo.rotate_remember_token(&series, &second, &first, 30).await.unwrap(),
+            RememberLogin::Reused(user_id)
+        );
+        assert_eq!(
+            repo.rotate_remember_token(&series, &third, &first, 30).await.unwrap(),
+            RememberLogin::Invalid
+        );
+
+        // Logging out a session ends its series
+        let series = token("series");
+        let remember_id = repo.create_remember_token(user_id, &series, &first, 30).await.unwrap();
+        repo.create_session(user_id, &second, None, None, None, Some(remember_id)).await.unwrap();
+        repo.delete_session_by_token(&second).await.unwrap();
+        assert_eq!(
+            repo.rotate_remember_token(&series, &first, &third, 30).await.unwrap(),
+            RememberLogin::Invalid
+        );
     }
 
     #[tokio::test]
-    async fn test_add_message_block() {
+    async fn test_admin_user_management() {
+        let (pool, repo, admin_id) = setup().await;
+        let email = format!("{}@admin.test", uuid::Uuid::new_v4());
+        let user_id = sqlx::query_scalar!(
+            r#"INSERT INTO users (email, password) VALUES (?, 'test') RETURNING id AS "id!""#,
+            email
+        )
+        .fetch_one(&*pool)
+        .await
+        .unwrap();
+        repo.create_chat(user_id, "Usage", "gpt-4o").await.unwrap();
+
+        let listed = repo.admin_users().await.unwrap();
+        let user = listed.iter().find(|u| u.id == user_id).unwrap();
+        assert_eq!((user.chats, user.responses, user.total_tokens), (1, 0, 0));
+        assert!(!user.is_admin && user.disabled_at.is_none());
+
+        // Sessions opened for support name the admin
+        let session = format!("{}-support", uuid::Uuid::new_v4());
+        repo.create_session(user_id, &session, None, None, Some(admin_id), None).await.unwrap();
+        let impersonator = repo.authenticate_session(&session, 24).await.unwrap().unwrap().impersonator;
+        assert_eq!(impersonator.as_deref(), Some("test@test.com"));
+
+        assert_eq!(repo.set_user_disabled(user_id, true).await.unwrap(), 1);
+        assert_eq!(repo.authenticate_session(&session, 24).await.unwrap(), None);
+        let listed = repo.admin_users().await.unwrap();
+        assert!(listed.iter().find(|u| u.id == user_id).unwrap().disabled_at.is_some());
+        assert_eq!(repo.set_user_disabled(user_id, false).await.unwrap(), 1);
+
+        assert_eq!(repo.delete_user(user_id).await.unwrap(), 1);
+        assert_eq!(repo.get_user_email(user_id).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_delete_user_purges_data() {
+        let (pool, repo, other_id) = setup().await;
+        let email = format!("{}@delete.test", uuid::Uuid::new_v4());
+        let user_id = sqlx::query_scalar!(
+            r#"INSERT INTO users (email, password) VALUES (?, 'test') RETURNING id AS "id!""#,
+            email
+        )
+        .fetch_one(&*pool)
+        .await
+        .unwrap();
+        let own = format!("1-{}.png", uuid::Uuid::new_v4());
+        let shared = format!("2-{}.png", uuid::Uuid::new_v4());
+
+        let chat_id = repo.create_chat(user_id, "mine", "gpt-4").await.unwrap();
+        let pair_id = repo
+            .add_message_block(chat_id, &format!("![a](/uploads/{}) ![b](/uploads/{})", own, shared))
+            .await
+            .unwrap();
+        repo.add_ai_message_to_pair(pair_id, "answer").await.unwrap();
+        let other_chat = repo.create_chat(other_id, "theirs", "gpt-4").await.unwrap();
+        repo.add_message_block(other_chat, &format!("[📎 b](/uploads/{})", shared))
+            .await
+            .unwrap();
+        repo.create_api_token(user_id, "script", ApiScope::Read, None).await.unwrap();
+
+        assert_eq!(repo.user_uploads(user_id).await.unwrap(), vec![own]);
+
+        let pair = sqlx::query!(
+            "SELECT human_message_id, ai_message_id FROM message_pairs WHERE id = ?",
+            pair_id
+        )
+        .fetch_one(&*pool)
+        .await
+        .unwrap();
+        assert_eq!(repo.delete_user(user_id).await.unwrap(), 1);
+
+        let left = sqlx::query_scalar!(
+            r#"SELECT COUNT(*) AS "count!: i64" FROM messages WHERE id IN (?, ?)"#,
+            pair.human_message_id,
+            pair.ai_message_id
+        )
+        .fetch_one(&*pool)
+        .await
+        .unwrap();
+        assert_eq!(left, 0);
+        assert!(repo.get_api_tokens(user_id).await.unwrap().is_empty());
+        assert_eq!(repo.get_all_chats(other_id, None, None, NO_LIMIT).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_api_tokens() {
+        let (_pool, repo, user_id) = setup().await;
+
+        let token = repo.create_api_token(user_id, "script", ApiScope::Read, Some(30)).await.unwrap();
+        assert_eq!(
+            repo.authenticate_api_token(&token).await.unwrap(),
+            Some((user_id, ApiScope::Read))
+        );
+        assert_eq!(repo.authenticate_api_token("ac_wrong").await.unwrap(), None);
+
+        let tokens = repo.get_api_tokens(user_id).await.unwrap();
+        let created = tokens.iter().find(|t| t.name == "script").unwrap();
+        assert!(created.last_used_at.is_some());
+        assert!(created.expires_at.is_some());
+
+        assert_eq!(repo.delete_api_token(user_id + 1, created.id).await.unwrap(), 0);
+        assert_eq!(repo.delete_api_token(user_id, created.id).await.unwrap(), 1);
+        assert_eq!(repo.authenticate_api_token(&token).await.unwrap(), None);
+
+        let expired = repo.create_api_token(user_id, "old", ApiScope::Write, Some(-1)).await.unwrap();
+        assert_eq!(repo.authenticate_api_token(&expired).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_legacy_api_token() {
         let (pool, repo, user_id) = setup().await;
-        let chat = repo.create_chat(user_id, "test", "gpt-4").await;
-        assert!(chat.is_ok(), "Failed to create chat");
-        let chat_id = chat.unwrap();
+        let token = format!("ac_{}", uuid::Uuid::new_v4().simple());
+        sqlx::query!(
+            "INSERT INTO api_tokens (user_id, name, token_hash) VALUES (?, 'legacy', ?)",
+            user_id,
+            token
+        )
+        .execute(&*pool)
+        .await
+        .unwrap();
 
-        let message_block = repo.add_message_block(chat_id, "Test").await;
-        assert!(message_block.is_ok(), "Failed to add message_block")
+        assert_eq!(
+            repo.authenticate_api_token(&token).await.unwrap(),
+            Some((user_id, ApiScope::Write))
+        );
+        let stored = sqlx::query_scalar!("SELECT token_hash FROM api_tokens WHERE user_id = ?", user_id)
+            .fetch_one(&*pool)
+            .await
+            .unwrap();
+        assert_eq!(stored, hash_token(&token));
+        // Knowing the stored hash doesn't help
+        assert_eq!(repo.authenticate_api_token(&stored).await.unwrap(), None);
+        assert!(repo.authenticate_api_token(&token).await.unwrap().is_some());
     }
 
     #[tokio::test]
-    async fn test_json() {
+    async fn test_tool_call_approval() {
+        let (_pool, repo, user_id) = setup().await;
+        let chat_id = repo.create_chat(user_id, "tools", "gpt-4o").await.unwrap();
+        let other_chat = repo.create_chat(user_id, "other", "gpt-4o").await.unwrap();
+        let pair_id = repo.add_message_block(chat_id, "What time is it?").await.unwrap();
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let tool_call = ToolCall {
+            id: id.clone(),
+            r#type: "function".to_string(),
+            function: FunctionCall {
+                name: "time".to_string(),
+                arguments: "{}".to_string(),
+            },
+        };
+        let confirmation = ToolCallConfirmation {
+            id: id.clone(),
+            chat_id,
+            message_pair_id: pair_id,
+            tool_call,
+            status: ToolCallStatus::Pending,
+            created_at: Utc::now(),
+            user_response: None,
+            result: None,
+        };
+        repo.save_tool_call_confirmation(&confirmation).await.unwrap();
+
+        // Confirmations belong to their chat
+        assert_eq!(repo.get_tool_call(other_chat, &id).await.unwrap(), None);
+        assert_eq!(repo.approve_tool_call(other_chat, &id, "Executing tool: time").await.unwrap(), None);
+        assert_eq!(repo.retrieve_chat(other_chat).await.unwrap().len(), 0);
+
+        let stored = repo.get_tool_call(chat_id, &id).await.unwrap().unwrap();
+        assert!(stored.contains("\"time\""));
+        let result_pair = repo
+            .approve_tool_call(chat_id, &id, "Executing tool: time")
+            .await
+            .unwrap()
+            .unwrap();
+        let pairs = repo.retrieve_chat(chat_id).await.unwrap();
+        assert_eq!(pairs.len(), 2);
+        assert_eq!(pairs[1].id, result_pair);
+        assert_eq!(pairs[1].human_message, "Executing tool: time");
+
+        // Only a pending call can be answered, and only once
+        assert_eq!(repo.approve_tool_call(chat_id, &id, "Executing tool: time").await.unwrap(), None);
+        assert!(!repo.reject_tool_call(chat_id, &id).await.unwrap());
+        assert_eq!(repo.retrieve_chat(chat_id).await.unwrap().len(), 2);
+
+        let rejected = ToolCallConfirmation { id: uuid::Uuid::new_v4().to_string(), ..confirmation };
+        repo.save_tool_call_confirmation(&rejected).await.unwrap();
+        assert!(!repo.reject_tool_call(other_chat, &rejected.id).await.unwrap());
+        assert!(repo.reject_tool_call(chat_id, &rejected.id).await.unwrap());
+        assert!(!repo.reject_tool_call(chat_id, &rejected.id).await.unwrap());
+        assert_eq!(repo.approve_tool_call(chat_id, &rejected.id, "Executing tool: time").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_retention() {
         let (pool, repo, user_id) = setup().await;
-        let chat = repo.create_chat(user_id, "test", "gpt-4").await;
-        assert!(chat.is_ok(), "Failed to create chat");
-        let chat_id = chat.unwrap();
+        let old_chat = repo.create_chat(user_id, "old", "gpt-4o").await.unwrap();
+        let recent_chat = repo.create_chat(user_id, "recent", "gpt-4o").await.unwrap();
+        let marker = uuid::Uuid::new_v4().to_string();
+        let old_pair = repo.add_message_block(old_chat, &marker).await.unwrap();
+        let recent_pair = repo.add_message_block(recent_chat, "still here").await.unwrap();
 
-        let message_block = repo.add_message_block(chat_id, "Test").await;
-        assert!(message_block.is_ok(), "Failed to add message_block");
+        let confirm = |chat_id, message_pair_id, created_at| {
+            let id = uuid::Uuid::new_v4().to_string();
+            let confirmation = ToolCallConfirmation {
+                id: id.clone(),
+                chat_id,
+                message_pair_id,
+                tool_call: ToolCall {
+                    id: id.clone(),
+                    r#type: "function".to_string(),
+                    function: FunctionCall {
+                        name: "time".to_string(),
+                        arguments: "{}".to_string(),
+                    },
+                },
+                status: ToolCallStatus::Pending,
+                created_at,
+                user_response: None,
+                result: None,
+            };
+            (id, confirmation)
+        };
+        let (_, old_chat_call) = confirm(old_chat, old_pair, Utc::now());
+        repo.save_tool_call_confirmation(&old_chat_call).await.unwrap();
+        let (stale_id, stale_call) = confirm(recent_chat, recent_pair, Utc::now() - chrono::Duration::days(40));
+        repo.save_tool_call_confirmation(&stale_call).await.unwrap();
+        let (fresh_id, fresh_call) = confirm(recent_chat, recent_pair, Utc::now());
+        repo.save_tool_call_confirmation(&fresh_call).await.unwrap();
 
-        let chat_message_pairs = repo.retrieve_chat(chat_id).await;
-        print!("{:#?}", chat_message_pairs)
+        // The last message counts, not when the chat started
+        sqlx::query("UPDATE chats SET created_at = datetime('now', '-90 days') WHERE id IN (?, ?)")
+            .bind(old_chat)
+            .bind(recent_chat)
+            .execute(&*pool)
+            .await
+            .unwrap();
+        sqlx::query("UPDATE message_blocks SET created_at = datetime('now', '-40 days') WHERE chat_id = ?")
+            .bind(old_chat)
+            .execute(&*pool)
+            .await
+            .unwrap();
+
+        assert!(repo.purge_inactive_chats(30).await.unwrap() >= 1);
+        assert!(repo.get_chat(old_chat).await.unwrap().is_none());
+        assert!(repo.get_chat(recent_chat).await.unwrap().is_some());
+        let left: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM messages WHERE message = ?")
+            .bind(&marker)
+            .fetch_one(&*pool)
+            .await
+            .unwrap();
+        assert_eq!(left, 0);
+
+        assert!(repo.purge_tool_call_confirmations(30).await.unwrap() >= 1);
+        assert!(repo.get_tool_call(recent_chat, &stale_id).await.unwrap().is_none());
+        assert!(repo.get_tool_call(recent_chat, &fresh_id).await.unwrap().is_some());
+
+        sqlx::query("INSERT INTO audit_log (user_id, event, created_at) VALUES (?, 'login', datetime('now', '-40 days'))")
+            .bind(user_id)
+            .execute(&*pool)
+            .await
+            .unwrap();
+        assert!(repo.purge_audit_log(30).await.unwrap() >= 1);
+        let old_entries: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM audit_log WHERE user_id = ? AND created_at <= datetime('now', '-30 days')",
+        )
+        .bind(user_id)
+        .fetch_one(&*pool)
+        .await
+        .unwrap();
+        assert_eq!(old_entries, 0);
+    }
+
+    #[tokio::test]
+    async fn test_cached_chat_list() {
+        let (pool, repo, user_id) = setup().await;
+        let chat_id = repo.create_chat(user_id, "cached", "gpt-4o").await.unwrap();
+        let name = |chats: Vec<Chat>| chats.into_iter().find(|c| c.id == chat_id).unwrap().name;
+
+        let chats = repo.get_all_chats(user_id, None, None, NO_LIMIT).await.unwrap();
+        assert_eq!(name(chats), "cached");
+        let count = repo.count_chats(user_id, None).await.unwrap();
+
+        // Writes that bypass the repository aren't seen until the entry expires
+        sqlx::query("UPDATE chats SET name = 'behind its back' WHERE id = ?")
+            .bind(chat_id)
+            .execute(&*pool)
+            .await
+            .unwrap();
+        let chats = repo.get_all_chats(user_id, None, None, NO_LIMIT).await.unwrap();
+        assert_eq!(name(chats), "cached");
+
+        repo.rename_chat(chat_id, user_id, "renamed").await.unwrap();
+        let chats = repo.get_all_chats(user_id, None, None, NO_LIMIT).await.unwrap();
+        assert_eq!(name(chats), "renamed");
+
+        repo.delete_chat(chat_id).await.unwrap();
+        assert_eq!(repo.count_chats(user_id, None).await.unwrap(), count - 1);
+    }
+
+    #[tokio::test]
+    async fn test_pair_usage() {
+        let (pool, repo, _) = setup().await;
+        let email = format!("{}@usage.test", uuid::Uuid::new_v4());
+        let user_id: i64 = sqlx::query_scalar("INSERT INTO users (email, password) VALUES (?, 'x') RETURNING id")
+            .bind(&email)
+            .fetch_one(&*pool)
+            .await
+            .unwrap();
+        let chat_id = repo.create_chat(user_id, "costs", "deepseek-ai/DeepSeek-V3").await.unwrap();
+
+        // A priced model, one without a price, and a pair still waiting
+        for model in ["deepseek-ai/DeepSeek-V3", "unpriced-model"] {
+            let pair_id = repo.add_message_block(chat_id, "Hi").await.unwrap();
+            let message_id = repo
+                .add_ai_message_with_extended_data(
+                    pair_id,
+                    "Hello",
+                    None,
+                    None,
+                    None,
+                    None,
+                    Some(1_000),
+                    Some(2_000),
+                    Some(3_000),
+                    None,
+                )
+                .await
+                .unwrap();
+            repo.set_response_stats(message_id, model, 100).await.unwrap();
+        }
+        repo.add_message_block(chat_id, "Still there?").await.unwrap();
+
+        let usage = repo.chat_usage(chat_id).await.unwrap();
+        assert_eq!(usage.responses, 2);
+        assert_eq!(usage.prompt_tokens, 2_000);
+        assert_eq!(usage.completion_tokens, 4_000);
+        // 1000 * $0.27 + 2000 * $1.10 per million
+        assert!((usage.cost - 0.00247).abs() < 1e-12);
+
+        let (from, to) = ("2000-01-01", "2999-12-31");
+        let user = repo.user_usage(user_id, from, to).await.unwrap();
+        assert_eq!(user.responses, 2);
+        assert!((user.cost - usage.cost).abs() < 1e-12);
+        let days = repo.daily_costs(user_id, from, to).await.unwrap();
+        assert_eq!(days.len(), 1);
+        assert_eq!(days[0].responses, 2);
+        let before = repo.user_usage(user_id, "2000-01-01", "2000-01-02").await.unwrap();
+        assert_eq!(before.responses, 0);
     }
 }
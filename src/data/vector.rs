@@ -0,0 +1,67 @@
+// Embeddings are stored as little-endian f32 blobs, the format sqlite-vec
+// reads, so the same rows work with and without the `vector` feature
+
+// Loads sqlite-vec into every connection opened afterwards. Call before the
+// pool connects
+#[cfg(feature = "vector")]
+pub fn register() {
+    use libsqlite3_sys::{sqlite3, sqlite3_api_routines, sqlite3_auto_extension};
+    use std::os::raw::{c_char, c_int};
+
+    type EntryPoint = unsafe extern "C" fn(*mut sqlite3, *mut *mut c_char, *const sqlite3_api_routines) -> c_int;
+
+    static REGISTER: std::sync::Once = std::sync::Once::new();
+    REGISTER.call_once(|| unsafe {
+        let init = std::mem::transmute::<*const (), EntryPoint>(sqlite_vec::sqlite3_vec_init as *const ());
+        sqlite3_auto_extension(Some(init));
+    });
+}
+
+#[cfg(not(feature = "vector"))]
+pub fn register() {}
+
+pub fn encode(embedding: &[f32]) -> Vec<u8> {
+    embedding.iter().flat_map(|value| value.to_le_bytes()).collect()
+}
+
+// With sqlite-vec, ranking happens in SQL instead
+#[cfg_attr(feature = "vector", allow(dead_code))]
+pub fn decode(blob: &[u8]) -> Vec<f32> {
+    blob.as_chunks::<4>().0.iter().map(|bytes| f32::from_le_bytes(*bytes)).collect()
+}
+
+// Same as sqlite-vec's `vec_distance_cosine`: 0 for the same direction, up to 2
+#[cfg_attr(feature = "vector", allow(dead_code))]
+pub fn cosine_distance(a: &[f32], b: &[f32]) -> f32 {
+    let (mut dot, mut norm_a, mut norm_b) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b) {
+        dot += x * y;
+        norm_a += x * x;
+        norm_b += y * y;
+    }
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 1.0;
+    }
+    1.0 - dot / (norm_a.sqrt() * norm_b.sqrt())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_round_trip() {
+        let embedding = [0.5, -1.25, 3.0];
+        let blob = encode(&embedding);
+        assert_eq!(blob.len(), 12);
+        assert_eq!(decode(&blob), embedding);
+    }
+
+    #[test]
+    fn test_cosine_distance() {
+        assert!(cosine_distance(&[1.0, 0.0], &[2.0, 0.0]).abs() < 1e-6);
+        assert!((cosine_distance(&[1.0, 0.0], &[0.0, 1.0]) - 1.0).abs() < 1e-6);
+        assert!((cosine_distance(&[1.0, 0.0], &[-1.0, 0.0]) - 2.0).abs() < 1e-6);
+        assert_eq!(cosine_distance(&[0.0, 0.0], &[1.0, 0.0]), 1.0);
+    }
+}
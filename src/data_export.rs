@@ -0,0 +1,298 @@
+use chrono::NaiveDateTime;
+use futures::stream::{BoxStream, TryStreamExt};
+use serde::Serialize;
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipWriter};
+
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::data::repository::NO_LIMIT;
+use crate::utils::attachments::upload_files;
+use crate::utils::export::{build_export, render, ExportFormat};
+use crate::{AppState, User};
+
+// Finished exports wait here until the user downloads them or starts another
+pub const EXPORT_DIR: &str = "exports";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ExportError {
+    #[error("Database error: {0}")]
+    Database(#[from] sqlx::Error),
+
+    #[error("Failed to write the archive: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Failed to write the archive: {0}")]
+    Zip(#[from] zip::result::ZipError),
+
+    #[error("Failed to serialize: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+#[derive(Serialize)]
+struct Profile<'a> {
+    email: &'a str,
+    display_name: Option<&'a str>,
+    created_at: NaiveDateTime,
+    verified_at: Option<NaiveDateTime>,
+}
+
+// The provider key is left out: it's encrypted at rest and the archive isn't
+#[derive(Serialize)]
+struct Settings<'a> {
+    has_api_key: bool,
+    base_url: Option<&'a str>,
+    model: Option<&'a str>,
+    system_prompt: Option<&'a str>,
+    temperature: Option<f64>,
+    top_p: Option<f64>,
+    max_tokens: Option<i64>,
+}
+
+// A zip of JSON, Markdown and uploaded files
+pub struct Archive {
+    zip: ZipWriter<File>,
+}
+
+impl Archive {
+    pub fn create(path: &Path) -> std::io::Result<Self> {
+        Ok(Archive {
+            zip: ZipWriter::new(File::create(path)?),
+        })
+    }
+
+    pub fn add(&mut self, name: &str, data: &[u8]) -> Result<(), ExportError> {
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+        self.zip.start_file(name, options)?;
+        self.zip.write_all(data)?;
+        Ok(())
+    }
+
+    pub fn add_json<T: Serialize>(&mut self, name: &str, value: &T) -> Result<(), ExportError> {
+        self.add(name, &serde_json::to_vec_pretty(value)?)
+    }
+
+    // Writes rows as JSON Lines as they arrive; returns how many there were
+    pub async fn add_rows<T: Serialize>(
+        &mut self,
+        name: &str,
+        mut rows: BoxStream<'_, sqlx::Result<T>>,
+    ) -> Result<usize, ExportError> {
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated);
+        self.zip.start_file(name, options)?;
+        let mut count = 0;
+        while let Some(row) = rows.try_next().await? {
+            serde_json::to_writer(&mut self.zip, &row)?;
+            self.zip.write_all(b"\n")?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    // Copies a file in without reading it into memory. False when it's gone
+    pub fn add_file(&mut self, name: &str, path: &Path) -> Result<bool, ExportError> {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(e.into()),
+        };
+        // Images and most documents are compressed already
+        let options = SimpleFileOptions::default()
+            .compression_method(CompressionMethod::Stored)
+            .large_file(file.metadata()?.len() >= u32::MAX as u64);
+        self.zip.start_file(name, options)?;
+        std::io::copy(&mut file, &mut self.zip)?;
+        Ok(true)
+    }
+
+    pub fn finish(self) -> Result<(), ExportError> {
+        self.zip.finish()?;
+        Ok(())
+    }
+}
+
+// Records the export and builds it in the background; the settings page
+// polls its progress
+pub async fn start(state: Arc<AppState>, user: User) -> sqlx::Result<i64> {
+    let export_id = state.chat_repo.create_data_export(user.id).await?;
+
+    tokio::spawn(async move {
+        let file = format!("{}.zip", uuid::Uuid::new_v4().simple());
+        let path = Path::new(EXPORT_DIR).join(&file);
+        let result = build(&state, &user, export_id, &path).await;
+
+        let finished = match &result {
+            Ok(()) => state.chat_repo.finish_data_export(export_id, Some(&file), None).await,
+            Err(e) => {
+                tracing::warn!("Data export {} for user {} failed: {}", export_id, user.id, e);
+                let _ = tokio::fs::remove_file(&path).await;
+                state
+                    .chat_repo
+                    .finish_data_export(export_id, None, Some(&e.to_string()))
+                    .await
+            }
+        };
+        if let Err(e) = finished {
+            tracing::error!("Failed to record data export {}: {}", export_id, e);
+        }
+
+        // Only the latest export is kept
+        match state.chat_repo.delete_data_exports_before(user.id, export_id).await {
+            Ok(files) => {
+                for file in files {
+                    let _ = tokio::fs::remove_file(Path::new(EXPORT_DIR).join(file)).await;
+                }
+            }
+            Err(e) => tracing::error!("Failed to remove old data exports of user {}: {}", user.id, e),
+        }
+    });
+
+    Ok(export_id)
+}
+
+async fn build(state: &AppState, user: &User, export_id: i64, path: &Path) -> Result<(), ExportError> {
+    let repo = &state.chat_repo;
+    tokio::fs::create_dir_all(EXPORT_DIR).await?;
+    let mut archive = Archive::create(path)?;
+
+    archive.add_json(
+        "profile.json",
+        &Profile {
+            email: &user.email,
+            display_name: user.display_name.as_deref(),
+            created_at: user.created_at,
+            verified_at: user.verified_at,
+        },
+    )?;
+    archive.add_json(
+        "settings.json",
+        &Settings {
+            has_api_key: user.openai_api_key.as_deref().is_some_and(|key| !key.is_empty()),
+            base_url: user.base_url.as_deref(),
+            model: user.model.as_deref(),
+            system_prompt: user.system_prompt.as_deref(),
+            temperature: user.temperature,
+            top_p: user.top_p,
+            max_tokens: user.max_tokens,
+        },
+    )?;
+    archive.add_json("agents.json", &repo.get_agents(user.id, None, None, NO_LIMIT).await?)?;
+    archive.add_json("custom_tools.json", &repo.get_custom_tools(user.id).await?)?;
+    archive.add_json("webhooks.json", &repo.get_webhooks(user.id).await?)?;
+    let templates: Vec<_> = repo
+        .get_prompt_templates(Some(user.id))
+        .await?
+        .into_iter()
+        .filter(|template| template.user_id == Some(user.id))
+        .collect();
+    archive.add_json("prompt_templates.json", &templates)?;
+    archive.add_json("folders.json", &repo.get_folders(user.id).await?)?;
+    archive.add_json("bookmarks.json", &repo.get_bookmarks(user.id).await?)?;
+    archive.add_json("scheduled_messages.json", &repo.get_scheduled_messages(user.id).await?)?;
+
+    let chats = repo.get_owned_chats(user.id).await?;
+    archive.add_json("chats.json", &chats)?;
+
+    // The raw rows, every version of every message included
+    archive.add_rows("data/message_blocks.jsonl", repo.stream_message_blocks(user.id)).await?;
+    archive.add_rows("data/message_pairs.jsonl", repo.stream_message_pairs(user.id)).await?;
+    archive.add_rows("data/attachments.jsonl", repo.stream_attachments(user.id)).await?;
+
+    // Chats are most of the work, uploads the rest
+    let mut uploads = Vec::new();
+    let mut progress = 0;
+    for (done, chat) in chats.iter().enumerate() {
+        let pairs = repo.retrieve_chat(chat.id).await?;
+        for pair in &pairs {
+            let images = pair.images.as_deref().unwrap_or_default();
+            for file in upload_files(&pair.human_message).into_iter().chain(upload_files(images)) {
+                if !uploads.contains(&file) {
+                    uploads.push(file);
+                }
+            }
+        }
+        for attachment in repo.get_chat_attachments(chat.id).await? {
+            if !uploads.contains(&attachment.path) {
+                uploads.push(attachment.path);
+            }
+        }
+
+        let export = build_export(chat, &pairs);
+        for format in [ExportFormat::Json, ExportFormat::Md] {
+            let name = format!("chats/{}.{}", chat.id, format.extension());
+            archive.add(&name, render(&export, format).as_bytes())?;
+        }
+
+        let percent = ((done + 1) * 90 / chats.len()) as i64;
+        if percent > progress {
+            progress = percent;
+            repo.set_data_export_progress(export_id, progress).await?;
+        }
+    }
+
+    if let Some(avatar) = user.avatar.as_deref().and_then(|avatar| avatar.strip_prefix("/uploads/")) {
+        uploads.push(avatar.to_string());
+    }
+    for file in uploads {
+        // Files removed since are skipped
+        archive.add_file(&format!("uploads/{}", file), &Path::new("uploads").join(&file))?;
+    }
+
+    archive.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn test_archive() {
+        let path = std::env::temp_dir().join(format!("{}.zip", uuid::Uuid::new_v4()));
+        let mut archive = Archive::create(&path).unwrap();
+        archive.add_json("profile.json", &serde_json::json!({ "email": "a@b.c" })).unwrap();
+        archive.add("chats/1.md", b"# Hello\n").unwrap();
+        archive.finish().unwrap();
+
+        let mut zip = zip::ZipArchive::new(File::open(&path).unwrap()).unwrap();
+        let mut names: Vec<_> = zip.file_names().map(str::to_string).collect();
+        names.sort();
+        assert_eq!(names, ["chats/1.md", "profile.json"]);
+
+        let mut profile = String::new();
+        zip.by_name("profile.json").unwrap().read_to_string(&mut profile).unwrap();
+        let profile: serde_json::Value = serde_json::from_str(&profile).unwrap();
+        assert_eq!(profile["email"], "a@b.c");
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_archive_streams_rows_and_files() {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("{}.zip", uuid::Uuid::new_v4()));
+        let upload = dir.join(format!("{}.txt", uuid::Uuid::new_v4()));
+        std::fs::write(&upload, b"notes").unwrap();
+
+        let mut archive = Archive::create(&path).unwrap();
+        let rows = futures::stream::iter([Ok(serde_json::json!({ "id": 1 })), Ok(serde_json::json!({ "id": 2 }))]);
+        assert_eq!(archive.add_rows("data/rows.jsonl", Box::pin(rows)).await.unwrap(), 2);
+        assert!(archive.add_file("uploads/notes.txt", &upload).unwrap());
+        assert!(!archive.add_file("uploads/gone.txt", &dir.join("gone-missing.txt")).unwrap());
+        archive.finish().unwrap();
+
+        let mut zip = zip::ZipArchive::new(File::open(&path).unwrap()).unwrap();
+        let mut rows = String::new();
+        zip.by_name("data/rows.jsonl").unwrap().read_to_string(&mut rows).unwrap();
+        assert_eq!(rows, "{\"id\":1}\n{\"id\":2}\n");
+        let mut notes = String::new();
+        zip.by_name("uploads/notes.txt").unwrap().read_to_string(&mut notes).unwrap();
+        assert_eq!(notes, "notes");
+        assert!(zip.by_name("uploads/gone.txt").is_err());
+
+        std::fs::remove_file(path).unwrap();
+        std::fs::remove_file(upload).unwrap();
+    }
+}
@@ -0,0 +1,209 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sqlx::{
+    migrate::{MigrateError, Migrator},
+    sqlite::SqlitePool,
+    Row,
+};
+
+use std::collections::HashMap;
+use std::path::Path;
+
+// The migrations shipped in `MIGRATIONS_PATH`, read from disk each time so
+// files added since startup show up as pending
+pub async fn migrator() -> Result<Migrator, MigrateError> {
+    Migrator::new(Path::new(dotenv::var("MIGRATIONS_PATH").unwrap().as_str())).await
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MigrationState {
+    Applied,
+    Pending,
+    // Started but didn't finish; sqlx refuses to migrate until it's fixed
+    Failed,
+    // The file changed after it was applied
+    Changed,
+    // Applied by a newer version of the app
+    Unknown,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub description: String,
+    pub state: MigrationState,
+    pub installed_on: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TableSize {
+    pub name: String,
+    pub kind: String,
+    pub bytes: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DatabaseStatus {
+    pub migrations: Vec<MigrationStatus>,
+    pub pending: usize,
+    pub tables: Vec<TableSize>,
+    pub path: String,
+    pub journal_mode: String,
+    pub file_bytes: i64,
+    pub free_bytes: i64,
+    pub wal_bytes: Option<u64>,
+}
+
+pub async fn migration_status(pool: &SqlitePool, migrator: &Migrator) -> sqlx::Result<Vec<MigrationStatus>> {
+    let rows = sqlx::query(
+        "SELECT version, description, installed_on, success, checksum FROM _sqlx_migrations ORDER BY version",
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut applied: HashMap<i64, (String, DateTime<Utc>, bool, Vec<u8>)> = rows
+        .into_iter()
+        .map(|row| {
+            (
+                row.get("version"),
+                (row.get("description"), row.get("installed_on"), row.get("success"), row.get("checksum")),
+            )
+        })
+        .collect();
+
+    // Down migrations share the version of their up migration
+    let mut migrations: Vec<MigrationStatus> = migrator
+        .iter()
+        .filter(|migration| !migration.migration_type.is_down_migration())
+        .map(|migration| {
+            let (state, installed_on) = match applied.remove(&migration.version) {
+                None => (MigrationState::Pending, None),
+                Some((_, installed_on, false, _)) => (MigrationState::Failed, Some(installed_on)),
+                Some((_, installed_on, true, checksum)) if checksum != *migration.checksum => {
+                    (MigrationState::Changed, Some(installed_on))
+                }
+                Some((_, installed_on, true, _)) => (MigrationState::Applied, Some(installed_on)),
+            };
+            MigrationStatus {
+                version: migration.version,
+                description: migration.description.to_string(),
+                state,
+                installed_on,
+            }
+        })
+        .collect();
+    migrations.extend(applied.into_iter().map(|(version, (description, installed_on, _, _))| {
+        MigrationStatus {
+            version,
+            description,
+            state: MigrationState::Unknown,
+            installed_on: Some(installed_on),
+        }
+    }));
+    // Newest first
+    migrations.sort_by_key(|migration| std::cmp::Reverse(migration.version));
+    Ok(migrations)
+}
+
+pub async fn status(pool: &SqlitePool, migrator: &Migrator) -> sqlx::Result<DatabaseStatus> {
+    let migrations = migration_status(pool, migrator).await?;
+    let pending = migrations
+        .iter()
+        .filter(|migration| migration.state == MigrationState::Pending)
+        .count();
+
+    // Space used by each table and index, from the dbstat virtual table
+    let tables = sqlx::query(
+        r#"
+        SELECT s.name, s.type, SUM(d.pgsize) AS bytes
+        FROM dbstat d
+        JOIN sqlite_schema s ON s.name = d.name
+        GROUP BY s.name
+        ORDER BY bytes DESC, s.name
+        "#,
+    )
+    .fetch_all(pool)
+    .await?
+    .into_iter()
+    .map(|row| TableSize {
+        name: row.get("name"),
+        kind: row.get("type"),
+        bytes: row.get("bytes"),
+    })
+    .collect();
+
+    let path: String = sqlx::query("PRAGMA database_list").fetch_one(pool).await?.get("file");
+    let journal_mode: String = sqlx::query_scalar("PRAGMA journal_mode").fetch_one(pool).await?;
+    let page_size: i64 = sqlx::query_scalar("PRAGMA page_size").fetch_one(pool).await?;
+    let page_count: i64 = sqlx::query_scalar("PRAGMA page_count").fetch_one(pool).await?;
+    let freelist_count: i64 = sqlx::query_scalar("PRAGMA freelist_count").fetch_one(pool).await?;
+    let wal_bytes = tokio::fs::metadata(format!("{}-wal", path))
+        .await
+        .ok()
+        .map(|metadata| metadata.len());
+
+    Ok(DatabaseStatus {
+        migrations,
+        pending,
+        tables,
+        path,
+        journal_mode,
+        file_bytes: page_size * page_count,
+        free_bytes: page_size * freelist_count,
+        wal_bytes,
+    })
+}
+
+// Rebuilds the file without its free pages, and truncates the WAL after
+pub async fn vacuum(pool: &SqlitePool) -> sqlx::Result<()> {
+    sqlx::query("VACUUM").execute(pool).await?;
+    sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)").execute(pool).await?;
+    Ok(())
+}
+
+// Refreshes the statistics the query planner picks indexes with
+pub async fn analyze(pool: &SqlitePool) -> sqlx::Result<()> {
+    sqlx::query("ANALYZE").execute(pool).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_status() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let migrator = migrator().await.unwrap();
+        migrator.run(&pool).await.unwrap();
+
+        let status = status(&pool, &migrator).await.unwrap();
+        assert_eq!(status.pending, 0);
+        assert!(status
+            .migrations
+            .iter()
+            .all(|migration| migration.state == MigrationState::Applied));
+        assert!(status.tables.iter().any(|table| table.name == "chats" && table.kind == "table"));
+
+        // A migration this build doesn't ship, and one that failed halfway
+        sqlx::query(
+            "INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time)
+            VALUES (99990101000000, 'future', 1, x'00', 0)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let first = status.migrations.last().unwrap().version;
+        sqlx::query("UPDATE _sqlx_migrations SET success = 0 WHERE version = ?")
+            .bind(first)
+            .execute(&pool)
+            .await
+            .unwrap();
+        let migrations = migration_status(&pool, &migrator).await.unwrap();
+        assert_eq!(migrations[0].version, 99990101000000);
+        assert_eq!(migrations[0].state, MigrationState::Unknown);
+        assert_eq!(migrations.last().unwrap().state, MigrationState::Failed);
+
+        analyze(&pool).await.unwrap();
+    }
+}
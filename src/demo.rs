@@ -0,0 +1,260 @@
+use serde_json::json;
+
+use crate::data::model::{Agent, FunctionCall, NewToolExecution, Source, ToolCall, UserSettings};
+use crate::data::repository::ChatRepository;
+use crate::utils::password;
+
+// The account `--seed-demo` creates, for local development and screenshots
+pub const DEMO_EMAIL: &str = "demo@example.com";
+pub const DEMO_PASSWORD: &str = "demo-password";
+
+const DEMO_MODEL: &str = "Qwen/Qwen2.5-7B-Instruct";
+
+#[derive(Debug)]
+pub struct DemoSeed {
+    pub user_id: i64,
+    pub agents: usize,
+    pub chats: usize,
+}
+
+// A stored exchange; the response gets the extras the stream would have saved
+struct DemoPair {
+    human: &'static str,
+    ai: &'static str,
+    thinking: Option<&'static str>,
+    tool_calls: Vec<ToolCall>,
+    sources: Vec<Source>,
+    usage: (i64, i64),
+    latency_ms: i64,
+}
+
+impl DemoPair {
+    fn new(human: &'static str, ai: &'static str, usage: (i64, i64)) -> Self {
+        DemoPair {
+            human,
+            ai,
+            thinking: None,
+            tool_calls: Vec::new(),
+            sources: Vec::new(),
+            usage,
+            latency_ms: 1800,
+        }
+    }
+}
+
+// Creates the demo account with provider settings, a couple of agents and a
+// few chats showing off code blocks, tables, tool calls and sources. None
+// when the account already exists, so running it again changes nothing
+pub async fn seed(repo: &ChatRepository) -> Result<Option<DemoSeed>, Box<dyn std::error::Error>> {
+    let hashed = password::hash(DEMO_PASSWORD).map_err(|e| e.to_string())?;
+    let Some(user_id) = repo.create_demo_user(DEMO_EMAIL, &hashed).await? else {
+        return Ok(None);
+    };
+
+    let settings = UserSettings {
+        stored_api_key: String::new(),
+        base_url: "https://api.siliconflow.cn/v1".to_string(),
+        model: DEMO_MODEL.to_string(),
+        system_prompt: "You are a helpful assistant.".to_string(),
+        temperature: 0.7,
+        top_p: 1.0,
+        max_tokens: 2000,
+    };
+    repo.save_settings(user_id, &settings, 0).await?;
+
+    let agents = demo_agents(user_id);
+    for agent in &agents {
+        repo.save_agent(agent).await?;
+    }
+
+    let chats = demo_chats();
+    for (name, tag, pairs) in &chats {
+        let chat_id = repo.create_chat(user_id, name, DEMO_MODEL).await?;
+        repo.add_chat_tag(chat_id, user_id, tag).await?;
+        for pair in pairs {
+            add_pair(repo, user_id, chat_id, pair).await?;
+        }
+    }
+
+    Ok(Some(DemoSeed {
+        user_id,
+        agents: agents.len(),
+        chats: chats.len(),
+    }))
+}
+
+async fn add_pair(
+    repo: &ChatRepository,
+    user_id: i64,
+    chat_id: i64,
+    pair: &DemoPair,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let pair_id = repo.add_message_block(chat_id, pair.human).await?;
+
+    let tool_calls = (!pair.tool_calls.is_empty())
+        .then(|| serde_json::to_string(&pair.tool_calls))
+        .transpose()?;
+    let sources = (!pair.sources.is_empty())
+        .then(|| serde_json::to_string(&pair.sources))
+        .transpose()?;
+    let (prompt_tokens, completion_tokens) = pair.usage;
+    let message_id = repo
+        .add_ai_message_with_extended_data(
+            pair_id,
+            pair.ai,
+            pair.thinking,
+            tool_calls.as_deref(),
+            None,
+            None,
+            Some(prompt_tokens),
+            Some(completion_tokens),
+            Some(prompt_tokens + completion_tokens),
+            sources.as_deref(),
+        )
+        .await?;
+    repo.set_finish_reason(message_id, "stop").await?;
+    repo.set_response_stats(message_id, DEMO_MODEL, pair.latency_ms).await?;
+
+    // So the tool log has something to show too
+    for call in &pair.tool_calls {
+        let execution = NewToolExecution {
+            user_id,
+            chat_id,
+            server: "weather",
+            tool: &call.function.name,
+            arguments: &call.function.arguments,
+            result_bytes: 96,
+            duration_ms: 420,
+            error: None,
+        };
+        repo.record_tool_execution(&execution).await?;
+    }
+
+    Ok(())
+}
+
+fn demo_agents(user_id: i64) -> Vec<Agent> {
+    let agent = |name: &str, system_prompt: &str, temperature: f64, tools_enabled: bool| Agent {
+        id: 0,
+        user_id,
+        org_id: None,
+        name: name.to_string(),
+        model: DEMO_MODEL.to_string(),
+        system_prompt: Some(system_prompt.to_string()),
+        temperature: Some(temperature),
+        top_p: None,
+        max_tokens: None,
+        stop_sequences: None,
+        tools_enabled,
+        allow_tools: None,
+        version: 0,
+    };
+    vec![
+        agent(
+            "Code Reviewer",
+            "You review code. Point out bugs first, then style, and keep it short.",
+            0.2,
+            false,
+        ),
+        agent(
+            "Researcher",
+            "Answer with sources. Use the available tools to look things up.",
+            0.5,
+            true,
+        ),
+    ]
+}
+
+fn demo_chats() -> Vec<(&'static str, &'static str, Vec<DemoPair>)> {
+    let mut result_type = DemoPair::new(
+        "How do I read a file in Rust and handle the error?",
+        r#"Use `std::fs::read_to_string` and propagate the error with `?`:
+
+```rust
+use std::fs;
+use std::io;
+
+fn read_config(path: &str) -> io::Result<String> {
+    let contents = fs::read_to_string(path)?;
+    Ok(contents.trim().to_string())
+}
+
+fn main() {
+    match read_config("config.toml") {
+        Ok(config) => println!("{}", config),
+        Err(e) => eprintln!("Can't read the config: {}", e),
+    }
+}
+```
+
+The `?` returns early with the error, so callers decide how to report it."#,
+        (42, 156),
+    );
+    result_type.thinking = Some("The user wants idiomatic error handling, so show `?` with `io::Result`.");
+
+    let options = DemoPair::new(
+        "Should I use `unwrap`, `expect` or `?`?",
+        r#"| Option | When | On error |
+|---|---|---|
+| `unwrap()` | Tests and prototypes | Panics with a generic message |
+| `expect("...")` | Invariants that can't fail | Panics with your message |
+| `?` | Library and application code | Returns the error to the caller |
+
+Prefer `?` almost everywhere; reach for `expect` when a failure means a bug."#,
+        (230, 98),
+    );
+
+    let mut weather = DemoPair::new(
+        "What's the weather like in Paris right now?",
+        "It's **18°C and partly cloudy** in Paris, with a light wind from the west. \
+         Rain is expected later in the evening, so take an umbrella if you're going out.",
+        (310, 64),
+    );
+    weather.tool_calls = vec![ToolCall {
+        id: "call_demo_weather".to_string(),
+        r#type: "function".to_string(),
+        function: FunctionCall {
+            name: "get_weather".to_string(),
+            arguments: json!({ "city": "Paris", "units": "metric" }).to_string(),
+        },
+    }];
+    weather.sources = vec![Source {
+        title: Some("Paris weather forecast".to_string()),
+        url: Some("https://example.com/weather/paris".to_string()),
+        snippet: Some("Partly cloudy, 18°C. Showers likely after 8 pm.".to_string()),
+    }];
+    weather.latency_ms = 2600;
+
+    let mut databases = DemoPair::new(
+        "Compare SQLite and PostgreSQL for a small web app.",
+        r#"Both work well; the choice mostly comes down to how you deploy.
+
+| | SQLite | PostgreSQL |
+|---|---|---|
+| Setup | A single file | A server to run |
+| Concurrent writes | One at a time | Many |
+| Backups | Copy the file | `pg_dump` or replication |
+| Best for | One server, modest traffic | Several app servers |
+
+Start with SQLite and move when you need more than one app server writing at once."#,
+        (58, 210),
+    );
+    databases.sources = vec![
+        Source {
+            title: Some("Appropriate Uses For SQLite".to_string()),
+            url: Some("https://www.sqlite.org/whentouse.html".to_string()),
+            snippet: Some("SQLite works great as the database engine for most low to medium traffic websites.".to_string()),
+        },
+        Source {
+            title: Some("PostgreSQL: About".to_string()),
+            url: Some("https://www.postgresql.org/about/".to_string()),
+            snippet: None,
+        },
+    ];
+
+    vec![
+        ("Error handling in Rust", "rust", vec![result_type, options]),
+        ("Weather in Paris", "tools", vec![weather]),
+        ("SQLite or PostgreSQL?", "databases", vec![databases]),
+    ]
+}
@@ -0,0 +1,363 @@
+use aes_gcm::{
+    aead::{Aead, AeadCore, OsRng},
+    Aes256Gcm, Key, KeyInit, Nonce,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::data::repository::ChatRepository;
+use crate::mcp::McpServerConfig;
+
+// Marks stored values as encrypted; anything else is plaintext from before
+// encryption, which `main` encrypts at startup. Matched by
+// `ChatRepository::plaintext_api_keys`. Values are `enc:v2:<key version>:<sealed>`;
+// `enc:v1:<sealed>` ones predate key versions and were sealed with key 1
+const PREFIX: &str = "enc:v2:";
+const LEGACY_PREFIX: &str = "enc:v1:";
+const LEGACY_VERSION: u32 = 1;
+const NONCE_LEN: usize = 12;
+
+#[derive(Debug, thiserror::Error)]
+pub enum EncryptionError {
+    #[error("The master key must be 32 bytes, base64 encoded")]
+    InvalidKey,
+
+    #[error("Failed to read or create the key file: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("The stored secret can't be decrypted with this master key")]
+    Decrypt,
+
+    #[error("The stored secret was encrypted with master key {0}, which isn't configured")]
+    UnknownKey(u32),
+}
+
+// Encrypts secrets users store, such as their provider API keys, with
+// AES-256-GCM under versioned master keys. New secrets use the newest key;
+// older keys stay around to decrypt what they sealed until
+// `reencrypt_secrets` has moved it to the newest
+#[derive(Clone)]
+pub struct Encryption {
+    ciphers: BTreeMap<u32, Aes256Gcm>,
+}
+
+impl Encryption {
+    pub fn new(key: &[u8; 32]) -> Self {
+        Self::with_keys(&[(LEGACY_VERSION, *key)])
+    }
+
+    // `keys` must not be empty
+    pub fn with_keys(keys: &[(u32, [u8; 32])]) -> Self {
+        assert!(!keys.is_empty(), "no master key");
+        Encryption {
+            ciphers: keys
+                .iter()
+                .map(|(version, key)| (*version, Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key))))
+                .collect(),
+        }
+    }
+
+    // The master keys from `ENCRYPTION_KEY`, or else the key file at
+    // `ENCRYPTION_KEY_FILE`, by default `master.key` beside the database. A
+    // missing key file is created with a new key. Either holds one key per
+    // line or comma separated entry, see `parse_keys`
+    pub fn from_env(database_path: &str) -> Result<Self, EncryptionError> {
+        if let Ok(keys) = dotenv::var("ENCRYPTION_KEY") {
+            return Ok(Self::with_keys(&parse_keys(&keys)?));
+        }
+        let path = dotenv::var("ENCRYPTION_KEY_FILE")
+            .map(PathBuf::from)
+            .unwrap_or_else(|_| default_key_file(database_path));
+        if !path.exists() {
+            let key = Aes256Gcm::generate_key(OsRng);
+            write_key_file(&path, &STANDARD.encode(key))?;
+            tracing::info!("Created a new master key at {}", path.display());
+        }
+        Ok(Self::with_keys(&parse_keys(&std::fs::read_to_string(&path)?)?))
+    }
+
+    pub fn current_version(&self) -> u32 {
+        *self.ciphers.keys().next_back().expect("no master key")
+    }
+
+    pub fn encrypt(&self, plaintext: &str) -> String {
+        let version = self.current_version();
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        // Only fails for inputs of many gigabytes
+        let ciphertext = self.ciphers[&version]
+            .encrypt(&nonce, plaintext.as_bytes())
+            .expect("secret too large to encrypt");
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        format!("{}{}:{}", PREFIX, version, STANDARD.encode(sealed))
+    }
+
+    // Values stored before encryption are passed through as they are
+    pub fn decrypt(&self, stored: &str) -> Result<String, EncryptionError> {
+        let Some((version, sealed)) = split_sealed(stored)? else {
+            return Ok(stored.to_string());
+        };
+        let cipher = self.ciphers.get(&version).ok_or(EncryptionError::UnknownKey(version))?;
+        let sealed = STANDARD.decode(sealed).map_err(|_| EncryptionError::Decrypt)?;
+        if sealed.len() < NONCE_LEN {
+            return Err(EncryptionError::Decrypt);
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let plaintext = cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| EncryptionError::Decrypt)?;
+        String::from_utf8(plaintext).map_err(|_| EncryptionError::Decrypt)
+    }
+
+    // The value sealed again under the newest key, or `None` when it
+    // already is
+    pub fn reencrypt(&self, stored: &str) -> Result<Option<String>, EncryptionError> {
+        if split_sealed(stored)?.is_some_and(|(version, _)| version == self.current_version()) {
+            return Ok(None);
+        }
+        Ok(Some(self.encrypt(&self.decrypt(stored)?)))
+    }
+}
+
+pub fn is_encrypted(stored: &str) -> bool {
+    stored.starts_with(PREFIX) || stored.starts_with(LEGACY_PREFIX)
+}
+
+// The key version and sealed part of an encrypted value, `None` for plaintext
+fn split_sealed(stored: &str) -> Result<Option<(u32, &str)>, EncryptionError> {
+    if let Some(sealed) = stored.strip_prefix(LEGACY_PREFIX) {
+        return Ok(Some((LEGACY_VERSION, sealed)));
+    }
+    let Some(rest) = stored.strip_prefix(PREFIX) else {
+        return Ok(None);
+    };
+    let (version, sealed) = rest.split_once(':').ok_or(EncryptionError::Decrypt)?;
+    let version = version.parse().map_err(|_| EncryptionError::Decrypt)?;
+    Ok(Some((version, sealed)))
+}
+
+// Keys are `<version>:<base64 of 32 bytes>`, one per line or comma separated.
+// A key without a version is key 1, so a key file from before rotation still
+// reads. To rotate, add a key with a higher version, restart and run
+// `--reencrypt-secrets`; the old key can go once nothing uses it
+fn parse_keys(text: &str) -> Result<Vec<(u32, [u8; 32])>, EncryptionError> {
+    let mut keys: Vec<(u32, [u8; 32])> = Vec::new();
+    for entry in text.split([',', '\n']).map(str::trim).filter(|entry| !entry.is_empty()) {
+        let (version, key) = match entry.split_once(':') {
+            Some((version, key)) => (version.trim().parse().map_err(|_| EncryptionError::InvalidKey)?, key),
+            None => (LEGACY_VERSION, entry),
+        };
+        if keys.iter().any(|(seen, _)| *seen == version) {
+            return Err(EncryptionError::InvalidKey);
+        }
+        keys.push((version, parse_key(key)?));
+    }
+    if keys.is_empty() {
+        return Err(EncryptionError::InvalidKey);
+    }
+    Ok(keys)
+}
+
+fn parse_key(encoded: &str) -> Result<[u8; 32], EncryptionError> {
+    let key = STANDARD.decode(encoded.trim()).map_err(|_| EncryptionError::InvalidKey)?;
+    key.try_into().map_err(|_| EncryptionError::InvalidKey)
+}
+
+#[derive(Debug, Default)]
+pub struct RotationReport {
+    pub rotated: usize,
+    pub current: usize,
+    // Secrets no configured key opens, left as they are. Their owners are
+    // asked to enter them again
+    pub failed: Vec<String>,
+}
+
+// Seals every stored secret under the newest master key
+pub async fn reencrypt_secrets(repo: &ChatRepository, encryption: &Encryption) -> sqlx::Result<RotationReport> {
+    let mut report = RotationReport::default();
+
+    for (user_id, stored) in repo.encrypted_api_keys().await? {
+        match encryption.reencrypt(&stored) {
+            Ok(Some(sealed)) => {
+                repo.set_stored_api_key(user_id, &sealed).await?;
+                report.rotated += 1;
+            }
+            Ok(None) => report.current += 1,
+            Err(e) => report.failed.push(format!("API key of user {}: {}", user_id, e)),
+        }
+    }
+    for (org_id, stored) in repo.encrypted_org_api_keys().await? {
+        match encryption.reencrypt(&stored) {
+            Ok(Some(sealed)) => {
+                repo.set_org_api_key(org_id, Some(&sealed)).await?;
+                report.rotated += 1;
+            }
+            Ok(None) => report.current += 1,
+            Err(e) => report.failed.push(format!("API key of org {}: {}", org_id, e)),
+        }
+    }
+
+    for (tool_id, stored) in repo.custom_tool_auth_headers().await? {
+        match encryption.reencrypt(&stored) {
+            Ok(Some(sealed)) => {
+                repo.set_custom_tool_auth_header(tool_id, &sealed).await?;
+                report.rotated += 1;
+            }
+            Ok(None) => report.current += 1,
+            Err(e) => report.failed.push(format!("Auth header of custom tool {}: {}", tool_id, e)),
+        }
+    }
+    for server in repo.all_mcp_servers().await? {
+        let Ok(config) = serde_json::from_str::<McpServerConfig>(&server.config) else {
+            continue;
+        };
+        let resealed = config.resealed(encryption);
+        let oauth = server.oauth.as_deref().map(|oauth| encryption.reencrypt(oauth)).transpose();
+        match (resealed, oauth) {
+            (Ok(resealed), Ok(oauth)) => {
+                let oauth = oauth.flatten();
+                if resealed == config && oauth.is_none() {
+                    report.current += 1;
+                    continue;
+                }
+                let stored = serde_json::to_string(&resealed).expect("MCP server config serializes");
+                let oauth = oauth.as_deref().or(server.oauth.as_deref());
+                repo.set_mcp_server_secrets(server.id, &stored, oauth).await?;
+                report.rotated += 1;
+            }
+            (Err(e), _) | (_, Err(e)) => {
+                report.failed.push(format!("Secrets of MCP server {} ({}): {}", server.id, server.name, e))
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+// Seals secrets saved before they were encrypted, or before encryption
+// covered them: user API keys, custom tool auth headers, and the header and
+// env values and OAuth tokens of users' MCP servers. Runs at startup
+pub async fn encrypt_plaintext_secrets(repo: &ChatRepository, encryption: &Encryption) -> sqlx::Result<usize> {
+    let mut sealed = 0;
+
+    for (user_id, key) in repo.plaintext_api_keys().await? {
+        repo.set_stored_api_key(user_id, &encryption.encrypt(&key)).await?;
+        sealed += 1;
+    }
+    for (tool_id, stored) in repo.custom_tool_auth_headers().await? {
+        if !is_encrypted(&stored) {
+            repo.set_custom_tool_auth_header(tool_id, &encryption.encrypt(&stored)).await?;
+            sealed += 1;
+        }
+    }
+    for server in repo.all_mcp_servers().await? {
+        let Ok(config) = serde_json::from_str::<McpServerConfig>(&server.config) else {
+            tracing::warn!("Can't read the config of MCP server {} to encrypt it", server.id);
+            continue;
+        };
+        let config_sealed = config.sealed(encryption);
+        let oauth = server.oauth.as_deref();
+        let oauth_sealed = oauth.map(|oauth| {
+            if is_encrypted(oauth) {
+                oauth.to_string()
+            } else {
+                encryption.encrypt(oauth)
+            }
+        });
+        if config_sealed != config || oauth_sealed.as_deref() != oauth {
+            let stored = serde_json::to_string(&config_sealed).expect("MCP server config serializes");
+            repo.set_mcp_server_secrets(server.id, &stored, oauth_sealed.as_deref()).await?;
+            sealed += 1;
+        }
+    }
+
+    Ok(sealed)
+}
+
+fn default_key_file(database_path: &str) -> PathBuf {
+    Path::new(database_path).with_file_name("master.key")
+}
+
+// Readable by the owner only
+fn write_key_file(path: &Path, key: &str) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(key.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let encryption = Encryption::new(&[7; 32]);
+        let stored = encryption.encrypt("sk-secret");
+        assert!(stored.starts_with(PREFIX));
+        assert!(!stored.contains("sk-secret"));
+        // A new nonce every time
+        assert_ne!(stored, encryption.encrypt("sk-secret"));
+        assert_eq!(encryption.decrypt(&stored).unwrap(), "sk-secret");
+
+        // Rows from before encryption pass through
+        assert_eq!(encryption.decrypt("sk-plain").unwrap(), "sk-plain");
+
+        let other = Encryption::new(&[8; 32]);
+        assert!(matches!(other.decrypt(&stored), Err(EncryptionError::Decrypt)));
+        assert!(matches!(encryption.decrypt("enc:v2:1:AAAA"), Err(EncryptionError::Decrypt)));
+        assert!(matches!(encryption.decrypt("enc:v2:AAAA"), Err(EncryptionError::Decrypt)));
+    }
+
+    #[test]
+    fn test_rotation() {
+        let old = Encryption::new(&[7; 32]);
+        let stored = old.encrypt("sk-secret");
+        assert!(stored.starts_with("enc:v2:1:"));
+        // Sealed before key versions, with what is now key 1
+        let legacy = format!("{}{}", LEGACY_PREFIX, stored.strip_prefix("enc:v2:1:").unwrap());
+
+        let rotated = Encryption::with_keys(&[(1, [7; 32]), (2, [8; 32])]);
+        assert_eq!(rotated.current_version(), 2);
+        assert_eq!(rotated.decrypt(&stored).unwrap(), "sk-secret");
+        assert_eq!(rotated.decrypt(&legacy).unwrap(), "sk-secret");
+        assert!(rotated.encrypt("sk-new").starts_with("enc:v2:2:"));
+
+        let resealed = rotated.reencrypt(&legacy).unwrap().unwrap();
+        assert!(resealed.starts_with("enc:v2:2:"));
+        assert_eq!(rotated.reencrypt(&resealed).unwrap(), None);
+        assert!(rotated.reencrypt("sk-plain").unwrap().unwrap().starts_with("enc:v2:2:"));
+
+        // Once key 1 is dropped, only what was re-encrypted still opens
+        let new = Encryption::with_keys(&[(2, [8; 32])]);
+        assert_eq!(new.decrypt(&resealed).unwrap(), "sk-secret");
+        assert!(matches!(new.decrypt(&stored), Err(EncryptionError::UnknownKey(1))));
+        assert!(matches!(new.reencrypt(&legacy), Err(EncryptionError::UnknownKey(1))));
+    }
+
+    #[test]
+    fn test_parse_keys() {
+        let (one, two) = (STANDARD.encode([1; 32]), STANDARD.encode([2; 32]));
+        assert_eq!(parse_keys(&format!("{}\n", one)).unwrap(), vec![(1, [1; 32])]);
+        assert_eq!(
+            parse_keys(&format!("{}\n3:{}\n", one, two)).unwrap(),
+            vec![(1, [1; 32]), (3, [2; 32])]
+        );
+        assert_eq!(parse_keys(&format!("2:{}, 1:{}", two, one)).unwrap().len(), 2);
+        assert!(matches!(parse_keys(&format!("1:{},{}", one, two)), Err(EncryptionError::InvalidKey)));
+        assert!(matches!(parse_keys(&format!("x:{}", one)), Err(EncryptionError::InvalidKey)));
+        assert!(matches!(parse_keys(" \n"), Err(EncryptionError::InvalidKey)));
+    }
+
+    #[test]
+    fn test_parse_key() {
+        assert_eq!(parse_key(&STANDARD.encode([1; 32])).unwrap(), [1; 32]);
+        assert!(matches!(parse_key("c2hvcnQ="), Err(EncryptionError::InvalidKey)));
+        assert_eq!(default_key_file("db/db.db"), Path::new("db/master.key"));
+    }
+}
@@ -1,26 +1,46 @@
 use axum::{http::StatusCode, Router};
 use serde::Serialize;
 use sqlx::{
-    migrate::Migrator,
     sqlite::{SqliteConnectOptions, SqlitePoolOptions},
     types::chrono::NaiveDateTime,
-    Pool, Sqlite,
+    ConnectOptions, Pool, Sqlite,
 };
 use tera::Tera;
 use tower_cookies::CookieManagerLayer;
 use tower_http::services::ServeDir;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use tracing_subscriber::{filter::filter_fn, layer::SubscriberExt, util::SubscriberInitExt, Layer};
 
 mod router;
-use router::app_router;
-use std::{net::SocketAddr, path::Path, sync::Arc, time::Duration};
+use router::{api_router, app_router, run_guest_sweep, run_scheduled_messages, run_trash_sweep};
+use std::{
+    net::SocketAddr,
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
 mod ai;
+mod audit;
+mod backup;
+mod csrf;
+mod data_export;
+mod database;
+mod demo;
+mod encryption;
+mod mailer;
 mod middleware;
-use middleware::extract_user;
+mod oauth;
+mod quota;
+mod rate_limit;
+mod retention;
+mod session;
+use middleware::{csrf, extract_user, maintenance, trace_request};
 mod data;
 mod mcp;
+mod metrics;
 mod utils;
+mod webhooks;
+use ai::fanout::{ChatRooms, GenerationHub};
 use data::repository::ChatRepository;
+use rate_limit::RateLimits;
 
 use crate::middleware::handle_error;
 
@@ -29,85 +49,206 @@ struct AppState {
     pool: Arc<Pool<Sqlite>>,
     tera: Tera,
     chat_repo: ChatRepository,
+    generation_hub: GenerationHub,
+    chat_rooms: ChatRooms,
+    mailer: Arc<dyn mailer::Mailer>,
+    rate_limits: RateLimits,
+    encryption: encryption::Encryption,
+    // Set while a backup is restored, see `middleware::maintenance`
+    maintenance: Arc<AtomicBool>,
+    // Served at /metrics, see `metrics::MetricsLayer`
+    db_metrics: Arc<metrics::DbMetrics>,
 }
 
 #[tokio::main]
 async fn main() {
+    // Each layer filters on its own, so the metrics see sqlx's events
+    // whatever RUST_LOG lets through to the log
+    let db_metrics = Arc::new(metrics::DbMetrics::default());
+    let slow_query = metrics::slow_query_threshold();
     tracing_subscriber::registry()
-        .with(
+        .with(tracing_subscriber::fmt::layer().with_filter(
             tracing_subscriber::EnvFilter::try_from_default_env()
-                .unwrap_or_else(|_| "example_tokio_postgres=debug".into()),
+                .unwrap_or_else(|_| "rustgpt=info".into()),
+        ))
+        .with(
+            metrics::MetricsLayer::new(db_metrics.clone(), slow_query)
+                .with_filter(filter_fn(metrics::MetricsLayer::wants)),
         )
-        .with(tracing_subscriber::fmt::layer())
         .init();
 
+    // Before any connection opens, see `data::vector`
+    data::vector::register();
+
     let db_path = dotenv::var("DATABASE_PATH").unwrap();
     let options = SqliteConnectOptions::new()
-        .filename(db_path)
+        .filename(&db_path)
         .journal_mode(sqlx::sqlite::SqliteJournalMode::Wal)
-        .create_if_missing(true);
+        .create_if_missing(true)
+        .log_slow_statements(log::LevelFilter::Warn, slow_query);
 
     // setup connection pool
     let pool = SqlitePoolOptions::new()
         .max_connections(5)
         .acquire_timeout(Duration::from_secs(3))
+        // Every wait for a connection, for `metrics::DbMetrics`
+        .acquire_time_level(log::LevelFilter::Trace)
         .connect_with(options)
         .await
         .expect("can't connect to database");
 
     // Create a new instance of `Migrator` pointing to the migrations folder.
-    let migrator = Migrator::new(Path::new(dotenv::var("MIGRATIONS_PATH").unwrap().as_str()))
-        .await
-        .unwrap();
+    let migrator = database::migrator().await.unwrap();
     // Run the migrations.
     migrator.run(&pool).await.unwrap();
 
     let pool = Arc::new(pool);
 
-    // Store a reference to the pool in a global static for access from save_tool_call_confirmation
-    unsafe {
-        DB_POOL = Some(Arc::as_ptr(&pool) as *const sqlx::Pool<sqlx::Sqlite>);
+    let chat_repo = ChatRepository::new(pool.clone());
+    let encryption = encryption::Encryption::from_env(&db_path).expect("can't load the master key");
+
+    // Maintenance job: seal saved secrets under the newest master key after
+    // one was added, see `encryption::parse_keys`
+    if std::env::args().any(|a| a == "--reencrypt-secrets") {
+        let report = encryption::reencrypt_secrets(&chat_repo, &encryption)
+            .await
+            .expect("can't re-encrypt secrets");
+        for failure in &report.failed {
+            println!("{}", failure);
+        }
+        println!(
+            "Re-encrypted {} secrets under key {}, {} already were, {} can't be decrypted",
+            report.rotated,
+            encryption.current_version(),
+            report.current,
+            report.failed.len()
+        );
+        std::process::exit(if report.failed.is_empty() { 0 } else { 1 });
+    }
+
+    // Local development: fill a fresh database with a demo account to log in
+    // with, then start as usual
+    if std::env::args().any(|a| a == "--seed-demo") {
+        match demo::seed(&chat_repo).await.expect("can't seed the demo data") {
+            Some(seed) => println!(
+                "Created the demo account {} / {} (user {}) with {} agents and {} chats",
+                demo::DEMO_EMAIL,
+                demo::DEMO_PASSWORD,
+                seed.user_id,
+                seed.agents,
+                seed.chats
+            ),
+            None => println!("The demo account {} already exists", demo::DEMO_EMAIL),
+        }
     }
 
-    let chat_repo = ChatRepository { pool: pool.clone() };
+    // Maintenance job: re-render a sample of stored AI messages and report breakages
+    if let Some(arg) = std::env::args().find(|a| a.starts_with("--rerender-check")) {
+        let sample = arg
+            .strip_prefix("--rerender-check=")
+            .and_then(|n| n.parse::<i64>().ok())
+            .unwrap_or(200);
+        let messages = chat_repo
+            .sample_ai_messages(sample)
+            .await
+            .expect("can't load stored messages");
+
+        let report = utils::rerender::rerender_messages(&messages);
+        for issue in &report.issues {
+            println!(
+                "message {} (chat {}): {:?}",
+                issue.message_id, issue.chat_id, issue.kind
+            );
+        }
+        println!(
+            "Re-rendered {} messages, {} with issues",
+            report.checked,
+            report.issues.len()
+        );
+        std::process::exit(if report.issues.is_empty() { 0 } else { 1 });
+    }
 
     let static_files = ServeDir::new("assets");
     let uploads_files = ServeDir::new("uploads");
 
-    let tera = match Tera::new("templates/**/*") {
+    let mut tera = match Tera::new("templates/**/*") {
         Ok(t) => t,
         Err(e) => {
             println!("Parsing error(s): {}", e);
             ::std::process::exit(1);
         }
     };
+    tera.register_function("csrf_token", csrf::tera_csrf_token);
 
     // Initialize MCP manager
     let mcp_manager = mcp::get_mcp_manager();
     let mcp_config_path = std::path::PathBuf::from("mcp.json");
 
     if let Err(e) = mcp_manager.load_config(&mcp_config_path).await {
-        println!("Warning: Could not load mcp.json: {}", e);
-        println!("Using default MCP configuration. Copy mcp.json.example to mcp.json to configure MCP servers.");
+        tracing::warn!(
+            "Could not load mcp.json, so no global MCP servers are configured. Copy mcp.json.example to mcp.json to add some: {}",
+            e
+        );
     }
 
     // Initialize enabled MCP servers
     match mcp_manager.initialize_servers().await {
-        Ok(count) => {
-            println!("Successfully initialized {} MCP servers", count);
-        }
-        Err(e) => {
-            println!("Warning: Failed to initialize some MCP servers: {}", e);
-        }
+        Ok(count) => tracing::info!("Started {} MCP servers", count),
+        Err(e) => tracing::warn!("Failed to start some MCP servers: {}", e),
+    }
+    mcp::health::supervise(&mcp_manager);
+    if let Err(e) = mcp::watch::watch_config(mcp_manager.clone(), mcp_config_path.clone()) {
+        tracing::warn!("Changes to mcp.json won't be applied until restart: {}", e);
     }
 
     let state = AppState {
         pool,
         tera,
         chat_repo,
+        generation_hub: GenerationHub::default(),
+        chat_rooms: ChatRooms::default(),
+        mailer: mailer::from_env(),
+        rate_limits: RateLimits::from_env(),
+        encryption,
+        maintenance: Arc::new(AtomicBool::new(false)),
+        db_metrics,
     };
     let shared_app_state = Arc::new(state);
 
+    // Accounts listed in ADMIN_EMAILS (comma separated) get the admin role
+    let admin_emails: Vec<String> = dotenv::var("ADMIN_EMAILS")
+        .unwrap_or_default()
+        .split(',')
+        .map(|email| email.trim().to_string())
+        .filter(|email| !email.is_empty())
+        .collect();
+    if let Err(e) = shared_app_state.chat_repo.grant_admin(&admin_emails).await {
+        tracing::error!("Failed to grant the admin role: {}", e);
+    }
+
+    // Secrets saved before they were encrypted
+    match encryption::encrypt_plaintext_secrets(&shared_app_state.chat_repo, &shared_app_state.encryption).await {
+        Ok(0) => {}
+        Ok(sealed) => tracing::info!("Encrypted {} stored secrets", sealed),
+        Err(e) => tracing::error!("Failed to encrypt stored secrets: {}", e),
+    }
+
+    if let Err(e) = shared_app_state.chat_repo.fail_interrupted_data_exports().await {
+        tracing::error!("Failed to mark interrupted data exports: {}", e);
+    }
+
+    // Send scheduled prompts in the background
+    tokio::spawn(run_scheduled_messages(shared_app_state.clone()));
+    tokio::spawn(run_trash_sweep(shared_app_state.clone()));
+    tokio::spawn(run_guest_sweep(shared_app_state.clone()));
+    tokio::spawn(webhooks::run_deliveries(shared_app_state.chat_repo.clone()));
+    if let Some(config) = ai::embeddings::EmbeddingConfig::from_env() {
+        tokio::spawn(ai::embeddings::run_indexer(shared_app_state.chat_repo.clone(), config));
+    }
+    if let Some(policy) = retention::RetentionPolicy::from_env() {
+        tokio::spawn(retention::run_retention_sweep(shared_app_state.chat_repo.clone(), policy));
+    }
+
     // let jdoom = axum::middleware::from_fn_with_state(shared_app_state.clone(), auth);
 
     // build our application with some routes
@@ -124,11 +265,18 @@ async fn main() {
             shared_app_state.clone(),
             handle_error,
         ))
+        .merge(api_router(shared_app_state.clone()))
+        .layer(axum::middleware::from_fn(csrf))
         .layer(axum::middleware::from_fn_with_state(
             shared_app_state.clone(),
             extract_user,
         ))
-        .layer(CookieManagerLayer::new());
+        .layer(axum::middleware::from_fn_with_state(
+            shared_app_state.clone(),
+            maintenance,
+        ))
+        .layer(CookieManagerLayer::new())
+        .layer(axum::middleware::from_fn(trace_request));
 
     // run it with hyper
     let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
@@ -141,32 +289,43 @@ async fn main() {
             .await
             .expect("failed to install Ctrl+C handler");
 
-        println!("Shutting down MCP servers...");
+        tracing::info!("Shutting down MCP servers");
         mcp_manager.shutdown_all().await;
-        println!("Shutdown complete.");
+        mcp::users::shutdown_user_servers().await;
+        tracing::info!("Shutdown complete");
     };
 
-    axum::serve(listener, app)
+    // The peer address is recorded with login sessions
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
         .with_graceful_shutdown(shutdown_signal)
         .await
         .unwrap();
 }
 
-// Global function to access the database pool
-static mut DB_POOL: Option<*const sqlx::Pool<sqlx::Sqlite>> = None;
-
-pub fn get_db_pool() -> &'static sqlx::Pool<sqlx::Sqlite> {
-    unsafe {
-        DB_POOL.unwrap().as_ref().unwrap()
-    }
-}
-
 #[derive(Debug, sqlx::FromRow, Serialize, Clone)]
 pub struct User {
     id: i64,
     email: String,
+    // Never rendered into templates
+    #[serde(skip_serializing)]
     password: String,
     created_at: NaiveDateTime,
+    // `None` until the email address is confirmed
+    verified_at: Option<NaiveDateTime>,
+    is_admin: bool,
+    // Name shown instead of the email, and the URL of the avatar image, see
+    // `router::app::settings::profile`
+    display_name: Option<String>,
+    avatar: Option<String>,
+    // Email of the admin impersonating the user in this session, see
+    // `router::app::admin`
+    impersonator: Option<String>,
+    // The org workspace picked in the navbar, `None` for the personal one.
+    // Only set while the user is a member, see `router::app::orgs`
+    current_org_id: Option<i64>,
+    // When a guest trial account is removed, `None` for regular accounts.
+    // See `router::app::guest`
+    guest_expires_at: Option<NaiveDateTime>,
     openai_api_key: Option<String>,
     base_url: Option<String>,
     model: Option<String>,
@@ -176,6 +335,13 @@ pub struct User {
     max_tokens: Option<i64>,
 }
 
+impl User {
+    // The display name, or the email without one
+    pub fn name(&self) -> &str {
+        self.display_name.as_deref().unwrap_or(&self.email)
+    }
+}
+
 /// Utility function for mapping any error into a `500 Internal Server Error`
 /// response.
 fn internal_error<E>(err: E) -> (StatusCode, String)
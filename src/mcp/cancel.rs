@@ -0,0 +1,64 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{LazyLock, Mutex};
+use tokio_util::sync::CancellationToken;
+
+type Running = Mutex<HashMap<(i64, String), CancellationToken>>;
+
+// Tool calls running in a chat, by chat and execution id, that the chat's
+// members can cancel. The id is minted per run, as the ids models give tool
+// calls can repeat
+static RUNNING: LazyLock<Running> = LazyLock::new(Running::default);
+
+// Forgets the call however its run ends
+struct Registered(i64, String);
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        RUNNING.lock().unwrap().remove(&(self.0, std::mem::take(&mut self.1)));
+    }
+}
+
+// Runs a tool call until it's done or cancelled, `None` if it was. A
+// cancelled call is dropped, which abandons its request to the server
+pub async fn cancellable<F: Future>(chat_id: i64, execution_id: &str, run: F) -> Option<F::Output> {
+    let token = CancellationToken::new();
+    RUNNING
+        .lock()
+        .unwrap()
+        .insert((chat_id, execution_id.to_string()), token.clone());
+    let _registered = Registered(chat_id, execution_id.to_string());
+    tokio::select! {
+        output = run => Some(output),
+        _ = token.cancelled() => None,
+    }
+}
+
+// Whether the call was still running
+pub fn cancel(chat_id: i64, execution_id: &str) -> bool {
+    match RUNNING.lock().unwrap().remove(&(chat_id, execution_id.to_string())) {
+        Some(token) => {
+            token.cancel();
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_cancel_tool_call() {
+        let run = tokio::spawn(cancellable(1, "call_1", std::future::pending::<()>()));
+        tokio::task::yield_now().await;
+        assert!(!cancel(2, "call_1"));
+        assert!(cancel(1, "call_1"));
+        assert_eq!(run.await.unwrap(), None);
+        assert!(!cancel(1, "call_1"));
+
+        assert_eq!(cancellable(1, "call_2", async { 42 }).await, Some(42));
+        assert!(!cancel(1, "call_2"));
+    }
+}
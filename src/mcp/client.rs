@@ -1,15 +1,13 @@
 use async_trait::async_trait;
-use serde_json::Value;
-use std::collections::HashMap;
-use std::process::Stdio;
-use std::sync::{Arc, Mutex};
+use serde_json::{json, Value};
 use std::time::Duration;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::process::{Child, Command};
-use tokio::sync::Mutex as TokioMutex;
-use tokio::time::timeout;
 
 use super::config::{McpServerConfig, TransportType};
+use super::http::HttpTransport;
+use super::sampling;
+use super::sse::SseTransport;
+use super::stdio::StdioTransport;
+use crate::utils::outbound;
 
 #[derive(Debug, Clone)]
 pub struct McpConnectionInfo {
@@ -18,19 +16,39 @@ pub struct McpConnectionInfo {
     pub server_info: Option<Value>,
 }
 
-// Internal state for the MCP client
-struct RmcpClientState {
-    child: Child,
-    stdin: tokio::process::ChildStdin,
-    stdout: tokio::process::ChildStdout,
-    request_id: i64,
+// How long requests to a server wait when its config sets no timeout
+const DEFAULT_REQUEST_TIMEOUT: u64 = 60;
+
+// How long a health check waits for the server to answer
+const PING_TIMEOUT: Duration = Duration::from_secs(10);
+
+// The MCP revision this client speaks
+pub const PROTOCOL_VERSION: &str = "2024-11-05";
+
+// How a client reaches its server: carries JSON-RPC messages, answering
+// the server's own requests on the way. The protocol on top is the client's
+#[async_trait]
+pub trait McpTransport: Send + Sync {
+    // Sends a request and waits for the response to it
+    async fn request(&self, method: &str, params: Value, timeout: Duration) -> Result<Value, McpClientError>;
+
+    async fn notify(&self, method: &str, params: Value) -> Result<(), McpClientError>;
+
+    // Why the server is gone, for transports that can tell without asking it
+    async fn exited(&self) -> Option<String> {
+        None
+    }
+
+    async fn close(&self) -> Result<(), McpClientError>;
 }
 
-// MCP client using direct JSON-RPC communication with interior mutability
-#[derive(Clone)]
-pub struct RmcpClient {
+// MCP client for a server run as a child process or reached over HTTP or SSE
+pub struct McpClient {
     name: String,
-    state: Arc<TokioMutex<RmcpClientState>>,
+    transport_type: TransportType,
+    transport: Box<dyn McpTransport>,
+    timeout: Duration,
+    server_info: Option<Value>,
 }
 
 // Result types compatible with our interface
@@ -86,6 +104,27 @@ pub struct ReadResourceResult {
     pub contents: Vec<Value>,
 }
 
+impl ReadResourceResult {
+    // The text parts joined; binary (blob) parts are left out
+    pub fn text(&self) -> Option<String> {
+        let parts: Vec<&str> = self
+            .contents
+            .iter()
+            .filter_map(|content| content.get("text")?.as_str())
+            .collect();
+        (!parts.is_empty()).then(|| parts.join("\n\n"))
+    }
+
+    // The type of the first text part
+    pub fn mime_type(&self) -> Option<&str> {
+        self.contents
+            .iter()
+            .find(|content| content.get("text").is_some())?
+            .get("mimeType")?
+            .as_str()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ListPromptsResult {
     pub prompts: Vec<Value>,
@@ -98,6 +137,24 @@ pub struct GetPromptResult {
     pub messages: Vec<Value>,
 }
 
+impl GetPromptResult {
+    // The text of the messages, to send as one chat message. Embedded
+    // resources contribute their text; images are left out
+    pub fn text(&self) -> String {
+        self.messages
+            .iter()
+            .filter_map(|message| {
+                let content = message.get("content")?;
+                content
+                    .get("text")
+                    .or_else(|| content.get("resource")?.get("text"))?
+                    .as_str()
+            })
+            .collect::<Vec<_>>()
+            .join("\n\n")
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ServerInfo {
     pub name: String,
@@ -128,6 +185,10 @@ pub trait McpClientTrait: Send + Sync {
         arguments: Option<Value>,
     ) -> Result<GetPromptResult, McpClientError>;
     fn get_connection_info(&self) -> McpConnectionInfo;
+    // Whether the server is still there to answer
+    async fn ping(&self) -> Result<(), McpClientError>;
+    // Tells the server to ask for its roots again
+    async fn roots_changed(&self) -> Result<(), McpClientError>;
     async fn shutdown(&mut self) -> Result<(), McpClientError>;
 }
 
@@ -141,6 +202,8 @@ pub enum McpClientError {
     Transport(String),
     #[error("Timeout error")]
     Timeout,
+    #[error("The server requires authorization, authorize it under MCP Servers in settings")]
+    Unauthorized,
     #[error("Configuration error: {0}")]
     Configuration(String),
     #[error("IO error: {0}")]
@@ -153,377 +216,259 @@ pub enum McpClientError {
     Process(String),
 }
 
-impl RmcpClient {
-    pub async fn new(name: String, config: &McpServerConfig) -> Result<Self, McpClientError> {
-        if config.command.is_none() {
-            return Err(McpClientError::Configuration(
-                "Command is required for stdio transport".to_string(),
-            ));
-        }
-
-        let command = config.command.as_ref().unwrap().clone();
-        let args = config.args.as_ref().unwrap_or(&vec![]).clone();
-        let env = config.env.as_ref().unwrap_or(&HashMap::new()).clone();
-
-        let mut cmd = Command::new(&command);
-        cmd.args(&args)
-           .stdin(Stdio::piped())
-           .stdout(Stdio::piped())
-           .stderr(Stdio::inherit());
-
-        // Set environment variables
-        for (key, value) in &env {
-            cmd.env(key, value);
-        }
-
-        let mut child = cmd.spawn()
-            .map_err(|e| McpClientError::Process(format!("Failed to spawn process: {}", e)))?;
-
-        let stdin = child.stdin.take().ok_or_else(|| {
-            McpClientError::Process("Failed to get stdin handle".to_string())
-        })?;
-
-        let stdout = child.stdout.take().ok_or_else(|| {
-            McpClientError::Process("Failed to get stdout handle".to_string())
-        })?;
-
-        let state = RmcpClientState {
-            child,
-            stdin,
-            stdout,
-            request_id: 0,
-        };
+impl McpClient {
+    async fn connect(name: String, transport: Box<dyn McpTransport>, config: &McpServerConfig) -> Result<Self, McpClientError> {
+        let timeout = Duration::from_secs(config.timeout.unwrap_or(DEFAULT_REQUEST_TIMEOUT).max(1));
 
-        let client = Self {
-            name,
-            state: Arc::new(TokioMutex::new(state)),
-        };
-
-        // Initialize the MCP connection
-        client.initialize_connection().await?;
-
-        Ok(client)
-    }
-
-    async fn initialize_connection(&self) -> Result<(), McpClientError> {
-        let mut state = self.state.lock().await;
-
-        // Send initialize request
-        let init_request = serde_json::json!({
-            "jsonrpc": "2.0",
-            "id": self.next_request_id(&mut state),
-            "method": "initialize",
-            "params": {
-                "protocolVersion": "2024-11-05",
-                "capabilities": {
-                    "tools": {},
-                    "resources": {}
-                },
-                "clientInfo": {
-                    "name": "axum-chat",
-                    "version": "0.1.0"
-                }
-            }
-        });
-
-        let response = self.send_request(&mut state, init_request).await
+        let server_info = transport
+            .request("initialize", initialize_params(), timeout)
+            .await
             .map_err(|e| McpClientError::Initialization(format!("Initialize failed: {}", e)))?;
-
-        // Send initialized notification
-        let initialized_notification = serde_json::json!({
-            "jsonrpc": "2.0",
-            "method": "notifications/initialized"
-        });
-
-        self.send_notification(&mut state, initialized_notification).await
+        transport
+            .notify("notifications/initialized", json!({}))
+            .await
             .map_err(|e| McpClientError::Initialization(format!("Initialized notification failed: {}", e)))?;
 
-        println!("MCP client '{}' initialized successfully", self.name);
-        Ok(())
-    }
-
-    fn next_request_id(&self, state: &mut RmcpClientState) -> i64 {
-        state.request_id += 1;
-        state.request_id
+        tracing::info!("MCP client '{}' initialized successfully", name);
+        Ok(Self {
+            name,
+            transport_type: config.transport.clone().unwrap_or(TransportType::Stdio),
+            transport,
+            timeout,
+            server_info: Some(server_info),
+        })
     }
 
-    async fn send_request(&self, state: &mut RmcpClientState, request: Value) -> Result<Value, Box<dyn std::error::Error>> {
-        let request_str = serde_json::to_string(&request)?;
-        let timeout_secs = 30;
-
-        // Send request
-        state.stdin.write_all(request_str.as_bytes()).await?;
-        state.stdin.write_all(b"\n").await?;
-        state.stdin.flush().await?;
-
-        // Read response
-        let mut buffer = String::new();
-        let mut bytes_read = 0;
-        let max_bytes = 100_000; // Prevent infinite reading
-
+    // The items under `key` of every page of a list method; servers may page
+    // the list, so this follows the cursor to the end
+    async fn list_all(&self, method: &str, key: &str) -> Result<Vec<Value>, McpClientError> {
+        let mut items = Vec::new();
+        let mut cursor: Option<String> = None;
         loop {
-            let mut temp_buffer = [0; 1024];
-            let n = timeout(Duration::from_secs(timeout_secs), state.stdout.read(&mut temp_buffer)).await??;
-
-            if n == 0 {
-                break; // EOF
-            }
-
-            bytes_read += n;
-            if bytes_read > max_bytes {
-                return Err("Response too large".into());
-            }
-
-            let chunk = String::from_utf8_lossy(&temp_buffer[..n]);
-            buffer.push_str(&chunk);
-
-            // Try to parse complete JSON response
-            if let Ok(response) = self.extract_json_response(&buffer) {
-                return Ok(response);
+            let params = match &cursor {
+                Some(cursor) => json!({ "cursor": cursor }),
+                None => json!({}),
+            };
+            let result = self.transport.request(method, params, self.timeout).await?;
+            items.extend(result.get(key).and_then(|i| i.as_array()).into_iter().flatten().cloned());
+            match result.get("nextCursor").and_then(|c| c.as_str()) {
+                Some(next) if cursor.as_deref() != Some(next) => cursor = Some(next.to_string()),
+                _ => break,
             }
         }
-
-        Err("No complete response received".into())
-    }
-
-    async fn send_notification(&self, state: &mut RmcpClientState, notification: Value) -> Result<(), Box<dyn std::error::Error>> {
-        let notification_str = serde_json::to_string(&notification)?;
-        state.stdin.write_all(notification_str.as_bytes()).await?;
-        state.stdin.write_all(b"\n").await?;
-        state.stdin.flush().await?;
-        Ok(())
+        Ok(items)
     }
+}
 
-    fn extract_json_response(&self, buffer: &str) -> Result<Value, serde_json::Error> {
-        let trimmed = buffer.trim();
-        if trimmed.is_empty() {
-            return serde_json::from_str("{}"); // Empty response
+pub fn initialize_params() -> Value {
+    json!({
+        "protocolVersion": PROTOCOL_VERSION,
+        // Sampling requests are put to the user whose tool call made them.
+        // Servers are told when their roots change
+        "capabilities": { "sampling": {}, "roots": { "listChanged": true } },
+        "clientInfo": {
+            "name": "axum-chat",
+            "version": env!("CARGO_PKG_VERSION")
         }
+    })
+}
 
-        // Try to find the complete JSON object
-        let mut brace_count = 0;
-        let mut json_end = 0;
-
-        for (i, char) in trimmed.chars().enumerate() {
-            match char {
-                '{' => brace_count += 1,
-                '}' => {
-                    brace_count -= 1;
-                    if brace_count == 0 {
-                        json_end = i + 1;
-                        break;
-                    }
-                }
-                _ => {}
-            }
-        }
+pub fn parse_tool(tool: &Value) -> Option<Tool> {
+    let name = tool.get("name")?.as_str()?;
+    let description = tool.get("description").and_then(|d| d.as_str()).map(String::from);
+    let input_schema = tool.get("inputSchema").cloned().unwrap_or_default();
 
-        if brace_count == 0 && json_end > 0 {
-            let json_str = &trimmed[..json_end];
-            serde_json::from_str(json_str)
-        } else {
-            serde_json::from_str(trimmed) // Fallback
-        }
-    }
+    Some(Tool {
+        name: name.to_string(),
+        description,
+        input_schema,
+    })
+}
 
+pub fn parse_call_tool(result: &Value) -> Result<CallToolResult, McpClientError> {
+    let content_array = result
+        .get("content")
+        .and_then(|c| c.as_array())
+        .ok_or_else(|| McpClientError::ToolExecution("Invalid tool call response format".to_string()))?;
+    let content = content_array
+        .iter()
+        .filter_map(|c| {
+            let r#type = c.get("type")?.as_str()?.to_string();
+            let text = c.get("text").and_then(|t| t.as_str()).map(String::from);
+            let data = c.get("data").and_then(|d| d.as_str()).map(String::from);
+            let mime_type = c.get("mimeType").and_then(|m| m.as_str()).map(String::from);
+
+            Some(McpContent {
+                r#type,
+                text,
+                data,
+                mime_type,
+            })
+        })
+        .collect();
+
+    Ok(CallToolResult {
+        content,
+        is_error: result.get("isError").and_then(|e| e.as_bool()),
+        structured_content: result.get("structuredContent").cloned(),
+        meta: result.get("_meta").cloned(),
+    })
 }
 
 #[async_trait]
-impl McpClientTrait for RmcpClient {
+impl McpClientTrait for McpClient {
     async fn initialize(&mut self) -> Result<(), McpClientError> {
-        println!("Initializing MCP client: {}", self.name);
-        // rmcp client is already initialized during construction
+        // The handshake already happened in new
         Ok(())
     }
 
     async fn list_tools(&self) -> Result<ListToolsResult, McpClientError> {
-        let mut state = self.state.lock().await;
-
-        // Send tools/list request
-        let list_tools_request = serde_json::json!({
-            "jsonrpc": "2.0",
-            "id": self.next_request_id(&mut state),
-            "method": "tools/list"
-        });
-
-        let response = self.send_request(&mut state, list_tools_request).await
-            .map_err(|e| McpClientError::ToolExecution(format!("Failed to list tools: {}", e)))?;
-
-        // Extract tools from response
-        if let Some(result) = response.get("result") {
-            if let Some(tools_array) = result.get("tools").and_then(|t| t.as_array()) {
-                let converted_tools: Vec<Tool> = tools_array
-                    .iter()
-                    .filter_map(|tool| {
-                        let name = tool.get("name")?.as_str()?;
-                        let description = tool.get("description").and_then(|d| d.as_str()).map(String::from);
-                        let input_schema = tool.get("inputSchema").cloned().unwrap_or_default();
-
-                        Some(Tool {
-                            name: name.to_string(),
-                            description,
-                            input_schema,
-                        })
-                    })
-                    .collect();
-
-                let next_cursor = result.get("nextCursor").and_then(|c| c.as_str()).map(String::from);
-
-                return Ok(ListToolsResult {
-                    tools: converted_tools,
-                    next_cursor,
-                });
-            }
-        }
-
-        // If we get here, something went wrong with the response format
+        let tools = self.list_all("tools/list", "tools").await?;
         Ok(ListToolsResult {
-            tools: vec![],
+            tools: tools.iter().filter_map(parse_tool).collect(),
             next_cursor: None,
         })
     }
 
     async fn call_tool(&self, params: CallToolParams) -> Result<CallToolResult, McpClientError> {
-        let mut state = self.state.lock().await;
-
-        // Send tools/call request
-        let call_tool_request = serde_json::json!({
-            "jsonrpc": "2.0",
-            "id": self.next_request_id(&mut state),
-            "method": "tools/call",
-            "params": {
-                "name": params.name,
-                "arguments": params.arguments
-            }
+        let timeout = params
+            .timeout
+            .map(|t| Duration::from_secs(t.max(1) as u64))
+            .unwrap_or(self.timeout);
+        let mut request = json!({
+            "name": params.name,
+            "arguments": params.arguments.unwrap_or_else(|| json!({}))
         });
-
-        let response = self.send_request(&mut state, call_tool_request).await
-            .map_err(|e| McpClientError::ToolExecution(format!("Failed to call tool: {}", e)))?;
-
-        // Extract result from response
-        if let Some(result) = response.get("result") {
-            let content = if let Some(content_array) = result.get("content").and_then(|c| c.as_array()) {
-                content_array
-                    .iter()
-                    .filter_map(|c| {
-                        let r#type = c.get("type")?.as_str()?.to_string();
-                        let text = c.get("text").and_then(|t| t.as_str()).map(String::from);
-                        let data = c.get("data").and_then(|d| d.as_str()).map(String::from);
-                        let mime_type = c.get("mimeType").and_then(|m| m.as_str()).map(String::from);
-
-                        Some(McpContent {
-                            r#type,
-                            text,
-                            data,
-                            mime_type,
-                        })
-                    })
-                    .collect()
-            } else {
-                vec![]
-            };
-
-            let is_error = result.get("isError").and_then(|e| e.as_bool());
-            let meta = result.get("meta").cloned();
-
-            return Ok(CallToolResult {
-                content,
-                is_error,
-                structured_content: None,
-                meta,
-            });
+        // Lets the server tag its sampling requests with the call they're for
+        if let Some(token) = sampling::progress_token() {
+            request["_meta"] = json!({ "progressToken": token });
         }
 
-        // If we get here, something went wrong with the response format
-        Err(McpClientError::ToolExecution("Invalid tool call response format".to_string()))
+        let result = self.transport.request("tools/call", request, timeout).await?;
+        parse_call_tool(&result)
     }
 
     async fn list_resources(&self) -> Result<ListResourcesResult, McpClientError> {
-        // For now, return empty resources - you can implement this later
         Ok(ListResourcesResult {
-            resources: vec![],
+            resources: self.list_all("resources/list", "resources").await?,
             next_cursor: None,
         })
     }
 
     async fn read_resource(
         &self,
-        _params: ReadResourceParams,
+        params: ReadResourceParams,
     ) -> Result<ReadResourceResult, McpClientError> {
-        // For now, return empty contents - you can implement this later
-        Ok(ReadResourceResult {
-            contents: vec![],
-        })
+        let result = self
+            .transport
+            .request("resources/read", json!({ "uri": params.uri }), self.timeout)
+            .await?;
+        let contents = result
+            .get("contents")
+            .and_then(|c| c.as_array())
+            .cloned()
+            .unwrap_or_default();
+
+        Ok(ReadResourceResult { contents })
     }
 
     async fn list_prompts(&self) -> Result<ListPromptsResult, McpClientError> {
-        // For now, return empty prompts - you can implement this later
         Ok(ListPromptsResult {
-            prompts: vec![],
+            prompts: self.list_all("prompts/list", "prompts").await?,
             next_cursor: None,
         })
     }
 
     async fn get_prompt(
         &self,
-        _name: &str,
-        _arguments: Option<Value>,
+        name: &str,
+        arguments: Option<Value>,
     ) -> Result<GetPromptResult, McpClientError> {
-        // For now, return empty result - you can implement this later
+        let request = json!({
+            "name": name,
+            "arguments": arguments.unwrap_or_else(|| json!({}))
+        });
+        let result = self.transport.request("prompts/get", request, self.timeout).await?;
+
         Ok(GetPromptResult {
-            description: None,
-            messages: vec![],
+            description: result.get("description").and_then(|d| d.as_str()).map(String::from),
+            messages: result.get("messages").and_then(|m| m.as_array()).cloned().unwrap_or_default(),
         })
     }
 
     fn get_connection_info(&self) -> McpConnectionInfo {
         McpConnectionInfo {
             name: self.name.clone(),
-            transport_type: TransportType::Stdio,
-            server_info: None,
+            transport_type: self.transport_type.clone(),
+            server_info: self.server_info.clone(),
         }
     }
 
-    async fn shutdown(&mut self) -> Result<(), McpClientError> {
-        let mut state = self.state.lock().await;
-
-        // Send shutdown notification if possible
-        let shutdown_notification = serde_json::json!({
-            "jsonrpc": "2.0",
-            "method": "notifications/shutdown"
-        });
-
-        if let Err(e) = self.send_notification(&mut state, shutdown_notification).await {
-            eprintln!("Warning: Failed to send shutdown notification: {}", e);
+    async fn ping(&self) -> Result<(), McpClientError> {
+        if let Some(reason) = self.transport.exited().await {
+            return Err(McpClientError::Process(reason));
         }
-
-        // Kill the process
-        if let Err(e) = state.child.kill().await {
-            eprintln!("Warning: Failed to kill MCP process: {}", e);
+        match self.transport.request("ping", json!({}), self.timeout.min(PING_TIMEOUT)).await {
+            // An error answer still shows the server is there
+            Ok(_) | Err(McpClientError::Protocol(_)) => Ok(()),
+            Err(e) => Err(e),
         }
+    }
 
-        Ok(())
+    async fn roots_changed(&self) -> Result<(), McpClientError> {
+        self.transport.notify("notifications/roots/list_changed", json!({})).await
+    }
+
+    async fn shutdown(&mut self) -> Result<(), McpClientError> {
+        self.transport.close().await
     }
 }
 
-// Factory function to create appropriate client
+// Factory function to create appropriate client. Servers users add are
+// `public_only`: their URL has to stay off the server's own network
 pub async fn create_mcp_client(
     name: String,
     config: &McpServerConfig,
+    public_only: bool,
 ) -> Result<Box<dyn McpClientTrait>, McpClientError> {
     let transport_type = config.transport.as_ref().unwrap_or(&TransportType::Stdio);
 
-    match transport_type {
-        TransportType::Stdio => {
-            let client = RmcpClient::new(name, config).await?;
-            Ok(Box::new(client))
-        }
-        TransportType::Sse => Err(McpClientError::Configuration(
-            "SSE transport not yet implemented".to_string(),
-        )),
-        TransportType::Http => Err(McpClientError::Configuration(
-            "HTTP transport not yet implemented".to_string(),
-        )),
+    if let (true, TransportType::Http | TransportType::Sse, Some(url)) = (public_only, transport_type, &config.url) {
+        outbound::check_url(url).await.map_err(McpClientError::Configuration)?;
     }
+    let transport: Box<dyn McpTransport> = match transport_type {
+        TransportType::Stdio => Box::new(StdioTransport::spawn(&name, config)?),
+        TransportType::Http => Box::new(HttpTransport::new(&name, config, public_only)?),
+        TransportType::Sse => Box::new(SseTransport::connect(&name, config, public_only)?),
+    };
+    let client = McpClient::connect(name, transport, config).await?;
+    Ok(Box::new(client))
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prompt_and_resource_text() {
+        let prompt = GetPromptResult {
+            description: None,
+            messages: vec![
+                json!({ "role": "user", "content": { "type": "text", "text": "Review this file" } }),
+                json!({ "role": "user", "content": { "type": "image", "data": "AAAA", "mimeType": "image/png" } }),
+                json!({ "role": "user", "content": { "type": "resource", "resource": { "uri": "file:///a.rs", "text": "fn main() {}" } } }),
+            ],
+        };
+        assert_eq!(prompt.text(), "Review this file\n\nfn main() {}");
+
+        let resource = ReadResourceResult {
+            contents: vec![
+                json!({ "uri": "file:///logo.png", "blob": "AAAA", "mimeType": "image/png" }),
+                json!({ "uri": "file:///a.rs", "text": "fn main() {}", "mimeType": "text/x-rust" }),
+            ],
+        };
+        assert_eq!(resource.text().as_deref(), Some("fn main() {}"));
+        assert_eq!(resource.mime_type(), Some("text/x-rust"));
+        assert_eq!(ReadResourceResult { contents: vec![] }.text(), None);
+    }
+}
@@ -2,12 +2,14 @@ use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::path::PathBuf;
 
+use crate::encryption::{self, Encryption, EncryptionError};
+
 #[derive(Debug, Clone, Serialize, Deserialize, schemars::JsonSchema)]
 pub struct McpConfig {
     pub mcp_servers: HashMap<String, McpServerConfig>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, schemars::JsonSchema)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, schemars::JsonSchema)]
 pub struct McpServerConfig {
     pub command: Option<String>,
     pub args: Option<Vec<String>>,
@@ -15,12 +17,26 @@ pub struct McpServerConfig {
     pub disabled: Option<bool>,
     pub timeout: Option<u64>,
     pub description: Option<String>,
+    // Other clients' mcp.json files call this `type`
+    #[serde(alias = "type")]
     pub transport: Option<TransportType>,
     pub url: Option<String>,
     pub headers: Option<HashMap<String, String>>,
+    // How often the server is restarted after it goes down before it's left
+    // failed, see `health`
+    pub max_restarts: Option<u32>,
+    // Seconds to reuse a tool's result for calls with the same arguments, by
+    // the tool's name on the server. Only for tools without side effects
+    pub cache_ttl: Option<HashMap<String, u64>>,
+    // Seconds a call of a tool may take, by the tool's name on the server,
+    // instead of `timeout`
+    pub tool_timeouts: Option<HashMap<String, u64>>,
+    // Absolute paths of the directories the server may work in, offered to
+    // it as MCP roots, see `roots`
+    pub roots: Option<Vec<String>>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, schemars::JsonSchema)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, schemars::JsonSchema)]
 #[serde(rename_all = "lowercase")]
 pub enum TransportType {
     Stdio,
@@ -51,17 +67,6 @@ impl McpConfig {
         Ok(config)
     }
 
-    pub fn save_to_file(&self, path: &PathBuf) -> Result<(), Box<dyn std::error::Error>> {
-        // Create parent directories if they don't exist
-        if let Some(parent) = path.parent() {
-            std::fs::create_dir_all(parent)?;
-        }
-
-        let content = serde_json::to_string_pretty(self)?;
-        std::fs::write(path, content)?;
-        Ok(())
-    }
-
     pub fn add_server(&mut self, name: String, config: McpServerConfig) {
         self.mcp_servers.insert(name, config);
     }
@@ -97,6 +102,51 @@ impl McpConfig {
     }
 }
 
+// Header and env values often hold credentials, so the copy of a user's
+// server kept in the database has them encrypted like other secrets
+impl McpServerConfig {
+    fn map_secrets<E>(&self, mut f: impl FnMut(&str) -> Result<String, E>) -> Result<Self, E> {
+        let mut map = |values: &Option<HashMap<String, String>>| -> Result<_, E> {
+            values
+                .as_ref()
+                .map(|values| {
+                    values
+                        .iter()
+                        .map(|(key, value)| Ok((key.clone(), f(value)?)))
+                        .collect::<Result<HashMap<_, _>, E>>()
+                })
+                .transpose()
+        };
+        Ok(Self {
+            env: map(&self.env)?,
+            headers: map(&self.headers)?,
+            ..self.clone()
+        })
+    }
+
+    // The config to store. Values already encrypted stay as they are
+    pub fn sealed(&self, encryption: &Encryption) -> Self {
+        self.map_secrets(|value| {
+            Ok::<_, std::convert::Infallible>(if encryption::is_encrypted(value) {
+                value.to_string()
+            } else {
+                encryption.encrypt(value)
+            })
+        })
+        .unwrap_or_else(|never| match never {})
+    }
+
+    // The stored config with its values decrypted
+    pub fn opened(&self, encryption: &Encryption) -> Result<Self, EncryptionError> {
+        self.map_secrets(|value| encryption.decrypt(value))
+    }
+
+    // The stored config with its values sealed under the newest master key
+    pub fn resealed(&self, encryption: &Encryption) -> Result<Self, EncryptionError> {
+        self.map_secrets(|value| Ok(encryption.reencrypt(value)?.unwrap_or_else(|| value.to_string())))
+    }
+}
+
 // Common MCP server configurations
 impl McpServerConfig {
     pub fn filesystem_command(path: &str) -> Self {
@@ -114,6 +164,10 @@ impl McpServerConfig {
             transport: Some(TransportType::Stdio),
             url: None,
             headers: None,
+            max_restarts: None,
+            cache_ttl: None,
+            tool_timeouts: None,
+            roots: None,
         }
     }
 
@@ -131,6 +185,10 @@ impl McpServerConfig {
             transport: Some(TransportType::Stdio),
             url: None,
             headers: None,
+            max_restarts: None,
+            cache_ttl: None,
+            tool_timeouts: None,
+            roots: None,
         }
     }
 
@@ -151,6 +209,10 @@ impl McpServerConfig {
             transport: Some(TransportType::Stdio),
             url: None,
             headers: None,
+            max_restarts: None,
+            cache_ttl: None,
+            tool_timeouts: None,
+            roots: None,
         }
     }
 
@@ -168,6 +230,10 @@ impl McpServerConfig {
             transport: Some(TransportType::Stdio),
             url: None,
             headers: None,
+            max_restarts: None,
+            cache_ttl: None,
+            tool_timeouts: None,
+            roots: None,
         }
     }
 
@@ -185,6 +251,10 @@ impl McpServerConfig {
             transport: Some(TransportType::Stdio),
             url: None,
             headers: None,
+            max_restarts: None,
+            cache_ttl: None,
+            tool_timeouts: None,
+            roots: None,
         }
     }
 
@@ -199,6 +269,10 @@ impl McpServerConfig {
             transport: Some(TransportType::Sse),
             url: Some(url.to_string()),
             headers,
+            max_restarts: None,
+            cache_ttl: None,
+            tool_timeouts: None,
+            roots: None,
         }
     }
 
@@ -213,7 +287,47 @@ impl McpServerConfig {
             transport: Some(TransportType::Http),
             url: Some(url.to_string()),
             headers,
+            max_restarts: None,
+            cache_ttl: None,
+            tool_timeouts: None,
+            roots: None,
         }
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transport_from_type_field() {
+        let config: McpConfig = serde_json::from_str(
+            r#"{"mcp_servers": {"remote": {"type": "sse", "url": "https://example.com/sse"}}}"#,
+        )
+        .unwrap();
+        let server = &config.mcp_servers["remote"];
+        assert!(matches!(server.transport, Some(TransportType::Sse)));
+        assert_eq!(server.url.as_deref(), Some("https://example.com/sse"));
+    }
+
+    #[test]
+    fn test_secrets_are_sealed() {
+        let encryption = Encryption::new(&[7; 32]);
+        let mut config = McpServerConfig::http_transport(
+            "https://mcp.example.com",
+            Some(HashMap::from([("Authorization".to_string(), "Bearer secret".to_string())])),
+        );
+        config.env = Some(HashMap::from([("TOKEN".to_string(), "ghp_secret".to_string())]));
+
+        let sealed = config.sealed(&encryption);
+        let stored = serde_json::to_string(&sealed).unwrap();
+        assert!(!stored.contains("secret"));
+        assert_eq!(sealed.url, config.url);
+        // Sealing again leaves the stored values alone
+        assert_eq!(sealed.sealed(&encryption), sealed);
+        assert_eq!(sealed.opened(&encryption).unwrap(), config);
+        // Rows saved before encryption open as they are
+        assert_eq!(config.opened(&encryption).unwrap(), config);
+    }
+}
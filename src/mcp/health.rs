@@ -0,0 +1,140 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use tokio::time::MissedTickBehavior;
+
+use super::manager::McpManager;
+
+// How often running servers are pinged and those due a restart retried
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+// The longest wait between restarts
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+// A server up this long starts over with its restarts
+const STABLE_AFTER: Duration = Duration::from_secs(600);
+// Restarts a server gets when its config doesn't set `max_restarts`
+pub const DEFAULT_MAX_RESTARTS: u32 = 5;
+// Transitions kept per server
+const HISTORY: usize = 10;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ServerState {
+    Running,
+    // Went down and waits for its next restart
+    Restarting,
+    // Didn't start, or went down more often than it may be restarted
+    Failed,
+    #[default]
+    Stopped,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Transition {
+    pub state: ServerState,
+    pub at: DateTime<Utc>,
+    pub error: Option<String>,
+}
+
+// What the supervisor knows of a server, shown in settings
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ServerStatus {
+    pub state: ServerState,
+    pub last_error: Option<String>,
+    // Restarts since the server last stayed up for a while
+    pub restarts: u32,
+    // Oldest first
+    pub transitions: Vec<Transition>,
+    #[serde(skip)]
+    retry_at: Option<Instant>,
+    #[serde(skip)]
+    up_since: Option<Instant>,
+}
+
+impl ServerStatus {
+    pub fn set(&mut self, state: ServerState, error: Option<String>) {
+        self.up_since = (state == ServerState::Running).then(Instant::now);
+        self.retry_at = None;
+        if state == self.state && error.is_none() {
+            return;
+        }
+        if error.is_some() {
+            self.last_error = error.clone();
+        }
+        self.state = state;
+        self.transitions.push(Transition { state, at: Utc::now(), error });
+        if self.transitions.len() > HISTORY {
+            self.transitions.remove(0);
+        }
+    }
+
+    // Schedules the next restart after the server went down, or gives up on
+    // it once it has had `max_restarts`
+    pub fn went_down(&mut self, error: String, max_restarts: u32) {
+        if self.restarts >= max_restarts {
+            self.set(ServerState::Failed, Some(error));
+            return;
+        }
+        self.restarts += 1;
+        self.set(ServerState::Restarting, Some(error));
+        self.retry_at = Some(Instant::now() + backoff(self.restarts));
+    }
+
+    // A passed health check; a server that stays up earns its restarts back
+    pub fn answered(&mut self) {
+        if self.up_since.is_some_and(|since| since.elapsed() >= STABLE_AFTER) {
+            self.restarts = 0;
+        }
+    }
+
+    pub fn restart_due(&self) -> bool {
+        self.state == ServerState::Restarting && self.retry_at.is_some_and(|at| at <= Instant::now())
+    }
+}
+
+// The wait before the nth restart, doubling from the check interval
+pub fn backoff(restarts: u32) -> Duration {
+    let factor = 2u32.saturating_pow(restarts.saturating_sub(1));
+    CHECK_INTERVAL.saturating_mul(factor).min(MAX_BACKOFF)
+}
+
+// Checks the manager's servers every `CHECK_INTERVAL` for as long as the
+// manager is around
+pub fn supervise(manager: &Arc<McpManager>) {
+    let manager: Weak<McpManager> = Arc::downgrade(manager);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let Some(manager) = manager.upgrade() else {
+                break;
+            };
+            manager.check_health().await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_restart_backoff() {
+        assert_eq!(backoff(1), CHECK_INTERVAL);
+        assert_eq!(backoff(3), CHECK_INTERVAL * 4);
+        assert_eq!(backoff(30), MAX_BACKOFF);
+
+        let mut status = ServerStatus::default();
+        status.set(ServerState::Running, None);
+        status.went_down("exited".to_string(), 2);
+        assert_eq!(status.state, ServerState::Restarting);
+        assert!(!status.restart_due());
+        status.went_down("exited again".to_string(), 2);
+        status.went_down("exited for good".to_string(), 2);
+        assert_eq!(status.state, ServerState::Failed);
+        assert_eq!(status.restarts, 2);
+        assert_eq!(status.last_error.as_deref(), Some("exited for good"));
+        assert_eq!(status.transitions.len(), 4);
+    }
+}
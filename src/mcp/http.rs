@@ -0,0 +1,267 @@
+use async_trait::async_trait;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, ACCEPT, CONTENT_TYPE};
+use reqwest::{RequestBuilder, StatusCode};
+use serde_json::Value;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use super::client::{McpClientError, McpTransport};
+use super::config::McpServerConfig;
+use super::jsonrpc::{self, Message};
+use super::oauth;
+use crate::utils::outbound;
+
+// Set by the server on the initialize response and sent back on every request after
+const SESSION_HEADER: &str = "mcp-session-id";
+
+// The MCP streamable HTTP transport: every message is POSTed to the server's
+// endpoint, which answers with the JSON response or with an event stream
+// carrying it, so hosted servers work without spawning anything
+pub struct HttpTransport {
+    name: String,
+    url: String,
+    client: reqwest::Client,
+    session: Mutex<Option<String>>,
+    next_id: AtomicI64,
+}
+
+impl HttpTransport {
+    pub fn new(name: &str, config: &McpServerConfig, public_only: bool) -> Result<Self, McpClientError> {
+        let url = config.url.clone().ok_or_else(|| {
+            McpClientError::Configuration("URL is required for HTTP transport".to_string())
+        })?;
+        Ok(HttpTransport {
+            name: name.to_string(),
+            url,
+            client: client_for(config, public_only)?,
+            session: Mutex::new(None),
+            next_id: AtomicI64::new(1),
+        })
+    }
+}
+
+#[async_trait]
+impl McpTransport for HttpTransport {
+    async fn request(&self, method: &str, params: Value, timeout: Duration) -> Result<Value, McpClientError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let response = self
+            .post(&jsonrpc::request(id, method, params))
+            .await?
+            .timeout(timeout)
+            .send()
+            .await
+            .map_err(send_error)?;
+
+        if let Some(session) = response.headers().get(SESSION_HEADER).and_then(|v| v.to_str().ok()) {
+            *self.session.lock().unwrap() = Some(session.to_string());
+        }
+        let mut response = check_status(response).await?;
+
+        let is_stream = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.starts_with("text/event-stream"));
+        if !is_stream {
+            let message: Value = response.json().await.map_err(send_error)?;
+            return self.receive(id, &message).await.unwrap_or_else(|| no_response(method));
+        }
+
+        // A stream may carry notifications and server requests before the
+        // response, and the server waits for the answers to its requests, so
+        // it's read as it arrives
+        let mut stream = EventStream::default();
+        while let Some(chunk) = response.chunk().await.map_err(send_error)? {
+            for message in stream.push(&chunk) {
+                if let Some(result) = self.receive(id, &message).await {
+                    return result;
+                }
+            }
+        }
+        for message in stream.finish() {
+            if let Some(result) = self.receive(id, &message).await {
+                return result;
+            }
+        }
+        no_response(method)
+    }
+
+    async fn notify(&self, method: &str, params: Value) -> Result<(), McpClientError> {
+        self.send(&jsonrpc::notification(method, params)).await
+    }
+
+    // Ends the session; servers that don't support that answer 405
+    async fn close(&self) -> Result<(), McpClientError> {
+        let Some(session) = self.session.lock().unwrap().take() else {
+            return Ok(());
+        };
+        let response = self
+            .authorize(self.client.delete(&self.url))
+            .await?
+            .header(SESSION_HEADER, session)
+            .send()
+            .await
+            .map_err(send_error)?;
+        if response.status() != StatusCode::METHOD_NOT_ALLOWED {
+            check_status(response).await?;
+        }
+        Ok(())
+    }
+}
+
+impl HttpTransport {
+    // The result if this is the response to request `id`, after answering
+    // the server if it's a request
+    async fn receive(&self, id: i64, message: &Value) -> Option<Result<Value, McpClientError>> {
+        match jsonrpc::classify(message) {
+            Message::Response(Some(response_id)) if response_id == id => Some(jsonrpc::response_result(message)),
+            Message::Request(request_id, method) => {
+                let reply = jsonrpc::answer(&self.name, request_id, method, &message["params"]).await;
+                if let Err(e) = self.send(&reply).await {
+                    tracing::error!("Failed to answer MCP server {}: {}", self.name, e);
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    // Messages with nothing to answer get 202 Accepted and no body
+    async fn send(&self, message: &Value) -> Result<(), McpClientError> {
+        let response = self.post(message).await?.send().await.map_err(send_error)?;
+        check_status(response).await?;
+        Ok(())
+    }
+
+    async fn post(&self, message: &Value) -> Result<RequestBuilder, McpClientError> {
+        let request = self
+            .client
+            .post(&self.url)
+            .header(ACCEPT, "application/json, text/event-stream")
+            .json(message);
+        let request = match self.session.lock().unwrap().as_deref() {
+            Some(session) => request.header(SESSION_HEADER, session),
+            None => request,
+        };
+        self.authorize(request).await
+    }
+
+    // With the OAuth token when the user authorized the server, see `oauth`
+    async fn authorize(&self, request: RequestBuilder) -> Result<RequestBuilder, McpClientError> {
+        Ok(match oauth::access_token(&self.name, &self.url).await? {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        })
+    }
+}
+
+// A client sending the headers configured for the server with every request.
+// With `public_only` it only connects to public addresses, see `outbound`
+pub fn client_for(config: &McpServerConfig, public_only: bool) -> Result<reqwest::Client, McpClientError> {
+    let builder = if public_only { outbound::client_builder() } else { reqwest::Client::builder() };
+    builder
+        .default_headers(configured_headers(config)?)
+        .build()
+        .map_err(|e| McpClientError::Configuration(format!("Failed to create HTTP client: {}", e)))
+}
+
+fn configured_headers(config: &McpServerConfig) -> Result<HeaderMap, McpClientError> {
+    let mut headers = HeaderMap::new();
+    for (name, value) in config.headers.iter().flatten() {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|e| McpClientError::Configuration(format!("Invalid header name {}: {}", name, e)))?;
+        let value = HeaderValue::from_str(value)
+            .map_err(|e| McpClientError::Configuration(format!("Invalid value for header {}: {}", name, e)))?;
+        headers.insert(name, value);
+    }
+    Ok(headers)
+}
+
+pub fn send_error(e: reqwest::Error) -> McpClientError {
+    if e.is_timeout() {
+        McpClientError::Timeout
+    } else {
+        McpClientError::Transport(e.to_string())
+    }
+}
+
+pub async fn check_status(response: reqwest::Response) -> Result<reqwest::Response, McpClientError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    if status == StatusCode::UNAUTHORIZED {
+        return Err(McpClientError::Unauthorized);
+    }
+    let body = response.text().await.unwrap_or_default();
+    Err(McpClientError::Transport(format!("Server returned {}: {}", status, body.trim())))
+}
+
+fn no_response(method: &str) -> Result<Value, McpClientError> {
+    Err(McpClientError::Protocol(format!("No response to {} from the server", method)))
+}
+
+// Parses a text/event-stream body chunk by chunk into the JSON messages in
+// the data of each event
+#[derive(Default)]
+pub struct EventStream {
+    buffer: Vec<u8>,
+    data: String,
+}
+
+impl EventStream {
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<Value> {
+        self.buffer.extend_from_slice(chunk);
+        let mut messages = Vec::new();
+        while let Some(end) = self.buffer.iter().position(|&b| b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            self.line(line.trim_end_matches(['\n', '\r']), &mut messages);
+        }
+        messages
+    }
+
+    // The last event, when the body doesn't end with a blank line
+    pub fn finish(&mut self) -> Vec<Value> {
+        let mut messages = self.push(b"\n");
+        self.line("", &mut messages);
+        messages
+    }
+
+    fn line(&mut self, line: &str, messages: &mut Vec<Value>) {
+        if let Some(value) = line.strip_prefix("data:") {
+            if !self.data.is_empty() {
+                self.data.push('\n');
+            }
+            self.data.push_str(value.strip_prefix(' ').unwrap_or(value));
+        } else if line.is_empty() && !self.data.is_empty() {
+            if let Ok(message) = serde_json::from_str(&self.data) {
+                messages.push(message);
+            }
+            self.data.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_event_stream() {
+        let body = "event: message\r\nid: 1\r\ndata: {\"jsonrpc\":\"2.0\",\"method\":\"notifications/progress\"}\r\n\r\n\
+                    : keep-alive\n\n\
+                    data: {\"jsonrpc\":\"2.0\",\n\
+                    data: \"id\":3,\"result\":{}}\n";
+        let mut stream = EventStream::default();
+        let (first, rest) = body.as_bytes().split_at(20);
+        assert!(stream.push(first).is_empty());
+        let mut messages = stream.push(rest);
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0]["method"], "notifications/progress");
+        messages.extend(stream.finish());
+        assert_eq!(messages[1], json!({ "jsonrpc": "2.0", "id": 3, "result": {} }));
+    }
+}
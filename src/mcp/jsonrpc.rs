@@ -0,0 +1,60 @@
+use serde_json::{json, Value};
+
+use super::client::McpClientError;
+use super::{roots, sampling};
+
+// JSON-RPC error code for requests we don't handle
+const METHOD_NOT_FOUND: i64 = -32601;
+// The code MCP uses for sampling requests that aren't carried out
+const REQUEST_REJECTED: i64 = -1;
+
+pub fn request(id: i64, method: &str, params: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params })
+}
+
+pub fn notification(method: &str, params: Value) -> Value {
+    json!({ "jsonrpc": "2.0", "method": method, "params": params })
+}
+
+fn error(id: &Value, code: i64, message: &str) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+// The reply to a request from the server. Sampling waits for the user to
+// approve it, so transports answer off their read loop
+pub async fn answer(server: &str, id: &Value, method: &str, params: &Value) -> Value {
+    match method {
+        "sampling/createMessage" => match sampling::create_message(server, params).await {
+            Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Err(message) => error(id, REQUEST_REJECTED, &message),
+        },
+        "roots/list" => json!({ "jsonrpc": "2.0", "id": id, "result": roots::list(server) }),
+        _ => error(id, METHOD_NOT_FOUND, &format!("Method not supported: {}", method)),
+    }
+}
+
+// The result of a response, or its error
+pub fn response_result(message: &Value) -> Result<Value, McpClientError> {
+    if let Some(error) = message.get("error") {
+        let code = error.get("code").and_then(Value::as_i64).unwrap_or_default();
+        let text = error.get("message").and_then(Value::as_str).unwrap_or("Unknown error");
+        return Err(McpClientError::Protocol(format!("{} (code {})", text, code)));
+    }
+    Ok(message.get("result").cloned().unwrap_or(Value::Null))
+}
+
+pub enum Message<'a> {
+    Request(&'a Value, &'a str),
+    Notification,
+    Response(Option<i64>),
+    Invalid,
+}
+
+pub fn classify(message: &Value) -> Message<'_> {
+    match (message.get("id"), message.get("method").and_then(Value::as_str)) {
+        (Some(id), Some(method)) => Message::Request(id, method),
+        (None, Some(_)) => Message::Notification,
+        (Some(id), None) => Message::Response(id.as_i64()),
+        (None, None) => Message::Invalid,
+    }
+}
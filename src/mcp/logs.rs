@@ -0,0 +1,103 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, LazyLock, Mutex};
+use tokio::sync::broadcast;
+
+// Lines kept per server
+const CAPACITY: usize = 500;
+// Lines a follower may fall behind by before it skips some
+const FOLLOW_BUFFER: usize = 64;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LogLine {
+    pub at: DateTime<Utc>,
+    // "stderr", or "stdout" for output that isn't JSON-RPC
+    pub stream: &'static str,
+    pub text: String,
+}
+
+impl fmt::Display for LogLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} [{}] {}", self.at.format("%Y-%m-%d %H:%M:%S"), self.stream, self.text)
+    }
+}
+
+// The latest output of a server's process, kept across its restarts
+pub struct ServerLog {
+    lines: Mutex<VecDeque<LogLine>>,
+    live: broadcast::Sender<LogLine>,
+}
+
+impl ServerLog {
+    fn new() -> Self {
+        let (live, _) = broadcast::channel(FOLLOW_BUFFER);
+        ServerLog {
+            lines: Mutex::new(VecDeque::with_capacity(CAPACITY)),
+            live,
+        }
+    }
+
+    pub fn push(&self, stream: &'static str, text: String) {
+        let line = LogLine { at: Utc::now(), stream, text };
+        let mut lines = self.lines.lock().unwrap();
+        if lines.len() == CAPACITY {
+            lines.pop_front();
+        }
+        lines.push_back(line.clone());
+        // Fails when no one follows the log
+        let _ = self.live.send(line);
+    }
+
+    pub fn lines(&self) -> Vec<LogLine> {
+        self.lines.lock().unwrap().iter().cloned().collect()
+    }
+
+    // The kept lines and the ones after them, none missed or repeated
+    pub fn follow(&self) -> (Vec<LogLine>, broadcast::Receiver<LogLine>) {
+        let lines = self.lines.lock().unwrap();
+        (lines.iter().cloned().collect(), self.live.subscribe())
+    }
+}
+
+static LOGS: LazyLock<Mutex<HashMap<String, Arc<ServerLog>>>> = LazyLock::new(Default::default);
+
+// The log of a server by its client name, see `McpManager::client_name`
+pub fn server_log(client: &str) -> Arc<ServerLog> {
+    LOGS.lock()
+        .unwrap()
+        .entry(client.to_string())
+        .or_insert_with(|| Arc::new(ServerLog::new()))
+        .clone()
+}
+
+// Drops the log of a server that was removed
+pub fn forget(client: &str) {
+    LOGS.lock().unwrap().remove(client);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_server_log() {
+        let log = server_log("test_server_log");
+        for n in 0..CAPACITY + 2 {
+            log.push("stderr", n.to_string());
+        }
+        let lines = log.lines();
+        assert_eq!(lines.len(), CAPACITY);
+        assert_eq!(lines[0].text, "2");
+
+        let (kept, mut live) = server_log("test_server_log").follow();
+        assert_eq!(kept.len(), CAPACITY);
+        log.push("stdout", "not JSON".to_string());
+        let line = live.try_recv().unwrap();
+        assert!(line.to_string().ends_with("[stdout] not JSON"));
+
+        forget("test_server_log");
+        assert!(server_log("test_server_log").lines().is_empty());
+    }
+}
@@ -9,6 +9,15 @@ use super::client::{
     McpConnectionInfo, ServerCapabilities, Tool,
 };
 use super::config::{McpConfig, McpServerConfig};
+use super::health::{ServerState, ServerStatus, DEFAULT_MAX_RESTARTS};
+use super::{logs, oauth, roots};
+use crate::data::cache::QueryCache;
+
+// Results kept per cached tool
+const TOOL_CACHE_SIZE: usize = 100;
+
+// A tool's results by their arguments
+type ToolCache = QueryCache<String, CallToolResult>;
 
 #[derive(Debug, Clone)]
 pub struct McpTool {
@@ -22,6 +31,11 @@ pub struct McpManager {
     clients: Arc<RwLock<HashMap<String, Arc<Box<dyn McpClientTrait>>>>>,
     tools: Arc<RwLock<HashMap<String, McpTool>>>,
     config: Arc<RwLock<McpConfig>>,
+    statuses: Arc<RwLock<HashMap<String, ServerStatus>>>,
+    // By tool, for the tools in their server's `cache_ttl`
+    result_caches: Arc<std::sync::Mutex<HashMap<String, Arc<ToolCache>>>>,
+    // The user whose own servers these are, `None` for the global ones
+    owner: Option<i64>,
 }
 
 impl McpManager {
@@ -30,9 +44,31 @@ impl McpManager {
             clients: Arc::new(RwLock::new(HashMap::new())),
             tools: Arc::new(RwLock::new(HashMap::new())),
             config: Arc::new(RwLock::new(McpConfig::new())),
+            statuses: Arc::new(RwLock::new(HashMap::new())),
+            result_caches: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            owner: None,
+        }
+    }
+
+    pub fn for_user(user_id: i64) -> Self {
+        let mut manager = Self::new();
+        manager.owner = Some(user_id);
+        manager
+    }
+
+    // The name a server's connection goes by, unique across users since
+    // theirs may share names
+    pub fn client_name(&self, server: &str) -> String {
+        match self.owner {
+            Some(user_id) => format!("{} (user {})", server, user_id),
+            None => server.to_string(),
         }
     }
 
+    pub async fn has_server(&self, name: &str) -> bool {
+        self.config.read().await.mcp_servers.contains_key(name)
+    }
+
     pub async fn load_config(
         &self,
         config_path: &std::path::PathBuf,
@@ -42,21 +78,76 @@ impl McpManager {
         Ok(())
     }
 
-    pub async fn save_config(
-        &self,
-        config_path: &std::path::PathBuf,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let config = self.config.read().await;
-        config.save_to_file(config_path)?;
-        Ok(())
+    // Brings the running servers in line with a changed config: removed ones
+    // are stopped, new and changed ones (re)started and the rest left alone
+    pub async fn apply_config(&self, new: McpConfig) {
+        let old = self.get_server_configs().await;
+        for name in old.keys().filter(|name| !new.mcp_servers.contains_key(*name)) {
+            tracing::info!("Stopping removed MCP server {}", name);
+            self.remove_server_config(name).await;
+            if let Err(e) = self.shutdown_server(name).await {
+                tracing::error!("Error shutting down server {}: {}", name, e);
+            }
+        }
+
+        for (name, server_config) in new.mcp_servers {
+            if old.get(&name) == Some(&server_config) {
+                continue;
+            }
+            if self.update_roots(&name, &server_config).await {
+                tracing::info!("Sent the new roots of MCP server {}", name);
+                continue;
+            }
+            self.add_server_config(name.clone(), server_config.clone()).await;
+            if server_config.disabled == Some(true) {
+                self.shutdown_server(&name).await.ok();
+            } else {
+                match self.initialize_server(name.clone(), &server_config).await {
+                    Ok(()) => tracing::info!("Started changed MCP server {}", name),
+                    Err(e) => tracing::error!("Failed to start changed MCP server {}: {}", name, e),
+                }
+            }
+        }
+    }
+
+    // Takes the config when it only changes the roots of the running server,
+    // which is told about them instead of being restarted. False when the
+    // server needs (re)starting for it
+    pub async fn update_roots(&self, name: &str, server_config: &McpServerConfig) -> bool {
+        let Some(old) = self.get_server_configs().await.remove(name) else {
+            return false;
+        };
+        let without_roots = |config: &McpServerConfig| McpServerConfig { roots: None, ..config.clone() };
+        if old == *server_config || without_roots(&old) != without_roots(server_config) {
+            return false;
+        }
+        let Some(client) = self.clients.read().await.get(name).cloned() else {
+            return false;
+        };
+
+        self.add_server_config(name.to_string(), server_config.clone()).await;
+        roots::set(&self.client_name(name), server_config.roots.as_deref().unwrap_or_default());
+        match client.roots_changed().await {
+            Ok(()) => true,
+            Err(e) => {
+                tracing::error!("Failed to send the new roots of MCP server {}: {}", name, e);
+                false
+            }
+        }
     }
 
     pub async fn add_server_config(&self, name: String, server_config: McpServerConfig) {
+        self.forget_results(&name);
         let mut config = self.config.write().await;
         config.add_server(name, server_config);
     }
 
     pub async fn remove_server_config(&self, name: &str) -> Option<McpServerConfig> {
+        self.statuses.write().await.remove(name);
+        self.forget_results(name);
+        logs::forget(&self.client_name(name));
+        oauth::sign_out(&self.client_name(name));
+        roots::forget(&self.client_name(name));
         let mut config = self.config.write().await;
         config.remove_server(name)
     }
@@ -71,10 +162,10 @@ impl McpManager {
             match self.initialize_server(name.clone(), server_config).await {
                 Ok(_) => {
                     initialized_count += 1;
-                    println!("Successfully initialized MCP server: {}", name);
+                    tracing::info!("Successfully initialized MCP server: {}", name);
                 }
                 Err(e) => {
-                    eprintln!("Failed to initialize MCP server {}: {}", name, e);
+                    tracing::error!("Failed to initialize MCP server {}: {}", name, e);
                 }
             }
         }
@@ -82,16 +173,32 @@ impl McpManager {
         Ok(initialized_count)
     }
 
+    // Starts the server afresh, with all its restarts ahead of it
     pub async fn initialize_server(
         &self,
         name: String,
         server_config: &McpServerConfig,
     ) -> Result<(), McpManagerError> {
+        let result = self.connect(name.clone(), server_config).await;
+        let mut statuses = self.statuses.write().await;
+        let status = statuses.entry(name).or_default();
+        status.restarts = 0;
+        match &result {
+            Ok(()) => status.set(ServerState::Running, None),
+            Err(e) => status.set(ServerState::Failed, Some(e.to_string())),
+        }
+        result
+    }
+
+    async fn connect(&self, name: String, server_config: &McpServerConfig) -> Result<(), McpManagerError> {
         // Remove existing client if it exists
-        self.shutdown_server(&name).await.ok();
+        self.disconnect(&name).await.ok();
+        self.forget_results(&name);
+
+        roots::set(&self.client_name(&name), server_config.roots.as_deref().unwrap_or_default());
 
         // Create new client
-        let mut client = create_mcp_client(name.clone(), server_config)
+        let mut client = create_mcp_client(self.client_name(&name), server_config, self.owner.is_some())
             .await
             .map_err(|e| McpManagerError::Initialization(name.clone(), e))?;
 
@@ -136,6 +243,13 @@ impl McpManager {
     }
 
     pub async fn shutdown_server(&self, name: &str) -> Result<(), McpManagerError> {
+        if let Some(status) = self.statuses.write().await.get_mut(name) {
+            status.set(ServerState::Stopped, None);
+        }
+        self.disconnect(name).await
+    }
+
+    async fn disconnect(&self, name: &str) -> Result<(), McpManagerError> {
         let client = {
             let mut clients = self.clients.write().await;
             clients.remove(name)
@@ -169,7 +283,7 @@ impl McpManager {
 
         for name in clients {
             if let Err(e) = self.shutdown_server(&name).await {
-                eprintln!("Error shutting down server {}: {}", name, e);
+                tracing::error!("Error shutting down server {}: {}", name, e);
             }
         }
     }
@@ -194,7 +308,30 @@ impl McpManager {
             .get_tool(tool_name)
             .await
             .ok_or_else(|| McpManagerError::ToolNotFound(tool_name.to_string()))?;
+        let Some(cache) = self.result_cache(&tool).await else {
+            return self.run_tool(&tool, arguments, timeout_secs).await;
+        };
+
+        // Error results fail the load as `Ok`, so they're returned but not
+        // kept and the model can try again
+        cache
+            .get_or_load(arguments.to_string(), || async {
+                match self.run_tool(&tool, arguments, timeout_secs).await {
+                    Ok(result) if result.is_error == Some(true) => Err(Ok(result)),
+                    result => result.map_err(Err),
+                }
+            })
+            .await
+            .or_else(|uncached| uncached)
+    }
 
+    async fn run_tool(
+        &self,
+        tool: &McpTool,
+        arguments: serde_json::Value,
+        timeout_secs: Option<u64>,
+    ) -> Result<CallToolResult, McpManagerError> {
+        let tool_name = tool.name.as_str();
         let client = {
             let clients = self.clients.read().await;
             clients
@@ -203,6 +340,10 @@ impl McpManager {
                 .ok_or_else(|| McpManagerError::ServerNotFound(tool.server_name.clone()))?
         };
 
+        let timeout_secs = match timeout_secs {
+            Some(timeout_secs) => Some(timeout_secs),
+            None => self.tool_timeout(tool).await,
+        };
         let server_tool_name = tool.tool_info.name.clone();
         let call_params = CallToolParams {
             name: server_tool_name,
@@ -312,6 +453,103 @@ impl McpManager {
         config.mcp_servers.clone()
     }
 
+    // The timeout of a tool listed in its server's `tool_timeouts`
+    async fn tool_timeout(&self, tool: &McpTool) -> Option<u64> {
+        self.config
+            .read()
+            .await
+            .mcp_servers
+            .get(&tool.server_name)?
+            .tool_timeouts
+            .as_ref()?
+            .get(&tool.tool_info.name)
+            .copied()
+    }
+
+    // The cache of a tool listed in its server's `cache_ttl`
+    async fn result_cache(&self, tool: &McpTool) -> Option<Arc<ToolCache>> {
+        let ttl = *self
+            .config
+            .read()
+            .await
+            .mcp_servers
+            .get(&tool.server_name)?
+            .cache_ttl
+            .as_ref()?
+            .get(&tool.tool_info.name)?;
+        let mut caches = self.result_caches.lock().unwrap();
+        let cache = caches
+            .entry(tool.name.clone())
+            .or_insert_with(|| Arc::new(QueryCache::new(Duration::from_secs(ttl), TOOL_CACHE_SIZE)));
+        Some(cache.clone())
+    }
+
+    // Drops the cached results of a server's tools, when it changes or restarts
+    fn forget_results(&self, server: &str) {
+        let prefix = format!("{}__", server);
+        self.result_caches.lock().unwrap().retain(|tool, _| !tool.starts_with(&prefix));
+    }
+
+    pub async fn get_server_statuses(&self) -> HashMap<String, ServerStatus> {
+        self.statuses.read().await.clone()
+    }
+
+    // One round of the supervisor, see `health::supervise`: servers that
+    // stopped answering are taken down and restarted with backoff
+    pub async fn check_health(&self) {
+        let clients: Vec<_> = {
+            let clients = self.clients.read().await;
+            clients.iter().map(|(name, client)| (name.clone(), client.clone())).collect()
+        };
+        for (name, client) in clients {
+            let result = client.ping().await;
+            drop(client);
+            match result {
+                Ok(()) => {
+                    if let Some(status) = self.statuses.write().await.get_mut(&name) {
+                        status.answered();
+                    }
+                }
+                Err(e) => {
+                    tracing::warn!("MCP server {} is down: {}", name, e);
+                    self.disconnect(&name).await.ok();
+                    self.went_down(&name, e.to_string()).await;
+                }
+            }
+        }
+
+        let due: Vec<String> = {
+            let statuses = self.statuses.read().await;
+            statuses.iter().filter(|(_, status)| status.restart_due()).map(|(name, _)| name.clone()).collect()
+        };
+        for name in due {
+            let Some(server_config) = self.config.read().await.mcp_servers.get(&name).cloned() else {
+                continue;
+            };
+            match self.connect(name.clone(), &server_config).await {
+                Ok(()) => {
+                    tracing::debug!("Restarted MCP server {}", name);
+                    if let Some(status) = self.statuses.write().await.get_mut(&name) {
+                        status.set(ServerState::Running, None);
+                    }
+                }
+                Err(e) => self.went_down(&name, e.to_string()).await,
+            }
+        }
+    }
+
+    async fn went_down(&self, name: &str, error: String) {
+        let max_restarts = self
+            .config
+            .read()
+            .await
+            .mcp_servers
+            .get(name)
+            .and_then(|config| config.max_restarts)
+            .unwrap_or(DEFAULT_MAX_RESTARTS);
+        self.statuses.write().await.entry(name.to_string()).or_default().went_down(error, max_restarts);
+    }
+
     pub async fn get_connected_servers(&self) -> Vec<String> {
         let clients = self.clients.read().await;
         clients.keys().cloned().collect()
@@ -349,6 +587,9 @@ pub enum McpManagerError {
     #[error("Timeout while executing tool '{0}'")]
     Timeout(String),
 
+    #[error("Tool '{0}' was cancelled")]
+    Cancelled(String),
+
     #[error("Failed to discover resources from server '{0}': {1}")]
     ResourceDiscovery(String, McpClientError),
 
@@ -370,3 +611,172 @@ pub fn get_mcp_manager() -> Arc<McpManager> {
     MCP_MANAGER.clone()
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mcp::client::{
+        GetPromptResult, ListPromptsResult, ListResourcesResult, ListToolsResult, McpContent,
+        ReadResourceParams, ReadResourceResult,
+    };
+    use crate::mcp::config::TransportType;
+    use async_trait::async_trait;
+    use serde_json::{json, Value};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Answers tool calls with how many it has had, as an error result when
+    // asked to fail, after sleeping for the milliseconds it's asked to
+    #[derive(Default)]
+    struct CountingClient {
+        calls: AtomicUsize,
+    }
+
+    fn unsupported() -> McpClientError {
+        McpClientError::Protocol("Not supported".to_string())
+    }
+
+    #[async_trait]
+    impl McpClientTrait for CountingClient {
+        async fn initialize(&mut self) -> Result<(), McpClientError> {
+            Ok(())
+        }
+        async fn list_tools(&self) -> Result<ListToolsResult, McpClientError> {
+            Err(unsupported())
+        }
+        async fn call_tool(&self, params: CallToolParams) -> Result<CallToolResult, McpClientError> {
+            let calls = self.calls.fetch_add(1, Ordering::Relaxed) + 1;
+            if let Some(millis) = params.arguments.as_ref().and_then(|arguments| arguments["sleep"].as_u64()) {
+                tokio::time::sleep(Duration::from_millis(millis)).await;
+            }
+            let fail = params.arguments.as_ref().is_some_and(|arguments| arguments["fail"] == true);
+            Ok(CallToolResult {
+                content: vec![McpContent {
+                    r#type: "text".to_string(),
+                    text: Some(calls.to_string()),
+                    data: None,
+                    mime_type: None,
+                }],
+                is_error: Some(fail),
+                structured_content: None,
+                meta: None,
+            })
+        }
+        async fn list_resources(&self) -> Result<ListResourcesResult, McpClientError> {
+            Err(unsupported())
+        }
+        async fn read_resource(&self, _: ReadResourceParams) -> Result<ReadResourceResult, McpClientError> {
+            Err(unsupported())
+        }
+        async fn list_prompts(&self) -> Result<ListPromptsResult, McpClientError> {
+            Err(unsupported())
+        }
+        async fn get_prompt(&self, _: &str, _: Option<Value>) -> Result<GetPromptResult, McpClientError> {
+            Err(unsupported())
+        }
+        fn get_connection_info(&self) -> crate::mcp::client::McpConnectionInfo {
+            crate::mcp::client::McpConnectionInfo {
+                name: "search".to_string(),
+                transport_type: TransportType::Stdio,
+                server_info: None,
+            }
+        }
+        async fn ping(&self) -> Result<(), McpClientError> {
+            Ok(())
+        }
+        async fn roots_changed(&self) -> Result<(), McpClientError> {
+            Ok(())
+        }
+        async fn shutdown(&mut self) -> Result<(), McpClientError> {
+            Ok(())
+        }
+    }
+
+    async fn text(manager: &McpManager, arguments: Value) -> String {
+        let result = manager.call_tool("search__query", arguments, None).await.unwrap();
+        result.content[0].text.clone().unwrap()
+    }
+
+    fn config(servers: Value) -> McpConfig {
+        serde_json::from_value(json!({ "mcp_servers": servers })).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_apply_config() {
+        let manager = McpManager::new();
+        manager.apply_config(config(json!({ "off": { "command": "true", "disabled": true } }))).await;
+        assert!(manager.has_server("off").await);
+        assert!(manager.get_server_statuses().await.is_empty());
+
+        let missing = json!({ "missing": { "command": "/nonexistent/mcp-server" } });
+        manager.apply_config(config(missing.clone())).await;
+        assert!(!manager.has_server("off").await);
+        let statuses = manager.get_server_statuses().await;
+        assert_eq!(statuses["missing"].state, ServerState::Failed);
+
+        // Unchanged servers aren't started again
+        manager.apply_config(config(missing)).await;
+        assert_eq!(manager.get_server_statuses().await["missing"].transitions.len(), 1);
+    }
+
+    // A manager with the `search` server answered by a `CountingClient`
+    async fn counting_manager(server: Value) -> McpManager {
+        let manager = McpManager::new();
+        manager.add_server_config("search".to_string(), serde_json::from_value(server).unwrap()).await;
+        let client: Box<dyn McpClientTrait> = Box::new(CountingClient::default());
+        manager.clients.write().await.insert("search".to_string(), Arc::new(client));
+        manager.tools.write().await.insert(
+            "search__query".to_string(),
+            McpTool {
+                name: "search__query".to_string(),
+                description: String::new(),
+                server_name: "search".to_string(),
+                tool_info: Tool {
+                    name: "query".to_string(),
+                    description: None,
+                    input_schema: json!({}),
+                },
+            },
+        );
+        manager
+    }
+
+    #[tokio::test]
+    async fn test_tool_result_cache() {
+        let manager = counting_manager(json!({ "cache_ttl": { "query": 60 } })).await;
+        assert_eq!(text(&manager, json!({ "q": "rust", "page": 1 })).await, "1");
+        assert_eq!(text(&manager, json!({ "page": 1, "q": "rust" })).await, "1");
+        assert_eq!(text(&manager, json!({ "q": "axum" })).await, "2");
+        // Error results run again every time
+        assert_eq!(text(&manager, json!({ "fail": true })).await, "3");
+        assert_eq!(text(&manager, json!({ "fail": true })).await, "4");
+
+        manager.forget_results("search");
+        assert_eq!(text(&manager, json!({ "q": "rust", "page": 1 })).await, "5");
+    }
+
+    #[tokio::test]
+    async fn test_tool_timeouts() {
+        let manager = counting_manager(json!({ "timeout": 60, "tool_timeouts": { "query": 1 } })).await;
+        let slow = manager.call_tool("search__query", json!({ "sleep": 1500 }), None).await;
+        assert!(matches!(slow, Err(McpManagerError::Timeout(_))));
+        // A timeout asked for by the caller wins
+        let waited = manager.call_tool("search__query", json!({ "sleep": 1500 }), Some(5)).await;
+        assert!(waited.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_update_roots() {
+        let manager = counting_manager(json!({ "command": "files", "roots": ["/srv/a"] })).await;
+        let config = |server: Value| serde_json::from_value::<McpServerConfig>(server).unwrap();
+
+        // Other changes need a restart
+        assert!(!manager.update_roots("search", &config(json!({ "command": "files", "roots": ["/srv/a"] }))).await);
+        assert!(!manager.update_roots("search", &config(json!({ "command": "other", "roots": ["/srv/b"] }))).await);
+        assert!(!manager.update_roots("missing", &config(json!({ "command": "files" }))).await);
+
+        assert!(manager.update_roots("search", &config(json!({ "command": "files", "roots": ["/srv/b"] }))).await);
+        let saved = manager.get_server_configs().await.remove("search").unwrap();
+        assert_eq!(saved.roots, Some(vec!["/srv/b".to_string()]));
+        assert_eq!(roots::list("search")["roots"][0]["uri"], "file:///srv/b");
+    }
+}
@@ -1,7 +1,20 @@
+pub mod cancel;
 pub mod client;
 pub mod config;
+pub mod health;
+pub mod http;
+pub mod jsonrpc;
+pub mod logs;
 pub mod manager;
+pub mod oauth;
+pub mod registry;
+pub mod roots;
+pub mod sampling;
+pub mod sse;
+pub mod stdio;
 pub mod tools;
+pub mod users;
+pub mod watch;
 
 pub use client::*;
 pub use config::*;
@@ -0,0 +1,459 @@
+use chrono::{DateTime, Utc};
+use reqwest::Url;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use super::client::McpClientError;
+use crate::data::repository::ChatRepository;
+use crate::encryption::Encryption;
+use crate::oauth::{pkce_challenge, Attempt};
+use crate::utils::outbound;
+
+// How long the user has to finish authorizing at the server
+const ATTEMPT_TTL: Duration = Duration::from_secs(600);
+// Tokens this close to expiring are refreshed before they're sent
+const REFRESH_MARGIN: chrono::Duration = chrono::Duration::seconds(60);
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, thiserror::Error)]
+pub enum McpOAuthError {
+    #[error("Request to the authorization server failed: {0}")]
+    Http(#[from] reqwest::Error),
+
+    #[error("The authorization server refused: {0}")]
+    Refused(String),
+
+    #[error("The server doesn't support {0}")]
+    Unsupported(&'static str),
+
+    #[error("The authorization was cancelled or has expired")]
+    UnknownAttempt,
+
+    // See `outbound::check_url`
+    #[error("Not contacting the authorization server: {0}")]
+    PrivateAddress(String),
+}
+
+// What's kept in `mcp_servers.oauth` once the user authorized the server
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OAuthTokens {
+    // The server URL they were issued for; they aren't sent anywhere else
+    pub resource: String,
+    pub token_endpoint: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl OAuthTokens {
+    fn is_expiring(&self) -> bool {
+        self.expires_at.is_some_and(|at| at - REFRESH_MARGIN <= Utc::now())
+    }
+}
+
+// Protected resource metadata (RFC 9728), naming the server's authorization server
+#[derive(Deserialize)]
+struct ResourceMetadata {
+    #[serde(default)]
+    authorization_servers: Vec<String>,
+    #[serde(default)]
+    scopes_supported: Vec<String>,
+}
+
+// Authorization server metadata (RFC 8414) or its OpenID Connect equivalent
+#[derive(Debug, Deserialize)]
+struct AuthServer {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    registration_endpoint: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Registration {
+    client_id: String,
+    client_secret: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: Option<String>,
+    refresh_token: Option<String>,
+    expires_in: Option<i64>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+// The well-known URLs of `url` for `suffix`, the path of `url` inserted
+// after it first as RFC 8414 has it, then at the root
+fn well_known(url: &Url, suffix: &str) -> Vec<Url> {
+    let path = url.path().trim_end_matches('/');
+    let mut paths = Vec::new();
+    if !path.is_empty() {
+        paths.push(format!("/.well-known/{}{}", suffix, path));
+    }
+    paths.push(format!("/.well-known/{}", suffix));
+    paths.into_iter().filter_map(|path| url.join(&path).ok()).collect()
+}
+
+// The first of `urls` answering with the document, if any
+async fn fetch_first<T: DeserializeOwned>(client: &reqwest::Client, urls: Vec<Url>) -> Option<T> {
+    for url in urls {
+        if outbound::check_url(url.as_str()).await.is_err() {
+            continue;
+        }
+        let Ok(response) = client.get(url).send().await else {
+            continue;
+        };
+        if !response.status().is_success() {
+            continue;
+        }
+        if let Ok(document) = response.json().await {
+            return Some(document);
+        }
+    }
+    None
+}
+
+// The endpoints and scopes to authorize at for the server at `resource`.
+// Servers without protected resource metadata are their own authorization
+// server, and those without its metadata use the default endpoints
+async fn discover(client: &reqwest::Client, resource: &Url) -> Result<(AuthServer, Vec<String>), McpOAuthError> {
+    let metadata: Option<ResourceMetadata> = fetch_first(client, well_known(resource, "oauth-protected-resource")).await;
+    let (issuer, scopes) = match metadata {
+        Some(metadata) if !metadata.authorization_servers.is_empty() => {
+            let issuer = Url::parse(&metadata.authorization_servers[0])
+                .map_err(|_| McpOAuthError::Refused("invalid authorization server URL".to_string()))?;
+            (issuer, metadata.scopes_supported)
+        }
+        _ => (resource.join("/").expect("a URL's root is a URL"), Vec::new()),
+    };
+
+    let mut urls = well_known(&issuer, "oauth-authorization-server");
+    urls.extend(well_known(&issuer, "openid-configuration"));
+    let server = match fetch_first(client, urls).await {
+        Some(server) => server,
+        None => {
+            let endpoint = |path: &str| issuer.join(path).expect("an absolute path").to_string();
+            AuthServer {
+                authorization_endpoint: endpoint("/authorize"),
+                token_endpoint: endpoint("/token"),
+                registration_endpoint: Some(endpoint("/register")),
+            }
+        }
+    };
+    Ok((server, scopes))
+}
+
+// Dynamic client registration (RFC 7591), as a public client
+async fn register(
+    client: &reqwest::Client,
+    server: &AuthServer,
+    redirect_uri: &str,
+) -> Result<Registration, McpOAuthError> {
+    let endpoint = server
+        .registration_endpoint
+        .as_deref()
+        .ok_or(McpOAuthError::Unsupported("dynamic client registration"))?;
+    outbound::check_url(endpoint).await.map_err(McpOAuthError::PrivateAddress)?;
+    let response = client
+        .post(endpoint)
+        .json(&json!({
+            "client_name": "RustGPT",
+            "redirect_uris": [redirect_uri],
+            "grant_types": ["authorization_code", "refresh_token"],
+            "response_types": ["code"],
+            "token_endpoint_auth_method": "none",
+        }))
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        return Err(McpOAuthError::Refused(format!("registration returned {}: {}", status, body.trim())));
+    }
+    Ok(response.json().await?)
+}
+
+async fn token_request(
+    token_endpoint: &str,
+    client_id: &str,
+    client_secret: Option<&str>,
+    params: &[(&str, &str)],
+) -> Result<TokenResponse, McpOAuthError> {
+    let mut form = vec![("client_id", client_id)];
+    form.extend_from_slice(params);
+    if let Some(secret) = client_secret {
+        form.push(("client_secret", secret));
+    }
+    outbound::check_url(token_endpoint).await.map_err(McpOAuthError::PrivateAddress)?;
+    // Errors come as JSON with a 400, so the body is read either way
+    let token: TokenResponse = http_client()?
+        .post(token_endpoint)
+        .header(reqwest::header::ACCEPT, "application/json")
+        .form(&form)
+        .send()
+        .await?
+        .json()
+        .await?;
+    if token.access_token.is_none() {
+        let reason = token.error_description.or(token.error).unwrap_or_else(|| "no access token".to_string());
+        return Err(McpOAuthError::Refused(reason));
+    }
+    Ok(token)
+}
+
+fn expires_at(token: &TokenResponse) -> Option<DateTime<Utc>> {
+    token.expires_in.map(|seconds| Utc::now() + chrono::Duration::seconds(seconds))
+}
+
+// The endpoints come from the servers' metadata, so like the servers
+// themselves they have to be public
+fn http_client() -> Result<reqwest::Client, McpOAuthError> {
+    Ok(outbound::client_builder()
+        .user_agent("rustgpt")
+        .timeout(FETCH_TIMEOUT)
+        .build()?)
+}
+
+// An authorization the user started, until the server redirects back
+struct Pending {
+    user_id: i64,
+    server: String,
+    resource: String,
+    redirect_uri: String,
+    verifier: String,
+    token_endpoint: String,
+    client_id: String,
+    client_secret: Option<String>,
+    started: Instant,
+}
+
+static PENDING: LazyLock<Mutex<HashMap<String, Pending>>> = LazyLock::new(Default::default);
+
+// The page where the user authorizes us at the server's authorization
+// server, after registering a client there. `redirect_uri` gets the code
+// and the state for `finish`
+pub async fn authorize_url(
+    user_id: i64,
+    server: &str,
+    resource: &str,
+    redirect_uri: &str,
+) -> Result<Url, McpOAuthError> {
+    let resource_url =
+        Url::parse(resource).map_err(|_| McpOAuthError::Refused(format!("invalid server URL {}", resource)))?;
+    let client = http_client()?;
+    let (auth_server, scopes) = discover(&client, &resource_url).await?;
+    let registration = register(&client, &auth_server, redirect_uri).await?;
+
+    let attempt = Attempt::start();
+    let challenge = pkce_challenge(&attempt.verifier);
+    let mut params = vec![
+        ("response_type", "code"),
+        ("client_id", registration.client_id.as_str()),
+        ("redirect_uri", redirect_uri),
+        ("state", attempt.state.as_str()),
+        ("code_challenge", challenge.as_str()),
+        ("code_challenge_method", "S256"),
+        ("resource", resource),
+    ];
+    let scope = scopes.join(" ");
+    if !scope.is_empty() {
+        params.push(("scope", &scope));
+    }
+    let url = Url::parse_with_params(&auth_server.authorization_endpoint, params)
+        .map_err(|_| McpOAuthError::Refused("invalid authorization endpoint".to_string()))?;
+
+    let mut pending = PENDING.lock().unwrap();
+    pending.retain(|_, attempt| attempt.started.elapsed() < ATTEMPT_TTL);
+    pending.insert(
+        attempt.state,
+        Pending {
+            user_id,
+            server: server.to_string(),
+            resource: resource.to_string(),
+            redirect_uri: redirect_uri.to_string(),
+            verifier: attempt.verifier,
+            token_endpoint: auth_server.token_endpoint,
+            client_id: registration.client_id,
+            client_secret: registration.client_secret,
+            started: Instant::now(),
+        },
+    );
+    Ok(url)
+}
+
+// Trades the code the server redirected back with for tokens. The server
+// they're for comes with them
+pub async fn finish(user_id: i64, state: &str, code: &str) -> Result<(String, OAuthTokens), McpOAuthError> {
+    // Each attempt is good for a single callback
+    let pending = PENDING
+        .lock()
+        .unwrap()
+        .remove(state)
+        .filter(|pending| pending.user_id == user_id && pending.started.elapsed() < ATTEMPT_TTL)
+        .ok_or(McpOAuthError::UnknownAttempt)?;
+
+    let token = token_request(
+        &pending.token_endpoint,
+        &pending.client_id,
+        pending.client_secret.as_deref(),
+        &[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &pending.redirect_uri),
+            ("code_verifier", &pending.verifier),
+            ("resource", &pending.resource),
+        ],
+    )
+    .await?;
+
+    let tokens = OAuthTokens {
+        expires_at: expires_at(&token),
+        resource: pending.resource,
+        token_endpoint: pending.token_endpoint,
+        client_id: pending.client_id,
+        client_secret: pending.client_secret,
+        access_token: token.access_token.unwrap_or_default(),
+        refresh_token: token.refresh_token,
+    };
+    Ok((pending.server, tokens))
+}
+
+async fn refresh(tokens: &OAuthTokens) -> Result<OAuthTokens, McpOAuthError> {
+    let refresh_token = tokens
+        .refresh_token
+        .as_deref()
+        .ok_or(McpOAuthError::Unsupported("refreshing tokens"))?;
+    let token = token_request(
+        &tokens.token_endpoint,
+        &tokens.client_id,
+        tokens.client_secret.as_deref(),
+        &[
+            ("grant_type", "refresh_token"),
+            ("refresh_token", refresh_token),
+            ("resource", &tokens.resource),
+        ],
+    )
+    .await?;
+    Ok(OAuthTokens {
+        expires_at: expires_at(&token),
+        access_token: token.access_token.unwrap_or_default(),
+        // Servers that don't rotate it leave it out
+        refresh_token: token.refresh_token.or_else(|| tokens.refresh_token.clone()),
+        ..tokens.clone()
+    })
+}
+
+// The tokens of a user's server, saved again when refreshed
+struct Session {
+    user_id: i64,
+    server: String,
+    repo: ChatRepository,
+    // Tokens are stored encrypted, like the user's other secrets
+    encryption: Encryption,
+    tokens: tokio::sync::Mutex<OAuthTokens>,
+}
+
+// By client name, see `McpManager::client_name`
+static SESSIONS: LazyLock<Mutex<HashMap<String, Arc<Session>>>> = LazyLock::new(Default::default);
+
+// The server's requests carry the tokens from now on
+pub fn sign_in(
+    client: &str,
+    user_id: i64,
+    server: &str,
+    tokens: OAuthTokens,
+    repo: &ChatRepository,
+    encryption: &Encryption,
+) {
+    let session = Session {
+        user_id,
+        server: server.to_string(),
+        repo: repo.clone(),
+        encryption: encryption.clone(),
+        tokens: tokio::sync::Mutex::new(tokens),
+    };
+    SESSIONS.lock().unwrap().insert(client.to_string(), Arc::new(session));
+}
+
+pub fn sign_out(client: &str) {
+    SESSIONS.lock().unwrap().remove(client);
+}
+
+pub fn is_signed_in(client: &str) -> bool {
+    SESSIONS.lock().unwrap().contains_key(client)
+}
+
+// The access token for the server's requests to `url`, refreshed first when
+// about to expire. None when the user hasn't authorized the server
+pub async fn access_token(client: &str, url: &str) -> Result<Option<String>, McpClientError> {
+    let Some(session) = SESSIONS.lock().unwrap().get(client).cloned() else {
+        return Ok(None);
+    };
+    let mut tokens = session.tokens.lock().await;
+    if tokens.resource != url {
+        return Ok(None);
+    }
+    if tokens.is_expiring() && tokens.refresh_token.is_some() {
+        let refreshed = refresh(&tokens).await.map_err(|e| {
+            McpClientError::Transport(format!("Failed to refresh the OAuth token, authorize the server again: {}", e))
+        })?;
+        let stored = session.encryption.encrypt(&serde_json::to_string(&refreshed)?);
+        if let Err(e) = session
+            .repo
+            .save_mcp_server_oauth(session.user_id, &session.server, Some(&stored))
+            .await
+        {
+            tracing::error!("Failed to save the OAuth token of MCP server {}: {}", client, e);
+        }
+        *tokens = refreshed;
+    }
+    Ok(Some(tokens.access_token.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_well_known() {
+        let url = Url::parse("https://mcp.example.com/tenant/mcp/").unwrap();
+        let urls: Vec<String> = well_known(&url, "oauth-protected-resource")
+            .iter()
+            .map(Url::to_string)
+            .collect();
+        assert_eq!(
+            urls,
+            [
+                "https://mcp.example.com/.well-known/oauth-protected-resource/tenant/mcp",
+                "https://mcp.example.com/.well-known/oauth-protected-resource",
+            ]
+        );
+        let issuer = Url::parse("https://auth.example.com").unwrap();
+        assert_eq!(well_known(&issuer, "oauth-authorization-server").len(), 1);
+    }
+
+    #[test]
+    fn test_tokens_expiring() {
+        let mut tokens = OAuthTokens {
+            resource: "https://mcp.example.com/mcp".to_string(),
+            token_endpoint: "https://auth.example.com/token".to_string(),
+            client_id: "client".to_string(),
+            client_secret: None,
+            access_token: "access".to_string(),
+            refresh_token: Some("refresh".to_string()),
+            expires_at: None,
+        };
+        assert!(!tokens.is_expiring());
+        tokens.expires_at = Some(Utc::now() + chrono::Duration::seconds(30));
+        assert!(tokens.is_expiring());
+        tokens.expires_at = Some(Utc::now() + chrono::Duration::hours(1));
+        assert!(!tokens.is_expiring());
+    }
+}
@@ -0,0 +1,363 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use super::config::{McpServerConfig, TransportType};
+use crate::data::cache::QueryCache;
+
+// The official registry; `MCP_REGISTRY_URL` may point at a curated one
+// answering in the same format
+const DEFAULT_REGISTRY_URL: &str = "https://registry.modelcontextprotocol.io/v0/servers";
+// Servers fetched per search
+const PAGE_SIZE: usize = 50;
+// Searches are kept this long, the registry changes slowly
+const LISTING_TTL: Duration = Duration::from_secs(3600);
+const LISTING_CACHE_SIZE: usize = 50;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+static LISTINGS: LazyLock<QueryCache<String, Vec<RegistryServer>>> =
+    LazyLock::new(|| QueryCache::new(LISTING_TTL, LISTING_CACHE_SIZE));
+
+// A server the registry lists that can be run from here: from its first
+// npm, PyPI or OCI package over stdio, else its first remote
+#[derive(Debug, Clone, Serialize)]
+pub struct RegistryServer {
+    pub name: String,
+    // For the server in settings, from the last part of `name`
+    pub suggested_name: String,
+    pub description: String,
+    pub version: Option<String>,
+    pub repository: Option<String>,
+    // The command line or URL, for display
+    pub runs: String,
+    pub remote: bool,
+    // What to ask for before installing, like API keys
+    pub inputs: Vec<RegistryInput>,
+    #[serde(skip)]
+    install: Install,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RegistryInput {
+    pub name: String,
+    pub description: Option<String>,
+    pub required: bool,
+    pub secret: bool,
+    // Set in the server's headers rather than its env
+    pub header: bool,
+}
+
+#[derive(Debug, Clone)]
+enum Install {
+    Command(String, Vec<String>),
+    Remote(TransportType, String),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RegistryError {
+    #[error("Failed to fetch the registry: {0}")]
+    Fetch(#[from] reqwest::Error),
+    #[error("{0} isn't in the registry")]
+    NotFound(String),
+    #[error("{0} is required")]
+    MissingInput(String),
+}
+
+// The registry's answer, see the server.json schema at
+// https://github.com/modelcontextprotocol/registry
+#[derive(Deserialize)]
+struct Listing {
+    servers: Vec<Entry>,
+}
+
+// Newer registries wrap each server with its metadata
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Entry {
+    Wrapped { server: ServerJson },
+    Bare(ServerJson),
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ServerJson {
+    name: String,
+    #[serde(default)]
+    description: String,
+    version: Option<String>,
+    repository: Option<Repository>,
+    #[serde(default)]
+    packages: Vec<Package>,
+    #[serde(default)]
+    remotes: Vec<Remote>,
+}
+
+#[derive(Deserialize)]
+struct Repository {
+    url: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Package {
+    registry_type: String,
+    identifier: String,
+    version: Option<String>,
+    transport: Option<PackageTransport>,
+    #[serde(default)]
+    environment_variables: Vec<Variable>,
+}
+
+#[derive(Deserialize)]
+struct PackageTransport {
+    r#type: String,
+}
+
+#[derive(Deserialize)]
+struct Remote {
+    r#type: String,
+    url: String,
+    #[serde(default)]
+    headers: Vec<Variable>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Variable {
+    name: String,
+    description: Option<String>,
+    #[serde(default)]
+    is_required: bool,
+    #[serde(default)]
+    is_secret: bool,
+}
+
+fn inputs(variables: Vec<Variable>, header: bool) -> Vec<RegistryInput> {
+    variables
+        .into_iter()
+        .map(|variable| RegistryInput {
+            name: variable.name,
+            description: variable.description,
+            required: variable.is_required,
+            secret: variable.is_secret,
+            header,
+        })
+        .collect()
+}
+
+// The command running a package, `None` for registries we can't run
+fn package_command(package: &Package) -> Option<(String, Vec<String>)> {
+    let pinned = |separator: &str| match &package.version {
+        Some(version) => format!("{}{}{}", package.identifier, separator, version),
+        None => package.identifier.clone(),
+    };
+    match package.registry_type.as_str() {
+        "npm" => Some(("npx".to_string(), vec!["-y".to_string(), pinned("@")])),
+        "pypi" => Some(("uvx".to_string(), vec![pinned("==")])),
+        // The container only sees the env it's handed with `-e`
+        "oci" => {
+            let mut args = vec!["run".to_string(), "-i".to_string(), "--rm".to_string()];
+            for variable in &package.environment_variables {
+                args.push("-e".to_string());
+                args.push(variable.name.clone());
+            }
+            args.push(package.identifier.clone());
+            Some(("docker".to_string(), args))
+        }
+        _ => None,
+    }
+}
+
+impl ServerJson {
+    fn into_server(self) -> Option<RegistryServer> {
+        let stdio = self
+            .packages
+            .into_iter()
+            .filter(|package| package.transport.as_ref().is_none_or(|t| t.r#type == "stdio"))
+            .find_map(|package| package_command(&package).map(|command| (command, package)));
+        let (runs, inputs, install) = match stdio {
+            Some(((command, args), package)) => (
+                format!("{} {}", command, args.join(" ")),
+                inputs(package.environment_variables, false),
+                Install::Command(command, args),
+            ),
+            None => {
+                let remote = self.remotes.into_iter().find_map(|remote| {
+                    let transport = match remote.r#type.as_str() {
+                        "streamable-http" => TransportType::Http,
+                        "sse" => TransportType::Sse,
+                        _ => return None,
+                    };
+                    Some((transport, remote))
+                })?;
+                let (transport, remote) = remote;
+                (remote.url.clone(), inputs(remote.headers, true), Install::Remote(transport, remote.url))
+            }
+        };
+
+        let last = self.name.rsplit('/').next().unwrap_or(&self.name);
+        let suggested_name = last
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '-' })
+            .collect::<String>()
+            .trim_matches('-')
+            .to_string();
+        Some(RegistryServer {
+            remote: matches!(install, Install::Remote(..)),
+            suggested_name,
+            name: self.name,
+            description: self.description,
+            version: self.version,
+            repository: self.repository.and_then(|repository| repository.url),
+            runs,
+            inputs,
+            install,
+        })
+    }
+}
+
+fn parse_listing(listing: Listing) -> Vec<RegistryServer> {
+    listing
+        .servers
+        .into_iter()
+        .filter_map(|entry| match entry {
+            Entry::Wrapped { server } | Entry::Bare(server) => server.into_server(),
+        })
+        .collect()
+}
+
+// The servers matching `search`, or the first page of all of them
+pub async fn search(search: &str) -> Result<Vec<RegistryServer>, RegistryError> {
+    LISTINGS
+        .get_or_load(search.to_string(), || async {
+            let url = dotenv::var("MCP_REGISTRY_URL").unwrap_or_else(|_| DEFAULT_REGISTRY_URL.to_string());
+            let mut query = vec![("limit", PAGE_SIZE.to_string())];
+            if !search.is_empty() {
+                query.push(("search", search.to_string()));
+            }
+            let listing: Listing = reqwest::Client::builder()
+                .user_agent("rustgpt")
+                .timeout(FETCH_TIMEOUT)
+                .build()?
+                .get(url)
+                .query(&query)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            Ok(parse_listing(listing))
+        })
+        .await
+}
+
+pub async fn find(name: &str) -> Result<RegistryServer, RegistryError> {
+    search(name)
+        .await?
+        .into_iter()
+        .find(|server| server.name == name)
+        .ok_or_else(|| RegistryError::NotFound(name.to_string()))
+}
+
+impl RegistryServer {
+    // The server's config with the values the user gave for its inputs
+    pub fn config(&self, values: &HashMap<String, String>) -> Result<McpServerConfig, RegistryError> {
+        let mut env = HashMap::new();
+        let mut headers = HashMap::new();
+        for input in &self.inputs {
+            match values.get(&input.name).map(|value| value.trim()).filter(|value| !value.is_empty()) {
+                Some(value) if input.header => {
+                    headers.insert(input.name.clone(), value.to_string());
+                }
+                Some(value) => {
+                    env.insert(input.name.clone(), value.to_string());
+                }
+                None if input.required => return Err(RegistryError::MissingInput(input.name.clone())),
+                None => {}
+            }
+        }
+
+        let mut config = McpServerConfig {
+            command: None,
+            args: None,
+            env: (!env.is_empty()).then_some(env),
+            disabled: None,
+            timeout: None,
+            description: Some(self.description.clone()).filter(|description| !description.is_empty()),
+            transport: None,
+            url: None,
+            headers: (!headers.is_empty()).then_some(headers),
+            max_restarts: None,
+            cache_ttl: None,
+            tool_timeouts: None,
+            roots: None,
+        };
+        match &self.install {
+            Install::Command(command, args) => {
+                config.transport = Some(TransportType::Stdio);
+                config.command = Some(command.clone());
+                config.args = Some(args.clone());
+            }
+            Install::Remote(transport, url) => {
+                config.transport = Some(transport.clone());
+                config.url = Some(url.clone());
+            }
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_registry_servers() {
+        let listing = json!({
+            "servers": [
+                { "server": {
+                    "name": "io.github.example/weather",
+                    "description": "Forecasts",
+                    "version": "1.2.0",
+                    "packages": [{
+                        "registryType": "npm",
+                        "identifier": "@example/weather",
+                        "version": "1.2.0",
+                        "transport": { "type": "stdio" },
+                        "environmentVariables": [
+                            { "name": "WEATHER_KEY", "isRequired": true, "isSecret": true },
+                            { "name": "UNITS" }
+                        ]
+                    }]
+                }, "_meta": {} },
+                { "name": "com.example/search", "remotes": [{
+                    "type": "streamable-http",
+                    "url": "https://search.example.com/mcp",
+                    "headers": [{ "name": "Authorization", "isRequired": true }]
+                }] },
+                { "server": { "name": "com.example/dotnet", "packages": [{ "registryType": "nuget", "identifier": "Example" }] } }
+            ]
+        });
+        let servers = parse_listing(serde_json::from_value(listing).unwrap());
+        assert_eq!(servers.len(), 2);
+
+        let weather = &servers[0];
+        assert_eq!(weather.suggested_name, "weather");
+        assert!(!weather.remote);
+        assert_eq!(weather.runs, "npx -y @example/weather@1.2.0");
+        assert!(matches!(weather.config(&HashMap::new()), Err(RegistryError::MissingInput(_))));
+        let values = HashMap::from([("WEATHER_KEY".to_string(), "secret".to_string())]);
+        let config = weather.config(&values).unwrap();
+        assert_eq!(config.command.as_deref(), Some("npx"));
+        assert_eq!(config.env, Some(values));
+
+        let search = &servers[1];
+        let values = HashMap::from([("Authorization".to_string(), "Bearer token".to_string())]);
+        let config = search.config(&values).unwrap();
+        assert_eq!(config.transport, Some(TransportType::Http));
+        assert_eq!(config.headers, Some(values));
+        assert!(config.env.is_none());
+    }
+}
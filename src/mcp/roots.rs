@@ -0,0 +1,61 @@
+use reqwest::Url;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{LazyLock, Mutex};
+
+// The directories each server may work in, from its `roots`, by client name,
+// see `McpManager::client_name`. Servers ask for them with `roots/list`, and
+// filesystem servers keep to them
+static ROOTS: LazyLock<Mutex<HashMap<String, Vec<String>>>> = LazyLock::new(Default::default);
+
+// Roots must be absolute, as they're sent as file URIs
+pub fn is_valid(directory: &str) -> bool {
+    Path::new(directory).is_absolute()
+}
+
+pub fn set(client: &str, directories: &[String]) {
+    ROOTS.lock().unwrap().insert(client.to_string(), directories.to_vec());
+}
+
+pub fn forget(client: &str) {
+    ROOTS.lock().unwrap().remove(client);
+}
+
+// The `roots/list` result; none for servers without roots
+pub fn list(client: &str) -> Value {
+    let directories = ROOTS.lock().unwrap().get(client).cloned().unwrap_or_default();
+    let roots: Vec<Value> = directories.iter().filter_map(|directory| root(directory)).collect();
+    json!({ "roots": roots })
+}
+
+fn root(directory: &str) -> Option<Value> {
+    let uri = Url::from_directory_path(directory).ok()?;
+    let name = Path::new(directory)
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_else(|| directory.to_string());
+    Some(json!({ "uri": uri.as_str().trim_end_matches('/'), "name": name }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roots() {
+        assert!(is_valid("/srv/projects"));
+        assert!(!is_valid("projects"));
+
+        set("files (user 1)", &["/srv/my projects".to_string(), "/home/user/notes/".to_string()]);
+        assert_eq!(
+            list("files (user 1)"),
+            json!({ "roots": [
+                { "uri": "file:///srv/my%20projects", "name": "my projects" },
+                { "uri": "file:///home/user/notes", "name": "notes" },
+            ] })
+        );
+        forget("files (user 1)");
+        assert_eq!(list("files (user 1)"), json!({ "roots": [] }));
+    }
+}
@@ -0,0 +1,358 @@
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+// How long a sampling request waits for the user to answer
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(300);
+
+// A `sampling/createMessage` request from an MCP server, waiting for the user
+// whose tool call made it
+#[derive(Debug, Clone, Serialize)]
+pub struct SamplingRequest {
+    pub id: u64,
+    pub server: String,
+    pub user_id: i64,
+    pub chat_id: i64,
+    pub system_prompt: Option<String>,
+    pub messages: Vec<SamplingMessage>,
+    pub max_tokens: i64,
+    pub temperature: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SamplingMessage {
+    pub role: String,
+    pub text: String,
+}
+
+impl SamplingRequest {
+    // The request in the chat completions format, system prompt first
+    pub fn completion_messages(&self) -> Vec<Value> {
+        let system = self
+            .system_prompt
+            .iter()
+            .map(|prompt| json!({ "role": "system", "content": prompt }));
+        let messages = self
+            .messages
+            .iter()
+            .map(|message| json!({ "role": message.role, "content": message.text }));
+        system.chain(messages).collect()
+    }
+}
+
+// The result the server gets for an approved request
+pub fn create_message_result(text: &str, model: &str, finish_reason: Option<&str>) -> Value {
+    let stop_reason = match finish_reason {
+        Some("length") => "maxTokens",
+        Some("stop") | None => "endTurn",
+        Some(other) => other,
+    };
+    json!({
+        "role": "assistant",
+        "content": { "type": "text", "text": text },
+        "model": model,
+        "stopReason": stop_reason,
+    })
+}
+
+// Limits on what servers may spend of a user's provider, from
+// `MCP_SAMPLING_MAX_TOKENS` per request and `MCP_SAMPLING_BUDGET` for all
+// the requests of one tool call. A budget of 0 turns sampling off
+struct Limits {
+    max_tokens: i64,
+    budget: i64,
+}
+
+fn env_tokens(var: &str, default: i64) -> i64 {
+    dotenv::var(var)
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .filter(|tokens: &i64| *tokens >= 0)
+        .unwrap_or(default)
+}
+
+impl Limits {
+    fn from_env() -> Self {
+        Limits {
+            max_tokens: env_tokens("MCP_SAMPLING_MAX_TOKENS", 1_000),
+            budget: env_tokens("MCP_SAMPLING_BUDGET", 4_000),
+        }
+    }
+}
+
+// A tool call in progress, which sampling requests from its server are made for
+struct Caller {
+    call: u64,
+    user_id: i64,
+    chat_id: i64,
+    tokens_left: i64,
+}
+
+type Answer = oneshot::Sender<Result<Value, String>>;
+
+#[derive(Default)]
+struct Broker {
+    callers: Mutex<HashMap<String, Vec<Caller>>>,
+    pending: Mutex<HashMap<u64, (u64, SamplingRequest, Answer)>>,
+    next_id: AtomicU64,
+}
+
+static BROKER: LazyLock<Broker> = LazyLock::new(Broker::default);
+
+tokio::task_local! {
+    // The tool call `on_behalf_of` is running, for requests its server makes
+    // while the call is being answered on the same task
+    static CURRENT_CALL: u64;
+}
+
+fn token_of(call: u64) -> String {
+    format!("sampling-{}", call)
+}
+
+// The progress token of the tool call being run, sent with it so the server
+// can tag the sampling requests it makes for the call
+pub fn progress_token() -> Option<String> {
+    CURRENT_CALL.try_with(|call| token_of(*call)).ok()
+}
+
+// The tool call a sampling request is for: the one it arrived while
+// answering (streamable HTTP reads server requests on the call's own task),
+// the one whose progress token it carries, or the only one running on the
+// server. Calls of several users can't be told apart otherwise, and a request
+// put to the wrong one would show them another user's prompt and spend their
+// key, so it's refused
+fn caller_for<'a>(callers: &'a mut [Caller], params: &Value) -> Result<&'a mut Caller, String> {
+    let token = params.pointer("/_meta/progressToken").and_then(Value::as_str);
+    let current = CURRENT_CALL.try_with(|call| *call).ok();
+    let position = match callers
+        .iter()
+        .position(|caller| current == Some(caller.call) || token == Some(token_of(caller.call).as_str()))
+    {
+        Some(position) => position,
+        None if callers.len() == 1 => 0,
+        None => return Err("Several tool calls are running on this server, so the request can't be matched to one".to_string()),
+    };
+    Ok(&mut callers[position])
+}
+
+// Ends the tool call's turn at sampling when dropped, declining whatever it
+// still has waiting
+struct CallGuard {
+    server: String,
+    call: u64,
+}
+
+impl Drop for CallGuard {
+    fn drop(&mut self) {
+        let mut callers = BROKER.callers.lock().unwrap();
+        if let Some(list) = callers.get_mut(&self.server) {
+            list.retain(|caller| caller.call != self.call);
+            if list.is_empty() {
+                callers.remove(&self.server);
+            }
+        }
+        drop(callers);
+        BROKER.pending.lock().unwrap().retain(|_, (call, _, _)| *call != self.call);
+    }
+}
+
+// Runs a tool call of `user_id` in `chat_id`, so sampling requests the server
+// makes meanwhile are put to that user
+pub async fn on_behalf_of<F: Future>(server: &str, user_id: i64, chat_id: i64, call: F) -> F::Output {
+    let id = BROKER.next_id.fetch_add(1, Ordering::Relaxed);
+    BROKER.callers.lock().unwrap().entry(server.to_string()).or_default().push(Caller {
+        call: id,
+        user_id,
+        chat_id,
+        tokens_left: Limits::from_env().budget,
+    });
+    let _guard = CallGuard { server: server.to_string(), call: id };
+    CURRENT_CALL.scope(id, call).await
+}
+
+// Answers a `sampling/createMessage` request once the user approves it; the
+// error is what the server is told otherwise
+pub async fn create_message(server: &str, params: &Value) -> Result<Value, String> {
+    let limits = Limits::from_env();
+    let (system_prompt, messages) = parse_messages(params)?;
+    let requested = params.get("maxTokens").and_then(Value::as_i64).unwrap_or(limits.max_tokens);
+
+    let (receiver, id) = {
+        let mut callers = BROKER.callers.lock().unwrap();
+        let list = callers
+            .get_mut(server)
+            .filter(|list| !list.is_empty())
+            .ok_or("Sampling is only available during a tool call")?;
+        let caller = caller_for(list, params)?;
+        let max_tokens = requested.min(limits.max_tokens).min(caller.tokens_left);
+        if max_tokens <= 0 {
+            return Err("The sampling budget for this tool call is used up".to_string());
+        }
+        caller.tokens_left -= max_tokens;
+
+        let id = BROKER.next_id.fetch_add(1, Ordering::Relaxed);
+        let request = SamplingRequest {
+            id,
+            server: server.to_string(),
+            user_id: caller.user_id,
+            chat_id: caller.chat_id,
+            system_prompt,
+            messages,
+            max_tokens,
+            temperature: params.get("temperature").and_then(Value::as_f64),
+        };
+        let (sender, receiver) = oneshot::channel();
+        BROKER.pending.lock().unwrap().insert(id, (caller.call, request, sender));
+        (receiver, id)
+    };
+
+    let answer = tokio::time::timeout(ANSWER_TIMEOUT, receiver).await;
+    BROKER.pending.lock().unwrap().remove(&id);
+    match answer {
+        Ok(Ok(answer)) => answer,
+        Ok(Err(_)) => Err("The tool call ended before the user answered".to_string()),
+        Err(_) => Err("The user didn't answer in time".to_string()),
+    }
+}
+
+// The sampling requests waiting for the user, oldest first
+pub fn pending_for(user_id: i64) -> Vec<SamplingRequest> {
+    let pending = BROKER.pending.lock().unwrap();
+    let mut requests: Vec<SamplingRequest> = pending
+        .values()
+        .filter(|(_, request, _)| request.user_id == user_id)
+        .map(|(_, request, _)| request.clone())
+        .collect();
+    requests.sort_by_key(|request| request.id);
+    requests
+}
+
+// Takes the user's request off the queue so it can be answered with `respond`
+pub fn take(id: u64, user_id: i64) -> Option<(SamplingRequest, Answer)> {
+    let mut pending = BROKER.pending.lock().unwrap();
+    if pending.get(&id).is_none_or(|(_, request, _)| request.user_id != user_id) {
+        return None;
+    }
+    pending.remove(&id).map(|(_, request, answer)| (request, answer))
+}
+
+// The text of each message; images and audio can't be passed on
+fn parse_messages(params: &Value) -> Result<(Option<String>, Vec<SamplingMessage>), String> {
+    let system_prompt = params.get("systemPrompt").and_then(Value::as_str).map(String::from);
+    let messages = params
+        .get("messages")
+        .and_then(Value::as_array)
+        .ok_or("The request has no messages")?
+        .iter()
+        .map(|message| {
+            let role = message.get("role").and_then(Value::as_str).unwrap_or("user");
+            let content = &message["content"];
+            match content.get("type").and_then(Value::as_str) {
+                Some("text") => Ok(SamplingMessage {
+                    role: role.to_string(),
+                    text: content.get("text").and_then(Value::as_str).unwrap_or_default().to_string(),
+                }),
+                other => Err(format!("Unsupported {} content", other.unwrap_or("unknown"))),
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((system_prompt, messages))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sampling_requests() {
+        let params = json!({
+            "systemPrompt": "Be brief",
+            "messages": [{ "role": "user", "content": { "type": "text", "text": "Summarize this" } }],
+            "maxTokens": 1_000_000,
+        });
+        let error = create_message("sampler", &params).await.unwrap_err();
+        assert!(error.contains("only available during a tool call"));
+
+        let call = on_behalf_of("sampler", 7, 3, async {
+            let sampled = tokio::spawn(async move { create_message("sampler", &params).await });
+            let request = loop {
+                if let Some(request) = pending_for(7).pop() {
+                    break request;
+                }
+                tokio::task::yield_now().await;
+            };
+            assert!(pending_for(8).is_empty());
+            assert!(take(request.id, 8).is_none());
+            assert_eq!(request.chat_id, 3);
+            assert!(request.max_tokens <= Limits::from_env().max_tokens);
+            assert_eq!(request.completion_messages()[0], json!({ "role": "system", "content": "Be brief" }));
+
+            let (_, answer) = take(request.id, 7).unwrap();
+            answer.send(Ok(create_message_result("Done", "model", Some("length")))).unwrap();
+            sampled.await.unwrap()
+        });
+        let result = call.await.unwrap();
+        assert_eq!(result["content"]["text"], "Done");
+        assert_eq!(result["stopReason"], "maxTokens");
+
+        let image = json!({ "messages": [{ "role": "user", "content": { "type": "image", "data": "" } }] });
+        assert_eq!(parse_messages(&image).unwrap_err(), "Unsupported image content");
+    }
+
+    // Approves the user's next request with `text`, returning the request
+    async fn answer_next(user_id: i64, text: &str) -> SamplingRequest {
+        let request = loop {
+            if let Some(request) = pending_for(user_id).pop() {
+                break request;
+            }
+            tokio::task::yield_now().await;
+        };
+        let (request, answer) = take(request.id, user_id).unwrap();
+        answer.send(Ok(create_message_result(text, "model", None))).unwrap();
+        request
+    }
+
+    #[tokio::test]
+    async fn test_sampling_with_two_callers() {
+        let params = json!({ "messages": [{ "role": "user", "content": { "type": "text", "text": "Hi" } }] });
+
+        // User 8's call runs on the same global server until told to stop
+        let (token_sender, token_receiver) = oneshot::channel();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let other = tokio::spawn(on_behalf_of("shared", 8, 2, async move {
+            token_sender.send(progress_token().unwrap()).unwrap();
+            stopped.await.unwrap();
+        }));
+        let other_token = token_receiver.await.unwrap();
+
+        on_behalf_of("shared", 7, 1, async {
+            // Untagged and from another task, it could be either call's
+            let untagged = params.clone();
+            let error = tokio::spawn(async move { create_message("shared", &untagged).await }).await.unwrap();
+            assert!(error.unwrap_err().contains("Several tool calls"));
+
+            // Made while answering user 7's call, on its task
+            let (result, request) = tokio::join!(create_message("shared", &params), answer_next(7, "For 7"));
+            assert_eq!(result.unwrap()["content"]["text"], "For 7");
+            assert_eq!(request.chat_id, 1);
+
+            // Tagged with the progress token of user 8's call
+            let mut tagged = params.clone();
+            tagged["_meta"] = json!({ "progressToken": other_token });
+            let sampled = tokio::spawn(async move { create_message("shared", &tagged).await });
+            let request = answer_next(8, "For 8").await;
+            assert_eq!(request.chat_id, 2);
+            assert_eq!(sampled.await.unwrap().unwrap()["content"]["text"], "For 8");
+            assert!(pending_for(7).is_empty());
+        })
+        .await;
+
+        stop.send(()).unwrap();
+        other.await.unwrap();
+    }
+}
@@ -0,0 +1,229 @@
+use async_trait::async_trait;
+use futures::StreamExt;
+use reqwest::Url;
+use reqwest_eventsource::{Event, EventSource};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{oneshot, watch};
+use tokio::task::JoinHandle;
+
+use super::client::{McpClientError, McpTransport};
+use super::config::McpServerConfig;
+use super::http::{check_status, client_for, send_error};
+use super::jsonrpc::{self, Message};
+
+type Waiting = Mutex<HashMap<i64, oneshot::Sender<Result<Value, McpClientError>>>>;
+
+// The legacy MCP SSE transport: a GET opens an event stream whose first
+// `endpoint` event says where to POST messages, and responses come back as
+// `message` events on the stream. The stream reconnects on its own; each
+// reconnect starts a new session at a new endpoint, so the handshake is
+// replayed there
+pub struct SseTransport {
+    inner: Arc<Inner>,
+    reader: JoinHandle<()>,
+}
+
+struct Inner {
+    name: String,
+    client: reqwest::Client,
+    // The messages endpoint of the current session, None while (re)connecting
+    endpoint: watch::Sender<Option<Url>>,
+    waiting: Waiting,
+    // The initialize params, kept to start a new session after a reconnect
+    handshake: Mutex<Option<Value>>,
+    next_id: AtomicI64,
+}
+
+impl SseTransport {
+    pub fn connect(name: &str, config: &McpServerConfig, public_only: bool) -> Result<Self, McpClientError> {
+        let url = config.url.as_deref().ok_or_else(|| {
+            McpClientError::Configuration("URL is required for SSE transport".to_string())
+        })?;
+        let url = Url::parse(url)
+            .map_err(|e| McpClientError::Configuration(format!("Invalid URL {}: {}", url, e)))?;
+        let client = client_for(config, public_only)?;
+        let source = EventSource::new(client.get(url.clone()))
+            .map_err(|e| McpClientError::Configuration(e.to_string()))?;
+
+        let inner = Arc::new(Inner {
+            name: name.to_string(),
+            client,
+            endpoint: watch::Sender::new(None),
+            waiting: Waiting::default(),
+            handshake: Mutex::new(None),
+            next_id: AtomicI64::new(1),
+        });
+        let reader = tokio::spawn(read_events(inner.clone(), url, source));
+
+        Ok(SseTransport { inner, reader })
+    }
+}
+
+#[async_trait]
+impl McpTransport for SseTransport {
+    async fn request(&self, method: &str, params: Value, timeout: Duration) -> Result<Value, McpClientError> {
+        if method == "initialize" {
+            *self.inner.handshake.lock().unwrap() = Some(params.clone());
+        }
+        self.inner.request(method, params, timeout).await
+    }
+
+    async fn notify(&self, method: &str, params: Value) -> Result<(), McpClientError> {
+        self.inner.notify(method, params).await
+    }
+
+    async fn close(&self) -> Result<(), McpClientError> {
+        self.reader.abort();
+        self.inner.endpoint.send_replace(None);
+        self.inner.fail_waiting();
+        Ok(())
+    }
+}
+
+impl Drop for SseTransport {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+impl Inner {
+    async fn request(&self, method: &str, params: Value, timeout: Duration) -> Result<Value, McpClientError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+        let message = jsonrpc::request(id, method, params);
+
+        let sent = tokio::time::timeout(timeout, async {
+            let endpoint = self.current_endpoint().await?;
+            self.waiting.lock().unwrap().insert(id, sender);
+            self.post(endpoint, &message).await?;
+            receiver.await.unwrap_or_else(|_| Err(disconnected()))
+        })
+        .await;
+
+        match sent {
+            Ok(result) => {
+                self.waiting.lock().unwrap().remove(&id);
+                result
+            }
+            Err(_) => {
+                self.waiting.lock().unwrap().remove(&id);
+                let cancel = json!({ "requestId": id, "reason": "Timed out" });
+                if let Err(e) = self.notify("notifications/cancelled", cancel).await {
+                    tracing::error!("Failed to cancel request {} on MCP server {}: {}", id, self.name, e);
+                }
+                Err(McpClientError::Timeout)
+            }
+        }
+    }
+
+    async fn notify(&self, method: &str, params: Value) -> Result<(), McpClientError> {
+        let endpoint = self.endpoint.borrow().clone().ok_or_else(disconnected)?;
+        self.post(endpoint, &jsonrpc::notification(method, params)).await
+    }
+
+    // Waits out a reconnect when there's no session right now
+    async fn current_endpoint(&self) -> Result<Url, McpClientError> {
+        let mut endpoint = self.endpoint.subscribe();
+        let endpoint = endpoint.wait_for(Option::is_some).await.map_err(|_| disconnected())?;
+        endpoint.clone().ok_or_else(disconnected)
+    }
+
+    // The server acknowledges with 202 Accepted; any answer arrives on the stream
+    async fn post(&self, endpoint: Url, message: &Value) -> Result<(), McpClientError> {
+        let response = self.client.post(endpoint).json(message).send().await.map_err(send_error)?;
+        check_status(response).await?;
+        Ok(())
+    }
+
+    fn fail_waiting(&self) {
+        for (_, sender) in self.waiting.lock().unwrap().drain() {
+            let _ = sender.send(Err(disconnected()));
+        }
+    }
+
+    async fn resume(self: Arc<Self>, timeout: Duration) {
+        let Some(params) = self.handshake.lock().unwrap().clone() else {
+            return;
+        };
+        let resumed = match self.request("initialize", params, timeout).await {
+            Ok(_) => self.notify("notifications/initialized", json!({})).await,
+            Err(e) => Err(e),
+        };
+        match resumed {
+            Ok(()) => tracing::info!("MCP server {} reconnected", self.name),
+            Err(e) => tracing::error!("Failed to resume the session with MCP server {}: {}", self.name, e),
+        }
+    }
+}
+
+fn disconnected() -> McpClientError {
+    McpClientError::Transport("The connection to the server was lost".to_string())
+}
+
+async fn read_events(inner: Arc<Inner>, url: Url, mut source: EventSource) {
+    let mut connected_before = false;
+    while let Some(event) = source.next().await {
+        match event {
+            Ok(Event::Open) => {}
+            Ok(Event::Message(event)) if event.event == "endpoint" => {
+                let endpoint = match url.join(event.data.trim()) {
+                    Ok(endpoint) => endpoint,
+                    Err(e) => {
+                        tracing::warn!("MCP server {} sent an invalid endpoint {}: {}", inner.name, event.data, e);
+                        continue;
+                    }
+                };
+                // Messages only go back where the stream came from
+                if endpoint.origin() != url.origin() {
+                    tracing::warn!("MCP server {} sent an endpoint on another origin: {}", inner.name, endpoint);
+                    continue;
+                }
+                inner.endpoint.send_replace(Some(endpoint));
+                if std::mem::replace(&mut connected_before, true) {
+                    tokio::spawn(inner.clone().resume(Duration::from_secs(30)));
+                }
+            }
+            Ok(Event::Message(event)) => {
+                let Ok(message) = serde_json::from_str::<Value>(&event.data) else {
+                    tracing::warn!("Ignoring event from MCP server {} that isn't JSON-RPC: {}", inner.name, event.data);
+                    continue;
+                };
+                match jsonrpc::classify(&message) {
+                    Message::Request(id, method) => {
+                        let (inner, id, method) = (inner.clone(), id.clone(), method.to_string());
+                        tokio::spawn(async move {
+                            let reply = jsonrpc::answer(&inner.name, &id, &method, &message["params"]).await;
+                            let endpoint = inner.endpoint.borrow().clone();
+                            if let Some(endpoint) = endpoint {
+                                if let Err(e) = inner.post(endpoint, &reply).await {
+                                    tracing::error!("Failed to answer MCP server {}: {}", inner.name, e);
+                                }
+                            }
+                        });
+                    }
+                    Message::Notification => {}
+                    Message::Response(id) => {
+                        let sender = id.and_then(|id| inner.waiting.lock().unwrap().remove(&id));
+                        if let Some(sender) = sender {
+                            let _ = sender.send(jsonrpc::response_result(&message));
+                        }
+                    }
+                    Message::Invalid => {
+                        tracing::warn!("Ignoring malformed message from MCP server {}: {}", inner.name, event.data)
+                    }
+                }
+            }
+            // The source retries with a backoff; responses on the old stream are lost
+            Err(e) => {
+                if inner.endpoint.send_replace(None).is_some() {
+                    tracing::warn!("Lost the connection to MCP server {}, reconnecting: {}", inner.name, e);
+                }
+                inner.fail_waiting();
+            }
+        }
+    }
+}
@@ -0,0 +1,364 @@
+use async_trait::async_trait;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStderr, ChildStdin, ChildStdout, Command};
+use tokio::sync::{oneshot, Mutex as TokioMutex};
+
+use super::client::{McpClientError, McpTransport};
+use super::config::McpServerConfig;
+use super::jsonrpc::{self, Message};
+use super::logs::{self, ServerLog};
+
+// How long a server gets to exit after its stdin closes before it's killed
+const EXIT_GRACE: Duration = Duration::from_secs(2);
+
+type Waiting = Arc<Mutex<HashMap<i64, oneshot::Sender<Result<Value, McpClientError>>>>>;
+type Input = Arc<TokioMutex<Option<ChildStdin>>>;
+
+// JSON-RPC 2.0 with a child process over its stdin and stdout, one message
+// per line as the MCP stdio transport frames them. Responses are matched to
+// their request by id, so calls can overlap and notifications or log lines
+// the server prints in between are skipped rather than taken for a response
+pub struct StdioTransport {
+    name: String,
+    child: TokioMutex<Child>,
+    stdin: Input,
+    waiting: Waiting,
+    next_id: AtomicI64,
+}
+
+impl StdioTransport {
+    pub fn spawn(name: &str, config: &McpServerConfig) -> Result<Self, McpClientError> {
+        let command = config.command.as_deref().ok_or_else(|| {
+            McpClientError::Configuration("Command is required for stdio transport".to_string())
+        })?;
+        let expand = |text: &str, used_in: &str| {
+            expand_env(text, |name| dotenv::var(name).ok())
+                .map_err(|e| McpClientError::Configuration(format!("{}, used in {}", e, used_in)))
+        };
+        let command = expand(command, "the command")?;
+        let args = config
+            .args
+            .iter()
+            .flatten()
+            .map(|arg| expand(arg, "the args"))
+            .collect::<Result<Vec<_>, _>>()?;
+        let env = config
+            .env
+            .iter()
+            .flatten()
+            .map(|(key, value)| Ok((key, expand(value, &format!("env {}", key))?)))
+            .collect::<Result<Vec<_>, McpClientError>>()?;
+
+        let mut child = Command::new(command)
+            .args(args)
+            .envs(env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| McpClientError::Process(format!("Failed to spawn process: {}", e)))?;
+
+        let stdin = child
+            .stdin
+            .take()
+            .ok_or_else(|| McpClientError::Process("Failed to get stdin handle".to_string()))?;
+        let stdout = child
+            .stdout
+            .take()
+            .ok_or_else(|| McpClientError::Process("Failed to get stdout handle".to_string()))?;
+        let stderr = child
+            .stderr
+            .take()
+            .ok_or_else(|| McpClientError::Process("Failed to get stderr handle".to_string()))?;
+
+        let log = logs::server_log(name);
+        let stdin: Input = Arc::new(TokioMutex::new(Some(stdin)));
+        let waiting = Waiting::default();
+        tokio::spawn(read_messages(name.to_string(), stdout, stdin.clone(), waiting.clone(), log.clone()));
+        tokio::spawn(read_stderr(name.to_string(), stderr, log));
+
+        Ok(StdioTransport {
+            name: name.to_string(),
+            child: TokioMutex::new(child),
+            stdin,
+            waiting,
+            next_id: AtomicI64::new(1),
+        })
+    }
+}
+
+#[async_trait]
+impl McpTransport for StdioTransport {
+    // Sends a request and waits for the response with the same id. Requests
+    // that time out, or that are dropped because the tool call was cancelled,
+    // are cancelled on the server too
+    async fn request(&self, method: &str, params: Value, timeout: Duration) -> Result<Value, McpClientError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+        self.waiting.lock().unwrap().insert(id, sender);
+        let mut in_flight = InFlight { transport: self, id, cancel_reason: Some("Cancelled") };
+
+        let message = jsonrpc::request(id, method, params);
+        if let Err(e) = write_message(&self.stdin, &message).await {
+            in_flight.cancel_reason = None;
+            return Err(e);
+        }
+
+        match tokio::time::timeout(timeout, receiver).await {
+            Ok(Ok(result)) => {
+                in_flight.cancel_reason = None;
+                result
+            }
+            Ok(Err(_)) => {
+                in_flight.cancel_reason = None;
+                Err(closed())
+            }
+            Err(_) => {
+                in_flight.cancel_reason = Some("Timed out");
+                Err(McpClientError::Timeout)
+            }
+        }
+    }
+
+    async fn notify(&self, method: &str, params: Value) -> Result<(), McpClientError> {
+        write_message(&self.stdin, &jsonrpc::notification(method, params)).await
+    }
+
+    // Why the server process is gone, if it is
+    async fn exited(&self) -> Option<String> {
+        match self.child.lock().await.try_wait() {
+            Ok(Some(status)) => Some(format!("The server exited ({})", status)),
+            Ok(None) => None,
+            Err(e) => Some(e.to_string()),
+        }
+    }
+
+    // Closing stdin asks the server to exit; it's killed if it doesn't
+    async fn close(&self) -> Result<(), McpClientError> {
+        self.stdin.lock().await.take();
+        let mut child = self.child.lock().await;
+        if tokio::time::timeout(EXIT_GRACE, child.wait()).await.is_err() {
+            child.kill().await?;
+        }
+        Ok(())
+    }
+}
+
+// A request until its response is in. Dropping it forgets the request and,
+// with a `cancel_reason`, tells the server to stop working on it
+struct InFlight<'a> {
+    transport: &'a StdioTransport,
+    id: i64,
+    cancel_reason: Option<&'static str>,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.transport.waiting.lock().unwrap().remove(&self.id);
+        let Some(reason) = self.cancel_reason else {
+            return;
+        };
+        let (name, stdin, id) = (self.transport.name.clone(), self.transport.stdin.clone(), self.id);
+        tokio::spawn(async move {
+            let cancel = jsonrpc::notification("notifications/cancelled", json!({ "requestId": id, "reason": reason }));
+            if let Err(e) = write_message(&stdin, &cancel).await {
+                tracing::error!("Failed to cancel request {} on MCP server {}: {}", id, name, e);
+            }
+        });
+    }
+}
+
+fn closed() -> McpClientError {
+    McpClientError::Transport("The server closed the connection".to_string())
+}
+
+async fn write_message(stdin: &Input, message: &Value) -> Result<(), McpClientError> {
+    let mut line = serde_json::to_string(message)?;
+    line.push('\n');
+
+    let mut stdin = stdin.lock().await;
+    let stdin = stdin.as_mut().ok_or_else(closed)?;
+    stdin.write_all(line.as_bytes()).await?;
+    stdin.flush().await?;
+    Ok(())
+}
+
+// Keeps what the server prints to stderr in its log, still echoed to ours
+async fn read_stderr(name: String, stderr: ChildStderr, log: Arc<ServerLog>) {
+    let mut lines = BufReader::new(stderr).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        tracing::info!("[{}] {}", name, line);
+        log.push("stderr", line);
+    }
+}
+
+// Hands responses to the requests waiting for them until the server exits,
+// then fails the ones still waiting
+async fn read_messages(name: String, stdout: ChildStdout, stdin: Input, waiting: Waiting, log: Arc<ServerLog>) {
+    let mut lines = BufReader::new(stdout).lines();
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e) => {
+                tracing::error!("Failed to read from MCP server {}: {}", name, e);
+                break;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+        let Ok(message) = serde_json::from_str::<Value>(&line) else {
+            tracing::warn!("Ignoring output of MCP server {} that isn't JSON-RPC: {}", name, line);
+            log.push("stdout", line);
+            continue;
+        };
+
+        match jsonrpc::classify(&message) {
+            Message::Request(id, method) => {
+                let (name, stdin) = (name.clone(), stdin.clone());
+                let (id, method) = (id.clone(), method.to_string());
+                tokio::spawn(async move {
+                    let reply = jsonrpc::answer(&name, &id, &method, &message["params"]).await;
+                    if let Err(e) = write_message(&stdin, &reply).await {
+                        tracing::error!("Failed to answer MCP server {}: {}", name, e);
+                    }
+                });
+            }
+            Message::Notification => {}
+            Message::Response(id) => {
+                let sender = id.and_then(|id| waiting.lock().unwrap().remove(&id));
+                if let Some(sender) = sender {
+                    let _ = sender.send(jsonrpc::response_result(&message));
+                }
+            }
+            Message::Invalid => tracing::warn!("Ignoring malformed message from MCP server {}: {}", name, line),
+        }
+    }
+
+    for (_, sender) in waiting.lock().unwrap().drain() {
+        let _ = sender.send(Err(closed()));
+    }
+}
+
+// Replaces `${NAME}` with the variable `var` gives for it, so secrets can
+// stay out of mcp.json; `$${` is a literal `${`
+fn expand_env(text: &str, var: impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut expanded = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            expanded.push_str(&rest[..start - 1]);
+            expanded.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let end = after.find('}').ok_or_else(|| "A ${ isn't closed".to_string())?;
+        let name = &after[..end];
+        expanded.push_str(&var(name).ok_or_else(|| format!("The environment variable {} isn't set", name))?);
+        rest = &after[end + 1..];
+    }
+    Ok(expanded + rest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_env() {
+        let var = |name: &str| (name == "TOKEN").then(|| "secret".to_string());
+        assert_eq!(expand_env("Bearer ${TOKEN}", var).unwrap(), "Bearer secret");
+        assert_eq!(expand_env("${TOKEN}:${TOKEN}", var).unwrap(), "secret:secret");
+        assert_eq!(expand_env("$HOME and $${HOME}", var).unwrap(), "$HOME and ${HOME}");
+        assert_eq!(expand_env("${MISSING}", var).unwrap_err(), "The environment variable MISSING isn't set");
+        assert!(expand_env("${TOKEN", var).is_err());
+    }
+
+    // Answers every request with its own id after a notification and a log
+    // line, like real servers interleave them
+    fn echo_server() -> McpServerConfig {
+        let script = r#"while IFS= read -r line; do
+  id=$(printf '%s' "$line" | sed -n 's/.*"id":\([0-9]*\).*/\1/p')
+  [ -n "$id" ] || continue
+  echo 'starting up'
+  printf '{"jsonrpc":"2.0","method":"notifications/message","params":{}}\n'
+  if [ "$id" = 2 ]; then
+    printf '{"jsonrpc":"2.0","id":%s,"error":{"code":-32602,"message":"Bad params"}}\n' "$id"
+  else
+    printf '{"jsonrpc":"2.0","id":%s,"result":{"echo":%s}}\n' "$id" "$id"
+  fi
+done"#;
+        McpServerConfig {
+            command: Some("sh".to_string()),
+            args: Some(vec!["-c".to_string(), script.to_string()]),
+            env: None,
+            disabled: None,
+            timeout: None,
+            description: None,
+            transport: None,
+            url: None,
+            headers: None,
+            max_restarts: None,
+            cache_ttl: None,
+            tool_timeouts: None,
+            roots: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stdio_requests() {
+        let transport = StdioTransport::spawn("echo", &echo_server()).unwrap();
+        let timeout = Duration::from_secs(5);
+
+        let result = transport.request("tools/list", json!({}), timeout).await.unwrap();
+        assert_eq!(result, json!({ "echo": 1 }));
+        assert_eq!(logs::server_log("echo").lines()[0].text, "starting up");
+        let error = transport.request("tools/call", json!({}), timeout).await.unwrap_err();
+        assert!(error.to_string().contains("Bad params (code -32602)"));
+        let result = transport.request("tools/list", json!({}), timeout).await.unwrap();
+        assert_eq!(result, json!({ "echo": 3 }));
+
+        assert!(transport.exited().await.is_none());
+        transport.close().await.unwrap();
+        assert!(transport.exited().await.is_some());
+        assert!(transport.request("tools/list", json!({}), timeout).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dropped_request_is_cancelled() {
+        // Never answers the first request, and reports the last cancellation
+        let script = r#"cancelled=null
+while IFS= read -r line; do
+  case "$line" in
+    *notifications/cancelled*) cancelled=$(printf '%s' "$line" | sed -n 's/.*"requestId":\([0-9]*\).*/\1/p') ;;
+    *'"id":1,'*) ;;
+    *)
+      id=$(printf '%s' "$line" | sed -n 's/.*"id":\([0-9]*\).*/\1/p')
+      printf '{"jsonrpc":"2.0","id":%s,"result":{"cancelled":%s}}\n' "$id" "$cancelled" ;;
+  esac
+done"#;
+        let config = McpServerConfig { args: Some(vec!["-c".to_string(), script.to_string()]), ..echo_server() };
+        let transport = StdioTransport::spawn("silent", &config).unwrap();
+        let timeout = Duration::from_secs(5);
+
+        // Dropped while waiting, as when the user cancels the tool call
+        let request = transport.request("tools/call", json!({}), timeout);
+        assert!(tokio::time::timeout(Duration::from_millis(200), request).await.is_err());
+        assert!(transport.waiting.lock().unwrap().is_empty());
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let result = transport.request("tools/list", json!({}), timeout).await.unwrap();
+        assert_eq!(result, json!({ "cancelled": 1 }));
+        transport.close().await.unwrap();
+    }
+}
@@ -1,7 +1,8 @@
 use serde_json::Value;
 use std::collections::HashMap;
 
-use super::manager::{get_mcp_manager, McpManagerError};
+use super::manager::McpManagerError;
+use super::users::UserMcp;
 use crate::ai::stream::GenerationEvent;
 
 #[derive(Debug, Clone)]
@@ -25,9 +26,8 @@ pub struct McpContent {
     pub mime_type: Option<String>,
 }
 
-pub async fn execute_mcp_tool(tool_call: &McpToolCall) -> Result<McpToolResult, McpManagerError> {
-    let manager = get_mcp_manager();
-    let call_result = manager
+pub async fn execute_mcp_tool(mcp: &UserMcp, tool_call: &McpToolCall) -> Result<McpToolResult, McpManagerError> {
+    let call_result = mcp
         .call_tool(&tool_call.name, tool_call.arguments.clone(), None)
         .await?;
 
@@ -46,9 +46,8 @@ pub async fn execute_mcp_tool(tool_call: &McpToolCall) -> Result<McpToolResult,
     })
 }
 
-pub async fn get_available_tools() -> Result<Vec<crate::data::model::ToolInfo>, McpManagerError> {
-    let manager = get_mcp_manager();
-    let mcp_tools = manager.get_all_tools().await;
+pub async fn get_available_tools(mcp: &UserMcp) -> Result<Vec<crate::data::model::ToolInfo>, McpManagerError> {
+    let mcp_tools = mcp.get_all_tools().await;
 
     let mut tools = Vec::new();
 
@@ -142,6 +141,7 @@ pub fn parse_tool_call_from_ai(tool_call: &crate::data::model::ToolCall) -> Opti
 use tokio::sync::mpsc;
 
 pub async fn execute_mcp_tool_streaming(
+    mcp: &UserMcp,
     tool_call: &McpToolCall,
     mut sender: mpsc::Sender<Result<GenerationEvent, axum::Error>>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
@@ -156,7 +156,7 @@ pub async fn execute_mcp_tool_streaming(
     }
 
     // Execute the tool
-    match execute_mcp_tool(tool_call).await {
+    match execute_mcp_tool(mcp, tool_call).await {
         Ok(result) => {
             // Send tool result as text
             if let Some(openai_result) = format_tool_result_for_openai(&result).await {
@@ -186,6 +186,34 @@ pub async fn execute_mcp_tool_streaming(
     Ok(())
 }
 
+// What's wrong with the complete arguments a model gave a tool, checked
+// against the parameters the tool was offered with, so the model can correct
+// them rather than the tool failing on them. No arguments are an empty
+// object, and parameters that aren't a valid schema aren't checked
+pub fn argument_errors(parameters: &Value, arguments: &str) -> Vec<String> {
+    let arguments = match arguments.trim() {
+        "" => Value::Object(Default::default()),
+        arguments => match serde_json::from_str::<Value>(arguments) {
+            Ok(arguments) => arguments,
+            Err(e) => return vec![format!("- the arguments aren't valid JSON: {}", e)],
+        },
+    };
+    let validator = match jsonschema::validator_for(parameters) {
+        Ok(validator) => validator,
+        Err(e) => {
+            tracing::warn!("Not checking tool arguments against an invalid schema: {}", e);
+            return Vec::new();
+        }
+    };
+    validator
+        .iter_errors(&arguments)
+        .map(|error| match error.instance_path().to_string() {
+            path if path.is_empty() => format!("- {}", error),
+            path => format!("- {}: {}", path, error),
+        })
+        .collect()
+}
+
 // Security and permission utilities
 pub fn validate_tool_call(tool_name: &str, arguments: &Value) -> Result<(), SecurityError> {
     // Basic security checks
@@ -306,3 +334,32 @@ pub mod builtin {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_argument_errors() {
+        let parameters = json!({
+            "type": "object",
+            "properties": {
+                "city": { "type": "string" },
+                "days": { "type": "integer", "minimum": 1 }
+            },
+            "required": ["city"]
+        });
+        assert!(argument_errors(&parameters, r#"{"city":"Oslo","days":3}"#).is_empty());
+
+        let errors = argument_errors(&parameters, r#"{"days":0}"#);
+        assert_eq!(errors.len(), 2);
+        assert!(errors.iter().any(|error| error.starts_with("- /days: ")));
+        assert!(errors.iter().any(|error| error.contains("\"city\" is a required property")));
+
+        assert_eq!(argument_errors(&parameters, r#"{"city":"#).len(), 1);
+        assert_eq!(argument_errors(&parameters, "").len(), 1);
+        assert!(argument_errors(&json!({ "type": "object" }), "").is_empty());
+        assert!(argument_errors(&json!({ "type": 5 }), "{}").is_empty());
+    }
+}
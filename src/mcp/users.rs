@@ -0,0 +1,208 @@
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock};
+use tokio::sync::{Mutex, OnceCell};
+
+use super::client::{CallToolResult, GetPromptResult, ReadResourceResult};
+use super::config::McpServerConfig;
+use super::health;
+use super::manager::{get_mcp_manager, McpManager, McpManagerError, McpTool};
+use super::oauth::{self, OAuthTokens};
+use crate::data::repository::ChatRepository;
+use crate::encryption::Encryption;
+
+type UserManagers = Mutex<HashMap<i64, Arc<OnceCell<Arc<McpManager>>>>>;
+
+// Each user's own servers run in a manager of their own, started the first
+// time the user needs them
+static USER_MANAGERS: LazyLock<UserManagers> = LazyLock::new(UserManagers::default);
+
+// The manager of the servers the user added in settings. Their stored
+// secrets are decrypted with `encryption`
+pub async fn user_manager(repo: &ChatRepository, encryption: &Encryption, user_id: i64) -> Arc<McpManager> {
+    let cell = USER_MANAGERS.lock().await.entry(user_id).or_default().clone();
+    let started = cell
+        .get_or_try_init(|| async {
+            let manager = Arc::new(McpManager::for_user(user_id));
+            for server in repo.get_mcp_servers(user_id).await? {
+                let config = serde_json::from_str::<McpServerConfig>(&server.config)
+                    .map_err(|e| e.to_string())
+                    .and_then(|config| config.opened(encryption).map_err(|e| e.to_string()));
+                match config {
+                    Ok(config) => {
+                        if let Some(stored) = server.oauth.as_deref() {
+                            let tokens = encryption
+                                .decrypt(stored)
+                                .map_err(|e| e.to_string())
+                                .and_then(|tokens| serde_json::from_str::<OAuthTokens>(&tokens).map_err(|e| e.to_string()));
+                            match tokens {
+                                Ok(tokens) => {
+                                    let client = manager.client_name(&server.name);
+                                    oauth::sign_in(&client, user_id, &server.name, tokens, repo, encryption);
+                                }
+                                Err(e) => tracing::warn!("Ignoring OAuth tokens of MCP server {}: {}", server.name, e),
+                            }
+                        }
+                        manager.add_server_config(server.name, config).await
+                    }
+                    Err(e) => tracing::warn!("Ignoring MCP server {} of user {}: {}", server.name, user_id, e),
+                }
+            }
+            if let Err(e) = manager.initialize_servers().await {
+                tracing::error!("Failed to start the MCP servers of user {}: {}", user_id, e);
+            }
+            health::supervise(&manager);
+            Ok::<_, sqlx::Error>(manager)
+        })
+        .await;
+    match started {
+        Ok(manager) => manager.clone(),
+        // Tried again next time
+        Err(e) => {
+            tracing::error!("Failed to load the MCP servers of user {}: {}", user_id, e);
+            Arc::new(McpManager::for_user(user_id))
+        }
+    }
+}
+
+pub async fn for_user(repo: &ChatRepository, encryption: &Encryption, user_id: i64) -> UserMcp {
+    UserMcp {
+        own: Some(user_manager(repo, encryption, user_id).await),
+        global: get_mcp_manager(),
+    }
+}
+
+pub async fn shutdown_user_servers() {
+    let managers: Vec<_> = USER_MANAGERS.lock().await.drain().collect();
+    for (_, cell) in managers {
+        if let Some(manager) = cell.get() {
+            manager.shutdown_all().await;
+        }
+    }
+}
+
+// The MCP servers a user's chats can use: their own, then the global ones
+// from mcp.json. Their own server shadows a global one of the same name, so
+// importing a global server to change its settings replaces it
+#[derive(Clone)]
+pub struct UserMcp {
+    own: Option<Arc<McpManager>>,
+    global: Arc<McpManager>,
+}
+
+impl UserMcp {
+    // For work done on no one's behalf
+    pub fn global_only() -> Self {
+        UserMcp {
+            own: None,
+            global: get_mcp_manager(),
+        }
+    }
+
+    async fn is_own(&self, server: &str) -> bool {
+        match &self.own {
+            Some(own) => own.has_server(server).await,
+            None => false,
+        }
+    }
+
+    // The manager running the server
+    pub async fn manager_of(&self, server: &str) -> Option<Arc<McpManager>> {
+        if self.is_own(server).await {
+            return self.own.clone();
+        }
+        self.global.has_server(server).await.then(|| self.global.clone())
+    }
+
+    pub async fn get_all_tools(&self) -> Vec<McpTool> {
+        let mut tools = match &self.own {
+            Some(own) => own.get_all_tools().await,
+            None => Vec::new(),
+        };
+        for tool in self.global.get_all_tools().await {
+            if !self.is_own(&tool.server_name).await {
+                tools.push(tool);
+            }
+        }
+        tools
+    }
+
+    pub async fn get_tool(&self, name: &str) -> Option<McpTool> {
+        if let Some(tool) = self.tool_manager(name).await {
+            return tool.get_tool(name).await;
+        }
+        None
+    }
+
+    async fn tool_manager(&self, name: &str) -> Option<Arc<McpManager>> {
+        if let Some(own) = &self.own {
+            if own.get_tool(name).await.is_some() {
+                return Some(own.clone());
+            }
+        }
+        let tool = self.global.get_tool(name).await?;
+        (!self.is_own(&tool.server_name).await).then(|| self.global.clone())
+    }
+
+    pub async fn get_connected_servers(&self) -> Vec<String> {
+        let mut servers = match &self.own {
+            Some(own) => own.get_connected_servers().await,
+            None => Vec::new(),
+        };
+        for server in self.global.get_connected_servers().await {
+            if !self.is_own(&server).await {
+                servers.push(server);
+            }
+        }
+        servers.sort();
+        servers
+    }
+
+    // What sampling requests from the server are known by, see `sampling`
+    pub async fn client_name(&self, server: &str) -> String {
+        match self.manager_of(server).await {
+            Some(manager) => manager.client_name(server),
+            None => server.to_string(),
+        }
+    }
+
+    pub async fn call_tool(
+        &self,
+        tool_name: &str,
+        arguments: Value,
+        timeout_secs: Option<u64>,
+    ) -> Result<CallToolResult, McpManagerError> {
+        let manager = self
+            .tool_manager(tool_name)
+            .await
+            .ok_or_else(|| McpManagerError::ToolNotFound(tool_name.to_string()))?;
+        manager.call_tool(tool_name, arguments, timeout_secs).await
+    }
+
+    pub async fn list_resources_for_server(&self, server: &str) -> Result<Vec<Value>, McpManagerError> {
+        self.server(server).await?.list_resources_for_server(server).await
+    }
+
+    pub async fn read_resource(&self, server: &str, uri: &str) -> Result<ReadResourceResult, McpManagerError> {
+        self.server(server).await?.read_resource(server, uri).await
+    }
+
+    pub async fn list_prompts_for_server(&self, server: &str) -> Result<Vec<Value>, McpManagerError> {
+        self.server(server).await?.list_prompts_for_server(server).await
+    }
+
+    pub async fn get_prompt(
+        &self,
+        server: &str,
+        name: &str,
+        arguments: Option<Value>,
+    ) -> Result<GetPromptResult, McpManagerError> {
+        self.server(server).await?.get_prompt(server, name, arguments).await
+    }
+
+    async fn server(&self, server: &str) -> Result<Arc<McpManager>, McpManagerError> {
+        self.manager_of(server)
+            .await
+            .ok_or_else(|| McpManagerError::ServerNotFound(server.to_string()))
+    }
+}
@@ -0,0 +1,76 @@
+use notify::{RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use super::config::McpConfig;
+use super::manager::McpManager;
+
+// Editors write a file in several steps, so changes are applied once it's
+// been quiet this long
+const SETTLE: Duration = Duration::from_millis(500);
+
+// Applies changes to the config file to the manager's servers as they're
+// saved. The directory is watched rather than the file, since editors often
+// replace the file instead of writing to it
+pub fn watch_config(manager: Arc<McpManager>, path: PathBuf) -> notify::Result<()> {
+    let file_name = path.file_name().map(|name| name.to_os_string());
+    let (changed, mut changes) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+        Ok(event) => {
+            if event.paths.iter().any(|changed| changed.file_name() == file_name.as_deref()) {
+                changed.send(()).ok();
+            }
+        }
+        Err(e) => tracing::error!("Error watching the MCP config: {}", e),
+    })?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+
+    tokio::spawn(async move {
+        // Stops watching when the task ends
+        let _watcher = watcher;
+        while changes.recv().await.is_some() {
+            while let Ok(Some(())) = tokio::time::timeout(SETTLE, changes.recv()).await {}
+            let loaded = McpConfig::load_from_file(&path).map_err(|e| e.to_string());
+            match loaded {
+                Ok(config) => {
+                    tracing::info!("Reloading {}", path.display());
+                    manager.apply_config(config).await;
+                }
+                // The servers keep running as they are until the file is fixed
+                Err(e) => tracing::warn!("Ignoring invalid {}: {}", path.display(), e),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_watch_config() {
+        let dir = std::env::temp_dir().join(format!("mcp-watch-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("mcp.json");
+        std::fs::write(&path, r#"{"mcp_servers": {}}"#).unwrap();
+
+        let manager = Arc::new(McpManager::new());
+        watch_config(manager.clone(), path.clone()).unwrap();
+        std::fs::write(&path, r#"{"mcp_servers": {"off": {"command": "true", "disabled": true}}}"#).unwrap();
+        for _ in 0..50 {
+            if manager.has_server("off").await {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert!(manager.has_server("off").await);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+}
@@ -0,0 +1,254 @@
+use sqlx::sqlite::SqlitePool;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Metadata, Span, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Registry;
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Name of the span `middleware::trace_request` opens for each request
+pub const REQUEST_SPAN: &str = "request";
+
+// sqlx reports each statement and each connection taken from the pool as a
+// tracing event under these targets
+const QUERY_TARGET: &str = "sqlx::query";
+const ACQUIRE_TARGET: &str = "sqlx::pool::acquire";
+
+// Statements running longer than `DB_SLOW_QUERY_MS` (500 by default) are
+// logged as warnings and counted as slow
+pub fn slow_query_threshold() -> Duration {
+    let ms = dotenv::var("DB_SLOW_QUERY_MS")
+        .ok()
+        .and_then(|ms| ms.trim().parse().ok())
+        .unwrap_or(500);
+    Duration::from_millis(ms)
+}
+
+// Totals since startup. Durations are kept in microseconds
+#[derive(Debug, Default)]
+pub struct DbMetrics {
+    queries: AtomicU64,
+    query_micros: AtomicU64,
+    slow_queries: AtomicU64,
+    acquires: AtomicU64,
+    acquire_micros: AtomicU64,
+    max_acquire_micros: AtomicU64,
+}
+
+impl DbMetrics {
+    fn record_query(&self, elapsed: Duration, slow: bool) {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        self.query_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        if slow {
+            self.slow_queries.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn record_acquire(&self, waited: Duration) {
+        let micros = waited.as_micros() as u64;
+        self.acquires.fetch_add(1, Ordering::Relaxed);
+        self.acquire_micros.fetch_add(micros, Ordering::Relaxed);
+        self.max_acquire_micros.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    // The Prometheus text format, with the pool's current connections
+    pub fn render(&self, pool: &SqlitePool) -> String {
+        let size = pool.size() as u64;
+        let idle = pool.num_idle() as u64;
+        let secs = |micros: &AtomicU64| micros.load(Ordering::Relaxed) as f64 / 1_000_000.0;
+
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(&str, String)]| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for (labels, value) in samples {
+                let _ = writeln!(out, "{}{} {}", name, labels, value);
+            }
+        };
+        metric(
+            "db_pool_connections",
+            "gauge",
+            "Open database connections",
+            &[
+                ("{state=\"in_use\"}", size.saturating_sub(idle).to_string()),
+                ("{state=\"idle\"}", idle.to_string()),
+            ],
+        );
+        metric(
+            "db_pool_max_connections",
+            "gauge",
+            "Connections the pool opens at most",
+            &[("", pool.options().get_max_connections().to_string())],
+        );
+        metric(
+            "db_pool_acquires_total",
+            "counter",
+            "Connections taken from the pool",
+            &[("", self.acquires.load(Ordering::Relaxed).to_string())],
+        );
+        metric(
+            "db_pool_acquire_wait_seconds_total",
+            "counter",
+            "Time spent waiting for a connection",
+            &[("", secs(&self.acquire_micros).to_string())],
+        );
+        metric(
+            "db_pool_acquire_wait_seconds_max",
+            "gauge",
+            "Longest wait for a connection since startup",
+            &[("", secs(&self.max_acquire_micros).to_string())],
+        );
+        metric(
+            "db_queries_total",
+            "counter",
+            "Statements run",
+            &[("", self.queries.load(Ordering::Relaxed).to_string())],
+        );
+        metric(
+            "db_query_seconds_total",
+            "counter",
+            "Time spent running statements",
+            &[("", secs(&self.query_micros).to_string())],
+        );
+        metric(
+            "db_slow_queries_total",
+            "counter",
+            "Statements slower than DB_SLOW_QUERY_MS",
+            &[("", self.slow_queries.load(Ordering::Relaxed).to_string())],
+        );
+        out
+    }
+}
+
+// The queries a request ran, kept on its span
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RequestQueries {
+    pub count: u64,
+    pub elapsed: Duration,
+}
+
+// What the request's span collected, once the request is done
+pub fn request_queries(span: &Span) -> Option<RequestQueries> {
+    span.with_subscriber(|(id, dispatch)| {
+        let registry = dispatch.downcast_ref::<Registry>()?;
+        let span = registry.span(id)?;
+        let queries = span.extensions().get::<RequestQueries>().copied();
+        queries
+    })
+    .flatten()
+}
+
+// Counts sqlx's events into `DbMetrics`, and each statement into the request
+// it ran for. sqlx's worker threads enter the caller's span, so statements
+// show up under the request span
+pub struct MetricsLayer {
+    metrics: Arc<DbMetrics>,
+    slow: Duration,
+}
+
+impl MetricsLayer {
+    pub fn new(metrics: Arc<DbMetrics>, slow: Duration) -> Self {
+        MetricsLayer { metrics, slow }
+    }
+
+    // Only what the layer counts, so it doesn't turn on sqlx's events for
+    // the other layers
+    pub fn wants(metadata: &Metadata<'_>) -> bool {
+        matches!(metadata.target(), QUERY_TARGET | ACQUIRE_TARGET)
+            || (metadata.is_span() && metadata.name() == REQUEST_SPAN)
+    }
+}
+
+impl<S> Layer<S> for MetricsLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut seconds = Seconds::default();
+        event.record(&mut seconds);
+        let Some(seconds) = seconds.0 else {
+            return;
+        };
+        let elapsed = Duration::from_secs_f64(seconds.max(0.0));
+
+        match event.metadata().target() {
+            QUERY_TARGET => {
+                self.metrics.record_query(elapsed, elapsed >= self.slow);
+                let Some(scope) = ctx.event_scope(event) else {
+                    return;
+                };
+                if let Some(span) = scope.from_root().find(|span| span.name() == REQUEST_SPAN) {
+                    let mut extensions = span.extensions_mut();
+                    match extensions.get_mut::<RequestQueries>() {
+                        Some(queries) => {
+                            queries.count += 1;
+                            queries.elapsed += elapsed;
+                        }
+                        None => extensions.insert(RequestQueries { count: 1, elapsed }),
+                    }
+                }
+            }
+            ACQUIRE_TARGET => self.metrics.record_acquire(elapsed),
+            _ => {}
+        }
+    }
+}
+
+// The duration field of sqlx's events. "aquired" is sqlx's spelling
+#[derive(Default)]
+struct Seconds(Option<f64>);
+
+impl Visit for Seconds {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        if matches!(field.name(), "elapsed_secs" | "aquired_after_secs") {
+            self.0 = Some(value);
+        }
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing::Instrument;
+    use tracing_subscriber::{filter::filter_fn, layer::SubscriberExt};
+
+    #[tokio::test]
+    async fn test_metrics_layer() {
+        let metrics = Arc::new(DbMetrics::default());
+        let layer = MetricsLayer::new(metrics.clone(), Duration::from_millis(500));
+        let subscriber = Registry::default().with(layer.with_filter(filter_fn(MetricsLayer::wants)));
+        let _default = tracing::subscriber::set_default(subscriber);
+
+        // sqlx emits these from its connection's worker thread, which the
+        // default subscriber set here doesn't reach, so they're sent by hand
+        let span = tracing::info_span!(REQUEST_SPAN);
+        async {
+            tracing::trace!(target: "sqlx::pool::acquire", aquired_after_secs = 0.25, "acquired connection");
+            tracing::debug!(target: "sqlx::query", elapsed_secs = 0.002, "SELECT 1");
+            tracing::warn!(target: "sqlx::query", elapsed_secs = 0.75, "slow statement");
+        }
+        .instrument(span.clone())
+        .await;
+        tracing::debug!(target: "sqlx::query", elapsed_secs = 0.001, "outside any request");
+
+        let queries = request_queries(&span).unwrap();
+        assert_eq!(queries.count, 2);
+        assert_eq!((queries.elapsed.as_secs_f64() * 1000.0).round(), 752.0);
+        assert_eq!(metrics.queries.load(Ordering::Relaxed), 3);
+        assert_eq!(metrics.slow_queries.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.max_acquire_micros.load(Ordering::Relaxed), 250_000);
+
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let rendered = metrics.render(&pool);
+        assert!(rendered.contains("# TYPE db_queries_total counter\ndb_queries_total 3\n"));
+        assert!(rendered.contains("db_slow_queries_total 1\n"));
+        assert!(rendered.contains("db_pool_connections{state=\"idle\"}"));
+    }
+}
@@ -1,17 +1,28 @@
 use axum::{
-    body::Body,
-    extract::State,
-    http::{HeaderValue, Request, StatusCode},
+    body::{to_bytes, Body},
+    extract::{ConnectInfo, State},
+    http::{header, HeaderValue, Method, Request, StatusCode},
     middleware::Next,
-    response::{Html, IntoResponse, Redirect, Response},
+    response::{Html, IntoResponse, Json, Redirect, Response},
     Extension,
 };
 
 use tera::Context;
-use tower_cookies::Cookies;
+use tracing::Instrument;
+use tower_cookies::{cookie::SameSite, Cookie, Cookies};
 
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::Duration;
 
+use crate::audit::AuditSource;
+use crate::csrf::{self, CSRF_COOKIE, CSRF_HEADER, CSRF_TOKEN};
+use crate::data::model::{ApiScope, SessionUser};
+use crate::metrics;
+use crate::rate_limit::RateLimiter;
+use crate::session;
+use crate::utils::password;
 use crate::{AppState, User};
 
 pub fn error_response(code: u16, message: &str) -> Response {
@@ -23,18 +34,83 @@ pub fn error_response(code: u16, message: &str) -> Response {
     r
 }
 
+// Holds the login session's token, see `sessions`
+pub const SESSION_COOKIE: &str = "rust-gpt-session";
+
+// Marks requests authenticated with an API token rather than a session
+#[derive(Debug, Clone, Copy)]
+pub struct ApiAccess {
+    pub scope: ApiScope,
+}
+
+// Takes the user from an `Authorization: Bearer` API token or, without one,
+// from the session cookie
 pub async fn extract_user(
     State(state): State<Arc<AppState>>,
     cookies: Cookies,
     mut req: Request<Body>,
     next: Next,
 ) -> Result<Response, StatusCode> {
-    let session = cookies.get("rust-gpt-session");
+    let bearer = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim);
+    let with_token = bearer.is_some();
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| *addr);
+    let ip = session::client_ip(req.headers(), peer);
 
-    let id = session.map_or(-1, |x| x.value().parse::<i64>().unwrap_or(-1));
+    let session = match (bearer, cookies.get(SESSION_COOKIE)) {
+        (Some(token), _) => match state.chat_repo.authenticate_api_token(token).await {
+            Ok(Some((user_id, scope))) => {
+                req.extensions_mut().insert(ApiAccess { scope });
+                Some(SessionUser { user_id, impersonator: None })
+            }
+            _ => None,
+        },
+        (None, Some(session)) => state
+            .chat_repo
+            .authenticate_session(&password::hash_token(session.value()), session::idle_hours())
+            .await
+            .ok()
+            .flatten(),
+        (None, None) => None,
+    };
+    // Browsers that asked to be remembered get a new session
+    let session = match session {
+        None if !with_token => session::resume(&state, &cookies, req.headers(), peer).await,
+        session => session,
+    };
+
+    let user_id = session.as_ref().map_or(-1, |session| session.user_id);
+    match find_user(&state, user_id).await {
+        Ok(mut current_user) => {
+            current_user.impersonator = session.and_then(|session| session.impersonator);
+            // Where the request comes from, for `audit::record`
+            req.extensions_mut().insert(AuditSource {
+                actor: current_user.impersonator.clone(),
+                ip,
+            });
+            // insert the current user into a request extension so the handler can
+            // extract it, and make sure `user` is not used after this point
+            req.extensions_mut().insert(Some(current_user));
+            Ok(next.run(req).await)
+        }
+        _ => {
+            req.extensions_mut().insert(AuditSource { actor: None, ip });
+            req.extensions_mut().insert(None::<User>);
+            Ok(next.run(req).await)
+        }
+    }
+}
 
-    // Get the user
-    match sqlx::query_as!(
+// Get the user along with their settings, API key decrypted
+pub async fn find_user(state: &AppState, id: i64) -> sqlx::Result<User> {
+    let mut user = sqlx::query_as!(
         User,
         r#"
         SELECT
@@ -42,6 +118,14 @@ pub async fn extract_user(
             users.email,
             users.password,
             users.created_at,
+            users.verified_at,
+            users.is_admin AS "is_admin: bool",
+            users.display_name,
+            users.avatar,
+            NULL AS "impersonator?: String",
+            (SELECT org_id FROM org_members
+                WHERE org_id = users.current_org_id AND user_id = users.id) AS "current_org_id?: i64",
+            users.guest_expires_at,
             settings.openai_api_key,
             settings.base_url,
             settings.model,
@@ -51,24 +135,26 @@ pub async fn extract_user(
             settings.max_tokens
         FROM users
         LEFT JOIN settings ON settings.user_id=users.id
-        WHERE users.id = $1
+        WHERE users.id = $1 AND users.disabled_at IS NULL
+            AND (users.guest_expires_at IS NULL OR users.guest_expires_at > CURRENT_TIMESTAMP)
         "#,
         id
     )
     .fetch_one(&*state.pool)
-    .await
-    {
-        Ok(current_user) => {
-            // insert the current user into a request extension so the handler can
-            // extract it, and make sure `user` is not used after this point
-            req.extensions_mut().insert(Some(current_user));
-            Ok(next.run(req).await)
-        }
-        _ => {
-            req.extensions_mut().insert(None::<User>);
-            Ok(next.run(req).await)
-        }
-    }
+    .await?;
+
+    // A key the master key can't open is as good as none; saving a new one
+    // replaces it
+    user.openai_api_key = user
+        .openai_api_key
+        .and_then(|stored| match state.encryption.decrypt(&stored) {
+            Ok(key) => Some(key),
+            Err(e) => {
+                tracing::error!("Failed to decrypt the API key of user {}: {}", id, e);
+                None
+            }
+        });
+    Ok(user)
 }
 
 pub async fn auth(
@@ -82,12 +168,235 @@ pub async fn auth(
     let h = r.headers_mut();
     h.insert("HX-Redirect", HeaderValue::from_str(&to).unwrap());
 
+    // API tokens only open the JSON API, whose routes check their scope
     match current_user {
-        Some(_user) => next.run(req).await,
+        Some(_user) if req.extensions().get::<ApiAccess>().is_none() => next.run(req).await,
         _ => error_response(401, "You need to log in to view this page"),
     }
 }
 
+// Keeps accounts that haven't confirmed their email out of the chat; runs
+// after `auth`, so there is always a user
+pub async fn verified(
+    Extension(current_user): Extension<Option<User>>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    match current_user {
+        Some(user) if user.verified_at.is_none() => {
+            let mut response = Redirect::to("/verify").into_response();
+            response
+                .headers_mut()
+                .insert("HX-Redirect", HeaderValue::from_static("/verify"));
+            response
+        }
+        _ => next.run(req).await,
+    }
+}
+
+// Keeps everyone but admins out of /admin. An admin impersonating someone
+// is that user until they stop
+pub async fn admin(
+    Extension(current_user): Extension<Option<User>>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    match current_user {
+        Some(user) if user.is_admin && user.impersonator.is_none() => next.run(req).await,
+        Some(_) => error_response(403, "Only admins can view this page"),
+        None => error_response(401, "You need to log in to view this page"),
+    }
+}
+
+// Guards the JSON API: the user must come from an API token, as checked by
+// `extract_user`. Session cookies don't count, so the API can't be driven
+// from another site.
+pub async fn api_auth(
+    Extension(current_user): Extension<Option<User>>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    let access = req.extensions().get::<ApiAccess>();
+
+    match current_user {
+        Some(user) if access.is_some() && user.verified_at.is_none() => (
+            StatusCode::FORBIDDEN,
+            Json(serde_json::json!({ "error": "Confirm your email address first" })),
+        )
+            .into_response(),
+        Some(_) if access.is_some() => next.run(req).await,
+        _ => (
+            StatusCode::UNAUTHORIZED,
+            Json(serde_json::json!({ "error": "Missing, invalid or expired API token" })),
+        )
+            .into_response(),
+    }
+}
+
+// Route layer for API routes that need more than a read-only token. Runs
+// after `api_auth`, so the request always has `ApiAccess`
+pub async fn require_scope(
+    State(scope): State<ApiScope>,
+    Extension(access): Extension<ApiAccess>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    if access.scope >= scope {
+        return next.run(req).await;
+    }
+    (
+        StatusCode::FORBIDDEN,
+        Json(serde_json::json!({
+            "error": format!("This token needs the \"{}\" scope", scope.as_str())
+        })),
+    )
+        .into_response()
+}
+
+fn too_many_requests(req: &Request<Body>, retry_after: Duration) -> Response {
+    let message = "Too many requests, try again later";
+    let mut response = if req.extensions().get::<ApiAccess>().is_some() {
+        (
+            StatusCode::TOO_MANY_REQUESTS,
+            Json(serde_json::json!({ "error": message })),
+        )
+            .into_response()
+    } else {
+        (StatusCode::TOO_MANY_REQUESTS, message).into_response()
+    };
+    // Whole seconds, rounded up so clients don't come back too early
+    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, HeaderValue::from(seconds.max(1)));
+    response
+}
+
+// Limits requests per client IP, for the forms anyone can post to
+pub async fn limit_by_ip(
+    State(limiter): State<RateLimiter>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| *addr);
+    let key = session::client_ip(req.headers(), peer)
+        .map_or_else(|| "unknown".to_string(), |ip| ip.to_string());
+    match limiter.check(&key) {
+        Ok(()) => next.run(req).await,
+        Err(retry_after) => too_many_requests(&req, retry_after),
+    }
+}
+
+// Limits requests per signed in user, wherever they come from. Runs after
+// `auth` or `api_auth`, so there is always a user
+pub async fn limit_by_user(
+    State(limiter): State<RateLimiter>,
+    Extension(current_user): Extension<Option<User>>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    let Some(user) = current_user else {
+        return next.run(req).await;
+    };
+    match limiter.check(&user.id.to_string()) {
+        Ok(()) => next.run(req).await,
+        Err(retry_after) => too_many_requests(&req, retry_after),
+    }
+}
+
+// Plain forms carry the token in the body, which is read up to this size;
+// the chat import is the largest form
+const CSRF_FORM_LIMIT: usize = 64 * 1024 * 1024;
+
+// Issues each browser a CSRF token and rejects state-changing requests that
+// don't send it back, in the `X-CSRF-Token` header (htmx and fetch) or the
+// `csrf_token` form field. Pages embed it with `csrf_token()`
+pub async fn csrf(cookies: Cookies, req: Request<Body>, next: Next) -> Response {
+    let token = match cookies.get(CSRF_COOKIE) {
+        Some(cookie) => cookie.value().to_string(),
+        None => {
+            let token = csrf::new_token();
+            let cookie = Cookie::build((CSRF_COOKIE, token.clone()))
+                .path("/")
+                .http_only(true)
+                .same_site(SameSite::Lax)
+                .build();
+            cookies.add(cookie);
+            token
+        }
+    };
+
+    let safe = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    // The JSON API only takes bearer tokens, which browsers never send on
+    // their own
+    let api = req.uri().path().starts_with("/api/");
+    let req = if safe || api {
+        req
+    } else {
+        let header = req
+            .headers()
+            .get(CSRF_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        let (req, sent) = match header {
+            Some(sent) => (req, Some(sent)),
+            None => {
+                let (parts, body) = req.into_parts();
+                let Ok(body) = to_bytes(body, CSRF_FORM_LIMIT).await else {
+                    return StatusCode::PAYLOAD_TOO_LARGE.into_response();
+                };
+                let sent = csrf::token_from_form(&parts.headers, body.clone()).await;
+                (Request::from_parts(parts, Body::from(body)), sent)
+            }
+        };
+        if sent.as_deref() != Some(token.as_str()) {
+            return error_response(403, "The form expired, reload the page and try again");
+        }
+        req
+    };
+
+    CSRF_TOKEN.scope(token, next.run(req)).await
+}
+
+// Turns every request away while a backup is restored, so nothing reads or
+// writes the database halfway through
+pub async fn maintenance(State(state): State<Arc<AppState>>, req: Request<Body>, next: Next) -> Response {
+    if state.maintenance.load(Ordering::SeqCst) {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, "30")],
+            "Down for maintenance, try again in a minute",
+        )
+            .into_response();
+    }
+    next.run(req).await
+}
+
+// Runs each request in a span that counts its queries, see
+// `metrics::MetricsLayer`, and logs them when it's done
+pub async fn trace_request(req: Request<Body>, next: Next) -> Response {
+    let span = tracing::info_span!(
+        metrics::REQUEST_SPAN,
+        method = %req.method(),
+        path = %req.uri().path(),
+        status = tracing::field::Empty,
+        db_queries = tracing::field::Empty,
+        db_query_ms = tracing::field::Empty,
+    );
+    let response = next.run(req).instrument(span.clone()).await;
+
+    span.record("status", response.status().as_u16());
+    if let Some(queries) = metrics::request_queries(&span) {
+        span.record("db_queries", queries.count);
+        span.record("db_query_ms", queries.elapsed.as_millis() as u64);
+    }
+    tracing::debug!(parent: &span, "finished");
+    response
+}
+
 pub async fn handle_error(
     Extension(current_user): Extension<Option<User>>,
     State(state): State<Arc<AppState>>,
@@ -112,7 +421,11 @@ pub async fn handle_error(
             context.insert("current_user", &current_user);
             context.insert("with_footer", &true);
             let rendered = state.tera.render("views/main.html", &context).unwrap();
-            let h = Html(rendered).into_response();
+            // Keep the status so scripts and htmx still see the request failed
+            let mut h = (response.status(), Html(rendered)).into_response();
+            if let Some(retry_after) = response.headers().get(header::RETRY_AFTER) {
+                h.headers_mut().insert(header::RETRY_AFTER, retry_after.clone());
+            }
             Ok(h)
         }
         _ => Ok(response),
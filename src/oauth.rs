@@ -0,0 +1,382 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use reqwest::Url;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::OnceCell;
+
+use std::sync::OnceLock;
+
+#[derive(Debug, thiserror::Error)]
+pub enum OAuthError {
+    #[error("Request to the provider failed: {0}")]
+    Http(#[from] reqwest::Error),
+
+    #[error("The provider refused the sign in: {0}")]
+    Refused(String),
+
+    #[error("The provider didn't return {0}")]
+    Missing(&'static str),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Provider {
+    GitHub,
+    Google,
+    // Any OpenID Connect issuer, such as Keycloak, Okta or Entra ID
+    Oidc,
+}
+
+impl Provider {
+    pub const ALL: [Provider; 3] = [Provider::GitHub, Provider::Google, Provider::Oidc];
+
+    pub fn from_slug(slug: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|provider| provider.slug() == slug)
+    }
+
+    // Used in URLs and stored in `user_identities.provider`
+    pub fn slug(self) -> &'static str {
+        match self {
+            Provider::GitHub => "github",
+            Provider::Google => "google",
+            Provider::Oidc => "oidc",
+        }
+    }
+
+    // The OIDC issuer's button reads `OIDC_NAME`, "Single sign-on" by default
+    pub fn name(self) -> &'static str {
+        static OIDC_NAME: OnceLock<String> = OnceLock::new();
+        match self {
+            Provider::GitHub => "GitHub",
+            Provider::Google => "Google",
+            Provider::Oidc => OIDC_NAME
+                .get_or_init(|| dotenv::var("OIDC_NAME").unwrap_or_else(|_| "Single sign-on".to_string())),
+        }
+    }
+
+    // Client id and secret of the app registered with the provider, from
+    // `GITHUB_CLIENT_ID`/`GITHUB_CLIENT_SECRET` and the `GOOGLE_` and `OIDC_`
+    // equivalents
+    fn credentials(self) -> Option<(String, String)> {
+        let prefix = self.slug().to_uppercase();
+        let id = dotenv::var(format!("{}_CLIENT_ID", prefix)).ok()?;
+        let secret = dotenv::var(format!("{}_CLIENT_SECRET", prefix)).ok()?;
+        Some((id, secret))
+    }
+
+    pub fn is_configured(self) -> bool {
+        match self {
+            Provider::Oidc => oidc_issuer().is_some() && self.credentials().is_some(),
+            _ => self.credentials().is_some(),
+        }
+    }
+
+    // Whoever the OIDC issuer lets through gets an account on first sign in,
+    // even when signup is by invitation only
+    pub fn provisions_accounts(self) -> bool {
+        self == Provider::Oidc
+    }
+
+    async fn endpoints(self) -> Result<Endpoints, OAuthError> {
+        let fixed = |authorization: &str, token: &str, userinfo: &str| Endpoints {
+            authorization_endpoint: authorization.to_string(),
+            token_endpoint: token.to_string(),
+            userinfo_endpoint: Some(userinfo.to_string()),
+        };
+        match self {
+            Provider::GitHub => Ok(fixed(
+                "https://github.com/login/oauth/authorize",
+                "https://github.com/login/oauth/access_token",
+                "https://api.github.com/user",
+            )),
+            Provider::Google => Ok(fixed(
+                "https://accounts.google.com/o/oauth2/v2/auth",
+                "https://oauth2.googleapis.com/token",
+                "https://openidconnect.googleapis.com/v1/userinfo",
+            )),
+            Provider::Oidc => oidc_discovery().await.cloned(),
+        }
+    }
+
+    fn scope(self) -> &'static str {
+        match self {
+            Provider::GitHub => "read:user user:email",
+            Provider::Google => "openid email profile",
+            Provider::Oidc => "openid email profile",
+        }
+    }
+}
+
+// Issuer URL from `OIDC_ISSUER`, e.g.
+// https://keycloak.example.com/realms/acme, without a trailing slash
+fn oidc_issuer() -> Option<String> {
+    let issuer = dotenv::var("OIDC_ISSUER").ok()?;
+    Some(issuer.trim_end_matches('/').to_string())
+}
+
+// Where the issuer publishes its endpoints
+fn discovery_url(issuer: &str) -> String {
+    format!("{}/.well-known/openid-configuration", issuer)
+}
+
+// The parts of the discovery document sign in needs
+#[derive(Debug, Clone, Deserialize)]
+struct Endpoints {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    userinfo_endpoint: Option<String>,
+}
+
+// Fetched on the first sign in and kept until restart; a failed fetch is
+// retried on the next one
+async fn oidc_discovery() -> Result<&'static Endpoints, OAuthError> {
+    static DISCOVERY: OnceCell<Endpoints> = OnceCell::const_new();
+    DISCOVERY
+        .get_or_try_init(|| async {
+            let issuer = oidc_issuer().ok_or(OAuthError::Missing("an issuer"))?;
+            let endpoints = reqwest::get(discovery_url(&issuer))
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            Ok(endpoints)
+        })
+        .await
+}
+
+// `OIDC_TRUST_EMAILS=true` treats the issuer's emails as verified, for
+// company directories that don't send `email_verified`
+fn oidc_trusts_emails() -> bool {
+    dotenv::var("OIDC_TRUST_EMAILS").is_ok_and(|value| value == "true")
+}
+
+// The provider's account, as far as signing in is concerned
+#[derive(Debug)]
+pub struct Identity {
+    pub subject: String,
+    pub email: String,
+    pub email_verified: bool,
+    // Display name for accounts created on first sign in
+    pub name: Option<String>,
+}
+
+// Secrets of one sign in attempt, kept in a cookie until the callback:
+// `state` ties the callback to the browser that started it, and the PKCE
+// `verifier` ties the code exchange to the authorization request
+#[derive(Debug, PartialEq)]
+pub struct Attempt {
+    pub state: String,
+    pub verifier: String,
+}
+
+impl Attempt {
+    pub fn start() -> Self {
+        Attempt {
+            state: uuid::Uuid::new_v4().simple().to_string(),
+            // 64 characters, within the 43..=128 PKCE allows
+            verifier: format!(
+                "{}{}",
+                uuid::Uuid::new_v4().simple(),
+                uuid::Uuid::new_v4().simple()
+            ),
+        }
+    }
+
+    pub fn to_cookie(&self) -> String {
+        format!("{}.{}", self.state, self.verifier)
+    }
+
+    pub fn from_cookie(value: &str) -> Option<Self> {
+        let (state, verifier) = value.split_once('.')?;
+        Some(Attempt {
+            state: state.to_string(),
+            verifier: verifier.to_string(),
+        })
+    }
+}
+
+pub fn pkce_challenge(verifier: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+// The provider's consent page
+pub async fn authorize_url(provider: Provider, redirect_uri: &str, attempt: &Attempt) -> Result<Url, OAuthError> {
+    let (client_id, _) = provider
+        .credentials()
+        .ok_or(OAuthError::Missing("client credentials"))?;
+    let endpoints = provider.endpoints().await?;
+    let challenge = pkce_challenge(&attempt.verifier);
+    Url::parse_with_params(
+        &endpoints.authorization_endpoint,
+        [
+            ("response_type", "code"),
+            ("client_id", &client_id),
+            ("redirect_uri", redirect_uri),
+            ("scope", provider.scope()),
+            ("state", &attempt.state),
+            ("code_challenge", &challenge),
+            ("code_challenge_method", "S256"),
+        ],
+    )
+    .map_err(|_| OAuthError::Missing("a valid authorization endpoint"))
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GitHubUser {
+    id: i64,
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GitHubEmail {
+    email: String,
+    primary: bool,
+    verified: bool,
+}
+
+// Standard OIDC userinfo claims, from Google and generic issuers
+#[derive(Deserialize)]
+struct UserInfo {
+    sub: String,
+    email: Option<String>,
+    #[serde(default)]
+    email_verified: bool,
+    name: Option<String>,
+    preferred_username: Option<String>,
+}
+
+// Trades the callback's `code` for an access token and looks up the account
+// it belongs to
+pub async fn fetch_identity(
+    provider: Provider,
+    redirect_uri: &str,
+    code: &str,
+    verifier: &str,
+) -> Result<Identity, OAuthError> {
+    let (client_id, client_secret) = provider
+        .credentials()
+        .ok_or(OAuthError::Missing("client credentials"))?;
+    let endpoints = provider.endpoints().await?;
+    let userinfo_endpoint = endpoints
+        .userinfo_endpoint
+        .ok_or(OAuthError::Missing("a userinfo endpoint"))?;
+    // GitHub's API rejects requests without a user agent
+    let client = reqwest::Client::builder().user_agent("rustgpt").build()?;
+
+    let token: TokenResponse = client
+        .post(&endpoints.token_endpoint)
+        .header(reqwest::header::ACCEPT, "application/json")
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", redirect_uri),
+            ("client_id", &client_id),
+            ("client_secret", &client_secret),
+            ("code_verifier", verifier),
+        ])
+        .send()
+        .await?
+        .json()
+        .await?;
+    let access_token = match token {
+        TokenResponse { access_token: Some(access_token), .. } => access_token,
+        TokenResponse { error: Some(error), error_description, .. } => {
+            return Err(OAuthError::Refused(error_description.unwrap_or(error)))
+        }
+        _ => return Err(OAuthError::Missing("an access token")),
+    };
+
+    match provider {
+        Provider::GitHub => {
+            let user: GitHubUser = client
+                .get(&userinfo_endpoint)
+                .bearer_auth(&access_token)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            // The profile's email is optional and unverified, so take the
+            // primary address from the verified ones instead
+            let emails: Vec<GitHubEmail> = client
+                .get("https://api.github.com/user/emails")
+                .bearer_auth(&access_token)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            let email = emails
+                .into_iter()
+                .filter(|email| email.verified)
+                .max_by_key(|email| email.primary)
+                .ok_or(OAuthError::Missing("a verified email"))?;
+            Ok(Identity {
+                subject: user.id.to_string(),
+                email: email.email,
+                email_verified: true,
+                name: user.name,
+            })
+        }
+        Provider::Google | Provider::Oidc => {
+            let user: UserInfo = client
+                .get(&userinfo_endpoint)
+                .bearer_auth(&access_token)
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            Ok(Identity {
+                subject: user.sub,
+                email: user.email.ok_or(OAuthError::Missing("an email"))?,
+                email_verified: user.email_verified || (provider == Provider::Oidc && oidc_trusts_emails()),
+                name: user.name.or(user.preferred_username),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pkce_challenge() {
+        // The example from RFC 7636, appendix B
+        assert_eq!(
+            pkce_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[test]
+    fn test_attempt_cookie() {
+        let attempt = Attempt::start();
+        assert_eq!(Attempt::from_cookie(&attempt.to_cookie()), Some(attempt));
+        assert_eq!(Attempt::from_cookie("garbage"), None);
+        assert_eq!(Provider::from_slug("github"), Some(Provider::GitHub));
+        assert_eq!(Provider::from_slug("gitlab"), None);
+    }
+
+    #[test]
+    fn test_userinfo() {
+        let user: UserInfo = serde_json::from_str(
+            r#"{"sub": "f81d4fae", "email": "ada@example.com", "preferred_username": "ada"}"#,
+        )
+        .unwrap();
+        assert_eq!(user.sub, "f81d4fae");
+        assert!(!user.email_verified);
+        assert_eq!(user.name.or(user.preferred_username).as_deref(), Some("ada"));
+        assert_eq!(
+            discovery_url("https://sso.example.com/realms/acme"),
+            "https://sso.example.com/realms/acme/.well-known/openid-configuration"
+        );
+    }
+}
@@ -0,0 +1,211 @@
+use serde::Serialize;
+
+use crate::data::model::{QuotaOverride, Usage};
+use crate::AppState;
+
+// Limits on a user's chats per UTC day and calendar month; `None` is
+// unlimited
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+pub struct Quota {
+    pub daily_tokens: Option<i64>,
+    pub monthly_tokens: Option<i64>,
+    pub daily_messages: Option<i64>,
+    pub monthly_messages: Option<i64>,
+}
+
+fn env_limit(var: &str) -> Option<i64> {
+    let value = dotenv::var(var).ok()?;
+    match value.trim().parse::<i64>() {
+        Ok(limit) if limit > 0 => Some(limit),
+        Ok(_) => None,
+        Err(_) => {
+            tracing::warn!("Ignoring malformed {}={:?}", var, value);
+            None
+        }
+    }
+}
+
+// Like `env_limit`, with `default` when the variable is unset
+fn env_limit_or(var: &str, default: i64) -> Option<i64> {
+    match dotenv::var(var) {
+        Ok(_) => env_limit(var),
+        Err(_) => Some(default),
+    }
+}
+
+impl Quota {
+    // Everyone's limits, from `QUOTA_DAILY_TOKENS`, `QUOTA_MONTHLY_TOKENS`,
+    // `QUOTA_DAILY_MESSAGES` and `QUOTA_MONTHLY_MESSAGES`. Unset or 0 is
+    // unlimited
+    pub fn from_env() -> Self {
+        Quota {
+            daily_tokens: env_limit("QUOTA_DAILY_TOKENS"),
+            monthly_tokens: env_limit("QUOTA_MONTHLY_TOKENS"),
+            daily_messages: env_limit("QUOTA_DAILY_MESSAGES"),
+            monthly_messages: env_limit("QUOTA_MONTHLY_MESSAGES"),
+        }
+    }
+
+    // Guests' limits, from `GUEST_DAILY_TOKENS` and `GUEST_DAILY_MESSAGES`.
+    // Strict by default, since guests use the server's API key
+    pub fn for_guests() -> Self {
+        Quota {
+            daily_tokens: env_limit_or("GUEST_DAILY_TOKENS", 20_000),
+            daily_messages: env_limit_or("GUEST_DAILY_MESSAGES", 10),
+            ..Quota::default()
+        }
+    }
+
+    // An admin's override replaces the limits it sets: 0 lifts one, and
+    // unset ones keep the default
+    pub fn with_override(self, quota: &QuotaOverride) -> Self {
+        let pick = |limit: Option<i64>, default: Option<i64>| match limit {
+            Some(0) => None,
+            Some(limit) => Some(limit),
+            None => default,
+        };
+        Quota {
+            daily_tokens: pick(quota.daily_tokens, self.daily_tokens),
+            monthly_tokens: pick(quota.monthly_tokens, self.monthly_tokens),
+            daily_messages: pick(quota.daily_messages, self.daily_messages),
+            monthly_messages: pick(quota.monthly_messages, self.monthly_messages),
+        }
+    }
+
+    pub fn is_unlimited(&self) -> bool {
+        *self == Quota::default()
+    }
+}
+
+// One limit with how much of it is used, for the status widget
+#[derive(Debug, Clone, Serialize)]
+pub struct QuotaLine {
+    pub label: &'static str,
+    pub used: i64,
+    pub limit: i64,
+    pub percent: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QuotaStatus {
+    pub quota: Quota,
+    pub usage: Usage,
+    // The limits set, unlimited ones left out
+    pub lines: Vec<QuotaLine>,
+}
+
+impl QuotaStatus {
+    pub fn new(quota: Quota, usage: Usage) -> Self {
+        let lines = [
+            ("Tokens today", usage.daily_tokens, quota.daily_tokens),
+            ("Tokens this month", usage.monthly_tokens, quota.monthly_tokens),
+            ("Messages today", usage.daily_messages, quota.daily_messages),
+            ("Messages this month", usage.monthly_messages, quota.monthly_messages),
+        ]
+        .into_iter()
+        .filter_map(|(label, used, limit)| {
+            let limit = limit?;
+            Some(QuotaLine {
+                label,
+                used,
+                limit,
+                percent: (used * 100 / limit).min(100),
+            })
+        })
+        .collect();
+        QuotaStatus { quota, usage, lines }
+    }
+
+    // Why no more can be generated, if a limit is reached
+    pub fn exceeded(&self) -> Option<String> {
+        let reached = self.lines.iter().find(|line| line.used >= line.limit)?;
+        let resets = if reached.label.ends_with("today") {
+            "tomorrow"
+        } else {
+            "next month"
+        };
+        Some(format!(
+            "You've reached your limit of {} {}. It resets {} (UTC).",
+            reached.limit,
+            reached.label.to_lowercase(),
+            resets
+        ))
+    }
+}
+
+// The user's limits, usage and whether they're over. Guests get the guest
+// limits whatever the overrides
+pub async fn status(state: &AppState, user_id: i64) -> sqlx::Result<QuotaStatus> {
+    let quota = if state.chat_repo.is_guest(user_id).await? {
+        Quota::for_guests()
+    } else {
+        let quota = Quota::from_env();
+        match state.chat_repo.get_quota_override(user_id).await? {
+            Some(quota_override) => quota.with_override(&quota_override),
+            None => quota,
+        }
+    };
+    let usage = if quota.is_unlimited() {
+        Usage::default()
+    } else {
+        state.chat_repo.usage_totals(user_id).await?
+    };
+    Ok(QuotaStatus::new(quota, usage))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_with_override() {
+        let default = Quota {
+            daily_tokens: Some(1000),
+            monthly_tokens: Some(20000),
+            daily_messages: None,
+            monthly_messages: None,
+        };
+        let quota = default.with_override(&QuotaOverride {
+            daily_tokens: Some(0),
+            monthly_tokens: None,
+            daily_messages: Some(5),
+            monthly_messages: None,
+        });
+        assert_eq!(
+            quota,
+            Quota {
+                daily_tokens: None,
+                monthly_tokens: Some(20000),
+                daily_messages: Some(5),
+                monthly_messages: None,
+            }
+        );
+        assert!(Quota::default().is_unlimited());
+    }
+
+    #[test]
+    fn test_exceeded() {
+        let quota = Quota {
+            daily_tokens: Some(1000),
+            monthly_messages: Some(10),
+            ..Quota::default()
+        };
+        let usage = Usage {
+            daily_tokens: 1500,
+            monthly_tokens: 1500,
+            daily_messages: 3,
+            monthly_messages: 9,
+        };
+        let status = QuotaStatus::new(quota, usage.clone());
+        assert_eq!(status.lines.len(), 2);
+        assert_eq!(status.lines[0].percent, 100);
+        assert_eq!(
+            status.exceeded().as_deref(),
+            Some("You've reached your limit of 1000 tokens today. It resets tomorrow (UTC).")
+        );
+
+        let status = QuotaStatus::new(Quota { daily_tokens: None, ..quota }, usage);
+        assert_eq!(status.lines[0].percent, 90);
+        assert_eq!(status.exceeded(), None);
+    }
+}
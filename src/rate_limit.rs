@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// At most `requests` per `window` for each key (IP or user)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub requests: u32,
+    pub window: Duration,
+}
+
+impl RateLimit {
+    // `<requests>/<seconds>`, e.g. `10/60`; `off` disables the limit
+    pub fn parse(value: &str) -> Option<Option<Self>> {
+        let value = value.trim();
+        if value.eq_ignore_ascii_case("off") {
+            return Some(None);
+        }
+        let (requests, seconds) = value.split_once('/')?;
+        let requests = requests.trim().parse().ok()?;
+        let seconds: u64 = seconds.trim().parse().ok()?;
+        if requests == 0 || seconds == 0 {
+            return None;
+        }
+        Some(Some(RateLimit {
+            requests,
+            window: Duration::from_secs(seconds),
+        }))
+    }
+}
+
+// Fixed window counters, kept in memory: each instance of the app counts on
+// its own, and restarts forget everything
+#[derive(Clone, Default)]
+pub struct RateLimiter {
+    limit: Option<RateLimit>,
+    windows: Arc<Mutex<HashMap<String, (Instant, u32)>>>,
+}
+
+// Drop expired windows once this many keys pile up
+const PRUNE_AT: usize = 10_000;
+
+impl RateLimiter {
+    pub fn new(limit: Option<RateLimit>) -> Self {
+        RateLimiter {
+            limit,
+            windows: Arc::default(),
+        }
+    }
+
+    // Reads the limit from `var`, falling back to `default` when unset or
+    // malformed
+    pub fn from_env(var: &str, default: RateLimit) -> Self {
+        let limit = match dotenv::var(var) {
+            Ok(value) => RateLimit::parse(&value).unwrap_or_else(|| {
+                tracing::warn!("Ignoring malformed {}={:?}", var, value);
+                Some(default)
+            }),
+            Err(_) => Some(default),
+        };
+        Self::new(limit)
+    }
+
+    // Counts a request; `Err` holds how long until the key may try again
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        self.check_at(key, Instant::now())
+    }
+
+    fn check_at(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let Some(limit) = self.limit else {
+            return Ok(());
+        };
+        let mut windows = self.windows.lock().unwrap();
+
+        if windows.len() >= PRUNE_AT {
+            windows.retain(|_, (start, _)| now.duration_since(*start) < limit.window);
+        }
+
+        let (start, count) = windows.entry(key.to_string()).or_insert((now, 0));
+        if now.duration_since(*start) >= limit.window {
+            *start = now;
+            *count = 0;
+        }
+        if *count >= limit.requests {
+            return Err(limit.window - now.duration_since(*start));
+        }
+        *count += 1;
+        Ok(())
+    }
+}
+
+// The limits applied to routes, see `router::app::app_router`
+#[derive(Clone, Default)]
+pub struct RateLimits {
+    // Login, signup and password reset forms, per IP
+    pub auth: RateLimiter,
+    // New chats and messages, per user
+    pub messages: RateLimiter,
+    // Generations, per user
+    pub generations: RateLimiter,
+}
+
+impl RateLimits {
+    pub fn from_env() -> Self {
+        let per_minute = |requests| RateLimit {
+            requests,
+            window: Duration::from_secs(60),
+        };
+        RateLimits {
+            auth: RateLimiter::from_env("RATE_LIMIT_AUTH", per_minute(10)),
+            messages: RateLimiter::from_env("RATE_LIMIT_MESSAGES", per_minute(30)),
+            generations: RateLimiter::from_env("RATE_LIMIT_GENERATIONS", per_minute(20)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            RateLimit::parse(" 5 / 30 "),
+            Some(Some(RateLimit { requests: 5, window: Duration::from_secs(30) }))
+        );
+        assert_eq!(RateLimit::parse("off"), Some(None));
+        assert_eq!(RateLimit::parse("0/60"), None);
+        assert_eq!(RateLimit::parse("fast"), None);
+    }
+
+    #[test]
+    fn test_check() {
+        let limiter = RateLimiter::new(Some(RateLimit {
+            requests: 2,
+            window: Duration::from_secs(60),
+        }));
+        let start = Instant::now();
+
+        assert!(limiter.check_at("a", start).is_ok());
+        assert!(limiter.check_at("a", start).is_ok());
+        let retry_after = limiter.check_at("a", start + Duration::from_secs(20)).unwrap_err();
+        assert_eq!(retry_after, Duration::from_secs(40));
+        // Keys are counted separately, and the window starts over
+        assert!(limiter.check_at("b", start).is_ok());
+        assert!(limiter.check_at("a", start + Duration::from_secs(60)).is_ok());
+
+        assert!(RateLimiter::new(None).check_at("a", start).is_ok());
+    }
+}
@@ -0,0 +1,113 @@
+use chrono::{DateTime, Duration, NaiveTime, Utc};
+
+use crate::data::repository::ChatRepository;
+
+// How long data is kept, per deployment. Each is off unless its variable
+// holds a number of days
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    // `RETENTION_CHAT_DAYS`: chats without a new message for this long
+    pub chat_days: Option<i64>,
+    // `RETENTION_TOOL_CALL_DAYS`: tool call confirmations
+    pub tool_call_days: Option<i64>,
+    // `RETENTION_AUDIT_DAYS`: audit log entries
+    pub audit_days: Option<i64>,
+    // `RETENTION_HOUR`: the UTC hour the sweep runs at, 3 by default
+    pub hour: u32,
+}
+
+impl RetentionPolicy {
+    // None when no data has a retention period
+    pub fn from_env() -> Option<Self> {
+        let policy = RetentionPolicy {
+            chat_days: days("RETENTION_CHAT_DAYS"),
+            tool_call_days: days("RETENTION_TOOL_CALL_DAYS"),
+            audit_days: days("RETENTION_AUDIT_DAYS"),
+            hour: dotenv::var("RETENTION_HOUR")
+                .ok()
+                .and_then(|hour| hour.trim().parse().ok())
+                .filter(|hour| *hour < 24)
+                .unwrap_or(3),
+        };
+        (policy.chat_days.is_some() || policy.tool_call_days.is_some() || policy.audit_days.is_some())
+            .then_some(policy)
+    }
+}
+
+fn days(var: &str) -> Option<i64> {
+    let value = dotenv::var(var).ok()?;
+    match parse_days(&value) {
+        Some(days) => Some(days),
+        None => {
+            tracing::warn!("Ignoring malformed {}={:?}", var, value);
+            None
+        }
+    }
+}
+
+fn parse_days(value: &str) -> Option<i64> {
+    value.trim().parse().ok().filter(|days| *days > 0)
+}
+
+// Time from `now` to the next `hour`:00 UTC
+fn until_next_run(now: DateTime<Utc>, hour: u32) -> std::time::Duration {
+    let at = NaiveTime::from_hms_opt(hour, 0, 0).unwrap();
+    let mut next = now.date_naive().and_time(at).and_utc();
+    if next <= now {
+        next += Duration::days(1);
+    }
+    (next - now).to_std().unwrap_or_default()
+}
+
+// Deletes what the policy no longer keeps, once a night
+pub async fn run_retention_sweep(repo: ChatRepository, policy: RetentionPolicy) {
+    loop {
+        tokio::time::sleep(until_next_run(Utc::now(), policy.hour)).await;
+
+        if let Some(days) = policy.chat_days {
+            match repo.purge_inactive_chats(days).await {
+                Ok(0) => {}
+                Ok(purged) => tracing::info!("Deleted {} chats inactive for {} days", purged, days),
+                Err(e) => tracing::error!("Failed to delete inactive chats: {}", e),
+            }
+        }
+        if let Some(days) = policy.tool_call_days {
+            match repo.purge_tool_call_confirmations(days).await {
+                Ok(0) => {}
+                Ok(purged) => tracing::info!("Deleted {} tool call confirmations", purged),
+                Err(e) => tracing::error!("Failed to delete tool call confirmations: {}", e),
+            }
+        }
+        if let Some(days) = policy.audit_days {
+            match repo.purge_audit_log(days).await {
+                Ok(0) => {}
+                Ok(purged) => tracing::info!("Deleted {} audit log entries", purged),
+                Err(e) => tracing::error!("Failed to delete audit log entries: {}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_parse_days() {
+        assert_eq!(parse_days(" 90 "), Some(90));
+        assert_eq!(parse_days("0"), None);
+        assert_eq!(parse_days("-5"), None);
+        assert_eq!(parse_days("forever"), None);
+    }
+
+    #[test]
+    fn test_until_next_run() {
+        let now = Utc.with_ymd_and_hms(2026, 3, 1, 1, 30, 0).unwrap();
+        assert_eq!(until_next_run(now, 3).as_secs(), 90 * 60);
+        let now = Utc.with_ymd_and_hms(2026, 3, 1, 3, 0, 0).unwrap();
+        assert_eq!(until_next_run(now, 3).as_secs(), 24 * 60 * 60);
+        let now = Utc.with_ymd_and_hms(2026, 3, 1, 23, 0, 0).unwrap();
+        assert_eq!(until_next_run(now, 0).as_secs(), 60 * 60);
+    }
+}
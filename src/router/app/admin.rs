@@ -0,0 +1,712 @@
+use axum::{
+    extract::{ConnectInfo, Extension, Form, Multipart, Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Redirect, Response},
+};
+use serde::{Deserialize, Serialize};
+use tera::Context;
+use tokio::io::AsyncWriteExt;
+use tower_cookies::{Cookie, Cookies};
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::audit::{self, AuditEvent, AuditSource};
+use crate::backup;
+use crate::database;
+use crate::data::model::QuotaOverride;
+use crate::middleware::{find_user, SESSION_COOKIE};
+use crate::quota::{self, Quota};
+use crate::session::{end_session, set_session_cookie, start_session};
+use crate::utils::password;
+use crate::{AppState, User};
+
+use super::auth::{app_url, invite_only, send_password_reset};
+use super::settings::{purge_account, render_audit_log, AuditFilter};
+
+// Holds the admin's own session token while they impersonate someone
+const IMPERSONATOR_COOKIE: &str = "rust-gpt-impersonator";
+
+#[derive(Deserialize, Debug)]
+pub struct AdminNotice {
+    notice: Option<String>,
+}
+
+#[axum::debug_handler]
+pub async fn admin_users(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Query(params): Query<AdminNotice>,
+) -> Result<Html<String>, StatusCode> {
+    let admin = current_user.as_ref().unwrap();
+
+    let users = state.chat_repo.admin_users().await.map_err(|e| {
+        tracing::error!("Failed to load users: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let mut context = Context::new();
+    context.insert("users", &users);
+    context.insert("admin_id", &admin.id);
+    context.insert("notice", &params.notice);
+    let view = state.tera.render("views/admin_users.html", &context).unwrap();
+
+    let mut context = Context::new();
+    context.insert("view", &view);
+    context.insert("current_user", &current_user);
+    context.insert("with_footer", &true);
+    let rendered = state.tera.render("views/main.html", &context).unwrap();
+
+    Ok(Html(rendered))
+}
+
+// Everyone's security events
+#[axum::debug_handler]
+pub async fn admin_audit_log(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Query(filter): Query<AuditFilter>,
+) -> Result<Html<String>, StatusCode> {
+    render_audit_log(&state, &current_user, None, filter).await
+}
+
+// Admins can't lock themselves out
+fn other_user(current_user: Option<User>, user_id: i64) -> Result<User, StatusCode> {
+    let admin = current_user.unwrap();
+    if admin.id == user_id {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    Ok(admin)
+}
+
+// Logged on the user's account, with the admin as the actor
+async fn record_admin_action(state: &AppState, admin: &User, user_id: i64, event: AuditEvent, source: AuditSource) {
+    let source = AuditSource {
+        actor: Some(admin.email.clone()),
+        ..source
+    };
+    audit::record(state, Some(user_id), event, None, source).await;
+}
+
+async fn set_disabled(state: &AppState, user_id: i64, disabled: bool) -> Result<(), StatusCode> {
+    match state.chat_repo.set_user_disabled(user_id, disabled).await {
+        Ok(0) => Err(StatusCode::NOT_FOUND),
+        Ok(_) => Ok(()),
+        Err(e) => {
+            tracing::error!(user_id, "Failed to update user: {}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+#[axum::debug_handler]
+pub async fn disable_user(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Extension(source): Extension<AuditSource>,
+    Path(user_id): Path<i64>,
+) -> Result<Redirect, StatusCode> {
+    let admin = other_user(current_user, user_id)?;
+    set_disabled(&state, user_id, true).await?;
+    tracing::warn!("Admin {} disabled user {}", admin.email, user_id);
+    record_admin_action(&state, &admin, user_id, AuditEvent::AccountDisabled, source).await;
+    Ok(Redirect::to("/admin/users?notice=disabled"))
+}
+
+#[axum::debug_handler]
+pub async fn enable_user(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Extension(source): Extension<AuditSource>,
+    Path(user_id): Path<i64>,
+) -> Result<Redirect, StatusCode> {
+    let admin = other_user(current_user, user_id)?;
+    set_disabled(&state, user_id, false).await?;
+    tracing::warn!("Admin {} enabled user {}", admin.email, user_id);
+    record_admin_action(&state, &admin, user_id, AuditEvent::AccountEnabled, source).await;
+    Ok(Redirect::to("/admin/users?notice=enabled"))
+}
+
+// Clears the failed logins and lifts the lock they caused
+#[axum::debug_handler]
+pub async fn unlock_user(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Extension(source): Extension<AuditSource>,
+    Path(user_id): Path<i64>,
+) -> Result<Redirect, StatusCode> {
+    let admin = current_user.unwrap();
+    match state.chat_repo.reset_failed_logins(user_id).await {
+        Ok(0) => return Err(StatusCode::NOT_FOUND),
+        Ok(_) => {}
+        Err(e) => {
+            tracing::error!(user_id, "Failed to unlock user: {}", e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+    tracing::warn!("Admin {} unlocked user {}", admin.email, user_id);
+    record_admin_action(&state, &admin, user_id, AuditEvent::AccountUnlocked, source).await;
+    Ok(Redirect::to("/admin/users?notice=unlocked"))
+}
+
+#[axum::debug_handler]
+pub async fn delete_user(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Path(user_id): Path<i64>,
+) -> Result<Redirect, StatusCode> {
+    let admin = other_user(current_user, user_id)?;
+    if purge_account(&state, user_id).await? == 0 {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    tracing::warn!("Admin {} deleted user {}", admin.email, user_id);
+    Ok(Redirect::to("/admin/users?notice=deleted"))
+}
+
+// Emails the user a password reset link; the admin never sees the password
+#[axum::debug_handler]
+pub async fn reset_user_password(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Path(user_id): Path<i64>,
+) -> Result<Redirect, StatusCode> {
+    let admin = current_user.unwrap();
+
+    let email = state
+        .chat_repo
+        .get_user_email(user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    send_password_reset(&state, &email).await.map_err(|e| {
+        tracing::error!(user_id, "Failed to create reset link: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    tracing::warn!("Admin {} sent a password reset to user {}", admin.email, user_id);
+    Ok(Redirect::to("/admin/users?notice=reset"))
+}
+
+// Blank fields keep the default from the environment and 0 lifts the limit
+#[derive(Deserialize, Debug)]
+pub struct QuotaForm {
+    daily_tokens: String,
+    monthly_tokens: String,
+    daily_messages: String,
+    monthly_messages: String,
+}
+
+#[derive(Serialize)]
+struct QuotaField {
+    name: &'static str,
+    label: &'static str,
+    value: Option<i64>,
+    default: Option<i64>,
+}
+
+fn parse_limit(value: &str) -> Result<Option<i64>, StatusCode> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(None);
+    }
+    match value.parse::<i64>() {
+        Ok(limit) if limit >= 0 => Ok(Some(limit)),
+        _ => Err(StatusCode::UNPROCESSABLE_ENTITY),
+    }
+}
+
+#[axum::debug_handler]
+pub async fn user_quota(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Path(user_id): Path<i64>,
+) -> Result<Html<String>, StatusCode> {
+    let email = state
+        .chat_repo
+        .get_user_email(user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let quota_override = state.chat_repo.get_quota_override(user_id).await;
+    let status = quota::status(&state, user_id).await;
+    let (quota_override, status) = match (quota_override, status) {
+        (Ok(quota_override), Ok(status)) => (quota_override.unwrap_or_default(), status),
+        (Err(e), _) | (_, Err(e)) => {
+            tracing::error!(user_id, "Failed to load quota: {}", e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let default = Quota::from_env();
+    let fields = [
+        ("daily_tokens", "Tokens per day", quota_override.daily_tokens, default.daily_tokens),
+        ("monthly_tokens", "Tokens per month", quota_override.monthly_tokens, default.monthly_tokens),
+        ("daily_messages", "Messages per day", quota_override.daily_messages, default.daily_messages),
+        ("monthly_messages", "Messages per month", quota_override.monthly_messages, default.monthly_messages),
+    ]
+    .map(|(name, label, value, default)| QuotaField { name, label, value, default });
+
+    let mut context = Context::new();
+    context.insert("user_id", &user_id);
+    context.insert("email", &email);
+    context.insert("fields", &fields);
+    context.insert("status", &status);
+    context.insert("exceeded", &status.exceeded());
+    let view = state.tera.render("views/admin_quota.html", &context).unwrap();
+
+    let mut context = Context::new();
+    context.insert("view", &view);
+    context.insert("current_user", &current_user);
+    context.insert("with_footer", &true);
+    let rendered = state.tera.render("views/main.html", &context).unwrap();
+
+    Ok(Html(rendered))
+}
+
+#[axum::debug_handler]
+pub async fn update_user_quota(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Path(user_id): Path<i64>,
+    Form(form): Form<QuotaForm>,
+) -> Result<Redirect, StatusCode> {
+    let admin = current_user.unwrap();
+    let quota_override = QuotaOverride {
+        daily_tokens: parse_limit(&form.daily_tokens)?,
+        monthly_tokens: parse_limit(&form.monthly_tokens)?,
+        daily_messages: parse_limit(&form.daily_messages)?,
+        monthly_messages: parse_limit(&form.monthly_messages)?,
+    };
+    state
+        .chat_repo
+        .get_user_email(user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    state
+        .chat_repo
+        .set_quota_override(user_id, &quota_override)
+        .await
+        .map_err(|e| {
+            tracing::error!(user_id, "Failed to save quota: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    tracing::warn!("Admin {} changed the quota of user {}", admin.email, user_id);
+    Ok(Redirect::to("/admin/users?notice=quota"))
+}
+
+#[axum::debug_handler]
+pub async fn admin_invites(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Query(params): Query<AdminNotice>,
+) -> Result<Html<String>, StatusCode> {
+    let invites = state.chat_repo.get_invites().await.map_err(|e| {
+        tracing::error!("Failed to load invites: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let mut context = Context::new();
+    context.insert("invites", &invites);
+    context.insert("invite_only", &invite_only());
+    context.insert("app_url", &app_url());
+    context.insert("notice", &params.notice);
+    let view = state.tera.render("views/admin_invites.html", &context).unwrap();
+
+    let mut context = Context::new();
+    context.insert("view", &view);
+    context.insert("current_user", &current_user);
+    context.insert("with_footer", &true);
+    let rendered = state.tera.render("views/main.html", &context).unwrap();
+
+    Ok(Html(rendered))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewInvite {
+    note: String,
+}
+
+#[axum::debug_handler]
+pub async fn create_invite(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Form(invite): Form<NewInvite>,
+) -> Result<Redirect, StatusCode> {
+    let admin = current_user.unwrap();
+    let note = invite.note.trim();
+    let note = (!note.is_empty()).then_some(note);
+    let code = uuid::Uuid::new_v4().simple().to_string();
+
+    let invite_id = state
+        .chat_repo
+        .create_invite(admin.id, &code, note)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to create invite: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    tracing::warn!("Admin {} created invite {}", admin.email, invite_id);
+    Ok(Redirect::to("/admin/invites?notice=created"))
+}
+
+#[axum::debug_handler]
+pub async fn revoke_invite(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Path(invite_id): Path<i64>,
+) -> Result<Redirect, StatusCode> {
+    let admin = current_user.unwrap();
+    match state.chat_repo.revoke_invite(invite_id).await {
+        Ok(0) => return Err(StatusCode::NOT_FOUND),
+        Ok(_) => {}
+        Err(e) => {
+            tracing::error!(invite_id, "Failed to revoke invite: {}", e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+    tracing::warn!("Admin {} revoked invite {}", admin.email, invite_id);
+    Ok(Redirect::to("/admin/invites?notice=revoked"))
+}
+
+// Signs the admin in as the user for support. The admin's own session is put
+// aside in a cookie until `stop_impersonating`
+#[axum::debug_handler]
+pub async fn impersonate_user(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    cookies: Cookies,
+    headers: HeaderMap,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(source): Extension<AuditSource>,
+    Path(user_id): Path<i64>,
+) -> Result<Redirect, StatusCode> {
+    let admin = other_user(current_user, user_id)?;
+    let admin_session = cookies
+        .get(SESSION_COOKIE)
+        .map(|cookie| cookie.value().to_string())
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    // Disabled accounts aren't found, and couldn't use the session anyway
+    find_user(&state, user_id)
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    start_session(&state, &cookies, user_id, &headers, Some(addr), Some(admin.id), None)
+        .await
+        .map_err(|e| {
+            tracing::error!(user_id, "Failed to create impersonation session: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let cookie = Cookie::build((IMPERSONATOR_COOKIE, admin_session))
+        .path("/")
+        .http_only(true)
+        .build();
+    cookies.add(cookie);
+
+    tracing::warn!("Admin {} is impersonating user {}", admin.email, user_id);
+    record_admin_action(&state, &admin, user_id, AuditEvent::Impersonated, source).await;
+    Ok(Redirect::to("/chat"))
+}
+
+// Ends the impersonation session and gives the admin their own back. Not
+// behind the admin check, since the request comes as the impersonated user
+#[axum::debug_handler]
+pub async fn stop_impersonating(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    cookies: Cookies,
+) -> Result<Redirect, StatusCode> {
+    if current_user.and_then(|user| user.impersonator).is_none() {
+        return Ok(Redirect::to("/"));
+    }
+
+    if let Some(session) = cookies.get(SESSION_COOKIE) {
+        state
+            .chat_repo
+            .delete_session_by_token(&password::hash_token(session.value()))
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+
+    match cookies.get(IMPERSONATOR_COOKIE) {
+        Some(admin_session) => {
+            set_session_cookie(&cookies, admin_session.value().to_string());
+            let mut cookie = Cookie::build((IMPERSONATOR_COOKIE, "")).path("/").build();
+            cookie.make_removal();
+            cookies.add(cookie);
+            Ok(Redirect::to("/admin/users"))
+        }
+        None => {
+            end_session(&cookies);
+            Ok(Redirect::to("/login"))
+        }
+    }
+}
+
+#[axum::debug_handler]
+pub async fn admin_backups(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Query(params): Query<AdminNotice>,
+) -> Result<Html<String>, StatusCode> {
+    let backups = backup::list().await.map_err(|e| {
+        tracing::error!("Failed to list backups: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let mut context = Context::new();
+    context.insert("backups", &backups);
+    context.insert("backup_dir", &backup::backup_dir().to_string_lossy());
+    context.insert("notice", &params.notice);
+    let view = state.tera.render("views/admin_backups.html", &context).unwrap();
+
+    let mut context = Context::new();
+    context.insert("view", &view);
+    context.insert("current_user", &current_user);
+    context.insert("with_footer", &true);
+    let rendered = state.tera.render("views/main.html", &context).unwrap();
+
+    Ok(Html(rendered))
+}
+
+#[axum::debug_handler]
+pub async fn create_backup(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Redirect, StatusCode> {
+    let admin = current_user.unwrap();
+    let name = backup::create(&state.pool, "backup").await.map_err(|e| {
+        tracing::error!("Failed to back up the database: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    tracing::warn!("Admin {} created backup {}", admin.email, name);
+    Ok(Redirect::to("/admin/backups?notice=created"))
+}
+
+#[axum::debug_handler]
+pub async fn download_backup(Path(name): Path<String>) -> Result<Response, StatusCode> {
+    let path = backup::path_for(&name).ok_or(StatusCode::NOT_FOUND)?;
+    let file = tokio::fs::File::open(path).await.map_err(|_| StatusCode::NOT_FOUND)?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/vnd.sqlite3".to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", name)),
+        ],
+        axum::body::Body::from_stream(tokio_util::io::ReaderStream::new(file)),
+    )
+        .into_response())
+}
+
+// Replaces the whole database with an uploaded backup. The current one is
+// backed up first, and the app answers 503 until the copy is done
+#[axum::debug_handler]
+pub async fn restore_backup(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    mut multipart: Multipart,
+) -> Result<Response, StatusCode> {
+    let admin = current_user.unwrap();
+
+    let dir = backup::backup_dir();
+    tokio::fs::create_dir_all(&dir).await.map_err(|e| {
+        tracing::error!("Failed to create the backup directory: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    // Not named `.db`, so it never shows up as a backup
+    let upload = dir.join(format!("upload-{}.tmp", uuid::Uuid::new_v4().simple()));
+    let result = restore_upload(&state, &mut multipart, &upload).await;
+    let _ = tokio::fs::remove_file(&upload).await;
+
+    let notice = match result {
+        Ok(()) => {
+            tracing::warn!("Admin {} restored the database from a backup", admin.email);
+            "restored"
+        }
+        Err(RestoreFailure::Status(status)) => return Err(status),
+        Err(RestoreFailure::Backup(backup::BackupError::Invalid(reason))) => {
+            tracing::warn!("Admin {} uploaded an invalid backup: {}", admin.email, reason);
+            "invalid"
+        }
+        Err(RestoreFailure::Backup(e)) => {
+            tracing::error!("Failed to restore the database: {}", e);
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    // The form posts through htmx
+    let to = format!("/admin/backups?notice={}", notice);
+    Ok(([("HX-Redirect", to.clone())], Redirect::to(&to)).into_response())
+}
+
+enum RestoreFailure {
+    Status(StatusCode),
+    Backup(backup::BackupError),
+}
+
+impl From<backup::BackupError> for RestoreFailure {
+    fn from(e: backup::BackupError) -> Self {
+        RestoreFailure::Backup(e)
+    }
+}
+
+async fn restore_upload(
+    state: &Arc<AppState>,
+    multipart: &mut Multipart,
+    upload: &std::path::Path,
+) -> Result<(), RestoreFailure> {
+    let bad_request = || RestoreFailure::Status(StatusCode::BAD_REQUEST);
+
+    let mut received = false;
+    while let Some(mut field) = multipart.next_field().await.map_err(|_| bad_request())? {
+        if field.name() != Some("backup") {
+            continue;
+        }
+        // Streamed to disk, a backup can be larger than memory
+        let mut file = tokio::fs::File::create(upload).await.map_err(backup::BackupError::from)?;
+        while let Some(chunk) = field.chunk().await.map_err(|_| bad_request())? {
+            file.write_all(&chunk).await.map_err(backup::BackupError::from)?;
+        }
+        file.flush().await.map_err(backup::BackupError::from)?;
+        received = true;
+    }
+    if !received {
+        return Err(bad_request());
+    }
+
+    let migrator = database::migrator().await.map_err(backup::BackupError::from)?;
+    backup::validate(upload, &migrator).await?;
+    backup::create(&state.pool, "pre-restore").await?;
+
+    let pool = state.pool.clone();
+    let upload = upload.to_path_buf();
+    let cache = state.chat_repo.cache.clone();
+    in_maintenance(state, async move {
+        let restored = backup::restore(&pool, &upload, &migrator).await;
+        // What's cached came from the database that was replaced
+        cache.clear();
+        restored
+    })
+        .await
+        .map_err(RestoreFailure::Status)??;
+    Ok(())
+}
+
+// Clears the maintenance flag however the task ends
+struct MaintenanceGuard(Arc<AtomicBool>);
+
+impl Drop for MaintenanceGuard {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+// Runs `task` while the app answers 503, one at a time. It runs to the end
+// even if the admin's connection drops
+async fn in_maintenance<T: Send + 'static>(
+    state: &AppState,
+    task: impl Future<Output = T> + Send + 'static,
+) -> Result<T, StatusCode> {
+    if state
+        .maintenance
+        .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+        .is_err()
+    {
+        return Err(StatusCode::CONFLICT);
+    }
+    let guard = MaintenanceGuard(state.maintenance.clone());
+    tokio::spawn(async move {
+        let _guard = guard;
+        task.await
+    })
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+#[axum::debug_handler]
+pub async fn admin_db(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Query(params): Query<AdminNotice>,
+) -> Result<Html<String>, StatusCode> {
+    let migrator = database::migrator().await.map_err(|e| {
+        tracing::error!("Failed to load migrations: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let status = database::status(&state.pool, &migrator).await.map_err(|e| {
+        tracing::error!("Failed to load the database status: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let mut context = Context::new();
+    context.insert("status", &status);
+    context.insert("notice", &params.notice);
+    let view = state.tera.render("views/admin_db.html", &context).unwrap();
+
+    let mut context = Context::new();
+    context.insert("view", &view);
+    context.insert("current_user", &current_user);
+    context.insert("with_footer", &true);
+    let rendered = state.tera.render("views/main.html", &context).unwrap();
+
+    Ok(Html(rendered))
+}
+
+#[axum::debug_handler]
+pub async fn vacuum_db(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Redirect, StatusCode> {
+    let admin = current_user.unwrap();
+    // Holds a write lock until it's done
+    let pool = state.pool.clone();
+    in_maintenance(&state, async move { database::vacuum(&pool).await })
+        .await?
+        .map_err(|e| {
+            tracing::error!("Failed to vacuum the database: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    tracing::warn!("Admin {} vacuumed the database", admin.email);
+    Ok(Redirect::to("/admin/db?notice=vacuumed"))
+}
+
+#[axum::debug_handler]
+pub async fn analyze_db(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Redirect, StatusCode> {
+    let admin = current_user.unwrap();
+    database::analyze(&state.pool).await.map_err(|e| {
+        tracing::error!("Failed to analyze the database: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    tracing::warn!("Admin {} analyzed the database", admin.email);
+    Ok(Redirect::to("/admin/db?notice=analyzed"))
+}
+
+// Applies migrations added since startup. The database is backed up first
+#[axum::debug_handler]
+pub async fn migrate_db(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Redirect, StatusCode> {
+    let admin = current_user.unwrap();
+    let migrator = database::migrator().await.map_err(|e| {
+        tracing::error!("Failed to load migrations: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let backup = backup::create(&state.pool, "pre-migrate").await.map_err(|e| {
+        tracing::error!("Failed to back up the database before migrating: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let pool = state.pool.clone();
+    let result = in_maintenance(&state, async move { migrator.run(&*pool).await }).await?;
+    if let Err(e) = result {
+        tracing::error!("Admin {} failed to migrate the database: {}", admin.email, e);
+        return Ok(Redirect::to("/admin/db?notice=migrate_failed"));
+    }
+    tracing::warn!("Admin {} migrated the database, backup {}", admin.email, backup);
+    Ok(Redirect::to("/admin/db?notice=migrated"))
+}
@@ -0,0 +1,586 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{sse::Event, Html, IntoResponse, Response, Sse},
+    Extension, Json,
+};
+use chrono::{Duration, NaiveDate, Utc};
+use futures::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use utoipa::{
+    openapi::security::{Http, HttpAuthScheme, SecurityScheme},
+    IntoParams, Modify, OpenApi, ToSchema,
+};
+
+use std::sync::Arc;
+
+use super::chat::{
+    authorize_chat, count_workspace_chats, create_chat_with_message, load_chat_page, load_chat_stats,
+    start_background_generation, stream_generation_events, ChatError,
+};
+use crate::ai::stream::list_engines;
+use crate::data::model::{Agent, Chat, ChatMessagePair, ChatRole, ChatStats, DailyCost, PairUsage};
+use crate::{AppState, User};
+
+// JSON counterparts of the HTMX routes, for scripts and mobile apps. Requests
+// are authenticated with an API token by `middleware::extract_user`, and
+// `middleware::api_auth` and `require_scope` keep out everything else.
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "RustGPT API",
+        version = "1",
+        description = "Chats, messages and generation for scripts and mobile apps. \
+            Create a token on the settings page and send it as a bearer token. \
+            Read-only tokens get 403 from routes that change chats or generate.",
+        license(name = "AGPL-3.0")
+    ),
+    paths(
+        list_chats,
+        create_chat,
+        get_chat,
+        delete_chat,
+        chat_stats,
+        chat_usage,
+        usage,
+        add_message,
+        generate_stream,
+        generate_background,
+        list_agents,
+        list_providers
+    ),
+    components(schemas(ApiError, Created, PairCreated)),
+    modifiers(&BearerToken),
+    security(("api_token" = []))
+)]
+pub struct ApiDoc;
+
+// Every route takes a token from the settings page
+struct BearerToken;
+
+impl Modify for BearerToken {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "api_token",
+                SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+            );
+        }
+    }
+}
+
+// Body of every error response, see `ChatError`
+#[derive(Serialize, ToSchema)]
+pub struct ApiError {
+    error: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Created {
+    id: i64,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct PairCreated {
+    pair_id: i64,
+}
+
+pub async fn openapi_json() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+// Swagger UI for the spec above
+pub async fn api_docs(State(state): State<Arc<AppState>>) -> Result<Html<String>, ChatError> {
+    state
+        .tera
+        .render("views/api_docs.html", &tera::Context::new())
+        .map(Html)
+        .map_err(|e| ChatError::ServerError(format!("Failed to render API docs: {}", e)))
+}
+
+const DEFAULT_PAGE_SIZE: i64 = 50;
+const MAX_PAGE_SIZE: i64 = 200;
+
+// Listings come a page at a time; pass a response's `next_cursor` back as
+// `cursor` for the next one
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PageParams {
+    /// `next_cursor` of the previous page
+    cursor: Option<i64>,
+    /// Items per page, 50 by default and at most 200
+    limit: Option<i64>,
+}
+
+impl PageParams {
+    fn limit(&self) -> i64 {
+        self.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE)
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ChatList {
+    chats: Vec<Chat>,
+    shared_chats: Vec<Chat>,
+    // Absent on the last page
+    next_cursor: Option<i64>,
+    // Chats of the workspace over all pages, not counting shared ones
+    total: i64,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/chats",
+    tag = "chats",
+    params(PageParams),
+    responses((status = 200, description = "A page of chats of the user's current workspace, and chats shared with the user", body = ChatList))
+)]
+pub async fn list_chats(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Query(page): Query<PageParams>,
+) -> Result<Json<ChatList>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    let (chats, next_cursor) = load_chat_page(&state, &current_user, None, page.cursor, page.limit()).await?;
+    let total = count_workspace_chats(&state, &current_user, None).await?;
+    let shared_chats = state
+        .chat_repo
+        .get_shared_chats(current_user.id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to retrieve shared chats: {}", e)))?;
+
+    Ok(Json(ChatList {
+        chats,
+        shared_chats,
+        next_cursor,
+        total,
+    }))
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct NewChat {
+    message: String,
+    agent_id: Option<i64>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/chats",
+    tag = "chats",
+    request_body = NewChat,
+    responses(
+        (status = 201, description = "Chat created with its first message", body = Created),
+        (status = 400, description = "Empty message or unknown agent", body = ApiError)
+    )
+)]
+pub async fn create_chat(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Json(new_chat): Json<NewChat>,
+) -> Result<Response, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    if new_chat.message.trim().is_empty() {
+        return Err(ChatError::InvalidMessage);
+    }
+
+    let chat_id =
+        create_chat_with_message(&state, &current_user, &new_chat.message, new_chat.agent_id).await?;
+
+    Ok((StatusCode::CREATED, Json(Created { id: chat_id })).into_response())
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ChatDetail {
+    chat: Chat,
+    role: ChatRole,
+    // A response is being generated for the latest message
+    generating: bool,
+    messages: Vec<ChatMessagePair>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/chats/{id}",
+    tag = "chats",
+    params(("id" = i64, Path, description = "Chat id")),
+    responses(
+        (status = 200, description = "The chat with its messages", body = ChatDetail),
+        (status = 404, description = "No such chat, or not shared with the user", body = ApiError)
+    )
+)]
+pub async fn get_chat(
+    Path(chat_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Json<ChatDetail>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+    let role = authorize_chat(&state, current_user.id, chat_id, ChatRole::Viewer).await?;
+
+    let mut chat = state
+        .chat_repo
+        .get_chat(chat_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to retrieve chat: {}", e)))?
+        .ok_or(ChatError::ChatNotFound)?;
+    // Only the owner manages the public link
+    if role != ChatRole::Owner {
+        chat.share_token = None;
+    }
+
+    let messages = state
+        .chat_repo
+        .retrieve_chat(chat_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to retrieve chat: {}", e)))?;
+
+    Ok(Json(ChatDetail {
+        chat,
+        role,
+        generating: state.generation_hub.is_generating(chat_id),
+        messages,
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/chats/{id}/stats",
+    tag = "chats",
+    params(("id" = i64, Path, description = "Chat id")),
+    responses(
+        (status = 200, description = "Message counts, token usage, cost and latency", body = ChatStats),
+        (status = 404, description = "No such chat, or not shared with the user", body = ApiError)
+    )
+)]
+pub async fn chat_stats(
+    Path(chat_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Json<ChatStats>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+    authorize_chat(&state, current_user.id, chat_id, ChatRole::Viewer).await?;
+
+    Ok(Json(load_chat_stats(&state, chat_id).await?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/chats/{id}/usage",
+    tag = "usage",
+    params(("id" = i64, Path, description = "Chat id")),
+    responses(
+        (status = 200, description = "Tokens and cost recorded for every response in the chat", body = PairUsage),
+        (status = 404, description = "No such chat, or not shared with the user", body = ApiError)
+    )
+)]
+pub async fn chat_usage(
+    Path(chat_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Json<PairUsage>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+    authorize_chat(&state, current_user.id, chat_id, ChatRole::Viewer).await?;
+
+    let usage = state
+        .chat_repo
+        .chat_usage(chat_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to load chat usage: {}", e)))?;
+    Ok(Json(usage))
+}
+
+// UTC days, both ends included
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct UsageParams {
+    /// First day, 30 days ago by default
+    from: Option<NaiveDate>,
+    /// Last day, today by default
+    to: Option<NaiveDate>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct UsageReport {
+    from: NaiveDate,
+    to: NaiveDate,
+    total: PairUsage,
+    // Days without responses are left out
+    days: Vec<DailyCost>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/usage",
+    tag = "usage",
+    params(UsageParams),
+    responses((status = 200, description = "Tokens and cost over the user's chats, in total and per day", body = UsageReport))
+)]
+pub async fn usage(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Query(params): Query<UsageParams>,
+) -> Result<Json<UsageReport>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+    let to = params.to.unwrap_or_else(|| Utc::now().date_naive());
+    let from = params.from.unwrap_or(to - Duration::days(30));
+    let (first, last) = (from.to_string(), to.to_string());
+
+    let total = state
+        .chat_repo
+        .user_usage(current_user.id, &first, &last)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to load usage: {}", e)))?;
+    let days = state
+        .chat_repo
+        .daily_costs(current_user.id, &first, &last)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to load usage: {}", e)))?;
+
+    Ok(Json(UsageReport { from, to, total, days }))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/chats/{id}",
+    tag = "chats",
+    params(("id" = i64, Path, description = "Chat id")),
+    responses(
+        (status = 204, description = "Chat moved to the trash, where it is kept for 30 days"),
+        (status = 403, description = "Only the owner can delete a chat", body = ApiError),
+        (status = 404, description = "No such chat", body = ApiError)
+    )
+)]
+pub async fn delete_chat(
+    Path(chat_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<StatusCode, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+    authorize_chat(&state, current_user.id, chat_id, ChatRole::Owner).await?;
+
+    state
+        .chat_repo
+        .delete_chat(chat_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to delete chat: {}", e)))?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct NewMessage {
+    message: String,
+}
+
+// Adds the message only; ask for the response with one of the generate routes
+#[utoipa::path(
+    post,
+    path = "/api/v1/chats/{id}/messages",
+    tag = "messages",
+    params(("id" = i64, Path, description = "Chat id")),
+    request_body = NewMessage,
+    responses(
+        (status = 201, description = "Message added", body = PairCreated),
+        (status = 403, description = "Viewers can't send messages", body = ApiError)
+    )
+)]
+pub async fn add_message(
+    Path(chat_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Json(new_message): Json<NewMessage>,
+) -> Result<Response, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+    authorize_chat(&state, current_user.id, chat_id, ChatRole::Editor).await?;
+
+    if new_message.message.trim().is_empty() {
+        return Err(ChatError::InvalidMessage);
+    }
+
+    let pair_id = state
+        .chat_repo
+        .add_message_block(chat_id, &new_message.message)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to add message: {}", e)))?;
+
+    Ok((StatusCode::CREATED, Json(PairCreated { pair_id })).into_response())
+}
+
+// Stream the response to the latest message. A `start` event carries the pair
+// the response is stored on, then each generation event follows as JSON
+#[utoipa::path(
+    get,
+    path = "/api/v1/chats/{id}/generate",
+    tag = "messages",
+    params(("id" = i64, Path, description = "Chat id")),
+    responses(
+        (status = 200, description = "Server-sent events: `start` with the pair id, then one \
+            `{\"type\", \"data\"}` object per generation event until `end`", content_type = "text/event-stream"),
+        (status = 400, description = "No API key configured", body = ApiError),
+        (status = 429, description = "The chat owner's usage quota is used up", body = ApiError)
+    )
+)]
+pub async fn generate_stream(
+    Path(chat_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Sse<impl futures::Stream<Item = Result<Event, axum::Error>>>, ChatError> {
+    let (events, pair_id) = stream_generation_events(&state, current_user, chat_id).await?;
+
+    let start = Event::default()
+        .event("start")
+        .json_data(PairCreated { pair_id });
+    let events = events.map(|event| Event::default().json_data(event));
+
+    Ok(Sse::new(stream::once(async { start }).chain(events)))
+}
+
+// Generate the response in the background; poll the chat until `generating`
+// turns false to read it
+#[utoipa::path(
+    post,
+    path = "/api/v1/chats/{id}/generate",
+    tag = "messages",
+    params(("id" = i64, Path, description = "Chat id")),
+    responses(
+        (status = 202, description = "Generation started", body = PairCreated),
+        (status = 400, description = "No API key configured", body = ApiError),
+        (status = 429, description = "The chat owner's usage quota is used up", body = ApiError)
+    )
+)]
+pub async fn generate_background(
+    Path(chat_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Response, ChatError> {
+    let pair_id = start_background_generation(&state, current_user, chat_id).await?;
+
+    Ok((StatusCode::ACCEPTED, Json(PairCreated { pair_id })).into_response())
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct AgentList {
+    agents: Vec<Agent>,
+    // Absent on the last page
+    next_cursor: Option<i64>,
+    total: i64,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/agents",
+    tag = "agents",
+    params(PageParams),
+    responses((status = 200, description = "A page of the user's agents", body = AgentList))
+)]
+pub async fn list_agents(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Query(page): Query<PageParams>,
+) -> Result<Json<AgentList>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+    let limit = page.limit();
+
+    let mut agents = state
+        .chat_repo
+        .get_agents(current_user.id, current_user.current_org_id, page.cursor, limit + 1)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to retrieve agents: {}", e)))?;
+    let next_cursor = if agents.len() as i64 > limit {
+        agents.pop();
+        agents.last().map(|agent| agent.id)
+    } else {
+        None
+    };
+    let total = state
+        .chat_repo
+        .count_agents(current_user.id, current_user.current_org_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to count agents: {}", e)))?;
+
+    Ok(Json(AgentList {
+        agents,
+        next_cursor,
+        total,
+    }))
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Provider {
+    name: &'static str,
+    default_model: String,
+    api_key_configured: bool,
+    // Empty until an API key is configured
+    models: Vec<String>,
+}
+
+// The model provider configured in settings, with the models the key can use
+#[utoipa::path(
+    get,
+    path = "/api/v1/providers",
+    tag = "providers",
+    responses(
+        (status = 200, description = "Configured model providers", body = [Provider]),
+        (status = 502, description = "The provider couldn't be reached", body = ApiError)
+    )
+)]
+pub async fn list_providers(
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Json<Vec<Provider>>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    let key = current_user
+        .openai_api_key
+        .filter(|key| !key.trim().is_empty());
+    let models = match &key {
+        Some(key) => list_engines(key)
+            .await
+            .map_err(|e| ChatError::NetworkError(format!("Failed to list models: {}", e)))?
+            .into_iter()
+            .map(|model| model.id)
+            .collect(),
+        None => Vec::new(),
+    };
+
+    Ok(Json(vec![Provider {
+        name: "siliconflow",
+        default_model: current_user
+            .model
+            .unwrap_or_else(|| "Qwen/Qwen2.5-7B-Instruct".to_string()),
+        api_key_configured: key.is_some(),
+        models,
+    }]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_openapi_documents_every_route() {
+        let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let paths = spec["paths"].as_object().unwrap();
+        for path in [
+            "/api/v1/chats",
+            "/api/v1/chats/{id}",
+            "/api/v1/chats/{id}/stats",
+            "/api/v1/chats/{id}/messages",
+            "/api/v1/chats/{id}/generate",
+            "/api/v1/agents",
+            "/api/v1/providers",
+        ] {
+            assert!(paths.contains_key(path), "{} is not documented", path);
+        }
+        assert!(paths["/api/v1/chats/{id}/generate"]["post"].is_object());
+        assert_eq!(
+            spec["components"]["securitySchemes"]["api_token"]["scheme"],
+            "bearer"
+        );
+        assert_eq!(
+            spec["components"]["schemas"]["ChatRole"]["enum"],
+            serde_json::json!(["viewer", "editor", "owner"])
+        );
+    }
+}
@@ -1,21 +1,38 @@
 use axum::{
-    extract::State,
-    http::StatusCode,
+    extract::{ConnectInfo, Path, Query, State},
+    http::{HeaderMap, StatusCode},
     response::{Html, IntoResponse, Redirect, Response},
-    Form, Json,
+    Extension, Form, Json,
 };
 
 use serde::Deserialize;
 use tera::Context;
-use tower_cookies::{Cookie, Cookies};
+use tower_cookies::{cookie::SameSite, Cookie, Cookies};
 
+use std::net::SocketAddr;
 use std::sync::Arc;
 
+use crate::audit::{self, AuditEvent, AuditSource};
+use crate::data::model::{FailedLogin, OAuthSignIn};
+use crate::middleware::SESSION_COOKIE;
+use crate::oauth::{self, Attempt, Provider};
+use crate::session::{self, end_session, start_session};
+use crate::utils::password;
 use crate::{AppState, User};
 
+use super::guest::guest_mode;
+
 pub async fn login(State(state): State<Arc<AppState>>) -> Html<String> {
+    let providers: Vec<_> = Provider::ALL
+        .into_iter()
+        .filter(|provider| provider.is_configured())
+        .map(|provider| serde_json::json!({ "slug": provider.slug(), "name": provider.name() }))
+        .collect();
+
     let mut context = Context::new();
     context.insert("name", "World");
+    context.insert("oauth_providers", &providers);
+    context.insert("guest_mode", &guest_mode());
     let home = state.tera.render("views/login.html", &context).unwrap();
 
     let mut context = Context::new();
@@ -28,6 +45,10 @@ pub async fn login(State(state): State<Arc<AppState>>) -> Html<String> {
 #[derive(Debug)]
 pub enum LogInError {
     InvalidCredentials,
+    // Too many failed logins to the account, see `login_failed`
+    Locked,
+    // Too many failed logins from the client's IP
+    TooManyAttempts,
     DatabaseError(String),
 }
 
@@ -39,6 +60,16 @@ impl IntoResponse for LogInError {
                 Json("Invalid Username or Password"),
             )
                 .into_response(),
+            LogInError::Locked => (
+                StatusCode::LOCKED,
+                Json("Too many failed logins. Try again later, or sign in with an email link."),
+            )
+                .into_response(),
+            LogInError::TooManyAttempts => (
+                StatusCode::TOO_MANY_REQUESTS,
+                Json("Too many failed logins. Try again later."),
+            )
+                .into_response(),
             LogInError::DatabaseError(message) => {
                 (StatusCode::INTERNAL_SERVER_ERROR, Json(message)).into_response()
             }
@@ -50,14 +81,101 @@ impl IntoResponse for LogInError {
 pub struct LogIn {
     email: String,
     password: String,
+    // The "Remember me" checkbox
+    remember: Option<String>,
+}
+
+// Failed logins that lock an account, and for how long
+const MAX_FAILED_LOGINS: i64 = 5;
+const LOCKOUT_MINUTES: i64 = 15;
+// Failed logins from one IP within `LOCKOUT_MINUTES` before it is turned away
+const MAX_FAILED_LOGINS_PER_IP: i64 = 20;
+
+// Counts the failure and tells the owner when it locks their account. The
+// answer comes after a delay that grows with the IP's recent failures,
+// whether or not the email has an account
+async fn login_failed(state: &Arc<AppState>, email: &str, source: AuditSource) -> LogInError {
+    let ip = source.ip.map(|ip| ip.to_string());
+    let ip = ip.as_deref();
+    let failed = state
+        .chat_repo
+        .record_failed_login(email, ip, MAX_FAILED_LOGINS, LOCKOUT_MINUTES)
+        .await;
+
+    // The email tried goes in the log only when it has no account
+    match &failed {
+        Ok(Some(failed)) => {
+            audit::record(state, Some(failed.user_id), AuditEvent::LoginFailed, None, source.clone()).await
+        }
+        Ok(None) => audit::record(state, None, AuditEvent::LoginFailed, Some(email), source.clone()).await,
+        Err(_) => {}
+    }
+
+    match failed {
+        Ok(Some(FailedLogin { user_id, failed_logins, locked_until: Some(until) })) => {
+            tracing::warn!("Locked user {} after {} failed logins", user_id, failed_logins);
+            let detail = format!("after {} failed logins", failed_logins);
+            audit::record(state, Some(user_id), AuditEvent::AccountLocked, Some(&detail), source).await;
+            let body = format!(
+                "There were {} failed attempts to log in to your RustGPT account, so \
+                 logging in with its password is blocked until {} UTC.\n\n\
+                 If it was you, wait until then or sign in with an email link at:\n{}/login/link\n\n\
+                 If it wasn't, consider choosing a new password at:\n{}/password/forgot",
+                failed_logins,
+                until.format("%Y-%m-%d %H:%M"),
+                app_url(),
+                app_url()
+            );
+            send_email(state, email.to_string(), "Your RustGPT account was locked", body);
+        }
+        Ok(_) => {}
+        Err(e) => tracing::error!("Failed to record failed login: {}", e),
+    }
+
+    let failures = match ip {
+        Some(ip) => state
+            .chat_repo
+            .recent_login_failures(ip, LOCKOUT_MINUTES)
+            .await
+            .unwrap_or(1),
+        None => 1,
+    };
+    // 250ms after the first failure, doubling up to 4s
+    let delay = 250 << (failures.clamp(1, 5) - 1);
+    tokio::time::sleep(std::time::Duration::from_millis(delay)).await;
+
+    LogInError::InvalidCredentials
 }
 
 #[axum::debug_handler]
 pub async fn login_form(
     cookies: Cookies,
-    state: State<Arc<AppState>>,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(source): Extension<AuditSource>,
     Form(log_in): Form<LogIn>,
 ) -> Result<Redirect, LogInError> {
+    let ip = source.ip.map(|ip| ip.to_string());
+    if let Some(ip) = &ip {
+        let failures = state
+            .chat_repo
+            .recent_login_failures(ip, LOCKOUT_MINUTES)
+            .await
+            .map_err(|e| LogInError::DatabaseError(format!("Failed to check login attempts: {}", e)))?;
+        if failures >= MAX_FAILED_LOGINS_PER_IP {
+            return Err(LogInError::TooManyAttempts);
+        }
+    }
+    let locked_until = state
+        .chat_repo
+        .login_locked_until(&log_in.email)
+        .await
+        .map_err(|e| LogInError::DatabaseError(format!("Failed to check login attempts: {}", e)))?;
+    if locked_until.is_some() {
+        return Err(LogInError::Locked);
+    }
+
     // Verify password
     let user = sqlx::query_as!(
         User,
@@ -67,6 +185,13 @@ pub async fn login_form(
             users.email,
             users.password,
             users.created_at,
+            users.verified_at,
+            users.is_admin AS "is_admin: bool",
+            users.display_name,
+            users.avatar,
+            NULL AS "impersonator?: String",
+            NULL AS "current_org_id?: i64",
+            users.guest_expires_at,
             settings.openai_api_key,
             settings.base_url,
             settings.model,
@@ -76,31 +201,79 @@ pub async fn login_form(
             settings.max_tokens
         FROM users
         LEFT JOIN settings ON settings.user_id=users.id
-        WHERE users.email = $1
+        WHERE users.email = $1 AND users.disabled_at IS NULL
         "#,
         log_in.email,
     )
-    .fetch_one(&*state.pool)
+    .fetch_optional(&*state.pool)
     .await
-    .map_err(|_| LogInError::InvalidCredentials)?;
+    .map_err(|e| LogInError::DatabaseError(format!("Failed to find user: {}", e)))?;
 
-    if user.password != log_in.password {
-        return Err(LogInError::InvalidCredentials);
+    let user = match user {
+        Some(user) if password::verify(&log_in.password, &user.password) => user,
+        _ => return Err(login_failed(&state, &log_in.email, source).await),
+    };
+    if let Err(e) = state.chat_repo.reset_failed_logins(user.id).await {
+        tracing::error!("Failed to reset failed logins of user {}: {}", user.id, e);
     }
 
-    let cookie = Cookie::build(("rust-gpt-session", user.id.to_string()))
-        .path("/")
-        .http_only(true)
-        .build();
-    cookies.add(cookie);
+    // Replace a plain-text password from before hashing; the login goes
+    // through even if this fails, and it's retried next time
+    if password::is_legacy(&user.password) {
+        match password::hash(&log_in.password) {
+            Ok(hashed) => {
+                if let Err(e) = sqlx::query!("UPDATE users SET password = ? WHERE id = ?", hashed, user.id)
+                    .execute(&*state.pool)
+                    .await
+                {
+                    tracing::error!("Failed to re-hash password of user {}: {}", user.id, e);
+                }
+            }
+            Err(e) => tracing::error!("Failed to hash password of user {}: {}", user.id, e),
+        }
+    }
+
+    let remember_id = match log_in.remember {
+        Some(_) => Some(
+            session::remember(&state, &cookies, user.id)
+                .await
+                .map_err(|e| LogInError::DatabaseError(format!("Failed to remember login: {}", e)))?,
+        ),
+        None => None,
+    };
+    start_session(&state, &cookies, user.id, &headers, Some(addr), None, remember_id)
+        .await
+        .map_err(|e| LogInError::DatabaseError(format!("Failed to create session: {}", e)))?;
+    let detail = match remember_id {
+        Some(_) => "password, remember me",
+        None => "password",
+    };
+    audit::record(&state, Some(user.id), AuditEvent::Login, Some(detail), source).await;
 
     Ok(Redirect::to("/"))
 }
 
-pub async fn signup(State(state): State<Arc<AppState>>) -> Html<String> {
-    // TODO: Hash password
+// `INVITE_ONLY=true` closes open signup: new accounts need an invite code
+// from an admin
+pub(super) fn invite_only() -> bool {
+    dotenv::var("INVITE_ONLY").is_ok_and(|value| value == "true")
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SignUpQuery {
+    invite: Option<String>,
+}
+
+pub async fn signup(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Query(query): Query<SignUpQuery>,
+) -> Html<String> {
     let mut context = Context::new();
     context.insert("name", "World");
+    context.insert("guest", &current_user.is_some_and(|user| user.guest_expires_at.is_some()));
+    context.insert("invite_only", &invite_only());
+    context.insert("invite", &query.invite);
     let home = state.tera.render("views/signup.html", &context).unwrap();
 
     let mut context = Context::new();
@@ -113,6 +286,7 @@ pub async fn signup(State(state): State<Arc<AppState>>) -> Html<String> {
 #[derive(Debug)]
 pub enum SignUpError {
     PasswordMismatch,
+    InvalidInvite,
     DatabaseError(String),
 }
 
@@ -122,6 +296,11 @@ impl IntoResponse for SignUpError {
             SignUpError::PasswordMismatch => {
                 (StatusCode::BAD_REQUEST, Json("Passwords do not match.")).into_response()
             }
+            SignUpError::InvalidInvite => (
+                StatusCode::FORBIDDEN,
+                Json("This invite code is unknown, used or revoked."),
+            )
+                .into_response(),
             SignUpError::DatabaseError(message) => {
                 (StatusCode::INTERNAL_SERVER_ERROR, Json(message)).into_response()
             }
@@ -134,27 +313,60 @@ pub struct SignUp {
     email: String,
     password: String,
     password_confirmation: String,
+    invite: Option<String>,
 }
 
 #[axum::debug_handler]
 pub async fn form_signup(
     state: State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
     Form(sign_up): Form<SignUp>,
 ) -> Result<Redirect, SignUpError> {
     if sign_up.password != sign_up.password_confirmation {
         return Err(SignUpError::PasswordMismatch);
     }
 
+    let hashed = password::hash(&sign_up.password)
+        .map_err(|e| SignUpError::DatabaseError(format!("Failed to hash password: {}", e)))?;
+
+    // A guest signing up keeps their account and chats, and stays logged in
+    // while confirming the email
+    if let Some(guest) = current_user.filter(|user| user.guest_expires_at.is_some()) {
+        let invite = invite_only().then(|| sign_up.invite.as_deref().unwrap_or("").trim());
+        let claimed = state
+            .chat_repo
+            .claim_guest(guest.id, &sign_up.email, &hashed, invite)
+            .await
+            .map_err(|e| SignUpError::DatabaseError(format!("Failed to claim guest account: {}", e)))?;
+        if !claimed {
+            return Err(SignUpError::InvalidInvite);
+        }
+        welcome(&state, guest.id, &sign_up.email).await;
+        return Ok(Redirect::to("/verify"));
+    }
+
     // insert into db
-    match sqlx::query!(
-        "INSERT INTO users (email, password) VALUES ($1, $2) RETURNING id",
-        sign_up.email,
-        sign_up.password
-    )
-    .fetch_one(&*state.pool)
-    .await
-    {
-        Ok(_) => Ok(Redirect::to("/login")),
+    let created = if invite_only() {
+        let code = sign_up.invite.as_deref().unwrap_or("").trim();
+        match state.chat_repo.create_invited_user(code, &sign_up.email, &hashed).await {
+            Ok(None) => return Err(SignUpError::InvalidInvite),
+            Ok(Some(user_id)) => Ok(user_id),
+            Err(e) => Err(e),
+        }
+    } else {
+        sqlx::query_scalar!(
+            r#"INSERT INTO users (email, password) VALUES ($1, $2) RETURNING id AS "id!""#,
+            sign_up.email,
+            hashed
+        )
+        .fetch_one(&*state.pool)
+        .await
+    };
+    match created {
+        Ok(user_id) => {
+            welcome(&state, user_id, &sign_up.email).await;
+            Ok(Redirect::to("/login"))
+        }
         Err(_e) => {
             // Handle database error, for example, a unique constraint violation
             Err(SignUpError::DatabaseError("Dat".to_string()))
@@ -162,15 +374,525 @@ pub async fn form_signup(
     }
 }
 
+// Chats shared with this email before the account existed, and the link to
+// confirm it
+async fn welcome(state: &Arc<AppState>, user_id: i64, email: &str) {
+    if let Err(e) = state.chat_repo.accept_chat_invitations(user_id, email).await {
+        tracing::error!("Failed to accept chat invitations: {}", e);
+    }
+    if let Err(e) = send_verification_email(state, user_id, email).await {
+        tracing::error!("Failed to create verification link for user {}: {}", user_id, e);
+    }
+}
+
+#[axum::debug_handler]
+pub async fn logout(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Extension(source): Extension<AuditSource>,
+    cookies: Cookies,
+) -> Result<Redirect, StatusCode> {
+    if let Some(session) = cookies.get(SESSION_COOKIE) {
+        state
+            .chat_repo
+            .delete_session_by_token(&password::hash_token(session.value()))
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    }
+    end_session(&cookies);
+    if let Some(user) = current_user {
+        audit::record(&state, Some(user.id), AuditEvent::Logout, None, source).await;
+    }
+
+    Ok(Redirect::to("/"))
+}
+
+// Reset links are valid for an hour
+const RESET_TOKEN_TTL_MINUTES: i64 = 60;
+
+// Base of the links in emails. Taken from the environment rather than the
+// request, whose Host header anyone can set
+pub(super) fn app_url() -> String {
+    dotenv::var("APP_URL").unwrap_or_else(|_| "http://localhost:3000".to_string())
+}
+
+// Sent in the background: delivery can be slow, and a failure is only logged
+fn send_email(state: &Arc<AppState>, to: String, subject: &'static str, body: String) {
+    let state = state.clone();
+    tokio::spawn(async move {
+        if let Err(e) = state.mailer.send(&to, subject, &body).await {
+            tracing::error!("Failed to send \"{}\" email: {}", subject, e);
+        }
+    });
+}
+
+fn render_auth_view(state: &AppState, template: &str, context: &Context) -> Html<String> {
+    let view = state.tera.render(template, context).unwrap();
+
+    let mut context = Context::new();
+    context.insert("view", &view);
+    let rendered = state.tera.render("views/main.html", &context).unwrap();
+
+    Html(rendered)
+}
+
+pub async fn forgot_password(State(state): State<Arc<AppState>>) -> Html<String> {
+    let mut context = Context::new();
+    context.insert("sent", &false);
+    render_auth_view(&state, "views/forgot_password.html", &context)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ForgotPassword {
+    email: String,
+}
+
+// Errors of the password reset and email verification links
+#[derive(Debug)]
+pub enum EmailLinkError {
+    PasswordMismatch,
+    InvalidToken,
+    ServerError(String),
+}
+
+impl IntoResponse for EmailLinkError {
+    fn into_response(self) -> Response {
+        match self {
+            EmailLinkError::PasswordMismatch => {
+                (StatusCode::BAD_REQUEST, Json("Passwords do not match.")).into_response()
+            }
+            EmailLinkError::InvalidToken => (
+                StatusCode::BAD_REQUEST,
+                Json("This reset link is invalid or has expired."),
+            )
+                .into_response(),
+            EmailLinkError::ServerError(message) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, Json(message)).into_response()
+            }
+        }
+    }
+}
+
+// Emails a reset link if `email` has an account; returns whether it did
+pub(super) async fn send_password_reset(state: &Arc<AppState>, email: &str) -> sqlx::Result<bool> {
+    let token = uuid::Uuid::new_v4().simple().to_string();
+
+    let created = state
+        .chat_repo
+        .create_password_reset(email, &password::hash_token(&token), RESET_TOKEN_TTL_MINUTES)
+        .await?;
+
+    if created {
+        let body = format!(
+            "Someone asked to reset the password of your RustGPT account.\n\n\
+             Choose a new password within the next hour at:\n{}/password/reset/{}\n\n\
+             If it wasn't you, you can ignore this email.",
+            app_url(),
+            token
+        );
+        send_email(state, email.to_string(), "Reset your RustGPT password", body);
+    }
+    Ok(created)
+}
+
+// Answers the same whether or not the email has an account, and the email
+// goes out in the background so the response time doesn't tell either
 #[axum::debug_handler]
-pub async fn logout(cookies: Cookies) -> Result<Redirect, StatusCode> {
-    let mut cookie = Cookie::build(("rust-gpt-session", ""))
-        .path("/")
+pub async fn forgot_password_form(
+    State(state): State<Arc<AppState>>,
+    Form(forgot): Form<ForgotPassword>,
+) -> Result<Html<String>, EmailLinkError> {
+    send_password_reset(&state, forgot.email.trim())
+        .await
+        .map_err(|e| EmailLinkError::ServerError(format!("Failed to create reset link: {}", e)))?;
+
+    let mut context = Context::new();
+    context.insert("sent", &true);
+    Ok(render_auth_view(&state, "views/forgot_password.html", &context))
+}
+
+pub async fn reset_password(
+    Path(token): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Html<String>, EmailLinkError> {
+    let user_id = state
+        .chat_repo
+        .find_password_reset(&password::hash_token(&token))
+        .await
+        .map_err(|e| EmailLinkError::ServerError(format!("Failed to check reset link: {}", e)))?;
+
+    let mut context = Context::new();
+    context.insert("token", &token);
+    context.insert("valid", &user_id.is_some());
+    Ok(render_auth_view(&state, "views/reset_password.html", &context))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ResetPassword {
+    password: String,
+    password_confirmation: String,
+}
+
+#[axum::debug_handler]
+pub async fn reset_password_form(
+    Path(token): Path<String>,
+    State(state): State<Arc<AppState>>,
+    Extension(source): Extension<AuditSource>,
+    Form(reset): Form<ResetPassword>,
+) -> Result<Redirect, EmailLinkError> {
+    if reset.password != reset.password_confirmation {
+        return Err(EmailLinkError::PasswordMismatch);
+    }
+    let token_hash = password::hash_token(&token);
+    let user_id = state
+        .chat_repo
+        .find_password_reset(&token_hash)
+        .await
+        .map_err(|e| EmailLinkError::ServerError(format!("Failed to find reset link: {}", e)))?;
+
+    let hashed = password::hash(&reset.password)
+        .map_err(|e| EmailLinkError::ServerError(format!("Failed to hash password: {}", e)))?;
+
+    let reset = state
+        .chat_repo
+        .reset_password(&token_hash, &hashed)
+        .await
+        .map_err(|e| EmailLinkError::ServerError(format!("Failed to reset password: {}", e)))?;
+
+    if !reset {
+        return Err(EmailLinkError::InvalidToken);
+    }
+    audit::record(&state, user_id, AuditEvent::PasswordChanged, Some("reset link"), source).await;
+
+    Ok(Redirect::to("/login"))
+}
+
+// Verification links are valid for a day
+const VERIFICATION_TOKEN_TTL_MINUTES: i64 = 24 * 60;
+
+async fn send_verification_email(state: &Arc<AppState>, user_id: i64, email: &str) -> sqlx::Result<()> {
+    let token = uuid::Uuid::new_v4().simple().to_string();
+    state
+        .chat_repo
+        .create_email_verification(user_id, &password::hash_token(&token), VERIFICATION_TOKEN_TTL_MINUTES)
+        .await?;
+
+    let body = format!(
+        "Welcome to RustGPT!\n\n\
+         Confirm your email address within the next day to start chatting:\n{}/verify/{}\n\n\
+         If you didn't sign up, you can ignore this email.",
+        app_url(),
+        token
+    );
+    send_email(state, email.to_string(), "Confirm your RustGPT email address", body);
+    Ok(())
+}
+
+// Where `middleware::verified` sends accounts that haven't confirmed their email
+pub async fn verify_email_notice(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Response, EmailLinkError> {
+    let Some(user) = current_user else {
+        return Ok(Redirect::to("/login").into_response());
+    };
+    if user.verified_at.is_some() {
+        return Ok(Redirect::to("/chat").into_response());
+    }
+
+    let mut context = Context::new();
+    context.insert("status", "pending");
+    context.insert("email", &user.email);
+    Ok(render_auth_view(&state, "views/verify_email.html", &context).into_response())
+}
+
+#[axum::debug_handler]
+pub async fn resend_verification_email(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Response, EmailLinkError> {
+    let Some(user) = current_user else {
+        return Ok(Redirect::to("/login").into_response());
+    };
+    if user.verified_at.is_some() {
+        return Ok(Redirect::to("/chat").into_response());
+    }
+
+    send_verification_email(&state, user.id, &user.email)
+        .await
+        .map_err(|e| EmailLinkError::ServerError(format!("Failed to create verification link: {}", e)))?;
+
+    let mut context = Context::new();
+    context.insert("status", "sent");
+    context.insert("email", &user.email);
+    Ok(render_auth_view(&state, "views/verify_email.html", &context).into_response())
+}
+
+// Doesn't need a session, as the link is often opened on another device
+pub async fn verify_email(
+    Path(token): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Html<String>, EmailLinkError> {
+    let verified = state
+        .chat_repo
+        .verify_email(&password::hash_token(&token))
+        .await
+        .map_err(|e| EmailLinkError::ServerError(format!("Failed to verify email: {}", e)))?;
+
+    let mut context = Context::new();
+    context.insert("status", if verified { "verified" } else { "invalid" });
+    Ok(render_auth_view(&state, "views/verify_email.html", &context))
+}
+
+// Sign in links are valid for a quarter of an hour
+const LOGIN_LINK_TTL_MINUTES: i64 = 15;
+
+pub async fn login_link(State(state): State<Arc<AppState>>) -> Html<String> {
+    let mut context = Context::new();
+    context.insert("status", "form");
+    render_auth_view(&state, "views/login_link.html", &context)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LoginLinkRequest {
+    email: String,
+}
+
+// Like `forgot_password_form`, answers the same whether or not the email
+// has an account
+#[axum::debug_handler]
+pub async fn login_link_form(
+    State(state): State<Arc<AppState>>,
+    Form(request): Form<LoginLinkRequest>,
+) -> Result<Html<String>, EmailLinkError> {
+    let email = request.email.trim();
+    let token = uuid::Uuid::new_v4().simple().to_string();
+
+    let created = state
+        .chat_repo
+        .create_login_link(email, &password::hash_token(&token), LOGIN_LINK_TTL_MINUTES)
+        .await
+        .map_err(|e| EmailLinkError::ServerError(format!("Failed to create sign in link: {}", e)))?;
+
+    if created {
+        let body = format!(
+            "Sign in to RustGPT within the next 15 minutes at:\n{}/login/link/{}\n\n\
+             The link works once. If you didn't ask for it, you can ignore this email.",
+            app_url(),
+            token
+        );
+        send_email(&state, email.to_string(), "Your RustGPT sign in link", body);
+    }
+
+    let mut context = Context::new();
+    context.insert("status", "sent");
+    Ok(render_auth_view(&state, "views/login_link.html", &context))
+}
+
+// Opening the link only shows a button: mail scanners fetch links in emails,
+// and would use it up
+pub async fn login_link_confirm(
+    Path(token): Path<String>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Html<String>, EmailLinkError> {
+    let user_id = state
+        .chat_repo
+        .find_login_link(&password::hash_token(&token))
+        .await
+        .map_err(|e| EmailLinkError::ServerError(format!("Failed to check sign in link: {}", e)))?;
+
+    let mut context = Context::new();
+    context.insert("status", if user_id.is_some() { "confirm" } else { "invalid" });
+    context.insert("token", &token);
+    Ok(render_auth_view(&state, "views/login_link.html", &context))
+}
+
+#[axum::debug_handler]
+pub async fn login_link_sign_in(
+    Path(token): Path<String>,
+    State(state): State<Arc<AppState>>,
+    cookies: Cookies,
+    headers: HeaderMap,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(source): Extension<AuditSource>,
+) -> Result<Response, EmailLinkError> {
+    let user_id = state
+        .chat_repo
+        .use_login_link(&password::hash_token(&token))
+        .await
+        .map_err(|e| EmailLinkError::ServerError(format!("Failed to use sign in link: {}", e)))?;
+
+    let Some(user_id) = user_id else {
+        let mut context = Context::new();
+        context.insert("status", "invalid");
+        return Ok(render_auth_view(&state, "views/login_link.html", &context).into_response());
+    };
+
+    start_session(&state, &cookies, user_id, &headers, Some(addr), None, None)
+        .await
+        .map_err(|e| EmailLinkError::ServerError(format!("Failed to create session: {}", e)))?;
+    audit::record(&state, Some(user_id), AuditEvent::Login, Some("email link"), source).await;
+
+    Ok(Redirect::to("/").into_response())
+}
+
+const OAUTH_COOKIE: &str = "rust-gpt-oauth";
+
+#[derive(Debug)]
+pub enum OAuthLoginError {
+    UnknownProvider,
+    // Cancelled at the provider, or the callback doesn't match the attempt
+    Failed,
+    EmailTaken,
+    SignupClosed,
+    ServerError(String),
+}
+
+impl IntoResponse for OAuthLoginError {
+    fn into_response(self) -> Response {
+        match self {
+            OAuthLoginError::UnknownProvider => {
+                (StatusCode::NOT_FOUND, Json("Unknown sign in provider.")).into_response()
+            }
+            OAuthLoginError::Failed => (
+                StatusCode::BAD_REQUEST,
+                Json("The sign in was cancelled or has expired."),
+            )
+                .into_response(),
+            OAuthLoginError::EmailTaken => (
+                StatusCode::CONFLICT,
+                Json("An account already uses this email. Log in with your password instead."),
+            )
+                .into_response(),
+            OAuthLoginError::SignupClosed => (
+                StatusCode::FORBIDDEN,
+                Json("Signup is by invitation only. Sign up with your invite code first."),
+            )
+                .into_response(),
+            OAuthLoginError::ServerError(message) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, Json(message)).into_response()
+            }
+        }
+    }
+}
+
+fn oauth_redirect_uri(provider: Provider) -> String {
+    format!("{}/oauth/{}/callback", app_url(), provider.slug())
+}
+
+// Sends the browser to the provider's consent page
+pub async fn oauth_authorize(
+    cookies: Cookies,
+    Path(provider): Path<String>,
+) -> Result<Redirect, OAuthLoginError> {
+    let provider = Provider::from_slug(&provider)
+        .filter(|provider| provider.is_configured())
+        .ok_or(OAuthLoginError::UnknownProvider)?;
+    let attempt = Attempt::start();
+    let url = oauth::authorize_url(provider, &oauth_redirect_uri(provider), &attempt)
+        .await
+        .map_err(|e| {
+            tracing::warn!("{} sign in failed: {}", provider.name(), e);
+            OAuthLoginError::Failed
+        })?;
+
+    // Lax, so the cookie comes along on the provider's redirect back
+    let cookie = Cookie::build((OAUTH_COOKIE, attempt.to_cookie()))
+        .path("/oauth")
         .http_only(true)
+        .same_site(SameSite::Lax)
+        .max_age(time::Duration::minutes(10))
         .build();
-    cookie.make_removal();
+    cookies.add(cookie);
+
+    Ok(Redirect::to(url.as_str()))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct OAuthCallback {
+    code: Option<String>,
+    state: Option<String>,
+}
 
+pub async fn oauth_callback(
+    cookies: Cookies,
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(source): Extension<AuditSource>,
+    Path(provider): Path<String>,
+    Query(callback): Query<OAuthCallback>,
+) -> Result<Redirect, OAuthLoginError> {
+    let provider = Provider::from_slug(&provider).ok_or(OAuthLoginError::UnknownProvider)?;
+
+    // Each attempt is good for a single callback
+    let attempt = cookies
+        .get(OAUTH_COOKIE)
+        .and_then(|cookie| Attempt::from_cookie(cookie.value()));
+    let mut cookie = Cookie::build((OAUTH_COOKIE, "")).path("/oauth").build();
+    cookie.make_removal();
     cookies.add(cookie);
 
+    let (Some(attempt), Some(code)) = (attempt, callback.code) else {
+        return Err(OAuthLoginError::Failed);
+    };
+    if callback.state.as_deref() != Some(attempt.state.as_str()) {
+        return Err(OAuthLoginError::Failed);
+    }
+
+    let identity = oauth::fetch_identity(provider, &oauth_redirect_uri(provider), &code, &attempt.verifier)
+        .await
+        .map_err(|e| {
+            tracing::warn!("{} sign in failed: {}", provider.name(), e);
+            OAuthLoginError::Failed
+        })?;
+
+    // New accounts get a password nobody knows; a reset link sets a real one
+    let unusable_password = password::hash(&uuid::Uuid::new_v4().to_string())
+        .map_err(|e| OAuthLoginError::ServerError(format!("Failed to hash password: {}", e)))?;
+    let sign_in = state
+        .chat_repo
+        .oauth_sign_in(
+            provider.slug(),
+            &identity.subject,
+            &identity.email,
+            identity.email_verified,
+            &unusable_password,
+            !invite_only() || provider.provisions_accounts(),
+        )
+        .await
+        .map_err(|e| OAuthLoginError::ServerError(format!("Failed to sign in: {}", e)))?;
+
+    let user_id = match sign_in {
+        OAuthSignIn::Existing(user_id) => user_id,
+        OAuthSignIn::Created(user_id) => {
+            if let Err(e) = state
+                .chat_repo
+                .accept_chat_invitations(user_id, &identity.email)
+                .await
+            {
+                tracing::error!("Failed to accept chat invitations: {}", e);
+            }
+            if let Some(name) = identity.name.as_deref().map(str::trim).filter(|name| !name.is_empty()) {
+                if let Err(e) = state.chat_repo.update_display_name(user_id, Some(name)).await {
+                    tracing::error!("Failed to set display name of user {}: {}", user_id, e);
+                }
+            }
+            if !identity.email_verified {
+                if let Err(e) = send_verification_email(&state, user_id, &identity.email).await {
+                    tracing::error!("Failed to create verification link for user {}: {}", user_id, e);
+                }
+            }
+            user_id
+        }
+        OAuthSignIn::EmailTaken => return Err(OAuthLoginError::EmailTaken),
+        OAuthSignIn::SignupClosed => return Err(OAuthLoginError::SignupClosed),
+    };
+
+    start_session(&state, &cookies, user_id, &headers, Some(addr), None, None)
+        .await
+        .map_err(|e| OAuthLoginError::ServerError(format!("Failed to create session: {}", e)))?;
+    audit::record(&state, Some(user_id), AuditEvent::Login, Some(provider.name()), source).await;
+
     Ok(Redirect::to("/"))
 }
@@ -1,7 +1,10 @@
 use axum::{
-    extract::{Extension, Path, State},
-    http::StatusCode,
-    response::{sse::Event, Html, IntoResponse, Response, Sse},
+    extract::{
+        ws::{Message as WsMessage, WebSocket, WebSocketUpgrade},
+        Extension, Path, Query, State,
+    },
+    http::{header, HeaderMap, StatusCode},
+    response::{sse::{Event, KeepAlive}, Html, IntoResponse, Response, Sse},
     Form, Json,
 };
 use tokio::sync::mpsc;
@@ -14,16 +17,51 @@ use tokio_stream::wrappers::ReceiverStream; // This brings the necessary stream
 use std::sync::Arc;
 
 use crate::{
-    ai::stream::{generate_sse_stream, list_engines, GenerationEvent},
-    data::model::ChatMessagePair,
-    utils::markdown_to_html,
-    AppState, User,
+    ai::{
+        acp, custom_tools, embeddings,
+        fanout::RoomEvent,
+        stream::{
+            complete, generate_sse_stream, list_engines, ConfirmationTarget, parse_stop_sequences,
+            GenerationEvent, GenerationOptions,
+        },
+    },
+    audit::{self, AuditEvent, AuditSource},
+    quota,
+    data::model::{Attachment, Bookmark, BulkChatAction, Chat, ChatMessagePair, ChatResource, ChatRole, ChatSettings, ChatStats, NewAttachment, NewChatResource, NewToolExecution, ScheduledMessage, SearchHit, SimilarChunk},
+    data::repository::NO_LIMIT,
+    mcp::{
+        cancel,
+        sampling::{self, SamplingRequest},
+        tools::get_available_tools,
+        users::{self, UserMcp},
+    },
+    utils::{
+        attachments::{attachment_links, collect_attachments},
+        commands::{parse_command, Command, SlashCommand, COMMANDS},
+        export::{self, ExportFormat},
+        import::parse_import,
+        markdown_to_html,
+    },
+    middleware::find_user,
+    webhooks, AppState, User,
 };
 
+use super::guest::{guest_api_key, guest_model};
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_same_origin() {
+        let app_url = "https://chat.example.com/";
+        assert!(same_origin(Some("https://chat.example.com"), app_url));
+        assert!(!same_origin(Some("https://evil.example.com"), app_url));
+        assert!(!same_origin(Some("http://chat.example.com"), app_url));
+        assert!(!same_origin(Some("null"), app_url));
+        assert!(!same_origin(None, app_url));
+    }
+
     #[test]
     fn test_enhanced_markdown_features() {
         let test_markdown = r#"| Name | Age |
@@ -122,12 +160,53 @@ code block
 
         println!("✅ Enhanced markdown features with DaisyUI styling are working!");
     }
+
+    #[test]
+    fn test_highlight_snippet_escapes_message_html() {
+        let html = highlight_snippet("<b>x</b> \u{2}rust\u{3} code");
+        assert_eq!(html, "&lt;b&gt;x&lt;/b&gt; <mark>rust</mark> code");
+    }
+
+    #[test]
+    fn test_ws_frames_match_sse_rendering() {
+        let mut acc = MessageAccumulator::default();
+        let event = GenerationEvent::Text("**hi**".to_string());
+        let html = acc.apply(event.clone());
+        assert!(html.contains("<strong>hi</strong>"));
+
+        let frame = serde_json::to_value(WsServerFrame::Event { event, html }).unwrap();
+        assert_eq!(frame["frame"], "event");
+        assert_eq!(frame["event"]["type"], "text");
+        assert_eq!(frame["event"]["data"], "**hi**");
+
+        let cancel: WsClientMessage = serde_json::from_str(r#"{"type":"cancel"}"#).unwrap();
+        assert!(matches!(cancel, WsClientMessage::Cancel));
+    }
+
+    #[test]
+    fn test_resource_context() {
+        assert_eq!(resource_context(&[]), None);
+
+        let resource = ChatResource {
+            id: 1,
+            chat_id: 1,
+            server: "db".to_string(),
+            uri: "schema://users".to_string(),
+            name: "users".to_string(),
+            mime_type: None,
+            content: "id INTEGER".to_string(),
+            created_at: chrono::Utc::now(),
+        };
+        let context = resource_context(&[resource]).unwrap();
+        assert!(context.contains("## users (schema://users)"));
+        assert!(context.contains("```\nid INTEGER\n```"));
+    }
 }
 
 use tokio_stream::StreamExt as TokioStreamExt;
 
 // Accumulator structure for all message types
-#[derive(Clone)]
+#[derive(Clone, Default)]
 struct MessageAccumulator {
     text: String,
     thinking: String,
@@ -136,6 +215,27 @@ struct MessageAccumulator {
     images: Vec<String>,
     usage: Option<crate::data::model::UsageInfo>,
     sources: Vec<crate::data::model::Source>,
+    finish_reason: Option<String>,
+    // Set when generating, for the chat stats
+    model: Option<String>,
+    started_at: Option<std::time::Instant>,
+    // Set when generating, for cancelling the tool call running until the
+    // next event, by its execution id
+    chat_id: Option<i64>,
+    running_tool: Option<String>,
+}
+
+// Cancels the tool call while it runs, which is always the last one shown
+fn cancel_tool_button(acc: &MessageAccumulator, tool_call: &crate::data::model::ToolCall) -> String {
+    let is_last = acc.tool_calls.last().is_some_and(|last| std::ptr::eq(last, tool_call));
+    match (acc.chat_id, &acc.running_tool) {
+        (Some(chat_id), Some(execution_id)) if is_last => format!(
+            r#"<button class="btn btn-ghost btn-xs ml-auto" hx-post="/chat/{}/tool-cancel/{}" hx-swap="none">Cancel</button>"#,
+            chat_id,
+            html_escape::encode_double_quoted_attribute(execution_id)
+        ),
+        _ => String::new(),
+    }
 }
 
 fn render_message_text_only(acc: &MessageAccumulator) -> String {
@@ -156,6 +256,7 @@ fn render_message_text_only(acc: &MessageAccumulator) -> String {
         html.push_str(r#"<svg xmlns="http://www.w3.org/2000/svg" class="h-5 w-5 text-accent" fill="none" viewBox="0 0 24 24" stroke="currentColor"><path stroke-linecap="round" stroke-linejoin="round" stroke-width="2" d="M10.325 4.317c.426-1.756 2.924-1.756 3.35 0a1.724 1.724 0 002.573 1.066c1.543-.94 3.31.826 2.37 2.37a1.724 1.724 0 001.065 2.572c1.756.426 1.756 2.924 0 3.35a1.724 1.724 0 00-1.066 2.573c.94 1.543-.826 3.31-2.37 2.37a1.724 1.724 0 00-2.572 1.065c-.426 1.756-2.924 1.756-3.35 0a1.724 1.724 0 00-2.573-1.066c-1.543.94-3.31-.826-2.37-2.37a1.724 1.724 0 00-1.065-2.572c-1.756-.426-1.756-2.924 0-3.35a1.724 1.724 0 001.066-2.573c-.94-1.543.826-3.31 2.37-2.37.996.608 2.296.07 2.572-1.065z" /><path stroke-linecap="round" stroke-linejoin="round" stroke-width="2" d="M15 12a3 3 0 11-6 0 3 3 0 016 0z" /></svg>"#);
         html.push_str(r#"<span class="font-semibold text-accent">Tool Call: </span>"#);
         html.push_str(&html_escape::encode_text(&tool_call.function.name));
+        html.push_str(&cancel_tool_button(acc, tool_call));
         html.push_str("</div>");
         html.push_str(r#"<div class="mockup-code text-xs"><pre><code>"#);
         // Pretty print JSON arguments if possible
@@ -290,6 +391,7 @@ fn render_message_html(acc: &MessageAccumulator) -> String {
         html.push_str(r#"<svg xmlns="http://www.w3.org/2000/svg" class="h-5 w-5 text-accent" fill="none" viewBox="0 0 24 24" stroke="currentColor"><path stroke-linecap="round" stroke-linejoin="round" stroke-width="2" d="M10.325 4.317c.426-1.756 2.924-1.756 3.35 0a1.724 1.724 0 002.573 1.066c1.543-.94 3.31.826 2.37 2.37a1.724 1.724 0 001.065 2.572c1.756.426 1.756 2.924 0 3.35a1.724 1.724 0 00-1.066 2.573c.94 1.543-.826 3.31-2.37 2.37a1.724 1.724 0 00-2.572 1.065c-.426 1.756-2.924 1.756-3.35 0a1.724 1.724 0 00-2.573-1.066c-1.543.94-3.31-.826-2.37-2.37a1.724 1.724 0 00-1.065-2.572c-1.756-.426-1.756-2.924 0-3.35a1.724 1.724 0 001.066-2.573c-.94-1.543.826-3.31 2.37-2.37.996.608 2.296.07 2.572-1.065z" /><path stroke-linecap="round" stroke-linejoin="round" stroke-width="2" d="M15 12a3 3 0 11-6 0 3 3 0 016 0z" /></svg>"#);
         html.push_str(r#"<span class="font-semibold text-accent">Tool Call: </span>"#);
         html.push_str(&html_escape::encode_text(&tool_call.function.name));
+        html.push_str(&cancel_tool_button(acc, tool_call));
         html.push_str("</div>");
         html.push_str(r#"<div class="mockup-code text-xs"><pre><code>"#);
         html.push_str(&html_escape::encode_text(&tool_call.function.arguments));
@@ -409,9 +511,17 @@ pub enum ChatError {
     DatabaseError(String),
     InvalidAPIKey,
     EmptyAPIKey,
+    // A saved key none of the master keys opens, see `encryption`
+    UnreadableAPIKey,
     ChatNotFound,
     MissingUser,
+    Forbidden,
     InvalidMessage,
+    InvalidImport(String),
+    // A tool call someone already approved or rejected
+    AlreadyAnswered,
+    // Carries why, see `quota::QuotaStatus::exceeded`
+    QuotaExceeded(String),
     NetworkError(String),
     ServerError(String),
     InternalError(String),
@@ -423,9 +533,14 @@ impl std::fmt::Display for ChatError {
             ChatError::DatabaseError(msg) => write!(f, "Database error: {}", msg),
             ChatError::InvalidAPIKey => write!(f, "Invalid API key"),
             ChatError::EmptyAPIKey => write!(f, "API key is required"),
+            ChatError::UnreadableAPIKey => write!(f, "API key can't be decrypted"),
             ChatError::ChatNotFound => write!(f, "Chat not found"),
             ChatError::MissingUser => write!(f, "User not authenticated"),
+            ChatError::Forbidden => write!(f, "Not allowed for this chat role"),
             ChatError::InvalidMessage => write!(f, "Invalid message format"),
+            ChatError::InvalidImport(msg) => write!(f, "Invalid import: {}", msg),
+            ChatError::AlreadyAnswered => write!(f, "Tool call already answered"),
+            ChatError::QuotaExceeded(msg) => write!(f, "Quota exceeded: {}", msg),
             ChatError::NetworkError(msg) => write!(f, "Network error: {}", msg),
             ChatError::ServerError(msg) => write!(f, "Server error: {}", msg),
             ChatError::InternalError(msg) => write!(f, "Internal error: {}", msg),
@@ -435,7 +550,7 @@ impl std::fmt::Display for ChatError {
 
 impl IntoResponse for ChatError {
     fn into_response(self) -> Response {
-        let (status, error_message) = match self {
+        let (status, error_message) = match &self {
             ChatError::DatabaseError(msg) => {
                 tracing::error!("Database error: {}", msg);
                 (StatusCode::INTERNAL_SERVER_ERROR, "Internal database error")
@@ -448,9 +563,26 @@ impl IntoResponse for ChatError {
                 StatusCode::BAD_REQUEST,
                 "API key is required. Please configure it in settings.",
             ),
+            ChatError::UnreadableAPIKey => (
+                StatusCode::BAD_REQUEST,
+                "The saved API key can no longer be read. Please enter it again in settings.",
+            ),
             ChatError::ChatNotFound => (StatusCode::NOT_FOUND, "Chat not found"),
             ChatError::MissingUser => (StatusCode::UNAUTHORIZED, "User not authenticated"),
+            ChatError::Forbidden => (
+                StatusCode::FORBIDDEN,
+                "You don't have permission to do this in this chat",
+            ),
             ChatError::InvalidMessage => (StatusCode::BAD_REQUEST, "Message cannot be empty"),
+            ChatError::InvalidImport(msg) => {
+                tracing::warn!("Rejected chat import: {}", msg);
+                (StatusCode::BAD_REQUEST, "Could not read the uploaded export file")
+            }
+            ChatError::AlreadyAnswered => (
+                StatusCode::CONFLICT,
+                "This tool call was already approved or rejected",
+            ),
+            ChatError::QuotaExceeded(msg) => (StatusCode::TOO_MANY_REQUESTS, msg.as_str()),
             ChatError::NetworkError(msg) => {
                 tracing::error!("Network error: {}", msg);
                 (StatusCode::BAD_GATEWAY, "Failed to connect to AI service")
@@ -473,19 +605,30 @@ impl IntoResponse for ChatError {
     }
 }
 
+#[derive(Deserialize, Debug)]
+pub struct ChatListParams {
+    tag: Option<String>,
+    // Shows the sidebar's chats after this one
+    cursor: Option<i64>,
+}
+
 #[axum::debug_handler]
 pub async fn chat(
     State(state): State<Arc<AppState>>,
     Extension(current_user): Extension<Option<User>>,
-) -> Html<String> {
-    let user_chats = state
+    Query(params): Query<ChatListParams>,
+) -> Result<Html<String>, ChatError> {
+    let user = current_user.as_ref().ok_or(ChatError::MissingUser)?;
+
+    let agents = state
         .chat_repo
-        .get_all_chats(current_user.as_ref().unwrap().id)
+        .get_agents(user.id, user.current_org_id, None, NO_LIMIT)
         .await
-        .unwrap();
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to retrieve agents: {}", e)))?;
 
     let mut context = Context::new();
-    context.insert("user_chats", &user_chats);
+    context.insert("agents", &agents);
+    insert_sidebar(&state, &mut context, user, params.tag.as_deref(), params.cursor).await?;
     let home = state.tera.render("views/chat.html", &context).unwrap();
 
     let mut context = Context::new();
@@ -493,12 +636,94 @@ pub async fn chat(
     context.insert("current_user", &current_user);
     let rendered = state.tera.render("views/main.html", &context).unwrap();
 
-    Html(rendered)
+    Ok(Html(rendered))
+}
+
+const SIDEBAR_PAGE_SIZE: i64 = 100;
+
+// Up to `limit` chats of the current workspace after the chat `cursor`, plus
+// the cursor of the next page when there is one
+pub(super) async fn load_chat_page(
+    state: &AppState,
+    user: &User,
+    tag: Option<&str>,
+    cursor: Option<i64>,
+    limit: i64,
+) -> Result<(Vec<Chat>, Option<i64>), ChatError> {
+    let mut chats = match user.current_org_id {
+        Some(org_id) => {
+            state
+                .chat_repo
+                .get_org_chats(org_id, user.id, tag, cursor, limit + 1)
+                .await
+        }
+        None => state.chat_repo.get_all_chats(user.id, tag, cursor, limit + 1).await,
+    }
+    .map_err(|e| ChatError::DatabaseError(format!("Failed to retrieve user chats: {}", e)))?;
+
+    let next_cursor = if chats.len() as i64 > limit {
+        chats.pop();
+        chats.last().map(|chat| chat.id)
+    } else {
+        None
+    };
+    Ok((chats, next_cursor))
+}
+
+pub(super) async fn count_workspace_chats(state: &AppState, user: &User, tag: Option<&str>) -> Result<i64, ChatError> {
+    match user.current_org_id {
+        Some(org_id) => state.chat_repo.count_org_chats(org_id, tag).await,
+        None => state.chat_repo.count_chats(user.id, tag).await,
+    }
+    .map_err(|e| ChatError::DatabaseError(format!("Failed to count user chats: {}", e)))
+}
+
+// A page of chats of the current workspace grouped by folder plus the tag
+// filter shown in the sidebar
+async fn insert_sidebar(
+    state: &AppState,
+    context: &mut Context,
+    user: &User,
+    tag: Option<&str>,
+    cursor: Option<i64>,
+) -> Result<(), ChatError> {
+    let user_id = user.id;
+    let (user_chats, older_chats_cursor) =
+        load_chat_page(state, user, tag, cursor, SIDEBAR_PAGE_SIZE).await?;
+    let chats_total = count_workspace_chats(state, user, tag).await?;
+    let folders = state
+        .chat_repo
+        .get_folders(user_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to retrieve folders: {}", e)))?;
+    let tags = state
+        .chat_repo
+        .get_tags(user_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to retrieve tags: {}", e)))?;
+    let shared_chats = state
+        .chat_repo
+        .get_shared_chats(user_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to retrieve shared chats: {}", e)))?;
+
+    context.insert("user_chats", &user_chats);
+    context.insert("chats_total", &chats_total);
+    context.insert("chats_cursor", &cursor);
+    context.insert("older_chats_cursor", &older_chats_cursor);
+    context.insert("shared_chats", &shared_chats);
+    context.insert("folders", &folders);
+    context.insert("tags", &tags);
+    context.insert("active_tag", &tag);
+
+    Ok(())
 }
 
 #[derive(Deserialize, Debug)]
 pub struct NewChat {
     message: String,
+    #[serde(default)]
+    agent_id: String, // empty for no agent
 }
 
 #[axum::debug_handler]
@@ -514,7 +739,167 @@ pub async fn new_chat(
 
     let current_user = current_user.ok_or_else(|| ChatError::MissingUser)?;
 
-    // Use model from user settings, fallback to default if not set
+    let agent_id = if new_chat.agent_id.is_empty() {
+        None
+    } else {
+        Some(new_chat.agent_id.parse().map_err(|_| ChatError::InvalidMessage)?)
+    };
+    let chat_id = create_chat_with_message(&state, &current_user, &new_chat.message, agent_id).await?;
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("HX-Redirect", format!("/chat/{}", chat_id).as_str())
+        .body("".to_string())
+        .map_err(|e| ChatError::ServerError(format!("Failed to build response: {}", e)))
+}
+
+// New chats belong to the org workspace the user is in
+async fn place_in_workspace(state: &AppState, user: &User, chat_id: i64) -> Result<(), ChatError> {
+    if let Some(org_id) = user.current_org_id {
+        state
+            .chat_repo
+            .set_chat_org(chat_id, Some(org_id))
+            .await
+            .map_err(|e| ChatError::DatabaseError(format!("Failed to add chat to org: {}", e)))?;
+    }
+    Ok(())
+}
+
+// Create a chat, optionally bound to one of the user's agents, that starts
+// with `message`. The response is generated once a client asks for it
+pub(super) async fn create_chat_with_message(
+    state: &AppState,
+    current_user: &User,
+    message: &str,
+    agent_id: Option<i64>,
+) -> Result<i64, ChatError> {
+    check_user_quota(state, current_user.id).await?;
+
+    let agent = match agent_id {
+        None => None,
+        Some(agent_id) => {
+            let agents = state
+                .chat_repo
+                .get_agents(current_user.id, current_user.current_org_id, None, NO_LIMIT)
+                .await
+                .map_err(|e| ChatError::DatabaseError(format!("Failed to retrieve agents: {}", e)))?;
+            Some(
+                agents
+                    .into_iter()
+                    .find(|agent| agent.id == agent_id)
+                    .ok_or(ChatError::InvalidMessage)?,
+            )
+        }
+    };
+
+    // Use the agent's model, then the one from user settings, then the default
+    let model = agent
+        .as_ref()
+        .map(|agent| agent.model.as_str())
+        .or(current_user.model.as_deref())
+        .unwrap_or("Qwen/Qwen2.5-7B-Instruct");
+
+    let chat_id = state
+        .chat_repo
+        .create_chat(current_user.id, message, model)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to create chat: {}", e)))?;
+    place_in_workspace(state, current_user, chat_id).await?;
+
+    if let Some(agent) = &agent {
+        state
+            .chat_repo
+            .set_chat_agent(chat_id, current_user.id, agent.id)
+            .await
+            .map_err(|e| ChatError::DatabaseError(format!("Failed to bind agent: {}", e)))?;
+    }
+
+    state
+        .chat_repo
+        .add_message_block(chat_id, message)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to add message: {}", e)))?;
+
+    let created = serde_json::json!({ "chat_id": chat_id, "name": message, "message": message });
+    webhooks::emit(&state.chat_repo, chat_id, webhooks::CHAT_CREATED, created).await;
+
+    Ok(chat_id)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PromptTemplateForm {
+    title: String,
+    #[serde(default)]
+    description: String,
+    prompt: String,
+}
+
+pub async fn create_prompt_template(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Form(form): Form<PromptTemplateForm>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    let title = form.title.trim();
+    let prompt = form.prompt.trim();
+    if title.is_empty() || prompt.is_empty() {
+        return Err(ChatError::InvalidMessage);
+    }
+
+    let template = state
+        .chat_repo
+        .create_prompt_template(current_user.id, title, form.description.trim(), prompt)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to save template: {}", e)))?;
+
+    let mut context = Context::new();
+    context.insert("template", &template);
+    context.insert("logged_in", &true);
+    let card = state
+        .tera
+        .render("htmx_updates/prompt_template_card.html", &context)
+        .map_err(|e| ChatError::ServerError(format!("Failed to render template: {}", e)))?;
+
+    Ok(Html(card))
+}
+
+pub async fn delete_prompt_template(
+    Path(template_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    let rows_affected = state
+        .chat_repo
+        .delete_prompt_template(template_id, current_user.id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to delete template: {}", e)))?;
+
+    if rows_affected == 0 {
+        return Err(ChatError::ChatNotFound);
+    }
+
+    Ok(Html(String::new()))
+}
+
+// New chat whose first message is the template's prompt; the chat page starts
+// generating the answer right away
+pub async fn start_from_template(
+    Path(template_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Response<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    let template = state
+        .chat_repo
+        .get_prompt_template(template_id, current_user.id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to retrieve template: {}", e)))?
+        .ok_or(ChatError::ChatNotFound)?;
+
     let model = current_user
         .model
         .as_deref()
@@ -522,121 +907,303 @@ pub async fn new_chat(
 
     let chat_id = state
         .chat_repo
-        .create_chat(current_user.id, &new_chat.message, model)
+        .create_chat(current_user.id, &template.title, model)
         .await
         .map_err(|e| ChatError::DatabaseError(format!("Failed to create chat: {}", e)))?;
+    place_in_workspace(&state, &current_user, chat_id).await?;
 
     state
         .chat_repo
-        .add_message_block(chat_id, &new_chat.message)
+        .add_message_block(chat_id, &template.prompt)
         .await
         .map_err(|e| ChatError::DatabaseError(format!("Failed to add message: {}", e)))?;
 
-    Ok(Response::builder()
+    let created = serde_json::json!({ "chat_id": chat_id, "name": template.title, "message": template.prompt });
+    webhooks::emit(&state.chat_repo, chat_id, webhooks::CHAT_CREATED, created).await;
+
+    Response::builder()
         .status(StatusCode::OK)
         .header("HX-Redirect", format!("/chat/{}", chat_id).as_str())
         .body("".to_string())
-        .map_err(|e| ChatError::ServerError(format!("Failed to build response: {}", e)))?)
+        .map_err(|e| ChatError::ServerError(format!("Failed to build response: {}", e)))
+}
+
+// Accepts a ChatGPT `conversations.json` or one of our own JSON exports and
+// recreates every conversation in it
+pub async fn import_chats(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    mut multipart: Multipart,
+) -> Result<Response<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    let mut data = None;
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| ChatError::ServerError(format!("Failed to read multipart field: {}", e)))?
+    {
+        if field.name() == Some("file") {
+            data = Some(field.bytes().await.map_err(|e| {
+                ChatError::ServerError(format!("Failed to read file data: {}", e))
+            })?);
+        }
+    }
+    let data = data.ok_or_else(|| ChatError::InvalidImport("missing file".to_string()))?;
+
+    let chats = parse_import(&data).map_err(|e| ChatError::InvalidImport(e.to_string()))?;
+
+    let mut last_chat_id = None;
+    for chat in &chats {
+        let chat_id = state
+            .chat_repo
+            .import_chat(current_user.id, chat)
+            .await
+            .map_err(|e| ChatError::DatabaseError(format!("Failed to import chat: {}", e)))?;
+        last_chat_id = Some(chat_id);
+    }
+
+    let location = last_chat_id
+        .map(|id| format!("/chat/{}", id))
+        .unwrap_or_else(|| "/chat".to_string());
+
+    Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(header::LOCATION, location)
+        .body("".to_string())
+        .map_err(|e| ChatError::ServerError(format!("Failed to build response: {}", e)))
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 struct ParsedMessagePair {
     pair: ChatMessagePair,
     human_message_html: String,
+    attachments: Vec<Attachment>,
     ai_message_html: String,
+    feedback: Option<i64>,
+    bookmarked: bool,
 }
 
-#[axum::debug_handler]
-pub async fn chat_by_id(
-    Path(chat_id): Path<i64>,
-    State(state): State<Arc<AppState>>,
-    Extension(current_user): Extension<Option<User>>,
-) -> Result<Html<String>, ChatError> {
-    let chat_message_pairs = state
-        .chat_repo
-        .retrieve_chat(chat_id)
-        .await
-        .map_err(|e| ChatError::DatabaseError(format!("Failed to retrieve chat: {}", e)))?;
-
-    let current_user = current_user.ok_or_else(|| ChatError::MissingUser)?;
-    let user_chats = state
-        .chat_repo
-        .get_all_chats(current_user.id)
-        .await
-        .map_err(|e| ChatError::DatabaseError(format!("Failed to retrieve user chats: {}", e)))?;
+// Rebuild the streamed state of a stored AI response, for rendering it again
+// or continuing it
+fn accumulator_from_pair(pair: &ChatMessagePair) -> Option<MessageAccumulator> {
+    let ai_message = pair.ai_message.as_ref()?;
+    let mut acc = MessageAccumulator {
+        text: ai_message.clone(),
+        thinking: pair.thinking.clone().unwrap_or_default(),
+        reasoning: pair.reasoning.clone().unwrap_or_default(),
+        tool_calls: Vec::new(),
+        images: Vec::new(),
+        usage: None,
+        sources: Vec::new(),
+        finish_reason: pair.finish_reason.clone(),
+        model: None,
+        started_at: None,
+        chat_id: None,
+        running_tool: None,
+    };
 
-    let parsed_pairs = chat_message_pairs
-        .iter()
-        .map(|pair| {
-            let human_message_html = markdown_to_html(&pair.human_message);
+    // Parse tool calls
+    if let Some(tool_calls_json) = &pair.tool_calls {
+        if let Ok(parsed) =
+            serde_json::from_str::<Vec<crate::data::model::ToolCall>>(tool_calls_json)
+        {
+            acc.tool_calls = parsed;
+        }
+    }
 
-            // Reconstruct extended message data if AI message exists
-            let ai_message_html = if let Some(ai_message) = &pair.ai_message {
-                let mut acc = MessageAccumulator {
-                    text: ai_message.clone(),
-                    thinking: pair.thinking.clone().unwrap_or_default(),
-                    reasoning: pair.reasoning.clone().unwrap_or_default(),
-                    tool_calls: Vec::new(),
-                    images: Vec::new(),
-                    usage: None,
-                    sources: Vec::new(),
-                };
+    // Parse images
+    if let Some(images_json) = &pair.images {
+        if let Ok(parsed) = serde_json::from_str::<Vec<String>>(images_json) {
+            acc.images = parsed;
+        }
+    }
 
-                // Parse tool calls
-                if let Some(tool_calls_json) = &pair.tool_calls {
-                    if let Ok(parsed) =
-                        serde_json::from_str::<Vec<crate::data::model::ToolCall>>(tool_calls_json)
-                    {
-                        acc.tool_calls = parsed;
-                    }
-                }
+    // Parse sources
+    if let Some(sources_json) = &pair.sources {
+        if let Ok(parsed) =
+            serde_json::from_str::<Vec<crate::data::model::Source>>(sources_json)
+        {
+            acc.sources = parsed;
+        }
+    }
 
-                // Parse images
-                if let Some(images_json) = &pair.images {
-                    if let Ok(parsed) = serde_json::from_str::<Vec<String>>(images_json) {
-                        acc.images = parsed;
-                    }
-                }
+    // Parse usage
+    if pair.usage_prompt_tokens.is_some()
+        || pair.usage_completion_tokens.is_some()
+        || pair.usage_total_tokens.is_some()
+    {
+        acc.usage = Some(crate::data::model::UsageInfo {
+            prompt_tokens: pair.usage_prompt_tokens.unwrap_or(0),
+            completion_tokens: pair.usage_completion_tokens.unwrap_or(0),
+            total_tokens: pair.usage_total_tokens.unwrap_or(0),
+        });
+    }
 
-                // Parse sources
-                if let Some(sources_json) = &pair.sources {
-                    if let Ok(parsed) =
-                        serde_json::from_str::<Vec<crate::data::model::Source>>(sources_json)
-                    {
-                        acc.sources = parsed;
-                    }
-                }
+    Some(acc)
+}
 
-                // Parse usage
-                if pair.usage_prompt_tokens.is_some()
-                    || pair.usage_completion_tokens.is_some()
-                    || pair.usage_total_tokens.is_some()
-                {
-                    acc.usage = Some(crate::data::model::UsageInfo {
-                        prompt_tokens: pair.usage_prompt_tokens.unwrap_or(0),
-                        completion_tokens: pair.usage_completion_tokens.unwrap_or(0),
-                        total_tokens: pair.usage_total_tokens.unwrap_or(0),
-                    });
-                }
+// Render stored pairs the same way they were streamed, for the chat page and
+// shared links. Each pair gets the files sent with it from `attachments`
+fn parse_message_pairs(pairs: &[ChatMessagePair], attachments: &[Attachment]) -> Vec<ParsedMessagePair> {
+    pairs
+        .iter()
+        .map(|pair| {
+            let human_message_html = markdown_to_html(&pair.human_message);
 
-                render_message_html(&acc)
-            } else {
-                String::new()
-            };
+            let ai_message_html = accumulator_from_pair(pair)
+                .map(|acc| render_message_html(&acc))
+                .unwrap_or_default();
 
             ParsedMessagePair {
                 pair: pair.clone(),
                 human_message_html,
+                attachments: attachments
+                    .iter()
+                    .filter(|attachment| attachment.message_pair_id == pair.id)
+                    .cloned()
+                    .collect(),
                 ai_message_html,
+                feedback: None,
+                bookmarked: false,
             }
         })
-        .collect::<Vec<_>>();
+        .collect()
+}
+
+const HISTORY_PAGE_SIZE: i64 = 50;
+
+// The user's role in a shared chat, if it's at least `min`. Chats the user
+// can't see at all are reported as missing
+pub(super) async fn authorize_chat(
+    state: &AppState,
+    user_id: i64,
+    chat_id: i64,
+    min: ChatRole,
+) -> Result<ChatRole, ChatError> {
+    let role = state
+        .chat_repo
+        .get_chat_role(chat_id, user_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to check chat access: {}", e)))?
+        .ok_or(ChatError::ChatNotFound)?;
+
+    if role < min {
+        return Err(ChatError::Forbidden);
+    }
+    Ok(role)
+}
+
+// One page of history with the user's ratings and bookmarks, plus the cursor of the page
+// before it when there is one
+async fn load_history_page(
+    state: &AppState,
+    user_id: i64,
+    chat_id: i64,
+    before: Option<i64>,
+) -> Result<(Vec<ParsedMessagePair>, Option<i64>), ChatError> {
+    let mut pairs = state
+        .chat_repo
+        .retrieve_chat_page(chat_id, before, HISTORY_PAGE_SIZE + 1)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to retrieve chat: {}", e)))?;
+
+    let older_cursor = if pairs.len() as i64 > HISTORY_PAGE_SIZE {
+        pairs.remove(0);
+        pairs.first().map(|pair| pair.message_block_id)
+    } else {
+        None
+    };
+
+    let feedback = state
+        .chat_repo
+        .get_feedback(user_id, chat_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to retrieve feedback: {}", e)))?;
+
+    let bookmarked = state
+        .chat_repo
+        .get_bookmarked_pairs(user_id, chat_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to retrieve bookmarks: {}", e)))?;
+
+    let attachments = state
+        .chat_repo
+        .get_chat_attachments(chat_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to retrieve attachments: {}", e)))?;
+
+    let mut parsed_pairs = parse_message_pairs(&pairs, &attachments);
+    for parsed in &mut parsed_pairs {
+        parsed.feedback = feedback
+            .iter()
+            .find(|f| f.pair_id == parsed.pair.id)
+            .map(|f| f.rating);
+        parsed.bookmarked = bookmarked.contains(&parsed.pair.id);
+    }
+
+    Ok((parsed_pairs, older_cursor))
+}
+
+#[axum::debug_handler]
+pub async fn chat_by_id(
+    Path(chat_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Query(params): Query<ChatListParams>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+    let role = authorize_chat(&state, current_user.id, chat_id, ChatRole::Viewer).await?;
+    let (parsed_pairs, older_cursor) =
+        load_history_page(&state, current_user.id, chat_id, None).await?;
+
+    let chat = state
+        .chat_repo
+        .get_chat(chat_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to retrieve chat: {}", e)))?;
+
+    let chat_settings = state
+        .chat_repo
+        .get_chat_settings(chat_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to load chat settings: {}", e)))?;
+    let agent = state
+        .chat_repo
+        .get_chat_agent(chat_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to load agent: {}", e)))?;
+    let resources = state
+        .chat_repo
+        .get_chat_resources(chat_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to load resources: {}", e)))?;
+
+    // Opening the chat shows the answers to any scheduled prompts
+    state
+        .chat_repo
+        .mark_scheduled_messages_seen(current_user.id, None, Some(chat_id))
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to update scheduled messages: {}", e)))?;
 
     let mut context = Context::new();
     context.insert("name", "World");
+    context.insert("chat_role", &role);
+    context.insert("generating", &state.generation_hub.is_generating(chat_id));
+    // Only the owner manages who else can see the chat
+    if role == ChatRole::Owner {
+        insert_chat_members(&state, &mut context, chat_id).await?;
+    }
+    context.insert("agent", &agent);
+    context.insert("chat_resources", &resources);
+    insert_chat_settings(&mut context, &chat_settings, &current_user);
+    insert_profile(&mut context, &current_user);
     context.insert("chat_message_pairs", &parsed_pairs);
+    context.insert("older_cursor", &older_cursor);
+    context.insert("older_page", &false);
     context.insert("chat_id", &chat_id);
-    context.insert("user_chats", &user_chats);
+    context.insert("chat", &chat);
+    insert_sidebar(&state, &mut context, &current_user, params.tag.as_deref(), params.cursor).await?;
 
     let home = state.tera.render("views/chat.html", &context).unwrap();
 
@@ -649,24 +1216,485 @@ pub async fn chat_by_id(
 }
 
 #[derive(Deserialize, Debug)]
-pub struct ChatAddMessage {
-    message: String,
+pub struct HistoryParams {
+    before: i64,
 }
 
-use axum::extract::Multipart;
-use std::path::PathBuf;
-use tokio::fs::File;
-use tokio::io::AsyncWriteExt;
-
-#[axum::debug_handler]
-pub async fn chat_add_message(
+// Older messages, prepended by the "load older" marker at the top of the chat
+pub async fn chat_history(
     Path(chat_id): Path<i64>,
+    Query(params): Query<HistoryParams>,
     State(state): State<Arc<AppState>>,
-    Extension(_current_user): Extension<Option<User>>,
-    mut multipart: Multipart,
+    Extension(current_user): Extension<Option<User>>,
 ) -> Result<Html<String>, ChatError> {
-    let mut message = String::new();
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    let role = authorize_chat(&state, current_user.id, chat_id, ChatRole::Viewer).await?;
+
+    let (parsed_pairs, older_cursor) = load_history_page(&state, current_user.id, chat_id, Some(params.before)).await?;
+
+    let mut context = Context::new();
+    context.insert("chat_id", &chat_id);
+    context.insert("chat_role", &role);
+    insert_profile(&mut context, &current_user);
+    context.insert("generating", &false);
+    context.insert("chat_message_pairs", &parsed_pairs);
+    context.insert("older_cursor", &older_cursor);
+    context.insert("older_page", &true);
+    let update = state
+        .tera
+        .render("htmx_updates/chat_history.html", &context)
+        .map_err(|e| ChatError::ServerError(format!("Failed to render history: {}", e)))?;
+
+    Ok(Html(update))
+}
+
+#[axum::debug_handler]
+pub async fn chat_attachments(
+    Path(chat_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+    let role = authorize_chat(&state, current_user.id, chat_id, ChatRole::Viewer).await?;
+
+    let chat_message_pairs = state
+        .chat_repo
+        .retrieve_chat(chat_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to retrieve chat: {}", e)))?;
+
+    let stored = state
+        .chat_repo
+        .get_chat_attachments(chat_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to retrieve attachments: {}", e)))?;
+
+    let attachments = collect_attachments(&chat_message_pairs, &stored);
+
+    let mut context = Context::new();
+    context.insert("chat_id", &chat_id);
+    context.insert("chat_role", &role);
+    context.insert("attachments", &attachments);
+    let gallery = state
+        .tera
+        .render("views/attachments.html", &context)
+        .map_err(|e| ChatError::ServerError(format!("Failed to render attachments: {}", e)))?;
+
+    let mut context = Context::new();
+    context.insert("view", &gallery);
+    context.insert("current_user", &current_user);
+    let rendered = state
+        .tera
+        .render("views/main.html", &context)
+        .map_err(|e| ChatError::ServerError(format!("Failed to render page: {}", e)))?;
+
+    Ok(Html(rendered))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ExportParams {
+    #[serde(default)]
+    format: ExportFormat,
+}
+
+#[axum::debug_handler]
+pub async fn export_chat(
+    Path(chat_id): Path<i64>,
+    Query(params): Query<ExportParams>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Response, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    let chat = state
+        .chat_repo
+        .get_chat(chat_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to retrieve chat: {}", e)))?
+        .ok_or(ChatError::ChatNotFound)?;
+    authorize_chat(&state, current_user.id, chat_id, ChatRole::Viewer).await?;
+
+    let chat_message_pairs = state
+        .chat_repo
+        .retrieve_chat(chat_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to retrieve chat: {}", e)))?;
+
+    let body = export::render(&export::build_export(&chat, &chat_message_pairs), params.format);
+    let disposition = format!(
+        "attachment; filename=\"chat-{}.{}\"",
+        chat_id,
+        params.format.extension()
+    );
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, params.format.content_type().to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum StatsFormat {
+    #[default]
+    Html,
+    Json,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct StatsParams {
+    #[serde(default)]
+    format: StatsFormat,
+}
+
+pub(super) async fn load_chat_stats(state: &AppState, chat_id: i64) -> Result<ChatStats, ChatError> {
+    let messages = state
+        .chat_repo
+        .count_chat_messages(chat_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to count messages: {}", e)))?;
+    let models = state
+        .chat_repo
+        .chat_model_stats(chat_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to load chat stats: {}", e)))?;
+
+    Ok(ChatStats::new(chat_id, messages, models))
+}
+
+#[axum::debug_handler]
+pub async fn chat_stats(
+    Path(chat_id): Path<i64>,
+    Query(params): Query<StatsParams>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Response, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    let chat = state
+        .chat_repo
+        .get_chat(chat_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to retrieve chat: {}", e)))?
+        .ok_or(ChatError::ChatNotFound)?;
+    authorize_chat(&state, current_user.id, chat_id, ChatRole::Viewer).await?;
+
+    let stats = load_chat_stats(&state, chat_id).await?;
+    if let StatsFormat::Json = params.format {
+        return Ok(Json(stats).into_response());
+    }
+
+    let mut context = Context::new();
+    context.insert("chat", &chat);
+    context.insert("stats", &stats);
+    let card = state
+        .tera
+        .render("views/chat_stats.html", &context)
+        .map_err(|e| ChatError::ServerError(format!("Failed to render stats: {}", e)))?;
+
+    let mut context = Context::new();
+    context.insert("view", &card);
+    context.insert("current_user", &current_user);
+    let rendered = state
+        .tera
+        .render("views/main.html", &context)
+        .map_err(|e| ChatError::ServerError(format!("Failed to render page: {}", e)))?;
+
+    Ok(Html(rendered).into_response())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SearchParams {
+    #[serde(default)]
+    q: String,
+}
+
+const RELATED_CHUNKS: i64 = 5;
+
+// Escape a search snippet and turn the match markers into <mark> tags
+fn highlight_snippet(snippet: &str) -> String {
+    html_escape::encode_text(snippet)
+        .replace('\u{2}', "<mark>")
+        .replace('\u{3}', "</mark>")
+}
+
+// Answers close in meaning to the search that it didn't match word for
+// word, when embeddings are configured. A failing endpoint only hides them
+async fn related_chunks(state: &AppState, user_id: i64, query: &str, hits: &[SearchHit]) -> Vec<SimilarChunk> {
+    let Some(config) = embeddings::EmbeddingConfig::from_env() else {
+        return Vec::new();
+    };
+    if query.trim().is_empty() {
+        return Vec::new();
+    }
+    let embedding = match embeddings::embed(&config, &[query.to_string()]).await {
+        Ok(mut embedded) if !embedded.is_empty() => embedded.remove(0),
+        Ok(_) => return Vec::new(),
+        Err(e) => {
+            tracing::warn!("Failed to embed search: {}", e);
+            return Vec::new();
+        }
+    };
+    match state.chat_repo.similar_chunks(user_id, &embedding, RELATED_CHUNKS).await {
+        Ok(chunks) => chunks
+            .into_iter()
+            .filter(|chunk| !hits.iter().any(|hit| hit.pair_id == chunk.pair_id))
+            .collect(),
+        Err(e) => {
+            tracing::error!("Failed to find related messages: {}", e);
+            Vec::new()
+        }
+    }
+}
+
+#[axum::debug_handler]
+pub async fn chat_search(
+    Query(params): Query<SearchParams>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    let mut hits = state
+        .chat_repo
+        .search_messages(current_user.id, &params.q)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to search chats: {}", e)))?;
+    for hit in &mut hits {
+        hit.snippet = highlight_snippet(&hit.snippet);
+    }
+    let related = related_chunks(&state, current_user.id, &params.q, &hits).await;
+
+    let mut context = Context::new();
+    context.insert("query", &params.q);
+    context.insert("hits", &hits);
+    context.insert("related", &related);
+    let results = state
+        .tera
+        .render("views/search.html", &context)
+        .map_err(|e| ChatError::ServerError(format!("Failed to render search: {}", e)))?;
+
+    let mut context = Context::new();
+    context.insert("view", &results);
+    context.insert("current_user", &current_user);
+    let rendered = state
+        .tera
+        .render("views/main.html", &context)
+        .map_err(|e| ChatError::ServerError(format!("Failed to render page: {}", e)))?;
+
+    Ok(Html(rendered))
+}
+
+// Only files sent with a message can be removed; the message itself stays
+pub async fn delete_attachment(
+    Path((chat_id, attachment_id)): Path<(i64, i64)>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+    authorize_chat(&state, current_user.id, chat_id, ChatRole::Editor).await?;
+
+    let unused = state
+        .chat_repo
+        .delete_attachment(chat_id, attachment_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to delete attachment: {}", e)))?;
+
+    // Copies made by forks and edits share the file
+    if let Some(file) = unused {
+        let path = std::path::Path::new("uploads").join(&file);
+        match tokio::fs::remove_file(&path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                tracing::warn!("Failed to remove {}: {}", path.display(), e);
+            }
+            _ => {}
+        }
+    }
+
+    Ok(Html(String::new()))
+}
+
+// MCP resources longer than this would crowd everything else out of the prompt
+const MAX_RESOURCE_CHARS: usize = 100_000;
+
+#[derive(Serialize, Debug)]
+struct ResourceOption {
+    server: String,
+    uri: String,
+    name: String,
+    description: Option<String>,
+}
+
+// What the connected MCP servers offer to attach, for the composer's picker
+pub async fn chat_resource_picker(
+    Path(chat_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+    authorize_chat(&state, current_user.id, chat_id, ChatRole::Editor).await?;
+
+    let mcp = users::for_user(&state.chat_repo, &state.encryption, current_user.id).await;
+    let mut resources = Vec::new();
+    for server in mcp.get_connected_servers().await {
+        match mcp.list_resources_for_server(&server).await {
+            Ok(listed) => resources.extend(listed.iter().filter_map(|resource| {
+                let uri = resource.get("uri")?.as_str()?;
+                Some(ResourceOption {
+                    server: server.clone(),
+                    uri: uri.to_string(),
+                    name: resource.get("name").and_then(|n| n.as_str()).unwrap_or(uri).to_string(),
+                    description: resource.get("description").and_then(|d| d.as_str()).map(str::to_string),
+                })
+            })),
+            // Servers without resources answer with an error
+            Err(e) => tracing::debug!("No resources from MCP server {}: {}", server, e),
+        }
+    }
+
+    let mut context = Context::new();
+    context.insert("chat_id", &chat_id);
+    context.insert("resources", &resources);
+    let picker = state
+        .tera
+        .render("htmx_updates/resource_picker.html", &context)
+        .map_err(|e| ChatError::ServerError(format!("Failed to render resources: {}", e)))?;
+    Ok(Html(picker))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AttachResourceForm {
+    server: String,
+    uri: String,
+    name: String,
+}
+
+// Reads the resource now and keeps its text with the chat
+pub async fn attach_chat_resource(
+    Path(chat_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Form(form): Form<AttachResourceForm>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+    authorize_chat(&state, current_user.id, chat_id, ChatRole::Editor).await?;
+
+    let mcp = users::for_user(&state.chat_repo, &state.encryption, current_user.id).await;
+    let error = match read_resource_text(&mcp, &form).await {
+        Ok((content, mime_type)) => {
+            let resource = NewChatResource {
+                server: &form.server,
+                uri: &form.uri,
+                name: &form.name,
+                mime_type: mime_type.as_deref(),
+                content: &content,
+            };
+            state
+                .chat_repo
+                .attach_chat_resource(chat_id, &resource)
+                .await
+                .map_err(|e| ChatError::DatabaseError(format!("Failed to attach resource: {}", e)))?;
+            None
+        }
+        Err(e) => Some(e),
+    };
+    render_chat_resources(&state, chat_id, error.as_deref()).await
+}
+
+async fn read_resource_text(mcp: &UserMcp, form: &AttachResourceForm) -> Result<(String, Option<String>), String> {
+    let result = mcp
+        .read_resource(&form.server, &form.uri)
+        .await
+        .map_err(|e| e.to_string())?;
+    let content = result
+        .text()
+        .ok_or_else(|| format!("{} has no text to attach", form.name))?;
+    if content.chars().count() > MAX_RESOURCE_CHARS {
+        return Err(format!("{} is too large to attach", form.name));
+    }
+    Ok((content, result.mime_type().map(str::to_string)))
+}
+
+pub async fn detach_chat_resource(
+    Path((chat_id, resource_id)): Path<(i64, i64)>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+    authorize_chat(&state, current_user.id, chat_id, ChatRole::Editor).await?;
+
+    state
+        .chat_repo
+        .detach_chat_resource(chat_id, resource_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to detach resource: {}", e)))?;
+    render_chat_resources(&state, chat_id, None).await
+}
+
+async fn render_chat_resources(state: &AppState, chat_id: i64, error: Option<&str>) -> Result<Html<String>, ChatError> {
+    let resources = state
+        .chat_repo
+        .get_chat_resources(chat_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to load resources: {}", e)))?;
+
+    let mut context = Context::new();
+    context.insert("chat_id", &chat_id);
+    context.insert("chat_resources", &resources);
+    context.insert("resource_error", &error);
+    let pills = state
+        .tera
+        .render("htmx_updates/chat_resources.html", &context)
+        .map_err(|e| ChatError::ServerError(format!("Failed to render resources: {}", e)))?;
+    Ok(Html(pills))
+}
+
+// The attached resources as a section of the system prompt
+fn resource_context(resources: &[ChatResource]) -> Option<String> {
+    if resources.is_empty() {
+        return None;
+    }
+    let mut context = String::from("The user attached these resources to the conversation:");
+    for resource in resources {
+        context.push_str(&format!(
+            "\n\n## {} ({})\n\n```\n{}\n```",
+            resource.name, resource.uri, resource.content
+        ));
+    }
+    Some(context)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ChatAddMessage {
+    message: String,
+}
+
+use axum::extract::Multipart;
+use std::path::PathBuf;
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+
+#[axum::debug_handler]
+pub async fn chat_add_message(
+    Path(chat_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    mut multipart: Multipart,
+) -> Result<Html<String>, ChatError> {
+    let user_id = current_user.as_ref().ok_or(ChatError::MissingUser)?.id;
+    authorize_chat(&state, user_id, chat_id, ChatRole::Editor).await?;
+
+    let mut message = String::new();
     let mut file_attachments = Vec::new();
+    // Set when the message is an MCP prompt submitted from its arguments form
+    let mut prompt_server = None;
+    let mut prompt_name = None;
+    let mut prompt_arguments = serde_json::Map::new();
 
     // Create uploads directory if it doesn't exist
     tokio::fs::create_dir_all("uploads").await.map_err(|e| {
@@ -685,8 +1713,29 @@ pub async fn chat_add_message(
             message = field.text().await.map_err(|e| {
                 ChatError::ServerError(format!("Failed to read message text: {}", e))
             })?;
+        } else if name == "prompt_server" || name == "prompt_name" {
+            let value = field.text().await.map_err(|e| {
+                ChatError::ServerError(format!("Failed to read prompt: {}", e))
+            })?;
+            if name == "prompt_server" {
+                prompt_server = Some(value);
+            } else {
+                prompt_name = Some(value);
+            }
+        } else if let Some(argument) = name.strip_prefix("argument.") {
+            let value = field.text().await.map_err(|e| {
+                ChatError::ServerError(format!("Failed to read prompt argument: {}", e))
+            })?;
+            // Optional arguments left empty aren't sent
+            if !value.is_empty() {
+                prompt_arguments.insert(argument.to_string(), value.into());
+            }
         } else if name == "files" {
             let filename = field.file_name().unwrap_or("unknown").to_string();
+            let mime = field
+                .content_type()
+                .unwrap_or("application/octet-stream")
+                .to_string();
             let data = field
                 .bytes()
                 .await
@@ -718,472 +1767,2531 @@ pub async fn chat_add_message(
                 || filename.ends_with(".gif")
                 || filename.ends_with(".webp");
 
-            file_attachments.push((filename, file_path, is_image));
+            file_attachments.push(NewAttachment {
+                name: filename,
+                path: unique_filename,
+                mime,
+                size: data.len() as i64,
+                kind: if is_image { "image" } else { "file" }.to_string(),
+            });
         }
     }
 
-    // Add file references to message
-    if !file_attachments.is_empty() {
-        let attachments_text = file_attachments
-            .iter()
-            .map(|(name, path, is_image)| {
-                if *is_image {
-                    format!("\n\n![{}](/{})  ", name, path)
-                } else {
-                    format!("\n\n[📎 {}](/{})  ", name, path)
-                }
-            })
-            .collect::<String>();
-        message.push_str(&attachments_text);
+    if let (Some(server), Some(name)) = (prompt_server, prompt_name) {
+        let mcp = users::for_user(&state.chat_repo, &state.encryption, user_id).await;
+        match run_mcp_prompt(&mcp, &server, &name, prompt_arguments).await {
+            Ok(text) => message = text,
+            Err(e) => return render_command_notice(&state, &e),
+        }
+    }
+
+    // Commands either rewrite the message or are answered right away
+    match parse_command(&message) {
+        Some(Ok(Command::Prompt(prompt))) => message = prompt,
+        Some(Ok(command)) => {
+            let notice = run_command(&state, current_user, chat_id, command).await?;
+            return render_command_notice(&state, &notice);
+        }
+        Some(Err(usage)) => return render_command_notice(&state, &usage),
+        None => {}
+    }
+    // Told here rather than when the response fails to stream
+    match check_user_quota(&state, user_id).await {
+        Err(ChatError::QuotaExceeded(reason)) => return render_command_notice(&state, &reason),
+        result => result?,
     }
 
     // Validate message
-    if message.trim().is_empty() {
+    if message.trim().is_empty() && file_attachments.is_empty() {
         return Err(ChatError::InvalidMessage);
     }
 
-    state
+    let pair_id = state
+        .chat_repo
+        .add_message_with_attachments(chat_id, &message, &file_attachments)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to add message: {}", e)))?;
+
+    let human_message_html = markdown_to_html(&message);
+
+    // Other members of a shared chat see the message and then the answer streaming in
+    if let Some(author) = &current_user {
+        let mut context = Context::new();
+        context.insert("human_message_html", &human_message_html);
+        context.insert("attachments", &file_attachments);
+        context.insert("author", author.name());
+        context.insert("avatar", &author.avatar);
+        match state.tera.render("htmx_updates/live_message.html", &context) {
+            Ok(html) => state.chat_rooms.publish(
+                chat_id,
+                RoomEvent::Message {
+                    author_id: author.id,
+                    html,
+                },
+            ),
+            Err(e) => tracing::error!("Failed to render live message: {}", e),
+        }
+    }
+
+    let mut context = Context::new();
+    context.insert("human_message_html", &human_message_html);
+    context.insert("attachments", &file_attachments);
+    context.insert("chat_id", &chat_id);
+    context.insert("pair_id", &pair_id);
+    if let Some(user) = &current_user {
+        insert_profile(&mut context, user);
+    }
+    let update = state
+        .tera
+        .render("htmx_updates/add_message.html", &context)
+        .unwrap();
+
+    Ok(Html(update))
+}
+
+// Runs a command that doesn't need the model and describes the outcome
+async fn run_command(
+    state: &Arc<AppState>,
+    current_user: Option<User>,
+    chat_id: i64,
+    command: Command,
+) -> Result<String, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    match command {
+        Command::Model(model) => {
+            let rows_affected = state
+                .chat_repo
+                .set_chat_model(chat_id, current_user.id, &model)
+                .await
+                .map_err(|e| ChatError::DatabaseError(format!("Failed to set model: {}", e)))?;
+            if rows_affected == 0 {
+                return Err(ChatError::ChatNotFound);
+            }
+            Ok(format!("This chat now uses {}.", model))
+        }
+        Command::Tools => {
+            let agent = state
+                .chat_repo
+                .get_chat_agent(chat_id)
+                .await
+                .map_err(|e| ChatError::DatabaseError(format!("Failed to load agent: {}", e)))?;
+            if agent.is_some_and(|agent| !agent.tools_enabled) {
+                return Ok("Tools are turned off for this chat's agent.".to_string());
+            }
+
+            let mcp = users::for_user(&state.chat_repo, &state.encryption, current_user.id).await;
+            let mut names = get_available_tools(&mcp)
+                .await
+                .map(|tools| tools.into_iter().map(|tool| tool.name).collect::<Vec<_>>())
+                .unwrap_or_default();
+            let custom_tools = state
+                .chat_repo
+                .get_custom_tools(current_user.id)
+                .await
+                .map_err(|e| ChatError::DatabaseError(format!("Failed to load custom tools: {}", e)))?;
+            names.extend(custom_tools.into_iter().map(|tool| tool.name));
+
+            if names.is_empty() {
+                Ok("No tools are available.".to_string())
+            } else {
+                Ok(format!("Available tools: {}", names.join(", ")))
+            }
+        }
+        Command::Prompt(_) => Err(ChatError::InvalidMessage),
+    }
+}
+
+fn render_command_notice(state: &Arc<AppState>, notice: &str) -> Result<Html<String>, ChatError> {
+    let mut context = Context::new();
+    context.insert("notice", notice);
+    state
+        .tera
+        .render("htmx_updates/command_notice.html", &context)
+        .map(Html)
+        .map_err(|e| ChatError::ServerError(format!("Failed to render template: {}", e)))
+}
+
+// A command offered by the composer's autocomplete. Prompts from MCP servers
+// carry their server and name, and open a form for their arguments
+#[derive(Serialize, Debug)]
+pub struct CommandSuggestion {
+    name: String,
+    usage: String,
+    description: String,
+    server: Option<String>,
+    prompt: Option<String>,
+}
+
+impl From<&SlashCommand> for CommandSuggestion {
+    fn from(command: &SlashCommand) -> Self {
+        CommandSuggestion {
+            name: command.name.to_string(),
+            usage: command.usage.to_string(),
+            description: command.description.to_string(),
+            server: None,
+            prompt: None,
+        }
+    }
+}
+
+// The prompts of every connected MCP server, by server
+async fn mcp_prompts(mcp: &UserMcp) -> Vec<(String, serde_json::Value)> {
+    let mut prompts = Vec::new();
+    for server in mcp.get_connected_servers().await {
+        match mcp.list_prompts_for_server(&server).await {
+            Ok(listed) => prompts.extend(listed.into_iter().map(|prompt| (server.clone(), prompt))),
+            // Servers without prompts answer with an error
+            Err(e) => tracing::debug!("No prompts from MCP server {}: {}", server, e),
+        }
+    }
+    prompts
+}
+
+pub async fn list_commands(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Json<Vec<CommandSuggestion>> {
+    let mut commands: Vec<CommandSuggestion> = COMMANDS.iter().map(CommandSuggestion::from).collect();
+    let mcp = match current_user {
+        Some(user) => users::for_user(&state.chat_repo, &state.encryption, user.id).await,
+        None => UserMcp::global_only(),
+    };
+    for (server, prompt) in mcp_prompts(&mcp).await {
+        let Some(name) = prompt.get("name").and_then(|n| n.as_str()) else {
+            continue;
+        };
+        let description = prompt.get("description").and_then(|d| d.as_str()).unwrap_or("");
+        commands.push(CommandSuggestion {
+            name: format!("{}.{}", server, name),
+            usage: format!("/{}.{}", server, name),
+            description: format!("{} (from {})", description, server).trim_start().to_string(),
+            server: Some(server),
+            prompt: Some(name.to_string()),
+        });
+    }
+    Json(commands)
+}
+
+// The arguments form for an MCP prompt, opened from the autocomplete
+pub async fn mcp_prompt_form(
+    Path((chat_id, server, name)): Path<(i64, String, String)>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+    authorize_chat(&state, current_user.id, chat_id, ChatRole::Editor).await?;
+
+    // None when the server went away since the list was loaded
+    let mcp = users::for_user(&state.chat_repo, &state.encryption, current_user.id).await;
+    let prompt = mcp_prompts(&mcp)
+        .await
+        .into_iter()
+        .find(|(prompt_server, prompt)| *prompt_server == server && prompt["name"] == name.as_str())
+        .map(|(_, prompt)| prompt);
+
+    let mut context = Context::new();
+    context.insert("chat_id", &chat_id);
+    context.insert("server", &server);
+    context.insert("name", &name);
+    context.insert("prompt", &prompt);
+    let form = state
+        .tera
+        .render("htmx_updates/mcp_prompt_form.html", &context)
+        .map_err(|e| ChatError::ServerError(format!("Failed to render prompt form: {}", e)))?;
+    Ok(Html(form))
+}
+
+// The text of an MCP prompt filled in with the submitted arguments
+async fn run_mcp_prompt(
+    mcp: &UserMcp,
+    server: &str,
+    name: &str,
+    arguments: serde_json::Map<String, serde_json::Value>,
+) -> Result<String, String> {
+    let result = mcp
+        .get_prompt(server, name, Some(serde_json::Value::Object(arguments)))
+        .await
+        .map_err(|e| e.to_string())?;
+    let text = result.text();
+    if text.trim().is_empty() {
+        return Err(format!("The prompt {} returned no text", name));
+    }
+    Ok(text)
+}
+
+// Usage counts against the user who generates, whose key is used, also in
+// chats shared with them
+async fn check_user_quota(state: &AppState, user_id: i64) -> Result<(), ChatError> {
+    let status = quota::status(state, user_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to check quota: {}", e)))?;
+    match status.exceeded() {
+        Some(reason) => Err(ChatError::QuotaExceeded(reason)),
+        None => Ok(()),
+    }
+}
+
+// The API key a chat's responses are generated with and the model to use
+// unless the chat or its agent sets one. An org chat uses the org's key and
+// model when it has a key, and the user's otherwise
+async fn chat_api_key(state: &AppState, user: &User, chat_id: i64) -> Result<(String, Option<String>), ChatError> {
+    let org = state
+        .chat_repo
+        .get_chat_org(chat_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to load org: {}", e)))?
+        .filter(|org| org.has_api_key());
+    let (key, default_model) = match &org {
+        Some(org) => {
+            let stored = org.openai_api_key.as_deref().unwrap_or_default();
+            let key = state.encryption.decrypt(stored).map_err(|e| {
+                tracing::error!("Failed to decrypt the API key of org {}: {}", org.id, e);
+                ChatError::UnreadableAPIKey
+            })?;
+            (key, org.model.clone().or(user.model.clone()))
+        }
+        // Guests without a key of their own use the server's
+        None if user.guest_expires_at.is_some()
+            && user.openai_api_key.as_deref().is_none_or(|key| key.trim().is_empty()) =>
+        {
+            (
+                guest_api_key().ok_or(ChatError::EmptyAPIKey)?,
+                user.model.clone().or_else(guest_model),
+            )
+        }
+        None => match user.openai_api_key.clone() {
+            Some(key) => (key, user.model.clone()),
+            // `find_user` drops keys it can't decrypt
+            None => {
+                let stored = state
+                    .chat_repo
+                    .stored_api_key(user.id)
+                    .await
+                    .map_err(|e| ChatError::DatabaseError(format!("Failed to load API key: {}", e)))?;
+                return Err(if stored.is_some() {
+                    ChatError::UnreadableAPIKey
+                } else {
+                    ChatError::EmptyAPIKey
+                });
+            }
+        },
+    };
+
+    if key.trim().is_empty() {
+        return Err(ChatError::EmptyAPIKey);
+    }
+
+    Ok((key, default_model))
+}
+
+// Validate the request and spawn the generation task. Returns the event
+// receiver, the id of the message pair the response will be stored on and the
+// response so far (empty unless a cut-off response is being continued).
+async fn start_generation(
+    state: &Arc<AppState>,
+    current_user: Option<User>,
+    chat_id: i64,
+    continue_from: Option<i64>,
+) -> Result<
+    (mpsc::Receiver<Result<GenerationEvent, axum::Error>>, i64, MessageAccumulator),
+    ChatError,
+> {
+    let user = current_user.ok_or(ChatError::MissingUser)?;
+    authorize_chat(state, user.id, chat_id, ChatRole::Editor).await?;
+    check_user_quota(state, user.id).await?;
+
+    let (key, default_model) = chat_api_key(state, &user, chat_id).await?;
+
+    // Retrieve chat messages
+    let mut chat_message_pairs = state
+        .chat_repo
+        .retrieve_chat(chat_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to retrieve chat: {}", e)))?;
+
+    if chat_message_pairs.is_empty() {
+        return Err(ChatError::ChatNotFound);
+    }
+
+    // The model sees uploads as the links messages used to carry
+    let attachments = state
+        .chat_repo
+        .get_chat_attachments(chat_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to retrieve attachments: {}", e)))?;
+    for pair in &mut chat_message_pairs {
+        let sent: Vec<Attachment> = attachments
+            .iter()
+            .filter(|attachment| attachment.message_pair_id == pair.id)
+            .cloned()
+            .collect();
+        pair.human_message.push_str(&attachment_links(&sent));
+    }
+
+    let agent = state
+        .chat_repo
+        .get_chat_agent(chat_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to load agent: {}", e)))?;
+
+    let chat_settings = state
+        .chat_repo
+        .get_chat_settings(chat_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to load chat settings: {}", e)))?;
+
+    let resources = state
+        .chat_repo
+        .get_chat_resources(chat_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to load resources: {}", e)))?;
+
+    // Use the chat's model, then the agent's, then the org's or the one from
+    // user settings, then the default
+    let model = chat_settings
+        .model
+        .clone()
+        .or_else(|| agent.as_ref().map(|agent| agent.model.clone()))
+        .or(default_model)
+        .unwrap_or_else(|| "Qwen/Qwen2.5-7B-Instruct".to_string());
+
+    // Validate API key
+    match list_engines(&key).await {
+        Ok(_res) => {}
+        Err(e) => {
+            tracing::error!("API key validation failed: {:?}", e);
+            return Err(ChatError::InvalidAPIKey);
+        }
+    };
+
+    let last_pair = chat_message_pairs.last().unwrap();
+    let lat_message_id = last_pair.id;
+    state
+        .chat_repo
+        .bill_message_pair(lat_message_id, user.id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to bill message: {}", e)))?;
+
+    // Only the latest response can be continued, and only if it hit the token limit
+    let mut partial = match continue_from {
+        Some(pair_id) => {
+            if pair_id != lat_message_id || last_pair.finish_reason.as_deref() != Some("length") {
+                return Err(ChatError::InvalidMessage);
+            }
+            accumulator_from_pair(last_pair).ok_or(ChatError::InvalidMessage)?
+        }
+        None => MessageAccumulator::default(),
+    };
+    partial.model = Some(model.clone());
+    partial.chat_id = Some(chat_id);
+    partial.started_at = Some(std::time::Instant::now());
+
+    let disable_tools = agent.as_ref().is_some_and(|agent| !agent.tools_enabled);
+    let custom_tools = if disable_tools {
+        Vec::new()
+    } else {
+        state
+            .chat_repo
+            .get_custom_tools(user.id)
+            .await
+            .map_err(|e| ChatError::DatabaseError(format!("Failed to load custom tools: {}", e)))?
+            .into_iter()
+            .map(|tool| custom_tools::opened(tool, &state.encryption))
+            .collect()
+    };
+
+    // Chat overrides win over the agent's parameters, which win over the
+    // user's defaults
+    let mut stop = chat_settings.stop_sequences();
+    if stop.is_empty() {
+        stop = agent.as_ref().map(|agent| agent.stop_sequences()).unwrap_or_default();
+    }
+    let options = GenerationOptions {
+        model,
+        system_prompt: agent.as_ref().and_then(|agent| agent.system_prompt.clone()),
+        context: resource_context(&resources),
+        disable_tools,
+        allow_tools: agent.as_ref().and_then(|agent| agent.allow_tools()),
+        stop,
+        temperature: chat_settings
+            .temperature
+            .or(agent.as_ref().and_then(|agent| agent.temperature))
+            .or(user.temperature),
+        top_p: chat_settings
+            .top_p
+            .or(agent.as_ref().and_then(|agent| agent.top_p))
+            .or(user.top_p),
+        max_tokens: chat_settings
+            .max_tokens
+            .or(agent.as_ref().and_then(|agent| agent.max_tokens))
+            .or(user.max_tokens),
+    };
+
+    // Create a channel for sending SSE events
+    let (sender, mut generated) = mpsc::channel::<Result<GenerationEvent, axum::Error>>(10);
+    let (forward, receiver) = mpsc::channel::<Result<GenerationEvent, axum::Error>>(10);
+
+    // Spawn a task that generates SSE events and sends them into the channel
+    let repo = state.chat_repo.clone();
+    let encryption = state.encryption.clone();
+    let user_id = user.id;
+    tokio::spawn(async move {
+        // Call your existing function to start generating events
+        if let Err(e) = generate_sse_stream(&key, &options, chat_message_pairs, custom_tools, sender, Some(ConfirmationTarget { repo: &repo, encryption: &encryption, user_id, chat_id, message_pair_id: lat_message_id })).await {
+            tracing::error!("Failed to generate a response: {:?}", e);
+        }
+    });
+
+    // Fan events out to session subscribers while forwarding them to the caller.
+    // Generation still stops when the caller goes away, as the `generated`
+    // receiver is dropped with this task.
+    let hub = state.generation_hub.clone();
+    hub.begin(chat_id);
+    tokio::spawn(async move {
+        let mut ended = false;
+        while let Some(event) = generated.recv().await {
+            if let Ok(event) = &event {
+                ended |= matches!(event, GenerationEvent::End(_));
+                hub.publish(chat_id, event);
+            }
+            if forward.send(event).await.is_err() {
+                break;
+            }
+        }
+        // Let subscribers know a cancelled generation won't finish
+        if !ended {
+            hub.publish(chat_id, &GenerationEvent::End(String::new()));
+        }
+        hub.release(chat_id);
+    });
+
+    Ok((receiver, lat_message_id, partial))
+}
+
+impl MessageAccumulator {
+    // Fold a streamed event into the accumulator and return the data to push to
+    // the client. `End` is handled separately by `complete_generation`.
+    fn apply(&mut self, event: GenerationEvent) -> String {
+        // Tools run one at a time, so anything after a call means it's done
+        self.running_tool = None;
+        match event {
+            GenerationEvent::Text(text) => {
+                self.text.push_str(&text);
+                // Render HTML without reasoning/thinking (those are handled separately)
+                render_message_text_only(self)
+            }
+            GenerationEvent::Thinking(thinking) => {
+                self.thinking.push_str(&thinking);
+                render_thinking_section(&self.thinking)
+            }
+            GenerationEvent::Reasoning(reasoning) => {
+                self.reasoning.push_str(&reasoning);
+                render_reasoning_section(&self.reasoning)
+            }
+            // Not produced by the current stream implementation, which sends
+            // Thinking/Reasoning events directly
+            GenerationEvent::ThinkingUpdate(_) | GenerationEvent::ReasoningUpdate(_) => {
+                String::new()
+            }
+            GenerationEvent::ToolCall(tool_call) => {
+                self.tool_calls.push(tool_call);
+                render_message_html(self)
+            }
+            GenerationEvent::ToolRunning(execution_id) => {
+                self.running_tool = Some(execution_id);
+                render_message_html(self)
+            }
+            GenerationEvent::Image(image_url) => {
+                self.images.push(image_url);
+                render_message_html(self)
+            }
+            GenerationEvent::Usage(usage) => {
+                self.usage = Some(usage);
+                render_message_html(self)
+            }
+            GenerationEvent::Sources(sources) => {
+                self.sources = sources;
+                render_message_html(self)
+            }
+            GenerationEvent::ToolCancelled(_) => {
+                self.text.push_str("Tool Execution Cancelled");
+                render_message_text_only(self)
+            }
+            GenerationEvent::FinishReason(reason) => {
+                self.finish_reason = Some(reason);
+                String::new()
+            }
+            GenerationEvent::ToolCallConfirmation(confirmation) => {
+                // Send tool call confirmation request as JSON
+                serde_json::json!({
+                    "type": "tool_call_confirmation",
+                    "content": confirmation
+                })
+                .to_string()
+            }
+            GenerationEvent::End(_) => String::new(),
+        }
+    }
+}
+
+// Save the finished response with its extended data and render the final HTML
+async fn complete_generation(
+    state: &AppState,
+    chat_id: i64,
+    pair_id: i64,
+    acc: &MessageAccumulator,
+) -> String {
+    let tool_calls_json = if !acc.tool_calls.is_empty() {
+        serde_json::to_string(&acc.tool_calls).ok()
+    } else {
+        None
+    };
+
+    let images_json = if !acc.images.is_empty() {
+        serde_json::to_string(&acc.images).ok()
+    } else {
+        None
+    };
+
+    let sources_json = if !acc.sources.is_empty() {
+        serde_json::to_string(&acc.sources).ok()
+    } else {
+        None
+    };
+
+    let saved = state
+        .chat_repo
+        .add_ai_message_with_extended_data(
+            pair_id,
+            &acc.text,
+            if !acc.thinking.is_empty() {
+                Some(&acc.thinking)
+            } else {
+                None
+            },
+            tool_calls_json.as_deref(),
+            images_json.as_deref(),
+            if !acc.reasoning.is_empty() {
+                Some(&acc.reasoning)
+            } else {
+                None
+            },
+            acc.usage.as_ref().map(|u| u.prompt_tokens),
+            acc.usage.as_ref().map(|u| u.completion_tokens),
+            acc.usage.as_ref().map(|u| u.total_tokens),
+            sources_json.as_deref(),
+        )
+        .await;
+
+    match (saved, &acc.finish_reason) {
+        (Ok(message_id), Some(reason)) => {
+            if let Err(e) = state.chat_repo.set_finish_reason(message_id, reason).await {
+                tracing::error!("Failed to save finish reason for pair {}: {}", pair_id, e);
+            }
+            record_response_stats(state, message_id, acc).await;
+        }
+        (Ok(message_id), None) => record_response_stats(state, message_id, acc).await,
+        (Err(e), _) => tracing::error!("Failed to save AI message for pair {}: {}", pair_id, e),
+    }
+
+    let completed = serde_json::json!({
+        "chat_id": chat_id,
+        "pair_id": pair_id,
+        "text": acc.text,
+        "finish_reason": acc.finish_reason,
+        "usage": acc.usage,
+        "tool_calls": acc.tool_calls,
+    });
+    webhooks::emit(&state.chat_repo, chat_id, webhooks::GENERATION_COMPLETED, completed).await;
+
+    let mut html = render_complete_message(acc);
+    if acc.finish_reason.as_deref() == Some("length") {
+        let mut context = Context::new();
+        context.insert("chat_id", &chat_id);
+        context.insert("pair_id", &pair_id);
+        if let Ok(button) = state.tera.render("htmx_updates/continue_button.html", &context) {
+            html.push_str(&button);
+        }
+    }
+    html
+}
+
+async fn record_response_stats(state: &AppState, message_id: i64, acc: &MessageAccumulator) {
+    let (Some(model), Some(started_at)) = (&acc.model, acc.started_at) else {
+        return;
+    };
+    let latency_ms = started_at.elapsed().as_millis() as i64;
+    if let Err(e) = state.chat_repo.set_response_stats(message_id, model, latency_ms).await {
+        tracing::error!("Failed to save stats for message {}: {}", message_id, e);
+    }
+}
+
+fn render_complete_message(acc: &MessageAccumulator) -> String {
+    // Send final content update without the collapse sections
+    let final_text = if !acc.text.is_empty() {
+        markdown_to_html(&acc.text)
+    } else {
+        String::new()
+    };
+
+    // Build the complete message HTML
+    let mut complete_html = String::new();
+
+    // Add thinking section if exists
+    if !acc.thinking.is_empty() {
+        complete_html.push_str(&render_thinking_section(&acc.thinking));
+    }
+
+    // Add reasoning section if exists
+    if !acc.reasoning.is_empty() {
+        complete_html.push_str(&render_reasoning_section(&acc.reasoning));
+    }
+
+    // Add main content
+    if !acc.text.is_empty() {
+        complete_html.push_str(&final_text);
+    }
+
+    // Add tool calls
+    for tool_call in &acc.tool_calls {
+        complete_html.push_str(r#"<div class="card bg-accent/10 mb-4 border border-accent/20">"#);
+        complete_html.push_str(r#"<div class="card-body p-4">"#);
+        complete_html
+            .push_str(r#"<div class="flex items-center gap-2 mb-2">"#);
+        complete_html.push_str(r#"<svg xmlns="http://www.w3.org/2000/svg" class="h-5 w-5 text-accent" fill="none" viewBox="0 0 24 24" stroke="currentColor"><path stroke-linecap="round" stroke-linejoin="round" stroke-width="2" d="M10.325 4.317c.426-1.756 2.924-1.756 3.35 0a1.724 1.724 0 002.573 1.066c1.543-.94 3.31.826 2.37 2.37a1.724 1.724 0 001.065 2.572c1.756.426 1.756 2.924 0 3.35a1.724 1.724 0 00-1.066 2.573c.94 1.543-.826 3.31-2.37 2.37a1.724 1.724 0 00-2.572 1.065c-.426 1.756-2.924 1.756-3.35 0a1.724 1.724 0 00-2.573-1.066c-1.543.94-3.31-.826-2.37-2.37a1.724 1.724 0 00-1.065-2.572c-1.756-.426-1.756-2.924 0-3.35a1.724 1.724 0 001.066-2.573c-.94-1.543.826-3.31 2.37-2.37.996.608 2.296.07 2.572-1.065z" /><path stroke-linecap="round" stroke-linejoin="round" stroke-width="2" d="M15 12a3 3 0 11-6 0 3 3 0 016 0z" /></svg>"#);
+        complete_html.push_str(
+            r#"<span class="font-semibold text-accent">Tool Call: </span>"#,
+        );
+        complete_html
+            .push_str(&html_escape::encode_text(&tool_call.function.name));
+        complete_html.push_str("</div>");
+        complete_html
+            .push_str(r#"<div class="mockup-code text-xs"><pre><code>"#);
+        if let Ok(parsed) = serde_json::from_str::<serde_json::Value>(
+            &tool_call.function.arguments,
+        ) {
+            if let Ok(pretty) = serde_json::to_string_pretty(&parsed) {
+                complete_html.push_str(&html_escape::encode_text(&pretty));
+            } else {
+                complete_html.push_str(&html_escape::encode_text(
+                    &tool_call.function.arguments,
+                ));
+            }
+        } else {
+            complete_html.push_str(&html_escape::encode_text(
+                &tool_call.function.arguments,
+            ));
+        }
+        complete_html.push_str("</code></pre></div>");
+        complete_html.push_str("</div></div>");
+    }
+
+    // Add images
+    for image_url in &acc.images {
+        complete_html.push_str(r#"<div class="mb-4"><img src=""#);
+        complete_html
+            .push_str(&html_escape::encode_quoted_attribute(image_url));
+        complete_html.push_str(r#"" alt="Generated image" class="rounded-lg max-w-md shadow-lg" /></div>"#);
+    }
+
+    // Add sources
+    if !acc.sources.is_empty() {
+        complete_html
+            .push_str(r#"<div class="divider mt-4">Sources</div>"#);
+        complete_html.push_str(r#"<div class="flex flex-col gap-2">"#);
+        for (idx, source) in acc.sources.iter().enumerate() {
+            complete_html
+                .push_str(r#"<div class="card bg-base-200 compact">"#);
+            complete_html.push_str(r#"<div class="card-body p-3">"#);
+            complete_html
+                .push_str(r#"<div class="flex items-start gap-2">"#);
+            complete_html.push_str(&format!(
+                r#"<span class="badge badge-primary badge-sm">{}</span>"#,
+                idx + 1
+            ));
+            complete_html.push_str(r#"<div class="flex-1">"#);
+            if let Some(title) = &source.title {
+                complete_html
+                    .push_str(r#"<h4 class="font-semibold text-sm">"#);
+                complete_html.push_str(&html_escape::encode_text(title));
+                complete_html.push_str("</h4>");
+            }
+            if let Some(snippet) = &source.snippet {
+                complete_html
+                    .push_str(r#"<p class="text-xs opacity-75 mt-1">"#);
+                complete_html.push_str(&html_escape::encode_text(snippet));
+                complete_html.push_str("</p>");
+            }
+            if let Some(url) = &source.url {
+                complete_html.push_str(r#"<a href=""#);
+                complete_html
+                    .push_str(&html_escape::encode_quoted_attribute(url));
+                complete_html.push_str(r#"" target="_blank" class="link link-primary text-xs mt-1">View source →</a>"#);
+            }
+            complete_html.push_str("</div></div></div></div>");
+        }
+        complete_html.push_str("</div>");
+    }
+
+    // Add usage statistics
+    if let Some(usage) = &acc.usage {
+        complete_html.push_str(
+            r#"<div class="stats stats-horizontal shadow mt-4 text-xs">"#,
+        );
+        complete_html.push_str(r#"<div class="stat py-2 px-4"><div class="stat-title text-xs">Prompt</div><div class="stat-value text-sm">"#);
+        complete_html.push_str(&usage.prompt_tokens.to_string());
+        complete_html
+            .push_str(r#"</div><div class="stat-desc">tokens</div></div>"#);
+        complete_html.push_str(r#"<div class="stat py-2 px-4"><div class="stat-title text-xs">Completion</div><div class="stat-value text-sm">"#);
+        complete_html.push_str(&usage.completion_tokens.to_string());
+        complete_html
+            .push_str(r#"</div><div class="stat-desc">tokens</div></div>"#);
+        complete_html.push_str(r#"<div class="stat py-2 px-4"><div class="stat-title text-xs">Total</div><div class="stat-value text-sm">"#);
+        complete_html.push_str(&usage.total_tokens.to_string());
+        complete_html
+            .push_str(r#"</div><div class="stat-desc">tokens</div></div>"#);
+        complete_html.push_str("</div>");
+    }
+
+    complete_html
+}
+
+#[derive(Deserialize, Debug)]
+pub struct GenerateParams {
+    // Pair whose cut-off response should be continued
+    #[serde(rename = "continue")]
+    continue_from: Option<i64>,
+}
+
+pub async fn chat_generate(
+    Extension(current_user): Extension<Option<User>>,
+    Path(chat_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<GenerateParams>,
+) -> Result<Sse<impl tokio_stream::Stream<Item = Result<Event, axum::Error>>>, ChatError> {
+    let (receiver, lat_message_id, partial) =
+        start_generation(&state, current_user, chat_id, params.continue_from).await?;
+
+    let receiver_stream = ReceiverStream::new(receiver);
+
+    let initial_state = (receiver_stream, partial);
+    let event_stream = stream::unfold(initial_state, move |(mut rc, mut acc)| {
+        let state = Arc::clone(&state);
+        async move {
+            match rc.next().await {
+                Some(Ok(GenerationEvent::End(_))) => {
+                    let complete_html =
+                        complete_generation(&state, chat_id, lat_message_id, &acc).await;
+                    let close_event = Event::default().data(complete_html).event("close");
+                    Some((Ok(close_event), (rc, acc)))
+                }
+                Some(Ok(event)) => {
+                    let data = acc.apply(event);
+                    Some((Ok(Event::default().data(data)), (rc, acc)))
+                }
+                Some(Err(e)) => Some((Err(axum::Error::new(e)), (rc, acc))),
+                None => None,
+            }
+        }
+    });
+
+    Ok(Sse::new(event_stream))
+}
+
+// Frames sent to WebSocket clients. Events carry the same HTML the SSE path
+// would send so both transports render identically.
+#[derive(Serialize)]
+#[serde(tag = "frame", rename_all = "snake_case")]
+enum WsServerFrame {
+    Event { event: GenerationEvent, html: String },
+    Error { message: String },
+    Cancelled,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum WsClientMessage {
+    Cancel,
+}
+
+pub async fn chat_generate_ws(
+    ws: WebSocketUpgrade,
+    headers: HeaderMap,
+    Extension(current_user): Extension<Option<User>>,
+    Path(chat_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<GenerateParams>,
+) -> Result<Response, ChatError> {
+    let origin = headers.get(header::ORIGIN).and_then(|origin| origin.to_str().ok());
+    if !same_origin(origin, &super::auth::app_url()) {
+        return Err(ChatError::Forbidden);
+    }
+
+    let (receiver, lat_message_id, partial) =
+        start_generation(&state, current_user, chat_id, params.continue_from).await?;
+
+    Ok(ws.on_upgrade(move |socket| {
+        stream_generation_ws(socket, state, receiver, chat_id, lat_message_id, partial)
+    }))
+}
+
+// A handshake carries the session cookie but no CSRF token, so only pages
+// served from APP_URL may open the socket. Browsers always send the Origin
+fn same_origin(origin: Option<&str>, app_url: &str) -> bool {
+    let (Some(origin), Ok(app_url)) = (origin, reqwest::Url::parse(app_url)) else {
+        return false;
+    };
+    reqwest::Url::parse(origin).is_ok_and(|origin| origin.origin() == app_url.origin())
+}
+
+async fn send_ws_frame(socket: &mut WebSocket, frame: &WsServerFrame) -> bool {
+    let Ok(json) = serde_json::to_string(frame) else {
+        return false;
+    };
+    socket.send(WsMessage::Text(json.into())).await.is_ok()
+}
+
+async fn stream_generation_ws(
+    mut socket: WebSocket,
+    state: Arc<AppState>,
+    mut receiver: mpsc::Receiver<Result<GenerationEvent, axum::Error>>,
+    chat_id: i64,
+    pair_id: i64,
+    mut acc: MessageAccumulator,
+) {
+    loop {
+        tokio::select! {
+            event = receiver.recv() => match event {
+                Some(Ok(GenerationEvent::End(text))) => {
+                    let html = complete_generation(&state, chat_id, pair_id, &acc).await;
+                    let frame = WsServerFrame::Event { event: GenerationEvent::End(text), html };
+                    send_ws_frame(&mut socket, &frame).await;
+                    break;
+                }
+                Some(Ok(event)) => {
+                    let html = acc.apply(event.clone());
+                    if !send_ws_frame(&mut socket, &WsServerFrame::Event { event, html }).await {
+                        break;
+                    }
+                }
+                Some(Err(e)) => {
+                    let frame = WsServerFrame::Error { message: e.to_string() };
+                    if !send_ws_frame(&mut socket, &frame).await {
+                        break;
+                    }
+                }
+                None => break,
+            },
+            message = socket.recv() => match message {
+                Some(Ok(WsMessage::Text(text))) => {
+                    if let Ok(WsClientMessage::Cancel) = serde_json::from_str(&text) {
+                        // Dropping the receiver makes the generation task stop
+                        // at its next send, same as an SSE disconnect
+                        send_ws_frame(&mut socket, &WsServerFrame::Cancelled).await;
+                        break;
+                    }
+                }
+                Some(Ok(WsMessage::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+
+    let _ = socket.send(WsMessage::Close(None)).await;
+}
+
+// Follow a chat as an ACP session: every generation event for the chat is
+// re-sent as a `session/update` JSON-RPC notification, next to the web stream
+pub async fn chat_session_updates(
+    Extension(current_user): Extension<Option<User>>,
+    Path(chat_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Sse<impl tokio_stream::Stream<Item = Result<Event, axum::Error>>>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+    authorize_chat(&state, current_user.id, chat_id, ChatRole::Viewer).await?;
+
+    let receiver = state.generation_hub.subscribe(chat_id);
+    let session_id = chat_id.to_string();
+
+    let event_stream = stream::unfold(receiver, move |mut rx| {
+        let session_id = session_id.clone();
+        async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => {
+                        if let Some(notification) = acp::session_update(&session_id, &event) {
+                            let event = Event::default()
+                                .event("session/update")
+                                .data(notification.to_string());
+                            return Some((Ok(event), rx));
+                        }
+                    }
+                    // A slow subscriber misses chunks rather than stalling generation
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(skipped)) => {
+                        tracing::warn!("Session feed for chat {} skipped {} events", session_id, skipped);
+                    }
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        }
+    });
+
+    Ok(Sse::new(event_stream))
+}
+
+// Let the other members of a shared chat know someone is composing a message
+pub async fn chat_typing(
+    Extension(current_user): Extension<Option<User>>,
+    Path(chat_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+    authorize_chat(&state, current_user.id, chat_id, ChatRole::Editor).await?;
+
+    state.chat_rooms.publish(
+        chat_id,
+        RoomEvent::Typing {
+            user_id: current_user.id,
+            email: current_user.email,
+        },
+    );
+    Ok(Html(String::new()))
+}
+
+// Live feed for a shared chat: who is viewing it, who is typing, messages sent
+// by other members and the answers streaming in for them
+pub async fn chat_live_updates(
+    Extension(current_user): Extension<Option<User>>,
+    Path(chat_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Sse<impl tokio_stream::Stream<Item = Result<Event, axum::Error>>>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+    authorize_chat(&state, current_user.id, chat_id, ChatRole::Viewer).await?;
+
+    let (room, guard) = state.chat_rooms.join(chat_id, current_user.id, &current_user.email);
+    let generation = state.generation_hub.subscribe(chat_id);
+    let initial = (room, generation, MessageAccumulator::default(), guard);
+
+    let event_stream = stream::unfold(initial, move |(mut room, mut generation, mut acc, guard)| {
+        let state = state.clone();
+        async move {
+            use tokio::sync::broadcast::error::RecvError;
+            loop {
+                let event = tokio::select! {
+                    event = room.recv() => match event {
+                        Ok(RoomEvent::Presence(present)) => {
+                            let mut context = Context::new();
+                            context.insert("present", &present);
+                            match state.tera.render("htmx_updates/chat_presence.html", &context) {
+                                Ok(html) => Event::default().event("presence").data(html),
+                                Err(e) => {
+                                    tracing::error!("Failed to render presence: {}", e);
+                                    continue;
+                                }
+                            }
+                        }
+                        Ok(RoomEvent::Typing { user_id, email }) => Event::default().event("typing").data(
+                            serde_json::json!({ "user_id": user_id, "text": format!("{} is typing…", email) })
+                                .to_string(),
+                        ),
+                        Ok(RoomEvent::Message { author_id, html }) => Event::default().event("message").data(
+                            serde_json::json!({ "author_id": author_id, "html": html }).to_string(),
+                        ),
+                        Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => return None,
+                    },
+                    event = generation.recv() => match event {
+                        Ok(GenerationEvent::End(_)) => {
+                            acc = MessageAccumulator::default();
+                            Event::default().event("generated").data("")
+                        }
+                        Ok(event) => {
+                            acc.apply(event);
+                            Event::default().event("generating").data(render_message_html(&acc))
+                        }
+                        // Missed chunks show up once the page is reloaded
+                        Err(RecvError::Lagged(skipped)) => {
+                            tracing::warn!("Live feed for chat {} skipped {} events", chat_id, skipped);
+                            continue;
+                        }
+                        Err(RecvError::Closed) => return None,
+                    },
+                };
+                return Some((Ok(event), (room, generation, acc, guard)));
+            }
+        }
+    });
+
+    Ok(Sse::new(event_stream).keep_alive(KeepAlive::default()))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct EditMessage {
+    message: String,
+}
+
+// Replace an earlier human message and regenerate the conversation from there;
+// the chat page streams a response for the new pair once reloaded
+pub async fn edit_message(
+    Path((chat_id, pair_id)): Path<(i64, i64)>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Form(edit): Form<EditMessage>,
+) -> Result<Response<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    if edit.message.trim().is_empty() {
+        return Err(ChatError::InvalidMessage);
+    }
+
+    authorize_chat(&state, current_user.id, chat_id, ChatRole::Editor).await?;
+
+    state
+        .chat_repo
+        .edit_human_message(chat_id, pair_id, &edit.message)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to edit message: {}", e)))?
+        .ok_or(ChatError::ChatNotFound)?;
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("HX-Redirect", format!("/chat/{}", chat_id).as_str())
+        .body("".to_string())
+        .map_err(|e| ChatError::ServerError(format!("Failed to build response: {}", e)))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct FeedbackForm {
+    rating: i64, // 1, -1, or 0 to clear
+}
+
+pub async fn message_feedback(
+    Path((chat_id, pair_id)): Path<(i64, i64)>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Form(form): Form<FeedbackForm>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    let rating = match form.rating {
+        0 => None,
+        1 | -1 => Some(form.rating),
+        _ => return Err(ChatError::InvalidMessage),
+    };
+
+    let rows_affected = state
+        .chat_repo
+        .set_feedback(current_user.id, chat_id, pair_id, rating)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to save feedback: {}", e)))?;
+
+    if rating.is_some() && rows_affected == 0 {
+        return Err(ChatError::ChatNotFound);
+    }
+
+    let mut context = Context::new();
+    context.insert("chat_id", &chat_id);
+    context.insert("pair_id", &pair_id);
+    context.insert("feedback", &rating);
+    let update = state
+        .tera
+        .render("htmx_updates/message_feedback.html", &context)
+        .map_err(|e| ChatError::ServerError(format!("Failed to render feedback: {}", e)))?;
+
+    Ok(Html(update))
+}
+
+// Swap a cut-off response for a streaming one that picks up where it stopped
+pub async fn continue_message(
+    Path((chat_id, pair_id)): Path<(i64, i64)>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+    authorize_chat(&state, current_user.id, chat_id, ChatRole::Editor).await?;
+
+    let mut context = Context::new();
+    context.insert("chat_id", &chat_id);
+    context.insert("pair_id", &pair_id);
+    let update = state
+        .tera
+        .render("htmx_updates/continue_message.html", &context)
+        .map_err(|e| ChatError::ServerError(format!("Failed to render message: {}", e)))?;
+
+    Ok(Html(update))
+}
+
+pub async fn toggle_bookmark(
+    Path((chat_id, pair_id)): Path<(i64, i64)>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    let bookmarked = state
+        .chat_repo
+        .toggle_bookmark(current_user.id, chat_id, pair_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to save bookmark: {}", e)))?
+        .ok_or(ChatError::ChatNotFound)?;
+
+    let mut context = Context::new();
+    context.insert("chat_id", &chat_id);
+    context.insert("pair_id", &pair_id);
+    context.insert("bookmarked", &bookmarked);
+    let update = state
+        .tera
+        .render("htmx_updates/bookmark_button.html", &context)
+        .map_err(|e| ChatError::ServerError(format!("Failed to render bookmark: {}", e)))?;
+
+    Ok(Html(update))
+}
+
+#[derive(Serialize)]
+struct ParsedBookmark {
+    bookmark: Bookmark,
+    ai_message_html: String,
+}
+
+pub async fn bookmarks(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    let bookmarks: Vec<ParsedBookmark> = state
+        .chat_repo
+        .get_bookmarks(current_user.id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to retrieve bookmarks: {}", e)))?
+        .into_iter()
+        .map(|bookmark| ParsedBookmark {
+            ai_message_html: markdown_to_html(&bookmark.ai_message),
+            bookmark,
+        })
+        .collect();
+
+    let mut context = Context::new();
+    context.insert("bookmarks", &bookmarks);
+    let saved = state
+        .tera
+        .render("views/bookmarks.html", &context)
+        .map_err(|e| ChatError::ServerError(format!("Failed to render bookmarks: {}", e)))?;
+
+    let mut context = Context::new();
+    context.insert("view", &saved);
+    context.insert("current_user", &current_user);
+    let rendered = state
+        .tera
+        .render("views/main.html", &context)
+        .map_err(|e| ChatError::ServerError(format!("Failed to render page: {}", e)))?;
+
+    Ok(Html(rendered))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ScheduleForm {
+    message: String,
+    run_at: String, // `datetime-local` value in the browser's time zone
+    #[serde(default)]
+    tz_offset: i64, // minutes, as returned by `Date.getTimezoneOffset()`
+}
+
+pub async fn schedule_message(
+    Path(chat_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Form(form): Form<ScheduleForm>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    let message = form.message.trim();
+    if message.is_empty() {
+        return Err(ChatError::InvalidMessage);
+    }
+    let local = chrono::NaiveDateTime::parse_from_str(&form.run_at, "%Y-%m-%dT%H:%M")
+        .map_err(|_| ChatError::InvalidMessage)?;
+    let run_at = (local + chrono::Duration::minutes(form.tz_offset)).and_utc();
+    if run_at <= chrono::Utc::now() {
+        return Err(ChatError::InvalidMessage);
+    }
+
+    state
+        .chat_repo
+        .schedule_message(current_user.id, chat_id, message, run_at)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to schedule message: {}", e)))?
+        .ok_or(ChatError::ChatNotFound)?;
+
+    render_command_notice(
+        &state,
+        &format!("Scheduled for {}.", local.format("%Y-%m-%d %H:%M")),
+    )
+}
+
+pub async fn scheduled_messages(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    let scheduled = state
+        .chat_repo
+        .get_scheduled_messages(current_user.id)
+        .await
+        .map_err(|e| {
+            ChatError::DatabaseError(format!("Failed to retrieve scheduled messages: {}", e))
+        })?;
+
+    let mut context = Context::new();
+    context.insert("scheduled_messages", &scheduled);
+    let page = state
+        .tera
+        .render("views/scheduled.html", &context)
+        .map_err(|e| ChatError::ServerError(format!("Failed to render scheduled messages: {}", e)))?;
+
+    let mut context = Context::new();
+    context.insert("view", &page);
+    context.insert("current_user", &current_user);
+    let rendered = state
+        .tera
+        .render("views/main.html", &context)
+        .map_err(|e| ChatError::ServerError(format!("Failed to render page: {}", e)))?;
+
+    Ok(Html(rendered))
+}
+
+pub async fn cancel_scheduled_message(
+    Path(scheduled_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    let rows_affected = state
+        .chat_repo
+        .cancel_scheduled_message(scheduled_id, current_user.id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to cancel scheduled message: {}", e)))?;
+
+    if rows_affected == 0 {
+        return Err(ChatError::ChatNotFound);
+    }
+
+    Ok(Html(r#"<span class="badge badge-ghost">cancelled</span>"#.to_string()))
+}
+
+// The sidebar's quota widget, empty without limits
+pub async fn quota_status(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    let status = quota::status(&state, current_user.id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to check quota: {}", e)))?;
+
+    let mut context = Context::new();
+    context.insert("status", &status);
+    context.insert("exceeded", &status.exceeded());
+    state
+        .tera
+        .render("htmx_updates/quota_status.html", &context)
+        .map(Html)
+        .map_err(|e| ChatError::ServerError(format!("Failed to render template: {}", e)))
+}
+
+// Polled by the sidebar to tell the user about finished runs
+pub async fn scheduled_notifications(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    let notifications = state
+        .chat_repo
+        .get_scheduled_notifications(current_user.id)
+        .await
+        .map_err(|e| {
+            ChatError::DatabaseError(format!("Failed to retrieve scheduled messages: {}", e))
+        })?;
+
+    let mut context = Context::new();
+    context.insert("notifications", &notifications);
+    state
+        .tera
+        .render("htmx_updates/scheduled_notifications.html", &context)
+        .map(Html)
+        .map_err(|e| ChatError::ServerError(format!("Failed to render template: {}", e)))
+}
+
+pub async fn dismiss_scheduled_notification(
+    Path(scheduled_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    state
+        .chat_repo
+        .mark_scheduled_messages_seen(current_user.id, Some(scheduled_id), None)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to update scheduled messages: {}", e)))?;
+
+    Ok(Html(String::new()))
+}
+
+// Background task spawned at startup: sends due prompts and stores the
+// answers as if the user had been there to watch them stream
+pub async fn run_scheduled_messages(state: Arc<AppState>) {
+    if let Err(e) = state.chat_repo.fail_interrupted_scheduled_messages().await {
+        tracing::error!("Failed to clean up scheduled messages: {}", e);
+    }
+
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(30));
+    loop {
+        interval.tick().await;
+
+        let due = match state.chat_repo.claim_due_scheduled_messages().await {
+            Ok(due) => due,
+            Err(e) => {
+                tracing::error!("Failed to load scheduled messages: {}", e);
+                continue;
+            }
+        };
+
+        for scheduled in due {
+            let state = state.clone();
+            tokio::spawn(async move {
+                let error = send_scheduled_message(&state, &scheduled)
+                    .await
+                    .err()
+                    .map(|e| e.to_string());
+                if let Some(error) = &error {
+                    tracing::warn!("Scheduled message {} failed: {}", scheduled.id, error);
+                }
+                if let Err(e) = state
+                    .chat_repo
+                    .finish_scheduled_message(scheduled.id, error.as_deref())
+                    .await
+                {
+                    tracing::error!("Failed to update scheduled message {}: {}", scheduled.id, e);
+                }
+            });
+        }
+    }
+}
+
+async fn send_scheduled_message(
+    state: &Arc<AppState>,
+    scheduled: &ScheduledMessage,
+) -> Result<(), ChatError> {
+    let user = find_user(state, scheduled.user_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to load user: {}", e)))?;
+
+    state
+        .chat_repo
+        .add_message_block(scheduled.chat_id, &scheduled.message)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to add message: {}", e)))?;
+
+    let (receiver, pair_id, acc) =
+        start_generation(state, Some(user), scheduled.chat_id, None).await?;
+    finish_generation(state, receiver, scheduled.chat_id, pair_id, acc).await
+}
+
+// Generate the response for the chat's latest message without a client
+// attached. Returns the pair the response will be stored on
+pub(super) async fn start_background_generation(
+    state: &Arc<AppState>,
+    current_user: Option<User>,
+    chat_id: i64,
+) -> Result<i64, ChatError> {
+    let (receiver, pair_id, acc) = start_generation(state, current_user, chat_id, None).await?;
+
+    let state = state.clone();
+    tokio::spawn(async move {
+        if let Err(e) = finish_generation(&state, receiver, chat_id, pair_id, acc).await {
+            tracing::warn!("Background generation for chat {} failed: {}", chat_id, e);
+        }
+    });
+    Ok(pair_id)
+}
+
+// Drain a generation nobody is watching and save the response
+async fn finish_generation(
+    state: &AppState,
+    mut receiver: mpsc::Receiver<Result<GenerationEvent, axum::Error>>,
+    chat_id: i64,
+    pair_id: i64,
+    mut acc: MessageAccumulator,
+) -> Result<(), ChatError> {
+    // Nobody is around to confirm tool calls, so don't wait forever on one
+    let generation = async {
+        while let Some(event) = receiver.recv().await {
+            match event {
+                Ok(GenerationEvent::End(_)) => return Ok(()),
+                Ok(event) => {
+                    acc.apply(event);
+                }
+                Err(e) => return Err(ChatError::NetworkError(e.to_string())),
+            }
+        }
+        Err(ChatError::NetworkError("Generation ended unexpectedly".to_string()))
+    };
+    tokio::time::timeout(std::time::Duration::from_secs(600), generation)
+        .await
+        .map_err(|_| ChatError::NetworkError("Generation timed out".to_string()))??;
+
+    complete_generation(state, chat_id, pair_id, &acc).await;
+    Ok(())
+}
+
+// Raw generation events for API clients. The response is saved when `End`
+// comes through, as with the web stream
+pub(super) async fn stream_generation_events(
+    state: &Arc<AppState>,
+    current_user: Option<User>,
+    chat_id: i64,
+) -> Result<(impl tokio_stream::Stream<Item = GenerationEvent>, i64), ChatError> {
+    let (receiver, pair_id, partial) = start_generation(state, current_user, chat_id, None).await?;
+
+    let state = state.clone();
+    let initial_state = (ReceiverStream::new(receiver), partial);
+    let events = stream::unfold(initial_state, move |(mut rc, mut acc)| {
+        let state = Arc::clone(&state);
+        async move {
+            match rc.next().await {
+                Some(Ok(GenerationEvent::End(reason))) => {
+                    complete_generation(&state, chat_id, pair_id, &acc).await;
+                    Some((GenerationEvent::End(reason), (rc, acc)))
+                }
+                Some(Ok(event)) => {
+                    acc.apply(event.clone());
+                    Some((event, (rc, acc)))
+                }
+                Some(Err(e)) => {
+                    tracing::error!("Generation for chat {} failed: {}", chat_id, e);
+                    None
+                }
+                None => None,
+            }
+        }
+    });
+
+    Ok((events, pair_id))
+}
+
+// Show another version of a message block; the branch that followed that
+// version comes back with it
+pub async fn select_version(
+    Path((chat_id, block_id, rank)): Path<(i64, i64, i64)>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Response<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    let rows_affected = state
+        .chat_repo
+        .select_block_version(chat_id, current_user.id, block_id, rank)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to select version: {}", e)))?;
+
+    if rows_affected == 0 {
+        return Err(ChatError::ChatNotFound);
+    }
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("HX-Redirect", format!("/chat/{}", chat_id).as_str())
+        .body("".to_string())
+        .map_err(|e| ChatError::ServerError(format!("Failed to build response: {}", e)))
+}
+
+// Deleting an exchange can bring back an earlier version of it, so the page is
+// reloaded rather than patched
+pub async fn delete_message(
+    Path((chat_id, pair_id)): Path<(i64, i64)>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Response<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    let rows_affected = state
+        .chat_repo
+        .delete_message_pair(chat_id, current_user.id, pair_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to delete message: {}", e)))?;
+
+    if rows_affected == 0 {
+        return Err(ChatError::ChatNotFound);
+    }
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("HX-Redirect", format!("/chat/{}", chat_id).as_str())
+        .body("".to_string())
+        .map_err(|e| ChatError::ServerError(format!("Failed to build response: {}", e)))
+}
+
+pub async fn duplicate_chat(
+    Path(chat_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Response<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    let copy_id = state
+        .chat_repo
+        .duplicate_chat(chat_id, current_user.id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to duplicate chat: {}", e)))?
+        .ok_or(ChatError::ChatNotFound)?;
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("HX-Redirect", format!("/chat/{}", copy_id).as_str())
+        .body("".to_string())
+        .map_err(|e| ChatError::ServerError(format!("Failed to build response: {}", e)))
+}
+
+// Who the user's messages are shown from
+fn insert_profile(context: &mut Context, user: &User) {
+    context.insert("avatar", &user.avatar);
+    context.insert("user_name", user.name());
+}
+
+// Context for the settings drawer; the user's defaults are shown as
+// placeholders for the overrides left empty
+fn insert_chat_settings(context: &mut Context, settings: &ChatSettings, user: &User) {
+    context.insert("chat_settings", settings);
+    context.insert("stop_sequences", &settings.stop_sequences());
+    context.insert("default_temperature", &user.temperature.unwrap_or(0.7));
+    context.insert("default_top_p", &user.top_p.unwrap_or(1.0));
+    context.insert("default_max_tokens", &user.max_tokens.unwrap_or(2000));
+}
+
+// Empty means "use the default"
+fn parse_override<T: std::str::FromStr>(value: &str) -> Result<Option<T>, ChatError> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(None);
+    }
+    value.parse().map(Some).map_err(|_| ChatError::InvalidMessage)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ChatSettingsForm {
+    stop_sequences: String, // one per line
+    temperature: String,
+    top_p: String,
+    max_tokens: String,
+}
+
+async fn insert_chat_members(
+    state: &AppState,
+    context: &mut Context,
+    chat_id: i64,
+) -> Result<(), ChatError> {
+    let members = state
+        .chat_repo
+        .get_chat_members(chat_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to retrieve members: {}", e)))?;
+    let invitations = state
+        .chat_repo
+        .get_chat_invitations(chat_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to retrieve invitations: {}", e)))?;
+
+    context.insert("members", &members);
+    context.insert("invitations", &invitations);
+    Ok(())
+}
+
+async fn render_chat_members(state: &AppState, chat_id: i64) -> Result<Html<String>, ChatError> {
+    let mut context = Context::new();
+    context.insert("chat_id", &chat_id);
+    context.insert("members_open", &true);
+    insert_chat_members(state, &mut context, chat_id).await?;
+    state
+        .tera
+        .render("htmx_updates/chat_members.html", &context)
+        .map(Html)
+        .map_err(|e| ChatError::ServerError(format!("Failed to render members: {}", e)))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct InviteForm {
+    email: String,
+    role: String,
+}
+
+pub async fn invite_chat_member(
+    Path(chat_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Form(form): Form<InviteForm>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+    authorize_chat(&state, current_user.id, chat_id, ChatRole::Owner).await?;
+
+    let email = form.email.trim();
+    let role = match ChatRole::parse(&form.role) {
+        Some(role @ (ChatRole::Editor | ChatRole::Viewer)) => role,
+        _ => return Err(ChatError::InvalidMessage),
+    };
+    if !email.contains('@') {
+        return Err(ChatError::InvalidMessage);
+    }
+
+    let rows_affected = state
+        .chat_repo
+        .invite_to_chat(chat_id, email, role)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to invite member: {}", e)))?;
+
+    // The owner's own email
+    if rows_affected == 0 {
+        return Err(ChatError::InvalidMessage);
+    }
+
+    render_chat_members(&state, chat_id).await
+}
+
+pub async fn remove_chat_member(
+    Path((chat_id, user_id)): Path<(i64, i64)>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+    authorize_chat(&state, current_user.id, chat_id, ChatRole::Owner).await?;
+
+    state
+        .chat_repo
+        .remove_chat_member(chat_id, user_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to remove member: {}", e)))?;
+
+    render_chat_members(&state, chat_id).await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct InvitationParams {
+    email: String,
+}
+
+pub async fn cancel_chat_invitation(
+    Path(chat_id): Path<i64>,
+    Query(params): Query<InvitationParams>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+    authorize_chat(&state, current_user.id, chat_id, ChatRole::Owner).await?;
+
+    state
+        .chat_repo
+        .cancel_chat_invitation(chat_id, &params.email)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to cancel invitation: {}", e)))?;
+
+    render_chat_members(&state, chat_id).await
+}
+
+pub async fn save_chat_settings(
+    Path(chat_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Form(form): Form<ChatSettingsForm>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    let stop_sequences =
+        parse_stop_sequences(&form.stop_sequences).ok_or(ChatError::InvalidMessage)?;
+
+    let temperature = parse_override::<f64>(&form.temperature)?;
+    let top_p = parse_override::<f64>(&form.top_p)?;
+    let max_tokens = parse_override::<i64>(&form.max_tokens)?;
+    if temperature.is_some_and(|t| !(0.0..=2.0).contains(&t))
+        || top_p.is_some_and(|p| !(0.0..=1.0).contains(&p))
+        || max_tokens.is_some_and(|n| n < 1)
+    {
+        return Err(ChatError::InvalidMessage);
+    }
+
+    let settings = ChatSettings {
+        chat_id,
+        stop_sequences: if stop_sequences.is_empty() {
+            None
+        } else {
+            serde_json::to_string(&stop_sequences).ok()
+        },
+        temperature,
+        top_p,
+        max_tokens,
+        ..Default::default()
+    };
+    let rows_affected = state
+        .chat_repo
+        .save_chat_settings(current_user.id, &settings)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to save chat settings: {}", e)))?;
+
+    if rows_affected == 0 {
+        return Err(ChatError::ChatNotFound);
+    }
+
+    let mut context = Context::new();
+    context.insert("chat_id", &chat_id);
+    insert_chat_settings(&mut context, &settings, &current_user);
+    context.insert("settings_open", &true);
+    context.insert("settings_saved", &true);
+    let update = state
+        .tera
+        .render("htmx_updates/chat_settings.html", &context)
+        .map_err(|e| ChatError::ServerError(format!("Failed to render chat settings: {}", e)))?;
+
+    Ok(Html(update))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RenameChat {
+    name: String,
+}
+
+pub async fn rename_chat(
+    Path(chat_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    headers: HeaderMap,
+    Form(rename): Form<RenameChat>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    let name = rename.name.trim();
+    if name.is_empty() {
+        return Err(ChatError::InvalidMessage);
+    }
+
+    let rows_affected = state
+        .chat_repo
+        .rename_chat(chat_id, current_user.id, name)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to rename chat: {}", e)))?;
+
+    if rows_affected == 0 {
+        return Err(ChatError::ChatNotFound);
+    }
+
+    let chat = state
+        .chat_repo
+        .get_chat(chat_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to retrieve chat: {}", e)))?
+        .ok_or(ChatError::ChatNotFound)?;
+
+    let mut context = Context::new();
+    context.insert("chat", &chat);
+    context.insert("active", &is_current_chat(&headers, chat_id));
+    let update = state
+        .tera
+        .render("htmx_updates/chat_list_item.html", &context)
+        .map_err(|e| ChatError::ServerError(format!("Failed to render chat item: {}", e)))?;
+
+    Ok(Html(update))
+}
+
+// Whether an HTMX request comes from the page of the given chat, so sidebar
+// updates keep it highlighted
+fn is_current_chat(headers: &HeaderMap, chat_id: i64) -> bool {
+    headers
+        .get("HX-Current-URL")
+        .and_then(|url| url.to_str().ok())
+        .is_some_and(|url| url.trim_end_matches('/').ends_with(&format!("/chat/{}", chat_id)))
+}
+
+pub async fn toggle_pin_chat(
+    Path(chat_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    headers: HeaderMap,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    let rows_affected = state
+        .chat_repo
+        .toggle_pin(chat_id, current_user.id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to pin chat: {}", e)))?;
+
+    if rows_affected == 0 {
+        return Err(ChatError::ChatNotFound);
+    }
+
+    // Pinning moves the chat, so the whole list is re-rendered
+    render_chat_list(&state, &headers, &current_user).await
+}
+
+// Re-render the sidebar list for an HTMX request, keeping the page's tag
+// filter, page and highlighted chat
+async fn render_chat_list(
+    state: &AppState,
+    headers: &HeaderMap,
+    user: &User,
+) -> Result<Html<String>, ChatError> {
+    let current_url = headers
+        .get("HX-Current-URL")
+        .and_then(|url| url.to_str().ok())
+        .and_then(|url| reqwest::Url::parse(url).ok());
+    let tag = current_url.as_ref().and_then(|url| {
+        url.query_pairs()
+            .find(|(key, _)| key == "tag")
+            .map(|(_, value)| value.into_owned())
+    });
+    let cursor = current_url.as_ref().and_then(|url| {
+        url.query_pairs()
+            .find(|(key, _)| key == "cursor")
+            .and_then(|(_, value)| value.parse().ok())
+    });
+    let current_chat_id = current_url.as_ref().and_then(|url| {
+        url.path()
+            .trim_end_matches('/')
+            .strip_prefix("/chat/")?
+            .parse::<i64>()
+            .ok()
+    });
+
+    let mut context = Context::new();
+    insert_sidebar(state, &mut context, user, tag.as_deref(), cursor).await?;
+    context.insert("chat_id", &current_chat_id);
+
+    let update = state
+        .tera
+        .render("htmx_updates/chat_list.html", &context)
+        .map_err(|e| ChatError::ServerError(format!("Failed to render chat list: {}", e)))?;
+
+    Ok(Html(update))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ReorderChats {
+    ids: Vec<i64>,
+}
+
+pub async fn reorder_chats(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Json(reorder): Json<ReorderChats>,
+) -> Result<StatusCode, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    state
+        .chat_repo
+        .reorder_chats(current_user.id, &reorder.ids)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to reorder chats: {}", e)))?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BulkChats {
+    ids: Vec<i64>,
+    #[serde(flatten)]
+    action: BulkChatAction,
+}
+
+pub async fn bulk_chats(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    headers: HeaderMap,
+    Json(mut bulk): Json<BulkChats>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    bulk.ids.sort_unstable();
+    bulk.ids.dedup();
+    if bulk.ids.is_empty() {
+        return Err(ChatError::InvalidMessage);
+    }
+
+    let rows_affected = state
+        .chat_repo
+        .bulk_update_chats(current_user.id, &bulk.ids, bulk.action)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to update chats: {}", e)))?;
+
+    if rows_affected == 0 {
+        return Err(ChatError::ChatNotFound);
+    }
+
+    render_chat_list(&state, &headers, &current_user).await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct FolderForm {
+    name: String,
+}
+
+pub async fn create_folder(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    headers: HeaderMap,
+    Form(folder): Form<FolderForm>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    let name = folder.name.trim();
+    if name.is_empty() {
+        return Err(ChatError::InvalidMessage);
+    }
+
+    state
+        .chat_repo
+        .create_folder(current_user.id, name)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to create folder: {}", e)))?;
+
+    render_chat_list(&state, &headers, &current_user).await
+}
+
+pub async fn rename_folder(
+    Path(folder_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    headers: HeaderMap,
+    Form(folder): Form<FolderForm>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    let name = folder.name.trim();
+    if name.is_empty() {
+        return Err(ChatError::InvalidMessage);
+    }
+
+    let rows_affected = state
+        .chat_repo
+        .rename_folder(folder_id, current_user.id, name)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to rename folder: {}", e)))?;
+
+    if rows_affected == 0 {
+        return Err(ChatError::ChatNotFound);
+    }
+
+    render_chat_list(&state, &headers, &current_user).await
+}
+
+pub async fn delete_folder(
+    Path(folder_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    headers: HeaderMap,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    let rows_affected = state
+        .chat_repo
+        .delete_folder(folder_id, current_user.id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to delete folder: {}", e)))?;
+
+    if rows_affected == 0 {
+        return Err(ChatError::ChatNotFound);
+    }
+
+    render_chat_list(&state, &headers, &current_user).await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MoveChat {
+    folder_id: String, // empty moves the chat out of its folder
+}
+
+pub async fn move_chat_to_folder(
+    Path(chat_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    headers: HeaderMap,
+    Form(move_chat): Form<MoveChat>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    let folder_id = match move_chat.folder_id.trim() {
+        "" => None,
+        id => Some(id.parse::<i64>().map_err(|_| ChatError::InvalidMessage)?),
+    };
+
+    let rows_affected = state
+        .chat_repo
+        .move_chat_to_folder(chat_id, current_user.id, folder_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to move chat: {}", e)))?;
+
+    if rows_affected == 0 {
+        return Err(ChatError::ChatNotFound);
+    }
+
+    render_chat_list(&state, &headers, &current_user).await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ChatTagForm {
+    tag: String,
+}
+
+// Tags are stored comma-separated on the chat list, so names can't contain commas
+fn is_valid_tag(tag: &str) -> bool {
+    !tag.is_empty() && tag.len() <= 50 && !tag.contains(',')
+}
+
+pub async fn add_chat_tag(
+    Path(chat_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    headers: HeaderMap,
+    Form(form): Form<ChatTagForm>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    let tag = form.tag.trim();
+    if !is_valid_tag(tag) {
+        return Err(ChatError::InvalidMessage);
+    }
+
+    state
+        .chat_repo
+        .add_chat_tag(chat_id, current_user.id, tag)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to tag chat: {}", e)))?;
+
+    render_chat_list(&state, &headers, &current_user).await
+}
+
+pub async fn remove_chat_tag(
+    Path((chat_id, tag)): Path<(i64, String)>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    headers: HeaderMap,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    state
+        .chat_repo
+        .remove_chat_tag(chat_id, current_user.id, &tag)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to untag chat: {}", e)))?;
+
+    render_chat_list(&state, &headers, &current_user).await
+}
+
+// Reuses the chat's existing token so a shared link stays stable until revoked
+pub async fn share_chat(
+    Path(chat_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    let chat = state
+        .chat_repo
+        .get_chat(chat_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to retrieve chat: {}", e)))?
+        .filter(|chat| chat.user_id == current_user.id)
+        .ok_or(ChatError::ChatNotFound)?;
+
+    let token = match chat.share_token {
+        Some(token) => token,
+        None => {
+            let token = uuid::Uuid::new_v4().simple().to_string();
+            state
+                .chat_repo
+                .set_share_token(chat_id, current_user.id, Some(&token))
+                .await
+                .map_err(|e| ChatError::DatabaseError(format!("Failed to share chat: {}", e)))?;
+            token
+        }
+    };
+
+    render_share_link(&state, chat_id, Some(&token))
+}
+
+pub async fn unshare_chat(
+    Path(chat_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
+    let rows_affected = state
         .chat_repo
-        .add_message_block(chat_id, &message)
+        .set_share_token(chat_id, current_user.id, None)
         .await
-        .map_err(|e| ChatError::DatabaseError(format!("Failed to add message: {}", e)))?;
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to revoke share link: {}", e)))?;
 
-    let human_message_html = markdown_to_html(&message);
+    if rows_affected == 0 {
+        return Err(ChatError::ChatNotFound);
+    }
+
+    render_share_link(&state, chat_id, None)
+}
 
+fn render_share_link(
+    state: &AppState,
+    chat_id: i64,
+    share_token: Option<&str>,
+) -> Result<Html<String>, ChatError> {
     let mut context = Context::new();
-    context.insert("human_message_html", &human_message_html);
     context.insert("chat_id", &chat_id);
+    context.insert("share_token", &share_token);
     let update = state
         .tera
-        .render("htmx_updates/add_message.html", &context)
-        .unwrap();
+        .render("htmx_updates/share_link.html", &context)
+        .map_err(|e| ChatError::ServerError(format!("Failed to render share link: {}", e)))?;
 
     Ok(Html(update))
 }
 
-pub async fn chat_generate(
+// Public, read-only view of a shared chat
+pub async fn shared_chat(
+    Path(token): Path<String>,
+    State(state): State<Arc<AppState>>,
     Extension(current_user): Extension<Option<User>>,
+) -> Result<Html<String>, ChatError> {
+    let chat = state
+        .chat_repo
+        .get_chat_by_share_token(&token)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to retrieve chat: {}", e)))?
+        .ok_or(ChatError::ChatNotFound)?;
+
+    let chat_message_pairs = state
+        .chat_repo
+        .retrieve_chat(chat.id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to retrieve chat: {}", e)))?;
+
+    let attachments = state
+        .chat_repo
+        .get_chat_attachments(chat.id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to retrieve attachments: {}", e)))?;
+
+    let mut context = Context::new();
+    context.insert("chat", &chat);
+    context.insert("chat_message_pairs", &parse_message_pairs(&chat_message_pairs, &attachments));
+    let shared = state
+        .tera
+        .render("views/share.html", &context)
+        .map_err(|e| ChatError::ServerError(format!("Failed to render shared chat: {}", e)))?;
+
+    let mut context = Context::new();
+    context.insert("view", &shared);
+    context.insert("current_user", &current_user);
+    let rendered = state
+        .tera
+        .render("views/main.html", &context)
+        .map_err(|e| ChatError::ServerError(format!("Failed to render page: {}", e)))?;
+
+    Ok(Html(rendered))
+}
+
+pub async fn archive_chat(
     Path(chat_id): Path<i64>,
     State(state): State<Arc<AppState>>,
-) -> Result<Sse<impl tokio_stream::Stream<Item = Result<Event, axum::Error>>>, ChatError> {
-    let user = current_user.ok_or_else(|| ChatError::MissingUser)?;
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Html<String>, ChatError> {
+    set_chat_archived(&state, current_user, chat_id, true).await
+}
 
-    // Check if user has API key configured
-    let key = user.openai_api_key.ok_or_else(|| ChatError::EmptyAPIKey)?;
+pub async fn unarchive_chat(
+    Path(chat_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Html<String>, ChatError> {
+    set_chat_archived(&state, current_user, chat_id, false).await
+}
 
-    if key.trim().is_empty() {
-        return Err(ChatError::EmptyAPIKey);
-    }
+// Both directions remove the chat from the list the request came from
+async fn set_chat_archived(
+    state: &AppState,
+    current_user: Option<User>,
+    chat_id: i64,
+    archived: bool,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
 
-    // Retrieve chat messages
-    let chat_message_pairs = state
+    let rows_affected = state
         .chat_repo
-        .retrieve_chat(chat_id)
+        .set_archived(chat_id, current_user.id, archived)
         .await
-        .map_err(|e| ChatError::DatabaseError(format!("Failed to retrieve chat: {}", e)))?;
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to archive chat: {}", e)))?;
 
-    if chat_message_pairs.is_empty() {
+    if rows_affected == 0 {
         return Err(ChatError::ChatNotFound);
     }
 
-    // Use model from user settings, fallback to default if not set
-    let model = user
-        .model
-        .clone()
-        .unwrap_or_else(|| "Qwen/Qwen2.5-7B-Instruct".to_string());
+    Ok(Html(r#"<div class="hidden"></div>"#.to_string()))
+}
 
-    // Validate API key
-    match list_engines(&key).await {
-        Ok(_res) => {}
-        Err(e) => {
-            tracing::error!("API key validation failed: {:?}", e);
-            return Err(ChatError::InvalidAPIKey);
-        }
-    };
+pub async fn archived_chats(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
 
-    let lat_message_id = chat_message_pairs.last().unwrap().id;
+    let chats = state
+        .chat_repo
+        .get_archived_chats(current_user.id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to retrieve archived chats: {}", e)))?;
 
-    // Create a channel for sending SSE events
-    let (sender, receiver) = mpsc::channel::<Result<GenerationEvent, axum::Error>>(10);
+    let mut context = Context::new();
+    context.insert("chats", &chats);
+    let archived = state
+        .tera
+        .render("views/archived.html", &context)
+        .map_err(|e| ChatError::ServerError(format!("Failed to render archived chats: {}", e)))?;
 
-    // Spawn a task that generates SSE events and sends them into the channel
-    tokio::spawn(async move {
-        // Call your existing function to start generating events
-        if let Err(e) = generate_sse_stream(&key, &model, chat_message_pairs, sender, Some(chat_id), Some(lat_message_id)).await {
-            eprintln!("Error generating SSE stream: {:?}", e);
-        }
-    });
+    let mut context = Context::new();
+    context.insert("view", &archived);
+    context.insert("current_user", &current_user);
+    let rendered = state
+        .tera
+        .render("views/main.html", &context)
+        .map_err(|e| ChatError::ServerError(format!("Failed to render page: {}", e)))?;
 
-    // Convert the receiver into a Stream that can be used by Sse
-    // let event_stream = ReceiverStream::new(receiver);
-    let state_clone = Arc::clone(&state);
+    Ok(Html(rendered))
+}
 
-    let receiver_stream = ReceiverStream::new(receiver);
+// Chats stay in the trash for this long before `run_trash_sweep` deletes them
+pub(super) const TRASH_RETENTION_DAYS: i64 = 30;
 
-    let initial_accumulator = MessageAccumulator {
-        text: String::new(),
-        thinking: String::new(),
-        reasoning: String::new(),
-        tool_calls: Vec::new(),
-        images: Vec::new(),
-        usage: None,
-        sources: Vec::new(),
-    };
+pub async fn delete_chat(
+    Path(chat_id): Path<i64>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+    authorize_chat(&state, current_user.id, chat_id, ChatRole::Owner).await?;
 
-    let initial_state = (receiver_stream, initial_accumulator);
-    let event_stream = stream::unfold(initial_state, move |(mut rc, mut acc)| {
-        let state_clone = Arc::clone(&state_clone);
-        async move {
-            match rc.next().await {
-                Some(Ok(event)) => {
-                    match event {
-                        GenerationEvent::Text(text) => {
-                            acc.text.push_str(&text);
-                            // Render HTML without reasoning/thinking (those are handled separately)
-                            let html = render_message_text_only(&acc);
-                            Some((Ok(Event::default().data(html)), (rc, acc)))
-                        }
-                        GenerationEvent::Thinking(thinking) => {
-                            acc.thinking.push_str(&thinking);
-                            // Send thinking content as HTML
-                            let html = render_thinking_section(&acc.thinking);
-                            Some((Ok(Event::default().data(html)), (rc, acc)))
-                        }
-                        GenerationEvent::Reasoning(reasoning) => {
-                            acc.reasoning.push_str(&reasoning);
-                            // Send reasoning content as HTML
-                            let html = render_reasoning_section(&acc.reasoning);
-                            Some((Ok(Event::default().data(html)), (rc, acc)))
-                        }
-                        GenerationEvent::ThinkingUpdate(_) => {
-                            // This shouldn't happen in the current implementation
-                            // as we handle Thinking events directly
-                            Some((Ok(Event::default().data("")), (rc, acc)))
-                        }
-                        GenerationEvent::ReasoningUpdate(_) => {
-                            // This shouldn't happen in the current implementation
-                            // as we handle Reasoning events directly
-                            Some((Ok(Event::default().data("")), (rc, acc)))
-                        }
-                        GenerationEvent::ToolCall(tool_call) => {
-                            acc.tool_calls.push(tool_call);
-                            let html = render_message_html(&acc);
-                            Some((Ok(Event::default().data(html)), (rc, acc)))
-                        }
-                        GenerationEvent::Image(image_url) => {
-                            acc.images.push(image_url);
-                            let html = render_message_html(&acc);
-                            Some((Ok(Event::default().data(html)), (rc, acc)))
-                        }
-                        GenerationEvent::Usage(usage) => {
-                            acc.usage = Some(usage);
-                            let html = render_message_html(&acc);
-                            Some((Ok(Event::default().data(html)), (rc, acc)))
-                        }
-                        GenerationEvent::Sources(sources) => {
-                            acc.sources = sources;
-                            let html = render_message_html(&acc);
-                            Some((Ok(Event::default().data(html)), (rc, acc)))
-                        }
-                        GenerationEvent::ToolCallConfirmation(confirmation) => {
-                            // Send tool call confirmation request as JSON
-                            let json_data = serde_json::json!({
-                                "type": "tool_call_confirmation",
-                                "content": confirmation
-                            });
-                            Some((Ok(Event::default().data(json_data.to_string())), (rc, acc)))
-                        }
-                        GenerationEvent::End(_text) => {
-                            // Save to database with extended data
-                            let tool_calls_json = if !acc.tool_calls.is_empty() {
-                                serde_json::to_string(&acc.tool_calls).ok()
-                            } else {
-                                None
-                            };
-
-                            let images_json = if !acc.images.is_empty() {
-                                serde_json::to_string(&acc.images).ok()
-                            } else {
-                                None
-                            };
-
-                            let sources_json = if !acc.sources.is_empty() {
-                                serde_json::to_string(&acc.sources).ok()
-                            } else {
-                                None
-                            };
-
-                            state_clone
-                                .chat_repo
-                                .add_ai_message_with_extended_data(
-                                    lat_message_id,
-                                    &acc.text,
-                                    if !acc.thinking.is_empty() {
-                                        Some(&acc.thinking)
-                                    } else {
-                                        None
-                                    },
-                                    tool_calls_json.as_deref(),
-                                    images_json.as_deref(),
-                                    if !acc.reasoning.is_empty() {
-                                        Some(&acc.reasoning)
-                                    } else {
-                                        None
-                                    },
-                                    acc.usage.as_ref().map(|u| u.prompt_tokens),
-                                    acc.usage.as_ref().map(|u| u.completion_tokens),
-                                    acc.usage.as_ref().map(|u| u.total_tokens),
-                                    sources_json.as_deref(),
-                                )
-                                .await
-                                .unwrap();
-
-                            // Send final content update without the collapse sections
-                            let final_text = if !acc.text.is_empty() {
-                                markdown_to_html(&acc.text)
-                            } else {
-                                String::new()
-                            };
-
-                            // Build the complete message HTML
-                            let mut complete_html = String::new();
-
-                            // Add thinking section if exists
-                            if !acc.thinking.is_empty() {
-                                complete_html.push_str(&render_thinking_section(&acc.thinking));
-                            }
+    let rows_affected = state
+        .chat_repo
+        .delete_chat(chat_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to delete chat: {}", e)))?;
 
-                            // Add reasoning section if exists
-                            if !acc.reasoning.is_empty() {
-                                complete_html.push_str(&render_reasoning_section(&acc.reasoning));
-                            }
+    if rows_affected == 0 {
+        return Err(ChatError::ChatNotFound);
+    }
 
-                            // Add main content
-                            if !acc.text.is_empty() {
-                                complete_html.push_str(&final_text);
-                            }
+    // Replaces the chat's entry with a way to undo
+    let mut context = Context::new();
+    context.insert("chat_id", &chat_id);
+    let rendered = state
+        .tera
+        .render("htmx_updates/chat_trashed.html", &context)
+        .map_err(|e| ChatError::ServerError(format!("Failed to render trash notice: {}", e)))?;
 
-                            // Add tool calls
-                            for tool_call in &acc.tool_calls {
-                                complete_html.push_str(r#"<div class="card bg-accent/10 mb-4 border border-accent/20">"#);
-                                complete_html.push_str(r#"<div class="card-body p-4">"#);
-                                complete_html
-                                    .push_str(r#"<div class="flex items-center gap-2 mb-2">"#);
-                                complete_html.push_str(r#"<svg xmlns="http://www.w3.org/2000/svg" class="h-5 w-5 text-accent" fill="none" viewBox="0 0 24 24" stroke="currentColor"><path stroke-linecap="round" stroke-linejoin="round" stroke-width="2" d="M10.325 4.317c.426-1.756 2.924-1.756 3.35 0a1.724 1.724 0 002.573 1.066c1.543-.94 3.31.826 2.37 2.37a1.724 1.724 0 001.065 2.572c1.756.426 1.756 2.924 0 3.35a1.724 1.724 0 00-1.066 2.573c.94 1.543-.826 3.31-2.37 2.37a1.724 1.724 0 00-2.572 1.065c-.426 1.756-2.924 1.756-3.35 0a1.724 1.724 0 00-2.573-1.066c-1.543.94-3.31-.826-2.37-2.37a1.724 1.724 0 00-1.065-2.572c-1.756-.426-1.756-2.924 0-3.35a1.724 1.724 0 001.066-2.573c-.94-1.543.826-3.31 2.37-2.37.996.608 2.296.07 2.572-1.065z" /><path stroke-linecap="round" stroke-linejoin="round" stroke-width="2" d="M15 12a3 3 0 11-6 0 3 3 0 016 0z" /></svg>"#);
-                                complete_html.push_str(
-                                    r#"<span class="font-semibold text-accent">Tool Call: </span>"#,
-                                );
-                                complete_html
-                                    .push_str(&html_escape::encode_text(&tool_call.function.name));
-                                complete_html.push_str("</div>");
-                                complete_html
-                                    .push_str(r#"<div class="mockup-code text-xs"><pre><code>"#);
-                                if let Ok(parsed) = serde_json::from_str::<serde_json::Value>(
-                                    &tool_call.function.arguments,
-                                ) {
-                                    if let Ok(pretty) = serde_json::to_string_pretty(&parsed) {
-                                        complete_html.push_str(&html_escape::encode_text(&pretty));
-                                    } else {
-                                        complete_html.push_str(&html_escape::encode_text(
-                                            &tool_call.function.arguments,
-                                        ));
-                                    }
-                                } else {
-                                    complete_html.push_str(&html_escape::encode_text(
-                                        &tool_call.function.arguments,
-                                    ));
-                                }
-                                complete_html.push_str("</code></pre></div>");
-                                complete_html.push_str("</div></div>");
-                            }
+    Ok(Html(rendered))
+}
 
-                            // Add images
-                            for image_url in &acc.images {
-                                complete_html.push_str(r#"<div class="mb-4"><img src=""#);
-                                complete_html
-                                    .push_str(&html_escape::encode_quoted_attribute(image_url));
-                                complete_html.push_str(r#"" alt="Generated image" class="rounded-lg max-w-md shadow-lg" /></div>"#);
-                            }
+pub async fn trashed_chats(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
 
-                            // Add sources
-                            if !acc.sources.is_empty() {
-                                complete_html
-                                    .push_str(r#"<div class="divider mt-4">Sources</div>"#);
-                                complete_html.push_str(r#"<div class="flex flex-col gap-2">"#);
-                                for (idx, source) in acc.sources.iter().enumerate() {
-                                    complete_html
-                                        .push_str(r#"<div class="card bg-base-200 compact">"#);
-                                    complete_html.push_str(r#"<div class="card-body p-3">"#);
-                                    complete_html
-                                        .push_str(r#"<div class="flex items-start gap-2">"#);
-                                    complete_html.push_str(&format!(
-                                        r#"<span class="badge badge-primary badge-sm">{}</span>"#,
-                                        idx + 1
-                                    ));
-                                    complete_html.push_str(r#"<div class="flex-1">"#);
-                                    if let Some(title) = &source.title {
-                                        complete_html
-                                            .push_str(r#"<h4 class="font-semibold text-sm">"#);
-                                        complete_html.push_str(&html_escape::encode_text(title));
-                                        complete_html.push_str("</h4>");
-                                    }
-                                    if let Some(snippet) = &source.snippet {
-                                        complete_html
-                                            .push_str(r#"<p class="text-xs opacity-75 mt-1">"#);
-                                        complete_html.push_str(&html_escape::encode_text(snippet));
-                                        complete_html.push_str("</p>");
-                                    }
-                                    if let Some(url) = &source.url {
-                                        complete_html.push_str(r#"<a href=""#);
-                                        complete_html
-                                            .push_str(&html_escape::encode_quoted_attribute(url));
-                                        complete_html.push_str(r#"" target="_blank" class="link link-primary text-xs mt-1">View source →</a>"#);
-                                    }
-                                    complete_html.push_str("</div></div></div></div>");
-                                }
-                                complete_html.push_str("</div>");
-                            }
+    let chats = state
+        .chat_repo
+        .get_trashed_chats(current_user.id, TRASH_RETENTION_DAYS)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to retrieve trash: {}", e)))?;
+    let agents = state
+        .chat_repo
+        .get_trashed_agents(current_user.id, TRASH_RETENTION_DAYS)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to retrieve trashed agents: {}", e)))?;
 
-                            // Add usage statistics
-                            if let Some(usage) = &acc.usage {
-                                complete_html.push_str(
-                                    r#"<div class="stats stats-horizontal shadow mt-4 text-xs">"#,
-                                );
-                                complete_html.push_str(r#"<div class="stat py-2 px-4"><div class="stat-title text-xs">Prompt</div><div class="stat-value text-sm">"#);
-                                complete_html.push_str(&usage.prompt_tokens.to_string());
-                                complete_html
-                                    .push_str(r#"</div><div class="stat-desc">tokens</div></div>"#);
-                                complete_html.push_str(r#"<div class="stat py-2 px-4"><div class="stat-title text-xs">Completion</div><div class="stat-value text-sm">"#);
-                                complete_html.push_str(&usage.completion_tokens.to_string());
-                                complete_html
-                                    .push_str(r#"</div><div class="stat-desc">tokens</div></div>"#);
-                                complete_html.push_str(r#"<div class="stat py-2 px-4"><div class="stat-title text-xs">Total</div><div class="stat-value text-sm">"#);
-                                complete_html.push_str(&usage.total_tokens.to_string());
-                                complete_html
-                                    .push_str(r#"</div><div class="stat-desc">tokens</div></div>"#);
-                                complete_html.push_str("</div>");
-                            }
+    let mut context = Context::new();
+    context.insert("chats", &chats);
+    context.insert("agents", &agents);
+    context.insert("retention_days", &TRASH_RETENTION_DAYS);
+    let trash = state
+        .tera
+        .render("views/trash.html", &context)
+        .map_err(|e| ChatError::ServerError(format!("Failed to render trash: {}", e)))?;
 
-                            let close_event = Event::default().data(complete_html).event("close");
-                            Some((Ok(close_event), (rc, acc)))
-                        }
-                    }
-                }
-                Some(Err(e)) => Some((Err(axum::Error::new(e)), (rc, acc))),
-                None => None,
-            }
-        }
-    });
+    let mut context = Context::new();
+    context.insert("view", &trash);
+    context.insert("current_user", &current_user);
+    let rendered = state
+        .tera
+        .render("views/main.html", &context)
+        .map_err(|e| ChatError::ServerError(format!("Failed to render page: {}", e)))?;
 
-    Ok(Sse::new(event_stream))
+    Ok(Html(rendered))
 }
 
-pub async fn delete_chat(
+// Reloads the page, as the chat may come back to the sidebar, the archive or both
+pub async fn restore_chat(
     Path(chat_id): Path<i64>,
     State(state): State<Arc<AppState>>,
-) -> Result<Html<String>, ChatError> {
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Response, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+
     let rows_affected = state
         .chat_repo
-        .delete_chat(chat_id)
+        .restore_chat(chat_id, current_user.id)
         .await
-        .map_err(|e| ChatError::DatabaseError(format!("Failed to delete chat: {}", e)))?;
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to restore chat: {}", e)))?;
 
     if rows_affected == 0 {
         return Err(ChatError::ChatNotFound);
     }
 
-    let html = r#"<div class="hidden"></div>"#;
+    Ok(([("HX-Refresh", "true")], StatusCode::OK).into_response())
+}
+
+pub async fn run_trash_sweep(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+    loop {
+        interval.tick().await;
+
+        match state.chat_repo.purge_trashed_chats(TRASH_RETENTION_DAYS).await {
+            Ok(0) => {}
+            Ok(purged) => tracing::info!("Purged {} chats from the trash", purged),
+            Err(e) => tracing::error!("Failed to purge the trash: {}", e),
+        }
+        match state.chat_repo.purge_trashed_agents(TRASH_RETENTION_DAYS).await {
+            Ok(0) => {}
+            Ok(purged) => tracing::info!("Purged {} agents from the trash", purged),
+            Err(e) => tracing::error!("Failed to purge trashed agents: {}", e),
+        }
+        match state.chat_repo.purge_trashed_messages(TRASH_RETENTION_DAYS).await {
+            Ok(0) => {}
+            Ok(purged) => tracing::info!("Purged {} messages from the trash", purged),
+            Err(e) => tracing::error!("Failed to purge trashed messages: {}", e),
+        }
+    }
+}
 
-    Ok(Html(html.to_string()))
+#[derive(Deserialize)]
+pub struct ToolConfirmParams {
+    // Also run the tool without asking from now on
+    #[serde(default)]
+    always: bool,
 }
 
 pub async fn confirm_tool_call(
     Path((chat_id, confirmation_id)): Path<(i64, String)>,
     State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Extension(source): Extension<AuditSource>,
+    Query(params): Query<ToolConfirmParams>,
 ) -> Result<Html<String>, ChatError> {
-    // Update confirmation status in database
-    let confirmation_id_str = &confirmation_id as &str;
-    sqlx::query!(
-        "UPDATE tool_call_confirmations SET status = 'Approved' WHERE id = ?",
-        confirmation_id_str
-    )
-    .execute(&*state.pool)
-    .await
-    .map_err(|e| ChatError::DatabaseError(format!("Failed to update tool call confirmation: {}", e)))?;
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+    authorize_chat(&state, current_user.id, chat_id, ChatRole::Editor).await?;
 
     // Get the tool call details
-    let confirmation_id_str2 = &confirmation_id as &str;
-    let row = sqlx::query!(
-        "SELECT tool_call, chat_id, message_pair_id FROM tool_call_confirmations WHERE id = ?",
-        confirmation_id_str2
-    )
-    .fetch_one(&*state.pool)
-    .await
-    .map_err(|e| ChatError::DatabaseError(format!("Failed to fetch tool call confirmation: {}", e)))?;
+    let tool_call = state
+        .chat_repo
+        .get_tool_call(chat_id, &confirmation_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to fetch tool call confirmation: {}", e)))?
+        .ok_or(ChatError::ChatNotFound)?;
 
     // Parse tool call
-    let tool_call: crate::data::model::ToolCall = serde_json::from_str(&row.tool_call)
+    let tool_call: crate::data::model::ToolCall = serde_json::from_str(&tool_call)
         .map_err(|e| ChatError::DatabaseError(format!("Failed to parse tool call: {}", e)))?;
 
     // Execute the tool
     let mcp_tool_call = crate::mcp::tools::parse_tool_call_from_ai(&tool_call)
         .ok_or_else(|| ChatError::InternalError("Invalid MCP tool call".to_string()))?;
 
-    // Create a new message pair for the tool execution result
-    let message_pair_id = state.chat_repo.add_message_block(
-        chat_id,
-        &format!("Executing tool: {}", tool_call.function.name),
-    ).await
-    .map_err(|e| ChatError::DatabaseError(format!("Failed to add tool execution message: {}", e)))?;
-
-    // Update the confirmation with the new message pair ID
-    let confirmation_id_str3 = &confirmation_id as &str;
-    sqlx::query!(
-        "UPDATE tool_call_confirmations SET message_pair_id = ? WHERE id = ?",
-        message_pair_id,
-        confirmation_id_str3
-    )
-    .execute(&*state.pool)
-    .await
-    .map_err(|e| ChatError::DatabaseError(format!("Failed to update confirmation: {}", e)))?;
+    // Approve it along with a new message pair for the tool execution result
+    let message_pair_id = state
+        .chat_repo
+        .approve_tool_call(chat_id, &confirmation_id, &format!("Executing tool: {}", tool_call.function.name))
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to approve tool call: {}", e)))?
+        .ok_or(ChatError::AlreadyAnswered)?;
+    let mut detail = format!("{} in chat {}", tool_call.function.name, chat_id);
+    if params.always {
+        let mcp = users::for_user(&state.chat_repo, &state.encryption, current_user.id).await;
+        if let Some(tool) = mcp.get_tool(&mcp_tool_call.name).await {
+            state
+                .chat_repo
+                .approve_tool(current_user.id, &tool.server_name, &tool.tool_info.name)
+                .await
+                .map_err(|e| ChatError::DatabaseError(format!("Failed to save tool approval: {}", e)))?;
+            detail.push_str(", always allowed from now on");
+        }
+    }
+    audit::record(&state, Some(current_user.id), AuditEvent::ToolApproved, Some(&detail), source).await;
 
-    // Show processing message
-    let processing_html = r#"
+    // Show processing message, cancelled by the run's own id as the model's
+    // ids aren't unique
+    let execution_id = uuid::Uuid::new_v4().to_string();
+    let processing_html = format!(
+        r#"
     <div class="alert alert-success">
         <div class="flex items-center gap-3">
             <div class="loading loading-spinner loading-sm"></div>
-            <div>
+            <div class="flex-1">
                 <h4 class="font-bold">Tool Call Approved</h4>
                 <p class="text-sm">Executing the tool call...</p>
             </div>
+            <button class="btn btn-ghost btn-sm" hx-post="/chat/{}/tool-cancel/{}" hx-swap="none">Cancel</button>
         </div>
     </div>
-    "#;
+    "#,
+        chat_id,
+        html_escape::encode_double_quoted_attribute(&execution_id)
+    );
 
     // Spawn background task to execute the tool and update the message
     let state_clone = state.clone();
     tokio::spawn(async move {
-        if let Err(e) = execute_tool_and_update_message(state_clone, chat_id, message_pair_id, mcp_tool_call).await {
+        if let Err(e) = execute_tool_and_update_message(state_clone, current_user.id, chat_id, message_pair_id, mcp_tool_call, execution_id).await {
             tracing::error!("Failed to execute tool: {}", e);
         }
     });
 
-    Ok(Html(processing_html.to_string()))
+    Ok(Html(processing_html))
 }
 
 pub async fn reject_tool_call(
     Path((chat_id, confirmation_id)): Path<(i64, String)>,
     State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Extension(source): Extension<AuditSource>,
 ) -> Result<Html<String>, ChatError> {
-    // Update confirmation status in database
-    let confirmation_id_str4 = &confirmation_id as &str;
-    sqlx::query!(
-        "UPDATE tool_call_confirmations SET status = 'Rejected', user_response = 'Rejected by user' WHERE id = ?",
-        confirmation_id_str4
-    )
-    .execute(&*state.pool)
-    .await
-    .map_err(|e| ChatError::DatabaseError(format!("Failed to update tool call confirmation: {}", e)))?;
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+    authorize_chat(&state, current_user.id, chat_id, ChatRole::Editor).await?;
+
+    state
+        .chat_repo
+        .get_tool_call(chat_id, &confirmation_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to fetch tool call confirmation: {}", e)))?
+        .ok_or(ChatError::ChatNotFound)?;
+    let rejected = state
+        .chat_repo
+        .reject_tool_call(chat_id, &confirmation_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to update tool call confirmation: {}", e)))?;
+    if !rejected {
+        return Err(ChatError::AlreadyAnswered);
+    }
+    let detail = format!("chat {}", chat_id);
+    audit::record(&state, Some(current_user.id), AuditEvent::ToolRejected, Some(&detail), source).await;
 
     let rejected_html = r#"
     <div class="alert alert-error">
@@ -1202,21 +4310,179 @@ pub async fn reject_tool_call(
     Ok(Html(rejected_html.to_string()))
 }
 
+// Cancels a tool call while it runs, by the execution id it was shown with,
+// as the ids models give calls can repeat. The run
+// ends with a `ToolCancelled` event, or a cancelled message for a confirmed
+// call
+pub async fn cancel_tool_call(
+    Path((chat_id, execution_id)): Path<(i64, String)>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<StatusCode, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+    authorize_chat(&state, current_user.id, chat_id, ChatRole::Editor).await?;
+
+    // Also when it finished in the meantime
+    if !cancel::cancel(chat_id, &execution_id) {
+        return Err(ChatError::ChatNotFound);
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+// The sampling requests of MCP servers waiting for the user, polled by the
+// chat page
+pub async fn sampling_requests(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+    render_sampling_requests(&state, current_user.id, None)
+}
+
+pub async fn approve_sampling(
+    Path(request_id): Path<u64>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Extension(source): Extension<AuditSource>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+    let (request, answer) = sampling::take(request_id, current_user.id).ok_or(ChatError::ChatNotFound)?;
+
+    let detail = format!("{} in chat {}", request.server, request.chat_id);
+    audit::record(&state, Some(current_user.id), AuditEvent::SamplingApproved, Some(&detail), source).await;
+
+    let result = sample(&state, &current_user, &request).await;
+    let error = result.as_ref().err().map(ToString::to_string);
+    let _ = answer.send(result.map_err(|e| e.to_string()));
+    render_sampling_requests(&state, current_user.id, error.as_deref())
+}
+
+pub async fn decline_sampling(
+    Path(request_id): Path<u64>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Extension(source): Extension<AuditSource>,
+) -> Result<Html<String>, ChatError> {
+    let current_user = current_user.ok_or(ChatError::MissingUser)?;
+    let (request, answer) = sampling::take(request_id, current_user.id).ok_or(ChatError::ChatNotFound)?;
+    let _ = answer.send(Err("The user declined the request".to_string()));
+
+    let detail = format!("{} in chat {}", request.server, request.chat_id);
+    audit::record(&state, Some(current_user.id), AuditEvent::SamplingDeclined, Some(&detail), source).await;
+    render_sampling_requests(&state, current_user.id, None)
+}
+
+fn render_sampling_requests(state: &AppState, user_id: i64, error: Option<&str>) -> Result<Html<String>, ChatError> {
+    let mut context = Context::new();
+    context.insert("requests", &sampling::pending_for(user_id));
+    context.insert("error", &error);
+    state
+        .tera
+        .render("htmx_updates/sampling_requests.html", &context)
+        .map(Html)
+        .map_err(|e| ChatError::ServerError(format!("Failed to render template: {}", e)))
+}
+
+// Answers an approved sampling request with the chat's key and model, within
+// the approver's quota like any other response
+async fn sample(state: &AppState, user: &User, request: &SamplingRequest) -> Result<serde_json::Value, ChatError> {
+    authorize_chat(state, user.id, request.chat_id, ChatRole::Editor).await?;
+    check_user_quota(state, user.id).await?;
+    let (key, default_model) = chat_api_key(state, user, request.chat_id).await?;
+
+    let settings = state
+        .chat_repo
+        .get_chat_settings(request.chat_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to load chat settings: {}", e)))?;
+    let agent = state
+        .chat_repo
+        .get_chat_agent(request.chat_id)
+        .await
+        .map_err(|e| ChatError::DatabaseError(format!("Failed to load agent: {}", e)))?;
+    let model = settings
+        .model
+        .or_else(|| agent.map(|agent| agent.model))
+        .or(default_model)
+        .unwrap_or_else(|| "Qwen/Qwen2.5-7B-Instruct".to_string());
+
+    let completion = complete(&key, &model, request.completion_messages(), request.max_tokens, request.temperature)
+        .await
+        .map_err(|e| ChatError::NetworkError(e.to_string()))?;
+    Ok(sampling::create_message_result(&completion.text, &model, completion.finish_reason.as_deref()))
+}
+
 async fn execute_tool_and_update_message(
     state: Arc<AppState>,
+    user_id: i64,
     chat_id: i64,
     message_pair_id: i64,
     mcp_tool_call: crate::mcp::tools::McpToolCall,
+    execution_id: String,
 ) -> Result<(), Box<dyn std::error::Error>> {
     // Create a channel for the tool execution result
     let (sender, mut receiver) = tokio::sync::mpsc::channel::<String>(10);
 
     // We need to create a proper sender for execute_mcp_tool_streaming
     // But since it expects GenerationEvent, let's execute the tool directly
-    let tool_result = crate::mcp::tools::execute_mcp_tool(&mcp_tool_call).await?;
+    let mcp = users::for_user(&state.chat_repo, &state.encryption, user_id).await;
+    let server = mcp
+        .get_tool(&mcp_tool_call.name)
+        .await
+        .map(|tool| tool.server_name)
+        .unwrap_or_default();
+
+    // The server may ask this user's model for completions while it runs
+    let started = std::time::Instant::now();
+    let client = mcp.client_name(&server).await;
+    let call = sampling::on_behalf_of(
+        &client,
+        user_id,
+        chat_id,
+        crate::mcp::tools::execute_mcp_tool(&mcp, &mcp_tool_call),
+    );
+    let outcome = cancel::cancellable(chat_id, &execution_id, call)
+        .await
+        .unwrap_or_else(|| Err(crate::mcp::McpManagerError::Cancelled(mcp_tool_call.name.clone())));
+    let duration_ms = started.elapsed().as_millis() as i64;
+    let result = outcome
+        .as_ref()
+        .ok()
+        .map(serde_json::to_string_pretty)
+        .transpose()?;
+    let arguments = mcp_tool_call.arguments.to_string();
+    let error = match &outcome {
+        Ok(result) if result.is_error => Some("The tool reported an error".to_string()),
+        Ok(_) => None,
+        Err(e) => Some(e.to_string()),
+    };
+    let execution = NewToolExecution {
+        user_id,
+        chat_id,
+        server: &server,
+        tool: &mcp_tool_call.name,
+        arguments: &arguments,
+        result_bytes: result.as_ref().map_or(0, |result| result.len() as i64),
+        duration_ms,
+        error,
+    };
+    if let Err(e) = state.chat_repo.record_tool_execution(&execution).await {
+        tracing::error!("Failed to log tool execution: {}", e);
+    }
 
-    // Convert the result to string
-    let result = serde_json::to_string_pretty(&tool_result)?;
+    if let Err(crate::mcp::McpManagerError::Cancelled(_)) = outcome {
+        state.chat_repo.add_ai_message_to_pair(message_pair_id, "Tool Execution Cancelled").await?;
+        sqlx::query!(
+            "UPDATE tool_call_confirmations SET status = 'Failed', user_response = 'Cancelled by user' WHERE message_pair_id = ?",
+            message_pair_id
+        )
+        .execute(&*state.pool)
+        .await?;
+        return Ok(());
+    }
+
+    let tool_result = outcome?;
+    let result = result.unwrap_or_default();
     let result_str = result.as_str();
 
     // Update the message with the result
@@ -1231,5 +4497,14 @@ async fn execute_tool_and_update_message(
     .execute(&*state.pool)
     .await?;
 
+    let executed = serde_json::json!({
+        "chat_id": chat_id,
+        "pair_id": message_pair_id,
+        "tool": mcp_tool_call.name,
+        "arguments": mcp_tool_call.arguments,
+        "result": tool_result,
+    });
+    webhooks::emit(&state.chat_repo, chat_id, webhooks::TOOL_EXECUTED, executed).await;
+
     Ok(())
 }
@@ -0,0 +1,107 @@
+use axum::{
+    extract::{ConnectInfo, Extension, State},
+    http::{HeaderMap, StatusCode},
+    response::Redirect,
+};
+use tower_cookies::Cookies;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::audit::{self, AuditEvent, AuditSource};
+use crate::session::start_session;
+use crate::utils::password;
+use crate::{AppState, User};
+
+use super::settings::purge_account;
+
+// Guest trials are on with `GUEST_MODE=true` and a `GUEST_API_KEY` to pay
+// for them
+pub(super) fn guest_mode() -> bool {
+    dotenv::var("GUEST_MODE").is_ok_and(|value| value == "true") && guest_api_key().is_some()
+}
+
+pub(super) fn guest_api_key() -> Option<String> {
+    dotenv::var("GUEST_API_KEY").ok().filter(|key| !key.trim().is_empty())
+}
+
+pub(super) fn guest_model() -> Option<String> {
+    dotenv::var("GUEST_MODEL").ok()
+}
+
+// How long a guest's chats last, from `GUEST_TTL_HOURS`
+fn guest_ttl_hours() -> i64 {
+    dotenv::var("GUEST_TTL_HOURS")
+        .ok()
+        .and_then(|hours| hours.parse().ok())
+        .filter(|hours| *hours > 0)
+        .unwrap_or(24)
+}
+
+// Signs the visitor in to a new guest account. Signing up later keeps its
+// chats, see `auth::form_signup`
+#[axum::debug_handler]
+pub async fn start_guest(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Extension(source): Extension<AuditSource>,
+    cookies: Cookies,
+    headers: HeaderMap,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+) -> Result<Redirect, StatusCode> {
+    if !guest_mode() {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    if current_user.is_some() {
+        return Ok(Redirect::to("/chat"));
+    }
+
+    // Nobody knows the password, so the account is only reachable through
+    // this session
+    let email = format!("guest-{}@guest.invalid", uuid::Uuid::new_v4().simple());
+    let hashed = password::hash(&uuid::Uuid::new_v4().to_string()).map_err(|e| {
+        tracing::error!("Failed to hash guest password: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let user_id = state
+        .chat_repo
+        .create_guest(&email, &hashed, guest_ttl_hours())
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to create guest: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    start_session(&state, &cookies, user_id, &headers, Some(addr), None, None)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to create guest session: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    audit::record(&state, Some(user_id), AuditEvent::Login, Some("guest"), source).await;
+
+    Ok(Redirect::to("/chat"))
+}
+
+// Removes expired guests with their chats and uploads
+pub async fn run_guest_sweep(state: Arc<AppState>) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(15 * 60));
+    loop {
+        interval.tick().await;
+
+        let guests = match state.chat_repo.expired_guests().await {
+            Ok(guests) => guests,
+            Err(e) => {
+                tracing::error!("Failed to find expired guests: {}", e);
+                continue;
+            }
+        };
+        for user_id in &guests {
+            // Failures are logged by `purge_account` and retried next time
+            let _ = purge_account(&state, *user_id).await;
+        }
+        if !guests.is_empty() {
+            tracing::info!("Removed {} expired guests", guests.len());
+        }
+    }
+}
@@ -14,8 +14,19 @@ pub async fn app(
     State(state): State<Arc<AppState>>,
     Extension(current_user): Extension<Option<User>>,
 ) -> Html<String> {
+    let templates = state
+        .chat_repo
+        .get_prompt_templates(current_user.as_ref().map(|user| user.id))
+        .await
+        .unwrap_or_else(|e| {
+            tracing::error!("Failed to load prompt templates: {}", e);
+            Vec::new()
+        });
+
     let mut context = Context::new();
     context.insert("name", "World");
+    context.insert("templates", &templates);
+    context.insert("logged_in", &current_user.is_some());
 
     let home = state.tera.render("views/home.html", &context).unwrap();
 
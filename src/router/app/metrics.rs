@@ -0,0 +1,33 @@
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+
+use std::sync::Arc;
+
+use crate::utils::password::hash_token;
+use crate::AppState;
+
+// Database and pool metrics in the Prometheus text format, for scrapers
+// sending `METRICS_TOKEN` as a bearer token. Without the variable set there
+// is no endpoint
+pub async fn metrics(State(state): State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    let Ok(token) = dotenv::var("METRICS_TOKEN") else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    // Hashed first so the comparison doesn't leak how much of it matched
+    if bearer.map(hash_token) != Some(hash_token(&token)) {
+        return (StatusCode::UNAUTHORIZED, [(header::WWW_AUTHENTICATE, "Bearer")]).into_response();
+    }
+
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")],
+        state.db_metrics.render(&state.pool),
+    )
+        .into_response()
+}
@@ -1,5 +1,8 @@
 use axum::{
-    routing::{get, post},
+    extract::DefaultBodyLimit,
+    handler::Handler,
+    middleware::from_fn_with_state,
+    routing::{delete, get, patch, post},
     Router,
 };
 
@@ -10,25 +13,101 @@ use crate::AppState;
 mod home;
 use home::app;
 mod chat;
-use chat::{chat, chat_add_message, chat_by_id, chat_generate, delete_chat, new_chat, confirm_tool_call, reject_tool_call};
+pub use chat::{run_scheduled_messages, run_trash_sweep};
+use chat::{chat, chat_history, list_commands, invite_chat_member, remove_chat_member, cancel_chat_invitation, schedule_message, scheduled_messages, cancel_scheduled_message, scheduled_notifications, quota_status, sampling_requests, approve_sampling, decline_sampling, dismiss_scheduled_notification, create_prompt_template, delete_prompt_template, start_from_template, duplicate_chat, save_chat_settings, edit_message, continue_message, message_feedback, toggle_bookmark, bookmarks, delete_message, select_version, export_chat, import_chats, rename_chat, reorder_chats, bulk_chats, toggle_pin_chat, share_chat, unshare_chat, shared_chat, archive_chat, unarchive_chat, archived_chats, trashed_chats, restore_chat, create_folder, rename_folder, delete_folder, move_chat_to_folder, add_chat_tag, remove_chat_tag, chat_add_message, mcp_prompt_form, chat_attachments, delete_attachment, chat_resource_picker, attach_chat_resource, detach_chat_resource, chat_stats, chat_by_id, chat_search, chat_generate, chat_generate_ws, chat_session_updates, chat_live_updates, chat_typing, delete_chat, new_chat, confirm_tool_call, reject_tool_call, cancel_tool_call};
 mod auth;
-use auth::{form_signup, login, login_form, logout, signup};
+use auth::{form_signup, login, login_form, logout, signup, forgot_password, forgot_password_form, reset_password, reset_password_form, verify_email_notice, resend_verification_email, verify_email, login_link, login_link_form, login_link_confirm, login_link_sign_in, oauth_authorize, oauth_callback};
 mod settings;
-use settings::{settings, settings_openai_api_key, mcp_settings, update_mcp_settings, delete_mcp_server, restart_mcp_server};
+use settings::{settings, settings_openai_api_key, mcp_settings, update_mcp_settings, delete_mcp_server, restart_mcp_server, import_mcp_servers, mcp_registry, install_mcp_server, authorize_mcp_server, mcp_oauth_callback, sign_out_mcp_server, mcp_server_logs, stream_mcp_server_logs, download_mcp_server_logs, save_custom_tool, delete_custom_tool, save_agent, delete_agent, restore_agent, save_webhook, delete_webhook, create_api_token, delete_api_token, revoke_tool_approval, sessions, revoke_session, revoke_all_sessions, audit_log, tool_executions, export_tool_executions, tool_analytics, profile, update_profile, upload_avatar, delete_avatar, delete_account, export_analytics, data_export_status, start_data_export, download_data_export};
+mod guest;
+pub use guest::run_guest_sweep;
+use guest::start_guest;
 mod error;
 use error::error;
+mod admin;
+use admin::{admin_users, admin_audit_log, admin_invites, create_invite, revoke_invite, user_quota, update_user_quota, disable_user, enable_user, unlock_user, delete_user, reset_user_password, impersonate_user, stop_impersonating, admin_backups, create_backup, download_backup, restore_backup, admin_db, vacuum_db, analyze_db, migrate_db};
+mod orgs;
+use orgs::{orgs, create_org, org, update_org, add_member, set_member_role, remove_member, delete_org, switch_org, org_switcher};
+mod api;
+mod metrics;
 
-use crate::middleware::auth;
+use crate::data::model::ApiScope;
+use crate::middleware::{admin, api_auth, auth, limit_by_ip, limit_by_user, require_scope, verified};
 
 pub fn app_router(state: Arc<AppState>) -> Router {
+    let limits = &state.rate_limits;
+    let auth_limit = from_fn_with_state(limits.auth.clone(), limit_by_ip);
+    let message_limit = from_fn_with_state(limits.messages.clone(), limit_by_user);
+    let generation_limit = from_fn_with_state(limits.generations.clone(), limit_by_user);
+
     let chat_router = Router::new()
-        .route("/", get(chat).post(new_chat))
+        .route("/", get(chat).post(new_chat.layer(message_limit.clone())))
+        .route("/search", get(chat_search))
+        .route("/archived", get(archived_chats))
+        .route("/trash", get(trashed_chats))
+        .route("/templates", post(create_prompt_template))
+        .route("/templates/{template_id}", delete(delete_prompt_template))
+        .route("/templates/{template_id}/start", post(start_from_template))
+        .route("/bookmarks", get(bookmarks))
+        .route("/commands", get(list_commands))
+        .route("/scheduled", get(scheduled_messages))
+        .route("/scheduled/notifications", get(scheduled_notifications))
+        .route("/quota", get(quota_status))
+        .route("/sampling", get(sampling_requests))
+        .route("/sampling/{request_id}/approve", post(approve_sampling))
+        .route("/sampling/{request_id}/decline", post(decline_sampling))
+        .route("/scheduled/{scheduled_id}/cancel", post(cancel_scheduled_message))
+        .route("/scheduled/{scheduled_id}/seen", post(dismiss_scheduled_notification))
+        // ChatGPT exports of a long history easily exceed the default 2MB body limit
+        .route("/import", post(import_chats).layer(DefaultBodyLimit::max(64 * 1024 * 1024)))
         .route("/{id}", get(chat_by_id).delete(delete_chat))
-        .route("/{id}/message/add", post(chat_add_message))
-        .route("/{id}/generate", get(chat_generate))
+        .route("/{id}/name", patch(rename_chat))
+        .route("/{id}/pin", post(toggle_pin_chat))
+        .route("/{id}/share", post(share_chat).delete(unshare_chat))
+        .route("/{id}/duplicate", post(duplicate_chat))
+        .route("/{id}/settings", post(save_chat_settings))
+        .route("/{id}/schedule", post(schedule_message))
+        .route("/{id}/members", post(invite_chat_member))
+        .route("/{id}/members/{user_id}", delete(remove_chat_member))
+        .route("/{id}/invitations", delete(cancel_chat_invitation))
+        .route("/{id}/archive", post(archive_chat))
+        .route("/{id}/unarchive", post(unarchive_chat))
+        .route("/{id}/restore", post(restore_chat))
+        .route("/reorder", post(reorder_chats))
+        .route("/bulk", post(bulk_chats))
+        .route("/folders", post(create_folder))
+        .route("/folders/{folder_id}", patch(rename_folder).delete(delete_folder))
+        .route("/{id}/folder", post(move_chat_to_folder))
+        .route("/{id}/tags", post(add_chat_tag))
+        .route("/{id}/tags/{tag}", delete(remove_chat_tag))
+        .route("/{id}/history", get(chat_history))
+        .route("/{id}/message/add", post(chat_add_message.layer(message_limit.clone())))
+        .route("/{id}/message/{pair_id}", delete(delete_message))
+        .route("/{id}/message/{pair_id}/edit", post(edit_message.layer(message_limit)))
+        .route("/{id}/message/{pair_id}/feedback", post(message_feedback))
+        .route("/{id}/message/{pair_id}/bookmark", post(toggle_bookmark))
+        .route(
+            "/{id}/message/{pair_id}/continue",
+            get(continue_message.layer(generation_limit.clone())),
+        )
+        .route("/{id}/block/{block_id}/select/{rank}", post(select_version))
+        .route("/{id}/attachments", get(chat_attachments))
+        .route("/{id}/attachments/{attachment_id}", delete(delete_attachment))
+        .route("/{id}/resources", get(chat_resource_picker).post(attach_chat_resource))
+        .route("/{id}/resources/{resource_id}", delete(detach_chat_resource))
+        .route("/{id}/prompts/{server}/{name}", get(mcp_prompt_form))
+        .route("/{id}/export", get(export_chat))
+        .route("/{id}/stats", get(chat_stats))
+        .route("/{id}/generate", get(chat_generate.layer(generation_limit.clone())))
+        .route("/{id}/ws", get(chat_generate_ws.layer(generation_limit)))
+        .route("/{id}/session-updates", get(chat_session_updates))
+        .route("/{id}/live", get(chat_live_updates))
+        .route("/{id}/typing", post(chat_typing))
         .route("/{id}/tool-confirm/{confirmation_id}", post(confirm_tool_call))
         .route("/{id}/tool-reject/{confirmation_id}", post(reject_tool_call))
+        .route("/{id}/tool-cancel/{execution_id}", post(cancel_tool_call))
         .with_state(state.clone())
+        .layer(axum::middleware::from_fn(verified))
         .layer(axum::middleware::from_fn(auth));
 
     let settings_router = Router::new()
@@ -37,21 +116,152 @@ pub fn app_router(state: Arc<AppState>) -> Router {
         .route("/mcp/update", post(update_mcp_settings))
         .route("/mcp/delete", post(delete_mcp_server))
         .route("/mcp/restart", post(restart_mcp_server))
+        .route("/mcp/import", post(import_mcp_servers))
+        .route("/mcp/registry", get(mcp_registry))
+        .route("/mcp/registry/install", post(install_mcp_server))
+        .route("/mcp/oauth/authorize", post(authorize_mcp_server))
+        .route("/mcp/oauth/callback", get(mcp_oauth_callback))
+        .route("/mcp/oauth/sign-out", post(sign_out_mcp_server))
+        .route("/mcp/{server}/logs", get(mcp_server_logs))
+        .route("/mcp/{server}/logs/stream", get(stream_mcp_server_logs))
+        .route("/mcp/{server}/logs/download", get(download_mcp_server_logs))
+        .route("/tools", post(save_custom_tool))
+        .route("/tools/delete", post(delete_custom_tool))
+        .route("/agents", post(save_agent))
+        .route("/agents/delete", post(delete_agent))
+        .route("/agents/restore", post(restore_agent))
+        .route("/webhooks", post(save_webhook))
+        .route("/webhooks/delete", post(delete_webhook))
+        .route("/tokens", post(create_api_token))
+        .route("/tokens/{token_id}", delete(delete_api_token))
+        .route("/tool-approvals/{approval_id}", delete(revoke_tool_approval))
+        .route("/sessions", get(sessions))
+        .route("/sessions/revoke-all", post(revoke_all_sessions))
+        .route("/sessions/{session_id}", delete(revoke_session))
+        .route("/audit", get(audit_log))
+        .route("/tool-executions", get(tool_executions))
+        .route("/tool-executions/export", get(export_tool_executions))
+        .route("/tool-analytics", get(tool_analytics))
+        .route("/profile", get(profile).post(update_profile))
+        .route(
+            "/profile/avatar",
+            post(upload_avatar).layer(DefaultBodyLimit::max(10 * 1024 * 1024)),
+        )
+        .route("/profile/avatar/delete", post(delete_avatar))
+        .route("/analytics/export", get(export_analytics))
+        .route("/account/export", get(data_export_status).post(start_data_export))
+        .route("/account/export/download", get(download_data_export))
+        .route("/account/delete", post(delete_account))
+        .layer(axum::middleware::from_fn(auth));
+
+    let orgs_router = Router::new()
+        .route("/", get(orgs).post(create_org))
+        .route("/switch", post(switch_org))
+        .route("/switcher", get(org_switcher))
+        .route("/{org_id}", get(org).post(update_org))
+        .route("/{org_id}/members", post(add_member))
+        .route("/{org_id}/members/{user_id}/role", post(set_member_role))
+        .route("/{org_id}/members/{user_id}/remove", post(remove_member))
+        .route("/{org_id}/delete", post(delete_org))
         .layer(axum::middleware::from_fn(auth));
 
+    let admin_router = Router::new()
+        .route("/users", get(admin_users))
+        .route("/audit", get(admin_audit_log))
+        .route("/invites", get(admin_invites).post(create_invite))
+        .route("/invites/{invite_id}/revoke", post(revoke_invite))
+        .route("/users/{user_id}/disable", post(disable_user))
+        .route("/users/{user_id}/enable", post(enable_user))
+        .route("/users/{user_id}/unlock", post(unlock_user))
+        .route("/users/{user_id}/delete", post(delete_user))
+        .route("/users/{user_id}/reset", post(reset_user_password))
+        .route("/users/{user_id}/impersonate", post(impersonate_user))
+        .route("/users/{user_id}/quota", get(user_quota).post(update_user_quota))
+        .route("/backups", get(admin_backups).post(create_backup))
+        .route("/backups/{name}", get(download_backup))
+        // Streamed to disk, so no size limit
+        .route("/backups/restore", post(restore_backup).layer(DefaultBodyLimit::disable()))
+        .route("/db", get(admin_db))
+        .route("/db/vacuum", post(vacuum_db))
+        .route("/db/analyze", post(analyze_db))
+        .route("/db/migrate", post(migrate_db))
+        .layer(axum::middleware::from_fn(admin));
+
     Router::new()
         .route("/", get(app))
         .route("/error", get(error))
-        .route("/login", get(login).post(login_form))
-        .route("/signup", get(signup).post(form_signup))
+        .route("/login", get(login).post(login_form.layer(auth_limit.clone())))
+        .route("/signup", get(signup).post(form_signup.layer(auth_limit.clone())))
+        .route("/login/link", get(login_link).post(login_link_form.layer(auth_limit.clone())))
+        .route("/login/link/{token}", get(login_link_confirm).post(login_link_sign_in))
+        .route("/guest", post(start_guest.layer(auth_limit.clone())))
+        .route("/verify", get(verify_email_notice))
+        .route("/verify/resend", post(resend_verification_email))
+        .route("/verify/{token}", get(verify_email))
+        .route("/oauth/{provider}", get(oauth_authorize))
+        .route("/oauth/{provider}/callback", get(oauth_callback))
+        .route(
+            "/password/forgot",
+            get(forgot_password).post(forgot_password_form.layer(auth_limit)),
+        )
+        .route("/password/reset/{token}", get(reset_password).post(reset_password_form))
         .route("/logout", get(logout))
+        .route("/share/{token}", get(shared_chat))
         .route("/demo", get(demo))
         .route("/demo-file-voice", get(demo_file_voice))
         .route("/demo-multi-turn", get(demo_multi_turn))
         .route("/demo-loading", get(demo_loading))
         .nest("/chat", chat_router)
         .nest("/settings", settings_router)
+        .nest("/orgs", orgs_router)
+        .nest("/admin", admin_router)
+        .route("/impersonation/stop", post(stop_impersonating))
+        .with_state(state.clone())
+}
+
+// JSON API for scripts and mobile apps. It is mounted outside the HTML error
+// pages, so failures come back as JSON
+pub fn api_router(state: Arc<AppState>) -> Router {
+    let limits = &state.rate_limits;
+    let message_limit = from_fn_with_state(limits.messages.clone(), limit_by_user);
+    let generation_limit = from_fn_with_state(limits.generations.clone(), limit_by_user);
+
+    let read = Router::new()
+        .route("/chats", get(api::list_chats))
+        .route("/chats/{id}", get(api::get_chat))
+        .route("/chats/{id}/stats", get(api::chat_stats))
+        .route("/chats/{id}/usage", get(api::chat_usage))
+        .route("/usage", get(api::usage))
+        .route("/agents", get(api::list_agents))
+        .route("/providers", get(api::list_providers))
+        .route_layer(axum::middleware::from_fn_with_state(ApiScope::Read, require_scope));
+
+    // Everything that changes chats or spends the user's API credits,
+    // streaming a generation included
+    let write = Router::new()
+        .route("/chats", post(api::create_chat.layer(message_limit.clone())))
+        .route("/chats/{id}", delete(api::delete_chat))
+        .route("/chats/{id}/messages", post(api::add_message.layer(message_limit)))
+        .route(
+            "/chats/{id}/generate",
+            get(api::generate_stream)
+                .post(api::generate_background)
+                .layer(generation_limit),
+        )
+        .route_layer(axum::middleware::from_fn_with_state(ApiScope::Write, require_scope));
+
+    let v1 = read
+        .merge(write)
+        .layer(axum::middleware::from_fn(api_auth))
+        .with_state(state.clone());
+
+    // The spec and its viewer are public so integrators can browse them
+    Router::new()
+        .route("/api/openapi.json", get(api::openapi_json))
+        .route("/api/docs", get(api::api_docs))
+        .route("/metrics", get(metrics::metrics))
         .with_state(state.clone())
+        .nest("/api/v1", v1)
 }
 
 async fn demo(
@@ -0,0 +1,360 @@
+use axum::{
+    extract::{Extension, Form, Path, Query, State},
+    http::StatusCode,
+    response::{Html, Redirect},
+};
+use serde::Deserialize;
+use tera::Context;
+
+use std::sync::Arc;
+
+use crate::data::model::OrgRole;
+use crate::{AppState, User};
+
+const MAX_ORG_NAME: usize = 80;
+
+#[derive(Deserialize, Debug)]
+pub struct OrgNotice {
+    notice: Option<String>,
+}
+
+fn org_name(name: &str) -> Result<&str, StatusCode> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_ORG_NAME {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    Ok(name)
+}
+
+fn db_error(action: &str) -> impl FnOnce(sqlx::Error) -> StatusCode + '_ {
+    move |e| {
+        tracing::error!("Failed to {}: {}", action, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
+// The user's role in the org, which must be at least `needed`. Outsiders get
+// a 404 so org ids can't be probed
+async fn require_role(state: &AppState, org_id: i64, user_id: i64, needed: OrgRole) -> Result<OrgRole, StatusCode> {
+    let role = state
+        .chat_repo
+        .get_org_role(org_id, user_id)
+        .await
+        .map_err(db_error("load org role"))?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if role < needed {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(role)
+}
+
+#[axum::debug_handler]
+pub async fn orgs(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Query(params): Query<OrgNotice>,
+) -> Result<Html<String>, StatusCode> {
+    let user = current_user.as_ref().unwrap();
+    let orgs = state.chat_repo.get_user_orgs(user.id).await.map_err(db_error("load orgs"))?;
+
+    let mut context = Context::new();
+    context.insert("orgs", &orgs);
+    context.insert("current_org_id", &user.current_org_id);
+    context.insert("max_org_name", &MAX_ORG_NAME);
+    context.insert("notice", &params.notice);
+    let view = state.tera.render("views/orgs.html", &context).unwrap();
+
+    let mut context = Context::new();
+    context.insert("view", &view);
+    context.insert("current_user", &current_user);
+    context.insert("with_footer", &true);
+    let rendered = state.tera.render("views/main.html", &context).unwrap();
+
+    Ok(Html(rendered))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewOrg {
+    name: String,
+}
+
+// The new org becomes the creator's workspace
+#[axum::debug_handler]
+pub async fn create_org(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Form(org): Form<NewOrg>,
+) -> Result<Redirect, StatusCode> {
+    let user = current_user.unwrap();
+    let name = org_name(&org.name)?;
+
+    let org_id = state.chat_repo.create_org(user.id, name).await.map_err(db_error("create org"))?;
+    state
+        .chat_repo
+        .set_current_org(user.id, Some(org_id))
+        .await
+        .map_err(db_error("switch org"))?;
+    Ok(Redirect::to(&format!("/orgs/{}?notice=created", org_id)))
+}
+
+#[axum::debug_handler]
+pub async fn org(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Path(org_id): Path<i64>,
+    Query(params): Query<OrgNotice>,
+) -> Result<Html<String>, StatusCode> {
+    let user = current_user.as_ref().unwrap();
+    let role = require_role(&state, org_id, user.id, OrgRole::Member).await?;
+    let org = state
+        .chat_repo
+        .get_org(org_id)
+        .await
+        .map_err(db_error("load org"))?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let members = state.chat_repo.get_org_members(org_id).await.map_err(db_error("load org members"))?;
+
+    let mut context = Context::new();
+    context.insert("org", &org);
+    context.insert("has_api_key", &org.has_api_key());
+    let api_key_unreadable = org.has_api_key()
+        && state
+            .encryption
+            .decrypt(org.openai_api_key.as_deref().unwrap_or_default())
+            .is_err();
+    context.insert("api_key_unreadable", &api_key_unreadable);
+    context.insert("members", &members);
+    context.insert("role", role.as_str());
+    context.insert("is_admin", &(role >= OrgRole::Admin));
+    context.insert("is_owner", &(role == OrgRole::Owner));
+    context.insert("user_id", &user.id);
+    context.insert("max_org_name", &MAX_ORG_NAME);
+    context.insert("notice", &params.notice);
+    let view = state.tera.render("views/org.html", &context).unwrap();
+
+    let mut context = Context::new();
+    context.insert("view", &view);
+    context.insert("current_user", &current_user);
+    context.insert("with_footer", &true);
+    let rendered = state.tera.render("views/main.html", &context).unwrap();
+
+    Ok(Html(rendered))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct OrgSettings {
+    name: String,
+    model: String,
+    // Blank keeps the current key
+    api_key: String,
+    remove_api_key: Option<String>,
+}
+
+// The org's name and the provider its chats use
+#[axum::debug_handler]
+pub async fn update_org(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Path(org_id): Path<i64>,
+    Form(settings): Form<OrgSettings>,
+) -> Result<Redirect, StatusCode> {
+    let user = current_user.unwrap();
+    require_role(&state, org_id, user.id, OrgRole::Admin).await?;
+    let name = org_name(&settings.name)?;
+    let model = settings.model.trim();
+    let model = (!model.is_empty()).then_some(model);
+
+    state
+        .chat_repo
+        .update_org(org_id, name, model)
+        .await
+        .map_err(db_error("update org"))?;
+
+    let api_key = settings.api_key.trim();
+    if settings.remove_api_key.is_some() {
+        state.chat_repo.set_org_api_key(org_id, None).await.map_err(db_error("remove org key"))?;
+        tracing::warn!("{} removed the API key of org {}", user.email, org_id);
+    } else if !api_key.is_empty() {
+        let stored = state.encryption.encrypt(api_key);
+        state
+            .chat_repo
+            .set_org_api_key(org_id, Some(&stored))
+            .await
+            .map_err(db_error("save org key"))?;
+        tracing::warn!("{} set the API key of org {}", user.email, org_id);
+    }
+    Ok(Redirect::to(&format!("/orgs/{}?notice=saved", org_id)))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewMember {
+    email: String,
+    role: String,
+}
+
+#[axum::debug_handler]
+pub async fn add_member(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Path(org_id): Path<i64>,
+    Form(member): Form<NewMember>,
+) -> Result<Redirect, StatusCode> {
+    let user = current_user.unwrap();
+    let own_role = require_role(&state, org_id, user.id, OrgRole::Admin).await?;
+    let role = OrgRole::parse(&member.role).ok_or(StatusCode::UNPROCESSABLE_ENTITY)?;
+    // Only owners make more owners
+    if role > own_role {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let added = state
+        .chat_repo
+        .add_org_member(org_id, member.email.trim(), role)
+        .await
+        .map_err(db_error("add org member"))?;
+    let notice = if added == 0 { "unknown" } else { "added" };
+    Ok(Redirect::to(&format!("/orgs/{}?notice={}", org_id, notice)))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MemberRole {
+    role: String,
+}
+
+#[axum::debug_handler]
+pub async fn set_member_role(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Path((org_id, member_id)): Path<(i64, i64)>,
+    Form(form): Form<MemberRole>,
+) -> Result<Redirect, StatusCode> {
+    let user = current_user.unwrap();
+    let own_role = require_role(&state, org_id, user.id, OrgRole::Admin).await?;
+    let role = OrgRole::parse(&form.role).ok_or(StatusCode::UNPROCESSABLE_ENTITY)?;
+    let current = state
+        .chat_repo
+        .get_org_role(org_id, member_id)
+        .await
+        .map_err(db_error("load org role"))?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    // Admins can't promote to owner or demote an owner
+    if role.max(current) > own_role {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    if current == OrgRole::Owner && role != OrgRole::Owner && last_owner(&state, org_id).await? {
+        return Ok(Redirect::to(&format!("/orgs/{}?notice=last_owner", org_id)));
+    }
+
+    state
+        .chat_repo
+        .set_org_role(org_id, member_id, role)
+        .await
+        .map_err(db_error("set org role"))?;
+    Ok(Redirect::to(&format!("/orgs/{}?notice=role", org_id)))
+}
+
+async fn last_owner(state: &AppState, org_id: i64) -> Result<bool, StatusCode> {
+    let owners = state
+        .chat_repo
+        .count_org_owners(org_id)
+        .await
+        .map_err(db_error("count org owners"))?;
+    Ok(owners <= 1)
+}
+
+// Admins remove others; anyone can leave
+#[axum::debug_handler]
+pub async fn remove_member(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Path((org_id, member_id)): Path<(i64, i64)>,
+) -> Result<Redirect, StatusCode> {
+    let user = current_user.unwrap();
+    let leaving = member_id == user.id;
+    let needed = if leaving { OrgRole::Member } else { OrgRole::Admin };
+    let own_role = require_role(&state, org_id, user.id, needed).await?;
+    let role = state
+        .chat_repo
+        .get_org_role(org_id, member_id)
+        .await
+        .map_err(db_error("load org role"))?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    if role > own_role {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    if role == OrgRole::Owner && last_owner(&state, org_id).await? {
+        return Ok(Redirect::to(&format!("/orgs/{}?notice=last_owner", org_id)));
+    }
+
+    state
+        .chat_repo
+        .remove_org_member(org_id, member_id)
+        .await
+        .map_err(db_error("remove org member"))?;
+    if leaving {
+        return Ok(Redirect::to("/orgs?notice=left"));
+    }
+    Ok(Redirect::to(&format!("/orgs/{}?notice=removed", org_id)))
+}
+
+#[axum::debug_handler]
+pub async fn delete_org(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Path(org_id): Path<i64>,
+) -> Result<Redirect, StatusCode> {
+    let user = current_user.unwrap();
+    require_role(&state, org_id, user.id, OrgRole::Owner).await?;
+
+    state.chat_repo.delete_org(org_id).await.map_err(db_error("delete org"))?;
+    tracing::warn!("{} deleted org {}", user.email, org_id);
+    Ok(Redirect::to("/orgs?notice=deleted"))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SwitchOrg {
+    // Blank for the personal workspace
+    org_id: String,
+}
+
+#[axum::debug_handler]
+pub async fn switch_org(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Form(form): Form<SwitchOrg>,
+) -> Result<Redirect, StatusCode> {
+    let user = current_user.unwrap();
+    let org_id = match form.org_id.trim() {
+        "" => None,
+        org_id => {
+            let org_id = org_id.parse().map_err(|_| StatusCode::UNPROCESSABLE_ENTITY)?;
+            require_role(&state, org_id, user.id, OrgRole::Member).await?;
+            Some(org_id)
+        }
+    };
+
+    state
+        .chat_repo
+        .set_current_org(user.id, org_id)
+        .await
+        .map_err(db_error("switch org"))?;
+    Ok(Redirect::to("/chat"))
+}
+
+// The workspace dropdown in the navbar
+#[axum::debug_handler]
+pub async fn org_switcher(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Html<String>, StatusCode> {
+    let user = current_user.unwrap();
+    let orgs = state.chat_repo.get_user_orgs(user.id).await.map_err(db_error("load orgs"))?;
+    let current = orgs.iter().find(|org| Some(org.id) == user.current_org_id);
+
+    let mut context = Context::new();
+    context.insert("orgs", &orgs);
+    context.insert("current", &current);
+    let rendered = state.tera.render("htmx_updates/org_switcher.html", &context).unwrap();
+
+    Ok(Html(rendered))
+}
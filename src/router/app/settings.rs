@@ -1,18 +1,42 @@
 use axum::{
-    extract::{Extension, State},
-    http::StatusCode,
-    response::{Html, Redirect, Json},
+    extract::{Extension, Multipart, Path, Query, State},
+    http::{header, StatusCode},
+    response::{
+        sse::{Event, Sse},
+        Html, IntoResponse, Redirect, Json, Response,
+    },
     Form,
 };
+use chrono::NaiveDate;
 
 use serde::{Deserialize, Serialize};
 use tera::Context;
+use tower_cookies::Cookies;
 
 use std::sync::Arc;
 use std::collections::HashMap;
 
 use crate::{AppState, User};
-use crate::mcp::{get_mcp_manager, McpServerConfig};
+use crate::audit::{self, AuditEvent, AuditSource};
+use crate::data_export::{self, EXPORT_DIR};
+use crate::ai::custom_tools;
+use crate::ai::stream::parse_stop_sequences;
+use crate::data::model::{Agent, ApiScope, OrgRole, Session, UserSettings};
+use crate::data::repository::NO_LIMIT;
+use crate::utils::export::to_csv;
+use crate::mcp::health::ServerStatus;
+use crate::mcp::logs::{self, ServerLog};
+use crate::mcp::oauth::{self as mcp_oauth, McpOAuthError};
+use crate::mcp::registry::{self, RegistryError};
+use crate::mcp::roots;
+use crate::mcp::{get_mcp_manager, users, McpManager, McpServerConfig, TransportType};
+use crate::middleware::SESSION_COOKIE;
+use crate::utils::{avatar, outbound, password};
+use crate::webhooks;
+
+use crate::session::{self, end_session};
+
+use super::auth::app_url;
 
 #[derive(Deserialize, Debug)]
 pub struct AISettings {
@@ -23,6 +47,8 @@ pub struct AISettings {
     temperature: Option<f64>,
     top_p: Option<f64>,
     max_tokens: Option<i64>,
+    // The settings' version when the page was rendered
+    version: i64,
 }
 
 #[derive(Deserialize, Debug, Serialize)]
@@ -37,11 +63,132 @@ pub struct McpServerSettings {
     pub transport: Option<String>,
     pub url: Option<String>,
     pub headers: Option<HashMap<String, String>>,
+    pub max_restarts: Option<u32>,
+    pub cache_ttl: Option<HashMap<String, u64>>,
+    pub tool_timeouts: Option<HashMap<String, u64>>,
+    pub roots: Option<Vec<String>>,
+}
+
+// An MCP server as entered in settings: args one per line, env as
+// `KEY=value` lines, headers as `Name: value` lines and cached tools and
+// tool timeouts as `tool=seconds` lines
+#[derive(Deserialize, Debug)]
+pub struct McpServerForm {
+    name: String,
+    transport: String,
+    #[serde(default)]
+    command: String,
+    #[serde(default)]
+    args: String,
+    #[serde(default)]
+    env: String,
+    #[serde(default)]
+    url: String,
+    #[serde(default)]
+    headers: String,
+    #[serde(default)]
+    timeout: String,
+    #[serde(default)]
+    description: String,
+    #[serde(default)]
+    max_restarts: String,
+    #[serde(default)]
+    cache_ttl: String,
+    #[serde(default)]
+    tool_timeouts: String,
+    #[serde(default)]
+    roots: String,
+    disabled: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CustomToolSettings {
+    name: String,
+    description: String,
+    parameters: Option<String>,
+    url: String,
+    auth_header: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DeleteCustomToolForm {
+    id: i64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AgentSettings {
+    name: String,
+    model: String,
+    system_prompt: String,
+    temperature: String,
+    top_p: String,
+    max_tokens: String,
+    stop_sequences: String, // one per line
+    tools_enabled: Option<String>, // checkbox
+    // One tool name per line, empty for every tool
+    #[serde(default)]
+    allow_tools: String,
+    share: Option<String>,         // checkbox, shares it in the current org
+    // Set when editing an agent, with its version when the page was rendered
+    id: Option<i64>,
+    version: Option<i64>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SettingsParams {
+    // An agent to load into the agent form
+    edit_agent: Option<i64>,
+    // "settings" or "agent" when a save from a stale page was rejected
+    conflict: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DeleteAgentForm {
+    id: i64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct WebhookForm {
+    url: String,
+    // Checkboxes, one per event
+    chat_created: Option<String>,
+    generation_completed: Option<String>,
+    tool_executed: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DeleteWebhookForm {
+    id: i64,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ApiTokenForm {
+    name: String,
+    scope: ApiScope,
+    // 0 for a token that never expires
+    expires_in_days: i64,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum AnalyticsReport {
+    Daily,
+    Costs,
+    Models,
+    Tools,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AnalyticsExportParams {
+    report: AnalyticsReport,
+    from: NaiveDate,
+    to: NaiveDate,
 }
 
 #[derive(Serialize)]
 pub struct McpSettingsResponse {
     pub servers: HashMap<String, McpServerSettings>,
+    pub statuses: HashMap<String, ServerStatus>,
     pub connected_servers: Vec<String>,
     pub available_tools: Vec<String>,
 }
@@ -50,152 +197,337 @@ pub struct McpSettingsResponse {
 pub async fn settings_openai_api_key(
     State(state): State<Arc<AppState>>,
     Extension(current_user): Extension<Option<User>>,
+    Extension(source): Extension<AuditSource>,
     Form(ai_settings): Form<AISettings>,
 ) -> Result<Redirect, StatusCode> {
-    let id = current_user.unwrap().id;
+    let user = current_user.unwrap();
+    let id = user.id;
 
+    // An empty key stays empty, so it still reads as not configured
+    let stored_api_key = if ai_settings.api_key.is_empty() {
+        String::new()
+    } else {
+        state.encryption.encrypt(&ai_settings.api_key)
+    };
     // Default values for optional fields
-    let base_url = ai_settings
-        .base_url
-        .as_deref()
-        .unwrap_or("https://api.siliconflow.cn/v1");
-    let model = ai_settings
-        .model
-        .as_deref()
-        .unwrap_or("Qwen/Qwen2.5-7B-Instruct");
-    let system_prompt = ai_settings
-        .system_prompt
-        .as_deref()
-        .unwrap_or("You are a helpful assistant.");
-    let temperature = ai_settings.temperature.unwrap_or(0.7);
-    let top_p = ai_settings.top_p.unwrap_or(1.0);
-    let max_tokens = ai_settings.max_tokens.unwrap_or(2000);
-
-    sqlx::query(r#"
-        INSERT INTO settings (user_id, openai_api_key, base_url, model, system_prompt, temperature, top_p, max_tokens)
-        VALUES (?, ?, ?, ?, ?, ?, ?, ?)
-        ON CONFLICT (user_id) DO UPDATE SET
-            openai_api_key = excluded.openai_api_key,
-            base_url = excluded.base_url,
-            model = excluded.model,
-            system_prompt = excluded.system_prompt,
-            temperature = excluded.temperature,
-            top_p = excluded.top_p,
-            max_tokens = excluded.max_tokens
-    "#)
-        .bind(id)
-        .bind(&ai_settings.api_key)
-        .bind(base_url)
-        .bind(model)
-        .bind(system_prompt)
-        .bind(temperature)
-        .bind(top_p)
-        .bind(max_tokens)
-        .execute(&*state.pool)
-        .await
-        .unwrap();
+    let settings = UserSettings {
+        stored_api_key,
+        base_url: ai_settings
+            .base_url
+            .unwrap_or_else(|| "https://api.siliconflow.cn/v1".to_string()),
+        model: ai_settings
+            .model
+            .unwrap_or_else(|| "Qwen/Qwen2.5-7B-Instruct".to_string()),
+        system_prompt: ai_settings
+            .system_prompt
+            .unwrap_or_else(|| "You are a helpful assistant.".to_string()),
+        temperature: ai_settings.temperature.unwrap_or(0.7),
+        top_p: ai_settings.top_p.unwrap_or(1.0),
+        max_tokens: ai_settings.max_tokens.unwrap_or(2000),
+    };
+
+    let saved = state
+        .chat_repo
+        .save_settings(id, &settings, ai_settings.version)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to save settings of user {}: {}", id, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    // Saved from another tab or device since this page was loaded
+    if !saved {
+        return Ok(Redirect::to("/settings?conflict=settings"));
+    }
+
+    // The key itself stays out of the log
+    if user.openai_api_key.as_deref().unwrap_or_default() != ai_settings.api_key {
+        let detail = if ai_settings.api_key.is_empty() { "removed" } else { "set" };
+        audit::record(&state, Some(id), AuditEvent::ApiKeyChanged, Some(detail), source).await;
+    }
 
     Ok(Redirect::to("/settings"))
 }
 
+fn server_settings(name: String, config: McpServerConfig) -> McpServerSettings {
+    McpServerSettings {
+        name,
+        command: config.command,
+        args: config.args,
+        env: config.env,
+        disabled: config.disabled,
+        timeout: config.timeout,
+        description: config.description,
+        transport: config.transport.map(|t| format!("{:?}", t).to_lowercase()),
+        url: config.url,
+        headers: config.headers,
+        max_restarts: config.max_restarts,
+        cache_ttl: config.cache_ttl,
+        tool_timeouts: config.tool_timeouts,
+        roots: config.roots,
+    }
+}
+
+// The global servers from mcp.json, overridden by the user's own
 #[axum::debug_handler]
 pub async fn mcp_settings(
+    State(state): State<Arc<AppState>>,
     Extension(current_user): Extension<Option<User>>,
 ) -> Result<Json<McpSettingsResponse>, StatusCode> {
-    let _user = current_user.as_ref().unwrap();
-
-    let mcp_manager = get_mcp_manager();
+    let user = current_user.as_ref().unwrap();
 
-    // Get server configurations
-    let server_configs = mcp_manager.get_server_configs().await;
+    let mcp = users::for_user(&state.chat_repo, &state.encryption, user.id).await;
+    let own = users::user_manager(&state.chat_repo, &state.encryption, user.id).await;
     let mut servers = HashMap::new();
-
-    for (name, config) in server_configs {
-        let server_settings = McpServerSettings {
-            name: name.clone(),
-            command: config.command,
-            args: config.args,
-            env: config.env,
-            disabled: config.disabled,
-            timeout: config.timeout,
-            description: config.description,
-            transport: config.transport.map(|t| format!("{:?}", t).to_lowercase()),
-            url: config.url,
-            headers: config.headers,
-        };
-        servers.insert(name, server_settings);
+    for (name, config) in get_mcp_manager().get_server_configs().await {
+        servers.insert(name.clone(), server_settings(name, config));
     }
+    for (name, config) in own.get_server_configs().await {
+        servers.insert(name.clone(), server_settings(name, config));
+    }
+    let mut statuses = get_mcp_manager().get_server_statuses().await;
+    statuses.extend(own.get_server_statuses().await);
 
-    // Get connected servers
-    let connected_servers = mcp_manager.get_connected_servers().await;
-
-    // Get available tools
-    let tools = mcp_manager.get_all_tools().await;
-    let available_tools = tools.into_iter().map(|tool| tool.name).collect();
+    let connected_servers = mcp.get_connected_servers().await;
+    let available_tools = mcp.get_all_tools().await.into_iter().map(|tool| tool.name).collect();
 
     Ok(Json(McpSettingsResponse {
         servers,
+        statuses,
         connected_servers,
         available_tools,
     }))
 }
 
+fn non_empty_lines(text: &str) -> Vec<&str> {
+    text.lines().map(str::trim).filter(|line| !line.is_empty()).collect()
+}
+
+// `KEY=value` or `Name: value` lines as a map, `None` if there are none
+fn parse_pairs(text: &str, separator: char) -> Result<Option<HashMap<String, String>>, StatusCode> {
+    let mut pairs = HashMap::new();
+    for line in non_empty_lines(text) {
+        let (key, value) = line.split_once(separator).ok_or(StatusCode::BAD_REQUEST)?;
+        pairs.insert(key.trim().to_string(), value.trim().to_string());
+    }
+    Ok((!pairs.is_empty()).then_some(pairs))
+}
+
+// `tool=seconds` lines as a map, `None` if there are none
+fn parse_tool_seconds(text: &str) -> Result<Option<HashMap<String, u64>>, StatusCode> {
+    let Some(pairs) = parse_pairs(text, '=')? else {
+        return Ok(None);
+    };
+    pairs
+        .into_iter()
+        .map(|(tool, seconds)| Ok((tool, seconds.parse().map_err(|_| StatusCode::BAD_REQUEST)?)))
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+fn mcp_server_config(form: &McpServerForm) -> Result<McpServerConfig, StatusCode> {
+    let optional = |text: &str| Some(text.trim().to_string()).filter(|text| !text.is_empty());
+    let timeout = match optional(&form.timeout) {
+        Some(timeout) => Some(timeout.parse().map_err(|_| StatusCode::BAD_REQUEST)?),
+        None => None,
+    };
+    let max_restarts = match optional(&form.max_restarts) {
+        Some(max_restarts) => Some(max_restarts.parse().map_err(|_| StatusCode::BAD_REQUEST)?),
+        None => None,
+    };
+    let mut config = McpServerConfig {
+        command: None,
+        args: None,
+        env: parse_pairs(&form.env, '=')?,
+        disabled: form.disabled.is_some().then_some(true),
+        timeout,
+        description: optional(&form.description),
+        transport: None,
+        url: None,
+        headers: None,
+        max_restarts,
+        cache_ttl: parse_tool_seconds(&form.cache_ttl)?,
+        tool_timeouts: parse_tool_seconds(&form.tool_timeouts)?,
+        roots: None,
+    };
+
+    match form.transport.as_str() {
+        "stdio" => {
+            config.transport = Some(TransportType::Stdio);
+            config.command = Some(optional(&form.command).ok_or(StatusCode::BAD_REQUEST)?);
+            let args = non_empty_lines(&form.args);
+            config.args = (!args.is_empty()).then(|| args.into_iter().map(String::from).collect());
+            let roots = non_empty_lines(&form.roots);
+            if !roots.iter().all(|root| roots::is_valid(root)) {
+                return Err(StatusCode::BAD_REQUEST);
+            }
+            config.roots = (!roots.is_empty()).then(|| roots.into_iter().map(String::from).collect());
+        }
+        "sse" | "http" => {
+            let url = form.url.trim();
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                return Err(StatusCode::BAD_REQUEST);
+            }
+            config.transport = Some(if form.transport == "sse" { TransportType::Sse } else { TransportType::Http });
+            config.url = Some(url.to_string());
+            config.headers = parse_pairs(&form.headers, ':')?;
+        }
+        _ => return Err(StatusCode::BAD_REQUEST),
+    }
+    Ok(config)
+}
+
+// Servers started from a command run on the host, so only admins may add
+// them unless `MCP_USER_COMMANDS` is `true`
+fn may_run_commands(user: &User) -> bool {
+    user.is_admin || dotenv::var("MCP_USER_COMMANDS").is_ok_and(|value| value == "true")
+}
+
+// Adds or replaces one of the user's servers and (re)starts it
 #[axum::debug_handler]
 pub async fn update_mcp_settings(
-    Form(settings): Form<McpServerSettings>,
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Form(form): Form<McpServerForm>,
 ) -> Result<Redirect, StatusCode> {
-    let mcp_manager = get_mcp_manager();
+    let user = current_user.unwrap();
 
-    // Convert settings to McpServerConfig
-    let transport = match settings.transport.as_deref() {
-        Some("stdio") => Some(crate::mcp::TransportType::Stdio),
-        Some("sse") => Some(crate::mcp::TransportType::Sse),
-        Some("http") => Some(crate::mcp::TransportType::Http),
-        _ => None,
-    };
+    let config = mcp_server_config(&form)?;
+    save_user_mcp_server(&state, &user, form.name.trim(), config).await?;
 
-    let server_config = McpServerConfig {
-        command: settings.command,
-        args: settings.args,
-        env: settings.env,
-        disabled: settings.disabled,
-        timeout: settings.timeout,
-        description: settings.description,
-        transport,
-        url: settings.url,
-        headers: settings.headers,
-    };
+    Ok(Redirect::to("/settings"))
+}
+
+async fn save_user_mcp_server(
+    state: &AppState,
+    user: &User,
+    name: &str,
+    config: McpServerConfig,
+) -> Result<(), StatusCode> {
+    if !custom_tools::is_valid_name(name) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if config.command.is_some() && !may_run_commands(user) {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    if let Some(url) = &config.url {
+        if let Err(e) = outbound::check_url(url).await {
+            tracing::warn!("Refused MCP server URL: {}", e);
+            return Err(StatusCode::BAD_REQUEST);
+        }
+    }
+    let json = serde_json::to_string(&config.sealed(&state.encryption)).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    // Loaded before saving, so a first load doesn't start the server as well
+    let manager = users::user_manager(&state.chat_repo, &state.encryption, user.id).await;
+    state
+        .chat_repo
+        .save_mcp_server(user.id, name, &json)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    if manager.update_roots(name, &config).await {
+        return Ok(());
+    }
+    manager.add_server_config(name.to_string(), config.clone()).await;
+    if config.disabled == Some(true) {
+        manager.shutdown_server(name).await.ok();
+    } else if let Err(e) = manager.initialize_server(name.to_string(), &config).await {
+        tracing::error!("Failed to start MCP server {} of user {}: {}", name, user.id, e);
+    }
+
+    Ok(())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct McpRegistryParams {
+    #[serde(default)]
+    q: String,
+}
 
-    // Add/update server configuration
-    mcp_manager.add_server_config(settings.name.clone(), server_config).await;
+// Servers from the MCP registry to add to the user's own
+#[axum::debug_handler]
+pub async fn mcp_registry(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Query(params): Query<McpRegistryParams>,
+) -> Result<Html<String>, StatusCode> {
+    let user = current_user.as_ref().unwrap();
+    let search = params.q.trim();
 
-    // Save configuration to file
-    let mcp_config_path = std::path::PathBuf::from("mcp.json");
-    if let Err(e) = mcp_manager.save_config(&mcp_config_path).await {
-        eprintln!("Failed to save MCP configuration: {}", e);
+    let mut context = Context::new();
+    match registry::search(search).await {
+        Ok(servers) => context.insert("servers", &servers),
+        Err(e) => {
+            tracing::error!("Failed to search the MCP registry: {}", e);
+            context.insert("servers", &Vec::<()>::new());
+            context.insert("error", &e.to_string());
+        }
     }
+    context.insert("q", search);
+    context.insert("may_run_commands", &may_run_commands(user));
+    let view = state.tera.render("views/mcp_registry.html", &context).unwrap();
+
+    let mut context = Context::new();
+    context.insert("view", &view);
+    context.insert("current_user", &current_user);
+    context.insert("with_footer", &true);
+    let rendered = state.tera.render("views/main.html", &context).unwrap();
+
+    Ok(Html(rendered))
+}
+
+// Adds a registry server to the user's own with the `input.NAME` values
+// given for its env or headers. The server is looked up again, so only what
+// the registry lists gets run
+#[axum::debug_handler]
+pub async fn install_mcp_server(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Form(form): Form<HashMap<String, String>>,
+) -> Result<Redirect, StatusCode> {
+    let user = current_user.unwrap();
+
+    let registry_name = form.get("registry_name").ok_or(StatusCode::BAD_REQUEST)?;
+    let server = registry::find(registry_name).await.map_err(|e| {
+        tracing::error!("Failed to find {} in the MCP registry: {}", registry_name, e);
+        match e {
+            RegistryError::NotFound(_) => StatusCode::NOT_FOUND,
+            _ => StatusCode::BAD_GATEWAY,
+        }
+    })?;
+    let values = form
+        .iter()
+        .filter_map(|(key, value)| Some((key.strip_prefix("input.")?.to_string(), value.clone())))
+        .collect();
+    let config = server.config(&values).map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    let name = form.get("name").map(|name| name.trim()).unwrap_or_default();
+    save_user_mcp_server(&state, &user, name, config).await?;
 
     Ok(Redirect::to("/settings"))
 }
 
 #[axum::debug_handler]
 pub async fn delete_mcp_server(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
     Form(settings): Form<McpServerSettings>,
 ) -> Result<Redirect, StatusCode> {
-    let mcp_manager = get_mcp_manager();
-
-    // Remove server configuration
-    mcp_manager.remove_server_config(&settings.name).await;
+    let id = current_user.unwrap().id;
 
-    // Shutdown the server if it's running
-    if let Err(e) = mcp_manager.shutdown_server(&settings.name).await {
-        eprintln!("Error shutting down server {}: {}", settings.name, e);
+    let deleted = state
+        .chat_repo
+        .delete_mcp_server(id, &settings.name)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !deleted {
+        return Err(StatusCode::NOT_FOUND);
     }
 
-    // Save configuration to file
-    let mcp_config_path = std::path::PathBuf::from("mcp.json");
-    if let Err(e) = mcp_manager.save_config(&mcp_config_path).await {
-        eprintln!("Failed to save MCP configuration: {}", e);
+    let manager = users::user_manager(&state.chat_repo, &state.encryption, id).await;
+    manager.remove_server_config(&settings.name).await;
+    if let Err(e) = manager.shutdown_server(&settings.name).await {
+        tracing::error!("Error shutting down server {}: {}", settings.name, e);
     }
 
     Ok(Redirect::to("/settings"))
@@ -203,40 +535,1410 @@ pub async fn delete_mcp_server(
 
 #[axum::debug_handler]
 pub async fn restart_mcp_server(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Form(settings): Form<McpServerSettings>,
+) -> Result<Redirect, StatusCode> {
+    let id = current_user.unwrap().id;
+
+    let manager = users::user_manager(&state.chat_repo, &state.encryption, id).await;
+    let server_configs = manager.get_server_configs().await;
+    let server_config = server_configs.get(&settings.name).ok_or(StatusCode::NOT_FOUND)?;
+    if let Err(e) = manager.initialize_server(settings.name.clone(), server_config).await {
+        tracing::error!("Failed to restart MCP server {}: {}", settings.name, e);
+    }
+
+    Ok(Redirect::to("/settings"))
+}
+
+fn mcp_oauth_redirect_uri() -> String {
+    format!("{}/settings/mcp/oauth/callback", app_url())
+}
+
+// Starts the server again with the tokens it now has, or no longer has
+async fn reconnect_mcp_server(manager: &McpManager, name: &str) {
+    let Some(config) = manager.get_server_configs().await.remove(name) else {
+        return;
+    };
+    if config.disabled == Some(true) {
+        return;
+    }
+    if let Err(e) = manager.initialize_server(name.to_string(), &config).await {
+        tracing::error!("Failed to restart MCP server {}: {}", name, e);
+    }
+}
+
+// Sends the browser to authorize one of the user's HTTP servers at its
+// authorization server, which redirects back to `mcp_oauth_callback`
+#[axum::debug_handler]
+pub async fn authorize_mcp_server(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
     Form(settings): Form<McpServerSettings>,
 ) -> Result<Redirect, StatusCode> {
-    let mcp_manager = get_mcp_manager();
+    let id = current_user.unwrap().id;
+
+    let manager = users::user_manager(&state.chat_repo, &state.encryption, id).await;
+    let config = manager
+        .get_server_configs()
+        .await
+        .remove(&settings.name)
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let (Some(TransportType::Http), Some(url)) = (&config.transport, &config.url) else {
+        return Err(StatusCode::BAD_REQUEST);
+    };
+    let authorize_url = mcp_oauth::authorize_url(id, &settings.name, url, &mcp_oauth_redirect_uri())
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to authorize MCP server {} of user {}: {}", settings.name, id, e);
+            StatusCode::BAD_GATEWAY
+        })?;
+
+    Ok(Redirect::to(authorize_url.as_str()))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct McpOAuthCallback {
+    code: Option<String>,
+    state: Option<String>,
+}
 
-    // Get server configuration
-    let server_configs = mcp_manager.get_server_configs().await;
-    if let Some(server_config) = server_configs.get(&settings.name) {
-        // Shutdown the server if it's running
-        mcp_manager.shutdown_server(&settings.name).await.ok();
+#[axum::debug_handler]
+pub async fn mcp_oauth_callback(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Query(callback): Query<McpOAuthCallback>,
+) -> Result<Redirect, StatusCode> {
+    let id = current_user.unwrap().id;
 
-        // Restart the server
-        if let Err(e) = mcp_manager.initialize_server(settings.name.clone(), server_config).await {
-            eprintln!("Failed to restart MCP server {}: {}", settings.name, e);
+    // No code when the user declined
+    let (Some(code), Some(attempt)) = (callback.code, callback.state) else {
+        return Err(StatusCode::BAD_REQUEST);
+    };
+    let (name, tokens) = mcp_oauth::finish(id, &attempt, &code).await.map_err(|e| {
+        tracing::error!("Failed to authorize an MCP server of user {}: {}", id, e);
+        match e {
+            McpOAuthError::UnknownAttempt => StatusCode::BAD_REQUEST,
+            _ => StatusCode::BAD_GATEWAY,
         }
+    })?;
+    let stored = serde_json::to_string(&tokens).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let stored = state.encryption.encrypt(&stored);
+
+    // Loaded before saving, so a first load doesn't start the server as well
+    let manager = users::user_manager(&state.chat_repo, &state.encryption, id).await;
+    let saved = state
+        .chat_repo
+        .save_mcp_server_oauth(id, &name, Some(&stored))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // Deleted while the user was away authorizing it
+    if !saved {
+        return Err(StatusCode::NOT_FOUND);
     }
+    mcp_oauth::sign_in(&manager.client_name(&name), id, &name, tokens, &state.chat_repo, &state.encryption);
+    reconnect_mcp_server(&manager, &name).await;
 
     Ok(Redirect::to("/settings"))
 }
 
+// Forgets the tokens of one of the user's servers
 #[axum::debug_handler]
-pub async fn settings(
+pub async fn sign_out_mcp_server(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Form(settings): Form<McpServerSettings>,
+) -> Result<Redirect, StatusCode> {
+    let id = current_user.unwrap().id;
+
+    let saved = state
+        .chat_repo
+        .save_mcp_server_oauth(id, &settings.name, None)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !saved {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    let manager = users::user_manager(&state.chat_repo, &state.encryption, id).await;
+    mcp_oauth::sign_out(&manager.client_name(&settings.name));
+    reconnect_mcp_server(&manager, &settings.name).await;
+
+    Ok(Redirect::to("/settings"))
+}
+
+// The log of one of the user's servers, or for admins of a shared one, as
+// their output may show the host's setup
+async fn server_log(state: &AppState, user: &User, server: &str) -> Result<Arc<ServerLog>, StatusCode> {
+    let own = users::user_manager(&state.chat_repo, &state.encryption, user.id).await;
+    if own.has_server(server).await {
+        return Ok(logs::server_log(&own.client_name(server)));
+    }
+    if !get_mcp_manager().has_server(server).await {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    if !user.is_admin {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(logs::server_log(server))
+}
+
+// A page following the server's log as it's written
+#[axum::debug_handler]
+pub async fn mcp_server_logs(
     State(state): State<Arc<AppState>>,
     Extension(current_user): Extension<Option<User>>,
+    Path(server): Path<String>,
 ) -> Result<Html<String>, StatusCode> {
-    let user = current_user.as_ref().unwrap();
+    let user = current_user.as_ref().ok_or(StatusCode::UNAUTHORIZED)?;
+    server_log(&state, user, &server).await?;
 
     let mut context = Context::new();
-    context.insert("openai_api_key", &user.openai_api_key);
-    context.insert("base_url", &user.base_url);
-    context.insert("model", &user.model);
-    context.insert("system_prompt", &user.system_prompt);
-    context.insert("temperature", &user.temperature);
-    context.insert("top_p", &user.top_p);
-    context.insert("max_tokens", &user.max_tokens);
+    context.insert("server", &server);
+    let view = state.tera.render("views/mcp_logs.html", &context).map_err(|e| {
+        tracing::error!("Failed to render the log of {}: {}", server, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let mut context = Context::new();
+    context.insert("view", &view);
+    context.insert("current_user", &current_user);
+    context.insert("with_footer", &true);
+    let rendered = state.tera.render("views/main.html", &context).map_err(|e| {
+        tracing::error!("Failed to render the log of {}: {}", server, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(Html(rendered))
+}
+
+// The kept lines, then each new one as it's written
+#[axum::debug_handler]
+pub async fn stream_mcp_server_logs(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Path(server): Path<String>,
+) -> Result<Sse<impl futures::Stream<Item = Result<Event, axum::Error>>>, StatusCode> {
+    let user = current_user.ok_or(StatusCode::UNAUTHORIZED)?;
+    let (kept, live) = server_log(&state, &user, &server).await?.follow();
+
+    let kept = futures::stream::iter(kept.into_iter().map(|line| Ok(Event::default().data(line.to_string()))));
+    let live = futures::stream::unfold(live, |mut live| async move {
+        let data = match live.recv().await {
+            Ok(line) => line.to_string(),
+            Err(tokio::sync::broadcast::error::RecvError::Lagged(skipped)) => format!("({} lines skipped)", skipped),
+            Err(tokio::sync::broadcast::error::RecvError::Closed) => return None,
+        };
+        Some((Ok(Event::default().data(data)), live))
+    });
+
+    Ok(Sse::new(futures::StreamExt::chain(kept, live)))
+}
+
+#[axum::debug_handler]
+pub async fn download_mcp_server_logs(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Path(server): Path<String>,
+) -> Result<Response, StatusCode> {
+    let user = current_user.ok_or(StatusCode::UNAUTHORIZED)?;
+    let log = server_log(&state, &user, &server).await?;
+
+    let mut text = String::new();
+    for line in log.lines() {
+        text.push_str(&line.to_string());
+        text.push('\n');
+    }
+    // Server names from mcp.json may hold anything
+    let file_name: String = server
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    let disposition = format!("attachment; filename=\"mcp-{}.log\"", file_name);
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/plain; charset=utf-8".to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        text,
+    )
+        .into_response())
+}
+
+// Copies the servers of mcp.json into the admin's own, leaving any they
+// already have of the same name as they are
+#[axum::debug_handler]
+pub async fn import_mcp_servers(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Redirect, StatusCode> {
+    let user = current_user.unwrap();
+    if !user.is_admin {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let config = crate::mcp::McpConfig::load_from_file(&std::path::PathBuf::from("mcp.json"))
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    let manager = users::user_manager(&state.chat_repo, &state.encryption, user.id).await;
+    for (name, server_config) in config.mcp_servers {
+        let json = serde_json::to_string(&server_config.sealed(&state.encryption))
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        let added = state
+            .chat_repo
+            .add_mcp_server(user.id, &name, &json)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        if !added {
+            continue;
+        }
+        manager.add_server_config(name.clone(), server_config.clone()).await;
+        if server_config.disabled != Some(true) {
+            if let Err(e) = manager.initialize_server(name.clone(), &server_config).await {
+                tracing::error!("Failed to start MCP server {} of user {}: {}", name, user.id, e);
+            }
+        }
+    }
+
+    Ok(Redirect::to("/settings"))
+}
+
+#[axum::debug_handler]
+pub async fn save_custom_tool(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Form(tool): Form<CustomToolSettings>,
+) -> Result<Redirect, StatusCode> {
+    let id = current_user.unwrap().id;
+
+    let name = tool.name.trim();
+    if !custom_tools::is_valid_name(name) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    if let Err(e) = outbound::check_url(&tool.url).await {
+        tracing::warn!("Refused custom tool URL: {}", e);
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    // The schema is sent to the model as-is, so it has to be a JSON object
+    let parameters = match tool.parameters.as_deref().map(str::trim) {
+        Some(schema) if !schema.is_empty() => serde_json::from_str::<serde_json::Value>(schema)
+            .ok()
+            .filter(|value| value.is_object())
+            .ok_or(StatusCode::BAD_REQUEST)?
+            .to_string(),
+        _ => r#"{"type":"object","properties":{}}"#.to_string(),
+    };
+    let auth_header = tool
+        .auth_header
+        .as_deref()
+        .filter(|h| !h.trim().is_empty())
+        .map(|h| state.encryption.encrypt(h));
+
+    state
+        .chat_repo
+        .save_custom_tool(id, name, &tool.description, &parameters, &tool.url, auth_header.as_deref())
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to save custom tool {}: {}", name, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(Redirect::to("/settings"))
+}
+
+#[axum::debug_handler]
+pub async fn delete_custom_tool(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Form(form): Form<DeleteCustomToolForm>,
+) -> Result<Redirect, StatusCode> {
+    let id = current_user.unwrap().id;
+
+    state
+        .chat_repo
+        .delete_custom_tool(id, form.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Redirect::to("/settings"))
+}
+
+// Empty means "not set"
+fn parse_optional<T: std::str::FromStr>(value: &str) -> Result<Option<T>, StatusCode> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(None);
+    }
+    value.parse().map(Some).map_err(|_| StatusCode::BAD_REQUEST)
+}
+
+#[axum::debug_handler]
+pub async fn save_agent(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Form(form): Form<AgentSettings>,
+) -> Result<Redirect, StatusCode> {
+    let user = current_user.unwrap();
+    let id = user.id;
+
+    let name = form.name.trim();
+    let model = form.model.trim();
+    if name.is_empty() || model.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let temperature = parse_optional::<f64>(&form.temperature)?;
+    let top_p = parse_optional::<f64>(&form.top_p)?;
+    let max_tokens = parse_optional::<i64>(&form.max_tokens)?;
+    if temperature.is_some_and(|t| !(0.0..=2.0).contains(&t))
+        || top_p.is_some_and(|p| !(0.0..=1.0).contains(&p))
+        || max_tokens.is_some_and(|n| n < 1)
+    {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let stop_sequences = parse_stop_sequences(&form.stop_sequences).ok_or(StatusCode::BAD_REQUEST)?;
+    let allow_tools: Vec<&str> = non_empty_lines(&form.allow_tools);
+    let allow_tools = if allow_tools.is_empty() {
+        None
+    } else {
+        serde_json::to_string(&allow_tools).ok()
+    };
+
+    // Only the org's admins share agents with its members
+    let org_id = match (form.share.is_some(), user.current_org_id) {
+        (true, Some(org_id)) => {
+            let role = state
+                .chat_repo
+                .get_org_role(org_id, id)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            if role < Some(OrgRole::Admin) {
+                return Err(StatusCode::FORBIDDEN);
+            }
+            Some(org_id)
+        }
+        _ => None,
+    };
+
+    let agent = Agent {
+        id: form.id.unwrap_or(0),
+        user_id: id,
+        org_id,
+        name: name.to_string(),
+        model: model.to_string(),
+        system_prompt: Some(form.system_prompt.trim().to_string()).filter(|p| !p.is_empty()),
+        temperature,
+        top_p,
+        max_tokens,
+        stop_sequences: if stop_sequences.is_empty() {
+            None
+        } else {
+            serde_json::to_string(&stop_sequences).ok()
+        },
+        tools_enabled: form.tools_enabled.is_some(),
+        allow_tools,
+        version: 0,
+    };
+
+    // An edit keeps the agent's name and only applies to the version it was made on
+    if let (Some(agent_id), Some(version)) = (form.id, form.version) {
+        let saved = state.chat_repo.update_agent(&agent, version).await.map_err(|e| {
+            tracing::error!("Failed to update agent {}: {}", agent_id, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        if !saved {
+            return Ok(Redirect::to(&format!("/settings?edit_agent={}&conflict=agent#agent-form", agent_id)));
+        }
+        return Ok(Redirect::to("/settings"));
+    }
+
+    state.chat_repo.save_agent(&agent).await.map_err(|e| {
+        tracing::error!("Failed to save agent {}: {}", name, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(Redirect::to("/settings"))
+}
+
+#[axum::debug_handler]
+pub async fn delete_agent(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Form(form): Form<DeleteAgentForm>,
+) -> Result<Redirect, StatusCode> {
+    let id = current_user.unwrap().id;
+
+    state
+        .chat_repo
+        .delete_agent(id, form.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Redirect::to("/settings"))
+}
+
+// From the trash page
+#[axum::debug_handler]
+pub async fn restore_agent(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Form(form): Form<DeleteAgentForm>,
+) -> Result<Redirect, StatusCode> {
+    let id = current_user.unwrap().id;
+
+    state
+        .chat_repo
+        .restore_agent(id, form.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Redirect::to("/chat/trash"))
+}
+
+#[axum::debug_handler]
+pub async fn save_webhook(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Form(form): Form<WebhookForm>,
+) -> Result<Redirect, StatusCode> {
+    let id = current_user.unwrap().id;
+
+    let url = form.url.trim();
+    if let Err(e) = outbound::check_url(url).await {
+        tracing::warn!("Refused webhook URL: {}", e);
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let events = [
+        (webhooks::CHAT_CREATED, form.chat_created.is_some()),
+        (webhooks::GENERATION_COMPLETED, form.generation_completed.is_some()),
+        (webhooks::TOOL_EXECUTED, form.tool_executed.is_some()),
+    ]
+    .into_iter()
+    .filter_map(|(event, selected)| selected.then_some(event))
+    .collect::<Vec<_>>();
+    if events.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    state
+        .chat_repo
+        .create_webhook(id, url, &events.join(","))
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to save webhook {}: {}", url, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(Redirect::to("/settings"))
+}
+
+#[axum::debug_handler]
+pub async fn delete_webhook(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Form(form): Form<DeleteWebhookForm>,
+) -> Result<Redirect, StatusCode> {
+    let id = current_user.unwrap().id;
+
+    state
+        .chat_repo
+        .delete_webhook(id, form.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Redirect::to("/settings"))
+}
+
+// Token list for the API card; `new_token` is the secret of a token just
+// created, shown this one time
+async fn render_api_tokens(
+    state: &AppState,
+    user_id: i64,
+    new_token: Option<&str>,
+) -> Result<Html<String>, StatusCode> {
+    let api_tokens = state
+        .chat_repo
+        .get_api_tokens(user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let mut context = Context::new();
+    context.insert("api_tokens", &api_tokens);
+    context.insert("new_token", &new_token);
+    let html = state
+        .tera
+        .render("htmx_updates/api_tokens.html", &context)
+        .map_err(|e| {
+            tracing::error!("Failed to render API tokens: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(Html(html))
+}
+
+#[axum::debug_handler]
+pub async fn create_api_token(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Extension(source): Extension<AuditSource>,
+    Form(form): Form<ApiTokenForm>,
+) -> Result<Html<String>, StatusCode> {
+    let id = current_user.unwrap().id;
+
+    let name = form.name.trim();
+    if name.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let expires_in_days = match form.expires_in_days {
+        0 => None,
+        days if days > 0 => Some(days),
+        _ => return Err(StatusCode::BAD_REQUEST),
+    };
+
+    let token = state
+        .chat_repo
+        .create_api_token(id, name, form.scope, expires_in_days)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to create API token {}: {}", name, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    audit::record(&state, Some(id), AuditEvent::ApiTokenCreated, Some(name), source).await;
+
+    render_api_tokens(&state, id, Some(&token)).await
+}
+
+#[axum::debug_handler]
+pub async fn delete_api_token(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Extension(source): Extension<AuditSource>,
+    Path(token_id): Path<i64>,
+) -> Result<Html<String>, StatusCode> {
+    let id = current_user.unwrap().id;
+
+    let deleted = state
+        .chat_repo
+        .delete_api_token(id, token_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if deleted > 0 {
+        let detail = format!("token {}", token_id);
+        audit::record(&state, Some(id), AuditEvent::ApiTokenDeleted, Some(&detail), source).await;
+    }
+
+    render_api_tokens(&state, id, None).await
+}
+
+// Stops running an always allowed tool without asking
+pub async fn revoke_tool_approval(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Path(approval_id): Path<i64>,
+) -> Result<Html<String>, StatusCode> {
+    let id = current_user.unwrap().id;
+
+    state
+        .chat_repo
+        .revoke_tool_approval(id, approval_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let tool_approvals = state
+        .chat_repo
+        .get_tool_approvals(id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let mut context = Context::new();
+    context.insert("tool_approvals", &tool_approvals);
+    let html = state
+        .tera
+        .render("htmx_updates/tool_approvals.html", &context)
+        .map_err(|e| {
+            tracing::error!("Failed to render tool approvals: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(Html(html))
+}
+
+async fn load_sessions(state: &AppState, user_id: i64, cookies: &Cookies) -> Result<Vec<Session>, StatusCode> {
+    let current = cookies
+        .get(SESSION_COOKIE)
+        .map(|session| password::hash_token(session.value()))
+        .unwrap_or_default();
+    state
+        .chat_repo
+        .get_sessions(user_id, &current, session::idle_hours())
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+#[axum::debug_handler]
+pub async fn sessions(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    cookies: Cookies,
+) -> Result<Html<String>, StatusCode> {
+    let id = current_user.as_ref().unwrap().id;
+
+    let mut context = Context::new();
+    context.insert("sessions", &load_sessions(&state, id, &cookies).await?);
+    let view = state.tera.render("views/sessions.html", &context).unwrap();
+
+    let mut context = Context::new();
+    context.insert("view", &view);
+    context.insert("current_user", &current_user);
+    context.insert("with_footer", &true);
+    let rendered = state.tera.render("views/main.html", &context).unwrap();
+
+    Ok(Html(rendered))
+}
+
+#[axum::debug_handler]
+pub async fn revoke_session(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Extension(source): Extension<AuditSource>,
+    cookies: Cookies,
+    Path(session_id): Path<i64>,
+) -> Result<Html<String>, StatusCode> {
+    let id = current_user.unwrap().id;
+
+    let deleted = state
+        .chat_repo
+        .delete_session(id, session_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if deleted > 0 {
+        let detail = format!("session {}", session_id);
+        audit::record(&state, Some(id), AuditEvent::SessionRevoked, Some(&detail), source).await;
+    }
+
+    let mut context = Context::new();
+    context.insert("sessions", &load_sessions(&state, id, &cookies).await?);
+    let html = state
+        .tera
+        .render("htmx_updates/sessions.html", &context)
+        .map_err(|e| {
+            tracing::error!("Failed to render sessions: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    Ok(Html(html))
+}
+
+// "Log out everywhere", this browser included
+#[axum::debug_handler]
+pub async fn revoke_all_sessions(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Extension(source): Extension<AuditSource>,
+    cookies: Cookies,
+) -> Result<Redirect, StatusCode> {
+    let id = current_user.unwrap().id;
+
+    state
+        .chat_repo
+        .delete_sessions(id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    end_session(&cookies);
+    audit::record(&state, Some(id), AuditEvent::SessionRevoked, Some("all sessions"), source).await;
+
+    Ok(Redirect::to("/login"))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ProfileParams {
+    notice: Option<String>,
+}
+
+#[axum::debug_handler]
+pub async fn profile(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Query(params): Query<ProfileParams>,
+) -> Result<Html<String>, StatusCode> {
+    let mut context = Context::new();
+    context.insert("user", &current_user);
+    context.insert("notice", &params.notice);
+    context.insert("max_display_name", &MAX_DISPLAY_NAME);
+    let view = state.tera.render("views/profile.html", &context).unwrap();
+
+    let mut context = Context::new();
+    context.insert("view", &view);
+    context.insert("current_user", &current_user);
+    context.insert("with_footer", &true);
+    let rendered = state.tera.render("views/main.html", &context).unwrap();
+
+    Ok(Html(rendered))
+}
+
+const MAX_DISPLAY_NAME: usize = 50;
+
+#[derive(Deserialize, Debug)]
+pub struct ProfileForm {
+    display_name: String,
+}
+
+// An empty name goes back to showing the email
+#[axum::debug_handler]
+pub async fn update_profile(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Form(form): Form<ProfileForm>,
+) -> Result<Redirect, StatusCode> {
+    let id = current_user.unwrap().id;
+
+    let display_name = form.display_name.trim();
+    if display_name.chars().count() > MAX_DISPLAY_NAME {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let display_name = Some(display_name).filter(|name| !name.is_empty());
+
+    state
+        .chat_repo
+        .update_display_name(id, display_name)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to update profile of user {}: {}", id, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(Redirect::to("/settings/profile?notice=saved"))
+}
+
+const AVATAR_DIR: &str = "uploads/avatars";
+
+// Removes an avatar written by `upload_avatar`, given its URL
+async fn remove_avatar(avatar: Option<String>) {
+    let Some(file) = avatar
+        .as_deref()
+        .and_then(|avatar| avatar.strip_prefix("/uploads/avatars/"))
+        .filter(|file| !file.contains('/') && !file.contains(".."))
+    else {
+        return;
+    };
+    let path = std::path::Path::new(AVATAR_DIR).join(file);
+    match tokio::fs::remove_file(&path).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            tracing::error!("Failed to remove {}: {}", path.display(), e);
+        }
+        _ => {}
+    }
+}
+
+// The image comes with the square picked in the form, see `avatar::crop_box`,
+// and is stored cropped and scaled down
+#[axum::debug_handler]
+pub async fn upload_avatar(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    mut multipart: Multipart,
+) -> Result<Redirect, StatusCode> {
+    let id = current_user.unwrap().id;
+
+    let (mut data, mut zoom, mut x, mut y) = (None, 1.0, 0.5, 0.5);
+    while let Some(field) = multipart.next_field().await.map_err(|_| StatusCode::BAD_REQUEST)? {
+        let name = field.name().unwrap_or_default().to_string();
+        if name == "avatar" {
+            data = Some(field.bytes().await.map_err(|_| StatusCode::BAD_REQUEST)?);
+            continue;
+        }
+        let value = field.text().await.map_err(|_| StatusCode::BAD_REQUEST)?;
+        let target = match name.as_str() {
+            "zoom" => &mut zoom,
+            "x" => &mut x,
+            "y" => &mut y,
+            _ => continue,
+        };
+        *target = value.trim().parse().map_err(|_| StatusCode::BAD_REQUEST)?;
+    }
+    let data = data.filter(|data| !data.is_empty()).ok_or(StatusCode::BAD_REQUEST)?;
+
+    // Decoding a large photo takes a while
+    let png = tokio::task::spawn_blocking(move || avatar::make_avatar(&data, zoom, x, y))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|e| {
+            tracing::error!("Failed to read avatar of user {}: {}", id, e);
+            StatusCode::UNPROCESSABLE_ENTITY
+        })?;
+
+    let file = format!("{}-{}.png", id, uuid::Uuid::new_v4().simple());
+    let saved = match tokio::fs::create_dir_all(AVATAR_DIR).await {
+        Ok(()) => tokio::fs::write(std::path::Path::new(AVATAR_DIR).join(&file), &png).await,
+        Err(e) => Err(e),
+    };
+    saved.map_err(|e| {
+        tracing::error!("Failed to save avatar of user {}: {}", id, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let previous = state
+        .chat_repo
+        .set_avatar(id, Some(&format!("/{}/{}", AVATAR_DIR, file)))
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to set avatar of user {}: {}", id, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    remove_avatar(previous).await;
+
+    Ok(Redirect::to("/settings/profile?notice=avatar"))
+}
+
+#[axum::debug_handler]
+pub async fn delete_avatar(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Redirect, StatusCode> {
+    let id = current_user.unwrap().id;
+
+    let previous = state
+        .chat_repo
+        .set_avatar(id, None)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    remove_avatar(previous).await;
+
+    Ok(Redirect::to("/settings/profile?notice=removed"))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AuditFilter {
+    event: Option<String>,
+    // Part of the account's email, admins only
+    email: Option<String>,
+}
+
+// Entries shown at once; the filters narrow down older ones
+const AUDIT_LOG_LIMIT: i64 = 200;
+
+// The audit log page, for one user or with `user_id` unset for everyone
+pub(super) async fn render_audit_log(
+    state: &AppState,
+    current_user: &Option<User>,
+    user_id: Option<i64>,
+    filter: AuditFilter,
+) -> Result<Html<String>, StatusCode> {
+    let event = filter.event.as_deref().and_then(AuditEvent::parse);
+    let email = filter
+        .email
+        .as_deref()
+        .map(str::trim)
+        .filter(|email| user_id.is_none() && !email.is_empty());
+
+    let entries = state
+        .chat_repo
+        .get_audit_log(user_id, event.map(AuditEvent::as_str), email, AUDIT_LOG_LIMIT)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to load audit log: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let mut context = Context::new();
+    context.insert("entries", &entries);
+    context.insert("events", &AuditEvent::names());
+    context.insert("event", &event.map(AuditEvent::as_str));
+    context.insert("email", &email);
+    context.insert("admin", &user_id.is_none());
+    context.insert("limit", &AUDIT_LOG_LIMIT);
+    let view = state.tera.render("views/audit_log.html", &context).unwrap();
+
+    let mut context = Context::new();
+    context.insert("view", &view);
+    context.insert("current_user", current_user);
+    context.insert("with_footer", &true);
+    let rendered = state.tera.render("views/main.html", &context).unwrap();
+
+    Ok(Html(rendered))
+}
+
+#[axum::debug_handler]
+pub async fn audit_log(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Query(filter): Query<AuditFilter>,
+) -> Result<Html<String>, StatusCode> {
+    let id = current_user.as_ref().unwrap().id;
+    render_audit_log(&state, &current_user, Some(id), filter).await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ToolExecutionFilter {
+    tool: Option<String>,
+    outcome: Option<String>,
+}
+
+impl ToolExecutionFilter {
+    fn tool(&self) -> Option<&str> {
+        self.tool.as_deref().filter(|tool| !tool.is_empty())
+    }
+
+    fn outcome(&self) -> Option<&str> {
+        self.outcome
+            .as_deref()
+            .filter(|outcome| matches!(*outcome, "success" | "error"))
+    }
+}
+
+// Calls shown at once and exported
+const TOOL_EXECUTION_LIMIT: i64 = 200;
+const TOOL_EXECUTION_EXPORT_LIMIT: i64 = 10_000;
+
+#[axum::debug_handler]
+pub async fn tool_executions(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Query(filter): Query<ToolExecutionFilter>,
+) -> Result<Html<String>, StatusCode> {
+    let id = current_user.as_ref().unwrap().id;
+
+    let executions = state
+        .chat_repo
+        .get_tool_executions(id, filter.tool(), filter.outcome(), TOOL_EXECUTION_LIMIT)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to load tool executions: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let tools = state.chat_repo.get_executed_tool_names(id).await.map_err(|e| {
+        tracing::error!("Failed to load executed tools: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let mut context = Context::new();
+    context.insert("executions", &executions);
+    context.insert("tools", &tools);
+    context.insert("tool", &filter.tool());
+    context.insert("outcome", &filter.outcome());
+    context.insert("limit", &TOOL_EXECUTION_LIMIT);
+    let view = state.tera.render("views/tool_executions.html", &context).unwrap();
+
+    let mut context = Context::new();
+    context.insert("view", &view);
+    context.insert("current_user", &current_user);
+    context.insert("with_footer", &true);
+    let rendered = state.tera.render("views/main.html", &context).unwrap();
+
+    Ok(Html(rendered))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ToolAnalyticsParams {
+    days: Option<i64>,
+}
+
+// The periods the dashboard covers, in days
+const TOOL_ANALYTICS_PERIODS: [i64; 4] = [7, 30, 90, 365];
+
+// Top tools, failure rates and latencies from `tool_executions`, by tool and
+// by server
+#[axum::debug_handler]
+pub async fn tool_analytics(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Query(params): Query<ToolAnalyticsParams>,
+) -> Result<Html<String>, StatusCode> {
+    let id = current_user.as_ref().unwrap().id;
+    let days = params
+        .days
+        .filter(|days| TOOL_ANALYTICS_PERIODS.contains(days))
+        .unwrap_or(30);
+
+    let tools = state.chat_repo.tool_stats(id, days).await.map_err(|e| {
+        tracing::error!("Failed to load tool stats: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let servers = state.chat_repo.server_tool_stats(id, days).await.map_err(|e| {
+        tracing::error!("Failed to load server tool stats: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let mut context = Context::new();
+    context.insert("tools", &tools);
+    context.insert("servers", &servers);
+    context.insert("calls", &tools.iter().map(|tool| tool.calls).sum::<i64>());
+    context.insert("failures", &tools.iter().map(|tool| tool.failures).sum::<i64>());
+    context.insert("days", &days);
+    context.insert("periods", &TOOL_ANALYTICS_PERIODS);
+    let view = state.tera.render("views/tool_analytics.html", &context).unwrap();
+
+    let mut context = Context::new();
+    context.insert("view", &view);
+    context.insert("current_user", &current_user);
+    context.insert("with_footer", &true);
+    let rendered = state.tera.render("views/main.html", &context).unwrap();
+
+    Ok(Html(rendered))
+}
+
+#[axum::debug_handler]
+pub async fn export_tool_executions(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Query(filter): Query<ToolExecutionFilter>,
+) -> Result<Response, StatusCode> {
+    let id = current_user.unwrap().id;
+
+    let executions = state
+        .chat_repo
+        .get_tool_executions(id, filter.tool(), filter.outcome(), TOOL_EXECUTION_EXPORT_LIMIT)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to load tool executions: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let csv = to_csv(&executions).map_err(|e| {
+        tracing::error!("Failed to write tool executions CSV: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/csv; charset=utf-8".to_string()),
+            (header::CONTENT_DISPOSITION, "attachment; filename=\"tool-executions.csv\"".to_string()),
+        ],
+        csv,
+    )
+        .into_response())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DeleteAccountForm {
+    email: String,
+}
+
+// Deletes the user with everything they own, see `ChatRepository::delete_user`,
+// then the uploads no one else refers to. Returns the number of users deleted
+pub(super) async fn purge_account(state: &AppState, user_id: i64) -> Result<u64, StatusCode> {
+    let uploads = state.chat_repo.user_uploads(user_id).await.map_err(|e| {
+        tracing::error!("Failed to list uploads of user {}: {}", user_id, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let avatar = state.chat_repo.get_avatar(user_id).await.map_err(|e| {
+        tracing::error!("Failed to find avatar of user {}: {}", user_id, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let exports = state
+        .chat_repo
+        .delete_data_exports_before(user_id, i64::MAX)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to remove data exports of user {}: {}", user_id, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    let deleted = state.chat_repo.delete_user(user_id).await.map_err(|e| {
+        tracing::error!("Failed to delete user {}: {}", user_id, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    for file in uploads {
+        let path = std::path::Path::new("uploads").join(&file);
+        match tokio::fs::remove_file(&path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                tracing::error!("Failed to remove {}: {}", path.display(), e);
+            }
+            _ => {}
+        }
+    }
+    for file in exports {
+        let _ = tokio::fs::remove_file(std::path::Path::new(EXPORT_DIR).join(file)).await;
+    }
+    remove_avatar(avatar).await;
+
+    Ok(deleted)
+}
+
+// The user confirms by typing their email. An admin impersonating the user
+// deletes from the admin panel instead
+#[axum::debug_handler]
+pub async fn delete_account(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    cookies: Cookies,
+    Form(form): Form<DeleteAccountForm>,
+) -> Result<Redirect, StatusCode> {
+    let user = current_user.unwrap();
+    if user.impersonator.is_some() {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    if !form.email.trim().eq_ignore_ascii_case(&user.email) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    purge_account(&state, user.id).await?;
+    end_session(&cookies);
+    tracing::warn!("User {} deleted their account", user.id);
+
+    Ok(Redirect::to("/"))
+}
+
+#[axum::debug_handler]
+pub async fn export_analytics(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Query(params): Query<AnalyticsExportParams>,
+) -> Result<Response, StatusCode> {
+    let id = current_user.unwrap().id;
+
+    if params.from > params.to {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let from = params.from.to_string();
+    let to = params.to.to_string();
+
+    let repo = &state.chat_repo;
+    let (name, csv) = match params.report {
+        AnalyticsReport::Daily => ("daily-usage", repo.daily_usage(id, &from, &to).await.map(|rows| to_csv(&rows))),
+        AnalyticsReport::Costs => ("daily-costs", repo.daily_costs(id, &from, &to).await.map(|rows| to_csv(&rows))),
+        AnalyticsReport::Models => ("model-usage", repo.model_usage(id, &from, &to).await.map(|rows| to_csv(&rows))),
+        AnalyticsReport::Tools => ("tool-usage", repo.tool_usage(id, &from, &to).await.map(|rows| to_csv(&rows))),
+    };
+    let csv = csv
+        .map_err(|e| {
+            tracing::error!("Failed to load analytics: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .map_err(|e| {
+            tracing::error!("Failed to write analytics CSV: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    let disposition = format!("attachment; filename=\"{}-{}-{}.csv\"", name, from, to);
+    Ok((
+        [
+            (header::CONTENT_TYPE, "text/csv; charset=utf-8".to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        csv,
+    )
+        .into_response())
+}
+
+async fn render_data_export(state: &AppState, user_id: i64) -> Result<Html<String>, StatusCode> {
+    let export = state.chat_repo.get_latest_data_export(user_id).await.map_err(|e| {
+        tracing::error!("Failed to load data export of user {}: {}", user_id, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    let mut context = Context::new();
+    context.insert("export", &export);
+    let rendered = state.tera.render("htmx_updates/data_export.html", &context).unwrap();
+    Ok(Html(rendered))
+}
+
+// The state of the latest export, polled while it's running
+#[axum::debug_handler]
+pub async fn data_export_status(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Html<String>, StatusCode> {
+    render_data_export(&state, current_user.unwrap().id).await
+}
+
+// Starts a zip of everything the user stored, see `data_export`. An admin
+// impersonating the user can't take it
+#[axum::debug_handler]
+pub async fn start_data_export(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Html<String>, StatusCode> {
+    let user = current_user.unwrap();
+    if user.impersonator.is_some() {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    let user_id = user.id;
+
+    let running = state
+        .chat_repo
+        .get_latest_data_export(user_id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to load data export of user {}: {}", user_id, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .is_some_and(|export| export.status == "running");
+    if !running {
+        data_export::start(state.clone(), user).await.map_err(|e| {
+            tracing::error!("Failed to start data export of user {}: {}", user_id, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+    }
+
+    render_data_export(&state, user_id).await
+}
+
+#[axum::debug_handler]
+pub async fn download_data_export(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+) -> Result<Response, StatusCode> {
+    let user = current_user.unwrap();
+    if user.impersonator.is_some() {
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    let export = state
+        .chat_repo
+        .get_latest_data_export(user.id)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to load data export of user {}: {}", user.id, e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let file = export.file.ok_or(StatusCode::NOT_FOUND)?;
+    let zip = tokio::fs::File::open(std::path::Path::new(EXPORT_DIR).join(&file))
+        .await
+        .map_err(|_| StatusCode::NOT_FOUND)?;
+
+    let disposition = format!(
+        "attachment; filename=\"rustgpt-export-{}.zip\"",
+        export.created_at.format("%Y-%m-%d")
+    );
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/zip".to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        axum::body::Body::from_stream(tokio_util::io::ReaderStream::new(zip)),
+    )
+        .into_response())
+}
+
+#[axum::debug_handler]
+pub async fn settings(
+    State(state): State<Arc<AppState>>,
+    Extension(current_user): Extension<Option<User>>,
+    Query(params): Query<SettingsParams>,
+) -> Result<Html<String>, StatusCode> {
+    let user = current_user.as_ref().unwrap();
+
+    // A saved key the master keys can't open has to be entered again
+    let api_key_unreadable = user.openai_api_key.is_none()
+        && state
+            .chat_repo
+            .stored_api_key(user.id)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to load API key: {}", e);
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?
+            .is_some();
+
+    let mut context = Context::new();
+    context.insert("openai_api_key", &user.openai_api_key);
+    context.insert("api_key_unreadable", &api_key_unreadable);
+    context.insert("base_url", &user.base_url);
+    context.insert("model", &user.model);
+    context.insert("system_prompt", &user.system_prompt);
+    context.insert("temperature", &user.temperature);
+    context.insert("top_p", &user.top_p);
+    context.insert("max_tokens", &user.max_tokens);
+    let settings_version = state
+        .chat_repo
+        .get_settings_version(user.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    context.insert("settings_version", &settings_version);
+    context.insert("conflict", &params.conflict);
+
+    let custom_tools = state
+        .chat_repo
+        .get_custom_tools(user.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    context.insert("custom_tools", &custom_tools);
+
+    let agents = state
+        .chat_repo
+        .get_agents(user.id, user.current_org_id, None, NO_LIMIT)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    // Only the user's own agents can be edited
+    let editing = params
+        .edit_agent
+        .and_then(|agent_id| agents.iter().find(|agent| agent.id == agent_id && agent.user_id == user.id));
+    context.insert("editing", &editing);
+    context.insert("editing_stop_sequences", &editing.map(|agent| agent.stop_sequences().join("\n")));
+    context.insert(
+        "editing_allow_tools",
+        &editing.and_then(|agent| agent.allow_tools()).map(|tools| tools.join("\n")),
+    );
+    context.insert("agents", &agents);
+    context.insert("user_id", &user.id);
+
+    // Admins of the current org can share agents in it
+    let mut shares_in = None;
+    if let Some(org_id) = user.current_org_id {
+        let role = state
+            .chat_repo
+            .get_org_role(org_id, user.id)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        if role >= Some(OrgRole::Admin) {
+            shares_in = state
+                .chat_repo
+                .get_org(org_id)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+                .map(|org| org.name);
+        }
+    }
+    context.insert("shares_in", &shares_in);
+
+    let api_tokens = state
+        .chat_repo
+        .get_api_tokens(user.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    context.insert("api_tokens", &api_tokens);
+    context.insert("new_token", &None::<String>);
+
+    let tool_approvals = state
+        .chat_repo
+        .get_tool_approvals(user.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    context.insert("tool_approvals", &tool_approvals);
+
+    let own_servers = users::user_manager(&state.chat_repo, &state.encryption, user.id).await;
+    let mut mcp_servers: Vec<McpServerSettings> = own_servers
+        .get_server_configs()
+        .await
+        .into_iter()
+        .map(|(name, config)| server_settings(name, config))
+        .collect();
+    mcp_servers.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut shared_mcp_servers: Vec<String> = get_mcp_manager().get_server_configs().await.into_keys().collect();
+    shared_mcp_servers.sort();
+    context.insert("mcp_servers", &mcp_servers);
+    context.insert("mcp_statuses", &own_servers.get_server_statuses().await);
+    let mcp_authorized: Vec<&str> = mcp_servers
+        .iter()
+        .map(|server| server.name.as_str())
+        .filter(|name| mcp_oauth::is_signed_in(&own_servers.client_name(name)))
+        .collect();
+    context.insert("mcp_authorized", &mcp_authorized);
+    context.insert("shared_mcp_servers", &shared_mcp_servers);
+    context.insert("shared_mcp_statuses", &get_mcp_manager().get_server_statuses().await);
+    context.insert("may_run_commands", &may_run_commands(user));
+    context.insert("is_admin", &user.is_admin);
+
+    let webhooks = state
+        .chat_repo
+        .get_webhooks(user.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    context.insert("webhooks", &webhooks);
+
+    let feedback_summary = state
+        .chat_repo
+        .feedback_summary(user.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    context.insert("feedback_summary", &feedback_summary);
 
     let settings = state.tera.render("views/settings.html", &context).unwrap();
 
@@ -0,0 +1,186 @@
+use axum::http::{header, HeaderMap};
+use tower_cookies::{
+    cookie::{time, SameSite},
+    Cookie, Cookies,
+};
+
+use std::net::{IpAddr, SocketAddr};
+
+use crate::audit::{self, AuditEvent, AuditSource};
+use crate::data::model::{RememberLogin, SessionUser};
+use crate::middleware::SESSION_COOKIE;
+use crate::utils::password;
+use crate::AppState;
+
+// Holds `<series>.<token>` for "Remember me" logins, see `remember`
+pub const REMEMBER_COOKIE: &str = "rust-gpt-remember";
+
+fn env_number(var: &str, default: i64) -> i64 {
+    dotenv::var(var)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .filter(|value| *value > 0)
+        .unwrap_or(default)
+}
+
+// Hours a login session lasts without being used, from `SESSION_IDLE_HOURS`
+pub fn idle_hours() -> i64 {
+    env_number("SESSION_IDLE_HOURS", 24)
+}
+
+// Days "Remember me" keeps a browser logged in without being used, from
+// `REMEMBER_ME_DAYS`
+pub fn remember_days() -> i64 {
+    env_number("REMEMBER_ME_DAYS", 30)
+}
+
+// The client's address. X-Forwarded-For is only believed with
+// `BEHIND_PROXY=true`, since anyone can send it
+pub fn client_ip(headers: &HeaderMap, peer: Option<SocketAddr>) -> Option<IpAddr> {
+    let behind_proxy = dotenv::var("BEHIND_PROXY").is_ok_and(|value| value == "true");
+    let forwarded = headers
+        .get("x-forwarded-for")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .and_then(|ip| ip.trim().parse().ok());
+    match forwarded {
+        Some(ip) if behind_proxy => Some(ip),
+        _ => peer.map(|addr| addr.ip()),
+    }
+}
+
+fn new_token() -> String {
+    format!("{}{}", uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple())
+}
+
+// Records the device in `sessions` and hands the browser the session token.
+// `impersonator_id` is set when an admin signs in as the user, and
+// `remember_id` when the session comes from a "Remember me" series
+pub async fn start_session(
+    state: &AppState,
+    cookies: &Cookies,
+    user_id: i64,
+    headers: &HeaderMap,
+    peer: Option<SocketAddr>,
+    impersonator_id: Option<i64>,
+    remember_id: Option<i64>,
+) -> sqlx::Result<()> {
+    let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok());
+    let ip = client_ip(headers, peer).map(|ip| ip.to_string());
+
+    let token = new_token();
+    state
+        .chat_repo
+        .create_session(
+            user_id,
+            &password::hash_token(&token),
+            user_agent,
+            ip.as_deref(),
+            impersonator_id,
+            remember_id,
+        )
+        .await?;
+
+    set_session_cookie(cookies, token);
+    Ok(())
+}
+
+// The session cookie itself goes when the browser closes
+pub fn set_session_cookie(cookies: &Cookies, token: String) {
+    let cookie = Cookie::build((SESSION_COOKIE, token))
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .build();
+    cookies.add(cookie);
+}
+
+pub fn end_session(cookies: &Cookies) {
+    for name in [SESSION_COOKIE, REMEMBER_COOKIE] {
+        let mut cookie = Cookie::build((name, "")).path("/").http_only(true).build();
+        cookie.make_removal();
+        cookies.add(cookie);
+    }
+}
+
+fn set_remember_cookie(cookies: &Cookies, series: &str, token: &str) {
+    let cookie = Cookie::build((REMEMBER_COOKIE, format!("{}.{}", series, token)))
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .max_age(time::Duration::days(remember_days()))
+        .build();
+    cookies.add(cookie);
+}
+
+// Starts a "Remember me" series and gives the browser its first token; the
+// session started along with it should carry the returned id
+pub async fn remember(state: &AppState, cookies: &Cookies, user_id: i64) -> sqlx::Result<i64> {
+    let series = uuid::Uuid::new_v4().simple().to_string();
+    let token = new_token();
+    let remember_id = state
+        .chat_repo
+        .create_remember_token(user_id, &series, &password::hash_token(&token), remember_days())
+        .await?;
+    set_remember_cookie(cookies, &series, &token);
+    Ok(remember_id)
+}
+
+// Logs the browser back in with its "Remember me" cookie once the session is
+// gone, swapping the cookie's token for a new one
+pub async fn resume(
+    state: &AppState,
+    cookies: &Cookies,
+    headers: &HeaderMap,
+    peer: Option<SocketAddr>,
+) -> Option<SessionUser> {
+    let cookie = cookies.get(REMEMBER_COOKIE)?;
+    let (series, token) = cookie.value().split_once('.')?;
+    let next = new_token();
+
+    let login = state
+        .chat_repo
+        .rotate_remember_token(
+            series,
+            &password::hash_token(token),
+            &password::hash_token(&next),
+            remember_days(),
+        )
+        .await;
+    let (user_id, remember_id) = match login {
+        Ok(RememberLogin::Rotated { user_id, remember_id }) => {
+            set_remember_cookie(cookies, series, &next);
+            (user_id, remember_id)
+        }
+        Ok(RememberLogin::Recent { user_id, remember_id }) => (user_id, remember_id),
+        Ok(RememberLogin::Reused(user_id)) => {
+            tracing::warn!("Revoked a copied \"Remember me\" cookie of user {}", user_id);
+            end_session(cookies);
+            return None;
+        }
+        Ok(RememberLogin::Invalid) => {
+            end_session(cookies);
+            return None;
+        }
+        Err(e) => {
+            tracing::error!("Failed to check a \"Remember me\" cookie: {}", e);
+            return None;
+        }
+    };
+
+    if let Err(e) = start_session(state, cookies, user_id, headers, peer, None, Some(remember_id)).await {
+        tracing::error!("Failed to create session for user {}: {}", user_id, e);
+        return None;
+    }
+    let source = AuditSource {
+        actor: None,
+        ip: client_ip(headers, peer),
+    };
+    audit::record(state, Some(user_id), AuditEvent::Login, Some("remember me"), source).await;
+    Some(SessionUser {
+        user_id,
+        impersonator: None,
+    })
+}
@@ -0,0 +1,161 @@
+// Collects the files exchanged in a conversation for the attachment gallery
+
+use std::sync::LazyLock;
+
+use regex::Regex;
+use serde::Serialize;
+
+use crate::data::model::{Attachment, ChatMessagePair};
+
+// Uploads used to be embedded by `chat_add_message` as `![name](/uploads/..)` or `[📎 name](/uploads/..)`
+static UPLOAD_LINK: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(!?)\[(?:📎 )?([^\]]*)\]\((/uploads/[^)\s]+)\)").unwrap());
+
+// Any reference to a stored upload, whether a link or a generated image URL.
+// Names are `<timestamp>-<uuid>.<ext>`, so no dots or slashes lead
+static UPLOAD_FILE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"/uploads/(\w[\w.-]*)").unwrap());
+
+// File names under `uploads/` that the text refers to
+pub fn upload_files(text: &str) -> Vec<String> {
+    UPLOAD_FILE
+        .captures_iter(text)
+        .map(|caps| caps[1].to_string())
+        .collect()
+}
+
+// The links earlier messages embedded, for the model's copy of the transcript
+pub fn attachment_links(attachments: &[Attachment]) -> String {
+    attachments
+        .iter()
+        .map(|attachment| {
+            if attachment.is_image() {
+                format!("\n\n![{}](/uploads/{})  ", attachment.name, attachment.path)
+            } else {
+                format!("\n\n[📎 {}](/uploads/{})  ", attachment.name, attachment.path)
+            }
+        })
+        .collect()
+}
+
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct GalleryItem {
+    // Set for files stored as attachments, which can be removed
+    pub attachment_id: Option<i64>,
+    pub pair_id: i64,
+    pub name: String,
+    pub url: String,
+    pub is_image: bool,
+    pub from_ai: bool,
+}
+
+// `stored` may hold attachments of pairs other than `pairs`; only those of
+// `pairs` are shown
+pub fn collect_attachments(pairs: &[ChatMessagePair], stored: &[Attachment]) -> Vec<GalleryItem> {
+    let mut attachments = Vec::new();
+
+    for pair in pairs {
+        for caps in UPLOAD_LINK.captures_iter(&pair.human_message) {
+            attachments.push(GalleryItem {
+                attachment_id: None,
+                pair_id: pair.id,
+                name: caps[2].to_string(),
+                url: caps[3].to_string(),
+                is_image: &caps[1] == "!",
+                from_ai: false,
+            });
+        }
+
+        for attachment in stored.iter().filter(|a| a.message_pair_id == pair.id) {
+            attachments.push(GalleryItem {
+                attachment_id: Some(attachment.id),
+                pair_id: pair.id,
+                name: attachment.name.clone(),
+                url: format!("/uploads/{}", attachment.path),
+                is_image: attachment.is_image(),
+                from_ai: false,
+            });
+        }
+
+        // Images generated by the model are stored as a JSON array of URLs
+        let images = pair
+            .images
+            .as_deref()
+            .and_then(|json| serde_json::from_str::<Vec<String>>(json).ok())
+            .unwrap_or_default();
+        for url in images {
+            attachments.push(GalleryItem {
+                attachment_id: None,
+                pair_id: pair.id,
+                name: "Generated image".to_string(),
+                url,
+                is_image: true,
+                from_ai: true,
+            });
+        }
+    }
+
+    attachments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_upload_files() {
+        let text = r#"![a.png](/uploads/1-a.png) ["/uploads/2-b.webp"] /uploads/../main.rs"#;
+        assert_eq!(upload_files(text), vec!["1-a.png", "2-b.webp"]);
+    }
+
+    #[test]
+    fn test_collect_attachments_from_transcript() {
+        let pair = ChatMessagePair {
+            id: 3,
+            model: "gpt-4".to_string(),
+            message_block_id: 1,
+            chat_id: 1,
+            human_message: "look\n\n![cat.png](/uploads/1-a.png)  \n\n[📎 notes.pdf](/uploads/1-b.pdf)  "
+                .to_string(),
+            ai_message: None,
+            block_rank: 1,
+            block_size: 1,
+            thinking: None,
+            tool_calls: None,
+            images: Some(r#"["https://example.com/gen.png"]"#.to_string()),
+            reasoning: None,
+            usage_prompt_tokens: None,
+            usage_completion_tokens: None,
+            usage_total_tokens: None,
+            sources: None,
+            finish_reason: None,
+        };
+
+        let stored = |message_pair_id, name: &str, kind: &str| Attachment {
+            id: 1,
+            message_pair_id,
+            name: name.to_string(),
+            path: format!("1-{}", name),
+            mime: "application/octet-stream".to_string(),
+            size: 1,
+            kind: kind.to_string(),
+        };
+        let stored = [stored(3, "d.txt", "file"), stored(4, "other.png", "image")];
+
+        let attachments = collect_attachments(&[pair], &stored);
+        assert_eq!(attachments.len(), 4);
+        assert_eq!(attachments[0].name, "cat.png");
+        assert!(attachments[0].is_image);
+        assert_eq!(attachments[1].url, "/uploads/1-b.pdf");
+        assert!(!attachments[1].is_image);
+        assert_eq!(attachments[2].url, "/uploads/1-d.txt");
+        assert_eq!(attachments[2].attachment_id, Some(1));
+        assert!(!attachments[2].is_image);
+        assert!(attachments[3].from_ai);
+        assert!(attachments.iter().all(|a| a.pair_id == 3));
+
+        assert_eq!(
+            attachment_links(&stored),
+            "\n\n[📎 d.txt](/uploads/1-d.txt)  \n\n![other.png](/uploads/1-other.png)  "
+        );
+    }
+}
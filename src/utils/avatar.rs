@@ -0,0 +1,66 @@
+use image::{imageops::FilterType, ImageFormat, ImageReader, Limits};
+use std::io::Cursor;
+
+// Avatars are stored as square PNGs of this size
+pub const AVATAR_SIZE: u32 = 256;
+// Larger images are refused before decoding
+const MAX_DIMENSION: u32 = 8192;
+
+// The square to keep of a `width` x `height` image, as `(left, top, side)`.
+// `zoom` of 1 keeps the largest square that fits; `x` and `y` from 0 to 1
+// place it from the left/top edge to the right/bottom one
+pub fn crop_box(width: u32, height: u32, zoom: f64, x: f64, y: f64) -> (u32, u32, u32) {
+    let zoom = if zoom.is_finite() { zoom.clamp(1.0, 10.0) } else { 1.0 };
+    let side = ((width.min(height) as f64 / zoom).round() as u32).max(1);
+    let place = |room: u32, at: f64| {
+        let at = if at.is_finite() { at.clamp(0.0, 1.0) } else { 0.5 };
+        (room as f64 * at).round() as u32
+    };
+    (place(width - side, x), place(height - side, y), side)
+}
+
+// Crops the uploaded image as chosen in the profile form and scales it down
+// to an avatar
+pub fn make_avatar(data: &[u8], zoom: f64, x: f64, y: f64) -> image::ImageResult<Vec<u8>> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    let mut reader = ImageReader::new(Cursor::new(data)).with_guessed_format()?;
+    reader.limits(limits);
+    let image = reader.decode()?;
+
+    let (left, top, side) = crop_box(image.width(), image.height(), zoom, x, y);
+    let avatar = image
+        .crop_imm(left, top, side, side)
+        .resize_exact(AVATAR_SIZE, AVATAR_SIZE, FilterType::Lanczos3);
+
+    let mut png = Vec::new();
+    avatar.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+    Ok(png)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crop_box() {
+        assert_eq!(crop_box(400, 200, 1.0, 0.5, 0.5), (100, 0, 200));
+        assert_eq!(crop_box(400, 200, 2.0, 0.0, 1.0), (0, 100, 100));
+        assert_eq!(crop_box(400, 200, 2.0, 1.0, 0.0), (300, 0, 100));
+        // Out of range values are clamped
+        assert_eq!(crop_box(200, 400, 0.5, 2.0, f64::NAN), (0, 100, 200));
+    }
+
+    #[test]
+    fn test_make_avatar() {
+        let mut source = Vec::new();
+        image::RgbImage::new(300, 200)
+            .write_to(&mut Cursor::new(&mut source), ImageFormat::Png)
+            .unwrap();
+
+        let avatar = image::load_from_memory(&make_avatar(&source, 1.5, 0.2, 0.8).unwrap()).unwrap();
+        assert_eq!((avatar.width(), avatar.height()), (AVATAR_SIZE, AVATAR_SIZE));
+        assert!(make_avatar(b"not an image", 1.0, 0.5, 0.5).is_err());
+    }
+}
@@ -0,0 +1,118 @@
+// Slash commands typed into the chat composer
+
+use serde::Serialize;
+
+#[derive(Debug, Serialize, Clone, Copy)]
+pub struct SlashCommand {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub description: &'static str,
+}
+
+pub const COMMANDS: &[SlashCommand] = &[
+    SlashCommand {
+        name: "summarize",
+        usage: "/summarize",
+        description: "Summarize the conversation so far",
+    },
+    SlashCommand {
+        name: "translate",
+        usage: "/translate <language> [text]",
+        description: "Translate the text, or the previous answer, into a language",
+    },
+    SlashCommand {
+        name: "tools",
+        usage: "/tools",
+        description: "List the tools the assistant can call",
+    },
+    SlashCommand {
+        name: "model",
+        usage: "/model <name>",
+        description: "Switch the model used in this chat",
+    },
+];
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    // Sent to the model in place of the typed command
+    Prompt(String),
+    Tools,
+    Model(String),
+}
+
+// `None` if the message isn't a registered command, so messages that merely
+// start with a slash (like a path) are sent as they are
+pub fn parse_command(message: &str) -> Option<Result<Command, String>> {
+    let rest = message.trim_start().strip_prefix('/')?;
+    let (name, args) = match rest.split_once(char::is_whitespace) {
+        Some((name, args)) => (name, args.trim()),
+        None => (rest.trim_end(), ""),
+    };
+    let command = COMMANDS.iter().find(|command| command.name == name)?;
+
+    let usage = || format!("Usage: {}", command.usage);
+    let parsed = match command.name {
+        "summarize" => Ok(Command::Prompt(
+            "Summarize our conversation so far in a few concise bullet points.".to_string(),
+        )),
+        "translate" => {
+            let (language, text) = match args.split_once(char::is_whitespace) {
+                Some((language, text)) => (language, text.trim()),
+                None => (args, ""),
+            };
+            if language.is_empty() {
+                Err(usage())
+            } else if text.is_empty() {
+                Ok(Command::Prompt(format!(
+                    "Translate your previous answer into {}. Reply with the translation only.",
+                    language
+                )))
+            } else {
+                Ok(Command::Prompt(format!(
+                    "Translate the following text into {}. Reply with the translation only.\n\n{}",
+                    language, text
+                )))
+            }
+        }
+        "tools" => Ok(Command::Tools),
+        "model" if !args.is_empty() && !args.contains(char::is_whitespace) => {
+            Ok(Command::Model(args.to_string()))
+        }
+        _ => Err(usage()),
+    };
+    Some(parsed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(parse_command("hello"), None);
+        assert_eq!(parse_command("/etc/hosts is missing"), None);
+
+        assert_eq!(
+            parse_command(" /model gpt-4o "),
+            Some(Ok(Command::Model("gpt-4o".to_string())))
+        );
+        assert_eq!(
+            parse_command("/model"),
+            Some(Err("Usage: /model <name>".to_string()))
+        );
+        assert_eq!(parse_command("/tools"), Some(Ok(Command::Tools)));
+
+        let Some(Ok(Command::Prompt(prompt))) = parse_command("/translate French Good morning")
+        else {
+            panic!("expected a prompt");
+        };
+        assert!(prompt.contains("into French"));
+        assert!(prompt.ends_with("\n\nGood morning"));
+
+        let Some(Ok(Command::Prompt(prompt))) = parse_command("/translate German") else {
+            panic!("expected a prompt");
+        };
+        assert!(prompt.contains("previous answer into German"));
+        assert!(matches!(parse_command("/translate"), Some(Err(_))));
+    }
+}
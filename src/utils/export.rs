@@ -0,0 +1,213 @@
+// Serializes a conversation into a downloadable Markdown or JSON document
+
+use serde::{Deserialize, Serialize};
+
+use crate::data::model::{Chat, ChatMessagePair, Source, ToolCall, UsageInfo};
+
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Md,
+    Json,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Md => "text/markdown; charset=utf-8",
+            ExportFormat::Json => "application/json",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Md => "md",
+            ExportFormat::Json => "json",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportedChat {
+    pub id: i64,
+    pub name: String,
+    pub messages: Vec<ExportedMessage>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportedMessage {
+    pub model: String,
+    pub human: String,
+    pub ai: Option<String>,
+    pub thinking: Option<String>,
+    pub reasoning: Option<String>,
+    #[serde(default)]
+    pub tool_calls: Vec<ToolCall>,
+    #[serde(default)]
+    pub images: Vec<String>,
+    #[serde(default)]
+    pub sources: Vec<Source>,
+    pub usage: Option<UsageInfo>,
+}
+
+fn parse_json_list<T: serde::de::DeserializeOwned>(json: Option<&str>) -> Vec<T> {
+    json.and_then(|json| serde_json::from_str(json).ok())
+        .unwrap_or_default()
+}
+
+pub fn build_export(chat: &Chat, pairs: &[ChatMessagePair]) -> ExportedChat {
+    let messages = pairs
+        .iter()
+        .map(|pair| ExportedMessage {
+            model: pair.model.clone(),
+            human: pair.human_message.clone(),
+            ai: pair.ai_message.clone(),
+            thinking: pair.thinking.clone(),
+            reasoning: pair.reasoning.clone(),
+            tool_calls: parse_json_list(pair.tool_calls.as_deref()),
+            images: parse_json_list(pair.images.as_deref()),
+            sources: parse_json_list(pair.sources.as_deref()),
+            usage: match (
+                pair.usage_prompt_tokens,
+                pair.usage_completion_tokens,
+                pair.usage_total_tokens,
+            ) {
+                (Some(prompt_tokens), Some(completion_tokens), Some(total_tokens)) => {
+                    Some(UsageInfo {
+                        prompt_tokens,
+                        completion_tokens,
+                        total_tokens,
+                    })
+                }
+                _ => None,
+            },
+        })
+        .collect();
+
+    ExportedChat {
+        id: chat.id,
+        name: chat.name.clone(),
+        messages,
+    }
+}
+
+pub fn to_markdown(export: &ExportedChat) -> String {
+    let mut md = format!("# {}\n", export.name);
+
+    for message in &export.messages {
+        md.push_str(&format!("\n## You\n\n{}\n", message.human.trim()));
+
+        md.push_str(&format!("\n## Assistant ({})\n\n", message.model));
+        if let Some(thinking) = &message.thinking {
+            md.push_str(&format!(
+                "<details>\n<summary>Thinking</summary>\n\n{}\n\n</details>\n\n",
+                thinking.trim()
+            ));
+        }
+        if let Some(reasoning) = &message.reasoning {
+            md.push_str(&format!(
+                "<details>\n<summary>Reasoning</summary>\n\n{}\n\n</details>\n\n",
+                reasoning.trim()
+            ));
+        }
+        for tool_call in &message.tool_calls {
+            md.push_str(&format!(
+                "**Tool call:** `{}`\n\n```json\n{}\n```\n\n",
+                tool_call.function.name, tool_call.function.arguments
+            ));
+        }
+        match &message.ai {
+            Some(ai) => md.push_str(&format!("{}\n", ai.trim())),
+            None => md.push_str("_No response_\n"),
+        }
+        for image in &message.images {
+            md.push_str(&format!("\n![Generated image]({})\n", image));
+        }
+        if !message.sources.is_empty() {
+            md.push_str("\n**Sources:**\n\n");
+            for (idx, source) in message.sources.iter().enumerate() {
+                let title = source.title.as_deref().unwrap_or("Untitled");
+                match &source.url {
+                    Some(url) => md.push_str(&format!("{}. [{}]({})\n", idx + 1, title, url)),
+                    None => md.push_str(&format!("{}. {}\n", idx + 1, title)),
+                }
+            }
+        }
+        if let Some(usage) = &message.usage {
+            md.push_str(&format!(
+                "\n_Tokens: {} prompt, {} completion, {} total_\n",
+                usage.prompt_tokens, usage.completion_tokens, usage.total_tokens
+            ));
+        }
+    }
+
+    md
+}
+
+pub fn render(export: &ExportedChat, format: ExportFormat) -> String {
+    match format {
+        ExportFormat::Md => to_markdown(export),
+        ExportFormat::Json => serde_json::to_string_pretty(export).unwrap_or_default(),
+    }
+}
+
+pub fn to_csv<T: Serialize>(rows: &[T]) -> Result<String, csv::Error> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for row in rows {
+        writer.serialize(row)?;
+    }
+    let bytes = writer.into_inner().map_err(|e| e.into_error())?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_export_includes_extended_data() {
+        let chat = Chat {
+            id: 1,
+            name: "Rust questions".to_string(),
+            user_id: 1,
+            pinned: false,
+            sort_order: 0,
+            folder_id: None,
+            tags: None,
+            archived: false,
+            share_token: None,
+        };
+        let pair = ChatMessagePair {
+            id: 1,
+            model: "gpt-4".to_string(),
+            message_block_id: 1,
+            chat_id: 1,
+            human_message: "What is a lifetime?".to_string(),
+            ai_message: Some("A scope for references.".to_string()),
+            block_rank: 1,
+            block_size: 1,
+            thinking: Some("Explain briefly".to_string()),
+            tool_calls: None,
+            images: None,
+            reasoning: None,
+            usage_prompt_tokens: Some(10),
+            usage_completion_tokens: Some(5),
+            usage_total_tokens: Some(15),
+            sources: Some(r#"[{"title":"The Book","url":"https://doc.rust-lang.org/book/","snippet":null}]"#.to_string()),
+            finish_reason: None,
+        };
+
+        let export = build_export(&chat, &[pair]);
+        let md = render(&export, ExportFormat::Md);
+        assert!(md.starts_with("# Rust questions\n"));
+        assert!(md.contains("<summary>Thinking</summary>"));
+        assert!(md.contains("1. [The Book](https://doc.rust-lang.org/book/)"));
+        assert!(md.contains("15 total"));
+
+        let json: serde_json::Value =
+            serde_json::from_str(&render(&export, ExportFormat::Json)).unwrap();
+        assert_eq!(json["messages"][0]["usage"]["total_tokens"], 15);
+        assert_eq!(json["messages"][0]["sources"][0]["title"], "The Book");
+    }
+}
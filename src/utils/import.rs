@@ -0,0 +1,215 @@
+// Parses conversation exports (ChatGPT `conversations.json` or this app's own
+// JSON export) into chats ready to be recreated by the repository
+
+use std::collections::{HashMap, HashSet};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::export::{ExportedChat, ExportedMessage};
+
+const IMPORTED_MODEL: &str = "imported";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ImportError {
+    #[error("Invalid JSON: {0}")]
+    InvalidJson(#[from] serde_json::Error),
+
+    #[error("Unrecognized export format")]
+    UnknownFormat,
+
+    #[error("No conversations found")]
+    Empty,
+
+    #[error("Message tree loops back on itself at node {0}")]
+    Cycle(String),
+}
+
+#[derive(Deserialize)]
+struct GptConversation {
+    title: Option<String>,
+    mapping: HashMap<String, GptNode>,
+    current_node: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GptNode {
+    message: Option<GptMessage>,
+    parent: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GptMessage {
+    author: GptAuthor,
+    content: GptContent,
+    #[serde(default)]
+    metadata: HashMap<String, Value>,
+}
+
+#[derive(Deserialize)]
+struct GptAuthor {
+    role: String,
+}
+
+#[derive(Deserialize)]
+struct GptContent {
+    #[serde(default)]
+    parts: Vec<Value>,
+}
+
+pub fn parse_import(data: &[u8]) -> Result<Vec<ExportedChat>, ImportError> {
+    let value: Value = serde_json::from_slice(data)?;
+
+    let chats = match &value {
+        Value::Array(_) => serde_json::from_value::<Vec<GptConversation>>(value)?
+            .into_iter()
+            .map(from_chatgpt)
+            .collect::<Result<Vec<_>, _>>()?
+            .into_iter()
+            .flatten()
+            .collect(),
+        Value::Object(obj) if obj.contains_key("mapping") => {
+            from_chatgpt(serde_json::from_value(value)?)?.into_iter().collect()
+        }
+        Value::Object(obj) if obj.contains_key("messages") => {
+            vec![serde_json::from_value::<ExportedChat>(value)?]
+        }
+        _ => return Err(ImportError::UnknownFormat),
+    };
+
+    if chats.is_empty() {
+        return Err(ImportError::Empty);
+    }
+    Ok(chats)
+}
+
+// ChatGPT stores a conversation as a tree; the visible thread is the path from
+// `current_node` back to the root. A parent chain that comes back to a node
+// it already passed is an error rather than an endless walk
+fn from_chatgpt(conversation: GptConversation) -> Result<Option<ExportedChat>, ImportError> {
+    let mut thread = Vec::new();
+    let mut visited = HashSet::new();
+    let mut node_id = conversation.current_node.clone();
+    while let Some(id) = node_id {
+        let Some(node) = conversation.mapping.get(&id) else {
+            break;
+        };
+        if !visited.insert(id.clone()) {
+            return Err(ImportError::Cycle(id));
+        }
+        if let Some(message) = &node.message {
+            thread.push(message);
+        }
+        node_id = node.parent.clone();
+    }
+    thread.reverse();
+
+    let mut messages: Vec<ExportedMessage> = Vec::new();
+    for message in thread {
+        let text = message
+            .content
+            .parts
+            .iter()
+            .filter_map(Value::as_str)
+            .collect::<Vec<_>>()
+            .join("\n");
+        if text.trim().is_empty() {
+            continue;
+        }
+
+        match message.author.role.as_str() {
+            "user" => messages.push(ExportedMessage {
+                model: IMPORTED_MODEL.to_string(),
+                human: text,
+                ai: None,
+                thinking: None,
+                reasoning: None,
+                tool_calls: Vec::new(),
+                images: Vec::new(),
+                sources: Vec::new(),
+                usage: None,
+            }),
+            "assistant" => {
+                // Assistant text before the first user turn has nothing to pair with
+                if let Some(last) = messages.last_mut() {
+                    match &mut last.ai {
+                        Some(ai) => {
+                            ai.push_str("\n\n");
+                            ai.push_str(&text);
+                        }
+                        None => last.ai = Some(text),
+                    }
+                    if let Some(model) = message.metadata.get("model_slug").and_then(Value::as_str) {
+                        last.model = model.to_string();
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if messages.is_empty() {
+        return Ok(None);
+    }
+
+    Ok(Some(ExportedChat {
+        id: 0,
+        name: conversation
+            .title
+            .filter(|t| !t.trim().is_empty())
+            .unwrap_or_else(|| "Imported chat".to_string()),
+        messages,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_chatgpt_export_follows_current_branch() {
+        let data = r#"[{
+            "title": "Greetings",
+            "current_node": "c",
+            "mapping": {
+                "root": {"message": null, "parent": null},
+                "a": {"message": {"author": {"role": "user"}, "content": {"parts": ["Hi"]}}, "parent": "root"},
+                "old": {"message": {"author": {"role": "assistant"}, "content": {"parts": ["Discarded"]}}, "parent": "a"},
+                "c": {"message": {"author": {"role": "assistant"}, "content": {"parts": ["Hello!"]},
+                      "metadata": {"model_slug": "gpt-4o"}}, "parent": "a"}
+            }
+        }]"#;
+
+        let chats = parse_import(data.as_bytes()).unwrap();
+        assert_eq!(chats.len(), 1);
+        assert_eq!(chats[0].name, "Greetings");
+        assert_eq!(chats[0].messages.len(), 1);
+        assert_eq!(chats[0].messages[0].human, "Hi");
+        assert_eq!(chats[0].messages[0].ai.as_deref(), Some("Hello!"));
+        assert_eq!(chats[0].messages[0].model, "gpt-4o");
+    }
+
+    #[test]
+    fn test_parse_own_export_and_reject_unknown() {
+        let data = r#"{"id": 4, "name": "Mine", "messages": [{"model": "gpt-4", "human": "Q", "ai": "A",
+            "thinking": null, "reasoning": null, "tool_calls": [], "images": [], "sources": [], "usage": null}]}"#;
+        let chats = parse_import(data.as_bytes()).unwrap();
+        assert_eq!(chats[0].name, "Mine");
+        assert_eq!(chats[0].messages[0].ai.as_deref(), Some("A"));
+
+        assert!(matches!(parse_import(b"42"), Err(ImportError::UnknownFormat)));
+    }
+
+    #[test]
+    fn test_parse_chatgpt_export_with_cyclic_mapping() {
+        let data = r#"{
+            "title": "Loop",
+            "current_node": "b",
+            "mapping": {
+                "a": {"message": {"author": {"role": "user"}, "content": {"parts": ["Hi"]}}, "parent": "b"},
+                "b": {"message": {"author": {"role": "assistant"}, "content": {"parts": ["Hello!"]}}, "parent": "a"}
+            }
+        }"#;
+        assert!(matches!(parse_import(data.as_bytes()), Err(ImportError::Cycle(id)) if id == "b"));
+    }
+}
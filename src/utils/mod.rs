@@ -1,5 +1,14 @@
 // Utility functions used across multiple modules
 
+pub mod attachments;
+pub mod avatar;
+pub mod commands;
+pub mod export;
+pub mod import;
+pub mod outbound;
+pub mod password;
+pub mod rerender;
+
 // Enhanced function to add DaisyUI classes and basic code styling
 pub fn add_daisyui_classes(html: &str) -> String {
     let mut styled_html = html.to_string();
@@ -0,0 +1,145 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::redirect::{Attempt, Policy};
+use reqwest::Url;
+
+// Requests to URLs users configure (custom tools, webhooks, hosted MCP
+// servers) only go to public addresses, so they can't be pointed at the
+// server's own network. OUTBOUND_ALLOW_PRIVATE=true lifts this for
+// self-hosted setups whose tools live on the local network
+fn allow_private() -> bool {
+    dotenv::var("OUTBOUND_ALLOW_PRIVATE").is_ok_and(|value| value == "true")
+}
+
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        // "This network", carrier-grade NAT, benchmarking and reserved
+        || a == 0
+        || (a == 100 && (64..128).contains(&b))
+        || (a == 198 && (18..20).contains(&b))
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // Unique local, link-local and documentation
+        || (first & 0xfe00) == 0xfc00
+        || (first & 0xffc0) == 0xfe80
+        || (first == 0x2001 && ip.segments()[1] == 0x0db8))
+}
+
+fn allows(ip: IpAddr) -> bool {
+    is_public(ip) || allow_private()
+}
+
+// IP literals never reach the resolver, so they're checked on their own
+fn host_ip(url: &Url) -> Option<IpAddr> {
+    let host = url.host_str()?;
+    host.trim_start_matches('[').trim_end_matches(']').parse().ok()
+}
+
+fn literal_allowed(url: &Url) -> bool {
+    url.host_str().is_some() && host_ip(url).is_none_or(allows)
+}
+
+// Checks an http(s) URL before it's saved or requested: every address its
+// host resolves to has to be public
+pub async fn check_url(url: &str) -> Result<(), String> {
+    let parsed = Url::parse(url).map_err(|_| format!("invalid URL {}", url))?;
+    if !matches!(parsed.scheme(), "http" | "https") {
+        return Err(format!("{} isn't an http(s) URL", url));
+    }
+    if !literal_allowed(&parsed) {
+        return Err(format!("{} points to a private address", url));
+    }
+    if let (None, Some(host)) = (host_ip(&parsed), parsed.host_str()) {
+        let port = parsed.port_or_known_default().unwrap_or(80);
+        let addresses = tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|e| format!("can't resolve {}: {}", host, e))?;
+        for address in addresses {
+            if !allows(address.ip()) {
+                return Err(format!("{} resolves to the private address {}", host, address.ip()));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Resolves like the system does, but fails for hosts with a private address,
+// which also covers a host re-pointed between `check_url` and the request
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addresses: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+            if let Some(address) = addresses.iter().find(|address| !allows(address.ip())) {
+                return Err(format!("{} resolves to the private address {}", host, address.ip()).into());
+            }
+            Ok(Box::new(addresses.into_iter()) as Addrs)
+        })
+    }
+}
+
+fn follow(attempt: Attempt) -> reqwest::redirect::Action {
+    if attempt.previous().len() >= 10 {
+        attempt.error("too many redirects")
+    } else if !literal_allowed(attempt.url()) {
+        let url = attempt.url().to_string();
+        attempt.error(format!("redirected to the private address {}", url))
+    } else {
+        attempt.follow()
+    }
+}
+
+// A client that only connects to public addresses, redirects included.
+// Callers add their own timeouts
+pub fn client_builder() -> reqwest::ClientBuilder {
+    reqwest::Client::builder()
+        .dns_resolver(std::sync::Arc::new(PublicResolver))
+        .redirect(Policy::custom(follow))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_private_addresses_are_refused() {
+        for ip in ["127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1", "0.0.0.0", "::1", "fd00::1", "fe80::1", "::ffff:127.0.0.1"] {
+            assert!(!is_public(ip.parse().unwrap()), "{} should be private", ip);
+        }
+        for ip in ["8.8.8.8", "1.1.1.1", "2606:4700:4700::1111"] {
+            assert!(is_public(ip.parse().unwrap()), "{} should be public", ip);
+        }
+
+        assert!(check_url("http://127.0.0.1:8080/hook").await.is_err());
+        assert!(check_url("http://[::1]/hook").await.is_err());
+        assert!(check_url("http://localhost/hook").await.is_err());
+        assert!(check_url("ftp://example.com/hook").await.is_err());
+        assert!(check_url("not a url").await.is_err());
+    }
+}
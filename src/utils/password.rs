@@ -0,0 +1,53 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use sha2::{Digest, Sha256};
+
+// Argon2id PHC string for storing in `users.password`
+pub fn hash(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default().hash_password(password.as_bytes(), &salt)?.to_string())
+}
+
+// Accounts created before passwords were hashed still hold the plain text,
+// which `login_form` replaces with a hash on the next successful login
+pub fn is_legacy(stored: &str) -> bool {
+    PasswordHash::new(stored).is_err()
+}
+
+pub fn verify(password: &str, stored: &str) -> bool {
+    match PasswordHash::new(stored) {
+        Ok(parsed) => Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .is_ok(),
+        Err(_) => password == stored,
+    }
+}
+
+// Session cookies and emailed links are random and long, so a plain SHA-256
+// is enough; only the hash is stored, so the tables alone can't be used to
+// take over accounts
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_and_verify() {
+        let hashed = hash("hunter2").unwrap();
+        assert!(hashed.starts_with("$argon2id$"));
+        assert!(!is_legacy(&hashed));
+        assert!(verify("hunter2", &hashed));
+        assert!(!verify("hunter3", &hashed));
+        // Salted, so the same password never hashes the same way twice
+        assert_ne!(hashed, hash("hunter2").unwrap());
+
+        assert!(is_legacy("hunter2"));
+        assert!(verify("hunter2", "hunter2"));
+        assert!(!verify("hunter3", "hunter2"));
+    }
+}
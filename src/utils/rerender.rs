@@ -0,0 +1,116 @@
+// Re-render stored messages with the current markdown pipeline and flag any
+// output that lost content, so renderer upgrades can be checked before rollout.
+
+use std::collections::BTreeSet;
+
+use crate::data::model::StoredMessage;
+
+use super::markdown_to_html;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RenderIssueKind {
+    // Non-empty markdown rendered to nothing
+    Empty,
+    // Words from the source that no longer appear anywhere in the HTML
+    MissingContent(Vec<String>),
+}
+
+#[derive(Debug, Clone)]
+pub struct RenderIssue {
+    pub message_id: i64,
+    pub chat_id: i64,
+    pub kind: RenderIssueKind,
+}
+
+#[derive(Debug, Default)]
+pub struct RerenderReport {
+    pub checked: usize,
+    pub issues: Vec<RenderIssue>,
+}
+
+// Split text into lowercase alphanumeric words, ignoring single characters
+fn tokens(text: &str) -> BTreeSet<String> {
+    let decoded = html_escape::decode_html_entities(text);
+    decoded
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|t| t.chars().count() > 1)
+        .map(|t| t.to_lowercase())
+        .collect()
+}
+
+// Compare a markdown source against its rendered HTML. Attribute values are
+// part of the HTML string, so link targets and image sources still count.
+pub fn check_rendered(source: &str, html: &str) -> Option<RenderIssueKind> {
+    if source.trim().is_empty() {
+        return None;
+    }
+
+    if html.trim().is_empty() {
+        return Some(RenderIssueKind::Empty);
+    }
+
+    let rendered = tokens(html);
+    let missing: Vec<String> = tokens(source)
+        .into_iter()
+        .filter(|t| !rendered.contains(t))
+        .collect();
+
+    if missing.is_empty() {
+        None
+    } else {
+        Some(RenderIssueKind::MissingContent(missing))
+    }
+}
+
+pub fn rerender_messages(messages: &[StoredMessage]) -> RerenderReport {
+    let mut report = RerenderReport::default();
+
+    for stored in messages {
+        let html = markdown_to_html(&stored.message);
+        report.checked += 1;
+
+        if let Some(kind) = check_rendered(&stored.message, &html) {
+            report.issues.push(RenderIssue {
+                message_id: stored.id,
+                chat_id: stored.chat_id,
+                kind,
+            });
+        }
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_rendered_detects_lost_content() {
+        let source = "Hello **world**, see [docs](https://example.com)";
+
+        assert_eq!(check_rendered(source, &markdown_to_html(source)), None);
+        assert_eq!(check_rendered(source, "   "), Some(RenderIssueKind::Empty));
+        assert_eq!(
+            check_rendered(source, "<p>Hello <strong>world</strong>, see docs</p>"),
+            Some(RenderIssueKind::MissingContent(vec![
+                "com".to_string(),
+                "example".to_string(),
+                "https".to_string(),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_rerender_messages_reports_escaped_html_as_intact() {
+        let messages = vec![StoredMessage {
+            id: 1,
+            chat_id: 1,
+            message: "<script>alert('x')</script>\n\n```rust\nfn main() {}\n```".to_string(),
+        }];
+
+        let report = rerender_messages(&messages);
+        assert_eq!(report.checked, 1);
+        assert!(report.issues.is_empty(), "{:?}", report.issues);
+    }
+}
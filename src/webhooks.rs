@@ -0,0 +1,131 @@
+use std::time::Duration;
+
+use hmac::{Hmac, Mac};
+use reqwest::header::CONTENT_TYPE;
+use serde::Serialize;
+use sha2::Sha256;
+
+use crate::data::{model::WebhookDelivery, repository::ChatRepository};
+use crate::utils::outbound;
+
+pub const CHAT_CREATED: &str = "chat.created";
+pub const GENERATION_COMPLETED: &str = "generation.completed";
+pub const TOOL_EXECUTED: &str = "tool.executed";
+
+// Wait before each retry; a delivery is given up on once these run out
+const RETRY_DELAYS: [i64; 5] = [30, 120, 600, 3600, 21600];
+
+#[derive(Serialize)]
+struct Envelope<'a, T> {
+    event: &'a str,
+    created_at: chrono::DateTime<chrono::Utc>,
+    data: T,
+}
+
+// Queue `event` for the webhooks of the chat's owner. Failures are only
+// logged, as webhooks never hold up the chat itself
+pub async fn emit(repo: &ChatRepository, chat_id: i64, event: &str, data: impl Serialize) {
+    let envelope = Envelope {
+        event,
+        created_at: chrono::Utc::now(),
+        data,
+    };
+    let payload = match serde_json::to_string(&envelope) {
+        Ok(payload) => payload,
+        Err(e) => {
+            tracing::error!("Failed to serialize {} webhook payload: {}", event, e);
+            return;
+        }
+    };
+    if let Err(e) = repo.enqueue_webhook_event(chat_id, event, &payload).await {
+        tracing::error!("Failed to queue {} webhook for chat {}: {}", event, chat_id, e);
+    }
+}
+
+// `X-Webhook-Signature` value: HMAC-SHA256 of "<timestamp>.<body>" with the
+// webhook's secret, so receivers can check the sender and reject replays
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(format!("{}.{}", timestamp, body).as_bytes());
+    format!("t={},v1={}", timestamp, hex::encode(mac.finalize().into_bytes()))
+}
+
+// Seconds to wait after the `attempts`-th failure, `None` to give up
+pub fn retry_delay(attempts: i64) -> Option<i64> {
+    usize::try_from(attempts - 1)
+        .ok()
+        .and_then(|i| RETRY_DELAYS.get(i).copied())
+}
+
+async fn deliver(client: &reqwest::Client, delivery: &WebhookDelivery) -> Result<(), String> {
+    // The host may have been re-pointed since the webhook was saved
+    outbound::check_url(&delivery.url).await?;
+    let timestamp = chrono::Utc::now().timestamp();
+    client
+        .post(&delivery.url)
+        .header(CONTENT_TYPE, "application/json")
+        .header("X-Webhook-Event", &delivery.event)
+        .header("X-Webhook-Delivery", delivery.id.to_string())
+        .header("X-Webhook-Signature", sign(&delivery.secret, timestamp, &delivery.payload))
+        .body(delivery.payload.clone())
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+// Background task sending queued events
+pub async fn run_deliveries(repo: ChatRepository) {
+    let client = outbound::client_builder()
+        .connect_timeout(Duration::from_secs(5))
+        .timeout(Duration::from_secs(10))
+        .build()
+        .expect("can't build webhook HTTP client");
+
+    let mut interval = tokio::time::interval(Duration::from_secs(5));
+    loop {
+        interval.tick().await;
+
+        let due = match repo.get_due_webhook_deliveries(50).await {
+            Ok(due) => due,
+            Err(e) => {
+                tracing::error!("Failed to load webhook deliveries: {}", e);
+                continue;
+            }
+        };
+
+        for delivery in due {
+            let error = deliver(&client, &delivery).await.err();
+            let retry_in = error.as_ref().and_then(|_| retry_delay(delivery.attempts + 1));
+            if let Some(error) = &error {
+                tracing::warn!("Webhook delivery {} to {} failed: {}", delivery.id, delivery.url, error);
+            }
+            if let Err(e) = repo
+                .record_webhook_attempt(delivery.id, error.as_deref(), retry_in)
+                .await
+            {
+                tracing::error!("Failed to update webhook delivery {}: {}", delivery.id, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signature_and_backoff() {
+        // Same as `echo -n '1700000000.{}' | openssl dgst -sha256 -hmac whsec_test`
+        assert_eq!(
+            sign("whsec_test", 1700000000, "{}"),
+            "t=1700000000,v1=35495024f4ef3f94e5a93e22221544c4b75e9a42300cd965ab81cb85cd994e91"
+        );
+        assert_ne!(sign("whsec_test", 1700000000, "{}"), sign("whsec_other", 1700000000, "{}"));
+
+        assert_eq!(retry_delay(1), Some(30));
+        assert_eq!(retry_delay(5), Some(21600));
+        assert_eq!(retry_delay(6), None);
+    }
+}